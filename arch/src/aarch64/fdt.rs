@@ -45,6 +45,9 @@ const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
 const IRQ_TYPE_EDGE_RISING: u32 = 1;
 const IRQ_TYPE_LEVEL_HI: u32 = 4;
 
+// Width of the data bus of the UEFI variable store flash, in bytes.
+const FLASH_BANK_WIDTH: u32 = 4;
+
 // This links to libfdt which handles the creation of the binary blob
 // flattened device tree (fdt) that is passed to the kernel and indicates
 // the hardware configuration of the machine.
@@ -519,6 +522,21 @@ fn create_rtc_node<T: DeviceInfoForFDT + Clone + Debug>(
     Ok(())
 }
 
+fn create_flash_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut Vec<u8>,
+    dev_info: &T,
+) -> Result<()> {
+    // See Documentation/devicetree/bindings/mtd/mtd-physmap.yaml in the kernel.
+    let flash_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
+    append_begin_node(fdt, &format!("flash@{:x}", dev_info.addr()))?;
+    append_property_string(fdt, "compatible", "cfi-flash")?;
+    append_property(fdt, "reg", &flash_reg_prop)?;
+    append_property_u32(fdt, "bank-width", FLASH_BANK_WIDTH)?;
+    append_end_node(fdt)?;
+
+    Ok(())
+}
+
 fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug, S: ::std::hash::BuildHasher>(
     fdt: &mut Vec<u8>,
     dev_info: &HashMap<(DeviceType, String), T, S>,
@@ -529,6 +547,7 @@ fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug, S: ::std::hash::Buil
     for ((device_type, _device_id), info) in dev_info {
         match device_type {
             DeviceType::RTC => create_rtc_node(fdt, info)?,
+            DeviceType::Flash => create_flash_node(fdt, info)?,
             DeviceType::Serial => create_serial_node(fdt, info)?,
             DeviceType::Virtio(_) => {
                 ordered_virtio_device.push(info);
@@ -40,9 +40,17 @@ pub mod kvm {
         SaveICCRegisters(crate::aarch64::gic::Error),
         /// Error in restoring GIC CPU interface registers.
         RestoreICCRegisters(crate::aarch64::gic::Error),
+        /// Error in saving ITS registers.
+        SaveITSRegisters(crate::aarch64::gic::Error),
+        /// Error in restoring ITS registers.
+        RestoreITSRegisters(crate::aarch64::gic::Error),
+        /// Error in saving ITS tables into guest RAM.
+        SaveITSTables(crate::aarch64::gic::Error),
+        /// Error in restoring ITS tables from guest RAM.
+        RestoreITSTables(crate::aarch64::gic::Error),
     }
 
-    type Result<T> = result::Result<T, Error>;
+    pub type Result<T> = result::Result<T, Error>;
 
     pub struct KvmGICv3 {
         /// The hypervisor agnostic device
@@ -99,45 +107,58 @@ pub mod kvm {
 
         /// Save the state of GIC.
         fn state(&self, gicr_typers: &[u64]) -> Result<Gicv3State> {
-            // Flush redistributors pending tables to guest RAM.
-            save_pending_tables(&self.device()).map_err(Error::SavePendingTables)?;
+            gicv3_state(self.device(), gicr_typers)
+        }
 
-            let gicd_ctlr =
-                read_ctlr(&self.device()).map_err(Error::SaveDistributorCtrlRegisters)?;
+        /// Restore the state of GIC.
+        fn set_state(&mut self, gicr_typers: &[u64], state: &Gicv3State) -> Result<()> {
+            set_gicv3_state(self.device(), gicr_typers, state)
+        }
+    }
 
-            let dist_state =
-                get_dist_regs(&self.device()).map_err(Error::SaveDistributorRegisters)?;
+    /// Save the state of the GICv3 distributor, redistributors and CPU
+    /// interfaces.
+    pub fn gicv3_state(
+        device: &Arc<dyn hypervisor::Device>,
+        gicr_typers: &[u64],
+    ) -> Result<Gicv3State> {
+        // Flush redistributors pending tables to guest RAM.
+        save_pending_tables(device).map_err(Error::SavePendingTables)?;
 
-            let rdist_state = get_redist_regs(&self.device(), &gicr_typers)
-                .map_err(Error::SaveRedistributorRegisters)?;
+        let gicd_ctlr = read_ctlr(device).map_err(Error::SaveDistributorCtrlRegisters)?;
 
-            let icc_state =
-                get_icc_regs(&self.device(), &gicr_typers).map_err(Error::SaveICCRegisters)?;
+        let dist_state = get_dist_regs(device).map_err(Error::SaveDistributorRegisters)?;
 
-            Ok(Gicv3State {
-                dist: dist_state,
-                rdist: rdist_state,
-                icc: icc_state,
-                gicd_ctlr,
-            })
-        }
+        let rdist_state =
+            get_redist_regs(device, gicr_typers).map_err(Error::SaveRedistributorRegisters)?;
 
-        /// Restore the state of GIC.
-        fn set_state(&mut self, gicr_typers: &[u64], state: &Gicv3State) -> Result<()> {
-            write_ctlr(&self.device(), state.gicd_ctlr)
-                .map_err(Error::RestoreDistributorCtrlRegisters)?;
+        let icc_state = get_icc_regs(device, gicr_typers).map_err(Error::SaveICCRegisters)?;
 
-            set_dist_regs(&self.device(), &state.dist)
-                .map_err(Error::RestoreDistributorRegisters)?;
+        Ok(Gicv3State {
+            dist: dist_state,
+            rdist: rdist_state,
+            icc: icc_state,
+            gicd_ctlr,
+        })
+    }
 
-            set_redist_regs(&self.device(), gicr_typers, &state.rdist)
-                .map_err(Error::RestoreRedistributorRegisters)?;
+    /// Restore the state of the GICv3 distributor, redistributors and CPU
+    /// interfaces.
+    pub fn set_gicv3_state(
+        device: &Arc<dyn hypervisor::Device>,
+        gicr_typers: &[u64],
+        state: &Gicv3State,
+    ) -> Result<()> {
+        write_ctlr(device, state.gicd_ctlr).map_err(Error::RestoreDistributorCtrlRegisters)?;
 
-            set_icc_regs(&self.device(), &gicr_typers, &state.icc)
-                .map_err(Error::RestoreICCRegisters)?;
+        set_dist_regs(device, &state.dist).map_err(Error::RestoreDistributorRegisters)?;
 
-            Ok(())
-        }
+        set_redist_regs(device, gicr_typers, &state.rdist)
+            .map_err(Error::RestoreRedistributorRegisters)?;
+
+        set_icc_regs(device, gicr_typers, &state.icc).map_err(Error::RestoreICCRegisters)?;
+
+        Ok(())
     }
 
     impl GICDevice for KvmGICv3 {
@@ -194,7 +215,7 @@ pub mod kvm {
 
         fn init_device_attributes(
             _vm: &Arc<dyn hypervisor::Vm>,
-            gic_device: &dyn GICDevice,
+            gic_device: &mut dyn GICDevice,
         ) -> crate::aarch64::gic::Result<()> {
             /* Setting up the distributor attribute.
              We are placing the GIC below 1GB so we need to substract the size of the distributor.
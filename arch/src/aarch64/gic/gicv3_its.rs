@@ -7,15 +7,86 @@ pub mod kvm {
     use std::sync::Arc;
     use std::{boxed::Box, result};
     type Result<T> = result::Result<T, Error>;
-    use crate::aarch64::gic::gicv3::kvm::KvmGICv3;
+    use crate::aarch64::gic::gicv3::kvm::{
+        gicv3_state, set_gicv3_state, Error as StateError, Gicv3State, KvmGICv3,
+    };
     use crate::aarch64::gic::kvm::KvmGICDevice;
     use crate::aarch64::gic::{Error, GICDevice};
+    use anyhow::anyhow;
     use hypervisor::kvm::kvm_bindings;
+    use vm_migration::{
+        Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+        Transportable,
+    };
+
+    // ITS registers saved and restored along with the ITS tables, see
+    // Documentation/virt/kvm/devices/arm-vgic-its.rst in the kernel code.
+    const GITS_CTLR: u64 = 0x0000;
+    const GITS_IIDR: u64 = 0x0004;
+    const GITS_CBASER: u64 = 0x0080;
+    const GITS_CWRITER: u64 = 0x0088;
+    const GITS_CREADR: u64 = 0x0090;
+    const GITS_BASER: u64 = 0x0100;
+    const GITS_BASER_COUNT: usize = 8;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Gicv3ItsState {
+        gicv3: Gicv3State,
+        its_ctlr: u64,
+        its_iidr: u64,
+        its_cbaser: u64,
+        its_cwriter: u64,
+        its_creadr: u64,
+        its_baser: [u64; GITS_BASER_COUNT],
+    }
+
+    fn its_reg_access(
+        its_device: &Arc<dyn hypervisor::Device>,
+        offset: u64,
+        val: &mut u64,
+        set: bool,
+    ) -> Result<()> {
+        let mut attr = kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_DEV_ARM_VGIC_GRP_ITS_REGS,
+            attr: offset,
+            addr: val as *mut u64 as u64,
+            flags: 0,
+        };
+        if set {
+            its_device
+                .set_device_attr(&attr)
+                .map_err(Error::SetDeviceAttribute)
+        } else {
+            its_device
+                .get_device_attr(&mut attr)
+                .map_err(Error::GetDeviceAttribute)
+        }
+    }
+
+    // Flush the ITS tables to guest RAM, or load them back from it.
+    fn its_tables_access(its_device: &Arc<dyn hypervisor::Device>, save: bool) -> Result<()> {
+        let attr = if save {
+            kvm_bindings::KVM_DEV_ARM_ITS_SAVE_TABLES
+        } else {
+            kvm_bindings::KVM_DEV_ARM_ITS_RESTORE_TABLES
+        };
+        its_device
+            .set_device_attr(&kvm_bindings::kvm_device_attr {
+                group: kvm_bindings::KVM_DEV_ARM_VGIC_GRP_CTRL,
+                attr: u64::from(attr),
+                addr: 0,
+                flags: 0,
+            })
+            .map_err(Error::SetDeviceAttribute)
+    }
 
     pub struct KvmGICv3ITS {
         /// The hypervisor agnostic device
         device: Arc<dyn hypervisor::Device>,
 
+        /// The hypervisor agnostic ITS device
+        its_device: Option<Arc<dyn hypervisor::Device>>,
+
         /// Vector holding values of GICR_TYPER for each vCPU
         gicr_typers: Vec<u64>,
 
@@ -39,6 +110,66 @@ pub mod kvm {
         fn get_msi_addr(vcpu_count: u64) -> u64 {
             KvmGICv3::get_redists_addr(vcpu_count) - KvmGICv3ITS::get_msi_size()
         }
+
+        /// Save the state of the GIC and its ITS.
+        fn state(&self, gicr_typers: &[u64]) -> result::Result<Gicv3ItsState, StateError> {
+            let gicv3 = gicv3_state(&self.device, gicr_typers)?;
+
+            // Safe to unwrap as the ITS device is created along with the GIC.
+            let its_device = self.its_device.as_ref().unwrap();
+            its_tables_access(its_device, true).map_err(StateError::SaveITSTables)?;
+
+            let read = |offset: u64| -> result::Result<u64, StateError> {
+                let mut val = 0;
+                its_reg_access(its_device, offset, &mut val, false)
+                    .map_err(StateError::SaveITSRegisters)?;
+                Ok(val)
+            };
+            let mut its_baser = [0; GITS_BASER_COUNT];
+            for (i, baser) in its_baser.iter_mut().enumerate() {
+                *baser = read(GITS_BASER + 8 * i as u64)?;
+            }
+
+            Ok(Gicv3ItsState {
+                gicv3,
+                its_ctlr: read(GITS_CTLR)?,
+                its_iidr: read(GITS_IIDR)?,
+                its_cbaser: read(GITS_CBASER)?,
+                its_cwriter: read(GITS_CWRITER)?,
+                its_creadr: read(GITS_CREADR)?,
+                its_baser,
+            })
+        }
+
+        /// Restore the state of the GIC and its ITS. The guest memory must
+        /// be restored first, as it holds the ITS tables.
+        fn set_state(
+            &mut self,
+            gicr_typers: &[u64],
+            state: &Gicv3ItsState,
+        ) -> result::Result<(), StateError> {
+            set_gicv3_state(&self.device, gicr_typers, &state.gicv3)?;
+
+            // Safe to unwrap as the ITS device is created along with the GIC.
+            let its_device = self.its_device.as_ref().unwrap();
+            let write = |offset: u64, mut val: u64| -> result::Result<(), StateError> {
+                its_reg_access(its_device, offset, &mut val, true)
+                    .map_err(StateError::RestoreITSRegisters)
+            };
+
+            // The IIDR carries the ABI revision of the tables, and the ITS
+            // must only be enabled through GITS_CTLR once the tables are
+            // loaded.
+            write(GITS_IIDR, state.its_iidr)?;
+            write(GITS_CBASER, state.its_cbaser)?;
+            write(GITS_CREADR, state.its_creadr)?;
+            write(GITS_CWRITER, state.its_cwriter)?;
+            for (i, baser) in state.its_baser.iter().enumerate() {
+                write(GITS_BASER + 8 * i as u64, *baser)?;
+            }
+            its_tables_access(its_device, false).map_err(StateError::RestoreITSTables)?;
+            write(GITS_CTLR, state.its_ctlr)
+        }
     }
 
     impl GICDevice for KvmGICv3ITS {
@@ -94,6 +225,7 @@ pub mod kvm {
         ) -> Box<dyn GICDevice> {
             Box::new(KvmGICv3ITS {
                 device,
+                its_device: None,
                 gicr_typers: vec![0; vcpu_count.try_into().unwrap()],
                 gic_properties: [
                     KvmGICv3::get_dist_addr(),
@@ -111,7 +243,7 @@ pub mod kvm {
 
         fn init_device_attributes(
             vm: &Arc<dyn hypervisor::Vm>,
-            gic_device: &dyn GICDevice,
+            gic_device: &mut dyn GICDevice,
         ) -> Result<()> {
             KvmGICv3::init_device_attributes(vm, gic_device)?;
 
@@ -141,7 +273,69 @@ pub mod kvm {
                 0,
             )?;
 
+            // Keep the ITS device around to save and restore its state.
+            if let Some(gic_device) = gic_device
+                .as_any_concrete_mut()
+                .downcast_mut::<KvmGICv3ITS>()
+            {
+                gic_device.its_device = Some(its_fd);
+            }
+
             Ok(())
         }
     }
+
+    pub const GIC_V3_ITS_SNAPSHOT_ID: &str = "gic-v3-its";
+    impl Snapshottable for KvmGICv3ITS {
+        fn id(&self) -> String {
+            GIC_V3_ITS_SNAPSHOT_ID.to_string()
+        }
+
+        fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+            let gicr_typers = self.gicr_typers.clone();
+            let state = self.state(&gicr_typers).map_err(|e| {
+                MigratableError::Snapshot(anyhow!("Could not save GICv3-ITS state {:?}", e))
+            })?;
+            let snapshot =
+                serde_json::to_vec(&state).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+            let mut gic_v3_its_snapshot = Snapshot::new(self.id().as_str());
+            gic_v3_its_snapshot.add_data_section(SnapshotDataSection {
+                id: format!("{}-section", self.id()),
+                snapshot,
+            });
+
+            Ok(gic_v3_its_snapshot)
+        }
+
+        fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+            if let Some(gic_v3_its_section) = snapshot
+                .snapshot_data
+                .get(&format!("{}-section", self.id()))
+            {
+                let gic_v3_its_state = serde_json::from_slice(&gic_v3_its_section.snapshot)
+                    .map_err(|e| {
+                        MigratableError::Restore(anyhow!("Could not deserialize GICv3-ITS {}", e))
+                    })?;
+
+                let gicr_typers = self.gicr_typers.clone();
+                return self
+                    .set_state(&gicr_typers, &gic_v3_its_state)
+                    .map_err(|e| {
+                        MigratableError::Restore(anyhow!(
+                            "Could not restore GICv3-ITS state {:?}",
+                            e
+                        ))
+                    });
+            }
+
+            Err(MigratableError::Restore(anyhow!(
+                "Could not find GICv3-ITS snapshot section"
+            )))
+        }
+    }
+
+    impl Pausable for KvmGICv3ITS {}
+    impl Transportable for KvmGICv3ITS {}
+    impl Migratable for KvmGICv3ITS {}
 }
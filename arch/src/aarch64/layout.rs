@@ -39,12 +39,30 @@
 //           |                                                               |
 //           |                    Reserved (now GIC is here)                 |
 //           |                                                               |
+// 96 M      +---------------------------------------------------------------+
+//           |                                                               |
+//           |                   UEFI variable store flash                   |
+//           |                                                               |
+// 64 M      +---------------------------------------------------------------+
+//           |                                                               |
+//           |                        UEFI code flash                        |
+//           |                                                               |
 // 0GB       +---------------------------------------------------------------+
 //
 //
 
 use vm_memory::GuestAddress;
 
+/// The UEFI firmware is loaded into a read only flash at the start of the address space,
+/// as expected by the EDK2 ArmVirtQemu firmware.
+pub const UEFI_START: u64 = 0;
+pub const UEFI_SIZE: u64 = 0x0400_0000;
+
+/// The UEFI variable store flash follows the code flash. It is smaller than on QEMU so that
+/// the GIC redistributors, which grow down from `MAPPED_IO_START`, don't overlap with it.
+pub const UEFI_VARS_START: u64 = 0x0400_0000;
+pub const UEFI_VARS_SIZE: u64 = 0x0200_0000;
+
 /// Below this address will reside the GIC, above this address will reside the MMIO devices.
 pub const MAPPED_IO_START: u64 = 0x0900_0000;
 
@@ -60,7 +60,7 @@ pub struct EntryPoint {
 /// Configure the specified VCPU, and return its MPIDR.
 pub fn configure_vcpu(
     fd: &Arc<dyn hypervisor::Vcpu>,
-    id: u8,
+    id: u16,
     kernel_entry_point: Option<EntryPoint>,
     vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
     _phys_bits: u8,
@@ -45,7 +45,7 @@ const PSTATE_FAULT_BITS_64: u64 = PSR_MODE_EL1h | PSR_A_BIT | PSR_F_BIT | PSR_I_
 /// * `mem` - Reserved DRAM for current VM.
 pub fn setup_regs(
     vcpu: &Arc<dyn hypervisor::Vcpu>,
-    cpu_id: u8,
+    cpu_id: u16,
     boot_ip: u64,
     mem: &GuestMemoryMmap,
 ) -> Result<()> {
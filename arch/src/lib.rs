@@ -102,9 +102,9 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
-    arch_memory_regions, configure_system, configure_vcpu, get_host_cpu_phys_bits,
-    initramfs_load_addr, layout, layout::CMDLINE_MAX_SIZE, layout::CMDLINE_START, regs,
-    BootProtocol, CpuidPatch, CpuidReg, EntryPoint,
+    arch_memory_regions, configure_system, configure_vcpu, configure_waking_vcpu,
+    get_host_cpu_phys_bits, initramfs_load_addr, layout, layout::CMDLINE_MAX_SIZE,
+    layout::CMDLINE_START, regs, BootProtocol, CpuidPatch, CpuidReg, EntryPoint, ReservedRegion,
 };
 
 /// Safe wrapper for `sysconf(_SC_PAGESIZE)`.
@@ -134,6 +134,9 @@ pub enum DeviceType {
     /// Device Type: RTC.
     #[cfg(target_arch = "aarch64")]
     RTC,
+    /// Device Type: Flash.
+    #[cfg(target_arch = "aarch64")]
+    Flash,
 }
 
 /// Default (smallest) memory page size for the supported architectures.
@@ -72,6 +72,16 @@ pub const RSDP_POINTER: GuestAddress = EBDA_START;
 
 pub const SMBIOS_START: u64 = 0xf0000; // First possible location per the spec.
 
+// Room for the SMBIOS tables and the MP table following them.
+pub const SMBIOS_MAX_SIZE: u64 = 0xf000;
+
+// VM generation ID, right after the room left to the SMBIOS and MP tables.
+pub const VMGENID_START: GuestAddress = GuestAddress(SMBIOS_START + SMBIOS_MAX_SIZE);
+pub const VMGENID_SIZE: u64 = 16;
+
+// Fail the build if the VM generation ID doesn't fit in the "EBDA" range.
+const _: [(); 0] = [(); (VMGENID_START.0 + VMGENID_SIZE > HIGH_RAM_START.0) as usize];
+
 // == End of "EBDA" range ==
 
 // ** High RAM (start: 1MiB, length: 3071MiB) **
@@ -56,6 +56,28 @@ pub struct EntryPoint {
 
 const E820_RAM: u32 = 1;
 const E820_RESERVED: u32 = 2;
+const E820_NVS: u32 = 4;
+
+/// Range the guest must not use as RAM, added to the memory map on top of
+/// the ranges the VMM reserves for itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReservedRegion {
+    pub start: GuestAddress,
+    pub size: GuestUsize,
+    /// ACPI Non-Volatile Storage, which the guest preserves across sleep
+    /// states, rather than plainly reserved memory.
+    pub nvs: bool,
+}
+
+impl ReservedRegion {
+    fn e820_type(&self) -> u32 {
+        if self.nvs {
+            E820_NVS
+        } else {
+            E820_RESERVED
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct SgxEpcSection {
@@ -331,7 +353,7 @@ impl CpuidPatch {
 
 pub fn configure_vcpu(
     fd: &Arc<dyn hypervisor::Vcpu>,
-    id: u8,
+    id: u16,
     kernel_entry_point: Option<EntryPoint>,
     vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
     cpuid: CpuId,
@@ -443,6 +465,16 @@ pub fn configure_vcpu(
     Ok(())
 }
 
+/// Restarts the boot vCPU from the waking vector the guest set up before
+/// suspending to RAM (ACPI S3), as the firmware does on resume.
+pub fn configure_waking_vcpu(
+    fd: &Arc<dyn hypervisor::Vcpu>,
+    waking_vector: u32,
+) -> super::Result<()> {
+    regs::setup_waking_regs(fd, waking_vector).map_err(Error::REGSConfiguration)?;
+    Ok(())
+}
+
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platform.
 /// For x86_64 all addresses are valid from the start of the kernel except a
@@ -498,24 +530,34 @@ pub fn arch_memory_regions(size: GuestUsize) -> Vec<(GuestAddress, usize, Region
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `reserved_regions` - Additional ranges reserved in the memory map.
 #[allow(clippy::too_many_arguments)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
     initramfs: &Option<InitramfsConfig>,
-    _num_cpus: u8,
+    _num_cpus: u16,
     setup_hdr: Option<setup_header>,
     rsdp_addr: Option<GuestAddress>,
     boot_prot: BootProtocol,
     sgx_epc_region: Option<SgxEpcRegion>,
+    reserved_regions: &[ReservedRegion],
+    serial_number: Option<&str>,
+    uuid: Option<&str>,
 ) -> super::Result<()> {
-    let size = smbios::setup_smbios(guest_mem).map_err(Error::SmbiosSetup)?;
+    let size = smbios::setup_smbios(guest_mem, serial_number, uuid).map_err(Error::SmbiosSetup)?;
 
     // Place the MP table after the SMIOS table aligned to 16 bytes
     let offset = GuestAddress(layout::SMBIOS_START).unchecked_add(size);
     let offset = GuestAddress((offset.0 + 16) & !0xf);
-    mptable::setup_mptable(offset, guest_mem, _num_cpus).map_err(Error::MpTableSetup)?;
+    // The MP table can only describe vCPUs with 8 bits APIC IDs, larger guests
+    // rely on the ACPI MADT instead.
+    if u32::from(_num_cpus) <= mptable::MAX_SUPPORTED_CPUS {
+        mptable::setup_mptable(offset, guest_mem, _num_cpus as u8).map_err(Error::MpTableSetup)?;
+    } else {
+        warn!("Skipping mptable creation for {} vCPUs", _num_cpus);
+    }
 
     // Check that the RAM is not smaller than the RSDP start address
     if let Some(rsdp_addr) = rsdp_addr {
@@ -532,6 +574,7 @@ pub fn configure_system(
                 initramfs,
                 rsdp_addr,
                 sgx_epc_region,
+                reserved_regions,
             )?;
         }
         BootProtocol::LinuxBoot => {
@@ -543,6 +586,7 @@ pub fn configure_system(
                 setup_hdr,
                 rsdp_addr,
                 sgx_epc_region,
+                reserved_regions,
             )?;
         }
     }
@@ -556,6 +600,7 @@ fn configure_pvh(
     initramfs: &Option<InitramfsConfig>,
     rsdp_addr: Option<GuestAddress>,
     sgx_epc_region: Option<SgxEpcRegion>,
+    reserved_regions: &[ReservedRegion],
 ) -> super::Result<()> {
     const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336ec578;
 
@@ -638,6 +683,15 @@ fn configure_pvh(
         )?;
     }
 
+    for region in reserved_regions.iter() {
+        add_memmap_entry(
+            &mut memmap,
+            region.start.raw_value(),
+            region.size,
+            region.e820_type(),
+        )?;
+    }
+
     start_info.0.memmap_entries = memmap.len() as u32;
 
     // Copy the vector with the memmap table to the MEMMAP_START address
@@ -705,6 +759,7 @@ fn configure_64bit_boot(
     setup_hdr: Option<setup_header>,
     rsdp_addr: Option<GuestAddress>,
     sgx_epc_region: Option<SgxEpcRegion>,
+    reserved_regions: &[ReservedRegion],
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x53726448;
@@ -777,6 +832,15 @@ fn configure_64bit_boot(
         )?;
     }
 
+    for region in reserved_regions.iter() {
+        add_e820_entry(
+            &mut params.0,
+            region.start.raw_value(),
+            region.size,
+            region.e820_type(),
+        )?;
+    }
+
     if let Some(rsdp_addr) = rsdp_addr {
         params.0.acpi_rsdp_addr = rsdp_addr.0;
     }
@@ -1002,6 +1066,9 @@ mod tests {
             Some(layout::RSDP_POINTER),
             BootProtocol::LinuxBoot,
             None,
+            &[],
+            None,
+            None,
         );
         assert!(config_err.is_err());
 
@@ -1024,6 +1091,9 @@ mod tests {
             None,
             BootProtocol::LinuxBoot,
             None,
+            &[],
+            None,
+            None,
         )
         .unwrap();
 
@@ -1037,6 +1107,9 @@ mod tests {
             None,
             BootProtocol::PvhBoot,
             None,
+            &[],
+            None,
+            None,
         )
         .unwrap();
 
@@ -1059,6 +1132,9 @@ mod tests {
             None,
             BootProtocol::LinuxBoot,
             None,
+            &[],
+            None,
+            None,
         )
         .unwrap();
 
@@ -1072,6 +1148,9 @@ mod tests {
             None,
             BootProtocol::PvhBoot,
             None,
+            &[],
+            None,
+            None,
         )
         .unwrap();
 
@@ -1094,6 +1173,9 @@ mod tests {
             None,
             BootProtocol::LinuxBoot,
             None,
+            &[],
+            None,
+            None,
         )
         .unwrap();
 
@@ -1107,8 +1189,62 @@ mod tests {
             None,
             BootProtocol::PvhBoot,
             None,
+            &[],
+            None,
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reserved_regions() {
+        let arch_mem_regions = arch_memory_regions(128 << 20);
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
+        let reserved_regions = [
+            ReservedRegion {
+                start: GuestAddress(0x7f0_0000),
+                size: 0x10_0000,
+                nvs: false,
+            },
+            ReservedRegion {
+                start: GuestAddress(0xfed0_0000),
+                size: 0x1000,
+                nvs: true,
+            },
+        ];
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &None,
+            1,
+            None,
+            None,
+            BootProtocol::LinuxBoot,
+            None,
+            &reserved_regions,
+            None,
+            None,
         )
         .unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        let entries = &params.0.e820_table[..params.0.e820_entries as usize];
+        let nvs = entries[entries.len() - 1];
+        let reserved = entries[entries.len() - 2];
+        assert_eq!(
+            (reserved.addr, reserved.size, reserved.type_),
+            (0x7f0_0000, 0x10_0000, E820_RESERVED)
+        );
+        assert_eq!(
+            (nvs.addr, nvs.size, nvs.type_),
+            (0xfed0_0000, 0x1000, E820_NVS)
+        );
     }
 
     #[test]
@@ -13,7 +13,7 @@ use std::slice;
 use libc::c_char;
 
 use arch_gen::x86::mpspec;
-use layout::{APIC_START, HIGH_RAM_START, IOAPIC_START};
+use layout::{APIC_START, IOAPIC_START, VMGENID_START};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
 };
@@ -133,7 +133,8 @@ pub fn setup_mptable(offset: GuestAddress, mem: &GuestMemoryMmap, num_cpus: u8)
 
     let mp_size = compute_mp_size(num_cpus);
 
-    if offset.unchecked_add(mp_size as u64) >= HIGH_RAM_START {
+    // The table must not overlap the VM generation ID.
+    if offset.unchecked_add(mp_size as u64) > VMGENID_START {
         warn!("Skipping mptable creation due to insufficient space");
         return Ok(());
     }
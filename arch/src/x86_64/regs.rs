@@ -111,6 +111,43 @@ pub fn setup_regs(
     vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
 }
 
+/// Puts a vCPU back in real mode, at the waking vector the guest provided
+/// through the FACS before suspending to RAM (ACPI S3).
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `waking_vector` - Physical address the guest resumes from.
+pub fn setup_waking_regs(vcpu: &Arc<dyn hypervisor::Vcpu>, waking_vector: u32) -> Result<()> {
+    let mut sregs: SpecialRegisters = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
+
+    // CS:IP points at the waking vector, per the ACPI specification.
+    let code_base = waking_vector & !0xf;
+    let mut code_seg = segment_from_gdt(gdt_entry(0x009b, code_base, 0xffff), 0);
+    code_seg.selector = (code_base >> 4) as u16;
+    let data_seg = segment_from_gdt(gdt_entry(0x0093, 0, 0xffff), 0);
+
+    sregs.cs = code_seg;
+    sregs.ds = data_seg;
+    sregs.es = data_seg;
+    sregs.fs = data_seg;
+    sregs.gs = data_seg;
+    sregs.ss = data_seg;
+    sregs.idt.base = 0;
+    sregs.idt.limit = 0x3ff;
+    sregs.cr0 &= !(CR0_PE | CR0_PG);
+    sregs.cr4 = 0;
+    sregs.efer = 0;
+    vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)?;
+
+    let regs = StandardRegisters {
+        rflags: 0x0000000000000002u64,
+        rip: u64::from(waking_vector & 0xf),
+        ..Default::default()
+    };
+    vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
+}
+
 /// Configures the segment registers and system page tables for a given CPU.
 ///
 /// # Arguments
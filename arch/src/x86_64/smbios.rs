@@ -6,7 +6,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use layout::SMBIOS_START;
+use layout::{SMBIOS_MAX_SIZE, SMBIOS_START};
 use std::fmt::{self, Display};
 use std::mem;
 use std::result;
@@ -27,6 +27,8 @@ pub enum Error {
     WriteSmbiosEp,
     /// Failure to write additional data to memory
     WriteData,
+    /// The system UUID is not in the 8-4-4-4-12 hexadecimal digits form
+    ParseUuid,
 }
 
 impl std::error::Error for Error {}
@@ -41,6 +43,7 @@ impl Display for Error {
             Clear => "Failure while zeroing out the memory for the SMBIOS table",
             WriteSmbiosEp => "Failure to write SMBIOS entrypoint structure",
             WriteData => "Failure to write additional data to memory",
+            ParseUuid => "The system UUID is not in the 8-4-4-4-12 hexadecimal digits form",
         };
 
         write!(f, "SMBIOS error: {}", description)
@@ -142,6 +145,10 @@ fn write_and_incr<T: ByteValued>(
     val: T,
     mut curptr: GuestAddress,
 ) -> Result<GuestAddress> {
+    // The tables must not spill over the VM generation ID.
+    if curptr.0 + mem::size_of::<T>() as u64 > SMBIOS_START + SMBIOS_MAX_SIZE {
+        return Err(Error::AddressOverflow);
+    }
     mem.write_obj(val, curptr).map_err(|_| Error::WriteData)?;
     curptr = curptr
         .checked_add(mem::size_of::<T>() as u64)
@@ -161,7 +168,35 @@ fn write_string(
     Ok(curptr)
 }
 
-pub fn setup_smbios(mem: &GuestMemoryMmap) -> Result<u64> {
+// The UUID as stored in the system information, the first three fields being
+// little endian.
+fn encode_uuid(uuid: &str) -> Result<[u8; 16usize]> {
+    let valid = uuid.len() == 36
+        && uuid.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    if !valid {
+        return Err(Error::ParseUuid);
+    }
+
+    let digits: String = uuid.chars().filter(|c| *c != '-').collect();
+    let mut bytes = [0u8; 16usize];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).map_err(|_| Error::ParseUuid)?;
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+
+    Ok(bytes)
+}
+
+pub fn setup_smbios(
+    mem: &GuestMemoryMmap,
+    serial_number: Option<&str>,
+    uuid: Option<&str>,
+) -> Result<u64> {
     let physptr = GuestAddress(SMBIOS_START)
         .checked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
         .ok_or(Error::NotEnoughMemory)?;
@@ -192,9 +227,18 @@ pub fn setup_smbios(mem: &GuestMemoryMmap) -> Result<u64> {
         smbios_sysinfo.handle = handle;
         smbios_sysinfo.manufacturer = 1; // First string written in this section
         smbios_sysinfo.product_name = 2; // Second string written in this section
+        if serial_number.is_some() {
+            smbios_sysinfo.serial_number = 3; // Third string written in this section
+        }
+        if let Some(uuid) = uuid {
+            smbios_sysinfo.uuid = encode_uuid(uuid)?;
+        }
         curptr = write_and_incr(mem, smbios_sysinfo, curptr)?;
         curptr = write_string(mem, "Cloud Hypervisor", curptr)?;
         curptr = write_string(mem, "cloud-hypervisor", curptr)?;
+        if let Some(serial_number) = serial_number {
+            curptr = write_string(mem, serial_number, curptr)?;
+        }
         curptr = write_and_incr(mem, 0 as u8, curptr)?;
     }
 
@@ -254,10 +298,40 @@ mod tests {
     fn entrypoint_checksum() {
         let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
 
-        setup_smbios(&mem).unwrap();
+        setup_smbios(&mem, None, None).unwrap();
 
         let smbios_ep: Smbios30Entrypoint = mem.read_obj(GuestAddress(SMBIOS_START)).unwrap();
 
         assert_eq!(compute_checksum(&smbios_ep), 0);
     }
+
+    #[test]
+    fn system_uuid() {
+        assert_eq!(
+            encode_uuid("00112233-4455-6677-8899-aabbccddeeff").unwrap(),
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff
+            ]
+        );
+        assert!(encode_uuid("00112233-4455-6677-8899-aabbccddeef").is_err());
+        assert!(encode_uuid("00112233+4455-6677-8899-aabbccddeeff").is_err());
+        assert!(encode_uuid("0011223g-4455-6677-8899-aabbccddeeff").is_err());
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
+        assert!(setup_smbios(&mem, Some("serial"), Some("not-a-uuid")).is_err());
+        let large_mem =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 0x10000)]).unwrap();
+        let serial_number = "0".repeat(SMBIOS_MAX_SIZE as usize);
+        assert!(matches!(
+            setup_smbios(&large_mem, Some(&serial_number), None),
+            Err(Error::AddressOverflow)
+        ));
+        setup_smbios(
+            &mem,
+            Some("serial"),
+            Some("00112233-4455-6677-8899-aabbccddeeff"),
+        )
+        .unwrap();
+    }
 }
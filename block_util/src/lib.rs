@@ -13,6 +13,8 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod luks;
+
 #[cfg(feature = "io_uring")]
 use io_uring::Probe;
 use io_uring::{opcode, squeue, IoUring};
@@ -71,21 +73,32 @@ fn build_device_id(disk_path: &PathBuf) -> result::Result<String, Error> {
     Ok(device_id)
 }
 
+/// Maximum length of the disk serial, the kernel reading at most
+/// VIRTIO_BLK_ID_BYTES.
+pub const MAX_SERIAL_LEN: usize = VIRTIO_BLK_ID_BYTES as usize;
+
+// Zero out any leftover bytes, and truncate the ID to VIRTIO_BLK_ID_BYTES.
+fn build_id(id: &[u8]) -> Vec<u8> {
+    let mut disk_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+    let bytes_to_copy = cmp::min(id.len(), VIRTIO_BLK_ID_BYTES as usize);
+    disk_id[..bytes_to_copy].clone_from_slice(&id[..bytes_to_copy]);
+    disk_id
+}
+
 pub fn build_disk_image_id(disk_path: &PathBuf) -> Vec<u8> {
-    let mut default_disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
     match build_device_id(disk_path) {
         Err(_) => {
             warn!("Could not generate device id. We'll use a default.");
+            build_id(&[])
         }
-        Ok(m) => {
-            // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
-            // This will also zero out any leftover bytes.
-            let disk_id = m.as_bytes();
-            let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
-            default_disk_image_id[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy])
-        }
+        Ok(m) => build_id(m.as_bytes()),
     }
-    default_disk_image_id
+}
+
+/// Disk ID reported to the guest when a serial has been provided, instead of
+/// the one generated from the disk image.
+pub fn build_serial(serial: &str) -> Vec<u8> {
+    build_id(serial.as_bytes())
 }
 
 #[derive(Debug)]
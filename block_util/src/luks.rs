@@ -0,0 +1,667 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Transparent encryption of disk images in the LUKS1 format, as created by
+//! `cryptsetup luksFormat --type luks1`.
+//!
+//! The header at the start of the image describes the cipher protecting the
+//! payload and holds up to eight key slots. Each slot stores the master key,
+//! anti-forensically split into stripes and encrypted with a key derived from
+//! a passphrase through PBKDF2. The payload is encrypted sector by sector,
+//! only AES in XTS mode being supported, which is the cryptsetup default.
+
+use aes::cipher::consts::U16;
+use aes::cipher::generic_array::GenericArray;
+use aes::{Aes128, Aes256, BlockCipher, NewBlockCipher};
+use hmac::Hmac;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+pub const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+const LUKS_VERSION: u16 = 1;
+const LUKS_SECTOR_SIZE: usize = 512;
+const HEADER_SIZE: usize = 592;
+const KEY_SLOTS_OFFSET: usize = 208;
+const KEY_SLOT_SIZE: usize = 48;
+const KEY_SLOT_ENABLED: u32 = 0x00ac_71f3;
+const DIGEST_SIZE: usize = 20;
+// cryptsetup always splits the keys into this many stripes. Bounding them,
+// as well as the PBKDF2 iterations, keeps a corrupted header from making the
+// VMM allocate gigabytes or spin for hours.
+const MAX_STRIPES: usize = 4000;
+// cryptsetup picks the iterations taking about two seconds on the host
+// formatting the image, which is a few millions.
+const MAX_PBKDF2_ITERATIONS: u32 = 1 << 24;
+// Sectors read or written at once, bounding the size of the bounce buffer.
+const MAX_SECTORS_PER_IO: usize = 256;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Overwrite a secret, like a passphrase, before it gets dropped. The writes
+/// are volatile for the compiler not to elide them.
+pub fn clear_secret(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // Safe because the pointer comes from a valid reference.
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Overwrite a value holding key material, like an expanded cipher key, before
+/// it gets dropped.
+///
+/// # Safety
+///
+/// `T` must not implement `Drop`, directly or through its fields, and zero must
+/// be a valid value for it.
+pub unsafe fn clear_secret_value<T>(value: &mut T) {
+    let bytes = value as *mut T as *mut u8;
+    for i in 0..mem::size_of::<T>() {
+        ptr::write_volatile(bytes.add(i), 0);
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Key, or key material, cleared when dropped.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(len: usize) -> Self {
+        Secret(vec![0u8; len])
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for Secret {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        clear_secret(&mut self.0);
+    }
+}
+
+fn check_iterations(iterations: u32) -> io::Result<u32> {
+    if iterations == 0 || iterations > MAX_PBKDF2_ITERATIONS {
+        return Err(invalid_data("Unsupported LUKS PBKDF2 iterations"));
+    }
+    Ok(iterations)
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Strings of the header are padded with NUL characters.
+fn header_str(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+fn xor(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+fn round_up_to_sector(len: usize) -> usize {
+    (len + LUKS_SECTOR_SIZE - 1) / LUKS_SECTOR_SIZE * LUKS_SECTOR_SIZE
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Hash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Hash {
+    fn from_spec(spec: &str) -> io::Result<Self> {
+        match spec {
+            "sha1" => Ok(Hash::Sha1),
+            "sha256" => Ok(Hash::Sha256),
+            "sha512" => Ok(Hash::Sha512),
+            _ => Err(invalid_data("Unsupported LUKS hash")),
+        }
+    }
+
+    fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+        match self {
+            Hash::Sha1 => pbkdf2::pbkdf2::<Hmac<Sha1>>(password, salt, iterations, out),
+            Hash::Sha256 => pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations, out),
+            Hash::Sha512 => pbkdf2::pbkdf2::<Hmac<Sha512>>(password, salt, iterations, out),
+        }
+    }
+
+    fn diffuse(self, buf: &mut [u8]) {
+        match self {
+            Hash::Sha1 => diffuse::<Sha1>(buf),
+            Hash::Sha256 => diffuse::<Sha256>(buf),
+            Hash::Sha512 => diffuse::<Sha512>(buf),
+        }
+    }
+}
+
+// Diffusion function of the anti-forensic splitter, replacing each block of
+// the size of the digest by the hash of its index and content.
+fn diffuse<D: Digest>(buf: &mut [u8]) {
+    for (i, block) in buf.chunks_mut(D::output_size()).enumerate() {
+        let mut hasher = D::new();
+        hasher.update((i as u32).to_be_bytes());
+        hasher.update(&*block);
+        let digest = hasher.finalize();
+        let len = block.len();
+        block.copy_from_slice(&digest[..len]);
+    }
+}
+
+// Recover the key split into `stripes` stripes by the anti-forensic
+// splitter.
+fn af_merge(hash: Hash, material: &[u8], key_size: usize, stripes: usize) -> Secret {
+    let mut key = Secret::new(key_size);
+    for (i, stripe) in material.chunks(key_size).take(stripes).enumerate() {
+        xor(&mut key, stripe);
+        if i < stripes - 1 {
+            hash.diffuse(&mut key);
+        }
+    }
+    key
+}
+
+// XTS encryption or decryption of a sector, the tweak being derived from the
+// sector number.
+fn xts<C: BlockCipher<BlockSize = U16>>(
+    data_cipher: &C,
+    tweak_cipher: &C,
+    iv: u64,
+    buf: &mut [u8],
+    encrypt: bool,
+) {
+    let mut tweak = [0u8; 16];
+    tweak[..8].copy_from_slice(&iv.to_le_bytes());
+    tweak_cipher.encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+
+    for block in buf.chunks_exact_mut(16) {
+        xor(block, &tweak);
+        if encrypt {
+            data_cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        } else {
+            data_cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        }
+        xor(block, &tweak);
+
+        // Multiply the tweak by x in GF(2^128).
+        let carry = tweak[15] >> 7;
+        for i in (1..16).rev() {
+            tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+        }
+        tweak[0] = (tweak[0] << 1) ^ (0x87 * carry);
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+enum XtsCipher {
+    Aes128(Aes128, Aes128),
+    Aes256(Aes256, Aes256),
+}
+
+impl XtsCipher {
+    // The first half of the key encrypts the data, the second one the tweak.
+    fn new(key: &[u8]) -> io::Result<Self> {
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        match key.len() {
+            32 => Ok(XtsCipher::Aes128(
+                Aes128::new(GenericArray::from_slice(data_key)),
+                Aes128::new(GenericArray::from_slice(tweak_key)),
+            )),
+            64 => Ok(XtsCipher::Aes256(
+                Aes256::new(GenericArray::from_slice(data_key)),
+                Aes256::new(GenericArray::from_slice(tweak_key)),
+            )),
+            _ => Err(invalid_data("Unsupported LUKS key size")),
+        }
+    }
+
+    fn encrypt_sector(&self, iv: u64, sector: &mut [u8]) {
+        match self {
+            XtsCipher::Aes128(data, tweak) => xts(data, tweak, iv, sector, true),
+            XtsCipher::Aes256(data, tweak) => xts(data, tweak, iv, sector, true),
+        }
+    }
+
+    fn decrypt_sector(&self, iv: u64, sector: &mut [u8]) {
+        match self {
+            XtsCipher::Aes128(data, tweak) => xts(data, tweak, iv, sector, false),
+            XtsCipher::Aes256(data, tweak) => xts(data, tweak, iv, sector, false),
+        }
+    }
+}
+
+// The ciphers hold the round keys, from which the key can be recovered.
+impl Drop for XtsCipher {
+    fn drop(&mut self) {
+        // Safe because the ciphers are plain arrays of round keys, not
+        // implementing Drop, for which zero is a valid value.
+        unsafe { clear_secret_value(self) };
+    }
+}
+
+/// Disk image in the LUKS1 format, exposing its payload decrypted. Sectors
+/// are decrypted when read and encrypted when written, the image itself
+/// never holding the payload in clear.
+#[derive(Clone)]
+pub struct LuksFile<T> {
+    inner: T,
+    cipher: XtsCipher,
+    // The plain IV generator only keeps the lower 32 bits of the sector.
+    iv_mask: u64,
+    payload_offset: u64,
+    payload_size: u64,
+    position: u64,
+}
+
+impl<T: Read + Seek> LuksFile<T> {
+    /// Unlock the image with the passphrase, trying each enabled key slot
+    /// until one of them holds the master key.
+    pub fn open(mut inner: T, passphrase: &[u8]) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        if header[..6] != LUKS_MAGIC[..] {
+            return Err(invalid_data("Disk image is not in the LUKS format"));
+        }
+        if be_u16(&header[6..8]) != LUKS_VERSION {
+            return Err(invalid_data("Unsupported LUKS version"));
+        }
+
+        if header_str(&header[8..40]) != "aes" {
+            return Err(invalid_data("Unsupported LUKS cipher"));
+        }
+        let iv_mask = match header_str(&header[40..72]).as_str() {
+            "xts-plain64" => u64::MAX,
+            "xts-plain" => u64::from(u32::MAX),
+            _ => return Err(invalid_data("Unsupported LUKS cipher mode")),
+        };
+        let hash = Hash::from_spec(&header_str(&header[72..104]))?;
+        let payload_offset = u64::from(be_u32(&header[104..108])) * LUKS_SECTOR_SIZE as u64;
+        let key_size = be_u32(&header[108..112]) as usize;
+        if key_size != 32 && key_size != 64 {
+            return Err(invalid_data("Unsupported LUKS key size"));
+        }
+        let mk_digest = &header[112..132];
+        let mk_digest_salt = &header[132..164];
+        let mk_digest_iterations = check_iterations(be_u32(&header[164..168]))?;
+
+        let mut master_key = None;
+        for slot in header[KEY_SLOTS_OFFSET..].chunks_exact(KEY_SLOT_SIZE) {
+            let stripes = be_u32(&slot[44..48]) as usize;
+            if be_u32(&slot[0..4]) != KEY_SLOT_ENABLED || stripes == 0 {
+                continue;
+            }
+            if stripes > MAX_STRIPES {
+                return Err(invalid_data("Unsupported LUKS key slot stripes"));
+            }
+            let iterations = check_iterations(be_u32(&slot[4..8]))?;
+
+            let mut slot_key = Secret::new(key_size);
+            hash.pbkdf2(passphrase, &slot[8..40], iterations, &mut slot_key);
+            let slot_cipher = XtsCipher::new(&slot_key)?;
+
+            // The key material is encrypted like the payload, its sectors
+            // being numbered from its start.
+            let material_size = key_size
+                .checked_mul(stripes)
+                .ok_or_else(|| invalid_data("Unsupported LUKS key slot stripes"))?;
+            let mut material = Secret::new(round_up_to_sector(material_size));
+            let material_offset = u64::from(be_u32(&slot[40..44])) * LUKS_SECTOR_SIZE as u64;
+            inner.seek(SeekFrom::Start(material_offset))?;
+            inner.read_exact(&mut material)?;
+            for (i, sector) in material.chunks_exact_mut(LUKS_SECTOR_SIZE).enumerate() {
+                slot_cipher.decrypt_sector(i as u64 & iv_mask, sector);
+            }
+
+            let key = af_merge(hash, &material[..material_size], key_size, stripes);
+            let mut digest = [0u8; DIGEST_SIZE];
+            hash.pbkdf2(&key, mk_digest_salt, mk_digest_iterations, &mut digest);
+            if digest[..] == *mk_digest {
+                master_key = Some(key);
+                break;
+            }
+        }
+        let master_key = master_key.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "No LUKS key slot can be unlocked with the passphrase",
+            )
+        })?;
+
+        // A partial sector at the end of the image isn't part of the payload.
+        let image_size = inner.seek(SeekFrom::End(0))?;
+        let payload_size = image_size
+            .checked_sub(payload_offset)
+            .ok_or_else(|| invalid_data("LUKS payload beyond the end of the image"))?
+            / LUKS_SECTOR_SIZE as u64
+            * LUKS_SECTOR_SIZE as u64;
+
+        Ok(LuksFile {
+            inner,
+            cipher: XtsCipher::new(&master_key)?,
+            iv_mask,
+            payload_offset,
+            payload_size,
+            position: 0,
+        })
+    }
+
+    // First sector, offset within that sector and number of bytes of the
+    // next I/O of at most `len` bytes from the current position.
+    fn io_range(&self, len: usize) -> (u64, usize, usize) {
+        let sector = self.position / LUKS_SECTOR_SIZE as u64;
+        let offset = (self.position % LUKS_SECTOR_SIZE as u64) as usize;
+        let max_len = cmp::min(
+            self.payload_size.saturating_sub(self.position),
+            (MAX_SECTORS_PER_IO * LUKS_SECTOR_SIZE - offset) as u64,
+        );
+        (sector, offset, cmp::min(len as u64, max_len) as usize)
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(
+            self.payload_offset + sector * LUKS_SECTOR_SIZE as u64,
+        ))?;
+        self.inner.read_exact(buf)?;
+        for (i, data) in buf.chunks_exact_mut(LUKS_SECTOR_SIZE).enumerate() {
+            self.cipher
+                .decrypt_sector((sector + i as u64) & self.iv_mask, data);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read + Seek> Read for LuksFile<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (sector, offset, count) = self.io_range(buf.len());
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let mut sectors = vec![0u8; round_up_to_sector(offset + count)];
+        self.read_sectors(sector, &mut sectors)?;
+        buf[..count].copy_from_slice(&sectors[offset..offset + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<T: Read + Seek + Write> Write for LuksFile<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (sector, offset, count) = self.io_range(buf.len());
+        if count == 0 {
+            return Ok(0);
+        }
+
+        // Sectors partially written are merged with their current content.
+        let mut sectors = vec![0u8; round_up_to_sector(offset + count)];
+        if offset != 0 {
+            self.read_sectors(sector, &mut sectors[..LUKS_SECTOR_SIZE])?;
+        }
+        if (offset + count) % LUKS_SECTOR_SIZE != 0 {
+            let last = sectors.len() - LUKS_SECTOR_SIZE;
+            let last_sector = sector + (last / LUKS_SECTOR_SIZE) as u64;
+            self.read_sectors(last_sector, &mut sectors[last..])?;
+        }
+        sectors[offset..offset + count].copy_from_slice(&buf[..count]);
+
+        for (i, data) in sectors.chunks_exact_mut(LUKS_SECTOR_SIZE).enumerate() {
+            self.cipher
+                .encrypt_sector((sector + i as u64) & self.iv_mask, data);
+        }
+        self.inner.seek(SeekFrom::Start(
+            self.payload_offset + sector * LUKS_SECTOR_SIZE as u64,
+        ))?;
+        self.inner.write_all(&sectors)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T> Seek for LuksFile<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i128),
+            SeekFrom::End(offset) => (self.payload_size, offset as i128),
+            SeekFrom::Current(offset) => (self.position, offset as i128),
+        };
+        let position = base as i128 + offset;
+        if position < 0 || position > i128::from(u64::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek in the LUKS payload",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY_MATERIAL_SECTOR: u32 = 8;
+    const PAYLOAD_SECTOR: u32 = 512;
+    const PAYLOAD_SECTORS: usize = 16;
+    const STRIPES: usize = 4000;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Image with a single key slot, formatted the way cryptsetup does, with
+    // fixed salts and master key.
+    fn format(passphrase: &[u8], key_size: usize) -> Vec<u8> {
+        let mut image = vec![0u8; (PAYLOAD_SECTOR as usize + PAYLOAD_SECTORS) * LUKS_SECTOR_SIZE];
+        let master_key: Vec<u8> = (0..key_size).map(|i| i as u8).collect();
+        let mk_digest_salt = [0x11u8; 32];
+        let slot_salt = [0x22u8; 32];
+        let iterations = 1000u32;
+
+        let mut mk_digest = [0u8; DIGEST_SIZE];
+        Hash::Sha256.pbkdf2(&master_key, &mk_digest_salt, iterations, &mut mk_digest);
+
+        let header = &mut image[..HEADER_SIZE];
+        header[..6].copy_from_slice(LUKS_MAGIC);
+        header[6..8].copy_from_slice(&LUKS_VERSION.to_be_bytes());
+        header[8..11].copy_from_slice(b"aes");
+        header[40..51].copy_from_slice(b"xts-plain64");
+        header[72..78].copy_from_slice(b"sha256");
+        header[104..108].copy_from_slice(&PAYLOAD_SECTOR.to_be_bytes());
+        header[108..112].copy_from_slice(&(key_size as u32).to_be_bytes());
+        header[112..132].copy_from_slice(&mk_digest);
+        header[132..164].copy_from_slice(&mk_digest_salt);
+        header[164..168].copy_from_slice(&iterations.to_be_bytes());
+        let slot = &mut header[KEY_SLOTS_OFFSET..KEY_SLOTS_OFFSET + KEY_SLOT_SIZE];
+        slot[0..4].copy_from_slice(&KEY_SLOT_ENABLED.to_be_bytes());
+        slot[4..8].copy_from_slice(&iterations.to_be_bytes());
+        slot[8..40].copy_from_slice(&slot_salt);
+        slot[40..44].copy_from_slice(&KEY_MATERIAL_SECTOR.to_be_bytes());
+        slot[44..48].copy_from_slice(&(STRIPES as u32).to_be_bytes());
+
+        // Split the master key, the last stripe being chosen for the merge of
+        // all of them to give the key back.
+        let material_size = key_size * STRIPES;
+        let mut material = vec![0u8; round_up_to_sector(material_size)];
+        for (i, b) in material[..material_size - key_size].iter_mut().enumerate() {
+            *b = (i * 7) as u8;
+        }
+        let mut d = vec![0u8; key_size];
+        for stripe in material.chunks(key_size).take(STRIPES - 1) {
+            xor(&mut d, stripe);
+            Hash::Sha256.diffuse(&mut d);
+        }
+        xor(&mut d, &master_key);
+        material[material_size - key_size..material_size].copy_from_slice(&d);
+        assert_eq!(
+            &af_merge(Hash::Sha256, &material, key_size, STRIPES)[..],
+            &master_key[..]
+        );
+
+        let mut slot_key = vec![0u8; key_size];
+        Hash::Sha256.pbkdf2(passphrase, &slot_salt, iterations, &mut slot_key);
+        let slot_cipher = XtsCipher::new(&slot_key).unwrap();
+        for (i, sector) in material.chunks_exact_mut(LUKS_SECTOR_SIZE).enumerate() {
+            slot_cipher.encrypt_sector(i as u64, sector);
+        }
+        let material_offset = KEY_MATERIAL_SECTOR as usize * LUKS_SECTOR_SIZE;
+        image[material_offset..material_offset + material.len()].copy_from_slice(&material);
+
+        image
+    }
+
+    #[test]
+    fn test_xts() {
+        // Vectors 1 and 2 of IEEE 1619.
+        let cipher = XtsCipher::new(&[0u8; 32]).unwrap();
+        let mut data = [0u8; 32];
+        cipher.encrypt_sector(0, &mut data);
+        assert_eq!(
+            data.to_vec(),
+            hex("917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e")
+        );
+        cipher.decrypt_sector(0, &mut data);
+        assert_eq!(data, [0u8; 32]);
+
+        let mut key = vec![0x11u8; 16];
+        key.extend_from_slice(&[0x22u8; 16]);
+        let cipher = XtsCipher::new(&key).unwrap();
+        let mut data = [0x44u8; 32];
+        cipher.encrypt_sector(0x33_3333_3333, &mut data);
+        assert_eq!(
+            data.to_vec(),
+            hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0")
+        );
+    }
+
+    #[test]
+    fn test_luks_file() {
+        for key_size in [32, 64].iter() {
+            let image = Cursor::new(format(b"passphrase", *key_size));
+            let mut file = LuksFile::open(image, b"passphrase").unwrap();
+            assert_eq!(
+                file.seek(SeekFrom::End(0)).unwrap(),
+                (PAYLOAD_SECTORS * LUKS_SECTOR_SIZE) as u64
+            );
+
+            let mut before = vec![0u8; 300];
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_exact(&mut before).unwrap();
+
+            // Unaligned write across sectors
+            let content: Vec<u8> = (0..1000).map(|i| (i % 251) as u8 + 1).collect();
+            file.seek(SeekFrom::Start(300)).unwrap();
+            file.write_all(&content).unwrap();
+
+            let mut read = vec![0u8; 1000];
+            file.seek(SeekFrom::Start(300)).unwrap();
+            file.read_exact(&mut read).unwrap();
+            assert_eq!(read, content);
+
+            // The rest of the first sector is preserved, and the content
+            // isn't written in clear.
+            let mut after = vec![0u8; 300];
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_exact(&mut after).unwrap();
+            assert_eq!(after, before);
+            let payload_offset = PAYLOAD_SECTOR as usize * LUKS_SECTOR_SIZE;
+            let image = file.inner.get_ref();
+            assert!(!image[payload_offset..]
+                .windows(16)
+                .any(|w| w == &content[..16]));
+
+            // Writes don't go past the end of the payload.
+            file.seek(SeekFrom::End(-10)).unwrap();
+            assert_eq!(file.write(&content).unwrap(), 10);
+            assert_eq!(file.write(&content).unwrap(), 0);
+
+            let image = file.inner.clone();
+            let mut file = LuksFile::open(image, b"passphrase").unwrap();
+            file.seek(SeekFrom::Start(300)).unwrap();
+            file.read_exact(&mut read).unwrap();
+            assert_eq!(read, content);
+
+            let image = Cursor::new(format(b"passphrase", *key_size));
+            assert_eq!(
+                LuksFile::open(image, b"wrong").err().unwrap().kind(),
+                io::ErrorKind::PermissionDenied
+            );
+        }
+
+        let image = Cursor::new(vec![0u8; 1 << 20]);
+        assert_eq!(
+            LuksFile::open(image, b"passphrase").err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_luks_invalid_header() {
+        let slot = KEY_SLOTS_OFFSET;
+        for (offset, value) in [
+            // Key size
+            (108, 48),
+            // Master key digest iterations
+            (164, 0),
+            (164, MAX_PBKDF2_ITERATIONS + 1),
+            // Key slot iterations
+            (slot + 4, u32::MAX),
+            // Key slot stripes
+            (slot + 44, MAX_STRIPES as u32 + 1),
+            (slot + 44, u32::MAX),
+        ]
+        .iter()
+        {
+            let mut image = format(b"passphrase", 32);
+            image[*offset..*offset + 4].copy_from_slice(&value.to_be_bytes());
+            assert_eq!(
+                LuksFile::open(Cursor::new(image), b"passphrase")
+                    .err()
+                    .unwrap()
+                    .kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+}
@@ -11,29 +11,51 @@ use vm_device::BusDevice;
 use vmm_sys_util::eventfd::EventFd;
 use HotPlugNotificationFlags;
 
-/// A device for handling ACPI shutdown and reboot
+// The ACPI DSDT table specifies the S3 sleep state (suspend to RAM) as value 3
+const S3_SLEEP_VALUE: u8 = 3;
+// The ACPI DSDT table specifies the S5 sleep state (shutdown) as value 5
+const S5_SLEEP_VALUE: u8 = 5;
+const SLEEP_STATUS_EN_BIT: u8 = 5;
+const SLEEP_VALUE_BIT: u8 = 2;
+// WAK_STS bit from the sleep status register, set when the platform wakes up.
+const WAKE_STATUS_BIT: u8 = 7;
+
+/// A device for handling ACPI shutdown, reboot and suspend
 pub struct AcpiShutdownDevice {
     exit_evt: EventFd,
     reset_evt: EventFd,
+    suspend_evt: EventFd,
+    wake_status: bool,
 }
 
 impl AcpiShutdownDevice {
     /// Constructs a device that will signal the given event when the guest requests it.
-    pub fn new(exit_evt: EventFd, reset_evt: EventFd) -> AcpiShutdownDevice {
+    pub fn new(exit_evt: EventFd, reset_evt: EventFd, suspend_evt: EventFd) -> AcpiShutdownDevice {
         AcpiShutdownDevice {
             exit_evt,
             reset_evt,
+            suspend_evt,
+            wake_status: false,
         }
     }
+
+    /// Reports the platform woke up from S3 through the sleep status
+    /// register, which the guest polls after it requested to suspend.
+    pub fn wake(&mut self) {
+        self.wake_status = true;
+    }
 }
 
-// Same I/O port used for shutdown and reboot
+// Same I/O port used for shutdown, reboot and suspend
 impl BusDevice for AcpiShutdownDevice {
-    // Spec has all fields as zero
+    // Spec has all fields as zero, except WAK_STS after a wake up
     fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
         for i in data.iter_mut() {
             *i = 0;
         }
+        if self.wake_status {
+            data[0] = 1 << WAKE_STATUS_BIT;
+        }
     }
 
     fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
@@ -43,10 +65,17 @@ impl BusDevice for AcpiShutdownDevice {
                 error!("Error triggering ACPI reset event: {}", e);
             }
         }
-        // The ACPI DSDT table specifies the S5 sleep state (shutdown) as value 5
-        const S5_SLEEP_VALUE: u8 = 5;
-        const SLEEP_STATUS_EN_BIT: u8 = 5;
-        const SLEEP_VALUE_BIT: u8 = 2;
+        // WAK_STS is cleared by writing 1 to it
+        if data[0] & (1 << WAKE_STATUS_BIT) != 0 {
+            self.wake_status = false;
+        }
+        if data[0] == (S3_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
+            debug!("ACPI Suspend signalled");
+            self.wake_status = false;
+            if let Err(e) = self.suspend_evt.write(1) {
+                error!("Error triggering ACPI suspend event: {}", e);
+            }
+        }
         if data[0] == (S5_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
             debug!("ACPI Shutdown signalled");
             extern crate bitflags;
@@ -58,6 +87,11 @@ impl BusDevice for AcpiShutdownDevice {
     }
 }
 
+/// I/O port the guest reads the pending GED notifications from.
+pub const GED_DEVICE_ADDRESS: u64 = 0xb000;
+/// Size of the GED notification register.
+pub const GED_DEVICE_SIZE: u64 = 0x1;
+
 /// A device for handling ACPI GED event generation
 pub struct AcpiGEDDevice {
     interrupt: Arc<Box<dyn InterruptSourceGroup>>,
@@ -114,7 +148,12 @@ impl Aml for AcpiGEDDevice {
                         self.ged_irq,
                     )]),
                 ),
-                &aml::OpRegion::new("GDST".into(), aml::OpRegionSpace::SystemIO, 0xb000, 0x1),
+                &aml::OpRegion::new(
+                    "GDST".into(),
+                    aml::OpRegionSpace::SystemIO,
+                    GED_DEVICE_ADDRESS as usize,
+                    GED_DEVICE_SIZE as usize,
+                ),
                 &aml::Field::new(
                     "GDST".into(),
                     aml::FieldAccessType::Byte,
@@ -142,6 +181,22 @@ impl Aml for AcpiGEDDevice {
                             &aml::Equal::new(&aml::Local(1), &4usize),
                             vec![&aml::MethodCall::new("\\_SB_.PCI0.PCNT".into(), vec![])],
                         ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &8usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &8usize),
+                            vec![&aml::Notify::new(
+                                &aml::Path::new("\\_SB_.PWRB"),
+                                &0x80usize,
+                            )],
+                        ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &16usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &16usize),
+                            vec![&aml::Notify::new(
+                                &aml::Path::new("\\_SB_.VGEN"),
+                                &0x80usize,
+                            )],
+                        ),
                     ],
                 ),
             ],
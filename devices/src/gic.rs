@@ -28,7 +28,7 @@ pub struct Gic {
 
 impl Gic {
     pub fn new(
-        _vcpu_count: u8,
+        _vcpu_count: u16,
         interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
     ) -> Result<Gic> {
         let interrupt_source_group = interrupt_manager
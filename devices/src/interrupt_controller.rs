@@ -4,6 +4,7 @@
 
 use std::io;
 use std::result;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum Error {
@@ -27,6 +28,10 @@ pub enum Error {
 
 type Result<T> = result::Result<T, Error>;
 
+/// Called on the end of a level-triggered interrupt, for the device to
+/// assert it again if it still needs to be serviced.
+pub type EoiHandler = Arc<dyn Fn() + Send + Sync>;
+
 pub struct MsiMessage {
     // Message Address Register
     //   31-20: Base address. Fixed value (0x0FEE)
@@ -56,4 +61,8 @@ pub trait InterruptController: Send {
     fn enable(&self) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     fn end_of_interrupt(&mut self, vec: u8);
+    // Register `handler` to be called on the end of interrupt of `irq`, for
+    // the devices masking their level-triggered interrupt until then.
+    fn add_eoi_handler(&mut self, _irq: usize, _handler: EoiHandler) {}
+    fn remove_eoi_handler(&mut self, _irq: usize, _handler: &EoiHandler) {}
 }
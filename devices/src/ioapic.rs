@@ -9,7 +9,7 @@
 // Implementation of an intel 82093AA Input/Output Advanced Programmable Interrupt Controller
 // See https://pdos.csail.mit.edu/6.828/2016/readings/ia32/ioapic.pdf for a specification.
 
-use super::interrupt_controller::{Error, InterruptController};
+use super::interrupt_controller::{EoiHandler, Error, InterruptController};
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use std::result;
@@ -38,7 +38,8 @@ type Result<T> = result::Result<T, Error>;
 // split between two 32 bits registers as follow:
 //
 // 63-56: Destination Field - R/W
-// 55-17: Reserved
+// 55-49: Extended Destination Field - R/W
+// 48-17: Reserved
 // 16:    Interrupt Mask - R/W
 // 15:    Trigger Mode - R/W
 // 14:    Remote IRR - RO
@@ -75,6 +76,12 @@ fn destination_field(entry: RedirectionTableEntry) -> u8 {
     // retrieve the destination field based on bits 56-63.
     ((entry >> 56) & 0xffu64) as u8
 }
+fn extended_destination_field(entry: RedirectionTableEntry) -> u8 {
+    // Bits 8-14 of the destination ID, used by guests supporting the extended
+    // destination ID to address APIC IDs above 255 without interrupt
+    // remapping.
+    ((entry >> 49) & 0x7fu64) as u8
+}
 fn set_delivery_status(entry: &mut RedirectionTableEntry, val: u8) {
     // Clear bit 12
     *entry &= 0xffff_ffff_ffff_efff;
@@ -137,6 +144,7 @@ pub struct Ioapic {
     used_entries: [bool; NUM_IOAPIC_PINS],
     apic_address: GuestAddress,
     interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    eoi_handlers: Vec<Vec<EoiHandler>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -208,6 +216,7 @@ impl Ioapic {
             used_entries: [false; NUM_IOAPIC_PINS],
             apic_address,
             interrupt_source_group,
+            eoi_handlers: vec![Vec::new(); NUM_IOAPIC_PINS],
         })
     }
 
@@ -308,6 +317,7 @@ impl Ioapic {
         // Validate Destination Mode value, and retrieve Destination ID
         let destination_mode = destination_mode(entry);
         let destination_id = destination_field(entry);
+        let extended_destination_id = extended_destination_field(entry);
 
         // When this bit is set, the message is directed to the processor with
         // the lowest interrupt priority among processors that can receive the
@@ -317,6 +327,7 @@ impl Ioapic {
         // Generate MSI message address
         let low_addr: u32 = self.apic_address.0 as u32
             | u32::from(destination_id) << 12
+            | u32::from(extended_destination_id) << 5
             | u32::from(redirection_hint) << 3
             | u32::from(destination_mode) << 2;
 
@@ -381,6 +392,9 @@ impl InterruptController for Ioapic {
             // Clear Remote IRR bit
             if vector(*entry) == vec && trigger_mode(*entry) == 1 {
                 set_remote_irr(entry, 0);
+                for handler in self.eoi_handlers[i].iter() {
+                    handler();
+                }
             }
         }
     }
@@ -405,6 +419,14 @@ impl InterruptController for Ioapic {
 
         Ok(())
     }
+
+    fn add_eoi_handler(&mut self, irq: usize, handler: EoiHandler) {
+        self.eoi_handlers[irq].push(handler);
+    }
+
+    fn remove_eoi_handler(&mut self, irq: usize, handler: &EoiHandler) {
+        self.eoi_handlers[irq].retain(|h| !Arc::ptr_eq(h, handler));
+    }
 }
 
 impl Snapshottable for Ioapic {
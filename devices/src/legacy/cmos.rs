@@ -6,7 +6,7 @@ use libc::{clock_gettime, gmtime_r, time_t, timespec, tm, CLOCK_REALTIME};
 use std::cmp::min;
 use std::mem;
 use std::sync::{Arc, Barrier};
-use vm_device::BusDevice;
+use vm_device::{BusDevice, RecordKind, Recorder};
 
 const INDEX_MASK: u8 = 0x7f;
 const INDEX_OFFSET: u64 = 0x0;
@@ -17,6 +17,7 @@ const DATA_LEN: usize = 128;
 pub struct Cmos {
     index: u8,
     data: [u8; DATA_LEN],
+    recorder: Option<Arc<Recorder>>,
 }
 
 impl Cmos {
@@ -40,7 +41,17 @@ impl Cmos {
         data[0x5c] = (high_mem >> 8) as u8;
         data[0x5d] = (high_mem >> 16) as u8;
 
-        Cmos { index: 0, data }
+        Cmos {
+            index: 0,
+            data,
+            recorder: None,
+        }
+    }
+
+    /// Record the register values read by the guest, as pairs of register
+    /// index and value.
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
     }
 }
 
@@ -121,6 +132,14 @@ impl BusDevice for Cmos {
                 }
             }
             o => panic!("bad read offset on CMOS device: {}", o),
+        };
+
+        if offset == DATA_OFFSET {
+            if let Some(recorder) = &self.recorder {
+                if let Err(e) = recorder.record("cmos", RecordKind::Rtc, &[self.index, data[0]]) {
+                    warn!("Failed recording CMOS read: {:?}", e);
+                }
+            }
         }
     }
 }
@@ -0,0 +1,361 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! CFI parallel NOR flash, compatible with the Intel command set expected by
+//! the EDK2 NorFlashDxe driver, which backs the UEFI variable store with a
+//! file so that the variables persist across boots.
+//!
+//! The flash is made of two 16 bits chips side by side, accessed 32 bits at
+//! a time, which is why the status and identification values are replicated
+//! into both halves of the bank.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+
+/// Size of the erase blocks, the length of the backing file must be a
+/// multiple of it.
+pub const FLASH_BLOCK_SIZE: u64 = 256 << 10;
+/// Width of the flash data bus, in bytes.
+pub const FLASH_BANK_WIDTH: u32 = 4;
+const CHIP_COUNT: u64 = 2;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ARRAY_ALT: u8 = 0x00;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_CFI_QUERY: u8 = 0x98;
+const CMD_LOCK_SETUP: u8 = 0x60;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_WORD_PROGRAM: u8 = 0x40;
+const CMD_WORD_PROGRAM_ALT: u8 = 0x10;
+const CMD_BUFFERED_PROGRAM: u8 = 0xe8;
+const CMD_CONFIRM: u8 = 0xd0;
+const CMD_LOCK_BLOCK: u8 = 0x01;
+
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+// Intel manufacturer ID, and ID of a P30 flash.
+const MANUFACTURER_ID: u8 = 0x89;
+const DEVICE_ID: u8 = 0x18;
+
+// Maximum number of words written at once by a buffered program.
+const WRITE_BUFFER_WORDS: usize = 32;
+
+enum Mode {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    CfiQuery,
+    LockSetup,
+    BlockErase,
+    WordProgram,
+    BufferedProgramCount,
+    BufferedProgramData(usize),
+    BufferedProgramConfirm,
+}
+
+pub struct CfiFlash {
+    file: File,
+    size: u64,
+    mode: Mode,
+    status: u8,
+    // Words of a buffered program, written to the file once confirmed.
+    write_buffer: Vec<(u64, Vec<u8>)>,
+}
+
+impl CfiFlash {
+    pub fn new(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        if size == 0 || size % FLASH_BLOCK_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Flash file size must be a non zero multiple of {} bytes",
+                    FLASH_BLOCK_SIZE
+                ),
+            ));
+        }
+
+        Ok(CfiFlash {
+            file,
+            size,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+            write_buffer: Vec::new(),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Query table, indexed by the offset divided by the bank width, as seen
+    // by each chip.
+    fn cfi_query(&self, index: u64) -> u8 {
+        let chip_size = self.size / CHIP_COUNT;
+        let chip_block_size = FLASH_BLOCK_SIZE / CHIP_COUNT;
+        let blocks = self.size / FLASH_BLOCK_SIZE - 1;
+        match index {
+            0x10 => b'Q',
+            0x11 => b'R',
+            0x12 => b'Y',
+            // Intel command set, with the extended table at 0x31.
+            0x13 => 0x01,
+            0x15 => 0x31,
+            // Vcc min and max voltages.
+            0x1b => 0x45,
+            0x1c => 0x55,
+            // Typical timeouts for a word program, a buffered program and a
+            // block erase, and multipliers for the maximum ones.
+            0x1f => 0x07,
+            0x20 => 0x07,
+            0x21 => 0x0a,
+            0x23 => 0x04,
+            0x24 => 0x04,
+            0x25 => 0x04,
+            0x27 => chip_size.next_power_of_two().trailing_zeros() as u8,
+            // x16 interface.
+            0x28 => 0x01,
+            0x2a => (WRITE_BUFFER_WORDS * 2).trailing_zeros() as u8,
+            // A single region of uniform erase blocks.
+            0x2c => 0x01,
+            0x2d => blocks as u8,
+            0x2e => (blocks >> 8) as u8,
+            0x2f => (chip_block_size >> 8) as u8,
+            0x30 => (chip_block_size >> 16) as u8,
+            0x31 => b'P',
+            0x32 => b'R',
+            0x33 => b'I',
+            0x34 => b'1',
+            0x35 => b'0',
+            _ => 0,
+        }
+    }
+
+    fn read_id(&self, offset: u64) -> u8 {
+        match (offset % FLASH_BLOCK_SIZE) / u64::from(FLASH_BANK_WIDTH) {
+            0 => MANUFACTURER_ID,
+            1 => DEVICE_ID,
+            // The blocks are never locked.
+            _ => 0,
+        }
+    }
+
+    // Programming a flash can only clear bits, setting them back requires
+    // erasing the whole block.
+    fn program(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut content = vec![0u8; data.len()];
+        self.file.read_exact_at(&mut content, offset)?;
+        for (c, d) in content.iter_mut().zip(data.iter()) {
+            *c &= *d;
+        }
+        self.file.write_all_at(&content, offset)
+    }
+
+    fn erase(&mut self, offset: u64) -> io::Result<()> {
+        let start = offset - offset % FLASH_BLOCK_SIZE;
+        self.file
+            .write_all_at(&vec![0xff; FLASH_BLOCK_SIZE as usize], start)
+    }
+
+    fn commit_write_buffer(&mut self) -> io::Result<()> {
+        for (offset, data) in std::mem::take(&mut self.write_buffer) {
+            self.program(offset, &data)?;
+        }
+        Ok(())
+    }
+
+    fn handle_command(&mut self, offset: u64, data: &[u8]) {
+        let command = data[0];
+        self.mode = match command {
+            CMD_READ_ARRAY | CMD_READ_ARRAY_ALT => Mode::ReadArray,
+            CMD_READ_STATUS => Mode::ReadStatus,
+            CMD_CLEAR_STATUS => {
+                self.status = STATUS_READY;
+                Mode::ReadArray
+            }
+            CMD_READ_ID => Mode::ReadId,
+            CMD_CFI_QUERY => Mode::CfiQuery,
+            CMD_LOCK_SETUP => Mode::LockSetup,
+            CMD_BLOCK_ERASE => Mode::BlockErase,
+            CMD_WORD_PROGRAM | CMD_WORD_PROGRAM_ALT => Mode::WordProgram,
+            CMD_BUFFERED_PROGRAM => {
+                self.write_buffer.clear();
+                Mode::BufferedProgramCount
+            }
+            _ => {
+                warn!(
+                    "Unsupported flash command {:#x} at offset {:#x}",
+                    command, offset
+                );
+                Mode::ReadArray
+            }
+        };
+    }
+
+    fn reply(&self, value: u8, data: &mut [u8]) {
+        let value = u32::from(value) | u32::from(value) << 16;
+        for (b, v) in data.iter_mut().zip(value.to_le_bytes().iter()) {
+            *b = *v;
+        }
+    }
+}
+
+impl BusDevice for CfiFlash {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match self.mode {
+            Mode::ReadArray => {
+                for b in data.iter_mut() {
+                    *b = 0xff;
+                }
+                if offset + data.len() as u64 <= self.size {
+                    if let Err(e) = self.file.read_exact_at(data, offset) {
+                        error!("Failed reading the flash file: {}", e);
+                    }
+                }
+            }
+            Mode::ReadId => self.reply(self.read_id(offset), data),
+            Mode::CfiQuery => {
+                self.reply(self.cfi_query(offset / u64::from(FLASH_BANK_WIDTH)), data)
+            }
+            // The status is returned while a command is in progress, and
+            // the operations are always completed immediately.
+            _ => self.reply(self.status, data),
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if data.is_empty() || offset + data.len() as u64 > self.size {
+            warn!(
+                "Invalid flash write: offset {:#x} length {}",
+                offset,
+                data.len()
+            );
+            return None;
+        }
+
+        match self.mode {
+            Mode::LockSetup => {
+                if data[0] != CMD_CONFIRM && data[0] != CMD_LOCK_BLOCK {
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_ERASE_ERROR;
+                }
+                self.mode = Mode::ReadStatus;
+            }
+            Mode::BlockErase => {
+                if data[0] != CMD_CONFIRM {
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_ERASE_ERROR;
+                } else if let Err(e) = self.erase(offset) {
+                    error!("Failed erasing the flash block: {}", e);
+                    self.status |= STATUS_ERASE_ERROR;
+                }
+                self.mode = Mode::ReadStatus;
+            }
+            Mode::WordProgram => {
+                if let Err(e) = self.program(offset, data) {
+                    error!("Failed programming the flash: {}", e);
+                    self.status |= STATUS_PROGRAM_ERROR;
+                }
+                self.mode = Mode::ReadStatus;
+            }
+            Mode::BufferedProgramCount => {
+                let count = data[0] as usize + 1;
+                if count > WRITE_BUFFER_WORDS {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                    self.mode = Mode::ReadStatus;
+                } else {
+                    self.mode = Mode::BufferedProgramData(count);
+                }
+            }
+            Mode::BufferedProgramData(remaining) => {
+                self.write_buffer.push((offset, data.to_vec()));
+                self.mode = if remaining > 1 {
+                    Mode::BufferedProgramData(remaining - 1)
+                } else {
+                    Mode::BufferedProgramConfirm
+                };
+            }
+            Mode::BufferedProgramConfirm => {
+                if data[0] != CMD_CONFIRM {
+                    self.write_buffer.clear();
+                    self.status |= STATUS_PROGRAM_ERROR | STATUS_ERASE_ERROR;
+                } else if let Err(e) = self.commit_write_buffer() {
+                    error!("Failed programming the flash: {}", e);
+                    self.status |= STATUS_PROGRAM_ERROR;
+                }
+                self.mode = Mode::ReadStatus;
+            }
+            _ => self.handle_command(offset, data),
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(flash: &mut CfiFlash, offset: u64, command: u8) {
+        let value = u32::from(command) | u32::from(command) << 16;
+        flash.write(0, offset, &value.to_le_bytes());
+    }
+
+    fn read(flash: &mut CfiFlash, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        flash.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_cfi_flash() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(FLASH_BLOCK_SIZE * 3 + 1).unwrap();
+        assert!(CfiFlash::new(file.try_clone().unwrap()).is_err());
+        file.set_len(FLASH_BLOCK_SIZE * 3).unwrap();
+        let mut flash = CfiFlash::new(file.try_clone().unwrap()).unwrap();
+
+        command(&mut flash, 0, CMD_CFI_QUERY);
+        assert_eq!(read(&mut flash, 0x10 * 4), 0x0051_0051);
+        assert_eq!(read(&mut flash, 0x2d * 4), 0x0002_0002);
+        command(&mut flash, 0, CMD_READ_ID);
+        assert_eq!(read(&mut flash, 0), 0x0089_0089);
+        assert_eq!(read(&mut flash, 4), 0x0018_0018);
+
+        // Erase the second block, and program a word into it.
+        let offset = FLASH_BLOCK_SIZE + 8;
+        command(&mut flash, offset, CMD_BLOCK_ERASE);
+        command(&mut flash, offset, CMD_CONFIRM);
+        assert_eq!(read(&mut flash, offset), 0x0080_0080);
+        command(&mut flash, offset, CMD_WORD_PROGRAM);
+        flash.write(0, offset, &0x1234_5678u32.to_le_bytes());
+        command(&mut flash, 0, CMD_READ_ARRAY);
+        assert_eq!(read(&mut flash, offset), 0x1234_5678);
+        assert_eq!(read(&mut flash, offset + 4), 0xffff_ffff);
+        // The first block wasn't erased.
+        assert_eq!(read(&mut flash, 0), 0);
+
+        // Buffered program of two words, only clearing bits.
+        command(&mut flash, offset, CMD_BUFFERED_PROGRAM);
+        command(&mut flash, offset, 1);
+        flash.write(0, offset, &0xffff_0000u32.to_le_bytes());
+        flash.write(0, offset + 4, &0xaaaa_aaaau32.to_le_bytes());
+        command(&mut flash, offset, CMD_CONFIRM);
+        assert_eq!(read(&mut flash, offset), 0x0080_0080);
+        command(&mut flash, 0, CMD_READ_ARRAY);
+        assert_eq!(read(&mut flash, offset), 0x1234_0000);
+        assert_eq!(read(&mut flash, offset + 4), 0xaaaa_aaaa);
+
+        // The content is stored in the file.
+        let mut content = [0u8; 4];
+        file.read_exact_at(&mut content, offset + 4).unwrap();
+        assert_eq!(u32::from_le_bytes(content), 0xaaaa_aaaa);
+    }
+}
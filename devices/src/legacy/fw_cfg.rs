@@ -0,0 +1,161 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Firmware configuration device, compatible with the QEMU fw_cfg interface
+//! through I/O ports, which lets the firmware read some configuration items
+//! and files provided by the VMM, such as the boot order.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_ID: u16 = 0x01;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_FILE_FIRST: u16 = 0x20;
+
+const FW_CFG_SIGNATURE_VALUE: &[u8; 4] = b"QEMU";
+// Only the traditional interface is supported, not the DMA one.
+const FW_CFG_VERSION_TRADITIONAL: u32 = 1;
+const FW_CFG_FILE_NAME_LEN: usize = 56;
+
+// Offsets of the registers from the base I/O port.
+const SELECTOR_OFFSET: u64 = 0;
+const DATA_OFFSET: u64 = 1;
+
+/// Exposes items to the firmware, each of them being selected through the
+/// 16 bits selector register, and then read byte per byte from the data
+/// register.
+pub struct FwCfg {
+    items: BTreeMap<u16, Vec<u8>>,
+    files: Vec<String>,
+    selector: u16,
+    offset: usize,
+}
+
+impl Default for FwCfg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FwCfg {
+    pub fn new() -> Self {
+        let mut fw_cfg = FwCfg {
+            items: BTreeMap::new(),
+            files: Vec::new(),
+            selector: FW_CFG_SIGNATURE,
+            offset: 0,
+        };
+        fw_cfg
+            .items
+            .insert(FW_CFG_SIGNATURE, FW_CFG_SIGNATURE_VALUE.to_vec());
+        fw_cfg
+            .items
+            .insert(FW_CFG_ID, FW_CFG_VERSION_TRADITIONAL.to_le_bytes().to_vec());
+        fw_cfg.update_file_dir();
+
+        fw_cfg
+    }
+
+    /// Expose `data` to the firmware as the file named `name`.
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) {
+        let key = FW_CFG_FILE_FIRST + self.files.len() as u16;
+        self.files.push(name.to_string());
+        self.items.insert(key, data);
+        self.update_file_dir();
+    }
+
+    // The directory starts with the number of files, followed by an entry
+    // for each of them: {size: u32, select: u16, reserved: u16, name: [u8; 56]},
+    // all the integers being big endian.
+    fn update_file_dir(&mut self) {
+        let mut dir = (self.files.len() as u32).to_be_bytes().to_vec();
+        for (index, name) in self.files.iter().enumerate() {
+            let key = FW_CFG_FILE_FIRST + index as u16;
+            dir.extend_from_slice(&(self.items[&key].len() as u32).to_be_bytes());
+            dir.extend_from_slice(&key.to_be_bytes());
+            dir.extend_from_slice(&[0u8; 2]);
+
+            // The name must be NUL terminated.
+            let mut file_name = [0u8; FW_CFG_FILE_NAME_LEN];
+            let len = std::cmp::min(name.len(), FW_CFG_FILE_NAME_LEN - 1);
+            file_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+            dir.extend_from_slice(&file_name);
+        }
+        self.items.insert(FW_CFG_FILE_DIR, dir);
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset != DATA_OFFSET {
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+            return;
+        }
+
+        // Reading past the end of the item, or an unknown item, returns 0.
+        let item = self.items.get(&self.selector);
+        for b in data.iter_mut() {
+            *b = item
+                .and_then(|item| item.get(self.offset))
+                .copied()
+                .unwrap_or(0);
+            self.offset += 1;
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset == SELECTOR_OFFSET && data.len() == 2 {
+            self.selector = u16::from_le_bytes([data[0], data[1]]);
+            self.offset = 0;
+        } else {
+            warn!(
+                "Invalid fw_cfg write: offset {} length {}",
+                offset,
+                data.len()
+            );
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_item(fw_cfg: &mut FwCfg, key: u16, len: usize) -> Vec<u8> {
+        fw_cfg.write(0, SELECTOR_OFFSET, &key.to_le_bytes());
+        let mut data = vec![0u8; len];
+        for b in data.iter_mut() {
+            fw_cfg.read(0, DATA_OFFSET, std::slice::from_mut(b));
+        }
+        data
+    }
+
+    #[test]
+    fn test_fw_cfg() {
+        let mut fw_cfg = FwCfg::new();
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_SIGNATURE, 4), b"QEMU");
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_ID, 4), vec![1, 0, 0, 0]);
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_FILE_DIR, 4), vec![0; 4]);
+
+        fw_cfg.add_file("bootorder", b"/pci@i0cf8/scsi@2/disk@0,0\0".to_vec());
+        let dir = read_item(&mut fw_cfg, FW_CFG_FILE_DIR, 4 + 64);
+        assert_eq!(&dir[..4], &[0, 0, 0, 1]);
+        assert_eq!(&dir[4..8], &[0, 0, 0, 27]);
+        assert_eq!(&dir[8..10], &[0, 0x20]);
+        assert_eq!(&dir[12..22], b"bootorder\0");
+
+        // Reading past the end of the file, or an unknown item, returns 0.
+        let bootorder = read_item(&mut fw_cfg, 0x20, 30);
+        assert_eq!(&bootorder[..27], b"/pci@i0cf8/scsi@2/disk@0,0\0");
+        assert_eq!(&bootorder[27..], &[0; 3]);
+        assert_eq!(read_item(&mut fw_cfg, 0x21, 2), vec![0; 2]);
+    }
+}
@@ -7,6 +7,9 @@
 
 #[cfg(feature = "cmos")]
 mod cmos;
+#[cfg(target_arch = "aarch64")]
+mod flash;
+mod fw_cfg;
 #[cfg(feature = "fwdebug")]
 mod fwdebug;
 mod i8042;
@@ -16,10 +19,13 @@ mod serial;
 
 #[cfg(feature = "cmos")]
 pub use self::cmos::Cmos;
+pub use self::fw_cfg::FwCfg;
 #[cfg(feature = "fwdebug")]
 pub use self::fwdebug::FwDebugDevice;
 pub use self::i8042::I8042Device;
 pub use self::serial::Serial;
 
+#[cfg(target_arch = "aarch64")]
+pub use self::flash::{CfiFlash, FLASH_BLOCK_SIZE};
 #[cfg(target_arch = "aarch64")]
 pub use self::rtc_pl031::RTC;
@@ -13,7 +13,7 @@ use std::sync::{Arc, Barrier};
 use std::time::Instant;
 use std::{io, result};
 use vm_device::interrupt::InterruptSourceGroup;
-use vm_device::BusDevice;
+use vm_device::{BusDevice, RecordKind, Recorder};
 
 // As you can see in https://static.docs.arm.com/ddi0224/c/real_time_clock_pl031_r1p3_technical_reference_manual_DDI0224C.pdf
 // at section 3.2 Summary of RTC registers, the total size occupied by this device is 0x000 -> 0xFFC + 4 = 0x1000.
@@ -270,6 +270,7 @@ pub struct RTC {
     imsc: u32,
     ris: u32,
     interrupt: Arc<Box<dyn InterruptSourceGroup>>,
+    recorder: Option<Arc<Recorder>>,
 }
 
 impl RTC {
@@ -284,9 +285,15 @@ impl RTC {
             imsc: 0,
             ris: 0,
             interrupt,
+            recorder: None,
         }
     }
 
+    /// Record the time read by the guest from the data register.
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
     fn trigger_interrupt(&mut self) -> Result<()> {
         self.interrupt.trigger(0).map_err(Error::InterruptFailure)?;
         Ok(())
@@ -362,6 +369,13 @@ impl BusDevice for RTC {
         }
         if read_ok && data.len() <= 4 {
             write_le_u32(data, v);
+            if offset == RTCDR {
+                if let Some(recorder) = &self.recorder {
+                    if let Err(e) = recorder.record("rtc", RecordKind::Rtc, &v.to_le_bytes()) {
+                        warn!("Failed recording RTC read: {:?}", e);
+                    }
+                }
+            }
         } else {
             warn!(
                 "Invalid RTC PL031 read: offset {}, data length {}",
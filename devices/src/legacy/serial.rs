@@ -202,8 +202,12 @@ impl Serial {
                     }
                 } else {
                     if let Some(out) = self.out.as_mut() {
-                        out.write_all(&[v])?;
-                        out.flush()?;
+                        // Failing to write the output, for instance when
+                        // nobody reads from the other end of a pty, must not
+                        // prevent the guest from sending more characters.
+                        if let Err(e) = out.write_all(&[v]).and_then(|_| out.flush()) {
+                            debug!("Failed writing serial output: {}", e);
+                        }
                     }
                     self.thr_empty()?;
                 }
@@ -35,7 +35,9 @@ pub mod ioapic;
 pub mod legacy;
 
 #[cfg(feature = "acpi")]
-pub use self::acpi::{AcpiGEDDevice, AcpiPMTimerDevice, AcpiShutdownDevice};
+pub use self::acpi::{
+    AcpiGEDDevice, AcpiPMTimerDevice, AcpiShutdownDevice, GED_DEVICE_ADDRESS, GED_DEVICE_SIZE,
+};
 
 bitflags! {
     pub struct HotPlugNotificationFlags: u8 {
@@ -43,5 +45,7 @@ bitflags! {
         const CPU_DEVICES_CHANGED = 0b1;
         const MEMORY_DEVICES_CHANGED = 0b10;
         const PCI_DEVICES_CHANGED = 0b100;
+        const POWER_BUTTON_CHANGED = 0b1000;
+        const GENERATION_ID_CHANGED = 0b10000;
     }
 }
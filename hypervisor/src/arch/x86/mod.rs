@@ -39,6 +39,21 @@ pub const MTRR_MEM_TYPE_WB: u64 = 0x6;
 // IOAPIC pins
 pub const NUM_IOAPIC_PINS: usize = 24;
 
+// Machine check architecture constants
+pub const MCE_BANKS: u64 = 10; // Number of error reporting banks
+pub const MCG_CTL_P: u64 = 1 << 8; // IA32_MCG_CAP: IA32_MCG_CTL present
+pub const MCG_SER_P: u64 = 1 << 24; // IA32_MCG_CAP: Software error recovery supported
+pub const MCG_STATUS_RIPV: u64 = 1 << 0; // IA32_MCG_STATUS: Restart IP valid
+pub const MCG_STATUS_EIPV: u64 = 1 << 1; // IA32_MCG_STATUS: Error IP valid
+pub const MCG_STATUS_MCIP: u64 = 1 << 2; // IA32_MCG_STATUS: Machine check in progress
+pub const MCI_STATUS_VAL: u64 = 1 << 63; // IA32_MCi_STATUS: Valid
+pub const MCI_STATUS_UC: u64 = 1 << 61; // IA32_MCi_STATUS: Uncorrected error
+pub const MCI_STATUS_EN: u64 = 1 << 60; // IA32_MCi_STATUS: Error enabled
+pub const MCI_STATUS_MISCV: u64 = 1 << 59; // IA32_MCi_STATUS: IA32_MCi_MISC valid
+pub const MCI_STATUS_ADDRV: u64 = 1 << 58; // IA32_MCi_STATUS: IA32_MCi_ADDR valid
+pub const MCI_STATUS_S: u64 = 1 << 56; // IA32_MCi_STATUS: Signaled through #MC
+pub const MCI_STATUS_AR: u64 = 1 << 55; // IA32_MCi_STATUS: Action required
+
 // X86 Exceptions
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
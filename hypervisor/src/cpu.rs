@@ -19,6 +19,8 @@ use crate::x86_64::{
     ExtendedControlRegisters, FpuState, MsrEntries, SpecialRegisters, StandardRegisters, VcpuEvents,
 };
 use crate::CpuState;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use crate::MachineCheckEvent;
 #[cfg(feature = "kvm")]
 use crate::MpState;
 #[cfg(target_arch = "x86_64")]
@@ -155,6 +157,16 @@ pub enum HypervisorCpuError {
     #[error("Failed to notify guest its clock was paused: {0}")]
     NotifyGuestClockPaused(#[source] anyhow::Error),
     ///
+    /// Setting up machine check banks error
+    ///
+    #[error("Failed to set up machine check banks: {0}")]
+    SetupMce(#[source] anyhow::Error),
+    ///
+    /// Injecting machine check error
+    ///
+    #[error("Failed to inject machine check: {0}")]
+    InjectMce(#[source] anyhow::Error),
+    ///
     /// Setting debug register error
     ///
     #[error("Failed to set debug registers: {0}")]
@@ -213,6 +225,8 @@ pub enum VmExit<'a> {
     Ignore,
     Reset,
     Shutdown,
+    /// The guest hit an unrecoverable condition, such as a triple fault.
+    Crash,
     Hyperv,
 }
 
@@ -338,6 +352,17 @@ pub trait Vcpu: Send + Sync {
     /// potential soft lockups when being resumed.
     ///
     fn notify_guest_clock_paused(&self) -> Result<()>;
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    ///
+    /// Expose the machine check banks to the guest, along with the support
+    /// for recovering from uncorrected errors.
+    ///
+    fn setup_mce(&self) -> Result<()>;
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    ///
+    /// Inject a machine check into the guest, reported by one of its banks.
+    ///
+    fn inject_mce(&self, mce: &MachineCheckEvent) -> Result<()>;
     ///
     /// Sets the type of CPU to be exposed to the guest and optional features.
     ///
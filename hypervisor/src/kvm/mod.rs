@@ -21,6 +21,7 @@ use crate::vm::{self, VmmOps};
 use crate::{arm64_core_reg_id, offset__of};
 use kvm_ioctls::{NoDatamatch, VcpuFd, VmFd};
 use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 #[cfg(target_arch = "x86_64")]
@@ -43,17 +44,28 @@ use aarch64::{RegList, Register, StandardRegisters};
 
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
-    CpuId, CpuIdEntry, ExtendedControlRegisters, LapicState, MsrEntries, VcpuKvmState as CpuState,
-    Xsave, CPUID_FLAG_VALID_INDEX,
+    CpuId, CpuIdEntry, ExtendedControlRegisters, LapicState, MachineCheckEvent, MsrEntries,
+    VcpuKvmState as CpuState, Xsave, CPUID_FLAG_VALID_INDEX,
 };
 
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
     kvm_enable_cap, kvm_msr_entry, MsrList, KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP,
+    KVM_CAP_X2APIC_API, KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK, KVM_X2APIC_API_USE_32BIT_IDS,
 };
 
 #[cfg(target_arch = "x86_64")]
-use crate::arch::x86::NUM_IOAPIC_PINS;
+use crate::arch::x86::{MCE_BANKS, MCG_CTL_P, MCG_SER_P, NUM_IOAPIC_PINS};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{kvm_x86_mce, KVMIO};
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::{errno, ioctl::ioctl_with_ref, ioctl_ioc_nr, ioctl_iow_nr};
+
+// Machine check ioctls, not wrapped by kvm-ioctls.
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_X86_SETUP_MCE, KVMIO, 0x9c, u64);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_X86_SET_MCE, KVMIO, 0x9e, kvm_x86_mce);
 
 // aarch64 dependencies
 #[cfg(target_arch = "aarch64")]
@@ -177,9 +189,13 @@ impl vm::Vm for KvmVm {
     ///
     fn create_vcpu(
         &self,
-        id: u8,
+        id: u16,
         vmmops: Option<Arc<Box<dyn VmmOps>>>,
     ) -> vm::Result<Arc<dyn cpu::Vcpu>> {
+        // The KVM bindings only take 8 bits vCPU ids.
+        let id = u8::try_from(id).map_err(|_| {
+            vm::HypervisorVmError::CreateVcpu(anyhow!("vCPU id {} not supported", id))
+        })?;
         let vc = self
             .fd
             .create_vcpu(id)
@@ -322,6 +338,18 @@ impl vm::Vm for KvmVm {
             .map_err(|e| vm::HypervisorVmError::EnableSplitIrq(e.into()))?;
         Ok(())
     }
+    #[cfg(target_arch = "x86_64")]
+    fn enable_x2apic_api(&self) -> vm::Result<()> {
+        // Let the local APICs use 32 bits x2APIC IDs, and the MSI routes
+        // carry the destination ID bits 8-31 in the upper address.
+        let mut cap: kvm_enable_cap = Default::default();
+        cap.cap = KVM_CAP_X2APIC_API;
+        cap.args[0] =
+            (KVM_X2APIC_API_USE_32BIT_IDS | KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK) as u64;
+        self.fd
+            .enable_cap(&cap)
+            .map_err(|e| vm::HypervisorVmError::EnableX2ApicApi(e.into()))
+    }
     /// Retrieve guest clock.
     #[cfg(target_arch = "x86_64")]
     fn get_clock(&self) -> vm::Result<ClockData> {
@@ -739,7 +767,9 @@ impl cpu::Vcpu for KvmVcpu {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
                 #[cfg(target_arch = "x86_64")]
-                VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Shutdown => Ok(cpu::VmExit::Crash),
 
                 #[cfg(target_arch = "aarch64")]
                 VcpuExit::SystemEvent(event_type, flags) => {
@@ -826,6 +856,40 @@ impl cpu::Vcpu for KvmVcpu {
             .kvmclock_ctrl()
             .map_err(|e| cpu::HypervisorCpuError::NotifyGuestClockPaused(e.into()))
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Expose the machine check banks to the guest, along with the support
+    /// for recovering from uncorrected errors.
+    ///
+    fn setup_mce(&self) -> cpu::Result<()> {
+        let mcg_cap: u64 = MCE_BANKS | MCG_CTL_P | MCG_SER_P;
+        // Safe because we know that our file is a vCPU fd, we know the kernel
+        // will only read the correct amount of memory from our pointer, and
+        // we verify the return result.
+        let ret = unsafe { ioctl_with_ref(&self.fd, KVM_X86_SETUP_MCE(), &mcg_cap) };
+        if ret < 0 {
+            return Err(cpu::HypervisorCpuError::SetupMce(
+                errno::Error::last().into(),
+            ));
+        }
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Inject a machine check into the guest, reported by one of its banks.
+    ///
+    fn inject_mce(&self, mce: &MachineCheckEvent) -> cpu::Result<()> {
+        // Safe because we know that our file is a vCPU fd, we know the kernel
+        // will only read the correct amount of memory from our pointer, and
+        // we verify the return result.
+        let ret = unsafe { ioctl_with_ref(&self.fd, KVM_X86_SET_MCE(), mce) };
+        if ret < 0 {
+            return Err(cpu::HypervisorCpuError::InjectMce(
+                errno::Error::last().into(),
+            ));
+        }
+        Ok(())
+    }
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     fn vcpu_init(&self, kvi: &VcpuInit) -> cpu::Result<()> {
         self.fd
@@ -26,6 +26,7 @@ pub use x86_64::VcpuMshvState as CpuState;
 pub use x86_64::*;
 // Wei: for emulating irqfd and ioeventfd
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
@@ -925,9 +926,12 @@ impl vm::Vm for MshvVm {
     ///
     fn create_vcpu(
         &self,
-        id: u8,
+        id: u16,
         vmmops: Option<Arc<Box<dyn VmmOps>>>,
     ) -> vm::Result<Arc<dyn cpu::Vcpu>> {
+        let id = u8::try_from(id).map_err(|_| {
+            vm::HypervisorVmError::CreateVcpu(anyhow!("vCPU id {} not supported", id))
+        })?;
         let vcpu_fd = self
             .fd
             .create_vcpu(id)
@@ -948,6 +952,10 @@ impl vm::Vm for MshvVm {
     fn enable_split_irq(&self) -> vm::Result<()> {
         Ok(())
     }
+    #[cfg(target_arch = "x86_64")]
+    fn enable_x2apic_api(&self) -> vm::Result<()> {
+        Ok(())
+    }
     fn register_ioevent(
         &self,
         fd: &EventFd,
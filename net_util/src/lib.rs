@@ -17,6 +17,7 @@ extern crate net_gen;
 extern crate rand;
 extern crate serde;
 extern crate virtio_bindings;
+extern crate vm_device;
 extern crate vm_memory;
 extern crate vm_virtio;
 extern crate vmm_sys_util;
@@ -11,7 +11,11 @@ use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_device::{RecordKind, Recorder};
+use vm_memory::{
+    Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryMmap,
+    GuestMemoryRegion,
+};
 use vm_virtio::{DescriptorChain, Queue};
 
 /// The maximum buffer size when segmentation offload is enabled. This
@@ -19,6 +23,27 @@ use vm_virtio::{DescriptorChain, Queue};
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
 
+// Bounds on the number of frames read from the TAP per wakeup.
+const MIN_RX_FRAME_BUDGET: usize = 16;
+const MAX_RX_FRAME_BUDGET: usize = 256;
+
+// Returns the host buffer backing the guest buffer at `addr`, or None if the
+// guest buffer is not entirely contained in a single guest memory region, in
+// which case it can't be accessed through a single host pointer.
+fn host_iovec(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) -> Option<libc::iovec> {
+    let region = mem.find_region(addr)?;
+    let offset = addr.0 - region.start_addr().0;
+    if offset.checked_add(len as u64)? > region.len() {
+        return None;
+    }
+    let host_addr = mem.get_host_address(addr).ok()?;
+
+    Some(libc::iovec {
+        iov_base: host_addr as *mut libc::c_void,
+        iov_len: len,
+    })
+}
+
 #[derive(Clone)]
 pub struct TxVirtio {
     pub iovec: Vec<(GuestAddress, usize)>,
@@ -43,10 +68,48 @@ impl TxVirtio {
         }
     }
 
+    // Copies the frame described by `self.iovec` into `self.frame_buf`, returning
+    // the number of bytes copied.
+    fn copy_frame(&mut self, mem: &GuestMemoryMmap) -> usize {
+        let mut read_count = 0;
+        for (desc_addr, desc_len) in self.iovec.iter() {
+            let limit = cmp::min(read_count + desc_len, self.frame_buf.len());
+
+            match mem.read_slice(&mut self.frame_buf[read_count..limit], *desc_addr) {
+                Ok(_) => {
+                    // Increment by number of bytes actually read
+                    read_count = limit;
+                }
+                Err(e) => {
+                    error!("Failed to read slice: {:?}", e);
+                    break;
+                }
+            }
+        }
+        read_count
+    }
+
+    // Builds the list of host buffers backing the frame described by
+    // `self.iovec`, so that the frame can be written to the TAP straight from
+    // the guest memory. Returns None if any buffer can't be mapped onto a host
+    // buffer.
+    fn frame_iovecs(&self, mem: &GuestMemoryMmap) -> Option<(Vec<libc::iovec>, usize)> {
+        let mut iovecs = Vec::with_capacity(self.iovec.len());
+        let mut read_count = 0;
+        for (desc_addr, desc_len) in self.iovec.iter() {
+            let len = cmp::min(*desc_len, MAX_BUFFER_SIZE - read_count);
+            if len == 0 {
+                break;
+            }
+            iovecs.push(host_iovec(mem, *desc_addr, len)?);
+            read_count += len;
+        }
+        Some((iovecs, read_count))
+    }
+
     pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
         while let Some(avail_desc) = queue.iter(&mem).next() {
             let head_index = avail_desc.index;
-            let mut read_count = 0;
             let mut next_desc = Some(avail_desc);
 
             self.iovec.clear();
@@ -55,40 +118,44 @@ impl TxVirtio {
                     break;
                 }
                 self.iovec.push((desc.addr, desc.len as usize));
-                read_count += desc.len as usize;
                 next_desc = desc.next_descriptor();
             }
 
-            read_count = 0;
-            // Copy buffer from across multiple descriptors.
-            // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
-            // and get rid of the intermediate buffer.
-            for (desc_addr, desc_len) in self.iovec.drain(..) {
-                let limit = cmp::min((read_count + desc_len) as usize, self.frame_buf.len());
-
-                let read_result =
-                    mem.read_slice(&mut self.frame_buf[read_count..limit as usize], desc_addr);
-                match read_result {
-                    Ok(_) => {
-                        // Increment by number of bytes actually read
-                        read_count += limit - read_count;
-                    }
-                    Err(e) => {
-                        println!("Failed to read slice: {:?}", e);
-                        break;
-                    }
+            // Write the frame directly from the guest memory, avoiding the
+            // copy through the intermediate buffer. Fall back onto the copy
+            // if the descriptors can't be safely mapped onto host buffers.
+            let (write_result, read_count) = match self.frame_iovecs(mem) {
+                Some((iovecs, read_count)) => {
+                    // Safe because the host buffers have been checked to be
+                    // part of the guest memory, which stays mapped while the
+                    // descriptors are being processed.
+                    let ret = unsafe {
+                        libc::writev(
+                            tap.as_raw_fd(),
+                            iovecs.as_ptr(),
+                            iovecs.len() as libc::c_int,
+                        )
+                    };
+                    let result = if ret < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(())
+                    };
+                    (result, read_count)
                 }
-            }
-
-            let write_result = tap.write(&self.frame_buf[..read_count]);
-            match write_result {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("net: tx: error failed to write to tap: {}", e);
+                None => {
+                    let read_count = self.copy_frame(mem);
+                    (
+                        tap.write(&self.frame_buf[..read_count]).map(|_| ()),
+                        read_count,
+                    )
                 }
             };
+            if let Err(e) = write_result {
+                error!("net: tx: error failed to write to tap: {}", e);
+            }
 
-            self.counter_bytes += Wrapping((read_count - vnet_hdr_len()) as u64);
+            self.counter_bytes += Wrapping(read_count.saturating_sub(vnet_hdr_len()) as u64);
             self.counter_frames += Wrapping(1);
 
             queue.add_used(&mem, head_index, 0);
@@ -105,6 +172,7 @@ pub struct RxVirtio {
     pub frame_buf: [u8; MAX_BUFFER_SIZE],
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    pub frame_budget: usize,
 }
 
 impl Default for RxVirtio {
@@ -122,6 +190,18 @@ impl RxVirtio {
             frame_buf: [0u8; MAX_BUFFER_SIZE],
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            frame_budget: MIN_RX_FRAME_BUDGET,
+        }
+    }
+
+    // Adapt the number of frames read per wakeup to the traffic: the budget
+    // grows while it gets entirely consumed, and shrinks back when the TAP
+    // drains before reaching half of it.
+    fn update_frame_budget(&mut self, frames: usize) {
+        if frames >= self.frame_budget {
+            self.frame_budget = cmp::min(self.frame_budget * 2, MAX_RX_FRAME_BUDGET);
+        } else if frames < self.frame_budget / 2 {
+            self.frame_budget = cmp::max(self.frame_budget / 2, MIN_RX_FRAME_BUDGET);
         }
     }
 
@@ -217,6 +297,9 @@ pub struct NetQueuePair {
     pub rx_tap_listening: bool,
     pub counters: NetCounters,
     pub tap_event_id: u16,
+    /// Records the frames received from the TAP, along with the identifier
+    /// of the device they are provided to.
+    pub recorder: Option<(String, Arc<Recorder>)>,
 }
 
 impl NetQueuePair {
@@ -250,13 +333,113 @@ impl NetQueuePair {
         Ok(self.rx.process_desc_chain(&mem, next_desc, &mut queue))
     }
 
+    fn record_frame(&self, frame: &[u8]) {
+        if let Some((id, recorder)) = &self.recorder {
+            if let Err(e) = recorder.record(id, RecordKind::NetRx, frame) {
+                warn!("Failed recording received frame: {:?}", e);
+            }
+        }
+    }
+
+    // Reads a single frame from the TAP straight into the next buffer made
+    // available by the driver, avoiding the copy through `self.rx.frame_buf`.
+    // Returns None if no buffer is available, or if the buffer can't hold a
+    // frame of maximum size or can't be mapped onto host buffers, in which
+    // case the frame must go through the intermediate buffer.
+    fn rx_direct(&mut self, mem: &GuestMemoryMmap, queue: &mut Queue) -> Option<io::Result<()>> {
+        let head = queue.iter(mem).next()?;
+        let head_index = head.index;
+
+        let mut iovecs = Vec::new();
+        let mut capacity = 0;
+        let mut next_desc = Some(head);
+        while let Some(desc) = next_desc {
+            if !desc.is_write_only() {
+                break;
+            }
+            match host_iovec(mem, desc.addr, desc.len as usize) {
+                Some(iovec) => iovecs.push(iovec),
+                None => {
+                    capacity = 0;
+                    break;
+                }
+            }
+            capacity += desc.len as usize;
+            next_desc = desc.next_descriptor();
+        }
+
+        if capacity < MAX_BUFFER_SIZE {
+            queue.go_to_previous_position();
+            return None;
+        }
+
+        // Safe because the host buffers have been checked to be part of the
+        // guest memory, which stays mapped while the descriptors are being
+        // processed.
+        let ret = unsafe {
+            libc::readv(
+                self.tap.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+            )
+        };
+        if ret < 0 {
+            queue.go_to_previous_position();
+            return Some(Err(io::Error::last_os_error()));
+        }
+        let count = ret as usize;
+
+        if self.recorder.is_some() {
+            let mut frame = Vec::with_capacity(count);
+            for iovec in iovecs.iter() {
+                let len = cmp::min(iovec.iov_len, count - frame.len());
+                // Safe because the host buffer has been validated above.
+                frame.extend_from_slice(unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, len)
+                });
+            }
+            self.record_frame(&frame);
+        }
+
+        self.rx.counter_bytes += Wrapping(count.saturating_sub(vnet_hdr_len()) as u64);
+        self.rx.counter_frames += Wrapping(1);
+
+        queue.add_used(mem, head_index, count as u32);
+        queue.update_avail_event(mem);
+
+        // Mark that we have at least one pending packet and we need to interrupt the guest.
+        self.rx.deferred_irqs = true;
+
+        Some(Ok(()))
+    }
+
     fn process_rx(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
-        // Read as many frames as possible.
-        loop {
-            match self.read_tap() {
-                Ok(count) => {
+        let mem = self
+            .mem
+            .as_ref()
+            .ok_or(NetQueuePairError::NoMemoryConfigured)
+            .map(|m| m.memory())?;
+
+        // Read up to the budget of frames. The TAP being level triggered,
+        // remaining frames are read on the next wakeup, giving a chance to
+        // the other events to be processed in between.
+        let mut frames = 0;
+        while frames < self.rx.frame_budget {
+            // Whether the frame has been read into the intermediate buffer,
+            // and still needs to be copied into the guest.
+            let result = match self.rx_direct(&mem, queue) {
+                Some(result) => result.map(|_| false),
+                None => self.read_tap().map(|count| {
+                    self.record_frame(&self.rx.frame_buf[..count]);
                     self.rx.bytes_read = count;
-                    if !self.rx_single_frame(queue)? {
+                    true
+                }),
+            };
+
+            match result {
+                Ok(copy_needed) => {
+                    frames += 1;
+                    if copy_needed && !self.rx_single_frame(queue)? {
                         self.rx.deferred_frame = true;
                         break;
                     }
@@ -275,6 +458,7 @@ impl NetQueuePair {
                 }
             }
         }
+        self.rx.update_frame_budget(frames);
 
         // Consume the counters from the Rx/Tx queues and accumulate into
         // the counters for the device as whole. This consumption is needed
@@ -4,6 +4,7 @@
 //
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::str::FromStr;
 
@@ -172,6 +173,32 @@ impl FromStr for ByteSized {
     }
 }
 
+/// Integer given in decimal, or in hexadecimal with the "0x" prefix, which
+/// suits the values usually written in hexadecimal, such as PCI identifiers.
+pub struct Integer<T>(pub T);
+
+pub enum IntegerParseError {
+    InvalidValue(String),
+}
+
+impl<T: TryFrom<u64>> FromStr for Integer<T> {
+    type Err = IntegerParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let value = if s.starts_with("0x") || s.starts_with("0X") {
+            u64::from_str_radix(&s[2..], 16)
+        } else {
+            s.parse::<u64>()
+        }
+        .map_err(|_| IntegerParseError::InvalidValue(s.to_owned()))?;
+
+        Ok(Integer(T::try_from(value).map_err(|_| {
+            IntegerParseError::InvalidValue(s.to_owned())
+        })?))
+    }
+}
+
 pub struct IntegerList(pub Vec<u64>);
 
 pub enum IntegerListParseError {
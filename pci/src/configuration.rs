@@ -16,7 +16,8 @@ const NUM_CONFIGURATION_REGISTERS: usize = 1024;
 const STATUS_REG: usize = 1;
 const STATUS_REG_CAPABILITIES_USED_MASK: u32 = 0x0010_0000;
 const BAR0_REG: usize = 4;
-const ROM_BAR_REG: usize = 12;
+pub(crate) const ROM_BAR_REG: usize = 12;
+const SUBSYSTEM_REG: usize = 11;
 const BAR_IO_ADDR_MASK: u32 = 0xffff_fffc;
 const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
 const ROM_BAR_ADDR_MASK: u32 = 0xffff_f800;
@@ -430,7 +431,7 @@ impl PciConfiguration {
                 writable_bits[15] = 0xffff_00ff; // Bridge control (r/w), interrupt line (r/w)
             }
         };
-        registers[11] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+        registers[SUBSYSTEM_REG] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
 
         let bars = [PciBar::default(); NUM_BAR_REGS];
 
@@ -472,6 +473,13 @@ impl PciConfiguration {
         self.msix_cap_reg_idx = state.msix_cap_reg_idx;
     }
 
+    /// Overrides the subsystem vendor and device IDs the guest drivers might
+    /// match on.
+    pub fn set_subsystem_id(&mut self, subsystem_vendor_id: u16, subsystem_id: u16) {
+        self.registers[SUBSYSTEM_REG] =
+            u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+    }
+
     /// Reads a 32bit register from `reg_idx` in the register map.
     pub fn read_reg(&self, reg_idx: usize) -> u32 {
         *(self.registers.get(reg_idx).unwrap_or(&0xffff_ffff))
@@ -1089,4 +1097,28 @@ mod tests {
         assert_eq!(subclass, 0x01);
         assert_eq!(prog_if, 0x5a);
     }
+
+    #[test]
+    fn subsystem_id() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x1,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+        );
+        assert_eq!(cfg.read_reg(SUBSYSTEM_REG), 0x2468_abcd);
+
+        cfg.set_subsystem_id(0x8086, 0x1100);
+        assert_eq!(cfg.read_reg(SUBSYSTEM_REG), 0x1100_8086);
+
+        // Read-only for the guest.
+        cfg.write_reg(SUBSYSTEM_REG, 0);
+        assert_eq!(cfg.read_reg(SUBSYSTEM_REG), 0x1100_8086);
+    }
 }
@@ -17,6 +17,7 @@ mod configuration;
 mod device;
 mod msi;
 mod msix;
+mod rom;
 mod vfio;
 
 pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError};
@@ -29,7 +30,10 @@ pub use self::device::{
     BarReprogrammingParams, DeviceRelocation, Error as PciDeviceError, PciDevice,
 };
 pub use self::msi::{msi_num_enabled_vectors, MsiCap, MsiConfig};
-pub use self::msix::{MsixCap, MsixConfig, MsixTableEntry, MSIX_TABLE_ENTRY_SIZE};
+pub use self::msix::{
+    MsixCap, MsixConfig, MsixTableEntry, MAX_MSIX_VECTORS_PER_DEVICE, MSIX_TABLE_ENTRY_SIZE,
+};
+pub use self::rom::PciRom;
 pub use self::vfio::{VfioPciDevice, VfioPciError};
 
 /// PCI has four interrupt pins A->D.
@@ -18,7 +18,7 @@ use vm_device::interrupt::{
 use vm_memory::ByteValued;
 use vm_migration::{MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable};
 
-const MAX_MSIX_VECTORS_PER_DEVICE: u16 = 2048;
+pub const MAX_MSIX_VECTORS_PER_DEVICE: u16 = 2048;
 const MSIX_TABLE_ENTRIES_MODULO: u64 = 16;
 const MSIX_PBA_ENTRIES_MODULO: u64 = 8;
 const BITS_PER_PBA_ENTRY: usize = 64;
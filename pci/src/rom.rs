@@ -0,0 +1,93 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Option ROM images, exposed to the guest through the expansion ROM BAR so
+//! that the firmware can run them, as needed by some GPUs or to boot from the
+//! network.
+
+use crate::configuration::ROM_BAR_REG;
+use crate::{PciBarConfiguration, PciBarRegionType};
+use std::fs;
+use std::io;
+use std::path::Path;
+use vm_memory::GuestAddress;
+
+// The expansion ROM BAR decodes at least 2KiB.
+const MIN_ROM_BAR_SIZE: u64 = 0x800;
+// Largest image accepted, since the BAR has to fit in the 32 bits MMIO hole.
+const MAX_ROM_SIZE: usize = 16 << 20;
+
+/// Read-only option ROM image.
+pub struct PciRom {
+    data: Vec<u8>,
+}
+
+impl PciRom {
+    pub fn new(data: Vec<u8>) -> io::Result<Self> {
+        if data.is_empty() || data.len() > MAX_ROM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid option ROM size {}, must be between 1 and {} bytes",
+                    data.len(),
+                    MAX_ROM_SIZE
+                ),
+            ));
+        }
+
+        Ok(PciRom { data })
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        PciRom::new(fs::read(path)?)
+    }
+
+    /// Size of the expansion ROM BAR, the image being padded with zeros up to
+    /// a power of two.
+    pub fn bar_size(&self) -> u64 {
+        std::cmp::max(
+            (self.data.len() as u64).next_power_of_two(),
+            MIN_ROM_BAR_SIZE,
+        )
+    }
+
+    /// Configuration of the expansion ROM BAR for the image, located at `addr`.
+    pub fn bar_configuration(&self, addr: GuestAddress) -> PciBarConfiguration {
+        PciBarConfiguration::default()
+            .set_register_index(ROM_BAR_REG)
+            .set_address(addr.0)
+            .set_size(self.bar_size())
+            .set_region_type(PciBarRegionType::Memory32BitRegion)
+    }
+
+    /// Read the image at `offset` from the start of the BAR.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.data.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pci_rom() {
+        assert!(PciRom::new(Vec::new()).is_err());
+        assert!(PciRom::new(vec![0; MAX_ROM_SIZE + 1]).is_err());
+
+        let rom = PciRom::new(vec![0x55, 0xaa, 0x01]).unwrap();
+        assert_eq!(rom.bar_size(), MIN_ROM_BAR_SIZE);
+        assert_eq!(PciRom::new(vec![0; 0x2001]).unwrap().bar_size(), 0x4000);
+
+        // The padding reads as zeros.
+        let mut data = [0xff; 4];
+        rom.read(1, &mut data);
+        assert_eq!(data, [0xaa, 0x01, 0, 0]);
+        rom.read(0x100, &mut data);
+        assert_eq!(data, [0; 4]);
+    }
+}
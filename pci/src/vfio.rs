@@ -8,13 +8,14 @@ extern crate vm_allocator;
 use crate::{
     msi_num_enabled_vectors, BarReprogrammingParams, MsiConfig, MsixCap, MsixConfig,
     PciBarConfiguration, PciBarRegionType, PciCapabilityID, PciClassCode, PciConfiguration,
-    PciDevice, PciDeviceError, PciHeaderType, PciSubclass, MSIX_TABLE_ENTRY_SIZE,
+    PciDevice, PciDeviceError, PciHeaderType, PciRom, PciSubclass, MSIX_TABLE_ENTRY_SIZE,
 };
 use byteorder::{ByteOrder, LittleEndian};
 use std::any::Any;
 use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::{fmt, io, result};
 use vfio_bindings::bindings::vfio::*;
@@ -88,6 +89,15 @@ enum InterruptUpdateAction {
     DisableMsix,
 }
 
+struct VfioIntx {
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    irq: u8,
+    // Signalled by the host when the device asserts INTx, which the host
+    // then masks until it is unmasked on the end of interrupt.
+    trigger: EventFd,
+    enabled: Arc<AtomicBool>,
+}
+
 struct VfioMsi {
     cfg: MsiConfig,
     cap_offset: u32,
@@ -153,11 +163,20 @@ impl VfioMsix {
 }
 
 struct Interrupt {
+    intx: Option<VfioIntx>,
     msi: Option<VfioMsi>,
     msix: Option<VfioMsix>,
 }
 
 impl Interrupt {
+    fn intx_in_use(&self) -> bool {
+        if let Some(intx) = &self.intx {
+            return intx.enabled.load(Ordering::Acquire);
+        }
+
+        false
+    }
+
     fn update_msi(&mut self, offset: u64, data: &[u8]) -> Option<InterruptUpdateAction> {
         if let Some(ref mut msi) = &mut self.msi {
             let action = msi.update(offset, data);
@@ -285,6 +304,11 @@ pub struct VfioPciDevice {
     mmio_regions: Vec<MmioRegion>,
     interrupt: Interrupt,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    // Option ROM replacing the one from the device.
+    rom: Option<PciRom>,
+    // Class code and revision ID replacing the ones from the device.
+    class_code: Option<u32>,
+    revision: Option<u8>,
 }
 
 impl VfioPciDevice {
@@ -320,13 +344,18 @@ impl VfioPciDevice {
             vfio_pci_configuration,
             mmio_regions: Vec::new(),
             interrupt: Interrupt {
+                intx: None,
                 msi: None,
                 msix: None,
             },
             mem,
+            rom: None,
+            class_code: None,
+            revision: None,
         };
 
         vfio_pci_device.parse_capabilities(interrupt_manager);
+        vfio_pci_device.apply_quirks();
 
         Ok(vfio_pci_device)
     }
@@ -425,9 +454,106 @@ impl VfioPciDevice {
         }
     }
 
+    /// Forward the INTx interrupt of the device to the guest through
+    /// `interrupt_source_group`, as the legacy interrupt `irq`. The device
+    /// keeps on relying on it until the guest enables MSI or MSI-X. The VMM
+    /// is in charge of triggering the interrupt when `intx_trigger()` is
+    /// signalled, and of calling `intx_eoi_handler()` on its end.
+    pub fn set_intx(
+        &mut self,
+        interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+        irq: u8,
+    ) {
+        // A device without any interrupt pin doesn't support INTx.
+        if self
+            .vfio_pci_configuration
+            .read_config_byte(PCI_INTX_PIN_OFFSET)
+            == 0
+        {
+            return;
+        }
+
+        let trigger = match EventFd::new(libc::EFD_NONBLOCK) {
+            Ok(trigger) => trigger,
+            Err(e) => {
+                warn!("Could not create the INTx eventfd: {}", e);
+                return;
+            }
+        };
+
+        self.interrupt.intx = Some(VfioIntx {
+            interrupt_source_group,
+            irq,
+            trigger,
+            enabled: Arc::new(AtomicBool::new(false)),
+        });
+        self.enable_intx();
+    }
+
+    /// The eventfd signalled when the device asserts INTx, along with the
+    /// interrupt to trigger then.
+    pub fn intx_trigger(&self) -> Option<(EventFd, Arc<Box<dyn InterruptSourceGroup>>)> {
+        let intx = self.interrupt.intx.as_ref()?;
+        match intx.trigger.try_clone() {
+            Ok(trigger) => Some((trigger, intx.interrupt_source_group.clone())),
+            Err(e) => {
+                warn!("Could not clone the INTx eventfd: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Unmask INTx, masked by the host when the device asserted it, once the
+    /// guest is done with the interrupt. The device asserts it again if it
+    /// still needs to be serviced.
+    pub fn intx_eoi_handler(&self) -> Option<Arc<dyn Fn() + Send + Sync>> {
+        let intx = self.interrupt.intx.as_ref()?;
+        let device = self.device.clone();
+        let enabled = intx.enabled.clone();
+        Some(Arc::new(move || {
+            if enabled.load(Ordering::Acquire) {
+                if let Err(e) = device.unmask_irq(VFIO_PCI_INTX_IRQ_INDEX) {
+                    error!("Could not unmask INTx: {}", e);
+                }
+            }
+        }))
+    }
+
+    fn enable_intx(&mut self) {
+        if let Some(intx) = &self.interrupt.intx {
+            if intx.enabled.load(Ordering::Acquire) {
+                return;
+            }
+
+            match self
+                .device
+                .enable_irq(VFIO_PCI_INTX_IRQ_INDEX, vec![&intx.trigger])
+            {
+                Ok(_) => intx.enabled.store(true, Ordering::Release),
+                Err(e) => warn!("Could not enable INTx: {}", e),
+            }
+        }
+    }
+
+    fn disable_intx(&mut self) {
+        if let Some(intx) = &self.interrupt.intx {
+            if !intx.enabled.load(Ordering::Acquire) {
+                return;
+            }
+
+            if let Err(e) = self.device.disable_irq(VFIO_PCI_INTX_IRQ_INDEX) {
+                warn!("Could not disable INTx: {}", e);
+            }
+            intx.enabled.store(false, Ordering::Release);
+        }
+    }
+
     fn update_msi_capabilities(&mut self, offset: u64, data: &[u8]) -> Result<()> {
         match self.interrupt.update_msi(offset, data) {
             Some(InterruptUpdateAction::EnableMsi) => {
+                // The device can only rely on one type of interrupt at a time.
+                self.disable_intx();
+
                 if let Some(msi) = &self.interrupt.msi {
                     let mut irq_fds: Vec<&EventFd> = Vec::new();
                     for i in 0..msi.cfg.num_enabled_vectors() {
@@ -449,6 +575,8 @@ impl VfioPciDevice {
                 if let Err(e) = self.device.disable_msi() {
                     warn!("Could not disable MSI: {}", e);
                 }
+
+                self.enable_intx();
             }
             _ => {}
         }
@@ -459,6 +587,8 @@ impl VfioPciDevice {
     fn update_msix_capabilities(&mut self, offset: u64, data: &[u8]) -> Result<()> {
         match self.interrupt.update_msix(offset, data) {
             Some(InterruptUpdateAction::EnableMsix) => {
+                self.disable_intx();
+
                 if let Some(msix) = &self.interrupt.msix {
                     let mut irq_fds: Vec<&EventFd> = Vec::new();
                     for i in 0..msix.bar.table_entries.len() {
@@ -480,6 +610,8 @@ impl VfioPciDevice {
                 if let Err(e) = self.device.disable_msix() {
                     warn!("Could not disable MSI-X: {}", e);
                 }
+
+                self.enable_intx();
             }
             _ => {}
         }
@@ -487,6 +619,46 @@ impl VfioPciDevice {
         Ok(())
     }
 
+    /// Expose the given option ROM to the guest instead of the one from the
+    /// device. This must be called before the BARs are allocated.
+    pub fn set_rom(&mut self, rom: PciRom) {
+        self.rom = Some(rom);
+    }
+
+    /// Expose the given class code, made of the base class, the subclass and
+    /// the programming interface, to the guest instead of the one from the
+    /// device.
+    pub fn set_class_code(&mut self, class_code: u32) {
+        self.class_code = Some(class_code & 0xff_ffff);
+    }
+
+    /// Expose the given revision ID to the guest instead of the one from the
+    /// device.
+    pub fn set_revision(&mut self, revision: u8) {
+        self.revision = Some(revision);
+    }
+
+    fn apply_quirks(&mut self) {
+        let id = self
+            .vfio_pci_configuration
+            .read_config_dword((PCI_VENDOR_DEVICE_ID_REG_INDEX * 4) as u32);
+        let (vendor_id, device_id) = (id as u16, (id >> 16) as u16);
+        let class_code = self
+            .vfio_pci_configuration
+            .read_config_dword((PCI_CLASS_REVISION_REG_INDEX * 4) as u32)
+            >> 8;
+
+        for quirk in VFIO_PCI_QUIRKS.iter() {
+            if quirk.vendor_id == vendor_id && quirk.device_id == device_id && class_code == 0 {
+                info!(
+                    "Exposing class code 0x{:06x} for device {:04x}:{:04x}",
+                    quirk.class_code, vendor_id, device_id
+                );
+                self.class_code = Some(quirk.class_code);
+            }
+        }
+    }
+
     fn find_region(&self, addr: u64) -> Option<MmioRegion> {
         for region in self.mmio_regions.iter() {
             if addr >= region.start.raw_value()
@@ -523,6 +695,11 @@ impl VfioPciDevice {
                 }
             }
 
+            // The option ROM provided by the user is emulated.
+            if region.index == VFIO_PCI_ROM_REGION_INDEX && self.rom.is_some() {
+                continue;
+            }
+
             let region_flags = self.device.get_region_flags(region.index);
             if region_flags & VFIO_REGION_INFO_FLAG_MMAP != 0 {
                 let mut prot = 0;
@@ -637,6 +814,11 @@ impl Drop for VfioPciDevice {
             }
         }
 
+        if self.interrupt.intx_in_use() && self.device.disable_irq(VFIO_PCI_INTX_IRQ_INDEX).is_err()
+        {
+            error!("Could not disable INTx");
+        }
+
         if self
             .device
             .unset_dma_map(self.mem.memory().deref())
@@ -657,6 +839,50 @@ impl BusDevice for VfioPciDevice {
     }
 }
 
+// Quirk of a device reporting an undefined class code, which is fixed for
+// the guest drivers and firmware matching on the class code to find it, as
+// the Linux PCI quirks do on the host.
+struct VfioPciQuirk {
+    vendor_id: u16,
+    device_id: u16,
+    class_code: u32,
+}
+
+// Multimedia video controller class code.
+const PCI_CLASS_MULTIMEDIA_VIDEO: u32 = 0x04_00_00;
+// Other multimedia controller class code.
+const PCI_CLASS_MULTIMEDIA_OTHER: u32 = 0x04_80_00;
+
+const VFIO_PCI_QUIRKS: &[VfioPciQuirk] = &[
+    // TI 816x in PCIe boot mode.
+    VfioPciQuirk {
+        vendor_id: 0x104c,
+        device_id: 0xb800,
+        class_code: PCI_CLASS_MULTIMEDIA_VIDEO,
+    },
+    // Techwell TW686x video capture cards.
+    VfioPciQuirk {
+        vendor_id: 0x1797,
+        device_id: 0x6864,
+        class_code: PCI_CLASS_MULTIMEDIA_OTHER,
+    },
+    VfioPciQuirk {
+        vendor_id: 0x1797,
+        device_id: 0x6865,
+        class_code: PCI_CLASS_MULTIMEDIA_OTHER,
+    },
+    VfioPciQuirk {
+        vendor_id: 0x1797,
+        device_id: 0x6868,
+        class_code: PCI_CLASS_MULTIMEDIA_OTHER,
+    },
+    VfioPciQuirk {
+        vendor_id: 0x1797,
+        device_id: 0x6869,
+        class_code: PCI_CLASS_MULTIMEDIA_OTHER,
+    },
+];
+
 // First BAR offset in the PCI config space.
 const PCI_CONFIG_BAR_OFFSET: u32 = 0x10;
 // Capability register offset in the PCI config space.
@@ -671,6 +897,10 @@ const PCI_CONFIG_MEMORY_BAR_64BIT: u32 = 0x4;
 const PCI_CONFIG_REGISTER_SIZE: usize = 4;
 // Number of BARs for a PCI device
 const BAR_NUMS: usize = 6;
+// PCI Vendor and Device ID register index
+const PCI_VENDOR_DEVICE_ID_REG_INDEX: usize = 0;
+// PCI Class Code and Revision ID register index
+const PCI_CLASS_REVISION_REG_INDEX: usize = 2;
 // PCI Header Type register index
 const PCI_HEADER_TYPE_REG_INDEX: usize = 3;
 // First BAR register index
@@ -679,6 +909,8 @@ const PCI_CONFIG_BAR0_INDEX: usize = 4;
 const PCI_ROM_EXP_BAR_INDEX: usize = 12;
 // PCI interrupt pin and line register index
 const PCI_INTX_REG_INDEX: usize = 15;
+// Interrupt Pin byte of the PCI configuration space.
+const PCI_INTX_PIN_OFFSET: u32 = 0x3d;
 
 impl PciDevice for VfioPciDevice {
     fn allocate_bars(
@@ -706,9 +938,17 @@ impl PciDevice for VfioPciDevice {
                 PCI_CONFIG_BAR_OFFSET + bar_id * 4
             };
 
-            self.vfio_pci_configuration
-                .write_config_dword(lsb_size, bar_offset);
-            lsb_size = self.vfio_pci_configuration.read_config_dword(bar_offset);
+            match &self.rom {
+                // The option ROM provided by the user sets the BAR size.
+                Some(rom) if bar_id == VFIO_PCI_ROM_REGION_INDEX => {
+                    lsb_size = !(rom.bar_size() as u32 - 1);
+                }
+                _ => {
+                    self.vfio_pci_configuration
+                        .write_config_dword(lsb_size, bar_offset);
+                    lsb_size = self.vfio_pci_configuration.read_config_dword(bar_offset);
+                }
+            }
 
             // We've just read the BAR size back. Or at least its LSB.
             let lsb_flag = lsb_size & PCI_CONFIG_MEMORY_BAR_FLAG_MASK;
@@ -951,11 +1191,13 @@ impl PciDevice for VfioPciDevice {
             return self.configuration.read_reg(reg_idx);
         }
 
-        // Since we don't support INTx (only MSI and MSI-X), we should not
-        // expose an invalid Interrupt Pin to the guest. By using a specific
-        // mask in case the register being read correspond to the interrupt
-        // register, this code makes sure to always expose an Interrupt Pin
-        // value of 0, which stands for no interrupt pin support.
+        // When INTx isn't forwarded to the guest, we should not expose an
+        // invalid Interrupt Pin to the guest. By using a specific mask in
+        // case the register being read correspond to the interrupt register,
+        // this code makes sure to expose an Interrupt Pin value of 0, which
+        // stands for no interrupt pin support. Otherwise, the device is
+        // exposed as using INTA, routed to the legacy interrupt it's
+        // forwarded to.
         //
         // Since we don't support passing multi-functions devices, we should
         // mask the multi-function bit, bit 7 of the Header Type byte on the
@@ -969,9 +1211,24 @@ impl PciDevice for VfioPciDevice {
         };
 
         // The config register read comes from the VFIO device itself.
-        self.vfio_pci_configuration
+        let value = self
+            .vfio_pci_configuration
             .read_config_dword((reg_idx * 4) as u32)
-            & mask
+            & mask;
+
+        if reg_idx == PCI_INTX_REG_INDEX {
+            if let Some(intx) = &self.interrupt.intx {
+                return (value & 0xffff_ff00) | 1 << 8 | u32::from(intx.irq);
+            }
+        }
+
+        if reg_idx == PCI_CLASS_REVISION_REG_INDEX {
+            let class_code = self.class_code.unwrap_or(value >> 8);
+            let revision = self.revision.map_or(value & 0xff, u32::from);
+            return class_code << 8 | revision;
+        }
+
+        value
     }
 
     fn detect_bar_reprogramming(
@@ -990,7 +1247,12 @@ impl PciDevice for VfioPciDevice {
             if self.interrupt.msix_table_accessed(region.index, offset) {
                 self.interrupt.msix_read_table(offset, data);
             } else {
-                self.device.region_read(region.index, data, offset);
+                match &self.rom {
+                    Some(rom) if region.index == VFIO_PCI_ROM_REGION_INDEX => {
+                        rom.read(offset, data)
+                    }
+                    _ => self.device.region_read(region.index, data, offset),
+                }
             }
         }
     }
@@ -1003,6 +1265,8 @@ impl PciDevice for VfioPciDevice {
             // If the MSI-X table is written to, we need to update our cache.
             if self.interrupt.msix_table_accessed(region.index, offset) {
                 self.interrupt.msix_write_table(offset, data);
+            } else if region.index == VFIO_PCI_ROM_REGION_INDEX && self.rom.is_some() {
+                // The option ROM provided by the user is read-only.
             } else {
                 self.device.region_write(region.index, data, offset);
             }
@@ -15,6 +15,7 @@ use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use option_parser::{ByteSized, ByteSizedParseError};
 use std::fmt;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::process;
 
 #[derive(Debug)]
@@ -24,6 +25,9 @@ enum Error {
     InvalidCPUCount(std::num::ParseIntError),
     InvalidMemorySize(ByteSizedParseError),
     InvalidBalloonSize(ByteSizedParseError),
+    InvalidCpuId(std::num::ParseIntError),
+    InvalidAddress(std::num::ParseIntError),
+    InvalidHotplugJobId(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
@@ -42,6 +46,9 @@ impl fmt::Display for Error {
             InvalidCPUCount(e) => write!(f, "Error parsing CPU count: {}", e),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {:?}", e),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {:?}", e),
+            InvalidCpuId(e) => write!(f, "Error parsing vCPU identifier: {}", e),
+            InvalidAddress(e) => write!(f, "Error parsing address: {}", e),
+            InvalidHotplugJobId(e) => write!(f, "Error parsing hotplug job identifier: {}", e),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {}", e),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {}", e),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {}", e),
@@ -59,7 +66,7 @@ fn resize_api_command(
     memory: Option<&str>,
     balloon: Option<&str>,
 ) -> Result<(), Error> {
-    let desired_vcpus: Option<u8> = if let Some(cpus) = cpus {
+    let desired_vcpus: Option<u16> = if let Some(cpus) = cpus {
         Some(cpus.parse().map_err(Error::InvalidCPUCount)?)
     } else {
         None
@@ -120,8 +127,15 @@ fn resize_zone_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Res
     .map_err(Error::ApiClient)
 }
 
-fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+fn add_device_api_command(
+    socket: &mut UnixStream,
+    config: &str,
+    asynchronous: bool,
+) -> Result<(), Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
+    if asynchronous {
+        return hotplug_api_command(socket, vmm::hotplug::HotplugDevice::Device(device_config));
+    }
 
     simple_api_command(
         socket,
@@ -144,8 +158,77 @@ fn remove_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Er
     .map_err(Error::ApiClient)
 }
 
-fn add_disk_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+fn set_net_link_api_command(socket: &mut UnixStream, id: &str, state: &str) -> Result<(), Error> {
+    let set_net_link_data = vmm::api::VmSetNetLinkData {
+        id: id.to_owned(),
+        up: state == "up",
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-net-link",
+        Some(&serde_json::to_string(&set_net_link_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn trace_queues_api_command(socket: &mut UnixStream, id: &str, state: &str) -> Result<(), Error> {
+    let trace_queues_data = vmm::api::VmTraceQueuesData {
+        id: id.to_owned(),
+        enable: state == "on",
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "trace-queues",
+        Some(&serde_json::to_string(&trace_queues_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn inject_mce_api_command(
+    socket: &mut UnixStream,
+    address: &str,
+    cpu: Option<&str>,
+    action_required: bool,
+) -> Result<(), Error> {
+    let address = if address.starts_with("0x") {
+        u64::from_str_radix(&address[2..], 16)
+    } else {
+        address.parse()
+    }
+    .map_err(Error::InvalidAddress)?;
+    let cpu_id = if let Some(cpu) = cpu {
+        cpu.parse().map_err(Error::InvalidCpuId)?
+    } else {
+        0
+    };
+    let inject_mce_data = vmm::api::VmInjectMceData {
+        cpu_id,
+        address,
+        action_required,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "inject-mce",
+        Some(&serde_json::to_string(&inject_mce_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn add_disk_api_command(
+    socket: &mut UnixStream,
+    config: &str,
+    asynchronous: bool,
+) -> Result<(), Error> {
     let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
+    if asynchronous {
+        return hotplug_api_command(socket, vmm::hotplug::HotplugDevice::Disk(disk_config));
+    }
 
     simple_api_command(
         socket,
@@ -156,8 +239,15 @@ fn add_disk_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Err
     .map_err(Error::ApiClient)
 }
 
-fn add_fs_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+fn add_fs_api_command(
+    socket: &mut UnixStream,
+    config: &str,
+    asynchronous: bool,
+) -> Result<(), Error> {
     let fs_config = vmm::config::FsConfig::parse(config).map_err(Error::AddFsConfig)?;
+    if asynchronous {
+        return hotplug_api_command(socket, vmm::hotplug::HotplugDevice::Fs(fs_config));
+    }
 
     simple_api_command(
         socket,
@@ -168,6 +258,33 @@ fn add_fs_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error
     .map_err(Error::ApiClient)
 }
 
+fn hotplug_api_command(
+    socket: &mut UnixStream,
+    device: vmm::hotplug::HotplugDevice,
+) -> Result<(), Error> {
+    simple_api_command(
+        socket,
+        "PUT",
+        "hotplug",
+        Some(&serde_json::to_string(&device).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn hotplug_job_api_command(socket: &mut UnixStream, command: &str, id: &str) -> Result<(), Error> {
+    let hotplug_job_data = vmm::api::VmHotplugJobData {
+        id: id.parse().map_err(Error::InvalidHotplugJobId)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        command,
+        Some(&serde_json::to_string(&hotplug_job_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn add_pmem_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let pmem_config = vmm::config::PmemConfig::parse(config).map_err(Error::AddPmemConfig)?;
 
@@ -180,8 +297,15 @@ fn add_pmem_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Err
     .map_err(Error::ApiClient)
 }
 
-fn add_net_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+fn add_net_api_command(
+    socket: &mut UnixStream,
+    config: &str,
+    asynchronous: bool,
+) -> Result<(), Error> {
     let net_config = vmm::config::NetConfig::parse(config).map_err(Error::AddNetConfig)?;
+    if asynchronous {
+        return hotplug_api_command(socket, vmm::hotplug::HotplugDevice::Net(net_config));
+    }
 
     simple_api_command(
         socket,
@@ -204,9 +328,18 @@ fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Er
     .map_err(Error::ApiClient)
 }
 
-fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+fn snapshot_api_command(
+    socket: &mut UnixStream,
+    url: &str,
+    key_file: Option<&str>,
+    compress: bool,
+    incremental: bool,
+) -> Result<(), Error> {
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
+        key_file: key_file.map(PathBuf::from),
+        compress,
+        incremental,
     };
 
     simple_api_command(
@@ -267,6 +400,12 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
         Some("counters") => {
             simple_api_command(&mut socket, "GET", "counters", None).map_err(Error::ApiClient)
         }
+        Some("memory-layout") => {
+            simple_api_command(&mut socket, "GET", "memory-layout", None).map_err(Error::ApiClient)
+        }
+        Some("access-trace") => {
+            simple_api_command(&mut socket, "GET", "access-trace", None).map_err(Error::ApiClient)
+        }
         Some("resize") => resize_api_command(
             &mut socket,
             matches
@@ -302,6 +441,10 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("device_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("add-device")
+                .unwrap()
+                .is_present("async"),
         ),
         Some("remove-device") => remove_device_api_command(
             &mut socket,
@@ -311,6 +454,48 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("id")
                 .unwrap(),
         ),
+        Some("set-net-link") => set_net_link_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-net-link")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-net-link")
+                .unwrap()
+                .value_of("state")
+                .unwrap(),
+        ),
+        Some("trace-queues") => trace_queues_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("trace-queues")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("trace-queues")
+                .unwrap()
+                .value_of("state")
+                .unwrap(),
+        ),
+        Some("inject-mce") => inject_mce_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("inject-mce")
+                .unwrap()
+                .value_of("address")
+                .unwrap(),
+            matches
+                .subcommand_matches("inject-mce")
+                .unwrap()
+                .value_of("cpu"),
+            matches
+                .subcommand_matches("inject-mce")
+                .unwrap()
+                .is_present("action_required"),
+        ),
         Some("add-disk") => add_disk_api_command(
             &mut socket,
             matches
@@ -318,6 +503,10 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("disk_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("add-disk")
+                .unwrap()
+                .is_present("async"),
         ),
         Some("add-fs") => add_fs_api_command(
             &mut socket,
@@ -326,6 +515,19 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("fs_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("add-fs")
+                .unwrap()
+                .is_present("async"),
+        ),
+        Some(c @ "hotplug-status") | Some(c @ "cancel-hotplug") => hotplug_job_api_command(
+            &mut socket,
+            c,
+            matches
+                .subcommand_matches(c)
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
         ),
         Some("add-pmem") => add_pmem_api_command(
             &mut socket,
@@ -342,6 +544,10 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("net_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("add-net")
+                .unwrap()
+                .is_present("async"),
         ),
         Some("add-vsock") => add_vsock_api_command(
             &mut socket,
@@ -358,6 +564,18 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("snapshot_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("key_file"),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .is_present("compress"),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .is_present("incremental"),
         ),
         Some("restore") => restore_api_command(
             &mut socket,
@@ -408,6 +626,11 @@ fn main() {
                     Arg::with_name("device_config")
                         .index(1)
                         .help(vmm::config::DeviceConfig::SYNTAX),
+                )
+                .arg(
+                    Arg::with_name("async")
+                        .long("async")
+                        .help("Add the device in the background, returning the hotplug job id"),
                 ),
         )
         .subcommand(
@@ -417,6 +640,11 @@ fn main() {
                     Arg::with_name("disk_config")
                         .index(1)
                         .help(vmm::config::DiskConfig::SYNTAX),
+                )
+                .arg(
+                    Arg::with_name("async")
+                        .long("async")
+                        .help("Add the device in the background, returning the hotplug job id"),
                 ),
         )
         .subcommand(
@@ -426,6 +654,11 @@ fn main() {
                     Arg::with_name("fs_config")
                         .index(1)
                         .help(vmm::config::FsConfig::SYNTAX),
+                )
+                .arg(
+                    Arg::with_name("async")
+                        .long("async")
+                        .help("Add the device in the background, returning the hotplug job id"),
                 ),
         )
         .subcommand(
@@ -444,6 +677,11 @@ fn main() {
                     Arg::with_name("net_config")
                         .index(1)
                         .help(vmm::config::NetConfig::SYNTAX),
+                )
+                .arg(
+                    Arg::with_name("async")
+                        .long("async")
+                        .help("Add the device in the background, returning the hotplug job id"),
                 ),
         )
         .subcommand(
@@ -460,9 +698,60 @@ fn main() {
                 .about("Remove VFIO device")
                 .arg(Arg::with_name("id").index(1).help("<device_id>")),
         )
+        .subcommand(
+            SubCommand::with_name("hotplug-status")
+                .about("Status of a hotplug job")
+                .arg(
+                    Arg::with_name("id")
+                        .index(1)
+                        .required(true)
+                        .help("<hotplug_job_id>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cancel-hotplug")
+                .about("Cancel a hotplug job not adding its device yet")
+                .arg(
+                    Arg::with_name("id")
+                        .index(1)
+                        .required(true)
+                        .help("<hotplug_job_id>"),
+                ),
+        )
         .subcommand(SubCommand::with_name("info").about("Info on the VM"))
         .subcommand(SubCommand::with_name("counters").about("Counters from the VM"))
+        .subcommand(
+            SubCommand::with_name("inject-mce")
+                .about("Report a memory error to the guest through a machine check")
+                .arg(
+                    Arg::with_name("address")
+                        .index(1)
+                        .required(true)
+                        .help("Guest physical address of the poisoned memory"),
+                )
+                .arg(
+                    Arg::with_name("cpu")
+                        .long("cpu")
+                        .help("vCPU the machine check is reported on (default 0)")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("action_required")
+                        .long("action-required")
+                        .help("Report the error as consumed by the guest"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("memory-layout")
+                .about("Guest physical address space layout of the VM"),
+        )
+        .subcommand(
+            SubCommand::with_name("access-trace")
+                .about("Last port I/O and MMIO accesses of each vCPU of the VM"),
+        )
         .subcommand(SubCommand::with_name("pause").about("Pause the VM"))
+        .subcommand(SubCommand::with_name("power-button").about("Trigger a power button in the VM"))
         .subcommand(SubCommand::with_name("reboot").about("Reboot the VM"))
         .subcommand(
             SubCommand::with_name("resize")
@@ -508,7 +797,45 @@ fn main() {
                 ),
         )
         .subcommand(SubCommand::with_name("resume").about("Resume the VM"))
+        .subcommand(
+            SubCommand::with_name("set-net-link")
+                .about("Set the link status of a network device")
+                .arg(
+                    Arg::with_name("id")
+                        .index(1)
+                        .required(true)
+                        .help("<device_id>"),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .index(2)
+                        .required(true)
+                        .possible_values(&["up", "down"])
+                        .help("up|down"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trace-queues")
+                .about(
+                    "Log the descriptor chains going through the virtqueues of a device \
+                     (logged at the info level, shown with -v)",
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .index(1)
+                        .required(true)
+                        .help("<device_id>"),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .index(2)
+                        .required(true)
+                        .possible_values(&["on", "off"])
+                        .help("on|off"),
+                ),
+        )
         .subcommand(SubCommand::with_name("shutdown").about("Shutdown the VM"))
+        .subcommand(SubCommand::with_name("wakeup").about("Wake the VM up from suspend"))
         .subcommand(
             SubCommand::with_name("snapshot")
                 .about("Create a snapshot from VM")
@@ -516,6 +843,22 @@ fn main() {
                     Arg::with_name("snapshot_config")
                         .index(1)
                         .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::with_name("key_file")
+                        .long("key-file")
+                        .help("File containing the key the snapshot is encrypted with")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compress")
+                        .long("compress")
+                        .help("Compress the memory of the snapshot"),
+                )
+                .arg(
+                    Arg::with_name("incremental")
+                        .long("incremental")
+                        .help("Only store the memory dirtied since the previous snapshot"),
                 ),
         )
         .subcommand(
@@ -533,7 +876,7 @@ fn main() {
                 .arg(
                     Arg::with_name("send_migration_config")
                         .index(1)
-                        .help("<destination_url>"),
+                        .help("<destination_url> (unix:///<path> or tcp://<host>:<port>)"),
                 ),
         )
         .subcommand(
@@ -542,7 +885,7 @@ fn main() {
                 .arg(
                     Arg::with_name("receive_migration_config")
                         .index(1)
-                        .help("<receiver_url>"),
+                        .help("<receiver_url> (unix:///<path> or tcp://<host>:<port>)"),
                 ),
         );
 
@@ -10,11 +10,12 @@ extern crate vmm_sys_util;
 #[macro_use(crate_authors)]
 extern crate clap;
 
-use clap::{App, Arg, ArgGroup, ArgMatches};
-use libc::EFD_NONBLOCK;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches};
+use libc::{EFD_NONBLOCK, EFD_SEMAPHORE};
 use log::LevelFilter;
 use seccomp::SeccompAction;
 use std::env;
+use std::path::Path;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use thiserror::Error;
@@ -46,20 +47,81 @@ enum Error {
     VmRestore(vmm::api::ApiError),
     #[error("Error parsing restore: {0}")]
     ParsingRestore(vmm::config::Error),
+    #[error("Error parsing metrics: {0}")]
+    ParsingMetrics(vmm::config::Error),
+    #[error("Failed to start the metrics thread: {0}")]
+    StartMetricsThread(#[source] vmm::Error),
+    #[error("Failed to open the audit log: {0}")]
+    OpenAuditLog(#[source] std::io::Error),
+    #[cfg(feature = "otlp")]
+    #[error("Error parsing otlp: {0}")]
+    ParsingOtlp(vmm::config::Error),
+    #[cfg(feature = "otlp")]
+    #[error("Failed to start the OTLP thread: {0}")]
+    StartOtlpThread(#[source] vmm::Error),
     #[error("Failed to join on VMM thread: {0:?}")]
     ThreadJoin(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
     #[error("VMM thread exited with error: {0}")]
     VmmThread(#[source] vmm::Error),
+    #[error("Error parsing --log-filter: {0}")]
+    ParsingLogFilter(String),
+    #[error("--config can't be combined with the VM configuration options")]
+    ConfigConflict,
 }
 
 struct Logger {
     output: Mutex<Box<dyn std::io::Write + Send>>,
     start: std::time::Instant,
+    level: LevelFilter,
+    // Levels overriding the default one for some modules and their children.
+    module_levels: Vec<(String, LevelFilter)>,
+}
+
+impl Logger {
+    // The most specific module matching the target wins.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, std::cmp::max)
+    }
+}
+
+// Parses a comma separated list of <module>=<level> entries.
+fn parse_log_filter(filter: &str) -> Result<Vec<(String, LevelFilter)>, Error> {
+    let mut module_levels = Vec::new();
+    for entry in filter.split(',').filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let module = parts.next().unwrap();
+        let level = parts
+            .next()
+            .and_then(|l| l.parse::<LevelFilter>().ok())
+            .ok_or_else(|| Error::ParsingLogFilter(entry.to_string()))?;
+        if module.is_empty() {
+            return Err(Error::ParsingLogFilter(entry.to_string()));
+        }
+        module_levels.push((module.to_string(), level));
+    }
+
+    Ok(module_levels)
 }
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
@@ -120,6 +182,8 @@ fn create_app<'a, 'b>(
         // 'BUILT_VERSION' is set by the build script 'build.rs' at
         // compile time
         .version(env!("BUILT_VERSION"))
+        // The version is printed by main(), for -v to detail it.
+        .setting(AppSettings::DisableVersion)
         .author(crate_authors!())
         .about("Launch a cloud-hypervisor VMM.")
         .group(ArgGroup::with_name("vm-config").multiple(true))
@@ -144,7 +208,8 @@ fn create_app<'a, 'b>(
                      \"size=<guest_memory_size>,mergeable=on|off,shared=on|off,hugepages=on|off,\
                      hotplug_method=acpi|virtio-mem,\
                      hotplug_size=<hotpluggable_memory_size>,\
-                     hotplugged_size=<hotplugged_memory_size>\"",
+                     hotplugged_size=<hotplugged_memory_size>,\
+                     crashkernel=<crash_kernel_memory_size>\"",
                 )
                 .default_value(&default_memory)
                 .group("vm-config"),
@@ -207,7 +272,7 @@ fn create_app<'a, 'b>(
             Arg::with_name("rng")
                 .long("rng")
                 .help(
-                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off\"",
+                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off,rate_limit=<bytes_per_second>\"",
                 )
                 .default_value(&default_rng)
                 .group("vm-config"),
@@ -238,7 +303,9 @@ fn create_app<'a, 'b>(
         .arg(
             Arg::with_name("serial")
                 .long("serial")
-                .help("Control serial port: off|null|tty|file=/path/to/a/file")
+                .help(
+                    "Control serial port: off|null|pty|tty|file=/path/to/a/file,max_size=<output_file_max_size>,rotate=<number_of_rotated_files>",
+                )
                 .default_value("null")
                 .group("vm-config"),
         )
@@ -246,11 +313,19 @@ fn create_app<'a, 'b>(
             Arg::with_name("console")
                 .long("console")
                 .help(
-                    "Control (virtio) console: \"off|null|tty|file=/path/to/a/file,iommu=on|off\"",
+                    "Control (virtio) console: \"off|null|pty|tty|file=/path/to/a/file,max_size=<output_file_max_size>,rotate=<number_of_rotated_files>,iommu=on|off\"",
                 )
                 .default_value("tty")
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("console-port")
+                .long("console-port")
+                .help(config::ConsolePortConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("device")
                 .long("device")
@@ -282,6 +357,58 @@ fn create_app<'a, 'b>(
                 .takes_value(false)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("on-crash")
+                .long("on-crash")
+                .help("Action taken when the guest crashes: reboot|exit|pause")
+                .default_value("reboot")
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("resource-group")
+                .long("resource-group")
+                .help(config::ResourceGroupConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .help("Path to the file recording the nondeterministic inputs provided by the devices to the guest")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("worker-pool")
+                .long("worker-pool")
+                .help(config::WorkerPoolConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("clock")
+                .long("clock")
+                .help(config::ClockConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("metadata")
+                .long("metadata")
+                .help(config::MetadataConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help(
+                    "Path to a JSON file holding the whole VM configuration, \
+                    in the format of the vm.create API request",
+                )
+                .takes_value(true)
+                .conflicts_with("restore"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -289,6 +416,31 @@ fn create_app<'a, 'b>(
                 .help("Sets the level of debugging output")
                 .group("logging"),
         )
+        .arg(
+            Arg::with_name("version")
+                .short("V")
+                .long("version")
+                .help(
+                    "Prints version information, along with the features built in and \
+                    what the host supports when combined with -v",
+                ),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .requires("version")
+                .help("Prints the detailed version information in JSON"),
+        )
+        .arg(
+            Arg::with_name("log-filter")
+                .long("log-filter")
+                .help(
+                    "Log level of some modules, overriding the one set with -v: \
+                    <module>=off|error|warn|info|debug|trace,...",
+                )
+                .takes_value(true)
+                .group("logging"),
+        )
         .arg(
             Arg::with_name("log-file")
                 .long("log-file")
@@ -306,6 +458,14 @@ fn create_app<'a, 'b>(
                 .default_value(&api_server_path)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::with_name("audit-log")
+                .long("audit-log")
+                .help("Audit log file, the state-changing API requests are appended to")
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("restore")
                 .long("restore")
@@ -314,6 +474,14 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .help(config::MetricsConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("seccomp")
                 .long("seccomp")
@@ -332,6 +500,44 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vm-config"),
         );
+        app = app.arg(
+            Arg::with_name("reserved-memory")
+                .long("reserved-memory")
+                .help(config::ReservedMemoryConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        );
+        app = app.arg(
+            Arg::with_name("platform")
+                .long("platform")
+                .help(config::PlatformConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        );
+    }
+
+    #[cfg(feature = "otlp")]
+    {
+        app = app.arg(
+            Arg::with_name("otlp")
+                .long("otlp")
+                .help(config::OtlpConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        );
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        app = app.arg(
+            Arg::with_name("efi-vars")
+                .long("efi-vars")
+                .help("Path to the file backing the UEFI variable store flash")
+                .takes_value(true)
+                .group("vm-config"),
+        );
     }
 
     app
@@ -339,7 +545,9 @@ fn create_app<'a, 'b>(
 
 fn start_vmm(cmd_arguments: ArgMatches, api_socket_path: &str) -> Result<(), Error> {
     let (api_request_sender, api_request_receiver) = channel();
-    let api_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateAPIEventFd)?;
+    // Each API request is signaled on its own, the requests being sent
+    // concurrently by the HTTP workers.
+    let api_evt = EventFd::new(EFD_NONBLOCK | EFD_SEMAPHORE).map_err(Error::CreateAPIEventFd)?;
 
     let http_sender = api_request_sender.clone();
     let seccomp_action = if let Some(seccomp_value) = cmd_arguments.value_of("seccomp") {
@@ -355,6 +563,20 @@ fn start_vmm(cmd_arguments: ArgMatches, api_socket_path: &str) -> Result<(), Err
     } else {
         SeccompAction::Trap
     };
+    let metrics_config = cmd_arguments
+        .value_of("metrics")
+        .map(config::MetricsConfig::parse)
+        .transpose()
+        .map_err(Error::ParsingMetrics)?;
+    if let Some(audit_log_path) = cmd_arguments.value_of("audit-log") {
+        vmm::api::audit::open_audit_log(Path::new(audit_log_path)).map_err(Error::OpenAuditLog)?;
+    }
+    #[cfg(feature = "otlp")]
+    let otlp_config = cmd_arguments
+        .value_of("otlp")
+        .map(config::OtlpConfig::parse)
+        .transpose()
+        .map_err(Error::ParsingOtlp)?;
     let hypervisor = hypervisor::new().map_err(Error::CreateHypervisor)?;
     let vmm_thread = vmm::start_vmm_thread(
         env!("CARGO_PKG_VERSION").to_string(),
@@ -367,12 +589,42 @@ fn start_vmm(cmd_arguments: ArgMatches, api_socket_path: &str) -> Result<(), Err
     )
     .map_err(Error::StartVMMThread)?;
 
+    if let Some(metrics_config) = metrics_config {
+        vmm::metrics::start_metrics_thread(
+            &metrics_config,
+            api_evt.try_clone().unwrap(),
+            api_request_sender.clone(),
+            &seccomp_action,
+        )
+        .map_err(Error::StartMetricsThread)?;
+    }
+
+    #[cfg(feature = "otlp")]
+    {
+        if let Some(otlp_config) = otlp_config {
+            vmm::otlp::start_otlp_thread(&otlp_config, &seccomp_action)
+                .map_err(Error::StartOtlpThread)?;
+        }
+    }
+
     // Can't test for "vm-config" group as some have default values. The kernel
-    // is the only required option for booting the VM.
-    if cmd_arguments.is_present("kernel") {
+    // is the only required option for booting the VM, unless the whole
+    // configuration comes from a file.
+    let vm_config = if let Some(config_path) = cmd_arguments.value_of("config") {
+        // Only the options explicitly given are counted as occurrences of the
+        // group, not the ones taking their default value.
+        if cmd_arguments.occurrences_of("vm-config") > 0 {
+            return Err(Error::ConfigConflict);
+        }
+        Some(config::VmConfig::from_file(Path::new(config_path)).map_err(Error::ParsingConfig)?)
+    } else if cmd_arguments.is_present("kernel") {
         let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
-        let vm_config = config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?;
+        Some(config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?)
+    } else {
+        None
+    };
 
+    if let Some(vm_config) = vm_config {
         println!(
             "Cloud Hypervisor Guest\n\tAPI server: {}\n\tvCPUs: {}\n\tMemory: {} MB\n\tKernel: \
              {:?}\n\tInitramfs: {:?}\n\tKernel cmdline: {}\n\tDisk(s): {:?}",
@@ -409,6 +661,19 @@ fn start_vmm(cmd_arguments: ArgMatches, api_socket_path: &str) -> Result<(), Err
         .map_err(Error::VmmThread)
 }
 
+fn print_version(cmd_arguments: &ArgMatches) {
+    // 'BUILT_VERSION' may end with the newline of the git output.
+    let version = env!("BUILT_VERSION").trim();
+    if cmd_arguments.is_present("json") {
+        let version_info = vmm::version::VersionInfo::new(version);
+        println!("{}", serde_json::to_string_pretty(&version_info).unwrap());
+    } else if cmd_arguments.is_present("v") {
+        print!("{}", vmm::version::VersionInfo::new(version));
+    } else {
+        println!("cloud-hypervisor {}", version);
+    }
+}
+
 fn main() {
     // Ensure all created files (.e.g sockets) are only accessible by this user
     let _ = unsafe { libc::umask(0o077) };
@@ -439,6 +704,11 @@ fn main() {
     )
     .get_matches();
 
+    if cmd_arguments.is_present("version") {
+        print_version(&cmd_arguments);
+        return;
+    }
+
     let log_level = match cmd_arguments.occurrences_of("v") {
         0 => LevelFilter::Warn,
         1 => LevelFilter::Info,
@@ -455,12 +725,28 @@ fn main() {
             Box::new(std::io::stderr())
         };
 
-    log::set_boxed_logger(Box::new(Logger {
+    let module_levels = match cmd_arguments
+        .value_of("log-filter")
+        .map(parse_log_filter)
+        .transpose()
+    {
+        Ok(module_levels) => module_levels.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let logger = Logger {
         output: Mutex::new(log_file),
         start: std::time::Instant::now(),
-    }))
-    .map(|()| log::set_max_level(log_level))
-    .expect("Expected to be able to setup logger");
+        level: log_level,
+        module_levels,
+    };
+    let max_level = logger.max_level();
+    log::set_boxed_logger(Box::new(logger))
+        .map(|()| log::set_max_level(max_level))
+        .expect("Expected to be able to setup logger");
 
     let api_socket_path = cmd_arguments
         .value_of("api-socket")
@@ -482,11 +768,13 @@ extern crate credibility;
 #[cfg(test)]
 mod unit_tests {
     use crate::config::HotplugMethod;
-    use crate::{create_app, prepare_default_values};
+    use crate::{create_app, parse_log_filter, prepare_default_values, Logger};
+    use log::LevelFilter;
     use std::path::PathBuf;
+    use std::sync::Mutex;
     use vmm::config::{
         CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, KernelConfig, MemoryConfig,
-        RngConfig, VmConfig, VmParams,
+        OnCrashAction, RngConfig, VmConfig, VmParams,
     };
 
     fn get_vm_config_from_vec(args: &[&str]) -> VmConfig {
@@ -544,6 +832,7 @@ mod unit_tests {
                     topology: None,
                     kvm_hyperv: false,
                     max_phys_bits: None,
+                    affinity: None,
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
@@ -554,6 +843,7 @@ mod unit_tests {
                     shared: false,
                     hugepages: false,
                     zones: None,
+                    crashkernel: None,
                 },
                 kernel: Some(KernelConfig {
                     path: PathBuf::from("/path/to/kernel"),
@@ -567,6 +857,7 @@ mod unit_tests {
                 rng: RngConfig {
                     src: PathBuf::from("/dev/urandom"),
                     iommu: false,
+                    rate_limit: None,
                 },
                 balloon: None,
                 fs: None,
@@ -575,19 +866,37 @@ mod unit_tests {
                     file: None,
                     mode: ConsoleOutputMode::Null,
                     iommu: false,
+                    max_size: None,
+                    rotate: None,
                 },
                 console: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Tty,
                     iommu: false,
+                    max_size: None,
+                    rotate: None,
                 },
+                console_ports: None,
                 devices: None,
                 vsock: None,
                 iommu: false,
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc: None,
+                #[cfg(target_arch = "x86_64")]
+                reserved_memory: None,
+                #[cfg(target_arch = "x86_64")]
+                platform: None,
                 numa: None,
+                numa_auto: false,
                 watchdog: false,
+                on_crash: OnCrashAction::Reboot,
+                resource_group: None,
+                record: None,
+                worker_pool: None,
+                clock: None,
+                metadata: None,
+                #[cfg(target_arch = "aarch64")]
+                efi_vars: None,
             };
 
             aver_eq!(tb, expected_vm_config, result_vm_config);
@@ -1285,6 +1594,22 @@ mod unit_tests {
                 }"#,
                 false,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--pmem",
+                    "file=/path/to/img/1,size=1G,nvdimm=on",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "pmem": [
+                        {"file": "/path/to/img/1", "size": 1073741824, "nvdimm": true}
+                    ]
+                }"#,
+                true,
+            ),
             #[cfg(target_arch = "x86_64")]
             (
                 vec![
@@ -1554,4 +1879,326 @@ mod unit_tests {
             compare_vm_config_cli_vs_json(cli, openapi, *equal);
         });
     }
+
+    #[test]
+    fn test_valid_vm_config_on_crash() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--on-crash",
+                    "pause",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "on_crash": "Pause"
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--on-crash",
+                    "exit",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_numa_auto() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--numa",
+                    "auto",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "numa_auto": true
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--numa",
+                    "guest_numa_id=0",
+                    "auto",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "numa": [{"guest_numa_id": 0}],
+                    "numa_auto": true
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_resource_group() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--resource-group",
+                    "name=vm0,cpu_quota=200,memory_max=1G",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "resource_group": {"name": "vm0", "cpu_quota": 200, "memory_max": 1073741824}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--resource-group",
+                    "name=vm0,io_weight=100",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "resource_group": {"name": "vm0"}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_record() {
+        vec![(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--record",
+                "/path/to/record",
+            ],
+            r#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "record": {"path": "/path/to/record"}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_worker_pool() {
+        vec![(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--worker-pool",
+                "threads=2",
+            ],
+            r#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "worker_pool": {"threads": 2}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_clock() {
+        vec![(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--console-port",
+                "name=org.qemu.guest_agent.0,socket=/tmp/qga.sock",
+                "--clock",
+                "guest_agent=org.qemu.guest_agent.0",
+            ],
+            r#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "console_ports": [{"name": "org.qemu.guest_agent.0", "mode": "Socket", "socket": "/tmp/qga.sock"}],
+                "clock": {"guest_agent": "org.qemu.guest_agent.0"}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_metadata() {
+        vec![(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--metadata",
+                "tap=tap0,instance_id=i-0123456789,hostname=vm-1,user_data=/path/to/user_data",
+            ],
+            r#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "metadata": {"tap": "tap0", "instance_id": "i-0123456789", "hostname": "vm-1", "user_data": "/path/to/user_data"}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_balloon() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--balloon",
+                    "size=1G",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "balloon": {"size": 1073741824}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--balloon",
+                    "size=1G,policy_min=512M,policy_max=2G",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "balloon": {"size": 1073741824, "policy_min": 536870912, "policy_max": 2147483648}
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_valid_vm_config_platform() {
+        vec![(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--platform",
+                "serial_number=abc123,uuid=4f1c3a86-2b1d-4c7e-9a8f-0d6e5b3c2a10",
+            ],
+            r#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "platform": {"serial_number": "abc123", "uuid": "4f1c3a86-2b1d-4c7e-9a8f-0d6e5b3c2a10"}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_log_filter() {
+        assert!(parse_log_filter("vmm=foo").is_err());
+        assert!(parse_log_filter("=debug").is_err());
+        assert!(parse_log_filter("vmm").is_err());
+
+        let logger = Logger {
+            output: Mutex::new(Box::new(std::io::sink())),
+            start: std::time::Instant::now(),
+            level: LevelFilter::Warn,
+            module_levels: parse_log_filter(
+                "vmm=debug,vmm::device_manager=trace,virtio_devices=off",
+            )
+            .unwrap(),
+        };
+        assert_eq!(logger.max_level(), LevelFilter::Trace);
+        assert_eq!(logger.level("vmm"), LevelFilter::Debug);
+        assert_eq!(logger.level("vmm::cpu"), LevelFilter::Debug);
+        assert_eq!(logger.level("vmm::device_manager"), LevelFilter::Trace);
+        assert_eq!(logger.level("virtio_devices::net"), LevelFilter::Off);
+        assert_eq!(logger.level("vmm_sys_util"), LevelFilter::Warn);
+        assert_eq!(logger.level("devices"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_config_conflicts() {
+        let (default_vcpus, default_memory, default_rng) = prepare_default_values();
+        let matches = |args: &[&str]| {
+            create_app(&default_vcpus, &default_memory, &default_rng, "")
+                .get_matches_from(args)
+                .occurrences_of("vm-config")
+        };
+
+        // The VM options taking their default value don't count.
+        assert_eq!(matches(&["cloud-hypervisor", "--config", "vm.json"]), 0);
+        assert!(
+            matches(&[
+                "cloud-hypervisor",
+                "--config",
+                "vm.json",
+                "--disk",
+                "path=/path/to/disk.img",
+            ]) > 0
+        );
+        assert!(
+            matches(&[
+                "cloud-hypervisor",
+                "--config",
+                "vm.json",
+                "--cpus",
+                "boot=2"
+            ]) > 0
+        );
+    }
 }
@@ -0,0 +1,182 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Minimal vhost-user-net backend looping every frame transmitted by the guest
+// back to its receive queue. It is meant as a starting point for writing a
+// custom network backend against the vhost_user_backend crate.
+//
+// Usage: loopback_net <socket_path>
+
+use std::io;
+use std::process;
+use std::result;
+use std::sync::{Arc, RwLock};
+use vhost_rs::vhost_user::message::*;
+use vhost_rs::vhost_user::Listener;
+use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring};
+use virtio_bindings::bindings::virtio_net::*;
+use vm_memory::{Bytes, GuestMemoryMmap};
+
+const QUEUE_SIZE: usize = 256;
+const NUM_QUEUES: usize = 2;
+const RX_QUEUE_EVENT: u16 = 0;
+const TX_QUEUE_EVENT: u16 = 1;
+// Offset of the num_buffers field in the virtio_net_hdr_mrg_rxbuf header.
+const NUM_BUFFERS_OFFSET: usize = 10;
+
+struct LoopbackNetBackend {
+    mem: Option<GuestMemoryMmap>,
+}
+
+impl LoopbackNetBackend {
+    // Copy a frame, including its virtio-net header, into the next buffer
+    // available on the receive queue. Returns false if the guest did not
+    // provide any receive buffer, in which case the frame is dropped.
+    fn deliver_frame(
+        &self,
+        mem: &GuestMemoryMmap,
+        rx_vring: &mut Vring,
+        frame: &mut [u8],
+    ) -> io::Result<bool> {
+        let head = match rx_vring.mut_queue().iter(mem).next() {
+            Some(head) => head,
+            None => return Ok(false),
+        };
+
+        if frame.len() > NUM_BUFFERS_OFFSET + 1 {
+            frame[NUM_BUFFERS_OFFSET..NUM_BUFFERS_OFFSET + 2].copy_from_slice(&1u16.to_le_bytes());
+        }
+
+        let mut written = 0;
+        let mut desc = Some(head.clone());
+        while let Some(d) = desc {
+            if written == frame.len() {
+                break;
+            }
+            if d.is_write_only() {
+                let len = std::cmp::min(d.len as usize, frame.len() - written);
+                mem.write_slice(&frame[written..written + len], d.addr)
+                    .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+                written += len;
+            }
+            desc = d.next_descriptor();
+        }
+
+        rx_vring
+            .mut_queue()
+            .add_used(mem, head.index, written as u32);
+
+        Ok(true)
+    }
+
+    fn process_tx(&self, rx_vring: &mut Vring, tx_vring: &mut Vring) -> io::Result<()> {
+        let mem = match self.mem.as_ref() {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+
+        let mut delivered = false;
+        let mut transmitted = false;
+        while let Some(head) = tx_vring.mut_queue().iter(mem).next() {
+            let mut frame = Vec::new();
+            let mut desc = Some(head.clone());
+            while let Some(d) = desc {
+                if !d.is_write_only() {
+                    let mut buf = vec![0u8; d.len as usize];
+                    mem.read_slice(&mut buf, d.addr)
+                        .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+                    frame.extend_from_slice(&buf);
+                }
+                desc = d.next_descriptor();
+            }
+
+            delivered |= self.deliver_frame(mem, rx_vring, &mut frame)?;
+            tx_vring.mut_queue().add_used(mem, head.index, 0);
+            transmitted = true;
+        }
+
+        if transmitted {
+            tx_vring.signal_used_queue()?;
+        }
+        if delivered {
+            rx_vring.signal_used_queue()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VhostUserBackend for LoopbackNetBackend {
+    fn num_queues(&self) -> usize {
+        NUM_QUEUES
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::MQ
+    }
+
+    fn set_event_idx(&mut self, _enabled: bool) {}
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> result::Result<(), io::Error> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        evset: epoll::Events,
+        vrings: &[Arc<RwLock<Vring>>],
+        _thread_id: usize,
+    ) -> result::Result<bool, io::Error> {
+        if evset != epoll::Events::EPOLLIN {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        match device_event {
+            // Frames are dropped when no receive buffer is available, hence
+            // there is nothing pending when the guest provides new ones.
+            RX_QUEUE_EVENT => Ok(false),
+            TX_QUEUE_EVENT => {
+                let mut rx_vring = vrings[0].write().unwrap();
+                let mut tx_vring = vrings[1].write().unwrap();
+                self.process_tx(&mut rx_vring, &mut tx_vring)?;
+                Ok(false)
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <socket_path>", args[0]);
+        process::exit(1);
+    }
+
+    let backend = Arc::new(RwLock::new(LoopbackNetBackend { mem: None }));
+
+    let listener = Listener::new(&args[1], true).unwrap();
+    let mut daemon = VhostUserDaemon::new("loopback-net-backend".to_string(), backend).unwrap();
+
+    if let Err(e) = daemon.start(listener) {
+        eprintln!("Failed to start daemon: {:?}", e);
+        process::exit(1);
+    }
+
+    if let Err(e) = daemon.wait() {
+        eprintln!("Error from the main thread: {:?}", e);
+    }
+}
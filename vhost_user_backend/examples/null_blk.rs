@@ -0,0 +1,229 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Minimal vhost-user-blk backend exposing a "null" disk: reads return zeroes
+// and writes are discarded. It is meant as a starting point for writing a
+// custom block backend against the vhost_user_backend crate.
+//
+// Usage: null_blk <socket_path> [size_in_mib]
+
+use std::io;
+use std::num::Wrapping;
+use std::process;
+use std::result;
+use std::sync::{Arc, RwLock};
+use vhost_rs::vhost_user::message::*;
+use vhost_rs::vhost_user::Listener;
+use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring};
+use virtio_bindings::bindings::virtio_blk::*;
+use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+const QUEUE_SIZE: usize = 256;
+const SECTOR_SHIFT: u8 = 9;
+const DEFAULT_SIZE_MIB: u64 = 1024;
+// Size of the request header: type (u32), reserved (u32) and sector (u64).
+const REQUEST_HEADER_SIZE: u32 = 16;
+const NULL_BLK_ID: &[u8] = b"null_blk";
+// Zeroes are written by chunks, not to allocate a buffer of the length
+// provided by the guest.
+static ZEROES: [u8; 4096] = [0u8; 4096];
+
+struct NullBlkBackend {
+    mem: Option<GuestMemoryMmap>,
+    nsectors: u64,
+    event_idx: bool,
+}
+
+impl NullBlkBackend {
+    // Handle a single request, returning the number of bytes written to the
+    // guest memory, including the status byte.
+    fn process_request(
+        &self,
+        mem: &GuestMemoryMmap,
+        head: &vm_virtio::DescriptorChain,
+    ) -> Option<u32> {
+        if head.is_write_only() || head.len < REQUEST_HEADER_SIZE {
+            return None;
+        }
+        let request_type: u32 = mem.read_obj(head.addr).ok()?;
+
+        let mut data_descs = Vec::new();
+        let mut desc = head.next_descriptor();
+        let mut status_addr = None;
+        while let Some(d) = desc {
+            if d.has_next() {
+                data_descs.push((d.addr, d.len, d.is_write_only()));
+            } else {
+                status_addr = Some(d.addr);
+            }
+            desc = d.next_descriptor();
+        }
+        let status_addr: GuestAddress = status_addr?;
+
+        let mut len = 0;
+        let status = match request_type {
+            VIRTIO_BLK_T_IN => {
+                for (addr, data_len, write_only) in data_descs {
+                    if !write_only {
+                        return None;
+                    }
+                    let mut offset = 0;
+                    while offset < data_len {
+                        let chunk_len = std::cmp::min(ZEROES.len() as u32, data_len - offset);
+                        let chunk_addr = addr.checked_add(u64::from(offset))?;
+                        mem.write_slice(&ZEROES[..chunk_len as usize], chunk_addr)
+                            .ok()?;
+                        offset += chunk_len;
+                    }
+                    len += data_len;
+                }
+                VIRTIO_BLK_S_OK
+            }
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_FLUSH => VIRTIO_BLK_S_OK,
+            VIRTIO_BLK_T_GET_ID => {
+                if let Some((addr, data_len, true)) = data_descs.first() {
+                    let id_len = std::cmp::min(NULL_BLK_ID.len(), *data_len as usize);
+                    mem.write_slice(&NULL_BLK_ID[..id_len], *addr).ok()?;
+                    len += id_len as u32;
+                    VIRTIO_BLK_S_OK
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                }
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        mem.write_obj(status as u8, status_addr).ok()?;
+
+        Some(len + 1)
+    }
+
+    fn process_queue(&self, vring: &mut Vring) -> io::Result<bool> {
+        let mem = match self.mem.as_ref() {
+            Some(m) => m,
+            None => return Ok(false),
+        };
+
+        let mut used_any = false;
+        while let Some(head) = vring.mut_queue().iter(mem).next() {
+            let len = self.process_request(mem, &head).unwrap_or_else(|| {
+                eprintln!("Invalid request, descriptor {}", head.index);
+                0
+            });
+
+            let queue = vring.mut_queue();
+            if let Some(used_idx) = queue.add_used(mem, head.index, len) {
+                if !self.event_idx || queue.needs_notification(mem, Wrapping(used_idx)) {
+                    vring.signal_used_queue()?;
+                }
+                used_any = true;
+            }
+        }
+
+        Ok(used_any)
+    }
+}
+
+impl VhostUserBackend for NullBlkBackend {
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        1 << VIRTIO_BLK_F_FLUSH
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_F_VERSION_1
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> result::Result<(), io::Error> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        evset: epoll::Events,
+        vrings: &[Arc<RwLock<Vring>>],
+        _thread_id: usize,
+    ) -> result::Result<bool, io::Error> {
+        if evset != epoll::Events::EPOLLIN || device_event != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut vring = vrings[0].write().unwrap();
+        if self.event_idx {
+            // Keep processing until no new request shows up, as the avail
+            // index is only read once per call to process_queue().
+            loop {
+                vring
+                    .mut_queue()
+                    .update_avail_event(self.mem.as_ref().unwrap());
+                if !self.process_queue(&mut vring)? {
+                    break;
+                }
+            }
+        } else {
+            self.process_queue(&mut vring)?;
+        }
+
+        Ok(false)
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        // Only the capacity, which is the first field of the configuration
+        // space, is relevant for this device.
+        let mut config = vec![0u8; (offset + size) as usize];
+        let capacity = self.nsectors.to_le_bytes();
+        let capacity_len = std::cmp::min(capacity.len(), config.len());
+        config[..capacity_len].copy_from_slice(&capacity[..capacity_len]);
+        config[offset as usize..].to_vec()
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <socket_path> [size_in_mib]", args[0]);
+        process::exit(1);
+    }
+    let size_mib = match args.get(2) {
+        Some(s) => s.parse::<u64>().unwrap_or_else(|e| {
+            eprintln!("Invalid size {}: {}", s, e);
+            process::exit(1);
+        }),
+        None => DEFAULT_SIZE_MIB,
+    };
+
+    let backend = Arc::new(RwLock::new(NullBlkBackend {
+        mem: None,
+        nsectors: (size_mib << 20) >> SECTOR_SHIFT,
+        event_idx: false,
+    }));
+
+    let listener = Listener::new(&args[1], true).unwrap();
+    let mut daemon = VhostUserDaemon::new("null-blk-backend".to_string(), backend).unwrap();
+
+    if let Err(e) = daemon.start(listener) {
+        eprintln!("Failed to start daemon: {:?}", e);
+        process::exit(1);
+    }
+
+    if let Err(e) = daemon.wait() {
+        eprintln!("Error from the main thread: {:?}", e);
+    }
+}
@@ -13,11 +13,15 @@ extern crate log;
 extern crate vhost_rs;
 extern crate vhost_user_backend;
 
-use block_util::{build_disk_image_id, Request, VirtioBlockConfig};
+use block_util::{
+    block_io_uring_is_supported, build_disk_image_id, Request, RequestType, VirtioBlockConfig,
+};
+use io_uring::IoUring;
 use libc::EFD_NONBLOCK;
 use log::*;
 use option_parser::{OptionParser, OptionParserError, Toggle};
 use qcow::{self, ImageType, QcowFile};
+use std::collections::HashMap;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
@@ -25,6 +29,7 @@ use std::io::{Seek, SeekFrom, Write};
 use std::num::Wrapping;
 use std::ops::DerefMut;
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::process;
 use std::result;
@@ -49,6 +54,12 @@ const BLK_SIZE: u32 = 512;
 // Polling for 50us should be enough to cover for the device latency
 // and the overhead of the emulation layer.
 const POLL_QUEUE_US: u128 = 50;
+// Event triggered when the queue has been kicked by the guest.
+const QUEUE_AVAIL_EVENT: u16 = 0;
+// Event triggered to stop the worker thread.
+const KILL_EVENT: u16 = 1;
+// Event triggered when asynchronous requests have completed.
+const IO_URING_EVENT: u16 = 2;
 
 trait DiskFile: Read + Seek + Write + Send + Sync {}
 impl<D: Read + Seek + Write + Send + Sync> DiskFile for D {}
@@ -60,6 +71,12 @@ type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
 enum Error {
     /// Failed to create kill eventfd
     CreateKillEventFd(io::Error),
+    /// Failed to create io_uring eventfd
+    CreateIoUringEventFd(io::Error),
+    /// Failed to create io_uring instance
+    CreateIoUring(io::Error),
+    /// Failed to register io_uring eventfd
+    RegisterIoUringEventFd(io::Error),
     /// Failed to parse configuration string
     FailedConfigParse(OptionParserError),
     /// Failed to handle event other than input event.
@@ -75,7 +92,7 @@ enum Error {
 pub const SYNTAX: &str = "vhost-user-block backend parameters \
  \"path=<image_path>,socket=<socket_path>,num_queues=<number_of_queues>,\
  queue_size=<size_of_each_queue>,readonly=true|false,direct=true|false,\
- poll_queue=true|false\"";
+ poll_queue=true|false,io_uring=true|false\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -99,6 +116,11 @@ struct VhostUserBlkThread {
     event_idx: bool,
     kill_evt: EventFd,
     writeback: Arc<AtomicBool>,
+    // Only set when requests are processed asynchronously through io_uring.
+    io_uring: Option<IoUring>,
+    io_uring_evt: EventFd,
+    disk_image_fd: RawFd,
+    request_list: HashMap<u16, Request>,
 }
 
 impl VhostUserBlkThread {
@@ -107,7 +129,20 @@ impl VhostUserBlkThread {
         disk_image_id: Vec<u8>,
         disk_nsectors: u64,
         writeback: Arc<AtomicBool>,
+        io_uring: Option<(RawFd, u32)>,
     ) -> Result<Self> {
+        let io_uring_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateIoUringEventFd)?;
+        let (io_uring, disk_image_fd) = if let Some((disk_image_fd, queue_size)) = io_uring {
+            let io_uring = IoUring::new(queue_size).map_err(Error::CreateIoUring)?;
+            io_uring
+                .submitter()
+                .register_eventfd(io_uring_evt.as_raw_fd())
+                .map_err(Error::RegisterIoUringEventFd)?;
+            (Some(io_uring), disk_image_fd)
+        } else {
+            (None, -1)
+        };
+
         Ok(VhostUserBlkThread {
             mem: None,
             disk_image,
@@ -116,10 +151,153 @@ impl VhostUserBlkThread {
             event_idx: false,
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
             writeback,
+            io_uring,
+            io_uring_evt,
+            disk_image_fd,
+            request_list: HashMap::new(),
         })
     }
 
+    // Put the descriptor back onto the used ring, and notify the guest if
+    // needed. Returns true if the descriptor has been returned.
+    fn return_descriptor(
+        mem: &GuestMemoryMmap,
+        event_idx: bool,
+        vring: &mut Vring,
+        desc_index: u16,
+        len: u32,
+    ) -> bool {
+        if event_idx {
+            let queue = vring.mut_queue();
+            if let Some(used_idx) = queue.add_used(mem, desc_index, len) {
+                if queue.needs_notification(&mem, Wrapping(used_idx)) {
+                    debug!("signalling queue");
+                    vring.signal_used_queue().unwrap();
+                } else {
+                    debug!("omitting signal (event_idx)");
+                }
+                return true;
+            }
+            false
+        } else {
+            debug!("signalling queue");
+            vring.mut_queue().add_used(mem, desc_index, len);
+            vring.signal_used_queue().unwrap();
+            true
+        }
+    }
+
+    // Submit the pending requests to the io_uring instance. Requests which
+    // don't need any I/O are completed right away.
+    fn process_queue_submit(&mut self, vring: &mut Vring) -> bool {
+        let mut processed_any = false;
+        let mem = match self.mem.as_ref() {
+            Some(m) => m,
+            None => return false,
+        };
+        let io_uring = match self.io_uring.as_mut() {
+            Some(io_uring) => io_uring,
+            None => return false,
+        };
+
+        while let Some(head) = vring.mut_queue().iter(mem).next() {
+            processed_any = true;
+            let len = match Request::parse(&head, mem) {
+                Ok(mut request) => {
+                    request.set_writeback(self.writeback.load(Ordering::Acquire));
+                    match request.execute_io_uring(
+                        mem,
+                        io_uring,
+                        self.disk_nsectors,
+                        self.disk_image_fd,
+                        &self.disk_image_id,
+                        head.index as u64,
+                    ) {
+                        Ok(true) => {
+                            // The descriptor will be returned on completion.
+                            self.request_list.insert(head.index, request);
+                            continue;
+                        }
+                        Ok(false) => {
+                            mem.write_obj(VIRTIO_BLK_S_OK, request.status_addr).unwrap();
+                            0
+                        }
+                        Err(e) => {
+                            mem.write_obj(e.status(), request.status_addr).unwrap();
+                            1
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("failed to parse available descriptor chain: {:?}", err);
+                    0
+                }
+            };
+
+            Self::return_descriptor(mem, self.event_idx, vring, head.index, len);
+        }
+
+        processed_any
+    }
+
+    // Return the descriptors associated with the completed requests.
+    fn process_queue_complete(&mut self, vring: &mut Vring) -> bool {
+        let mut used_any = false;
+        let mem = match self.mem.as_ref() {
+            Some(m) => m,
+            None => return false,
+        };
+        let io_uring = match self.io_uring.as_mut() {
+            Some(io_uring) => io_uring,
+            None => return false,
+        };
+
+        let cq = io_uring.completion();
+        for cq_entry in cq.available() {
+            let result = cq_entry.result();
+            let desc_index = cq_entry.user_data() as u16;
+            let request = match self.request_list.remove(&desc_index) {
+                Some(request) => request,
+                None => {
+                    error!("Missing entry for completed request {}", desc_index);
+                    continue;
+                }
+            };
+
+            let (status, len) = if result >= 0 {
+                match request.request_type {
+                    RequestType::In => (VIRTIO_BLK_S_OK, result as u32),
+                    RequestType::Out => {
+                        if !request.writeback {
+                            unsafe { libc::fsync(self.disk_image_fd) };
+                        }
+                        (VIRTIO_BLK_S_OK, 0)
+                    }
+                    _ => (VIRTIO_BLK_S_OK, 0),
+                }
+            } else {
+                error!(
+                    "Request failed: {:?}",
+                    io::Error::from_raw_os_error(-result)
+                );
+                (VIRTIO_BLK_S_IOERR, 1)
+            };
+
+            // We use unwrap because the request parsing process already
+            // checked that the status_addr was valid.
+            mem.write_obj(status, request.status_addr).unwrap();
+
+            used_any |= Self::return_descriptor(mem, self.event_idx, vring, desc_index, len);
+        }
+
+        used_any
+    }
+
     fn process_queue(&mut self, vring: &mut Vring) -> bool {
+        if self.io_uring.is_some() {
+            return self.process_queue_submit(vring);
+        }
+
         let mut used_any = false;
         let mem = match self.mem.as_ref() {
             Some(m) => m,
@@ -156,23 +334,7 @@ impl VhostUserBlkThread {
                 }
             }
 
-            if self.event_idx {
-                let queue = vring.mut_queue();
-                if let Some(used_idx) = queue.add_used(mem, head.index, len) {
-                    if queue.needs_notification(&mem, Wrapping(used_idx)) {
-                        debug!("signalling queue");
-                        vring.signal_used_queue().unwrap();
-                    } else {
-                        debug!("omitting signal (event_idx)");
-                    }
-                    used_any = true;
-                }
-            } else {
-                debug!("signalling queue");
-                vring.mut_queue().add_used(mem, head.index, len);
-                vring.signal_used_queue().unwrap();
-                used_any = true;
-            }
+            used_any |= Self::return_descriptor(mem, self.event_idx, vring, head.index, len);
         }
 
         used_any
@@ -198,6 +360,7 @@ impl VhostUserBlkBackend {
         direct: bool,
         poll_queue: bool,
         queue_size: usize,
+        io_uring: bool,
     ) -> Result<Self> {
         let mut options = OpenOptions::new();
         options.read(true);
@@ -206,10 +369,18 @@ impl VhostUserBlkBackend {
             options.custom_flags(libc::O_DIRECT);
         }
         let image: File = options.open(&image_path).unwrap();
+        let image_fd = image.as_raw_fd();
         let mut raw_img: qcow::RawFile = qcow::RawFile::new(image, direct);
 
         let image_id = build_disk_image_id(&PathBuf::from(&image_path));
         let image_type = qcow::detect_image_type(&mut raw_img).unwrap();
+        // Asynchronous I/O through io_uring is only possible when the image
+        // is accessed directly, which is the case for raw images.
+        let io_uring =
+            io_uring && matches!(image_type, ImageType::Raw) && block_io_uring_is_supported();
+        if io_uring {
+            info!("Using io_uring for asynchronous I/O");
+        }
         let image = match image_type {
             ImageType::Raw => Arc::new(Mutex::new(raw_img)) as Arc<Mutex<dyn DiskFile>>,
             ImageType::Qcow2 => {
@@ -238,6 +409,11 @@ impl VhostUserBlkBackend {
                 image_id.clone(),
                 nsectors,
                 writeback.clone(),
+                if io_uring {
+                    Some((image_fd, queue_size as u32))
+                } else {
+                    None
+                },
             )?);
             threads.push(thread);
             queues_per_thread.push(0b1 << i);
@@ -337,7 +513,7 @@ impl VhostUserBackend for VhostUserBlkBackend {
 
         let mut thread = self.threads[thread_id].lock().unwrap();
         match device_event {
-            0 => {
+            QUEUE_AVAIL_EVENT => {
                 let mut vring = vrings[0].write().unwrap();
 
                 if self.poll_queue {
@@ -373,6 +549,12 @@ impl VhostUserBackend for VhostUserBlkBackend {
 
                 Ok(false)
             }
+            IO_URING_EVENT => {
+                thread.io_uring_evt.read()?;
+                let mut vring = vrings[0].write().unwrap();
+                thread.process_queue_complete(&mut vring);
+                Ok(false)
+            }
             _ => Err(Error::HandleEventUnknownEvent.into()),
         }
     }
@@ -404,7 +586,7 @@ impl VhostUserBackend for VhostUserBlkBackend {
                 .kill_evt
                 .try_clone()
                 .unwrap(),
-            Some(1),
+            Some(KILL_EVENT),
         ))
     }
 
@@ -421,6 +603,7 @@ struct VhostUserBlkBackendConfig {
     readonly: bool,
     direct: bool,
     poll_queue: bool,
+    io_uring: bool,
 }
 
 impl VhostUserBlkBackendConfig {
@@ -433,7 +616,8 @@ impl VhostUserBlkBackendConfig {
             .add("num_queues")
             .add("queue_size")
             .add("socket")
-            .add("poll_queue");
+            .add("poll_queue")
+            .add("io_uring");
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
         let path = parser.get("path").ok_or(Error::PathParameterMissing)?;
@@ -457,6 +641,11 @@ impl VhostUserBlkBackendConfig {
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(Toggle(true))
             .0;
+        let io_uring = parser
+            .convert::<Toggle>("io_uring")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(Toggle(true))
+            .0;
         let queue_size = parser
             .convert("queue_size")
             .map_err(Error::FailedConfigParse)?
@@ -470,6 +659,7 @@ impl VhostUserBlkBackendConfig {
             direct,
             poll_queue,
             queue_size,
+            io_uring,
         })
     }
 }
@@ -491,6 +681,7 @@ pub fn start_block_backend(backend_command: &str) {
             backend_config.direct,
             backend_config.poll_queue,
             backend_config.queue_size,
+            backend_config.io_uring,
         )
         .unwrap(),
     ));
@@ -504,6 +695,27 @@ pub fn start_block_backend(backend_command: &str) {
 
     debug!("blk_daemon is created!\n");
 
+    let vring_workers = blk_daemon.get_vring_workers();
+    for (thread, worker) in blk_backend
+        .read()
+        .unwrap()
+        .threads
+        .iter()
+        .zip(vring_workers.iter())
+    {
+        let thread = thread.lock().unwrap();
+        if thread.io_uring.is_some() {
+            if let Err(e) = worker.register_listener(
+                thread.io_uring_evt.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(IO_URING_EVENT),
+            ) {
+                error!("Failed to register io_uring eventfd: {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
     if let Err(e) = blk_daemon.start(listener) {
         error!(
             "Failed to start daemon for vhost-user-block with error: {:?}\n",
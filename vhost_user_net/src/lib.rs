@@ -74,7 +74,8 @@ pub enum Error {
 
 pub const SYNTAX: &str = "vhost-user-net backend parameters \
 \"ip=<ip_addr>,mask=<net_mask>,socket=<socket_path>,\
-num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>\"";
+num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>,\
+host_mac=<host_mac_address>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -111,6 +112,7 @@ impl VhostUserNetThread {
                 epoll_fd: None,
                 counters: NetCounters::default(),
                 tap_event_id: 2,
+                recorder: None,
             },
         })
     }
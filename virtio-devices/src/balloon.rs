@@ -17,12 +17,14 @@ use super::{
     VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::vm_memory::GuestMemory;
+use crate::vm_memory::{GuestMemory, GuestMemoryRegion};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::HashMap;
 use std::io;
 use std::mem::size_of;
+use std::num::Wrapping;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
@@ -60,6 +62,8 @@ pub enum Error {
     InvalidRequest,
     // Madvise fail.
     MadviseFail(std::io::Error),
+    // Fallocate fail.
+    FallocateFail(std::io::Error),
     // Failed to EventFd write.
     EventFdWriteFail(std::io::Error),
     // Failed to EventFd try_clone.
@@ -140,6 +144,67 @@ impl VirtioBalloonResize {
     }
 }
 
+#[derive(Default, Clone)]
+pub struct BalloonCounters {
+    reclaimed_bytes: Arc<AtomicU64>,
+}
+
+// Release the host memory backing a page the guest put in the balloon.
+// Anonymous memory is lazily freed, letting the host kernel reclaim it when
+// needed. The pages of a shared file mapping would not be released by
+// madvise(), hence the hole punched into the file. A private file mapping
+// only needs its private copies of the pages to be dropped, as punching the
+// file would discard the content of the file itself, not the guest memory.
+fn release_page(mem: &GuestMemoryMmap, gpa: GuestAddress) -> result::Result<(), Error> {
+    let page_size = 1u64 << VIRTIO_BALLOON_PFN_SHIFT;
+    let region = mem.find_region(gpa).ok_or(Error::InvalidRequest)?;
+    let offset = gpa.0 - region.start_addr().0;
+    if offset + page_size > region.len() {
+        return Err(Error::InvalidRequest);
+    }
+    let hva = mem
+        .get_host_address(gpa)
+        .map_err(|_| Error::InvalidRequest)?;
+
+    let shared = region.flags() & libc::MAP_SHARED == libc::MAP_SHARED;
+    if let (Some(file_offset), true) = (region.file_offset(), shared) {
+        // Need unsafe to do syscall fallocate
+        let res = unsafe {
+            libc::fallocate64(
+                file_offset.file().as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (file_offset.start() + offset) as libc::off64_t,
+                page_size as libc::off64_t,
+            )
+        };
+        if res != 0 {
+            return Err(Error::FallocateFail(io::Error::last_os_error()));
+        }
+    }
+
+    let madvise = |advice| {
+        // Need unsafe to do syscall madvise
+        let res =
+            unsafe { libc::madvise(hva as *mut libc::c_void, page_size as libc::size_t, advice) };
+        if res != 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    };
+
+    if region.file_offset().is_some() {
+        return madvise(libc::MADV_DONTNEED).map_err(Error::MadviseFail);
+    }
+    // MADV_FREE is not available before Linux 4.5.
+    match madvise(libc::MADV_FREE) {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            madvise(libc::MADV_DONTNEED).map_err(Error::MadviseFail)
+        }
+        r => r.map_err(Error::MadviseFail),
+    }
+}
+
 struct BalloonEpollHandler {
     config: Arc<Mutex<VirtioBalloonConfig>>,
     resize_receiver: VirtioBalloonResizeReceiver,
@@ -150,6 +215,7 @@ struct BalloonEpollHandler {
     deflate_queue_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    counters: BalloonCounters,
 }
 
 impl BalloonEpollHandler {
@@ -197,18 +263,21 @@ impl BalloonEpollHandler {
                 offset += data_chunk_size as u64;
 
                 let gpa = (pfn as u64) << VIRTIO_BALLOON_PFN_SHIFT;
-                if let Ok(hva) = mem.get_host_address(GuestAddress(gpa)) {
-                    let advice = match ev_type {
-                        INFLATE_QUEUE_EVENT => libc::MADV_DONTNEED,
-                        DEFLATE_QUEUE_EVENT => libc::MADV_WILLNEED,
-                        _ => return Err(Error::ProcessQueueWrongEvType(ev_type)),
-                    };
+                if ev_type == INFLATE_QUEUE_EVENT {
+                    if let Err(e) = release_page(&mem, GuestAddress(gpa)) {
+                        error!("Failed releasing page at 0x{:x}: {:?}", gpa, e);
+                        return Err(e);
+                    }
+                    self.counters
+                        .reclaimed_bytes
+                        .fetch_add(1 << VIRTIO_BALLOON_PFN_SHIFT, Ordering::AcqRel);
+                } else if let Ok(hva) = mem.get_host_address(GuestAddress(gpa)) {
                     // Need unsafe to do syscall madvise
                     let res = unsafe {
                         libc::madvise(
                             hva as *mut libc::c_void,
                             (1 << VIRTIO_BALLOON_PFN_SHIFT) as libc::size_t,
-                            advice,
+                            libc::MADV_WILLNEED,
                         )
                     };
                     if res != 0 {
@@ -314,6 +383,7 @@ pub struct Balloon {
     resize: VirtioBalloonResize,
     config: Arc<Mutex<VirtioBalloonConfig>>,
     seccomp_action: SeccompAction,
+    counters: BalloonCounters,
 }
 
 impl Balloon {
@@ -336,6 +406,7 @@ impl Balloon {
             resize: VirtioBalloonResize::new()?,
             config: Arc::new(Mutex::new(config)),
             seccomp_action,
+            counters: BalloonCounters::default(),
         })
     }
 
@@ -435,6 +506,7 @@ impl VirtioDevice for Balloon {
             deflate_queue_evt: queue_evts.remove(0),
             kill_evt,
             pause_evt,
+            counters: self.counters.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -465,6 +537,17 @@ impl VirtioDevice for Balloon {
     fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
         self.common.reset()
     }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        counters.insert(
+            "reclaimed_bytes",
+            Wrapping(self.counters.reclaimed_bytes.load(Ordering::Acquire)),
+        );
+
+        Some(counters)
+    }
 }
 
 impl Pausable for Balloon {
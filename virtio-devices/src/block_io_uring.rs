@@ -10,13 +10,15 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    set_thread_affinity, ActivateError, ActivateResult, EpollHelper, EpollHelperError,
+    EpollHelperHandler, EpollWorkerPool, InterruptCoalescer, Queue, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
+use crate::coalescing::create_coalescer;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
-use block_util::{build_disk_image_id, Request, RequestType, VirtioBlockConfig};
+use block_util::{build_disk_image_id, build_serial, Request, RequestType, VirtioBlockConfig};
 use io_uring::IoUring;
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
@@ -48,6 +50,8 @@ pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 // New completed tasks are pending on the completion ring.
 const IO_URING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// The interrupt coalescing timer has expired.
+const COALESCING_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 
 #[derive(Debug)]
 pub enum Error {
@@ -104,6 +108,7 @@ struct BlockIoUringEpollHandler {
     io_uring: IoUring,
     io_uring_evt: EventFd,
     request_list: HashMap<u16, Request>,
+    coalescer: Option<InterruptCoalescer>,
 }
 
 impl BlockIoUringEpollHandler {
@@ -226,7 +231,16 @@ impl BlockIoUringEpollHandler {
         Ok(used_count > 0)
     }
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+    fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
+        if let Some(coalescer) = self.coalescer.as_mut() {
+            if !coalescer.add_event() {
+                return Ok(());
+            }
+        }
+        self.trigger_used_queue()
+    }
+
+    fn trigger_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_cb
             .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
             .map_err(|e| {
@@ -235,14 +249,22 @@ impl BlockIoUringEpollHandler {
             })
     }
 
+    fn epoll_helper(&self) -> result::Result<EpollHelper, EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.io_uring_evt.as_raw_fd(), IO_URING_EVENT)?;
+        if let Some(coalescer) = &self.coalescer {
+            helper.add_event(coalescer.as_raw_fd(), COALESCING_TIMER_EVENT)?;
+        }
+        Ok(helper)
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
-        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
-        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
-        helper.add_event(self.io_uring_evt.as_raw_fd(), IO_URING_EVENT)?;
+        let mut helper = self.epoll_helper()?;
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -295,6 +317,22 @@ impl EpollHelperHandler for BlockIoUringEpollHandler {
                     }
                 }
             }
+            COALESCING_TIMER_EVENT => {
+                let pending = match self.coalescer.as_mut().map(|c| c.timer_expired()) {
+                    Some(Ok(pending)) => pending,
+                    Some(Err(e)) => {
+                        error!("Failed to read interrupt coalescing timer: {:?}", e);
+                        return true;
+                    }
+                    None => false,
+                };
+                if pending {
+                    if let Err(e) = self.trigger_used_queue() {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                }
+            }
             _ => {
                 error!("Unexpected event: {}", ev_type);
                 return true;
@@ -315,6 +353,8 @@ pub struct BlockIoUring {
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
     seccomp_action: SeccompAction,
+    interrupt_coalescing: Option<(u32, u64)>,
+    serial: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -388,9 +428,35 @@ impl BlockIoUring {
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
             seccomp_action,
+            interrupt_coalescing: None,
+            serial: None,
         })
     }
 
+    /// Aggregate the interrupts notifying the guest about completed requests,
+    /// injecting one every `max_events` completions, or `max_usecs` after
+    /// the first pending completion.
+    pub fn set_interrupt_coalescing(&mut self, max_events: u32, max_usecs: u64) {
+        self.interrupt_coalescing = Some((max_events, max_usecs));
+    }
+
+    /// Report the given serial to the guest as the disk ID, instead of the
+    /// one generated from the disk image.
+    pub fn set_serial(&mut self, serial: String) {
+        self.serial = Some(serial);
+    }
+
+    /// Process the queues on the shared worker pool instead of one thread
+    /// per queue.
+    pub fn set_worker_pool(&mut self, worker_pool: Arc<EpollWorkerPool>) {
+        self.common.set_worker_pool(worker_pool);
+    }
+
+    /// Pin the thread processing each listed queue to the given host CPUs.
+    pub fn set_queue_affinity(&mut self, queue_affinity: HashMap<u16, Vec<usize>>) {
+        self.common.set_queue_affinity(queue_affinity);
+    }
+
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.clone(),
@@ -489,10 +555,15 @@ impl VirtioDevice for BlockIoUring {
     ) -> ActivateResult {
         self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
 
-        let disk_image_id = build_disk_image_id(&self.disk_path);
+        let disk_image_id = match &self.serial {
+            Some(serial) => build_serial(serial),
+            None => build_disk_image_id(&self.disk_path),
+        };
         self.update_writeback();
 
         let mut epoll_threads = Vec::new();
+        let mut pooled_handlers: Vec<(EpollHelper, Box<dyn EpollHelperHandler + Send>)> =
+            Vec::new();
         for i in 0..self.common.queue_sizes.len() {
             let queue_size = self.common.queue_sizes[i] as usize;
             let queue_evt = queue_evts.remove(0);
@@ -539,6 +610,7 @@ impl VirtioDevice for BlockIoUring {
                     ActivateError::BadActivate
                 })?,
                 request_list: HashMap::with_capacity(queue_size),
+                coalescer: create_coalescer(self.interrupt_coalescing)?,
             };
 
             let paused = self.common.paused.clone();
@@ -555,6 +627,17 @@ impl VirtioDevice for BlockIoUring {
                     ActivateError::BadActivate
                 })?;
 
+            if self.common.worker_pool.is_some() {
+                let helper = handler.epoll_helper().map_err(|e| {
+                    error!("failed to create the virtio-blk epoll helper: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+                pooled_handlers.push((helper, Box::new(handler)));
+                continue;
+            }
+
+            let host_cpus = self.common.queue_thread_affinity(i);
+
             // Retrieve seccomp filter for virtio_blk_io_uring thread
             let virtio_blk_io_uring_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioBlkIoUring)
@@ -563,6 +646,11 @@ impl VirtioDevice for BlockIoUring {
             thread::Builder::new()
                 .name("virtio_blk_io_uring".to_string())
                 .spawn(move || {
+                    if let Some(host_cpus) = host_cpus {
+                        if let Err(e) = set_thread_affinity(&host_cpus) {
+                            error!("Error setting the thread affinity: {}", e);
+                        }
+                    }
                     if let Err(e) = SeccompFilter::apply(virtio_blk_io_uring_seccomp_filter) {
                         error!("Error applying seccomp filter: {:?}", e);
                     } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
@@ -576,6 +664,7 @@ impl VirtioDevice for BlockIoUring {
                 })?;
         }
 
+        self.common.add_pooled_handlers(pooled_handlers)?;
         self.common.epoll_threads = Some(epoll_threads);
 
         Ok(())
@@ -0,0 +1,95 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::timer::{timerfd_create, timerfd_setup};
+use crate::ActivateError;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+/// Aggregates the interrupts notifying the guest about completions on a
+/// virtqueue. The interrupt is injected once `max_events` notifications are
+/// pending, or `max_usecs` after the first pending one, whichever comes first.
+pub struct InterruptCoalescer {
+    max_events: u32,
+    max_usecs: u64,
+    pending: u32,
+    timer: File,
+    timer_armed: bool,
+}
+
+impl InterruptCoalescer {
+    pub fn new(max_events: u32, max_usecs: u64) -> io::Result<Self> {
+        let timer_fd = timerfd_create()?;
+        // Safe because the file descriptor has just been created.
+        let timer = unsafe { File::from_raw_fd(timer_fd) };
+
+        Ok(InterruptCoalescer {
+            max_events,
+            max_usecs,
+            pending: 0,
+            timer,
+            timer_armed: false,
+        })
+    }
+
+    /// Account for a notification which must be delivered to the guest.
+    /// Returns true if the interrupt must be injected right away.
+    pub fn add_event(&mut self) -> bool {
+        self.pending += 1;
+        if self.pending >= self.max_events {
+            self.pending = 0;
+            return true;
+        }
+
+        if !self.timer_armed {
+            if let Err(e) = timerfd_setup(&self.timer, Duration::from_micros(self.max_usecs)) {
+                // Without the timer, the notification could be delayed
+                // indefinitely, hence the interrupt is injected right away.
+                error!("Failed to arm interrupt coalescing timer: {:?}", e);
+                self.pending = 0;
+                return true;
+            }
+            self.timer_armed = true;
+        }
+        false
+    }
+
+    /// Handle the expiration of the timer. Returns true if some notifications
+    /// are pending, in which case the interrupt must be injected.
+    pub fn timer_expired(&mut self) -> io::Result<bool> {
+        // When reading from the timerfd you get 8 bytes indicating
+        // the number of times this event has elapsed since the last read.
+        let mut buf = [0u8; 8];
+        self.timer.read_exact(&mut buf)?;
+        self.timer_armed = false;
+
+        let pending = self.pending > 0;
+        self.pending = 0;
+        Ok(pending)
+    }
+}
+
+// Create the interrupt coalescer of a virtqueue, if coalescing has been
+// configured with a (max_events, max_usecs) pair.
+pub(crate) fn create_coalescer(
+    params: Option<(u32, u64)>,
+) -> Result<Option<InterruptCoalescer>, ActivateError> {
+    match params {
+        Some((max_events, max_usecs)) => InterruptCoalescer::new(max_events, max_usecs)
+            .map(Some)
+            .map_err(|e| {
+                error!("failed creating interrupt coalescer: {}", e);
+                ActivateError::BadActivate
+            }),
+        None => Ok(None),
+    }
+}
+
+impl AsRawFd for InterruptCoalescer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
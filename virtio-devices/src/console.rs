@@ -15,10 +15,13 @@ use seccomp::{SeccompAction, SeccompFilter};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::cmp;
 use std::collections::VecDeque;
+use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::ops::DerefMut;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
@@ -41,9 +44,55 @@ const OUTPUT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 const INPUT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 // Console configuration change event is triggered.
 const CONFIG_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// New descriptors are pending on the control queues.
+const CONTROL_RECEIVE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+const CONTROL_TRANSMIT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// Each additional port gets PORT_EVENTS_COUNT events from this one: its
+// receive queue, its transmit queue, some input from its endpoint, a new
+// connection on its listening socket, and its device being writable again.
+const PORT_EVENTS_START: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+const PORT_EVENTS_COUNT: u16 = 5;
+const PORT_RECEIVE_QUEUE_EVENT: u16 = 0;
+const PORT_TRANSMIT_QUEUE_EVENT: u16 = 1;
+const PORT_INPUT_EVENT: u16 = 2;
+const PORT_CONNECTION_EVENT: u16 = 3;
+const PORT_OUTPUT_EVENT: u16 = 4;
+
+// The input of a port is not read from its endpoint anymore while this much
+// of it is waiting for the guest.
+const PORT_INPUT_MAX: usize = 64 << 10;
+
+// Control messages are dropped past this many waiting for the driver. The
+// opening and the resizing of a port only keep their last state pending, so
+// this is only reached with a driver not providing any buffer.
+const CONTROL_OUT_MAX: usize = 256;
 
 //Console size feature bit
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+// Multiple ports feature bit
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
+
+// Control messages exchanged through the control queues.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// With multiple ports, the control queues come right after the queues of the
+// first port, followed by the queues of the other ports.
+const CONTROL_RECEIVE_QUEUE: usize = 2;
+const CONTROL_TRANSMIT_QUEUE: usize = 3;
+
+fn port_receive_queue(port: u32) -> usize {
+    if port == 0 {
+        0
+    } else {
+        2 * (port as usize + 1)
+    }
+}
 
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
 #[repr(C, packed)]
@@ -81,11 +130,161 @@ impl Serialize for VirtioConsoleConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioConsoleConfig {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioConsoleControl {}
+
+/// Host side of an additional port of the virtio-console device.
+pub enum ConsolePortEndpoint {
+    /// The guest output is written to the file, without any input.
+    File(File),
+    /// Main side of a pseudo terminal, for both input and output.
+    Pty(File),
+    /// Listening socket, from the path it is bound to. A single client can
+    /// be connected at a time.
+    Socket(UnixListener, PathBuf),
+    /// Host character device, for both input and output.
+    Device(File),
+}
+
+impl ConsolePortEndpoint {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            ConsolePortEndpoint::File(f) => ConsolePortEndpoint::File(f.try_clone()?),
+            ConsolePortEndpoint::Pty(f) => ConsolePortEndpoint::Pty(f.try_clone()?),
+            ConsolePortEndpoint::Socket(l, path) => {
+                ConsolePortEndpoint::Socket(l.try_clone()?, path.clone())
+            }
+            ConsolePortEndpoint::Device(f) => ConsolePortEndpoint::Device(f.try_clone()?),
+        })
+    }
+}
+
+/// Additional port, which the guest finds from its name, for instance as
+/// /dev/virtio-ports/<name> on Linux.
+pub struct ConsolePort {
+    pub name: String,
+    pub endpoint: ConsolePortEndpoint,
+}
+
+/// State of an additional port, kept across snapshot and restore.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PortState {
+    in_buffer: VecDeque<u8>,
+    out_buffer: VecDeque<u8>,
+    ready: bool,
+}
+
+/// State of the multiple ports feature, which the device thread saves when
+/// it gets paused.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MultiportState {
+    control_out: VecDeque<Vec<u8>>,
+    ports: Vec<PortState>,
+}
+
+struct PortHandler {
+    name: String,
+    endpoint: ConsolePortEndpoint,
+    // Client connected to the listening socket.
+    connection: Option<UnixStream>,
+    in_buffer: VecDeque<u8>,
+    // The input is not read while the guest doesn't consume it.
+    input_paused: bool,
+    // The driver is ready to use the port.
+    ready: bool,
+    // Output the device didn't take yet. The transmit queue is not processed
+    // until it is written.
+    out_buffer: VecDeque<u8>,
+    // Duplicate of the device polled for room to write the pending output,
+    // the device itself being already polled for its input.
+    out_waiter: Option<File>,
+    receive_queue_evt: EventFd,
+    transmit_queue_evt: EventFd,
+}
+
+impl PortHandler {
+    fn input_fd(&self) -> Option<RawFd> {
+        match &self.endpoint {
+            ConsolePortEndpoint::File(_) => None,
+            ConsolePortEndpoint::Pty(f) | ConsolePortEndpoint::Device(f) => Some(f.as_raw_fd()),
+            ConsolePortEndpoint::Socket(..) => self.connection.as_ref().map(|c| c.as_raw_fd()),
+        }
+    }
+
+    fn host_connected(&self) -> bool {
+        match self.endpoint {
+            ConsolePortEndpoint::File(_)
+            | ConsolePortEndpoint::Pty(_)
+            | ConsolePortEndpoint::Device(_) => true,
+            ConsolePortEndpoint::Socket(..) => self.connection.is_some(),
+        }
+    }
+
+    // The output is dropped when nobody is connected to the socket, or when
+    // the client doesn't keep up with it. The output of a device is kept
+    // until the device takes it instead.
+    fn write_output(&mut self, buf: &[u8]) {
+        let result = match &mut self.endpoint {
+            ConsolePortEndpoint::Device(_) => {
+                self.out_buffer.extend(buf);
+                self.flush_output();
+                return;
+            }
+            ConsolePortEndpoint::File(f) | ConsolePortEndpoint::Pty(f) => f.write_all(buf),
+            ConsolePortEndpoint::Socket(..) => match &mut self.connection {
+                Some(c) => c.write_all(buf),
+                None => Ok(()),
+            },
+        };
+        if let Err(e) = result {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                warn!("Failed writing to console port {}: {}", self.name, e);
+            }
+        }
+    }
+
+    // Write as much of the pending output as the device takes, which is
+    // opened non-blocking for its input not to hold the device thread.
+    fn flush_output(&mut self) {
+        let f = match &mut self.endpoint {
+            ConsolePortEndpoint::Device(f) => f,
+            _ => return,
+        };
+        while !self.out_buffer.is_empty() {
+            match f.write(self.out_buffer.as_slices().0) {
+                Ok(0) => {
+                    warn!("Console port {} doesn't take any output", self.name);
+                    self.out_buffer.clear();
+                }
+                Ok(n) => {
+                    self.out_buffer.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("Failed writing to console port {}: {}", self.name, e);
+                    self.out_buffer.clear();
+                }
+            }
+        }
+    }
+}
+
 struct ConsoleEpollHandler {
     queues: Vec<Queue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     in_buffer: Arc<Mutex<VecDeque<u8>>>,
+    // Input queued by the VMM for the additional ports, by port name.
+    port_input: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
     out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
     input_queue_evt: EventFd,
     output_queue_evt: EventFd,
@@ -93,6 +292,54 @@ struct ConsoleEpollHandler {
     config_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    config: Arc<Mutex<VirtioConsoleConfig>>,
+    // Receive and transmit control queue events, with multiple ports only.
+    control_queue_evts: Option<(EventFd, EventFd)>,
+    // Control messages waiting for the driver to provide buffers.
+    control_out: VecDeque<Vec<u8>>,
+    ports: Vec<PortHandler>,
+    multiport: Arc<Mutex<MultiportState>>,
+}
+
+fn port_event(port: usize, event: u16) -> u16 {
+    PORT_EVENTS_START + port as u16 * PORT_EVENTS_COUNT + event
+}
+
+// Place the input into the empty buffers the driver added to a receive queue.
+fn fill_receive_queue(
+    mem: &GuestMemoryMmap,
+    recv_queue: &mut Queue,
+    in_buffer: &mut VecDeque<u8>,
+) -> bool {
+    let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+    let mut used_count = 0;
+
+    if in_buffer.is_empty() {
+        return false;
+    }
+
+    for avail_desc in recv_queue.iter(mem) {
+        let len = cmp::min(avail_desc.len as u32, in_buffer.len() as u32);
+        let source_slice = in_buffer.drain(..len as usize).collect::<Vec<u8>>();
+        if let Err(e) = mem.write_slice(&source_slice[..], avail_desc.addr) {
+            error!("Failed to write slice: {:?}", e);
+            recv_queue.go_to_previous_position();
+            break;
+        }
+
+        used_desc_heads[used_count] = (avail_desc.index, len);
+        used_count += 1;
+
+        if in_buffer.is_empty() {
+            break;
+        }
+    }
+
+    for &(desc_index, len) in &used_desc_heads[..used_count] {
+        recv_queue.add_used(mem, desc_index, len);
+    }
+
+    used_count > 0
 }
 
 impl ConsoleEpollHandler {
@@ -104,37 +351,8 @@ impl ConsoleEpollHandler {
      */
     fn process_input_queue(&mut self) -> bool {
         let mut in_buffer = self.in_buffer.lock().unwrap();
-        let recv_queue = &mut self.queues[0]; //receiveq
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
-
-        if in_buffer.is_empty() {
-            return false;
-        }
-
         let mem = self.mem.memory();
-        for avail_desc in recv_queue.iter(&mem) {
-            let len = cmp::min(avail_desc.len as u32, in_buffer.len() as u32);
-            let source_slice = in_buffer.drain(..len as usize).collect::<Vec<u8>>();
-            if let Err(e) = mem.write_slice(&source_slice[..], avail_desc.addr) {
-                error!("Failed to write slice: {:?}", e);
-                recv_queue.go_to_previous_position();
-                break;
-            }
-
-            used_desc_heads[used_count] = (avail_desc.index, len);
-            used_count += 1;
-
-            if in_buffer.is_empty() {
-                break;
-            }
-        }
-
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            recv_queue.add_used(&mem, desc_index, len);
-        }
-
-        used_count > 0
+        fill_receive_queue(&mem, &mut self.queues[0], &mut in_buffer) //receiveq
     }
 
     /*
@@ -171,15 +389,504 @@ impl ConsoleEpollHandler {
         used_count > 0
     }
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+    fn process_port_receive_queue(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: usize,
+    ) -> result::Result<bool, EpollHelperError> {
+        let mem = self.mem.memory();
+        let used = fill_receive_queue(
+            &mem,
+            &mut self.queues[port_receive_queue(port as u32 + 1)],
+            &mut self.ports[port].in_buffer,
+        );
+
+        // Read the input again once the guest caught up with it.
+        let p = &mut self.ports[port];
+        if p.input_paused && p.in_buffer.len() < PORT_INPUT_MAX {
+            if let Some(fd) = p.input_fd() {
+                helper.add_event(fd, port_event(port, PORT_INPUT_EVENT))?;
+            }
+            p.input_paused = false;
+        }
+
+        Ok(used)
+    }
+
+    // Move the input queued by the VMM to the ports it is meant for.
+    fn process_port_input(&mut self, helper: &mut EpollHelper) -> bool {
+        let inputs: Vec<(String, Vec<u8>)> = self.port_input.lock().unwrap().drain(..).collect();
+        for (name, input) in inputs {
+            let port = match self.ports.iter().position(|p| p.name == name) {
+                Some(port) => port,
+                None => {
+                    warn!("Dropping input for unavailable console port {}", name);
+                    continue;
+                }
+            };
+
+            self.ports[port].in_buffer.extend(input);
+            match self.process_port_receive_queue(helper, port) {
+                Ok(true) => {
+                    if self
+                        .signal_used_queue(port_receive_queue(port as u32 + 1))
+                        .is_err()
+                    {
+                        return true;
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Failed to resume console port input: {:?}", e);
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    // The buffers are left to the driver while the device doesn't take the
+    // output, rather than dropping it.
+    fn process_port_transmit_queue(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: usize,
+    ) -> result::Result<bool, EpollHelperError> {
+        let trans_queue = &mut self.queues[port_receive_queue(port as u32 + 1) + 1];
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+
+        let mem = self.mem.memory();
+        if self.ports[port].out_buffer.is_empty() {
+            for avail_desc in trans_queue.iter(&mem) {
+                let mut buf = vec![0u8; avail_desc.len as usize];
+                match mem.read_slice(&mut buf, avail_desc.addr) {
+                    Ok(()) => self.ports[port].write_output(&buf),
+                    Err(e) => error!("Failed to read slice: {:?}", e),
+                }
+
+                used_desc_heads[used_count] = (avail_desc.index, avail_desc.len);
+                used_count += 1;
+
+                if !self.ports[port].out_buffer.is_empty() {
+                    break;
+                }
+            }
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            trans_queue.add_used(&mem, desc_index, len);
+        }
+
+        self.poll_port_output(helper, port)?;
+        Ok(used_count > 0)
+    }
+
+    // Poll the device of a port for room to write, for as long as some of
+    // its output is pending.
+    fn poll_port_output(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: usize,
+    ) -> result::Result<(), EpollHelperError> {
+        let p = &mut self.ports[port];
+        match (p.out_buffer.is_empty(), &p.out_waiter) {
+            (false, None) => {
+                if let ConsolePortEndpoint::Device(f) = &p.endpoint {
+                    let waiter = f.try_clone().map_err(EpollHelperError::Ctl)?;
+                    helper.add_event_custom(
+                        waiter.as_raw_fd(),
+                        port_event(port, PORT_OUTPUT_EVENT),
+                        epoll::Events::EPOLLOUT,
+                    )?;
+                    p.out_waiter = Some(waiter);
+                }
+            }
+            (true, Some(waiter)) => {
+                helper.del_event_custom(
+                    waiter.as_raw_fd(),
+                    port_event(port, PORT_OUTPUT_EVENT),
+                    epoll::Events::EPOLLOUT,
+                )?;
+                p.out_waiter = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn read_port_input(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: usize,
+    ) -> result::Result<(), EpollHelperError> {
+        let p = &mut self.ports[port];
+        let mut buf = [0u8; 4096];
+        let result = match &mut p.endpoint {
+            ConsolePortEndpoint::Pty(f) | ConsolePortEndpoint::Device(f) => f.read(&mut buf),
+            ConsolePortEndpoint::Socket(..) => match &mut p.connection {
+                Some(c) => c.read(&mut buf),
+                None => return Ok(()),
+            },
+            ConsolePortEndpoint::File(_) => return Ok(()),
+        };
+
+        match result {
+            Ok(n) if n > 0 => {
+                p.in_buffer.extend(&buf[..n]);
+                if p.in_buffer.len() >= PORT_INPUT_MAX {
+                    if let Some(fd) = p.input_fd() {
+                        helper.del_event_custom(
+                            fd,
+                            port_event(port, PORT_INPUT_EVENT),
+                            epoll::Events::EPOLLIN,
+                        )?;
+                    }
+                    p.input_paused = true;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            _ => {
+                if let ConsolePortEndpoint::Socket(..) = p.endpoint {
+                    return self.disconnect_port(helper, port);
+                }
+                // Stop polling a pseudo terminal or a device which can't be
+                // read from, like a serial adapter which got unplugged.
+                if let Err(e) = result {
+                    error!("Failed reading from console port {}: {}", p.name, e);
+                }
+                if let Some(fd) = p.input_fd() {
+                    helper.del_event_custom(
+                        fd,
+                        port_event(port, PORT_INPUT_EVENT),
+                        epoll::Events::EPOLLIN,
+                    )?;
+                }
+                p.input_paused = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept_port_connection(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: usize,
+    ) -> result::Result<(), EpollHelperError> {
+        let p = &mut self.ports[port];
+        let listener_fd = match &p.endpoint {
+            ConsolePortEndpoint::Socket(listener, _) => listener.as_raw_fd(),
+            _ => return Ok(()),
+        };
+
+        // The connection is non blocking so that a client not reading the
+        // output doesn't block the other ports.
+        // Safe because the listener is a valid socket, and the peer address
+        // is not needed.
+        let fd = unsafe {
+            libc::accept4(
+                listener_fd,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            )
+        };
+        if fd < 0 {
+            error!(
+                "Failed accepting connection on console port {}: {}",
+                p.name,
+                io::Error::last_os_error()
+            );
+            return Ok(());
+        }
+        // Safe because accept4() returned a new file descriptor we take
+        // ownership of.
+        let connection = unsafe { UnixStream::from_raw_fd(fd) };
+
+        if p.connection.is_some() {
+            warn!("Console port {} already has a client connected", p.name);
+            return Ok(());
+        }
+
+        helper.add_event(fd, port_event(port, PORT_INPUT_EVENT))?;
+        p.connection = Some(connection);
+        p.input_paused = false;
+        if p.ready {
+            self.queue_control(port as u32 + 1, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+        }
+
+        Ok(())
+    }
+
+    fn disconnect_port(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: usize,
+    ) -> result::Result<(), EpollHelperError> {
+        let p = &mut self.ports[port];
+        if let Some(connection) = p.connection.take() {
+            if !p.input_paused {
+                helper.del_event_custom(
+                    connection.as_raw_fd(),
+                    port_event(port, PORT_INPUT_EVENT),
+                    epoll::Events::EPOLLIN,
+                )?;
+            }
+        }
+        p.input_paused = false;
+        if p.ready {
+            self.queue_control(port as u32 + 1, VIRTIO_CONSOLE_PORT_OPEN, 0, &[]);
+        }
+
+        Ok(())
+    }
+
+    fn queue_control(&mut self, id: u32, event: u16, value: u16, data: &[u8]) {
+        // Only the last state of a port and of the console size matter, so
+        // that clients connecting in a loop don't pile up messages.
+        if event == VIRTIO_CONSOLE_PORT_OPEN || event == VIRTIO_CONSOLE_RESIZE {
+            self.control_out.retain(|m| {
+                match VirtioConsoleControl::from_slice(
+                    &m[..std::mem::size_of::<VirtioConsoleControl>()],
+                ) {
+                    Some(c) => c.id != id || c.event != event,
+                    None => true,
+                }
+            });
+        }
+        if self.control_out.len() >= CONTROL_OUT_MAX {
+            warn!(
+                "Dropping virtio-console control message {} for port {}",
+                event, id
+            );
+            return;
+        }
+
+        let mut msg = VirtioConsoleControl { id, event, value }
+            .as_slice()
+            .to_vec();
+        msg.extend_from_slice(data);
+        self.control_out.push_back(msg);
+    }
+
+    // The size of the console is sent through a control message with
+    // multiple ports, instead of the configuration space.
+    fn queue_resize(&mut self) {
+        let (cols, rows) = {
+            let config = self.config.lock().unwrap();
+            (config.cols, config.rows)
+        };
+        let mut size = rows.to_le_bytes().to_vec();
+        size.extend_from_slice(&cols.to_le_bytes());
+        self.queue_control(0, VIRTIO_CONSOLE_RESIZE, 0, &size);
+    }
+
+    fn handle_control(&mut self, msg: VirtioConsoleControl) {
+        let (id, event, value) = (msg.id, msg.event, msg.value);
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value != 1 {
+                    error!("virtio-console driver failed to initialize");
+                    return;
+                }
+                for id in 0..=self.ports.len() as u32 {
+                    self.queue_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                if value != 1 {
+                    error!("virtio-console driver failed to add port {}", id);
+                    return;
+                }
+                if id == 0 {
+                    self.queue_control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                    self.queue_resize();
+                    self.queue_control(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                } else if let Some(p) = self.ports.get_mut(id as usize - 1) {
+                    p.ready = true;
+                    let name = p.name.clone();
+                    let host_connected = p.host_connected();
+                    self.queue_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                    if host_connected {
+                        self.queue_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                    }
+                } else {
+                    warn!("virtio-console driver added unknown port {}", id);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                debug!("virtio-console port {} opened by the guest: {}", id, value);
+            }
+            _ => warn!(
+                "Unexpected virtio-console control message {} for port {}",
+                event, id
+            ),
+        }
+    }
+
+    fn process_control_transmit_queue(&mut self) -> bool {
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mut messages = Vec::new();
+
+        let mem = self.mem.memory();
+        for avail_desc in self.queues[CONTROL_TRANSMIT_QUEUE].iter(&mem) {
+            if avail_desc.len as usize >= std::mem::size_of::<VirtioConsoleControl>() {
+                match mem.read_obj::<VirtioConsoleControl>(avail_desc.addr) {
+                    Ok(msg) => messages.push(msg),
+                    Err(e) => error!("Failed to read control message: {:?}", e),
+                }
+            } else {
+                error!("Invalid control message length {}", avail_desc.len);
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, 0);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            self.queues[CONTROL_TRANSMIT_QUEUE].add_used(&mem, desc_index, len);
+        }
+
+        for msg in messages {
+            self.handle_control(msg);
+        }
+
+        used_count > 0
+    }
+
+    // Send the pending control messages, each of them in its own buffer.
+    fn process_control_receive_queue(&mut self) -> bool {
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+
+        if self.control_out.is_empty() {
+            return false;
+        }
+
+        let mem = self.mem.memory();
+        let recv_queue = &mut self.queues[CONTROL_RECEIVE_QUEUE];
+        for avail_desc in recv_queue.iter(&mem) {
+            let msg = self.control_out.pop_front().unwrap();
+            let len = cmp::min(msg.len(), avail_desc.len as usize);
+            if let Err(e) = mem.write_slice(&msg[..len], avail_desc.addr) {
+                error!("Failed to write slice: {:?}", e);
+                recv_queue.go_to_previous_position();
+                self.control_out.push_front(msg);
+                break;
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, len as u32);
+            used_count += 1;
+
+            if self.control_out.is_empty() {
+                break;
+            }
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            recv_queue.add_used(&mem, desc_index, len);
+        }
+
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
         self.interrupt_cb
-            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[0]))
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
             .map_err(|e| {
                 error!("Failed to signal used queue: {:?}", e);
                 DeviceError::FailedSignalingUsedQueue(e)
             })
     }
 
+    fn flush_control(&mut self) -> result::Result<(), DeviceError> {
+        if self.process_control_receive_queue() {
+            self.signal_used_queue(CONTROL_RECEIVE_QUEUE)?;
+        }
+        Ok(())
+    }
+
+    fn handle_port_event(&mut self, helper: &mut EpollHelper, ev_type: u16) -> bool {
+        let port = usize::from((ev_type - PORT_EVENTS_START) / PORT_EVENTS_COUNT);
+        if port >= self.ports.len() {
+            error!("Unknown event for virtio-console");
+            return true;
+        }
+        let recv_queue = port_receive_queue(port as u32 + 1);
+
+        let mut input = false;
+        let mut output = false;
+        match (ev_type - PORT_EVENTS_START) % PORT_EVENTS_COUNT {
+            PORT_RECEIVE_QUEUE_EVENT => {
+                if let Err(e) = self.ports[port].receive_queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+                input = true;
+            }
+            PORT_TRANSMIT_QUEUE_EVENT => {
+                if let Err(e) = self.ports[port].transmit_queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+                output = true;
+            }
+            PORT_INPUT_EVENT => {
+                if let Err(e) = self.read_port_input(helper, port) {
+                    error!("Failed to read console port input: {:?}", e);
+                    return true;
+                }
+                input = true;
+            }
+            PORT_OUTPUT_EVENT => {
+                self.ports[port].flush_output();
+                output = true;
+            }
+            _ => {
+                if let Err(e) = self.accept_port_connection(helper, port) {
+                    error!("Failed to accept console port connection: {:?}", e);
+                    return true;
+                }
+            }
+        }
+
+        if output {
+            match self.process_port_transmit_queue(helper, port) {
+                Ok(true) => {
+                    if self.signal_used_queue(recv_queue + 1).is_err() {
+                        return true;
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Failed to write console port output: {:?}", e);
+                    return true;
+                }
+            }
+        }
+
+        if input {
+            match self.process_port_receive_queue(helper, port) {
+                Ok(true) => {
+                    if self.signal_used_queue(recv_queue).is_err() {
+                        return true;
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Failed to resume console port input: {:?}", e);
+                    return true;
+                }
+            }
+        }
+
+        // Connecting and disconnecting clients are reported to the driver.
+        self.flush_control().is_err()
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -190,6 +897,51 @@ impl ConsoleEpollHandler {
         helper.add_event(self.output_queue_evt.as_raw_fd(), OUTPUT_QUEUE_EVENT)?;
         helper.add_event(self.input_evt.as_raw_fd(), INPUT_EVENT)?;
         helper.add_event(self.config_evt.as_raw_fd(), CONFIG_EVENT)?;
+        if let Some((receive_evt, transmit_evt)) = &self.control_queue_evts {
+            helper.add_event(receive_evt.as_raw_fd(), CONTROL_RECEIVE_QUEUE_EVENT)?;
+            helper.add_event(transmit_evt.as_raw_fd(), CONTROL_TRANSMIT_QUEUE_EVENT)?;
+        }
+        for (port, p) in self.ports.iter().enumerate() {
+            helper.add_event(
+                p.receive_queue_evt.as_raw_fd(),
+                port_event(port, PORT_RECEIVE_QUEUE_EVENT),
+            )?;
+            helper.add_event(
+                p.transmit_queue_evt.as_raw_fd(),
+                port_event(port, PORT_TRANSMIT_QUEUE_EVENT),
+            )?;
+            match &p.endpoint {
+                ConsolePortEndpoint::File(_) => {}
+                ConsolePortEndpoint::Pty(f) | ConsolePortEndpoint::Device(f) => {
+                    if p.input_paused {
+                        continue;
+                    }
+                    helper.add_event(f.as_raw_fd(), port_event(port, PORT_INPUT_EVENT))?
+                }
+                ConsolePortEndpoint::Socket(listener, _) => helper.add_event(
+                    listener.as_raw_fd(),
+                    port_event(port, PORT_CONNECTION_EVENT),
+                )?,
+            }
+        }
+
+        // Hand the input and the control messages restored from a snapshot
+        // over to the driver, and write the output to the devices.
+        for port in 0..self.ports.len() {
+            self.ports[port].flush_output();
+            self.poll_port_output(&mut helper, port)?;
+            if self.process_port_receive_queue(&mut helper, port)? {
+                if let Err(e) = self.signal_used_queue(port_receive_queue(port as u32 + 1)) {
+                    error!("Failed to signal used queue: {:?}", e);
+                }
+            }
+        }
+        if self.control_queue_evts.is_some() {
+            if let Err(e) = self.flush_control() {
+                error!("Failed to send console control messages: {:?}", e);
+            }
+        }
+
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -197,7 +949,7 @@ impl ConsoleEpollHandler {
 }
 
 impl EpollHelperHandler for ConsoleEpollHandler {
-    fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+    fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool {
         let ev_type = event.data as u16;
         match ev_type {
             INPUT_QUEUE_EVENT => {
@@ -205,7 +957,7 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     error!("Failed to get queue event: {:?}", e);
                     return true;
                 } else if self.process_input_queue() {
-                    if let Err(e) = self.signal_used_queue() {
+                    if let Err(e) = self.signal_used_queue(0) {
                         error!("Failed to signal used queue: {:?}", e);
                         return true;
                     }
@@ -224,16 +976,24 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     error!("Failed to get input event: {:?}", e);
                     return true;
                 } else if self.process_input_queue() {
-                    if let Err(e) = self.signal_used_queue() {
+                    if let Err(e) = self.signal_used_queue(0) {
                         error!("Failed to signal used queue: {:?}", e);
                         return true;
                     }
                 }
+                if self.process_port_input(helper) {
+                    return true;
+                }
             }
             CONFIG_EVENT => {
                 if let Err(e) = self.config_evt.read() {
                     error!("Failed to get config event: {:?}", e);
                     return true;
+                } else if self.control_queue_evts.is_some() {
+                    self.queue_resize();
+                    if self.flush_control().is_err() {
+                        return true;
+                    }
                 } else if let Err(e) = self
                     .interrupt_cb
                     .trigger(&VirtioInterruptType::Config, None)
@@ -242,6 +1002,29 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     return true;
                 }
             }
+            CONTROL_RECEIVE_QUEUE_EVENT => {
+                if let Err(e) = self.control_queue_evts.as_ref().unwrap().0.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                } else if self.flush_control().is_err() {
+                    return true;
+                }
+            }
+            CONTROL_TRANSMIT_QUEUE_EVENT => {
+                if let Err(e) = self.control_queue_evts.as_ref().unwrap().1.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                } else if self.process_control_transmit_queue()
+                    && self.signal_used_queue(CONTROL_TRANSMIT_QUEUE).is_err()
+                {
+                    return true;
+                } else if self.flush_control().is_err() {
+                    return true;
+                }
+            }
+            ev_type if ev_type >= PORT_EVENTS_START => {
+                return self.handle_port_event(helper, ev_type);
+            }
             _ => {
                 error!("Unknown event for virtio-console");
                 return true;
@@ -249,6 +1032,26 @@ impl EpollHelperHandler for ConsoleEpollHandler {
         }
         false
     }
+
+    // The input and the control messages not handed over to the driver yet
+    // are part of the device state. The clients connected to the sockets
+    // are not, and get reconnected after a restore.
+    fn pause(&mut self) {
+        if self.control_queue_evts.is_none() {
+            return;
+        }
+        let mut multiport = self.multiport.lock().unwrap();
+        multiport.control_out = self.control_out.clone();
+        multiport.ports = self
+            .ports
+            .iter()
+            .map(|p| PortState {
+                in_buffer: p.in_buffer.clone(),
+                out_buffer: p.out_buffer.clone(),
+                ready: p.ready,
+            })
+            .collect();
+    }
 }
 
 /// Input device.
@@ -256,6 +1059,7 @@ pub struct ConsoleInput {
     input_evt: EventFd,
     config_evt: EventFd,
     in_buffer: Arc<Mutex<VecDeque<u8>>>,
+    port_input: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
     config: Arc<Mutex<VirtioConsoleConfig>>,
     acked_features: AtomicU64,
 }
@@ -267,6 +1071,17 @@ impl ConsoleInput {
         let _ = self.input_evt.write(1);
     }
 
+    /// Send the input to the additional port `name`, as if it came from its
+    /// endpoint. The input is dropped if the guest driver doesn't use the
+    /// additional ports.
+    pub fn queue_port_input_bytes(&self, name: &str, input: &[u8]) {
+        self.port_input
+            .lock()
+            .unwrap()
+            .push((name.to_owned(), input.to_vec()));
+        let _ = self.input_evt.write(1);
+    }
+
     pub fn update_console_size(&self, cols: u16, rows: u16) {
         if self
             .acked_features
@@ -281,11 +1096,11 @@ impl ConsoleInput {
 }
 
 impl VirtioConsoleConfig {
-    pub fn new(cols: u16, rows: u16) -> Self {
+    pub fn new(cols: u16, rows: u16, max_nr_ports: u32) -> Self {
         VirtioConsoleConfig {
             cols,
             rows,
-            max_nr_ports: 1u32,
+            max_nr_ports,
             emerg_wr: 0u32,
         }
     }
@@ -303,6 +1118,8 @@ pub struct Console {
     config: Arc<Mutex<VirtioConsoleConfig>>,
     input: Arc<ConsoleInput>,
     out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+    ports: Vec<ConsolePort>,
+    multiport: Arc<Mutex<MultiportState>>,
     seccomp_action: SeccompAction,
 }
 
@@ -312,15 +1129,20 @@ pub struct ConsoleState {
     acked_features: u64,
     config: VirtioConsoleConfig,
     in_buffer: VecDeque<u8>,
+    #[serde(default)]
+    multiport: MultiportState,
 }
 
 impl Console {
-    /// Create a new virtio console device that gets random data from /dev/urandom.
+    /// Create a new virtio console device, whose first port is the console
+    /// writing to `out`. Any additional port is exposed to the guest through
+    /// the multiple ports feature.
     pub fn new(
         id: String,
         out: Box<dyn io::Write + Send + Sync + 'static>,
         cols: u16,
         rows: u16,
+        ports: Vec<ConsolePort>,
         iommu: bool,
         seccomp_action: SeccompAction,
     ) -> io::Result<(Console, Arc<ConsoleInput>)> {
@@ -330,13 +1152,26 @@ impl Console {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
+        // Each port has a receive and a transmit queue, and the control
+        // queues are only needed with multiple ports.
+        let mut queue_sizes = QUEUE_SIZES.to_vec();
+        if !ports.is_empty() {
+            avail_features |= 1u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+            queue_sizes.resize(NUM_QUEUES * (ports.len() + 2), QUEUE_SIZE);
+        }
+
         let input_evt = EventFd::new(EFD_NONBLOCK).unwrap();
         let config_evt = EventFd::new(EFD_NONBLOCK).unwrap();
-        let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(cols, rows)));
+        let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(
+            cols,
+            rows,
+            ports.len() as u32 + 1,
+        )));
         let console_input = Arc::new(ConsoleInput {
             input_evt,
             config_evt,
             in_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            port_input: Arc::new(Mutex::new(Vec::new())),
             config: console_config.clone(),
             acked_features: AtomicU64::new(0),
         });
@@ -345,7 +1180,7 @@ impl Console {
             Console {
                 common: VirtioCommon {
                     device_type: VirtioDeviceType::TYPE_CONSOLE as u32,
-                    queue_sizes: QUEUE_SIZES.to_vec(),
+                    queue_sizes,
                     avail_features,
                     paused_sync: Some(Arc::new(Barrier::new(2))),
                     ..Default::default()
@@ -354,6 +1189,8 @@ impl Console {
                 config: console_config,
                 input: console_input.clone(),
                 out: Arc::new(Mutex::new(out)),
+                ports,
+                multiport: Arc::new(Mutex::new(MultiportState::default())),
                 seccomp_action,
             },
             console_input,
@@ -366,6 +1203,7 @@ impl Console {
             acked_features: self.common.acked_features,
             config: *(self.config.lock().unwrap()),
             in_buffer: self.input.in_buffer.lock().unwrap().clone(),
+            multiport: self.multiport.lock().unwrap().clone(),
         }
     }
 
@@ -374,6 +1212,7 @@ impl Console {
         self.common.acked_features = state.acked_features;
         *(self.config.lock().unwrap()) = state.config;
         *(self.input.in_buffer.lock().unwrap()) = state.in_buffer.clone();
+        *(self.multiport.lock().unwrap()) = state.multiport.clone();
 
         Ok(())
     }
@@ -448,18 +1287,71 @@ impl VirtioDevice for Console {
                 ActivateError::BadActivate
             })?;
 
+        let input_queue_evt = queue_evts.remove(0);
+        let output_queue_evt = queue_evts.remove(0);
+        let control_queue_evts = if self.common.feature_acked(VIRTIO_CONSOLE_F_MULTIPORT) {
+            Some((queue_evts.remove(0), queue_evts.remove(0)))
+        } else {
+            None
+        };
+
+        // The additional ports are only available with multiple ports. They
+        // start from the state restored from a snapshot, if any.
+        let multiport = self.multiport.lock().unwrap().clone();
+        let mut control_out = multiport.control_out;
+        let mut ports = Vec::new();
+        if control_queue_evts.is_some() {
+            for (i, port) in self.ports.iter().enumerate() {
+                let endpoint = port.endpoint.try_clone().map_err(|e| {
+                    error!("failed to clone console port {} endpoint: {}", port.name, e);
+                    ActivateError::BadActivate
+                })?;
+                let state = multiport.ports.get(i).cloned().unwrap_or_default();
+                // The clients of the sockets were not restored, hence the
+                // driver must learn the ports are closed on the host side.
+                if let ConsolePortEndpoint::Socket(..) = endpoint {
+                    if state.ready {
+                        let msg = VirtioConsoleControl {
+                            id: i as u32 + 1,
+                            event: VIRTIO_CONSOLE_PORT_OPEN,
+                            value: 0,
+                        };
+                        control_out.push_back(msg.as_slice().to_vec());
+                    }
+                }
+                ports.push(PortHandler {
+                    name: port.name.clone(),
+                    endpoint,
+                    connection: None,
+                    input_paused: state.in_buffer.len() >= PORT_INPUT_MAX,
+                    in_buffer: state.in_buffer,
+                    ready: state.ready,
+                    out_buffer: state.out_buffer,
+                    out_waiter: None,
+                    receive_queue_evt: queue_evts.remove(0),
+                    transmit_queue_evt: queue_evts.remove(0),
+                });
+            }
+        }
+
         let mut handler = ConsoleEpollHandler {
             queues,
             mem,
             interrupt_cb,
             in_buffer: self.input.in_buffer.clone(),
+            port_input: self.input.port_input.clone(),
             out: self.out.clone(),
-            input_queue_evt: queue_evts.remove(0),
-            output_queue_evt: queue_evts.remove(0),
+            input_queue_evt,
+            output_queue_evt,
             input_evt: self.input.input_evt.try_clone().unwrap(),
             config_evt: self.input.config_evt.try_clone().unwrap(),
             kill_evt,
             pause_evt,
+            config: self.config.clone(),
+            control_queue_evts,
+            control_out,
+            ports,
+            multiport: self.multiport.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -490,8 +1382,17 @@ impl VirtioDevice for Console {
     }
 
     fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        *(self.multiport.lock().unwrap()) = MultiportState::default();
         self.common.reset()
     }
+
+    fn shutdown(&mut self) {
+        for port in self.ports.iter() {
+            if let ConsolePortEndpoint::Socket(_, path) = &port.endpoint {
+                std::fs::remove_file(path).ok();
+            }
+        }
+    }
 }
 
 impl Pausable for Console {
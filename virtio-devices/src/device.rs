@@ -6,10 +6,12 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::{ActivateError, ActivateResult, Error, Queue};
+use crate::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperHandler, EpollWorkerPool, Error, Queue,
+};
 use libc::EFD_NONBLOCK;
 use std::collections::HashMap;
-use std::io::Write;
+use std::io::{self, Write};
 use std::num::Wrapping;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -152,6 +154,12 @@ pub trait VirtioDevice: Send {
         Vec::new()
     }
 
+    /// Whether the device hit an error it can't recover from, the driver
+    /// being expected to reset it.
+    fn needs_reset(&self) -> bool {
+        false
+    }
+
     /// Return the counters that this device exposes
     fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
         None
@@ -222,8 +230,14 @@ pub struct VirtioCommon {
     pub paused: Arc<AtomicBool>,
     pub paused_sync: Option<Arc<Barrier>>,
     pub epoll_threads: Option<Vec<thread::JoinHandle<()>>>,
+    pub worker_pool: Option<Arc<EpollWorkerPool>>,
+    // Host CPUs the thread handling each queue is pinned to, keyed by the
+    // queue index, or the queue pair index for virtio-net.
+    pub queue_affinity: HashMap<u16, Vec<usize>>,
     pub queue_sizes: Vec<u16>,
     pub device_type: u32,
+    // Set by the worker threads when the device can't operate anymore.
+    pub needs_reset: Arc<AtomicBool>,
 }
 
 impl VirtioCommon {
@@ -270,6 +284,7 @@ impl VirtioCommon {
             ActivateError::BadActivate
         })?;
         self.pause_evt = Some(pause_evt);
+        self.needs_reset.store(false, Ordering::SeqCst);
 
         // Save the interrupt EventFD as we need to return it on reset
         // but clone it to pass into the thread.
@@ -288,6 +303,42 @@ impl VirtioCommon {
         Ok(())
     }
 
+    /// Run the queue handlers on the shared worker pool instead of dedicated
+    /// threads. All the pooled handlers acknowledge the pause at once.
+    pub fn set_worker_pool(&mut self, worker_pool: Arc<EpollWorkerPool>) {
+        self.worker_pool = Some(worker_pool);
+        self.paused_sync = Some(Arc::new(Barrier::new(2)));
+    }
+
+    /// Pin the dedicated thread handling each listed queue to the given host
+    /// CPUs, the ones which aren't listed being left unrestricted.
+    pub fn set_queue_affinity(&mut self, queue_affinity: HashMap<u16, Vec<usize>>) {
+        self.queue_affinity = queue_affinity;
+    }
+
+    pub fn queue_thread_affinity(&self, index: usize) -> Option<Vec<usize>> {
+        self.queue_affinity.get(&(index as u16)).cloned()
+    }
+
+    pub fn add_pooled_handlers(
+        &self,
+        handlers: Vec<(EpollHelper, Box<dyn EpollHelperHandler + Send>)>,
+    ) -> ActivateResult {
+        if let Some(worker_pool) = &self.worker_pool {
+            worker_pool
+                .add_handlers(
+                    handlers,
+                    self.paused.clone(),
+                    self.paused_sync.clone().unwrap(),
+                )
+                .map_err(|e| {
+                    error!("failed to add handlers to the worker pool: {}", e);
+                    ActivateError::BadActivate
+                })?;
+        }
+        Ok(())
+    }
+
     pub fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
         // We first must resume the virtio thread if it was paused.
         if self.pause_evt.take().is_some() {
@@ -341,7 +392,38 @@ impl Pausable for VirtioCommon {
                 t.thread().unpark();
             }
         }
+        if let Some(worker_pool) = &self.worker_pool {
+            worker_pool.resume();
+        }
 
         Ok(())
     }
 }
+
+/// Restrict the calling thread to run on the provided host CPUs.
+pub fn set_thread_affinity(host_cpus: &[usize]) -> io::Result<()> {
+    // Safe because cpu_set_t is a plain bitmask, for which the all zeroes
+    // pattern is valid.
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for host_cpu in host_cpus {
+        // CPU_SET() indexes the bitmask without any bounds check.
+        if *host_cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("host CPU {} is beyond CPU_SETSIZE", host_cpu),
+            ));
+        }
+        // Safe because the host CPU has been checked to fit in the cpuset.
+        unsafe { libc::CPU_SET(*host_cpu, &mut cpuset) };
+    }
+
+    // Safe because the cpuset is properly initialized and its size is
+    // correctly provided.
+    let ret =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpuset) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
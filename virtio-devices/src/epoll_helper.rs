@@ -31,9 +31,23 @@ pub const EPOLL_HELPER_EVENT_PAUSE: u16 = 0;
 pub const EPOLL_HELPER_EVENT_KILL: u16 = 1;
 pub const EPOLL_HELPER_EVENT_LAST: u16 = 15;
 
+/// Outcome of `EpollHelper::poll()`.
+pub(crate) enum EpollHelperPoll {
+    /// Keep waiting for events.
+    Continue,
+    /// The pause event has been received.
+    Pause,
+    /// The handler must not be polled anymore.
+    Stop,
+}
+
 pub trait EpollHelperHandler {
     // Return true if execution of the loop should be stopped
     fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool;
+
+    // Complete the work which must not be left pending while the device is
+    // paused, before the pause is acknowledged.
+    fn pause(&mut self) {}
 }
 
 impl EpollHelper {
@@ -57,11 +71,35 @@ impl EpollHelper {
     }
 
     pub fn add_event(&mut self, fd: RawFd, id: u16) -> std::result::Result<(), EpollHelperError> {
+        self.add_event_custom(fd, id, epoll::Events::EPOLLIN)
+    }
+
+    pub fn add_event_custom(
+        &mut self,
+        fd: RawFd,
+        id: u16,
+        evts: epoll::Events,
+    ) -> std::result::Result<(), EpollHelperError> {
         epoll::ctl(
             self.epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_ADD,
             fd,
-            epoll::Event::new(epoll::Events::EPOLLIN, id.into()),
+            epoll::Event::new(evts, id.into()),
+        )
+        .map_err(EpollHelperError::Ctl)
+    }
+
+    pub fn del_event_custom(
+        &mut self,
+        fd: RawFd,
+        id: u16,
+        evts: epoll::Events,
+    ) -> std::result::Result<(), EpollHelperError> {
+        epoll::ctl(
+            self.epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_DEL,
+            fd,
+            epoll::Event::new(evts, id.into()),
         )
         .map_err(EpollHelperError::Ctl)
     }
@@ -112,6 +150,8 @@ impl EpollHelper {
                     EPOLL_HELPER_EVENT_PAUSE => {
                         debug!("PAUSE_EVENT received, pausing epoll loop");
 
+                        handler.pause();
+
                         // Acknowledge the pause is effective by using the
                         // paused_sync barrier.
                         paused_sync.wait();
@@ -137,6 +177,57 @@ impl EpollHelper {
             }
         }
     }
+
+    /// Process the events currently pending, without blocking. This is meant
+    /// for the shared worker pool, which waits on the epoll file descriptor of
+    /// many helpers at once. Unlike `run()`, the pause is not handled here but
+    /// reported to the caller.
+    pub(crate) fn poll(
+        &mut self,
+        handler: &mut dyn EpollHelperHandler,
+    ) -> std::result::Result<EpollHelperPoll, EpollHelperError> {
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        let num_events = match epoll::wait(self.epoll_file.as_raw_fd(), 0, &mut events[..]) {
+            Ok(res) => res,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(EpollHelperError::Wait(e)),
+        };
+
+        let mut pause = false;
+        for event in events.iter().take(num_events) {
+            let ev_type = event.data as u16;
+
+            match ev_type {
+                EPOLL_HELPER_EVENT_KILL => {
+                    debug!("KILL_EVENT received, stopping epoll loop");
+                    return Ok(EpollHelperPoll::Stop);
+                }
+                // The other events are still processed, as the pause is only
+                // effective once acknowledged.
+                EPOLL_HELPER_EVENT_PAUSE => pause = true,
+                _ => {
+                    if handler.handle_event(self, event) {
+                        return Ok(EpollHelperPoll::Stop);
+                    }
+                }
+            }
+        }
+
+        if pause {
+            debug!("PAUSE_EVENT received, pausing epoll loop");
+            handler.pause();
+            Ok(EpollHelperPoll::Pause)
+        } else {
+            Ok(EpollHelperPoll::Continue)
+        }
+    }
+
+    /// Drain the pause event once the device has been resumed.
+    pub(crate) fn drain_pause_evt(&self) {
+        let _ = self.pause_evt.read();
+    }
 }
 
 impl AsRawFd for EpollHelper {
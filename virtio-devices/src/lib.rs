@@ -31,6 +31,7 @@ mod device;
 pub mod balloon;
 pub mod block;
 pub mod block_io_uring;
+mod coalescing;
 mod console;
 pub mod epoll_helper;
 mod iommu;
@@ -40,14 +41,17 @@ pub mod net_util;
 mod pmem;
 mod rng;
 pub mod seccomp_filters;
+mod timer;
 pub mod transport;
 pub mod vhost_user;
 pub mod vsock;
 pub mod watchdog;
+mod worker_pool;
 
 pub use self::balloon::*;
 pub use self::block::*;
 pub use self::block_io_uring::*;
+pub use self::coalescing::*;
 pub use self::console::*;
 pub use self::device::*;
 pub use self::epoll_helper::*;
@@ -59,6 +63,7 @@ pub use self::pmem::*;
 pub use self::rng::*;
 pub use self::vsock::*;
 pub use self::watchdog::*;
+pub use self::worker_pool::*;
 use vm_virtio::{queue::*, VirtioDeviceType};
 
 const DEVICE_INIT: u32 = 0x00;
@@ -66,6 +71,7 @@ const DEVICE_ACKNOWLEDGE: u32 = 0x01;
 const DEVICE_DRIVER: u32 = 0x02;
 const DEVICE_DRIVER_OK: u32 = 0x04;
 const DEVICE_FEATURES_OK: u32 = 0x08;
+const DEVICE_NEEDS_RESET: u32 = 0x40;
 const DEVICE_FAILED: u32 = 0x80;
 
 const VIRTIO_F_VERSION_1: u32 = 32;
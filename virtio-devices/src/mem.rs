@@ -20,18 +20,23 @@ use super::{
 };
 
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::timer::{timerfd_create, timerfd_setup_periodic};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::cmp;
-use std::io;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
 use std::mem::size_of;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
@@ -48,6 +53,11 @@ pub const VIRTIO_MEM_ALIGN_SIZE: u64 = 128 * 1024 * 1024;
 // Use 2 MiB alignment so transparent hugepages can be used by KVM.
 const VIRTIO_MEM_DEFAULT_BLOCK_SIZE: u64 = 512 * 4096;
 const VIRTIO_MEM_USABLE_EXTENT: u64 = 256 * 1024 * 1024;
+// Interval between two notifications sent to the guest while it still has
+// more memory plugged than requested.
+const VIRTIO_MEM_UNPLUG_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// Number of notifications sent before giving up on an unplug request.
+const VIRTIO_MEM_UNPLUG_MAX_RETRIES: u32 = 12;
 
 // Request processed successfully, applicable for
 // - VIRTIO_MEM_REQ_PLUG
@@ -92,6 +102,8 @@ const VIRTIO_MEM_REQ_STATE: u16 = 3;
 const RESIZE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// The guest did not complete the pending unplug request yet.
+const UNPLUG_RETRY_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 
 // Virtio features
 const VIRTIO_MEM_F_ACPI_PXM: u8 = 0;
@@ -185,13 +197,10 @@ struct VirtioMemConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioMemConfig {}
 
+// Requesting the same size again is not an error, as it is the way to notify
+// the guest once more about a pending request it did not complete.
 fn virtio_mem_config_resize(config: &mut VirtioMemConfig, size: u64) -> result::Result<(), Error> {
-    if config.requested_size == size {
-        return Err(Error::ResizeInval(format!(
-            "Virtio-mem resize {} is same with current config.requested_size",
-            size
-        )));
-    } else if size > config.region_size {
+    if size > config.region_size {
         let region_size = config.region_size;
         return Err(Error::ResizeInval(format!(
             "Virtio-mem resize {} is bigger than config.region_size {}",
@@ -256,6 +265,7 @@ impl Request {
 
 pub struct Resize {
     size: Arc<AtomicU64>,
+    plugged_size: Arc<AtomicU64>,
     tx: mpsc::Sender<Result<(), Error>>,
     rx: Option<mpsc::Receiver<Result<(), Error>>>,
     evt: EventFd,
@@ -267,6 +277,7 @@ impl Resize {
 
         Ok(Resize {
             size: Arc::new(AtomicU64::new(0)),
+            plugged_size: Arc::new(AtomicU64::new(0)),
             tx,
             rx: Some(rx),
             evt: EventFd::new(EFD_NONBLOCK)?,
@@ -276,6 +287,7 @@ impl Resize {
     pub fn try_clone(&self) -> Result<Self, Error> {
         Ok(Resize {
             size: self.size.clone(),
+            plugged_size: self.plugged_size.clone(),
             tx: self.tx.clone(),
             rx: None,
             evt: self.evt.try_clone().map_err(Error::EventFdTryCloneFail)?,
@@ -296,11 +308,31 @@ impl Resize {
         self.size.load(Ordering::Acquire)
     }
 
+    /// Size last requested to the guest.
+    pub fn requested_size(&self) -> u64 {
+        self.get_size()
+    }
+
+    /// Size currently plugged by the guest. It differs from the requested
+    /// size as long as the guest has not completed the last resize.
+    pub fn plugged_size(&self) -> u64 {
+        self.plugged_size.load(Ordering::Acquire)
+    }
+
+    fn set_plugged_size(&self, size: u64) {
+        self.plugged_size.store(size, Ordering::Release)
+    }
+
     fn send(&self, r: Result<(), Error>) -> Result<(), mpsc::SendError<Result<(), Error>>> {
         self.tx.send(r)
     }
 }
 
+#[derive(Default, Clone)]
+pub struct MemCounters {
+    reclaimed_bytes: Arc<AtomicU64>,
+}
+
 struct MemEpollHandler {
     host_addr: u64,
     host_fd: Option<RawFd>,
@@ -313,6 +345,9 @@ struct MemEpollHandler {
     queue_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    unplug_retry_timer: File,
+    unplug_retries: Option<u32>,
+    counters: MemCounters,
 }
 
 struct StateChangeRequest<'a> {
@@ -566,6 +601,9 @@ impl MemEpollHandler {
                             );
                             if resp_type == VIRTIO_MEM_RESP_ACK {
                                 config.plugged_size -= size;
+                                self.counters
+                                    .reclaimed_bytes
+                                    .fetch_add(size, Ordering::AcqRel);
                             }
                             MemEpollHandler::virtio_mem_send_response(
                                 &mem,
@@ -582,6 +620,9 @@ impl MemEpollHandler {
                                 self.host_fd,
                             );
                             if resp_type == VIRTIO_MEM_RESP_ACK {
+                                self.counters
+                                    .reclaimed_bytes
+                                    .fetch_add(config.plugged_size, Ordering::AcqRel);
                                 config.plugged_size = 0;
                                 config.usable_region_size = cmp::min(
                                     config.region_size,
@@ -623,9 +664,68 @@ impl MemEpollHandler {
         for &(desc_index, len) in &used_desc_heads[..used_count] {
             self.queue.add_used(&mem, desc_index, len);
         }
+
+        let config = *self.config.lock().unwrap();
+        self.resize.set_plugged_size(config.plugged_size);
+        if self.unplug_retries.is_some() && config.plugged_size <= config.requested_size {
+            info!(
+                "Virtio-mem unplug completed, {} bytes plugged",
+                config.plugged_size
+            );
+            if let Err(e) = self.stop_unplug_retry() {
+                error!("Failed to disarm unplug retry timer: {:?}", e);
+            }
+        }
+
         used_count > 0
     }
 
+    // Keep notifying the guest periodically until it unplugs enough memory
+    // to match the requested size, as it might not be able to complete the
+    // request right away (e.g. memory blocks temporarily unmovable).
+    fn start_unplug_retry(&mut self) -> io::Result<()> {
+        timerfd_setup_periodic(&self.unplug_retry_timer, VIRTIO_MEM_UNPLUG_RETRY_INTERVAL)?;
+        self.unplug_retries = Some(0);
+        Ok(())
+    }
+
+    fn stop_unplug_retry(&mut self) -> io::Result<()> {
+        self.unplug_retries = None;
+        // A zero interval disarms the timer.
+        timerfd_setup_periodic(&self.unplug_retry_timer, Duration::from_secs(0))
+    }
+
+    fn unplug_retry_expired(&mut self) -> result::Result<(), DeviceError> {
+        // When reading from the timerfd you get 8 bytes indicating
+        // the number of times this event has elapsed since the last read.
+        let mut buf = [0u8; 8];
+        self.unplug_retry_timer
+            .read_exact(&mut buf)
+            .map_err(DeviceError::IoError)?;
+
+        let config = *self.config.lock().unwrap();
+        let retries = match self.unplug_retries {
+            Some(retries) if config.plugged_size > config.requested_size => retries + 1,
+            _ => return self.stop_unplug_retry().map_err(DeviceError::IoError),
+        };
+
+        if retries > VIRTIO_MEM_UNPLUG_MAX_RETRIES {
+            warn!(
+                "Virtio-mem unplug not completed after {} retries: {} bytes \
+                plugged, {} bytes requested",
+                VIRTIO_MEM_UNPLUG_MAX_RETRIES, config.plugged_size, config.requested_size
+            );
+            return self.stop_unplug_retry().map_err(DeviceError::IoError);
+        }
+
+        debug!(
+            "Virtio-mem unplug retry {}: {} bytes plugged, {} bytes requested",
+            retries, config.plugged_size, config.requested_size
+        );
+        self.unplug_retries = Some(retries);
+        self.signal(&VirtioInterruptType::Config)
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -634,6 +734,7 @@ impl MemEpollHandler {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.resize.evt.as_raw_fd(), RESIZE_EVENT)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.unplug_retry_timer.as_raw_fd(), UNPLUG_RETRY_EVENT)?;
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -653,6 +754,14 @@ impl EpollHelperHandler for MemEpollHandler {
                     let mut config = self.config.lock().unwrap();
                     let mut signal_error = false;
                     let mut r = virtio_mem_config_resize(&mut config, size);
+                    if r.is_err() {
+                        // Keep reporting the size actually requested to the guest.
+                        self.resize
+                            .size
+                            .store(config.requested_size, Ordering::Release);
+                    }
+                    let shrinking = config.plugged_size > config.requested_size;
+                    drop(config);
                     r = match r {
                         Err(e) => Err(e),
                         _ => match self.signal(&VirtioInterruptType::Config) {
@@ -663,6 +772,7 @@ impl EpollHelperHandler for MemEpollHandler {
                             _ => Ok(()),
                         },
                     };
+                    let resized = r.is_ok();
                     if let Err(e) = self.resize.send(r) {
                         error!("Sending \"resize\" response: {:?}", e);
                         return true;
@@ -670,6 +780,17 @@ impl EpollHelperHandler for MemEpollHandler {
                     if signal_error {
                         return true;
                     }
+                    if resized {
+                        let r = if shrinking {
+                            self.start_unplug_retry()
+                        } else {
+                            self.stop_unplug_retry()
+                        };
+                        if let Err(e) = r {
+                            error!("Failed to set unplug retry timer: {:?}", e);
+                            return true;
+                        }
+                    }
                 }
             }
             QUEUE_AVAIL_EVENT => {
@@ -683,6 +804,12 @@ impl EpollHelperHandler for MemEpollHandler {
                     }
                 }
             }
+            UNPLUG_RETRY_EVENT => {
+                if let Err(e) = self.unplug_retry_expired() {
+                    error!("Failed to process unplug retry: {:?}", e);
+                    return true;
+                }
+            }
             _ => {
                 error!("Unexpected event: {}", ev_type);
                 return true;
@@ -701,6 +828,7 @@ pub struct Mem {
     host_fd: Option<RawFd>,
     config: Arc<Mutex<VirtioMemConfig>>,
     seccomp_action: SeccompAction,
+    counters: MemCounters,
 }
 
 impl Mem {
@@ -743,6 +871,7 @@ impl Mem {
                     format!("Virtio-mem resize {} got {:?}", initial_size, e),
                 )
             })?;
+            resize.size.store(initial_size, Ordering::Release);
         }
 
         if let Some(node_id) = numa_node_id {
@@ -750,10 +879,14 @@ impl Mem {
             config.node_id = node_id;
         }
 
-        let host_fd = if let Some(f_offset) = region.file_offset() {
-            Some(f_offset.file().as_raw_fd())
-        } else {
-            None
+        // Only the pages of a shared file mapping live in the file. A private
+        // mapping, such as the memory of a clone mapping the snapshot files
+        // over the original ones, only needs its pages to be dropped, the
+        // file not even being the one backing the memory then.
+        let shared = region.flags() & libc::MAP_SHARED == libc::MAP_SHARED;
+        let host_fd = match region.file_offset() {
+            Some(f_offset) if shared => Some(f_offset.file().as_raw_fd()),
+            _ => None,
         };
 
         Ok(Mem {
@@ -770,6 +903,7 @@ impl Mem {
             host_fd,
             config: Arc::new(Mutex::new(config)),
             seccomp_action,
+            counters: MemCounters::default(),
         })
     }
 }
@@ -833,6 +967,11 @@ impl VirtioDevice for Mem {
                 ActivateError::BadActivate
             })?;
 
+        let unplug_retry_timer = timerfd_create().map_err(|e| {
+            error!("failed to create unplug retry timer: {}", e);
+            ActivateError::BadActivate
+        })?;
+
         let config = self.config.lock().unwrap();
         let mut handler = MemEpollHandler {
             host_addr: self.host_addr,
@@ -849,6 +988,9 @@ impl VirtioDevice for Mem {
             queue_evt: queue_evts.remove(0),
             kill_evt,
             pause_evt,
+            unplug_retry_timer: unsafe { File::from_raw_fd(unplug_retry_timer) },
+            unplug_retries: None,
+            counters: self.counters.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -879,6 +1021,17 @@ impl VirtioDevice for Mem {
     fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
         self.common.reset()
     }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        counters.insert(
+            "reclaimed_bytes",
+            Wrapping(self.counters.reclaimed_bytes.load(Ordering::Acquire)),
+        );
+
+        Some(counters)
+    }
 }
 
 impl Pausable for Mem {
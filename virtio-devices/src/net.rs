@@ -11,9 +11,11 @@ use super::net_util::{
 };
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    set_thread_affinity, ActivateError, ActivateResult, EpollHelper, EpollHelperError,
+    EpollHelperHandler, EpollWorkerPool, InterruptCoalescer, Queue, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
+use crate::coalescing::create_coalescer;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
@@ -32,6 +34,7 @@ use std::thread;
 use std::vec::Vec;
 use virtio_bindings::bindings::virtio_net::*;
 use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_device::Recorder;
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
@@ -45,6 +48,10 @@ pub const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 pub const TX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // A frame is available for reading from the tap device to receive in the guest.
 pub const RX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The interrupt coalescing timer of the receive queue has expired.
+pub const RX_COALESCING_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// The interrupt coalescing timer of the transmit queue has expired.
+pub const TX_COALESCING_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
 
 #[derive(Debug)]
 pub enum Error {
@@ -53,6 +60,9 @@ pub enum Error {
 
     // Using existing tap
     TapError(TapError),
+
+    /// Failed to signal the guest about a configuration change.
+    FailedSignalingConfig(std::io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -69,12 +79,26 @@ struct NetEpollHandler {
     // a restore as the vCPU thread isn't ready to handle the interrupt. This causes
     // issues when combined with VIRTIO_RING_F_EVENT_IDX interrupt suppression.
     driver_awake: bool,
+    // Interrupt coalescers of the receive and transmit queues, if enabled.
+    coalescers: Vec<InterruptCoalescer>,
 }
 
 impl NetEpollHandler {
-    fn signal_used_queue(&self, queue: &Queue) -> result::Result<(), DeviceError> {
+    fn signal_used_queue(&mut self, queue_index: usize) -> result::Result<(), DeviceError> {
+        if let Some(coalescer) = self.coalescers.get_mut(queue_index) {
+            if !coalescer.add_event() {
+                return Ok(());
+            }
+        }
+        self.trigger_used_queue(queue_index)
+    }
+
+    fn trigger_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
         self.interrupt_cb
-            .trigger(&VirtioInterruptType::Queue, Some(queue))
+            .trigger(
+                &VirtioInterruptType::Queue,
+                Some(&self.queue_pair[queue_index]),
+            )
             .map_err(|e| {
                 error!("Failed to signal used queue: {:?}", e);
                 DeviceError::FailedSignalingUsedQueue(e)
@@ -93,7 +117,7 @@ impl NetEpollHandler {
             .map_err(DeviceError::NetQueuePair)?
             || !self.driver_awake
         {
-            self.signal_used_queue(&self.queue_pair[0])?;
+            self.signal_used_queue(0)?;
             info!("Signalling RX queue");
         } else {
             info!("Not signalling RX queue");
@@ -113,7 +137,7 @@ impl NetEpollHandler {
             .map_err(DeviceError::NetQueuePair)?
             || !self.driver_awake
         {
-            self.signal_used_queue(&self.queue_pair[1])?;
+            self.signal_used_queue(1)?;
             info!("Signalling TX queue");
         } else {
             info!("Not signalling TX queue");
@@ -128,7 +152,7 @@ impl NetEpollHandler {
             .map_err(DeviceError::NetQueuePair)?
             || !self.driver_awake
         {
-            self.signal_used_queue(&self.queue_pair[0])?;
+            self.signal_used_queue(0)?;
             info!("Signalling RX queue");
         } else {
             info!("Not signalling RX queue");
@@ -136,14 +160,14 @@ impl NetEpollHandler {
         Ok(())
     }
 
-    fn run(
-        &mut self,
-        paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
-    ) -> result::Result<(), EpollHelperError> {
+    fn epoll_helper(&mut self) -> result::Result<EpollHelper, EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt_pair[0].as_raw_fd(), RX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evt_pair[1].as_raw_fd(), TX_QUEUE_EVENT)?;
+        if let [rx_coalescer, tx_coalescer] = self.coalescers.as_slice() {
+            helper.add_event(rx_coalescer.as_raw_fd(), RX_COALESCING_TIMER_EVENT)?;
+            helper.add_event(tx_coalescer.as_raw_fd(), TX_COALESCING_TIMER_EVENT)?;
+        }
 
         // If there are some already available descriptors on the RX queue,
         // then we can start the thread while listening onto the TAP.
@@ -159,6 +183,15 @@ impl NetEpollHandler {
         // The NetQueuePair needs the epoll fd.
         self.net.epoll_fd = Some(helper.as_raw_fd());
 
+        Ok(helper)
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = self.epoll_helper()?;
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -189,6 +222,22 @@ impl EpollHelperHandler for NetEpollHandler {
                     return true;
                 }
             }
+            RX_COALESCING_TIMER_EVENT | TX_COALESCING_TIMER_EVENT => {
+                let queue_index = (ev_type - RX_COALESCING_TIMER_EVENT) as usize;
+                match self.coalescers[queue_index].timer_expired() {
+                    Ok(true) => {
+                        if let Err(e) = self.trigger_used_queue(queue_index) {
+                            error!("Failed to signal used queue: {:?}", e);
+                            return true;
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Failed to read interrupt coalescing timer: {:?}", e);
+                        return true;
+                    }
+                }
+            }
             _ => {
                 error!("Unknown event: {}", ev_type);
                 return true;
@@ -206,6 +255,8 @@ pub struct Net {
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
     counters: NetCounters,
     seccomp_action: SeccompAction,
+    recorder: Option<Arc<Recorder>>,
+    interrupt_coalescing: Option<(u32, u64)>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -233,6 +284,7 @@ impl Net {
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
             | 1 << VIRTIO_RING_F_EVENT_IDX
             | 1 << VIRTIO_F_VERSION_1;
 
@@ -244,6 +296,7 @@ impl Net {
         let queue_num = num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
+        config.status = VIRTIO_NET_S_LINK_UP as u16;
         if let Some(mac) = guest_mac {
             build_net_config_space(&mut config, mac, num_queues, &mut avail_features);
         } else {
@@ -264,6 +317,8 @@ impl Net {
             ctrl_queue_epoll_thread: None,
             counters: NetCounters::default(),
             seccomp_action,
+            recorder: None,
+            interrupt_coalescing: None,
         })
     }
 
@@ -316,6 +371,48 @@ impl Net {
         )
     }
 
+    /// Update the link status reported through the configuration space and
+    /// notify the guest driver if the device has already been activated.
+    pub fn set_link_status(&mut self, up: bool) -> Result<()> {
+        let status = self.config.status;
+        self.config.status = if up {
+            status | VIRTIO_NET_S_LINK_UP as u16
+        } else {
+            status & !(VIRTIO_NET_S_LINK_UP as u16)
+        };
+
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb
+                .trigger(&VirtioInterruptType::Config, None)
+                .map_err(Error::FailedSignalingConfig)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record every frame received from the TAP interfaces.
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Aggregate the interrupts notifying the guest about received and
+    /// transmitted frames, injecting one every `max_events` notifications,
+    /// or `max_usecs` after the first pending one, for each queue.
+    pub fn set_interrupt_coalescing(&mut self, max_events: u32, max_usecs: u64) {
+        self.interrupt_coalescing = Some((max_events, max_usecs));
+    }
+
+    /// Process the RX/TX queue pairs on the shared worker pool instead of
+    /// one thread per pair.
+    pub fn set_worker_pool(&mut self, worker_pool: Arc<EpollWorkerPool>) {
+        self.common.set_worker_pool(worker_pool);
+    }
+
+    /// Pin the thread processing each listed queue pair to the given host CPUs.
+    pub fn set_queue_affinity(&mut self, queue_affinity: HashMap<u16, Vec<usize>>) {
+        self.common.set_queue_affinity(queue_affinity);
+    }
+
     fn state(&self) -> NetState {
         NetState {
             avail_features: self.common.avail_features,
@@ -328,7 +425,13 @@ impl Net {
     fn set_state(&mut self, state: &NetState) -> Result<()> {
         self.common.avail_features = state.avail_features;
         self.common.acked_features = state.acked_features;
+        // The MAC address comes from the configuration, which gives a new
+        // one to a clone of the VM. The guest only reads it when probing the
+        // device, drivers ignoring it on configuration changes, hence no
+        // interrupt is sent about it.
+        let mac = self.config.mac;
         self.config = state.config;
+        self.config.mac = mac;
         self.common.queue_sizes = state.queue_size.clone();
 
         Ok(())
@@ -412,8 +515,14 @@ impl VirtioDevice for Net {
                 let paused = self.common.paused.clone();
                 // Let's update the barrier as we need 1 for each RX/TX pair +
                 // 1 for the control queue + 1 for the main thread signalling
-                // the pause.
-                self.common.paused_sync = Some(Arc::new(Barrier::new(taps.len() + 2)));
+                // the pause. The RX/TX pairs handled by the worker pool are
+                // acknowledging the pause at once.
+                let queue_pair_threads = if self.common.worker_pool.is_some() {
+                    1
+                } else {
+                    taps.len()
+                };
+                self.common.paused_sync = Some(Arc::new(Barrier::new(queue_pair_threads + 2)));
                 let paused_sync = self.common.paused_sync.clone();
 
                 // Retrieve seccomp filter for virtio_net_ctl thread
@@ -439,7 +548,9 @@ impl VirtioDevice for Net {
             let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
 
             let mut epoll_threads = Vec::new();
-            for _ in 0..taps.len() {
+            let mut pooled_handlers: Vec<(EpollHelper, Box<dyn EpollHelperHandler + Send>)> =
+                Vec::new();
+            for i in 0..taps.len() {
                 let rx = RxVirtio::new();
                 let tx = TxVirtio::new();
                 let rx_tap_listening = false;
@@ -475,6 +586,14 @@ impl VirtioDevice for Net {
                         ActivateError::BadActivate
                     })?;
 
+                // One coalescer for each of the receive and transmit queues.
+                let mut coalescers = Vec::new();
+                for _ in 0..2 {
+                    if let Some(coalescer) = create_coalescer(self.interrupt_coalescing)? {
+                        coalescers.push(coalescer);
+                    }
+                }
+
                 let mut handler = NetEpollHandler {
                     net: NetQueuePair {
                         mem: Some(mem.clone()),
@@ -485,6 +604,7 @@ impl VirtioDevice for Net {
                         rx_tap_listening,
                         counters: self.counters.clone(),
                         tap_event_id: RX_TAP_EVENT,
+                        recorder: self.recorder.as_ref().map(|r| (self.id.clone(), r.clone())),
                     },
                     queue_pair,
                     queue_evt_pair,
@@ -492,10 +612,22 @@ impl VirtioDevice for Net {
                     kill_evt,
                     pause_evt,
                     driver_awake: false,
+                    coalescers,
                 };
 
+                if self.common.worker_pool.is_some() {
+                    let helper = handler.epoll_helper().map_err(|e| {
+                        error!("failed to create the virtio-net epoll helper: {:?}", e);
+                        ActivateError::BadActivate
+                    })?;
+                    pooled_handlers.push((helper, Box::new(handler)));
+                    continue;
+                }
+
                 let paused = self.common.paused.clone();
                 let paused_sync = self.common.paused_sync.clone();
+                let host_cpus = self.common.queue_thread_affinity(i);
+
                 // Retrieve seccomp filter for virtio_net thread
                 let virtio_net_seccomp_filter =
                     get_seccomp_filter(&self.seccomp_action, Thread::VirtioNet)
@@ -503,6 +635,11 @@ impl VirtioDevice for Net {
                 thread::Builder::new()
                     .name("virtio_net".to_string())
                     .spawn(move || {
+                        if let Some(host_cpus) = host_cpus {
+                            if let Err(e) = set_thread_affinity(&host_cpus) {
+                                error!("Error setting the thread affinity: {}", e);
+                            }
+                        }
                         if let Err(e) = SeccompFilter::apply(virtio_net_seccomp_filter) {
                             error!("Error applying seccomp filter: {:?}", e);
                         } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
@@ -516,6 +653,7 @@ impl VirtioDevice for Net {
                     })?;
             }
 
+            self.common.add_pooled_handlers(pooled_handlers)?;
             self.common.epoll_threads = Some(epoll_threads);
 
             return Ok(());
@@ -608,3 +746,66 @@ impl Snapshottable for Net {
 }
 impl Transportable for Net {}
 impl Migratable for Net {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // Offset of the status field in the configuration space, after the MAC.
+    const STATUS_OFFSET: u64 = 6;
+
+    #[derive(Default)]
+    struct TestInterrupt {
+        config_changes: AtomicUsize,
+    }
+
+    impl VirtioInterrupt for TestInterrupt {
+        fn trigger(
+            &self,
+            int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            if let VirtioInterruptType::Config = int_type {
+                self.config_changes.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    fn read_status(net: &Net) -> u16 {
+        let mut data = [0u8; 2];
+        net.read_config(STATUS_OFFSET, &mut data);
+        u16::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_set_link_status() {
+        let mut net = Net::new_with_tap(
+            "net0".to_string(),
+            Vec::new(),
+            None,
+            false,
+            2,
+            256,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        assert_ne!(read_status(&net) & VIRTIO_NET_S_LINK_UP as u16, 0);
+
+        // No interrupt can be raised before the device is activated.
+        net.set_link_status(false).unwrap();
+        assert_eq!(read_status(&net) & VIRTIO_NET_S_LINK_UP as u16, 0);
+
+        let interrupt = Arc::new(TestInterrupt::default());
+        net.common.interrupt_cb = Some(interrupt.clone());
+
+        net.set_link_status(true).unwrap();
+        assert_ne!(read_status(&net) & VIRTIO_NET_S_LINK_UP as u16, 0);
+        assert_eq!(interrupt.config_changes.load(Ordering::SeqCst), 1);
+
+        net.set_link_status(false).unwrap();
+        assert_eq!(read_status(&net) & VIRTIO_NET_S_LINK_UP as u16, 0);
+        assert_eq!(interrupt.config_changes.load(Ordering::SeqCst), 2);
+    }
+}
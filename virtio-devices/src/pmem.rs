@@ -23,6 +23,7 @@ use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use vm_memory::{
@@ -44,6 +45,8 @@ const VIRTIO_PMEM_RESP_TYPE_EIO: u32 = 1;
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The flush worker completed a flush of the backing file.
+const FLUSH_COMPLETE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 
 #[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
 #[repr(C)]
@@ -155,14 +158,42 @@ impl Request {
     }
 }
 
+// Flushes the backing file each time it is asked to, reporting the result
+// through the `results` channel, followed by a notification on `complete_evt`.
+// The worker stops once the handler owning the other end of the channels is
+// dropped.
+fn flush_worker(
+    disk: File,
+    requests: mpsc::Receiver<()>,
+    results: mpsc::Sender<io::Result<()>>,
+    complete_evt: EventFd,
+) {
+    for () in requests.iter() {
+        if results.send(disk.sync_all()).is_err() {
+            break;
+        }
+        if let Err(e) = complete_evt.write(1) {
+            error!("Failed to notify flush completion: {}", e);
+            break;
+        }
+    }
+}
+
 struct PmemEpollHandler {
     queue: Queue,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
-    disk: File,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    flush_sender: mpsc::Sender<()>,
+    flush_receiver: mpsc::Receiver<io::Result<()>>,
+    flush_complete_evt: EventFd,
+    // Flush requests waiting for the next flush of the backing file, given
+    // the one in progress might not cover the writes preceding them.
+    pending_flushes: Vec<(u16, GuestAddress)>,
+    // Flush requests completed by the flush in progress.
+    inflight_flushes: Vec<(u16, GuestAddress)>,
 }
 
 impl PmemEpollHandler {
@@ -173,22 +204,11 @@ impl PmemEpollHandler {
         for avail_desc in self.queue.iter(&mem) {
             let len = match Request::parse(&avail_desc, &mem) {
                 Ok(ref req) if (req.type_ == RequestType::Flush) => {
-                    let status_code = match self.disk.sync_all() {
-                        Ok(()) => VIRTIO_PMEM_RESP_TYPE_OK,
-                        Err(e) => {
-                            error!("failed flushing disk image: {}", e);
-                            VIRTIO_PMEM_RESP_TYPE_EIO
-                        }
-                    };
-
-                    let resp = VirtioPmemResp { ret: status_code };
-                    match mem.write_obj(resp, req.status_addr) {
-                        Ok(_) => size_of::<VirtioPmemResp>() as u32,
-                        Err(e) => {
-                            error!("bad guest memory address: {}", e);
-                            0
-                        }
-                    }
+                    // All the flush requests received while the backing file
+                    // is being flushed are completed by a single flush.
+                    self.pending_flushes
+                        .push((avail_desc.index, req.status_addr));
+                    continue;
                 }
                 Ok(ref req) => {
                     // Currently, there is only one virtio-pmem request, FLUSH.
@@ -208,7 +228,82 @@ impl PmemEpollHandler {
         for &(desc_index, len) in &used_desc_heads[..used_count] {
             self.queue.add_used(&mem, desc_index, len);
         }
-        used_count > 0
+
+        let flushed = self.start_flush();
+        used_count > 0 || flushed
+    }
+
+    // Start flushing the backing file on behalf of the pending flush requests,
+    // unless a flush is already in progress. Returns true if some requests
+    // have been completed right away.
+    fn start_flush(&mut self) -> bool {
+        if !self.inflight_flushes.is_empty() || self.pending_flushes.is_empty() {
+            return false;
+        }
+
+        std::mem::swap(&mut self.inflight_flushes, &mut self.pending_flushes);
+        if self.flush_sender.send(()).is_err() {
+            error!("virtio-pmem flush worker is not running");
+            return self.complete_flushes(VIRTIO_PMEM_RESP_TYPE_EIO);
+        }
+        false
+    }
+
+    fn complete_flushes(&mut self, status_code: u32) -> bool {
+        let mem = self.mem.memory();
+        let resp = VirtioPmemResp { ret: status_code };
+        let mut used = false;
+        for (desc_index, status_addr) in self.inflight_flushes.drain(..) {
+            let len = match mem.write_obj(resp, status_addr) {
+                Ok(_) => size_of::<VirtioPmemResp>() as u32,
+                Err(e) => {
+                    error!("bad guest memory address: {}", e);
+                    0
+                }
+            };
+            self.queue.add_used(&mem, desc_index, len);
+            used = true;
+        }
+        used
+    }
+
+    fn process_flush_completion(&mut self) -> bool {
+        let mut used = false;
+        while let Ok(res) = self.flush_receiver.try_recv() {
+            let status_code = match res {
+                Ok(()) => VIRTIO_PMEM_RESP_TYPE_OK,
+                Err(e) => {
+                    error!("failed flushing disk image: {}", e);
+                    VIRTIO_PMEM_RESP_TYPE_EIO
+                }
+            };
+            used |= self.complete_flushes(status_code);
+        }
+        // Flush again for the requests received in the meantime.
+        let flushed = self.start_flush();
+        used || flushed
+    }
+
+    // Wait for the flush in progress, and the one covering the requests
+    // received in the meantime, so that no flush request is left pending.
+    fn drain_flushes(&mut self) -> bool {
+        let mut used = false;
+        while !self.inflight_flushes.is_empty() {
+            let status_code = match self.flush_receiver.recv() {
+                Ok(Ok(())) => VIRTIO_PMEM_RESP_TYPE_OK,
+                Ok(Err(e)) => {
+                    error!("failed flushing disk image: {}", e);
+                    VIRTIO_PMEM_RESP_TYPE_EIO
+                }
+                Err(_) => {
+                    error!("virtio-pmem flush worker is not running");
+                    VIRTIO_PMEM_RESP_TYPE_EIO
+                }
+            };
+            used |= self.complete_flushes(status_code);
+            used |= self.start_flush();
+        }
+        used
     }
 
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
@@ -227,6 +322,7 @@ impl PmemEpollHandler {
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.flush_complete_evt.as_raw_fd(), FLUSH_COMPLETE_EVENT)?;
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -248,6 +344,17 @@ impl EpollHelperHandler for PmemEpollHandler {
                     }
                 }
             }
+            FLUSH_COMPLETE_EVENT => {
+                if let Err(e) = self.flush_complete_evt.read() {
+                    error!("Failed to get flush completion event: {:?}", e);
+                    return true;
+                } else if self.process_flush_completion() {
+                    if let Err(e) = self.signal_used_queue() {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                }
+            }
             _ => {
                 error!("Unexpected event: {}", ev_type);
                 return true;
@@ -255,6 +362,16 @@ impl EpollHelperHandler for PmemEpollHandler {
         }
         false
     }
+
+    // The flush requests are not part of the device state, hence they must
+    // all be completed before the device can be snapshotted or migrated.
+    fn pause(&mut self) {
+        if self.drain_flushes() {
+            if let Err(e) = self.signal_used_queue() {
+                error!("Failed to signal used queue: {:?}", e);
+            }
+        }
+    }
 }
 
 pub struct Pmem {
@@ -395,19 +512,54 @@ impl VirtioDevice for Pmem {
                 error!("failed cloning pmem disk: {}", e);
                 ActivateError::BadActivate
             })?;
+            let flush_complete_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                error!("failed creating flush completion eventfd: {}", e);
+                ActivateError::BadActivate
+            })?;
+            let worker_complete_evt = flush_complete_evt.try_clone().map_err(|e| {
+                error!("failed to clone flush completion eventfd: {}", e);
+                ActivateError::BadActivate
+            })?;
+            let (flush_sender, worker_requests) = mpsc::channel();
+            let (worker_results, flush_receiver) = mpsc::channel();
+
             let mut handler = PmemEpollHandler {
                 queue: queues.remove(0),
                 mem,
-                disk,
                 interrupt_cb,
                 queue_evt: queue_evts.remove(0),
                 kill_evt,
                 pause_evt,
+                flush_sender,
+                flush_receiver,
+                flush_complete_evt,
+                pending_flushes: Vec::new(),
+                inflight_flushes: Vec::new(),
             };
 
             let paused = self.common.paused.clone();
             let paused_sync = self.common.paused_sync.clone();
             let mut epoll_threads = Vec::new();
+
+            // Retrieve seccomp filter for virtio_pmem_flush thread
+            let virtio_pmem_flush_seccomp_filter =
+                get_seccomp_filter(&self.seccomp_action, Thread::VirtioPmemFlush)
+                    .map_err(ActivateError::CreateSeccompFilter)?;
+            thread::Builder::new()
+                .name("virtio_pmem_flush".to_string())
+                .spawn(move || {
+                    if let Err(e) = SeccompFilter::apply(virtio_pmem_flush_seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                    } else {
+                        flush_worker(disk, worker_requests, worker_results, worker_complete_evt);
+                    }
+                })
+                .map(|thread| epoll_threads.push(thread))
+                .map_err(|e| {
+                    error!("failed to clone virtio-pmem flush thread: {}", e);
+                    ActivateError::BadActivate
+                })?;
+
             // Retrieve seccomp filter for virtio_pmem thread
             let virtio_pmem_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioPmem)
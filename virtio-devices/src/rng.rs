@@ -9,16 +9,21 @@ use super::{
     VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::timer::{timerfd_create, timerfd_setup};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccomp::{SeccompAction, SeccompFilter};
+use std::cmp;
 use std::fs::File;
-use std::io;
-use std::os::unix::io::AsRawFd;
+use std::io::{self, Read};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::result;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
+use vm_device::{RecordKind, Recorder};
 use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
@@ -31,6 +36,75 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The rate limiter has refilled its budget.
+const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+
+// Period at which the rate limiter budget is replenished.
+const RATE_LIMITER_REFILL_MS: u64 = 100;
+
+// Token bucket limiting the number of bytes handed to the guest per second.
+// The bucket holds at most one second worth of bytes, and is refilled
+// proportionally to the time elapsed since the last refill.
+struct RateLimiter {
+    bytes_per_sec: u64,
+    budget: u64,
+    last_refill: Instant,
+    timer: File,
+    timer_armed: bool,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> io::Result<Self> {
+        let timer_fd = timerfd_create()?;
+        let timer = unsafe { File::from_raw_fd(timer_fd) };
+
+        Ok(RateLimiter {
+            bytes_per_sec,
+            budget: bytes_per_sec,
+            last_refill: Instant::now(),
+            timer,
+            timer_armed: false,
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let tokens = (elapsed.as_micros() * self.bytes_per_sec as u128 / 1_000_000) as u64;
+        if tokens > 0 {
+            self.budget = cmp::min(self.budget.saturating_add(tokens), self.bytes_per_sec);
+            self.last_refill = now;
+        }
+    }
+
+    // Returns how many bytes out of `wanted` can be consumed right now.
+    fn consume(&mut self, wanted: u64) -> u64 {
+        self.refill();
+        let granted = cmp::min(wanted, self.budget);
+        self.budget -= granted;
+        granted
+    }
+
+    // Program the timer so that the worker gets woken up once some budget
+    // is available again.
+    fn arm_timer(&mut self) -> io::Result<()> {
+        if self.timer_armed {
+            return Ok(());
+        }
+        timerfd_setup(&self.timer, Duration::from_millis(RATE_LIMITER_REFILL_MS))?;
+        self.timer_armed = true;
+        Ok(())
+    }
+
+    fn timer_expired(&mut self) -> io::Result<()> {
+        // When reading from the timerfd you get 8 bytes indicating
+        // the number of times this event has elapsed since the last read.
+        let mut buf = [0u8; 8];
+        self.timer.read_exact(&mut buf)?;
+        self.timer_armed = false;
+        Ok(())
+    }
+}
 
 struct RngEpollHandler {
     queues: Vec<Queue>,
@@ -40,10 +114,17 @@ struct RngEpollHandler {
     queue_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    rate_limiter: Option<RateLimiter>,
+    recorder: Option<(String, Arc<Recorder>)>,
+    source_exhausted: bool,
 }
 
 impl RngEpollHandler {
     fn process_queue(&mut self) -> bool {
+        if self.source_exhausted {
+            return false;
+        }
+
         let queue = &mut self.queues[0];
 
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
@@ -54,16 +135,47 @@ impl RngEpollHandler {
 
             // Drivers can only read from the random device.
             if avail_desc.is_write_only() {
+                let mut wanted = avail_desc.len;
+                if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                    wanted = rate_limiter.consume(u64::from(wanted)) as u32;
+                    if wanted == 0 {
+                        // Out of budget, leave the descriptor to the driver
+                        // and retry once the budget has been replenished.
+                        queue.go_to_previous_position();
+                        if let Err(e) = rate_limiter.arm_timer() {
+                            error!("Failed to arm rate limiter timer: {:?}", e);
+                        }
+                        break;
+                    }
+                }
+
                 // Fill the read with data from the random device on the host.
-                if mem
-                    .read_from(
-                        avail_desc.addr,
-                        &mut self.random_file,
-                        avail_desc.len as usize,
-                    )
-                    .is_ok()
-                {
-                    len = avail_desc.len;
+                // Hardware generators may return less data than requested,
+                // hence only report what has actually been written.
+                match mem.read_from(avail_desc.addr, &mut self.random_file, wanted as usize) {
+                    // A regular file runs out of entropy once its end is
+                    // reached. Completing the requests without any data would
+                    // have the driver retrying in a loop, hence they are left
+                    // pending.
+                    Ok(0) if wanted > 0 => {
+                        error!("Entropy source exhausted, no more entropy is provided");
+                        self.source_exhausted = true;
+                        queue.go_to_previous_position();
+                        break;
+                    }
+                    Ok(count) => len = count as u32,
+                    Err(e) => error!("Failed to read from rng source: {:?}", e),
+                }
+
+                if let Some((id, recorder)) = &self.recorder {
+                    let mut data = vec![0u8; len as usize];
+                    if let Err(e) = mem
+                        .read_slice(&mut data, avail_desc.addr)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                        .and_then(|_| recorder.record(id, RecordKind::Rng, &data))
+                    {
+                        warn!("Failed recording random bytes: {:?}", e);
+                    }
                 }
             }
 
@@ -93,6 +205,9 @@ impl RngEpollHandler {
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            helper.add_event(rate_limiter.timer.as_raw_fd(), RATE_LIMITER_EVENT)?;
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -114,6 +229,20 @@ impl EpollHelperHandler for RngEpollHandler {
                     }
                 }
             }
+            RATE_LIMITER_EVENT => {
+                if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                    if let Err(e) = rate_limiter.timer_expired() {
+                        error!("Failed to read rate limiter timer: {:?}", e);
+                        return true;
+                    }
+                }
+                if self.process_queue() {
+                    if let Err(e) = self.signal_used_queue() {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                }
+            }
             _ => {
                 error!("Unexpected event: {}", ev_type);
                 return true;
@@ -129,6 +258,8 @@ pub struct Rng {
     id: String,
     random_file: Option<File>,
     seccomp_action: SeccompAction,
+    rate_limit: Option<u64>,
+    recorder: Option<Arc<Recorder>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -139,13 +270,25 @@ pub struct RngState {
 
 impl Rng {
     /// Create a new virtio rng device that gets random data from /dev/urandom.
+    /// When `rate_limit` is set, the amount of entropy handed to the guest is
+    /// capped to that many bytes per second.
     pub fn new(
         id: String,
         path: &str,
         iommu: bool,
+        rate_limit: Option<u64>,
         seccomp_action: SeccompAction,
     ) -> io::Result<Rng> {
         let random_file = File::open(path)?;
+        // The entropy source can either be a character device (/dev/urandom,
+        // /dev/hwrng, ...) or a regular file, anything else is rejected.
+        let file_type = random_file.metadata()?.file_type();
+        if !file_type.is_char_device() && !file_type.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid rng source {}", path),
+            ));
+        }
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
 
         if iommu {
@@ -163,9 +306,16 @@ impl Rng {
             id,
             random_file: Some(random_file),
             seccomp_action,
+            rate_limit,
+            recorder: None,
         })
     }
 
+    /// Record the random bytes handed to the guest.
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
     fn state(&self) -> RngState {
         RngState {
             avail_features: self.common.avail_features,
@@ -240,6 +390,13 @@ impl VirtioDevice for Rng {
                 error!("failed cloning rng source: {}", e);
                 ActivateError::BadActivate
             })?;
+            let rate_limiter = match self.rate_limit {
+                Some(bytes_per_sec) => Some(RateLimiter::new(bytes_per_sec).map_err(|e| {
+                    error!("failed creating rng rate limiter: {}", e);
+                    ActivateError::BadActivate
+                })?),
+                None => None,
+            };
             let mut handler = RngEpollHandler {
                 queues,
                 mem,
@@ -248,6 +405,9 @@ impl VirtioDevice for Rng {
                 queue_evt: queue_evts.remove(0),
                 kill_evt,
                 pause_evt,
+                rate_limiter,
+                recorder: self.recorder.as_ref().map(|r| (self.id.clone(), r.clone())),
+                source_exhausted: false,
             };
 
             let paused = self.common.paused.clone();
@@ -0,0 +1,49 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+pub(crate) fn timerfd_create() -> Result<RawFd, io::Error> {
+    let res = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as RawFd)
+    }
+}
+
+// Arm the timer to expire once, after `timeout`.
+pub(crate) fn timerfd_setup(timer: &File, timeout: Duration) -> Result<(), io::Error> {
+    timerfd_settime(timer, timeout, Duration::from_secs(0))
+}
+
+// Arm the timer to expire every `interval`, a zero interval disarming it.
+pub(crate) fn timerfd_setup_periodic(timer: &File, interval: Duration) -> Result<(), io::Error> {
+    timerfd_settime(timer, interval, interval)
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
+
+fn timerfd_settime(timer: &File, value: Duration, interval: Duration) -> Result<(), io::Error> {
+    let spec = libc::itimerspec {
+        it_interval: timespec(interval),
+        it_value: timespec(value),
+    };
+
+    let res = unsafe { libc::timerfd_settime(timer.as_raw_fd(), 0, &spec, std::ptr::null_mut()) };
+
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
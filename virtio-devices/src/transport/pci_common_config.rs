@@ -7,7 +7,7 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 extern crate byteorder;
 
-use crate::{Queue, VirtioDevice};
+use crate::{Queue, VirtioDevice, DEVICE_NEEDS_RESET, VIRTIO_MSI_NO_VECTOR};
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use std::sync::atomic::{AtomicU16, Ordering};
@@ -54,6 +54,9 @@ pub struct VirtioPciCommonConfig {
     pub driver_feature_select: u32,
     pub queue_select: u16,
     pub msix_config: Arc<AtomicU16>,
+    // Number of MSI-X vectors of the device, the ones selected by the driver
+    // being checked against it.
+    pub msix_num: u16,
 }
 
 impl VirtioPciCommonConfig {
@@ -88,7 +91,7 @@ impl VirtioPciCommonConfig {
 
         match data.len() {
             1 => {
-                let v = self.read_common_config_byte(offset);
+                let v = self.read_common_config_byte(offset, device);
                 data[0] = v;
             }
             2 => {
@@ -127,10 +130,13 @@ impl VirtioPciCommonConfig {
         }
     }
 
-    fn read_common_config_byte(&self, offset: u64) -> u8 {
+    fn read_common_config_byte(&self, offset: u64, device: Arc<Mutex<dyn VirtioDevice>>) -> u8 {
         debug!("read_common_config_byte: offset 0x{:x}", offset);
         // The driver is only allowed to do aligned, properly sized access.
         match offset {
+            0x14 if device.lock().unwrap().needs_reset() => {
+                self.driver_status | DEVICE_NEEDS_RESET as u8
+            }
             0x14 => self.driver_status,
             0x15 => self.config_generation,
             _ => {
@@ -176,10 +182,15 @@ impl VirtioPciCommonConfig {
     fn write_common_config_word(&mut self, offset: u64, value: u16, queues: &mut Vec<Queue>) {
         debug!("write_common_config_word: offset 0x{:x}", offset);
         match offset {
-            0x10 => self.msix_config.store(value, Ordering::Release),
+            0x10 => self
+                .msix_config
+                .store(self.checked_vector(value), Ordering::Release),
             0x16 => self.queue_select = value,
             0x18 => self.with_queue_mut(queues, |q| q.size = value),
-            0x1a => self.with_queue_mut(queues, |q| q.vector = value),
+            0x1a => {
+                let vector = self.checked_vector(value);
+                self.with_queue_mut(queues, |q| q.vector = vector)
+            }
             0x1c => self.with_queue_mut(queues, |q| q.enable(value == 1)),
             _ => {
                 warn!("invalid virtio register word write: 0x{:x}", offset);
@@ -187,6 +198,16 @@ impl VirtioPciCommonConfig {
         }
     }
 
+    // A vector the device doesn't have reads back as VIRTIO_MSI_NO_VECTOR,
+    // for the driver to fall back to sharing the vectors between the queues.
+    fn checked_vector(&self, vector: u16) -> u16 {
+        if vector < self.msix_num {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+
     fn read_common_config_dword(&self, offset: u64, device: Arc<Mutex<dyn VirtioDevice>>) -> u32 {
         debug!("read_common_config_dword: offset 0x{:x}", offset);
         match offset {
@@ -380,6 +401,7 @@ mod tests {
             driver_feature_select: 0x0,
             queue_select: 0xff,
             msix_config: Arc::new(AtomicU16::new(0)),
+            msix_num: 2,
         };
 
         let dev = Arc::new(Mutex::new(DummyDevice(0)));
@@ -416,8 +438,17 @@ mod tests {
         // 'queue_select' can be read and written.
         regs.write(0x16, &[0xaa, 0x55], &mut queues, dev.clone());
         let mut read_back = vec![0x00, 0x00];
-        regs.read(0x16, &mut read_back, &mut queues, dev);
+        regs.read(0x16, &mut read_back, &mut queues, dev.clone());
         assert_eq!(read_back[0], 0xaa);
         assert_eq!(read_back[1], 0x55);
+
+        // Only the vectors of the device can be selected.
+        regs.write(0x10, &[1, 0], &mut queues, dev.clone());
+        let mut read_back = vec![0x00, 0x00];
+        regs.read(0x10, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u16(&read_back), 1);
+        regs.write(0x10, &[2, 0], &mut queues, dev.clone());
+        regs.read(0x10, &mut read_back, &mut queues, dev);
+        assert_eq!(LittleEndian::read_u16(&read_back), VIRTIO_MSI_NO_VECTOR);
     }
 }
@@ -23,7 +23,7 @@ use libc::EFD_NONBLOCK;
 use pci::{
     BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
     PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciSubclass,
+    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciRom, PciSubclass,
 };
 use std::any::Any;
 use std::cmp;
@@ -45,6 +45,7 @@ use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
+use vm_virtio::trace::QueueTracer;
 use vm_virtio::{queue, VirtioIommuRemapping};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
@@ -303,6 +304,9 @@ pub struct VirtioPciDevice {
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
 
+    // Whether the descriptor chains going through the queues are logged
+    queue_trace: Arc<AtomicBool>,
+
     // Guest memory
     memory: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
 
@@ -313,6 +317,10 @@ pub struct VirtioPciDevice {
     // Whether to use 64-bit bar location or 32-bit
     use_64bit_bar: bool,
 
+    // Option ROM exposed through the expansion ROM BAR
+    rom: Option<PciRom>,
+    rom_bar_addr: Option<GuestAddress>,
+
     // Add a dedicated structure to hold information about the very specific
     // virtio-pci capability VIRTIO_PCI_CAP_PCI_CFG. This is needed to support
     // the legacy/backward compatible mechanism of letting the guest access the
@@ -350,12 +358,18 @@ impl VirtioPciDevice {
         for _ in locked_device.queue_max_sizes().iter() {
             queue_evts.push(EventFd::new(EFD_NONBLOCK)?)
         }
+        let queue_trace = Arc::new(AtomicBool::new(false));
         let queues = locked_device
             .queue_max_sizes()
             .iter()
-            .map(|&s| {
+            .enumerate()
+            .map(|(i, &s)| {
                 let mut queue = Queue::new(s);
                 queue.iommu_mapping_cb = iommu_mapping_cb.clone();
+                queue.tracer = Some(Arc::new(QueueTracer::new(
+                    format!("{} queue {}", id, i),
+                    queue_trace.clone(),
+                )));
                 queue
             })
             .collect();
@@ -424,6 +438,7 @@ impl VirtioPciDevice {
                 driver_feature_select: 0,
                 queue_select: 0,
                 msix_config: Arc::new(AtomicU16::new(0)),
+                msix_num,
             },
             msix_config,
             msix_num,
@@ -433,10 +448,13 @@ impl VirtioPciDevice {
             virtio_interrupt: None,
             queues,
             queue_evts,
+            queue_trace,
             memory: Some(memory),
             settings_bar: 0,
             settings_bar_addr: None,
             use_64bit_bar,
+            rom: None,
+            rom_bar_addr: None,
             interrupt_source_group,
             cap_pci_cfg_info: VirtioPciCfgCapInfo::default(),
             bar_regions: vec![],
@@ -533,6 +551,32 @@ impl VirtioPciDevice {
         self.configuration.get_bar_addr(self.settings_bar as usize)
     }
 
+    /// Expose an option ROM to the guest through the expansion ROM BAR. This
+    /// must be called before the BARs are allocated.
+    pub fn set_rom(&mut self, rom: PciRom) {
+        self.rom = Some(rom);
+    }
+
+    /// Override the subsystem vendor and device IDs, which otherwise match
+    /// the virtio vendor and device IDs.
+    pub fn set_subsystem_id(
+        &mut self,
+        subsystem_vendor_id: Option<u16>,
+        subsystem_id: Option<u16>,
+    ) {
+        let device_type = self.device.lock().unwrap().device_type();
+        self.configuration.set_subsystem_id(
+            subsystem_vendor_id.unwrap_or(VIRTIO_PCI_VENDOR_ID),
+            subsystem_id.unwrap_or(VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16),
+        );
+    }
+
+    // This function is used by the caller to provide the expected base address
+    // for the expansion ROM BAR.
+    pub fn set_rom_bar_addr(&mut self, bar_addr: u64) {
+        self.rom_bar_addr = Some(GuestAddress(bar_addr));
+    }
+
     fn add_pci_capabilities(
         &mut self,
         settings_bar: u8,
@@ -653,6 +697,12 @@ impl VirtioPciDevice {
         self.device.clone()
     }
 
+    /// Start or stop logging the descriptor chains going through the queues
+    /// processed by the VMM, which excludes the vhost-user ones.
+    pub fn set_queue_trace(&self, enable: bool) {
+        self.queue_trace.store(enable, Ordering::Release);
+    }
+
     pub fn maybe_activate(&mut self) {
         if self.needs_activation() {
             if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
@@ -894,6 +944,22 @@ impl PciDevice for VirtioPciDevice {
             }
         }
 
+        // Allocate the expansion ROM BAR if an option ROM is provided.
+        if let Some(rom) = &self.rom {
+            let size = rom.bar_size();
+            let addr = allocator
+                .allocate_mmio_hole_addresses(self.rom_bar_addr, size, Some(size))
+                .ok_or(PciDeviceError::IoAllocationFailed(size))?;
+            self.configuration
+                .add_pci_rom_bar(&rom.bar_configuration(addr), 0)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.raw_value(), e))?;
+
+            let region_type = PciBarRegionType::Memory32BitRegion;
+            ranges.push((addr, size, region_type));
+            self.bar_regions.push((addr, size, region_type));
+            self.rom_bar_addr = Some(addr);
+        }
+
         Ok(ranges)
     }
 
@@ -923,11 +989,21 @@ impl PciDevice for VirtioPciDevice {
                 *addr = GuestAddress(new_base);
             }
         }
+        if self.rom_bar_addr == Some(GuestAddress(old_base)) {
+            self.rom_bar_addr = Some(GuestAddress(new_base));
+        }
 
         Ok(())
     }
 
-    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        if let Some(rom) = &self.rom {
+            if self.rom_bar_addr == Some(GuestAddress(base)) {
+                rom.read(offset, data);
+                return;
+            }
+        }
+
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.read(
                 o - COMMON_CONFIG_BAR_OFFSET,
@@ -972,7 +1048,12 @@ impl PciDevice for VirtioPciDevice {
         }
     }
 
-    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        // The option ROM is read-only.
+        if self.rom.is_some() && self.rom_bar_addr == Some(GuestAddress(base)) {
+            return None;
+        }
+
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.write(
                 o - COMMON_CONFIG_BAR_OFFSET,
@@ -6,15 +6,18 @@ use super::super::{
 };
 use super::handler::*;
 use super::vu_common_ctrl::*;
-use super::{Error, Result};
+use super::{Error, Result, VhostUserState};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use block_util::VirtioBlockConfig;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::mem;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::result;
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::VhostUserConfigFlags;
@@ -25,7 +28,10 @@ use vhost_rs::VhostBackend;
 use virtio_bindings::bindings::virtio_blk::*;
 use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 struct SlaveReqHandler {}
@@ -34,16 +40,25 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {}
 pub struct Blk {
     common: VirtioCommon,
     id: String,
-    vhost_user_blk: Master,
+    vhost_user_blk: Arc<Mutex<Master>>,
     config: VirtioBlockConfig,
     seccomp_action: SeccompAction,
+    socket_path: String,
+    acked_protocol_features: u64,
+    vrings: Option<VhostUserVrings>,
+    vring_bases: Option<Vec<u16>>,
+    inflight: Option<Inflight>,
 }
 
 impl Blk {
     /// Create a new vhost-user-blk device
-    pub fn new(id: String, vu_cfg: VhostUserConfig, seccomp_action: SeccompAction) -> Result<Blk> {
-        let mut vhost_user_blk = Master::connect(&vu_cfg.socket, vu_cfg.num_queues as u64)
-            .map_err(Error::VhostUserCreateMaster)?;
+    pub fn new(
+        id: String,
+        vu_cfg: VhostUserConfig,
+        stream: Option<UnixStream>,
+        seccomp_action: SeccompAction,
+    ) -> Result<Blk> {
+        let mut vhost_user_blk = connect_vhost_user(&vu_cfg.socket, stream, vu_cfg.num_queues)?;
 
         // Filling device and vring features VMM supports.
         let mut avail_features = 1 << VIRTIO_BLK_F_SEG_MAX
@@ -79,6 +94,7 @@ impl Blk {
 
         // Identify if protocol features are supported by the slave.
         let mut acked_features = 0;
+        let mut acked_protocol_features = 0;
         if avail_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
             acked_features |= VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
@@ -86,10 +102,10 @@ impl Blk {
                 .get_protocol_features()
                 .map_err(Error::VhostUserGetProtocolFeatures)?;
             protocol_features |= VhostUserProtocolFeatures::MQ;
-            protocol_features &= !VhostUserProtocolFeatures::INFLIGHT_SHMFD;
             vhost_user_blk
                 .set_protocol_features(protocol_features)
                 .map_err(Error::VhostUserSetProtocolFeatures)?;
+            acked_protocol_features = protocol_features.bits();
         }
         // Get the max queues number from backend, and the queue number set
         // should be less than this max queue number.
@@ -128,6 +144,15 @@ impl Blk {
                 .map_err(Error::VhostUserSetVringBase)?;
         }
 
+        // Let the backend track the requests in flight, which can then be
+        // resubmitted by a new backend instance after a reconnection.
+        let inflight =
+            if acked_protocol_features & VhostUserProtocolFeatures::INFLIGHT_SHMFD.bits() != 0 {
+                Some(Inflight::default())
+            } else {
+                None
+            };
+
         Ok(Blk {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_BLOCK as u32,
@@ -138,11 +163,60 @@ impl Blk {
                 ..Default::default()
             },
             id,
-            vhost_user_blk,
+            vhost_user_blk: Arc::new(Mutex::new(vhost_user_blk)),
             config,
             seccomp_action,
+            socket_path: vu_cfg.socket,
+            acked_protocol_features,
+            vrings: None,
+            vring_bases: None,
+            inflight,
         })
     }
+
+    fn state(&self) -> VhostUserState {
+        VhostUserState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            config: self.config.as_slice().to_vec(),
+            vring_bases: self.vring_bases.clone(),
+        }
+    }
+
+    fn set_state(&mut self, state: &VhostUserState) -> result::Result<(), MigratableError> {
+        if state.acked_features & !self.common.avail_features != 0 {
+            return Err(MigratableError::Restore(anyhow!(
+                "vhost-user-blk backend does not support the features acked by the guest"
+            )));
+        }
+        self.common.acked_features = state.acked_features;
+        self.config = *VirtioBlockConfig::from_slice(&state.config).ok_or_else(|| {
+            MigratableError::Restore(anyhow!("Invalid vhost-user-blk configuration"))
+        })?;
+        self.vring_bases = state.vring_bases.clone();
+
+        // The cache mode might have been changed by the guest.
+        if self.common.feature_acked(VIRTIO_BLK_F_CONFIG_WCE.into()) {
+            let writeback_offset =
+                (&self.config.writeback as *const _ as u64) - (&self.config as *const _ as u64);
+            self.vhost_user_blk
+                .lock()
+                .unwrap()
+                .set_config(
+                    writeback_offset as u32,
+                    VhostUserConfigFlags::WRITABLE,
+                    &[self.config.writeback],
+                )
+                .map_err(|e| {
+                    MigratableError::Restore(anyhow!(
+                        "Could not set vhost-user-blk cache mode {:?}",
+                        e
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for Blk {
@@ -192,6 +266,8 @@ impl VirtioDevice for Blk {
 
         self.config.writeback = data[0];
         self.vhost_user_blk
+            .lock()
+            .unwrap()
             .set_config(offset as u32, VhostUserConfigFlags::WRITABLE, data)
             .expect("Failed to set config");
     }
@@ -205,16 +281,61 @@ impl VirtioDevice for Blk {
     ) -> ActivateResult {
         self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
 
+        // Keep a copy of the vrings description, needed to bring a new
+        // backend instance up to speed in case of reconnection.
+        let reconnect_queues = queues.clone();
+        let reconnect_queue_evts = queue_evts
+            .iter()
+            .map(|e| e.try_clone())
+            .collect::<std::io::Result<Vec<EventFd>>>()
+            .map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        // The vrings resume from the state restored from a snapshot, if any.
+        let vring_bases = self.vring_bases.take();
         let mut vu_interrupt_list = setup_vhost_user(
-            &mut self.vhost_user_blk,
+            &mut self.vhost_user_blk.lock().unwrap(),
             &mem.memory(),
             queues,
             queue_evts,
             &interrupt_cb,
             self.common.acked_features,
+            vring_bases.as_deref(),
+            self.inflight.as_mut(),
         )
         .map_err(ActivateError::VhostUserBlkSetup)?;
 
+        // Only the first worker thread monitors the connection with the
+        // backend, as it is in charge of reconnecting all the vrings.
+        let reconnect = VhostUserReconnect {
+            vu: self.vhost_user_blk.clone(),
+            socket_path: self.socket_path.clone(),
+            mem,
+            queues: reconnect_queues,
+            queue_evts: reconnect_queue_evts,
+            call_evts: vring_call_evts(&vu_interrupt_list, &interrupt_cb)
+                .map_err(ActivateError::VhostUserBlkSetup)?,
+            acked_features: self.common.acked_features,
+            acked_protocol_features: self.acked_protocol_features,
+            inflight: self
+                .inflight
+                .as_ref()
+                .map(|inflight| inflight.try_clone())
+                .transpose()
+                .map_err(|e| {
+                    error!("failed to clone inflight fd: {}", e);
+                    ActivateError::BadActivate
+                })?,
+            needs_reset: self.common.needs_reset.clone(),
+        };
+        self.vrings = Some(reconnect.vrings().map_err(|e| {
+            error!("failed to clone vrings EventFd: {}", e);
+            ActivateError::BadActivate
+        })?);
+        let mut reconnect = Some(reconnect);
+
         let mut epoll_threads = Vec::new();
         for _ in 0..vu_interrupt_list.len() {
             let mut interrupt_list_sub: Vec<(Option<EventFd>, Queue)> = Vec::with_capacity(1);
@@ -247,7 +368,12 @@ impl VirtioDevice for Blk {
                 pause_evt,
                 vu_interrupt_list: interrupt_list_sub,
                 slave_req_handler: None,
-            });
+                reconnect: reconnect.take(),
+            })
+            .map_err(|e| {
+                error!("failed to create vhost-user epoll handler: {:?}", e);
+                ActivateError::BadActivate
+            })?;
 
             let paused = self.common.paused.clone();
             let paused_sync = self.common.paused_sync.clone();
@@ -280,11 +406,22 @@ impl VirtioDevice for Blk {
             self.common.resume().ok()?;
         }
 
-        if let Err(e) = reset_vhost_user(&mut self.vhost_user_blk, self.common.queue_sizes.len()) {
+        if let Err(e) = reset_vhost_user(
+            &mut self.vhost_user_blk.lock().unwrap(),
+            self.common.queue_sizes.len(),
+        ) {
             error!("Failed to reset vhost-user daemon: {:?}", e);
             return None;
         }
 
+        self.vrings = None;
+        self.vring_bases = None;
+        // The requests in flight don't survive a reset, hence a new inflight
+        // region is retrieved on the next activation.
+        if let Some(inflight) = self.inflight.as_mut() {
+            *inflight = Inflight::default();
+        }
+
         if let Some(kill_evt) = self.common.kill_evt.take() {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
@@ -297,21 +434,51 @@ impl VirtioDevice for Blk {
         ))
     }
 
+    fn needs_reset(&self) -> bool {
+        self.common.needs_reset.load(Ordering::SeqCst)
+    }
+
     fn shutdown(&mut self) {
-        let _ = unsafe { libc::close(self.vhost_user_blk.as_raw_fd()) };
+        let _ = unsafe { libc::close(self.vhost_user_blk.lock().unwrap().as_raw_fd()) };
     }
 
     fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
-        update_mem_table(&mut self.vhost_user_blk, mem).map_err(crate::Error::VhostUserUpdateMemory)
+        update_mem_table(&mut self.vhost_user_blk.lock().unwrap(), mem)
+            .map_err(crate::Error::VhostUserUpdateMemory)
     }
 }
 
 impl Pausable for Blk {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
-        self.common.pause()
+        self.common.pause()?;
+
+        if let Some(vrings) = self.vrings.as_ref() {
+            self.vring_bases = Some(
+                stop_vhost_user_vrings(&mut self.vhost_user_blk.lock().unwrap(), vrings.len())
+                    .map_err(|e| {
+                        MigratableError::Pause(anyhow!(
+                            "Could not stop vhost-user-blk vrings {:?}",
+                            e
+                        ))
+                    })?,
+            );
+        }
+
+        Ok(())
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        if let (Some(vrings), Some(vring_bases)) = (self.vrings.as_ref(), self.vring_bases.take()) {
+            start_vhost_user_vrings(
+                &mut self.vhost_user_blk.lock().unwrap(),
+                vrings,
+                &vring_bases,
+            )
+            .map_err(|e| {
+                MigratableError::Resume(anyhow!("Could not start vhost-user-blk vrings {:?}", e))
+            })?;
+        }
+
         self.common.resume()
     }
 }
@@ -320,6 +487,39 @@ impl Snapshottable for Blk {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let snapshot =
+            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut blk_snapshot = Snapshot::new(self.id.as_str());
+        blk_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id),
+            snapshot,
+        });
+
+        Ok(blk_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if let Some(blk_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let blk_state = match serde_json::from_slice(&blk_section.snapshot) {
+                Ok(state) => state,
+                Err(error) => {
+                    return Err(MigratableError::Restore(anyhow!(
+                        "Could not deserialize VHOST_USER_BLK {}",
+                        error
+                    )))
+                }
+            };
+
+            return self.set_state(&blk_state);
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find VHOST_USER_BLK snapshot section"
+        )))
+    }
 }
 impl Transportable for Blk {}
 impl Migratable for Blk {}
@@ -1,19 +1,25 @@
 // Copyright 2019 Intel Corporation. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::vu_common_ctrl::{reset_vhost_user, setup_vhost_user, update_mem_table};
-use super::{Error, Result};
+use super::vu_common_ctrl::{
+    connect_vhost_user, reset_vhost_user, setup_vhost_user, start_vhost_user_vrings,
+    stop_vhost_user_vrings, update_mem_table, vring_call_evts, VhostUserVrings,
+};
+use super::{Error, Result, VhostUserState};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::vhost_user::handler::{VhostUserEpollConfig, VhostUserEpollHandler};
+use crate::vhost_user::handler::{VhostUserEpollConfig, VhostUserEpollHandler, VhostUserReconnect};
 use crate::{
     ActivateError, ActivateResult, Queue, UserspaceMapping, VirtioCommon, VirtioDevice,
     VirtioDeviceType, VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_VERSION_1,
 };
+use anyhow::anyhow;
 use libc::{self, c_void, off64_t, pread64, pwrite64};
 use seccomp::{SeccompAction, SeccompFilter};
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::result;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use vhost_rs::vhost_user::message::{
@@ -28,7 +34,10 @@ use vm_memory::{
     Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryMmap, MmapRegion,
 };
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 const NUM_QUEUE_OFFSET: usize = 1;
@@ -267,17 +276,22 @@ unsafe impl ByteValued for VirtioFsConfig {}
 pub struct Fs {
     common: VirtioCommon,
     id: String,
-    vu: Master,
+    vu: Arc<Mutex<Master>>,
     config: VirtioFsConfig,
     // Hold ownership of the memory that is allocated for the device
     // which will be automatically dropped when the device is dropped
     cache: Option<(VirtioSharedMemoryList, MmapRegion)>,
     slave_req_support: bool,
     seccomp_action: SeccompAction,
+    socket_path: String,
+    acked_protocol_features: u64,
+    vrings: Option<VhostUserVrings>,
+    vring_bases: Option<Vec<u16>>,
 }
 
 impl Fs {
     /// Create a new virtio-fs device.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         path: &str,
@@ -285,6 +299,7 @@ impl Fs {
         req_num_queues: usize,
         queue_size: u16,
         cache: Option<(VirtioSharedMemoryList, MmapRegion)>,
+        stream: Option<UnixStream>,
         seccomp_action: SeccompAction,
     ) -> Result<Fs> {
         let mut slave_req_support = false;
@@ -293,8 +308,7 @@ impl Fs {
         let num_queues = NUM_QUEUE_OFFSET + req_num_queues;
 
         // Connect to the vhost-user socket.
-        let mut master =
-            Master::connect(path, num_queues as u64).map_err(Error::VhostUserCreateMaster)?;
+        let mut master = connect_vhost_user(path, stream, num_queues)?;
 
         // Filling device and vring features VMM supports.
         let mut avail_features =
@@ -315,6 +329,7 @@ impl Fs {
 
         // Identify if protocol features are supported by the slave.
         let mut acked_features = 0;
+        let mut acked_protocol_features = 0;
         if avail_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
             acked_features |= VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
@@ -335,6 +350,7 @@ impl Fs {
             master
                 .set_protocol_features(protocol_features)
                 .map_err(Error::VhostUserSetProtocolFeatures)?;
+            acked_protocol_features = protocol_features.bits();
 
             slave_req_support = true;
         }
@@ -355,13 +371,41 @@ impl Fs {
                 ..Default::default()
             },
             id,
-            vu: master,
+            vu: Arc::new(Mutex::new(master)),
             config,
             cache,
             slave_req_support,
             seccomp_action,
+            socket_path: path.to_string(),
+            acked_protocol_features,
+            vrings: None,
+            vring_bases: None,
         })
     }
+
+    fn state(&self) -> VhostUserState {
+        VhostUserState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            config: self.config.as_slice().to_vec(),
+            vring_bases: self.vring_bases.clone(),
+        }
+    }
+
+    fn set_state(&mut self, state: &VhostUserState) -> result::Result<(), MigratableError> {
+        if state.acked_features & !self.common.avail_features != 0 {
+            return Err(MigratableError::Restore(anyhow!(
+                "vhost-user-fs backend does not support the features acked by the guest"
+            )));
+        }
+        self.common.acked_features = state.acked_features;
+        self.config = *VirtioFsConfig::from_slice(&state.config).ok_or_else(|| {
+            MigratableError::Restore(anyhow!("Invalid vhost-user-fs configuration"))
+        })?;
+        self.vring_bases = state.vring_bases.clone();
+
+        Ok(())
+    }
 }
 
 impl Drop for Fs {
@@ -424,16 +468,50 @@ impl VirtioDevice for Fs {
                 ActivateError::BadActivate
             })?;
 
+        // Keep a copy of the vrings description, needed to bring a new
+        // backend instance up to speed in case of reconnection.
+        let reconnect_queues = queues.clone();
+        let reconnect_queue_evts = queue_evts
+            .iter()
+            .map(|e| e.try_clone())
+            .collect::<std::io::Result<Vec<EventFd>>>()
+            .map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        // The vrings resume from the state restored from a snapshot, if any.
+        let vring_bases = self.vring_bases.take();
         let vu_call_evt_queue_list = setup_vhost_user(
-            &mut self.vu,
+            &mut self.vu.lock().unwrap(),
             &mem.memory(),
             queues,
             queue_evts,
             &interrupt_cb,
             self.common.acked_features,
+            vring_bases.as_deref(),
+            None,
         )
         .map_err(ActivateError::VhostUserSetup)?;
 
+        let reconnect = VhostUserReconnect {
+            vu: self.vu.clone(),
+            socket_path: self.socket_path.clone(),
+            mem: mem.clone(),
+            queues: reconnect_queues,
+            queue_evts: reconnect_queue_evts,
+            call_evts: vring_call_evts(&vu_call_evt_queue_list, &interrupt_cb)
+                .map_err(ActivateError::VhostUserSetup)?,
+            acked_features: self.common.acked_features,
+            acked_protocol_features: self.acked_protocol_features,
+            inflight: None,
+            needs_reset: self.common.needs_reset.clone(),
+        };
+        self.vrings = Some(reconnect.vrings().map_err(|e| {
+            error!("failed to clone vrings EventFd: {}", e);
+            ActivateError::BadActivate
+        })?);
+
         // Initialize slave communication.
         let slave_req_handler = if self.slave_req_support {
             if let Some(cache) = self.cache.as_ref() {
@@ -448,6 +526,8 @@ impl VirtioDevice for Fs {
                     ActivateError::VhostUserSetup(Error::MasterReqHandlerCreation(e))
                 })?;
                 self.vu
+                    .lock()
+                    .unwrap()
                     .set_slave_request_fd(req_handler.get_tx_raw_fd())
                     .map_err(|e| {
                         ActivateError::VhostUserSetup(Error::VhostUserSetSlaveRequestFd(e))
@@ -466,7 +546,12 @@ impl VirtioDevice for Fs {
             kill_evt,
             pause_evt,
             slave_req_handler,
-        });
+            reconnect: Some(reconnect),
+        })
+        .map_err(|e| {
+            error!("failed to create vhost-user epoll handler: {:?}", e);
+            ActivateError::BadActivate
+        })?;
 
         let paused = self.common.paused.clone();
         let paused_sync = self.common.paused_sync.clone();
@@ -500,11 +585,16 @@ impl VirtioDevice for Fs {
             self.common.resume().ok()?;
         }
 
-        if let Err(e) = reset_vhost_user(&mut self.vu, self.common.queue_sizes.len()) {
+        if let Err(e) =
+            reset_vhost_user(&mut self.vu.lock().unwrap(), self.common.queue_sizes.len())
+        {
             error!("Failed to reset vhost-user daemon: {:?}", e);
             return None;
         }
 
+        self.vrings = None;
+        self.vring_bases = None;
+
         if let Some(kill_evt) = self.common.kill_evt.take() {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
@@ -517,8 +607,12 @@ impl VirtioDevice for Fs {
         ))
     }
 
+    fn needs_reset(&self) -> bool {
+        self.common.needs_reset.load(Ordering::SeqCst)
+    }
+
     fn shutdown(&mut self) {
-        let _ = unsafe { libc::close(self.vu.as_raw_fd()) };
+        let _ = unsafe { libc::close(self.vu.lock().unwrap().as_raw_fd()) };
     }
 
     fn get_shm_regions(&self) -> Option<VirtioSharedMemoryList> {
@@ -542,7 +636,8 @@ impl VirtioDevice for Fs {
     }
 
     fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
-        update_mem_table(&mut self.vu, mem).map_err(crate::Error::VhostUserUpdateMemory)
+        update_mem_table(&mut self.vu.lock().unwrap(), mem)
+            .map_err(crate::Error::VhostUserUpdateMemory)
     }
 
     fn userspace_mappings(&self) -> Vec<UserspaceMapping> {
@@ -563,10 +658,33 @@ impl VirtioDevice for Fs {
 
 impl Pausable for Fs {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
-        self.common.pause()
+        self.common.pause()?;
+
+        if let Some(vrings) = self.vrings.as_ref() {
+            self.vring_bases = Some(
+                stop_vhost_user_vrings(&mut self.vu.lock().unwrap(), vrings.len()).map_err(
+                    |e| {
+                        MigratableError::Pause(anyhow!(
+                            "Could not stop vhost-user-fs vrings {:?}",
+                            e
+                        ))
+                    },
+                )?,
+            );
+        }
+
+        Ok(())
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        if let (Some(vrings), Some(vring_bases)) = (self.vrings.as_ref(), self.vring_bases.take()) {
+            start_vhost_user_vrings(&mut self.vu.lock().unwrap(), vrings, &vring_bases).map_err(
+                |e| {
+                    MigratableError::Resume(anyhow!("Could not start vhost-user-fs vrings {:?}", e))
+                },
+            )?;
+        }
+
         self.common.resume()
     }
 }
@@ -575,6 +693,47 @@ impl Snapshottable for Fs {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        // The files mapped into the DAX window by the backend can't be
+        // mapped again on restore.
+        if self.cache.is_some() {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Snapshot of vhost-user-fs with a DAX window is not supported"
+            )));
+        }
+
+        let snapshot =
+            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut fs_snapshot = Snapshot::new(self.id.as_str());
+        fs_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id),
+            snapshot,
+        });
+
+        Ok(fs_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if let Some(fs_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let fs_state = match serde_json::from_slice(&fs_section.snapshot) {
+                Ok(state) => state,
+                Err(error) => {
+                    return Err(MigratableError::Restore(anyhow!(
+                        "Could not deserialize VHOST_USER_FS {}",
+                        error
+                    )))
+                }
+            };
+
+            return self.set_state(&fs_state);
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find VHOST_USER_FS snapshot section"
+        )))
+    }
 }
 impl Transportable for Fs {}
 impl Migratable for Fs {}
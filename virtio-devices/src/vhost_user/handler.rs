@@ -11,14 +11,59 @@ use super::super::{
     EpollHelper, EpollHelperError, EpollHelperHandler, Queue, VirtioInterruptType,
     EPOLL_HELPER_EVENT_LAST,
 };
+use super::vu_common_ctrl::{reinitialize_vhost_user, Inflight, VhostUserVrings};
 use super::{Error, Result};
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::timer::{timerfd_create, timerfd_setup};
 use crate::VirtioInterrupt;
-use std::os::unix::io::AsRawFd;
-use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier};
-use vhost_rs::vhost_user::{MasterReqHandler, VhostUserMasterReqHandler};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant};
+use vhost_rs::vhost_user::{Master, MasterReqHandler, VhostUserMaster, VhostUserMasterReqHandler};
+use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+
+// Delay between two attempts at reaching a vhost-user backend.
+const RECONNECT_RETRY_DELAY_MS: u64 = 100;
+// Maximum amount of time spent trying to reach a vhost-user backend which
+// closed the connection.
+const RECONNECT_TIMEOUT_SECS: u64 = 60;
+
+/// Everything needed to re-establish a vhost-user session after the backend
+/// went away, covering all the vrings of the device.
+///
+/// # Arguments
+/// * `vu` - vhost-user master shared with the device.
+/// * `socket_path` - path to the socket the backend listens to.
+/// * `mem` - guest memory to provide to the new backend instance.
+/// * `queues` - all the virtqueues handled by the backend.
+/// * `queue_evts` - EventFds the guest kicks to notify the backend.
+/// * `call_evts` - EventFds the backend writes to for notifying the guest.
+/// * `inflight` - region tracking the requests in flight, if negotiated.
+/// * `needs_reset` - flag raised when the backend could not be reached again.
+pub struct VhostUserReconnect {
+    pub vu: Arc<Mutex<Master>>,
+    pub socket_path: String,
+    pub mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    pub queues: Vec<Queue>,
+    pub queue_evts: Vec<EventFd>,
+    pub call_evts: Vec<EventFd>,
+    pub acked_features: u64,
+    pub acked_protocol_features: u64,
+    pub inflight: Option<Inflight>,
+    pub needs_reset: Arc<AtomicBool>,
+}
+
+impl VhostUserReconnect {
+    /// Copy of the vrings description, kept by the device to start the
+    /// vrings again after they have been stopped on pause.
+    pub fn vrings(&self) -> std::io::Result<VhostUserVrings> {
+        VhostUserVrings::new(&self.mem, &self.queues, &self.queue_evts, &self.call_evts)
+    }
+}
 
 /// Collection of common parameters required by vhost-user devices while
 /// call Epoll handler.
@@ -33,12 +78,17 @@ pub struct VhostUserEpollConfig<S: VhostUserMasterReqHandler> {
     pub pause_evt: EventFd,
     pub vu_interrupt_list: Vec<(Option<EventFd>, Queue)>,
     pub slave_req_handler: Option<MasterReqHandler<S>>,
+    pub reconnect: Option<VhostUserReconnect>,
 }
 
 pub struct VhostUserEpollHandler<S: VhostUserMasterReqHandler> {
     vu_epoll_cfg: VhostUserEpollConfig<S>,
     queue_evt_start_idx: u16,
     slave_evt_idx: u16,
+    hup_evt_idx: u16,
+    reconnect_evt_idx: u16,
+    reconnect_timer: Option<File>,
+    disconnected_at: Option<Instant>,
 }
 
 impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
@@ -49,14 +99,152 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
     ///
     /// # Return
     /// * `VhostUserEpollHandler` - epoll handler for vhost-user based devices
-    pub fn new(vu_epoll_cfg: VhostUserEpollConfig<S>) -> VhostUserEpollHandler<S> {
+    pub fn new(vu_epoll_cfg: VhostUserEpollConfig<S>) -> Result<VhostUserEpollHandler<S>> {
         let queue_evt_start_idx = EPOLL_HELPER_EVENT_LAST + 1;
         let slave_evt_idx = queue_evt_start_idx + vu_epoll_cfg.vu_interrupt_list.len() as u16;
+        let hup_evt_idx = slave_evt_idx + 1;
+        let reconnect_evt_idx = hup_evt_idx + 1;
+
+        // The timer is created before the worker thread gets confined by its
+        // seccomp filter, which only allows arming it.
+        let reconnect_timer = if vu_epoll_cfg.reconnect.is_some() {
+            let timer_fd = timerfd_create().map_err(Error::ReconnectTimer)?;
+            // Safe because we just created the timerfd and nothing else owns it.
+            Some(unsafe { File::from_raw_fd(timer_fd) })
+        } else {
+            None
+        };
 
-        VhostUserEpollHandler {
+        Ok(VhostUserEpollHandler {
             vu_epoll_cfg,
             queue_evt_start_idx,
             slave_evt_idx,
+            hup_evt_idx,
+            reconnect_evt_idx,
+            reconnect_timer,
+            disconnected_at: None,
+        })
+    }
+
+    // The backend closed the connection. Until a new backend instance shows
+    // up, the guest kicks simply accumulate on the queue EventFds, leaving
+    // the device quiesced. Reaching the backend again is driven by a timer,
+    // so that the worker thread keeps serving the pause and kill events.
+    fn disconnected(&mut self, helper: &mut EpollHelper) -> Result<()> {
+        let reconnect = match self.vu_epoll_cfg.reconnect.as_ref() {
+            Some(reconnect) => reconnect,
+            None => return Ok(()),
+        };
+
+        helper
+            .del_event_custom(
+                reconnect.vu.lock().unwrap().as_raw_fd(),
+                self.hup_evt_idx,
+                epoll::Events::EPOLLHUP,
+            )
+            .map_err(Error::EpollCtl)?;
+
+        warn!(
+            "vhost-user backend {} disconnected, trying to reconnect",
+            reconnect.socket_path
+        );
+
+        self.disconnected_at = Some(Instant::now());
+        self.arm_reconnect_timer().map_err(Error::ReconnectTimer)
+    }
+
+    fn arm_reconnect_timer(&self) -> io::Result<()> {
+        if let Some(timer) = self.reconnect_timer.as_ref() {
+            timerfd_setup(timer, Duration::from_millis(RECONNECT_RETRY_DELAY_MS))?;
+        }
+        Ok(())
+    }
+
+    // Single attempt at reaching the backend, the timer being re-armed on
+    // failure until the timeout expires. Once connected, the whole session
+    // is replayed so that the backend can resume processing where the
+    // previous one stopped.
+    fn reconnect(&mut self, helper: &mut EpollHelper) -> Result<()> {
+        if let Some(timer) = self.reconnect_timer.as_mut() {
+            // When reading from the timerfd you get 8 bytes indicating
+            // the number of times this event has elapsed since the last read.
+            let mut buf = [0u8; 8];
+            timer.read_exact(&mut buf).map_err(Error::ReconnectTimer)?;
+        }
+
+        let reconnect = match self.vu_epoll_cfg.reconnect.as_ref() {
+            Some(reconnect) => reconnect,
+            None => return Ok(()),
+        };
+
+        let mut vu = match Master::connect(&reconnect.socket_path, reconnect.queues.len() as u64) {
+            Ok(vu) => vu,
+            Err(e) => {
+                let elapsed = self
+                    .disconnected_at
+                    .map(|t| t.elapsed())
+                    .unwrap_or_default();
+                if elapsed < Duration::from_secs(RECONNECT_TIMEOUT_SECS) {
+                    return self.arm_reconnect_timer().map_err(Error::ReconnectTimer);
+                }
+                return Err(Error::VhostUserConnect(e));
+            }
+        };
+
+        reinitialize_vhost_user(
+            &mut vu,
+            &reconnect.mem.memory(),
+            &reconnect.queues,
+            &reconnect.queue_evts,
+            &reconnect.call_evts,
+            reconnect.acked_features,
+            reconnect.acked_protocol_features,
+            reconnect.inflight.as_ref(),
+        )?;
+
+        // The slave request channel must be handed over to the new backend.
+        if let Some(slave_req_handler) = &self.vu_epoll_cfg.slave_req_handler {
+            vu.set_slave_request_fd(slave_req_handler.get_tx_raw_fd())
+                .map_err(Error::VhostUserSetSlaveRequestFd)?;
+        }
+
+        helper
+            .add_event_custom(vu.as_raw_fd(), self.hup_evt_idx, epoll::Events::EPOLLHUP)
+            .map_err(Error::EpollCtl)?;
+        *reconnect.vu.lock().unwrap() = vu;
+        self.disconnected_at = None;
+
+        info!(
+            "vhost-user backend {} successfully reconnected",
+            reconnect.socket_path
+        );
+
+        Ok(())
+    }
+
+    // The backend is gone for good. The worker thread stays around to
+    // serve the pause and kill events, while the driver is told through
+    // the device status that the device must be reset.
+    fn reconnect_failed(&mut self, e: Error) {
+        self.disconnected_at = None;
+
+        let reconnect = match self.vu_epoll_cfg.reconnect.as_ref() {
+            Some(reconnect) => reconnect,
+            None => return,
+        };
+
+        error!(
+            "Failed to reconnect vhost-user backend {}: {:?}",
+            reconnect.socket_path, e
+        );
+
+        reconnect.needs_reset.store(true, Ordering::SeqCst);
+        if let Err(e) = self
+            .vu_epoll_cfg
+            .interrupt_cb
+            .trigger(&VirtioInterruptType::Config, None)
+        {
+            error!("Failed to signal device status change: {:?}", e);
         }
     }
 
@@ -85,6 +273,18 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
             helper.add_event(self_req_handler.as_raw_fd(), self.slave_evt_idx)?;
         }
 
+        if let Some(reconnect) = &self.vu_epoll_cfg.reconnect {
+            helper.add_event_custom(
+                reconnect.vu.lock().unwrap().as_raw_fd(),
+                self.hup_evt_idx,
+                epoll::Events::EPOLLHUP,
+            )?;
+        }
+
+        if let Some(timer) = &self.reconnect_timer {
+            helper.add_event(timer.as_raw_fd(), self.reconnect_evt_idx)?;
+        }
+
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -92,7 +292,7 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
 }
 
 impl<S: VhostUserMasterReqHandler> EpollHelperHandler for VhostUserEpollHandler<S> {
-    fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+    fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool {
         let ev_type = event.data as u16;
         match ev_type {
             x if (x >= self.queue_evt_start_idx && x < self.slave_evt_idx) => {
@@ -118,6 +318,16 @@ impl<S: VhostUserMasterReqHandler> EpollHelperHandler for VhostUserEpollHandler<
                     }
                 }
             }
+            x if x == self.hup_evt_idx => {
+                if let Err(e) = self.disconnected(helper) {
+                    self.reconnect_failed(e);
+                }
+            }
+            x if x == self.reconnect_evt_idx => {
+                if let Err(e) = self.reconnect(helper) {
+                    self.reconnect_failed(e);
+                }
+            }
             _ => {
                 error!("Unknown event for vhost-user");
                 return true;
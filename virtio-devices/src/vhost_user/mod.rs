@@ -7,9 +7,11 @@ extern crate vhost_rs;
 extern crate virtio_bindings;
 extern crate vm_memory;
 
+use super::EpollHelperError;
 use std::io;
 use vhost_rs::Error as VhostError;
 use vm_memory::Error as MmapError;
+use vm_virtio::queue::Error as QueueError;
 
 pub mod blk;
 pub mod fs;
@@ -68,6 +70,12 @@ pub enum Error {
     VhostUserSetVringAddr(VhostError),
     /// Set vring base failed.
     VhostUserSetVringBase(VhostError),
+    /// Get vring base failed.
+    VhostUserGetVringBase(VhostError),
+    /// Get inflight shared memory failed.
+    VhostUserGetInflight(VhostError),
+    /// Set inflight shared memory failed.
+    VhostUserSetInflight(VhostError),
     /// Set vring call failed.
     VhostUserSetVringCall(VhostError),
     /// Set vring kick failed.
@@ -88,5 +96,26 @@ pub enum Error {
     UsedAddress,
     /// Invalid features provided from vhost-user backend
     InvalidFeatures,
+    /// Failed to read the vring used index from guest memory.
+    GetVringUsedIndex(QueueError),
+    /// No vring base has been saved for the queue.
+    MissingVringBase(usize),
+    /// Failed to update the epoll context.
+    EpollCtl(EpollHelperError),
+    /// Failed to arm or read the reconnection timer.
+    ReconnectTimer(io::Error),
 }
 type Result<T> = std::result::Result<T, Error>;
+
+/// State of a vhost-user device, which does not include the state of the
+/// backend itself, only what is needed to hand the vrings to a new backend
+/// instance on restore.
+#[derive(Serialize, Deserialize)]
+pub struct VhostUserState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: Vec<u8>,
+    /// Index of the next available descriptor of each vring, when the
+    /// device has been activated.
+    pub vring_bases: Option<Vec<u16>>,
+}
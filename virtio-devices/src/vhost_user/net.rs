@@ -9,14 +9,17 @@ use super::super::{
 };
 use super::handler::*;
 use super::vu_common_ctrl::*;
-use super::{Error, Result};
+use super::{Error, Result, VhostUserState};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use net_util::MacAddr;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::result;
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
@@ -25,7 +28,10 @@ use vhost_rs::VhostBackend;
 use virtio_bindings::bindings::virtio_net;
 use virtio_bindings::bindings::virtio_ring;
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 const DEFAULT_QUEUE_NUMBER: usize = 2;
@@ -36,11 +42,15 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {}
 pub struct Net {
     common: VirtioCommon,
     id: String,
-    vhost_user_net: Master,
+    vhost_user_net: Arc<Mutex<Master>>,
     backend_features: u64,
     config: VirtioNetConfig,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
     seccomp_action: SeccompAction,
+    socket_path: String,
+    acked_protocol_features: u64,
+    vrings: Option<VhostUserVrings>,
+    vring_bases: Option<Vec<u16>>,
 }
 
 impl Net {
@@ -50,10 +60,10 @@ impl Net {
         id: String,
         mac_addr: MacAddr,
         vu_cfg: VhostUserConfig,
+        stream: Option<UnixStream>,
         seccomp_action: SeccompAction,
     ) -> Result<Net> {
-        let mut vhost_user_net = Master::connect(&vu_cfg.socket, vu_cfg.num_queues as u64)
-            .map_err(Error::VhostUserCreateMaster)?;
+        let mut vhost_user_net = connect_vhost_user(&vu_cfg.socket, stream, vu_cfg.num_queues)?;
 
         // Filling device and vring features VMM supports.
         let mut avail_features = 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
@@ -99,11 +109,13 @@ impl Net {
             return Err(Error::VhostUserProtocolNotSupport);
         }
 
+        let mut acked_protocol_features = 0;
         let max_queue_number =
             if protocol_features.bits() & VhostUserProtocolFeatures::MQ.bits() != 0 {
                 vhost_user_net
                     .set_protocol_features(protocol_features & VhostUserProtocolFeatures::MQ)
                     .map_err(Error::VhostUserSetProtocolFeatures)?;
+                acked_protocol_features = VhostUserProtocolFeatures::MQ.bits();
                 match vhost_user_net.get_queue_num() {
                     Ok(qn) => qn,
                     Err(_) => DEFAULT_QUEUE_NUMBER as u64,
@@ -146,13 +158,41 @@ impl Net {
                 paused_sync: Some(Arc::new(Barrier::new((vu_cfg.num_queues / 2) + 1))),
                 ..Default::default()
             },
-            vhost_user_net,
+            vhost_user_net: Arc::new(Mutex::new(vhost_user_net)),
             backend_features,
             config,
             ctrl_queue_epoll_thread: None,
             seccomp_action,
+            socket_path: vu_cfg.socket,
+            acked_protocol_features,
+            vrings: None,
+            vring_bases: None,
         })
     }
+
+    fn state(&self) -> VhostUserState {
+        VhostUserState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            config: self.config.as_slice().to_vec(),
+            vring_bases: self.vring_bases.clone(),
+        }
+    }
+
+    fn set_state(&mut self, state: &VhostUserState) -> result::Result<(), MigratableError> {
+        if state.acked_features & !self.common.avail_features != 0 {
+            return Err(MigratableError::Restore(anyhow!(
+                "vhost-user-net backend does not support the features acked by the guest"
+            )));
+        }
+        self.common.acked_features = state.acked_features;
+        self.config = *VirtioNetConfig::from_slice(&state.config).ok_or_else(|| {
+            MigratableError::Restore(anyhow!("Invalid vhost-user-net configuration"))
+        })?;
+        self.vring_bases = state.vring_bases.clone();
+
+        Ok(())
+    }
 }
 
 impl Drop for Net {
@@ -259,16 +299,54 @@ impl VirtioDevice for Net {
                 })?;
         }
 
+        // Keep a copy of the vrings description, needed to bring a new
+        // backend instance up to speed in case of reconnection.
+        let reconnect_queues = queues.clone();
+        let reconnect_queue_evts = queue_evts
+            .iter()
+            .map(|e| e.try_clone())
+            .collect::<std::io::Result<Vec<EventFd>>>()
+            .map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        let acked_features = self.common.acked_features & self.backend_features;
+        // The vrings resume from the state restored from a snapshot, if any.
+        let vring_bases = self.vring_bases.take();
         let mut vu_interrupt_list = setup_vhost_user(
-            &mut self.vhost_user_net,
+            &mut self.vhost_user_net.lock().unwrap(),
             &mem.memory(),
             queues,
             queue_evts,
             &interrupt_cb,
-            self.common.acked_features & self.backend_features,
+            acked_features,
+            vring_bases.as_deref(),
+            None,
         )
         .map_err(ActivateError::VhostUserNetSetup)?;
 
+        // Only the first worker thread monitors the connection with the
+        // backend, as it is in charge of reconnecting all the vrings.
+        let reconnect = VhostUserReconnect {
+            vu: self.vhost_user_net.clone(),
+            socket_path: self.socket_path.clone(),
+            mem,
+            queues: reconnect_queues,
+            queue_evts: reconnect_queue_evts,
+            call_evts: vring_call_evts(&vu_interrupt_list, &interrupt_cb)
+                .map_err(ActivateError::VhostUserNetSetup)?,
+            acked_features,
+            acked_protocol_features: self.acked_protocol_features,
+            inflight: None,
+            needs_reset: self.common.needs_reset.clone(),
+        };
+        self.vrings = Some(reconnect.vrings().map_err(|e| {
+            error!("failed to clone vrings EventFd: {}", e);
+            ActivateError::BadActivate
+        })?);
+        let mut reconnect = Some(reconnect);
+
         let mut epoll_threads = Vec::new();
         for _ in 0..vu_interrupt_list.len() / 2 {
             let mut interrupt_list_sub: Vec<(Option<EventFd>, Queue)> = Vec::with_capacity(2);
@@ -302,7 +380,12 @@ impl VirtioDevice for Net {
                 pause_evt,
                 vu_interrupt_list: interrupt_list_sub,
                 slave_req_handler: None,
-            });
+                reconnect: reconnect.take(),
+            })
+            .map_err(|e| {
+                error!("failed to create vhost-user epoll handler: {:?}", e);
+                ActivateError::BadActivate
+            })?;
 
             let paused = self.common.paused.clone();
             let paused_sync = self.common.paused_sync.clone();
@@ -336,11 +419,17 @@ impl VirtioDevice for Net {
             self.common.resume().ok()?;
         }
 
-        if let Err(e) = reset_vhost_user(&mut self.vhost_user_net, self.common.queue_sizes.len()) {
+        if let Err(e) = reset_vhost_user(
+            &mut self.vhost_user_net.lock().unwrap(),
+            self.common.queue_sizes.len(),
+        ) {
             error!("Failed to reset vhost-user daemon: {:?}", e);
             return None;
         }
 
+        self.vrings = None;
+        self.vring_bases = None;
+
         if let Some(kill_evt) = self.common.kill_evt.take() {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
@@ -353,21 +442,51 @@ impl VirtioDevice for Net {
         ))
     }
 
+    fn needs_reset(&self) -> bool {
+        self.common.needs_reset.load(Ordering::SeqCst)
+    }
+
     fn shutdown(&mut self) {
-        let _ = unsafe { libc::close(self.vhost_user_net.as_raw_fd()) };
+        let _ = unsafe { libc::close(self.vhost_user_net.lock().unwrap().as_raw_fd()) };
     }
 
     fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
-        update_mem_table(&mut self.vhost_user_net, mem).map_err(crate::Error::VhostUserUpdateMemory)
+        update_mem_table(&mut self.vhost_user_net.lock().unwrap(), mem)
+            .map_err(crate::Error::VhostUserUpdateMemory)
     }
 }
 
 impl Pausable for Net {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
-        self.common.pause()
+        self.common.pause()?;
+
+        if let Some(vrings) = self.vrings.as_ref() {
+            self.vring_bases = Some(
+                stop_vhost_user_vrings(&mut self.vhost_user_net.lock().unwrap(), vrings.len())
+                    .map_err(|e| {
+                        MigratableError::Pause(anyhow!(
+                            "Could not stop vhost-user-net vrings {:?}",
+                            e
+                        ))
+                    })?,
+            );
+        }
+
+        Ok(())
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        if let (Some(vrings), Some(vring_bases)) = (self.vrings.as_ref(), self.vring_bases.take()) {
+            start_vhost_user_vrings(
+                &mut self.vhost_user_net.lock().unwrap(),
+                vrings,
+                &vring_bases,
+            )
+            .map_err(|e| {
+                MigratableError::Resume(anyhow!("Could not start vhost-user-net vrings {:?}", e))
+            })?;
+        }
+
         self.common.resume()
     }
 }
@@ -376,6 +495,39 @@ impl Snapshottable for Net {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let snapshot =
+            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut net_snapshot = Snapshot::new(self.id.as_str());
+        net_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id),
+            snapshot,
+        });
+
+        Ok(net_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if let Some(net_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let net_state = match serde_json::from_slice(&net_section.snapshot) {
+                Ok(state) => state,
+                Err(error) => {
+                    return Err(MigratableError::Restore(anyhow!(
+                        "Could not deserialize VHOST_USER_NET {}",
+                        error
+                    )))
+                }
+            };
+
+            return self.set_state(&net_state);
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find VHOST_USER_NET snapshot section"
+        )))
+    }
 }
 impl Transportable for Net {}
 impl Migratable for Net {}
@@ -6,13 +6,21 @@ use super::{Error, Result};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
 use std::convert::TryInto;
+use std::fs::File;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::sync::Arc;
 use std::vec::Vec;
 use vfio_ioctls::get_host_address_range;
+use vhost_rs::vhost_user::message::{
+    VhostUserInflight, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
+};
 use vhost_rs::vhost_user::{Master, VhostUserMaster};
 use vhost_rs::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
-use vm_memory::{Address, Error as MmapError, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{
+    Address, Error as MmapError, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
+    GuestMemoryMmap, GuestMemoryRegion,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 #[derive(Debug, Clone)]
@@ -22,6 +30,69 @@ pub struct VhostUserConfig {
     pub queue_size: u16,
 }
 
+/// Connect to the vhost-user backend listening on `socket`, unless the caller
+/// is already connected to it through `stream`. Connecting beforehand lets the
+/// caller shut the connection down, aborting the creation of a device whose
+/// backend doesn't answer.
+pub fn connect_vhost_user(
+    socket: &str,
+    stream: Option<UnixStream>,
+    num_queues: usize,
+) -> Result<Master> {
+    match stream {
+        Some(stream) => Ok(Master::from_stream(stream, num_queues as u64)),
+        None => Master::connect(socket, num_queues as u64).map_err(Error::VhostUserCreateMaster),
+    }
+}
+
+/// Shared memory region, allocated by the backend, where the backend tracks
+/// the requests in flight. The region outlives the backend, which lets a new
+/// backend instance resubmit the requests left behind by a crashed one.
+#[derive(Default)]
+pub struct Inflight {
+    pub info: VhostUserInflight,
+    pub fd: Option<File>,
+}
+
+impl Inflight {
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Inflight {
+            info: self.info,
+            fd: match &self.fd {
+                Some(fd) => Some(fd.try_clone()?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Hand the inflight region to the backend, after retrieving it from the
+/// backend if this has not been done yet. The vrings must all be the same
+/// size.
+pub fn set_vhost_user_inflight(
+    vu: &mut Master,
+    inflight: &mut Inflight,
+    num_queues: usize,
+    queue_size: u16,
+) -> Result<()> {
+    if inflight.fd.is_none() {
+        let (info, fd) = vu
+            .get_inflight_fd(&VhostUserInflight {
+                mmap_size: 0,
+                mmap_offset: 0,
+                num_queues: num_queues as u16,
+                queue_size,
+            })
+            .map_err(Error::VhostUserGetInflight)?;
+        inflight.info = info;
+        inflight.fd = Some(fd);
+    }
+
+    // Unwrapping is safe since the fd has just been set if it was missing.
+    vu.set_inflight_fd(&inflight.info, inflight.fd.as_ref().unwrap().as_raw_fd())
+        .map_err(Error::VhostUserSetInflight)
+}
+
 pub fn update_mem_table(vu: &mut Master, mem: &GuestMemoryMmap) -> Result<()> {
     let mut regions: Vec<VhostUserMemoryRegionInfo> = Vec::new();
     mem.with_regions_mut(|_, region| {
@@ -50,12 +121,60 @@ pub fn update_mem_table(vu: &mut Master, mem: &GuestMemoryMmap) -> Result<()> {
     Ok(())
 }
 
+fn setup_vring(
+    vu: &mut Master,
+    mem: &GuestMemoryMmap,
+    queue_index: usize,
+    queue: &Queue,
+    queue_evt: &EventFd,
+    call_evt: &EventFd,
+    base: u16,
+) -> Result<()> {
+    let actual_size: usize = queue.actual_size().try_into().unwrap();
+
+    vu.set_vring_num(queue_index, queue.actual_size())
+        .map_err(Error::VhostUserSetVringNum)?;
+
+    let config_data = VringConfigData {
+        queue_max_size: queue.get_max_size(),
+        queue_size: queue.actual_size(),
+        flags: 0u32,
+        desc_table_addr: get_host_address_range(
+            mem,
+            queue.desc_table,
+            actual_size * std::mem::size_of::<Descriptor>(),
+        )
+        .ok_or(Error::DescriptorTableAddress)? as u64,
+        // The used ring is {flags: u16; idx: u16; virtq_used_elem [{id: u16, len: u16}; actual_size]},
+        // i.e. 4 + (4 + 4) * actual_size.
+        used_ring_addr: get_host_address_range(mem, queue.used_ring, 4 + actual_size * 8)
+            .ok_or(Error::UsedAddress)? as u64,
+        // The used ring is {flags: u16; idx: u16; elem [u16; actual_size]},
+        // i.e. 4 + (2) * actual_size.
+        avail_ring_addr: get_host_address_range(mem, queue.avail_ring, 4 + actual_size * 2)
+            .ok_or(Error::AvailAddress)? as u64,
+        log_addr: None,
+    };
+
+    vu.set_vring_addr(queue_index, &config_data)
+        .map_err(Error::VhostUserSetVringAddr)?;
+    vu.set_vring_base(queue_index, base)
+        .map_err(Error::VhostUserSetVringBase)?;
+    vu.set_vring_call(queue_index, call_evt)
+        .map_err(Error::VhostUserSetVringCall)?;
+    vu.set_vring_kick(queue_index, queue_evt)
+        .map_err(Error::VhostUserSetVringKick)?;
+    vu.set_vring_enable(queue_index, true)
+        .map_err(Error::VhostUserSetVringEnable)
+}
+
 pub fn setup_vhost_user_vring(
     vu: &mut Master,
     mem: &GuestMemoryMmap,
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
     virtio_interrupt: &Arc<dyn VirtioInterrupt>,
+    vring_bases: Option<&[u16]>,
 ) -> Result<Vec<(Option<EventFd>, Queue)>> {
     // Let's first provide the memory table to the backend.
     update_mem_table(vu, mem)?;
@@ -63,59 +182,66 @@ pub fn setup_vhost_user_vring(
     let mut vu_interrupt_list = Vec::new();
 
     for (queue_index, queue) in queues.into_iter().enumerate() {
-        let actual_size: usize = queue.actual_size().try_into().unwrap();
-
-        vu.set_vring_num(queue_index, queue.actual_size())
-            .map_err(Error::VhostUserSetVringNum)?;
-
-        let config_data = VringConfigData {
-            queue_max_size: queue.get_max_size(),
-            queue_size: queue.actual_size(),
-            flags: 0u32,
-            desc_table_addr: get_host_address_range(
-                mem,
-                queue.desc_table,
-                actual_size * std::mem::size_of::<Descriptor>(),
-            )
-            .ok_or(Error::DescriptorTableAddress)? as u64,
-            // The used ring is {flags: u16; idx: u16; virtq_used_elem [{id: u16, len: u16}; actual_size]},
-            // i.e. 4 + (4 + 4) * actual_size.
-            used_ring_addr: get_host_address_range(mem, queue.used_ring, 4 + actual_size * 8)
-                .ok_or(Error::UsedAddress)? as u64,
-            // The used ring is {flags: u16; idx: u16; elem [u16; actual_size]},
-            // i.e. 4 + (2) * actual_size.
-            avail_ring_addr: get_host_address_range(mem, queue.avail_ring, 4 + actual_size * 2)
-                .ok_or(Error::AvailAddress)? as u64,
-            log_addr: None,
+        let base = match vring_bases {
+            Some(bases) => *bases
+                .get(queue_index)
+                .ok_or(Error::MissingVringBase(queue_index))?,
+            None => 0,
         };
-
-        vu.set_vring_addr(queue_index, &config_data)
-            .map_err(Error::VhostUserSetVringAddr)?;
-        vu.set_vring_base(queue_index, 0u16)
-            .map_err(Error::VhostUserSetVringBase)?;
-
         if let Some(eventfd) = virtio_interrupt.notifier(&VirtioInterruptType::Queue, Some(&queue))
         {
-            vu.set_vring_call(queue_index, &eventfd)
-                .map_err(Error::VhostUserSetVringCall)?;
+            setup_vring(
+                vu,
+                mem,
+                queue_index,
+                &queue,
+                &queue_evts[queue_index],
+                &eventfd,
+                base,
+            )?;
             vu_interrupt_list.push((None, queue));
         } else {
             let eventfd = EventFd::new(EFD_NONBLOCK).map_err(Error::VhostIrqCreate)?;
-            vu.set_vring_call(queue_index, &eventfd)
-                .map_err(Error::VhostUserSetVringCall)?;
+            setup_vring(
+                vu,
+                mem,
+                queue_index,
+                &queue,
+                &queue_evts[queue_index],
+                &eventfd,
+                base,
+            )?;
             vu_interrupt_list.push((Some(eventfd), queue));
         }
+    }
 
-        vu.set_vring_kick(queue_index, &queue_evts[queue_index])
-            .map_err(Error::VhostUserSetVringKick)?;
+    Ok(vu_interrupt_list)
+}
 
-        vu.set_vring_enable(queue_index, true)
-            .map_err(Error::VhostUserSetVringEnable)?;
+/// Collect the EventFds the backend must write to when it wants to notify
+/// the guest about used buffers, so that they can be handed again to a new
+/// backend instance after a reconnection.
+pub fn vring_call_evts(
+    vu_interrupt_list: &[(Option<EventFd>, Queue)],
+    virtio_interrupt: &Arc<dyn VirtioInterrupt>,
+) -> Result<Vec<EventFd>> {
+    let mut call_evts = Vec::new();
+    for (eventfd, queue) in vu_interrupt_list.iter() {
+        let call_evt = match eventfd {
+            Some(eventfd) => eventfd.try_clone().map_err(Error::VhostIrqCreate)?,
+            None => virtio_interrupt
+                .notifier(&VirtioInterruptType::Queue, Some(queue))
+                .ok_or(Error::VhostIrqCreate(std::io::Error::from_raw_os_error(
+                    libc::EINVAL,
+                )))?,
+        };
+        call_evts.push(call_evt);
     }
 
-    Ok(vu_interrupt_list)
+    Ok(call_evts)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn setup_vhost_user(
     vu: &mut Master,
     mem: &GuestMemoryMmap,
@@ -123,12 +249,100 @@ pub fn setup_vhost_user(
     queue_evts: Vec<EventFd>,
     virtio_interrupt: &Arc<dyn VirtioInterrupt>,
     acked_features: u64,
+    vring_bases: Option<&[u16]>,
+    inflight: Option<&mut Inflight>,
 ) -> Result<Vec<(Option<EventFd>, Queue)>> {
     // Set features based on the acked features from the guest driver.
     vu.set_features(acked_features)
         .map_err(Error::VhostUserSetFeatures)?;
 
-    setup_vhost_user_vring(vu, mem, queues, queue_evts, virtio_interrupt)
+    if let Some(inflight) = inflight {
+        set_vhost_user_inflight(vu, inflight, queues.len(), queues[0].actual_size())?;
+    }
+
+    setup_vhost_user_vring(vu, mem, queues, queue_evts, virtio_interrupt, vring_bases)
+}
+
+/// Description of the vrings handed to the backend, needed to start them
+/// again once they have been stopped.
+pub struct VhostUserVrings {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+    call_evts: Vec<EventFd>,
+}
+
+impl VhostUserVrings {
+    pub fn new(
+        mem: &GuestMemoryAtomic<GuestMemoryMmap>,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+        call_evts: &[EventFd],
+    ) -> std::io::Result<Self> {
+        Ok(VhostUserVrings {
+            mem: mem.clone(),
+            queues: queues.to_vec(),
+            queue_evts: queue_evts
+                .iter()
+                .map(|e| e.try_clone())
+                .collect::<std::io::Result<Vec<EventFd>>>()?,
+            call_evts: call_evts
+                .iter()
+                .map(|e| e.try_clone())
+                .collect::<std::io::Result<Vec<EventFd>>>()?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
+
+/// Stop the vrings and retrieve the index of the next available descriptor
+/// the backend would have processed for each of them. The backend completes
+/// the requests in flight before replying, which means the vrings can later
+/// be started again from these indexes, possibly on a different backend
+/// instance.
+pub fn stop_vhost_user_vrings(vu: &mut Master, num_queues: usize) -> Result<Vec<u16>> {
+    let mut vring_bases = Vec::with_capacity(num_queues);
+    for queue_index in 0..num_queues {
+        vu.set_vring_enable(queue_index, false)
+            .map_err(Error::VhostUserSetVringEnable)?;
+        let base = vu
+            .get_vring_base(queue_index)
+            .map_err(Error::VhostUserGetVringBase)?;
+        vring_bases.push(base as u16);
+    }
+
+    Ok(vring_bases)
+}
+
+/// Start again the vrings previously stopped with `stop_vhost_user_vrings()`.
+pub fn start_vhost_user_vrings(
+    vu: &mut Master,
+    vrings: &VhostUserVrings,
+    vring_bases: &[u16],
+) -> Result<()> {
+    let mem = vrings.mem.memory();
+    for (queue_index, queue) in vrings.queues.iter().enumerate() {
+        setup_vring(
+            vu,
+            &mem,
+            queue_index,
+            queue,
+            &vrings.queue_evts[queue_index],
+            &vrings.call_evts[queue_index],
+            *vring_bases
+                .get(queue_index)
+                .ok_or(Error::MissingVringBase(queue_index))?,
+        )?;
+    }
+
+    Ok(())
 }
 
 pub fn reset_vhost_user(vu: &mut Master, num_queues: usize) -> Result<()> {
@@ -145,3 +359,60 @@ pub fn reset_vhost_user(vu: &mut Master, num_queues: usize) -> Result<()> {
     // Reset the owner.
     vu.reset_owner().map_err(Error::VhostUserResetOwner)
 }
+
+/// Replay the whole vhost-user session setup against a backend which has
+/// just been reconnected. The features previously negotiated with the guest
+/// are restored, and the vrings resume from the last used index found in
+/// guest memory. When provided, the inflight region is handed over to the
+/// new backend, so that it can resubmit the requests in flight.
+#[allow(clippy::too_many_arguments)]
+pub fn reinitialize_vhost_user(
+    vu: &mut Master,
+    mem: &GuestMemoryMmap,
+    queues: &[Queue],
+    queue_evts: &[EventFd],
+    call_evts: &[EventFd],
+    acked_features: u64,
+    acked_protocol_features: u64,
+    inflight: Option<&Inflight>,
+) -> Result<()> {
+    vu.set_owner().map_err(Error::VhostUserSetOwner)?;
+    vu.get_features().map_err(Error::VhostUserGetFeatures)?;
+    vu.set_features(acked_features)
+        .map_err(Error::VhostUserSetFeatures)?;
+
+    if acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0
+        && acked_protocol_features != 0
+    {
+        vu.get_protocol_features()
+            .map_err(Error::VhostUserGetProtocolFeatures)?;
+        vu.set_protocol_features(VhostUserProtocolFeatures::from_bits_truncate(
+            acked_protocol_features,
+        ))
+        .map_err(Error::VhostUserSetProtocolFeatures)?;
+    }
+
+    if let Some(Inflight { info, fd: Some(fd) }) = inflight {
+        vu.set_inflight_fd(info, fd.as_raw_fd())
+            .map_err(Error::VhostUserSetInflight)?;
+    }
+
+    update_mem_table(vu, mem)?;
+
+    for (queue_index, queue) in queues.iter().enumerate() {
+        let base = queue
+            .used_index_from_memory(mem)
+            .map_err(Error::GetVringUsedIndex)?;
+        setup_vring(
+            vu,
+            mem,
+            queue_index,
+            queue,
+            &queue_evts[queue_index],
+            &call_evts[queue_index],
+            base,
+        )?;
+    }
+
+    Ok(())
+}
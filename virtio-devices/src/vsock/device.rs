@@ -46,7 +46,7 @@ use std::result;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
-use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
@@ -66,6 +66,10 @@ pub const EVT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 // Notification coming from the backend.
 pub const BACKEND_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 
+// Event telling the driver the connections were lost and the CID may have
+// changed.
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
 /// The `VsockEpollHandler` implements the runtime logic of our vsock device:
 /// 1. Respond to TX queue events by wrapping virtio buffers into `VsockPacket`s, then sending those
 ///    packets to the `VsockBackend`;
@@ -83,6 +87,8 @@ pub const BACKEND_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 /// - on backend event:
 ///   - forward the event to the backend; then
 ///   - again, attempt to fetch any incoming packets queued by the backend into virtio RX buffers.
+/// - on event queue event:
+///   - report the pending transport reset, if any, into a newly available event buffer.
 ///
 pub struct VsockEpollHandler<B: VsockBackend> {
     pub mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -92,6 +98,7 @@ pub struct VsockEpollHandler<B: VsockBackend> {
     pub pause_evt: EventFd,
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub backend: Arc<RwLock<B>>,
+    pub transport_reset_pending: bool,
 }
 
 impl<B> VsockEpollHandler<B>
@@ -194,6 +201,38 @@ where
         }
     }
 
+    /// Report the pending transport reset into the first event buffer the driver made available,
+    /// for it to drop its connections and read the CID again.
+    ///
+    fn process_evt(&mut self) -> result::Result<(), DeviceError> {
+        debug!("vsock: epoll_handler::process_evt()");
+
+        if !self.transport_reset_pending {
+            return Ok(());
+        }
+
+        let mem = self.mem.memory();
+        let (desc_index, len) = match self.queues[2].iter(&mem).next() {
+            Some(avail_desc) => {
+                if avail_desc.is_write_only() && avail_desc.len >= 4 {
+                    mem.write_obj(VIRTIO_VSOCK_EVENT_TRANSPORT_RESET, avail_desc.addr)
+                        .map_err(|e| {
+                            DeviceError::IoError(io::Error::new(io::ErrorKind::Other, e))
+                        })?;
+                    self.transport_reset_pending = false;
+                    (avail_desc.index, 4)
+                } else {
+                    warn!("vsock: invalid event buffer");
+                    (avail_desc.index, 0)
+                }
+            }
+            None => return Ok(()),
+        };
+
+        self.queues[2].add_used(&mem, desc_index, len);
+        self.signal_used_queue(&self.queues[2])
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -204,6 +243,10 @@ where
         helper.add_event(self.queue_evts[1].as_raw_fd(), TX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evts[2].as_raw_fd(), EVT_QUEUE_EVENT)?;
         helper.add_event(self.backend.read().unwrap().get_polled_fd(), BACKEND_EVENT)?;
+        // The driver filled the event queue before the device was restored.
+        if let Err(e) = self.process_evt() {
+            error!("Failed to process EVT queue: {:?}", e);
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -264,6 +307,9 @@ where
                 if let Err(e) = self.queue_evts[2].read() {
                     error!("Failed to get EVT queue event: {:?}", e);
                     return true;
+                } else if let Err(e) = self.process_evt() {
+                    error!("Failed to process EVT queue: {:?}", e);
+                    return true;
                 }
             }
             BACKEND_EVENT => {
@@ -303,6 +349,7 @@ pub struct Vsock<B: VsockBackend> {
     backend: Arc<RwLock<B>>,
     path: PathBuf,
     seccomp_action: SeccompAction,
+    transport_reset: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -344,6 +391,7 @@ where
             backend: Arc::new(RwLock::new(backend)),
             path,
             seccomp_action,
+            transport_reset: false,
         })
     }
 
@@ -357,6 +405,9 @@ where
     fn set_state(&mut self, state: &VsockState) -> io::Result<()> {
         self.common.avail_features = state.avail_features;
         self.common.acked_features = state.acked_features;
+        // The connections didn't survive the snapshot, and the CID may have
+        // been changed by the restore configuration.
+        self.transport_reset = true;
 
         Ok(())
     }
@@ -446,7 +497,9 @@ where
             pause_evt,
             interrupt_cb,
             backend: self.backend.clone(),
+            transport_reset_pending: self.transport_reset,
         };
+        self.transport_reset = false;
 
         let paused = self.common.paused.clone();
         let paused_sync = self.common.paused_sync.clone();
@@ -550,6 +603,8 @@ mod tests {
     use super::*;
     use crate::vsock::device::{BACKEND_EVENT, EVT_QUEUE_EVENT, RX_QUEUE_EVENT, TX_QUEUE_EVENT};
     use libc::EFD_NONBLOCK;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
 
     #[test]
     fn test_virtio_device() {
@@ -822,6 +877,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_evq_transport_reset() {
+        let test_ctx = TestContext::new();
+        let mut ctx = test_ctx.create_epoll_handler_context();
+        ctx.handler.transport_reset_pending = true;
+
+        // The reset stays pending until the driver provides an event buffer.
+        ctx.handler.process_evt().unwrap();
+        assert!(ctx.handler.transport_reset_pending);
+        assert_eq!(ctx.guest_evvq.used.idx.get(), 0);
+
+        ctx.guest_evvq.dtable[0].set(0x0060_0000, 4, VIRTQ_DESC_F_WRITE, 0);
+        ctx.guest_evvq.avail.ring[0].set(0);
+        ctx.guest_evvq.avail.idx.set(1);
+        test_ctx
+            .mem
+            .write_obj(0xffff_ffffu32, GuestAddress(0x0060_0000))
+            .unwrap();
+
+        ctx.handler.process_evt().unwrap();
+        assert!(!ctx.handler.transport_reset_pending);
+        assert_eq!(ctx.guest_evvq.used.idx.get(), 1);
+        assert_eq!(ctx.guest_evvq.used.ring[0].get().len, 4);
+        assert_eq!(
+            test_ctx
+                .mem
+                .read_obj::<u32>(GuestAddress(0x0060_0000))
+                .unwrap(),
+            VIRTIO_VSOCK_EVENT_TRANSPORT_RESET
+        );
+    }
+
     #[test]
     fn test_backend_event() {
         // Test case:
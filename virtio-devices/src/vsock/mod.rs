@@ -326,6 +326,7 @@ mod tests {
                     pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
                     interrupt_cb,
                     backend: Arc::new(RwLock::new(TestBackend::new())),
+                    transport_reset_pending: false,
                 },
             }
         }
@@ -11,17 +11,18 @@ use super::{
     VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::timer::{timerfd_create, timerfd_setup_periodic};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::fs::File;
 use std::io::{self, Read};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::result;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
@@ -39,10 +40,10 @@ const TIMER_EXPIRED_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 
 // Number of seconds to check to see if there has been a ping
 // This needs to match what the driver is using.
-const WATCHDOG_TIMER_INTERVAL: i64 = 15;
+const WATCHDOG_TIMER_INTERVAL: u64 = 15;
 
 // Number of seconds since last ping to trigger reboot
-const WATCHDOG_TIMEOUT: u64 = WATCHDOG_TIMER_INTERVAL as u64 + 5;
+const WATCHDOG_TIMEOUT: u64 = WATCHDOG_TIMER_INTERVAL + 5;
 
 struct WatchdogEpollHandler {
     queues: Vec<Queue>,
@@ -75,7 +76,10 @@ impl WatchdogEpollHandler {
                         "First ping received. Starting timer (every {} seconds)",
                         WATCHDOG_TIMER_INTERVAL
                     );
-                    if let Err(e) = timerfd_setup(&self.timer, WATCHDOG_TIMER_INTERVAL) {
+                    if let Err(e) = timerfd_setup_periodic(
+                        &self.timer,
+                        Duration::from_secs(WATCHDOG_TIMER_INTERVAL),
+                    ) {
                         error!("Error programming timer fd: {:?}", e);
                     }
                 }
@@ -232,37 +236,6 @@ impl Drop for Watchdog {
     }
 }
 
-fn timerfd_create() -> Result<RawFd, io::Error> {
-    let res = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
-    if res < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(res as RawFd)
-    }
-}
-
-fn timerfd_setup(timer: &File, secs: i64) -> Result<(), io::Error> {
-    let periodic = libc::itimerspec {
-        it_interval: libc::timespec {
-            tv_sec: secs,
-            tv_nsec: 0,
-        },
-        it_value: libc::timespec {
-            tv_sec: secs,
-            tv_nsec: 0,
-        },
-    };
-
-    let res =
-        unsafe { libc::timerfd_settime(timer.as_raw_fd(), 0, &periodic, std::ptr::null_mut()) };
-
-    if res < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(())
-    }
-}
-
 impl VirtioDevice for Watchdog {
     fn device_type(&self) -> u32 {
         self.common.device_type
@@ -366,7 +339,7 @@ impl VirtioDevice for Watchdog {
 impl Pausable for Watchdog {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
         info!("Watchdog paused - disabling timer");
-        timerfd_setup(&self.timer, 0)
+        timerfd_setup_periodic(&self.timer, Duration::from_secs(0))
             .map_err(|e| MigratableError::Pause(anyhow!("Error clearing timer: {:?}", e)))?;
         self.common.pause()
     }
@@ -379,7 +352,7 @@ impl Pausable for Watchdog {
                 WATCHDOG_TIMER_INTERVAL
             );
             self.last_ping_time.lock().unwrap().replace(Instant::now());
-            timerfd_setup(&self.timer, WATCHDOG_TIMER_INTERVAL)
+            timerfd_setup_periodic(&self.timer, Duration::from_secs(WATCHDOG_TIMER_INTERVAL))
                 .map_err(|e| MigratableError::Resume(anyhow!("Error setting timer: {:?}", e)))?;
         }
         self.common.resume()
@@ -0,0 +1,375 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::epoll_helper::{EpollHelper, EpollHelperHandler, EpollHelperPoll};
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+const WORKER_KILL_EVENT: u64 = 0;
+const WORKER_RESUME_EVENT: u64 = 1;
+// First token identifying a registered handler.
+const FIRST_HANDLER_TOKEN: u64 = 2;
+
+// Handlers belonging to the same device, which must all acknowledge the pause
+// before the device is considered paused.
+struct PauseGroup {
+    paused: Arc<AtomicBool>,
+    paused_sync: Arc<Barrier>,
+    members: usize,
+    acked: Mutex<usize>,
+}
+
+impl PauseGroup {
+    // Returns true once every member of the group has been paused.
+    fn ack(&self) -> bool {
+        let mut acked = self.acked.lock().unwrap();
+        *acked += 1;
+        if *acked == self.members {
+            *acked = 0;
+            return true;
+        }
+        false
+    }
+}
+
+struct PoolEntry {
+    helper: EpollHelper,
+    handler: Box<dyn EpollHelperHandler + Send>,
+    group: Arc<PauseGroup>,
+    paused: bool,
+}
+
+struct Worker {
+    epoll_file: File,
+    kill_evt: EventFd,
+    resume_evt: EventFd,
+    entries: Mutex<BTreeMap<u64, PoolEntry>>,
+}
+
+impl Worker {
+    fn new() -> io::Result<Self> {
+        let epoll_fd = epoll::create(true)?;
+        // Use 'File' to enforce closing on 'epoll_fd'
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+        let worker = Worker {
+            epoll_file,
+            kill_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            resume_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            entries: Mutex::new(BTreeMap::new()),
+        };
+        worker.ctl(
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            worker.kill_evt.as_raw_fd(),
+            WORKER_KILL_EVENT,
+        )?;
+        worker.ctl(
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            worker.resume_evt.as_raw_fd(),
+            WORKER_RESUME_EVENT,
+        )?;
+
+        Ok(worker)
+    }
+
+    fn ctl(&self, op: epoll::ControlOptions, fd: RawFd, token: u64) -> io::Result<()> {
+        epoll::ctl(
+            self.epoll_file.as_raw_fd(),
+            op,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, token),
+        )
+    }
+
+    fn add(&self, token: u64, mut entry: PoolEntry) -> io::Result<()> {
+        let fd = entry.helper.as_raw_fd();
+        // Similarly to a dedicated thread, the handler does not process
+        // anything until the device has been resumed when it is registered
+        // while paused, which happens on the restore path.
+        entry.paused = entry.group.paused.load(Ordering::SeqCst);
+        let paused = entry.paused;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(token, entry);
+        if !paused {
+            if let Err(e) = self.ctl(epoll::ControlOptions::EPOLL_CTL_ADD, fd, token) {
+                entries.remove(&token);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, token: u64) {
+        let mut pause_ack = None;
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = match entries.get_mut(&token) {
+                Some(entry) => entry,
+                None => return,
+            };
+
+            let res = entry
+                .helper
+                .poll(entry.handler.as_mut())
+                .unwrap_or_else(|e| {
+                    error!("Error polling pooled handler: {:?}", e);
+                    EpollHelperPoll::Stop
+                });
+            match res {
+                EpollHelperPoll::Continue => {}
+                EpollHelperPoll::Pause => {
+                    // Stop listening to the handler events until the device
+                    // is resumed.
+                    if let Err(e) = self.ctl(
+                        epoll::ControlOptions::EPOLL_CTL_DEL,
+                        entry.helper.as_raw_fd(),
+                        token,
+                    ) {
+                        error!("Failed to pause pooled handler: {}", e);
+                    }
+                    entry.paused = true;
+                    if entry.group.ack() {
+                        pause_ack = Some(entry.group.paused_sync.clone());
+                    }
+                }
+                EpollHelperPoll::Stop => {
+                    let entry = entries.remove(&token).unwrap();
+                    let _ = self.ctl(
+                        epoll::ControlOptions::EPOLL_CTL_DEL,
+                        entry.helper.as_raw_fd(),
+                        token,
+                    );
+                }
+            }
+        }
+
+        // Acknowledge the pause of the whole device, without holding the lock
+        // so that the other handlers of this worker can still be paused.
+        if let Some(paused_sync) = pause_ack {
+            paused_sync.wait();
+        }
+    }
+
+    fn resume(&self) {
+        let _ = self.resume_evt.read();
+
+        let mut entries = self.entries.lock().unwrap();
+        for (token, entry) in entries.iter_mut() {
+            if entry.paused && !entry.group.paused.load(Ordering::SeqCst) {
+                entry.helper.drain_pause_evt();
+                if let Err(e) = self.ctl(
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    entry.helper.as_raw_fd(),
+                    *token,
+                ) {
+                    error!("Failed to resume pooled handler: {}", e);
+                    continue;
+                }
+                entry.paused = false;
+            }
+        }
+    }
+
+    fn run(&self) -> io::Result<()> {
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        loop {
+            let num_events = match epoll::wait(self.epoll_file.as_raw_fd(), -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    WORKER_KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping worker");
+                        return Ok(());
+                    }
+                    WORKER_RESUME_EVENT => self.resume(),
+                    token => self.dispatch(token),
+                }
+            }
+        }
+    }
+}
+
+/// Small pool of threads shared by the virtio devices. Instead of running its
+/// own epoll loop on a dedicated thread, each queue handler registers its
+/// `EpollHelper` with one of the workers, which waits on the epoll file
+/// descriptors of all the handlers it has been assigned.
+pub struct EpollWorkerPool {
+    workers: Vec<Arc<Worker>>,
+    threads: Vec<thread::JoinHandle<()>>,
+    next_worker: AtomicUsize,
+    next_token: AtomicU64,
+}
+
+impl EpollWorkerPool {
+    pub fn new(num_threads: usize, seccomp_action: &SeccompAction) -> io::Result<Self> {
+        let mut workers = Vec::new();
+        let mut threads = Vec::new();
+        for i in 0..num_threads {
+            let worker = Arc::new(Worker::new()?);
+
+            let virtio_worker_seccomp_filter =
+                get_seccomp_filter(seccomp_action, Thread::VirtioWorkerPool)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+            let thread_worker = worker.clone();
+            let thread = thread::Builder::new()
+                .name(format!("virtio_worker{}", i))
+                .spawn(move || {
+                    if let Err(e) = SeccompFilter::apply(virtio_worker_seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                    } else if let Err(e) = thread_worker.run() {
+                        error!("Error running worker: {:?}", e);
+                    }
+                })?;
+
+            workers.push(worker);
+            threads.push(thread);
+        }
+
+        Ok(EpollWorkerPool {
+            workers,
+            threads,
+            next_worker: AtomicUsize::new(0),
+            next_token: AtomicU64::new(FIRST_HANDLER_TOKEN),
+        })
+    }
+
+    /// Register the handlers of a device, spreading them across the workers.
+    /// The device is paused once all its handlers have acknowledged the pause
+    /// event, at which point the `paused_sync` barrier is used once, hence it
+    /// must be shared by 2 threads, plus any dedicated thread of the device.
+    pub fn add_handlers(
+        &self,
+        handlers: Vec<(EpollHelper, Box<dyn EpollHelperHandler + Send>)>,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> io::Result<()> {
+        let group = Arc::new(PauseGroup {
+            paused,
+            paused_sync,
+            members: handlers.len(),
+            acked: Mutex::new(0),
+        });
+
+        for (helper, handler) in handlers {
+            let index = self.next_worker.fetch_add(1, Ordering::SeqCst) % self.workers.len();
+            let token = self.next_token.fetch_add(1, Ordering::SeqCst);
+            self.workers[index].add(
+                token,
+                PoolEntry {
+                    helper,
+                    handler,
+                    group: group.clone(),
+                    paused: false,
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Notify the workers that some devices have been resumed.
+    pub fn resume(&self) {
+        for worker in self.workers.iter() {
+            if let Err(e) = worker.resume_evt.write(1) {
+                error!("Failed to resume worker: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for EpollWorkerPool {
+    fn drop(&mut self) {
+        for worker in self.workers.iter() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = worker.kill_evt.write(1);
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epoll_helper::EPOLL_HELPER_EVENT_LAST;
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+
+    const TEST_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+    struct TestHandler {
+        evt: EventFd,
+        sender: Sender<u64>,
+    }
+
+    impl EpollHelperHandler for TestHandler {
+        fn handle_event(&mut self, _helper: &mut EpollHelper, _event: &epoll::Event) -> bool {
+            self.sender.send(self.evt.read().unwrap()).unwrap();
+            false
+        }
+    }
+
+    #[test]
+    fn test_worker_pool() {
+        let pool = EpollWorkerPool::new(1, &SeccompAction::Allow).unwrap();
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let pause_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let paused = Arc::new(AtomicBool::new(false));
+        let paused_sync = Arc::new(Barrier::new(2));
+        let (sender, receiver) = channel();
+
+        let mut helper = EpollHelper::new(&kill_evt, &pause_evt).unwrap();
+        helper.add_event(evt.as_raw_fd(), TEST_EVENT).unwrap();
+        let handler = TestHandler {
+            evt: evt.try_clone().unwrap(),
+            sender,
+        };
+        pool.add_handlers(
+            vec![(helper, Box::new(handler))],
+            paused.clone(),
+            paused_sync.clone(),
+        )
+        .unwrap();
+
+        evt.write(3).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(3));
+
+        // No event is processed while the device is paused.
+        paused.store(true, Ordering::SeqCst);
+        pause_evt.write(1).unwrap();
+        paused_sync.wait();
+        evt.write(5).unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        paused.store(false, Ordering::SeqCst);
+        pool.resume();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(5));
+
+        // The handler is dropped once killed.
+        kill_evt.write(1).unwrap();
+        assert!(receiver.recv().is_err());
+    }
+}
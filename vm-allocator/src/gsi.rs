@@ -13,6 +13,9 @@ pub enum Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+// GSIs beyond the size of the KVM routing table can't be routed.
+const MAX_GSI: u32 = 4096;
+
 /// GsiApic
 #[cfg(target_arch = "x86_64")]
 #[derive(Copy, Clone)]
@@ -74,11 +77,20 @@ impl GsiAllocator {
 
     /// Allocate a GSI
     pub fn allocate_gsi(&mut self) -> Result<u32> {
+        if self.next_gsi >= MAX_GSI {
+            return Err(Error::Overflow);
+        }
+
         let gsi = self.next_gsi;
-        self.next_gsi = self.next_gsi.checked_add(1).ok_or(Error::Overflow)?;
+        self.next_gsi += 1;
         Ok(gsi)
     }
 
+    /// Number of GSIs left to allocate
+    pub fn available_gsis(&self) -> u32 {
+        MAX_GSI.saturating_sub(self.next_gsi)
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Allocate an IRQ
     pub fn allocate_irq(&mut self) -> Result<u32> {
@@ -101,6 +101,11 @@ impl SystemAllocator {
         self.gsi_allocator.allocate_gsi().ok()
     }
 
+    /// Number of GSIs which can still be reserved.
+    pub fn available_gsis(&self) -> u32 {
+        self.gsi_allocator.available_gsis()
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Reserves a section of `size` bytes of IO address space.
     pub fn allocate_io_addresses(
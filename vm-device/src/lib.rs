@@ -11,8 +11,10 @@ use std::io;
 
 mod bus;
 pub mod interrupt;
+mod recorder;
 
 pub use self::bus::{Bus, BusDevice, Error as BusError};
+pub use self::recorder::{Record, RecordKind, Recorder};
 
 #[derive(Debug)]
 pub enum Error {
@@ -0,0 +1,107 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Type of nondeterministic input provided by a device to the guest.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    /// Network frame received from the host, including the virtio-net header.
+    NetRx,
+    /// Random bytes handed to the guest.
+    Rng,
+    /// Value read by the guest from a real time clock register.
+    Rtc,
+}
+
+/// Single recorded input, written as one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Time elapsed since the recording started, in nanoseconds.
+    pub timestamp_ns: u64,
+    /// Identifier of the device providing the input.
+    pub source: String,
+    pub kind: RecordKind,
+    /// Hexadecimal representation of the input.
+    pub data: String,
+}
+
+/// Records the nondeterministic inputs provided by the devices to the guest,
+/// so that the traffic seen by the guest can be analysed or replayed against
+/// a test backend afterwards.
+pub struct Recorder {
+    start: Instant,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Records are appended to the file, as the recorder is created again
+    /// each time the VM reboots and the inputs recorded before must be kept.
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+
+        Ok(Recorder {
+            start: Instant::now(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, source: &str, kind: RecordKind, data: &[u8]) -> io::Result<()> {
+        let record = Record {
+            timestamp_ns: self.start.elapsed().as_nanos() as u64,
+            source: source.to_owned(),
+            kind,
+            data: data.iter().map(|b| format!("{:02x}", b)).collect(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        // Each record is written at once, preventing records coming from
+        // different threads from being interleaved.
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_recorder() {
+        let path = std::env::temp_dir().join(format!("recorder-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recorder = Recorder::new(&path).unwrap();
+        recorder
+            .record("_rng0", RecordKind::Rng, &[0xde, 0xad])
+            .unwrap();
+        recorder.record("cmos", RecordKind::Rtc, &[0x59]).unwrap();
+        drop(recorder);
+
+        // Recording again, as on reboot, keeps the previous records.
+        let recorder = Recorder::new(&path).unwrap();
+        recorder.record("cmos", RecordKind::Rtc, &[0x00]).unwrap();
+
+        let records: Vec<Record> = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].source, "_rng0");
+        assert_eq!(records[0].kind, RecordKind::Rng);
+        assert_eq!(records[0].data, "dead");
+        assert_eq!(records[1].kind, RecordKind::Rtc);
+        assert_eq!(records[1].data, "59");
+        assert!(records[0].timestamp_ns <= records[1].timestamp_ns);
+        assert_eq!(records[2].data, "00");
+    }
+}
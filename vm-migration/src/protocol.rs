@@ -186,6 +186,7 @@ impl Response {
 impl AsBytes for Response {}
 
 #[repr(C)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MemoryRange {
     pub gpa: u64,
     pub length: u64,
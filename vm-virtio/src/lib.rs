@@ -18,6 +18,7 @@ extern crate serde_derive;
 use std::fmt;
 
 pub mod queue;
+pub mod trace;
 pub use queue::*;
 
 pub type VirtioIommuRemapping =
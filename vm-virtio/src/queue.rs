@@ -8,6 +8,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use crate::trace::QueueTracer;
 use crate::VirtioIommuRemapping;
 use std::cmp::min;
 use std::convert::TryInto;
@@ -331,6 +332,7 @@ pub struct AvailIter<'a, 'b> {
     queue_size: u16,
     next_avail: &'b mut Wrapping<u16>,
     iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+    tracer: Option<Arc<QueueTracer>>,
 }
 
 impl<'a, 'b> AvailIter<'a, 'b> {
@@ -344,6 +346,7 @@ impl<'a, 'b> AvailIter<'a, 'b> {
             queue_size: 0,
             next_avail: q_next_avail,
             iommu_mapping_cb: None,
+            tracer: None,
         }
     }
 }
@@ -380,8 +383,11 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
             desc_index,
             self.iommu_mapping_cb.clone(),
         );
-        if ret.is_some() {
+        if let Some(head) = &ret {
             *self.next_avail += Wrapping(1);
+            if let Some(tracer) = &self.tracer {
+                tracer.submitted(head);
+            }
         }
         ret
     }
@@ -424,6 +430,10 @@ pub struct Queue {
     #[serde(skip)]
     pub iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
 
+    /// Trace of the descriptor chains going through the queue
+    #[serde(skip)]
+    pub tracer: Option<Arc<QueueTracer>>,
+
     /// VIRTIO_F_RING_EVENT_IDX negotiated
     event_idx: bool,
 
@@ -445,6 +455,7 @@ impl Queue {
             next_avail: Wrapping(0),
             next_used: Wrapping(0),
             iommu_mapping_cb: None,
+            tracer: None,
             event_idx: false,
             signalled_used: None,
         }
@@ -581,6 +592,7 @@ impl Queue {
             queue_size,
             next_avail: &mut self.next_avail,
             iommu_mapping_cb: self.iommu_mapping_cb.clone(),
+            tracer: self.tracer.clone(),
         }
     }
 
@@ -659,6 +671,10 @@ impl Queue {
         mem.write_obj(self.next_used.0 as u16, used_ring.unchecked_add(2))
             .unwrap();
 
+        if let Some(tracer) = &self.tracer {
+            tracer.completed(desc_index, len, self.next_used.0);
+        }
+
         Some(self.next_used.0)
     }
 
@@ -0,0 +1,167 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trace of the descriptor chains going through a virtqueue, logged while it
+//! is enabled for the device, to diagnose a misbehaving guest driver without
+//! attaching a debugger.
+
+use crate::queue::{DescriptorChain, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Records logged per queue and per window, for a busy queue not to flood
+// the log.
+const MAX_RECORDS_PER_WINDOW: u32 = 100;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+struct RateLimit {
+    window_start: Instant,
+    records: u32,
+    dropped: u64,
+}
+
+pub struct QueueTracer {
+    name: String,
+    enabled: Arc<AtomicBool>,
+    rate_limit: Mutex<RateLimit>,
+}
+
+impl QueueTracer {
+    /// Tracer of the queue `name`, logging while `enabled` is set, which is
+    /// shared with the other queues of the device.
+    pub fn new(name: String, enabled: Arc<AtomicBool>) -> Self {
+        QueueTracer {
+            name,
+            enabled,
+            rate_limit: Mutex::new(RateLimit {
+                window_start: Instant::now(),
+                records: 0,
+                dropped: 0,
+            }),
+        }
+    }
+
+    // Whether a record can be logged, reporting the ones dropped during the
+    // previous window once a new one starts.
+    fn allow_record(&self) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let mut rate_limit = self.rate_limit.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(rate_limit.window_start) >= RATE_LIMIT_WINDOW {
+            if rate_limit.dropped > 0 {
+                info!(
+                    "{}: {} trace records dropped",
+                    self.name, rate_limit.dropped
+                );
+            }
+            rate_limit.window_start = now;
+            rate_limit.records = 0;
+            rate_limit.dropped = 0;
+        }
+
+        if rate_limit.records < MAX_RECORDS_PER_WINDOW {
+            rate_limit.records += 1;
+            true
+        } else {
+            rate_limit.dropped += 1;
+            false
+        }
+    }
+
+    /// Record the descriptor chain `head` the driver made available.
+    pub fn submitted(&self, head: &DescriptorChain) {
+        if !self.allow_record() {
+            return;
+        }
+
+        let descriptors: Vec<String> = head
+            .clone()
+            .into_iter()
+            .map(|desc| {
+                format!(
+                    "(index {}, addr {:#x}, len {}, flags {})",
+                    desc.index,
+                    desc.addr.0,
+                    desc.len,
+                    flags_name(desc.flags)
+                )
+            })
+            .collect();
+        info!(
+            "{}: available chain {}: {}",
+            self.name,
+            head.index,
+            descriptors.join(" ")
+        );
+    }
+
+    /// Record the descriptor chain `head_index` being handed back to the
+    /// driver with `len` bytes written, as the used ring entry `used_index`.
+    pub fn completed(&self, head_index: u16, len: u32, used_index: u16) {
+        if !self.allow_record() {
+            return;
+        }
+
+        info!(
+            "{}: used chain {}: len {}, used index {}",
+            self.name, head_index, len, used_index
+        );
+    }
+}
+
+fn flags_name(flags: u16) -> String {
+    let names: Vec<&str> = [
+        (VIRTQ_DESC_F_NEXT, "NEXT"),
+        (VIRTQ_DESC_F_WRITE, "WRITE"),
+        (VIRTQ_DESC_F_INDIRECT, "INDIRECT"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect();
+
+    if names.is_empty() {
+        "0".to_owned()
+    } else {
+        names.join("|")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_tracer_rate_limit() {
+        let enabled = Arc::new(AtomicBool::new(false));
+        let tracer = QueueTracer::new("_disk0 queue 0".to_owned(), enabled.clone());
+        assert!(!tracer.allow_record());
+
+        enabled.store(true, Ordering::Relaxed);
+        for _ in 0..MAX_RECORDS_PER_WINDOW {
+            assert!(tracer.allow_record());
+        }
+        assert!(!tracer.allow_record());
+        assert_eq!(tracer.rate_limit.lock().unwrap().dropped, 1);
+
+        // A new window starts.
+        tracer.rate_limit.lock().unwrap().window_start -= RATE_LIMIT_WINDOW;
+        assert!(tracer.allow_record());
+        assert_eq!(tracer.rate_limit.lock().unwrap().dropped, 0);
+    }
+
+    #[test]
+    fn test_flags_name() {
+        assert_eq!(flags_name(0), "0");
+        assert_eq!(
+            flags_name(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE),
+            "NEXT|WRITE"
+        );
+        assert_eq!(flags_name(VIRTQ_DESC_F_INDIRECT), "INDIRECT");
+    }
+}
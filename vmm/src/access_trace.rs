@@ -0,0 +1,181 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Trace of the last port I/O and MMIO accesses of each vCPU, handled by the
+//! VMM, which shows what the guest was doing right before it crashed or got
+//! stuck, for instance while initializing a driver.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Number of accesses kept for each vCPU.
+pub const ACCESS_TRACE_SIZE: usize = 64;
+
+thread_local! {
+    // The hypervisor calls into the VmmOps from the vCPU threads, without
+    // telling which vCPU is running, hence each vCPU thread records its id.
+    static CURRENT_VCPU: Cell<Option<u16>> = Cell::new(None);
+}
+
+/// Flag the current thread as running the vCPU `cpu_id`, so that the
+/// accesses it handles are recorded in the trace of this vCPU.
+pub fn set_current_vcpu(cpu_id: u16) {
+    CURRENT_VCPU.with(|c| c.set(Some(cpu_id)));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum AccessType {
+    PioRead,
+    PioWrite,
+    MmioRead,
+    MmioWrite,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Access {
+    pub access_type: AccessType,
+    pub address: u64,
+    pub size: usize,
+    /// Value read or written, as a little endian integer.
+    pub data: u64,
+    /// Time elapsed since the VM creation, in microseconds.
+    pub timestamp_us: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct VcpuAccessTrace {
+    pub vcpu: u16,
+    /// Accesses from the oldest to the most recent one.
+    pub accesses: Vec<Access>,
+}
+
+pub struct AccessTrace {
+    vcpus: Vec<Mutex<VecDeque<Access>>>,
+    timestamp: Instant,
+}
+
+impl AccessTrace {
+    pub fn new(max_vcpus: u16) -> Self {
+        AccessTrace {
+            vcpus: (0..max_vcpus)
+                .map(|_| Mutex::new(VecDeque::with_capacity(ACCESS_TRACE_SIZE)))
+                .collect(),
+            timestamp: Instant::now(),
+        }
+    }
+
+    /// Record an access from the current vCPU, dropping the oldest one once
+    /// the trace is full. Accesses from other threads are ignored.
+    pub fn record(&self, access_type: AccessType, address: u64, data: &[u8]) {
+        let vcpu = match CURRENT_VCPU.with(|c| c.get()) {
+            Some(vcpu) => vcpu,
+            None => return,
+        };
+        let trace = match self.vcpus.get(usize::from(vcpu)) {
+            Some(trace) => trace,
+            None => return,
+        };
+
+        let mut bytes = [0u8; 8];
+        let size = std::cmp::min(data.len(), bytes.len());
+        bytes[..size].copy_from_slice(&data[..size]);
+        let access = Access {
+            access_type,
+            address,
+            size: data.len(),
+            data: u64::from_le_bytes(bytes),
+            timestamp_us: self.timestamp.elapsed().as_micros() as u64,
+        };
+
+        let mut trace = trace.lock().unwrap();
+        if trace.len() == ACCESS_TRACE_SIZE {
+            trace.pop_front();
+        }
+        trace.push_back(access);
+    }
+
+    /// The accesses of the vCPU `cpu_id`, from the oldest one.
+    pub fn vcpu_trace(&self, cpu_id: u16) -> VcpuAccessTrace {
+        VcpuAccessTrace {
+            vcpu: cpu_id,
+            accesses: self
+                .vcpus
+                .get(usize::from(cpu_id))
+                .map(|trace| trace.lock().unwrap().iter().cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The accesses of each vCPU, skipping the vCPUs without any access.
+    pub fn traces(&self) -> Vec<VcpuAccessTrace> {
+        (0..self.vcpus.len() as u16)
+            .map(|cpu_id| self.vcpu_trace(cpu_id))
+            .filter(|t| !t.accesses.is_empty())
+            .collect()
+    }
+
+    /// Log the accesses of the vCPU `cpu_id`, typically after it crashed.
+    pub fn log_vcpu_trace(&self, cpu_id: u16) {
+        let trace = self.vcpu_trace(cpu_id);
+        error!(
+            "Last {} I/O accesses of vCPU {}:",
+            trace.accesses.len(),
+            cpu_id
+        );
+        for a in trace.accesses.iter() {
+            error!(
+                "[{}.{:>06}] {:?} 0x{:x} size {} data 0x{:x}",
+                a.timestamp_us / 1_000_000,
+                a.timestamp_us % 1_000_000,
+                a.access_type,
+                a.address,
+                a.size,
+                a.data
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_trace() {
+        let trace = AccessTrace::new(2);
+
+        // Not recorded from a thread which is not running a vCPU.
+        trace.record(AccessType::PioWrite, 0x3f8, &[0x41]);
+        assert!(trace.traces().is_empty());
+
+        set_current_vcpu(1);
+        for i in 0..(ACCESS_TRACE_SIZE as u64 + 2) {
+            trace.record(
+                AccessType::MmioWrite,
+                0xd000_0000 + i,
+                &i.to_le_bytes()[..4],
+            );
+        }
+        trace.record(AccessType::PioRead, 0x64, &[0x1c]);
+
+        let traces = trace.traces();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].vcpu, 1);
+
+        // Only the most recent accesses are kept.
+        let accesses = &traces[0].accesses;
+        assert_eq!(accesses.len(), ACCESS_TRACE_SIZE);
+        assert_eq!(accesses[0].address, 0xd000_0003);
+        assert_eq!(accesses[0].size, 4);
+        assert_eq!(accesses[0].data, 3);
+        let last = accesses.last().unwrap();
+        assert_eq!(last.access_type, AccessType::PioRead);
+        assert_eq!((last.address, last.size, last.data), (0x64, 1, 0x1c));
+
+        assert!(trace.vcpu_trace(0).accesses.is_empty());
+    }
+}
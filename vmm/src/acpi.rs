@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 use crate::cpu::CpuManager;
-use crate::device_manager::DeviceManager;
+use crate::device_manager::{DeviceManager, NvdimmRegion};
 use crate::memory_manager::MemoryManager;
 use crate::vm::NumaNodes;
 use acpi_tables::{
@@ -17,6 +17,27 @@ use std::sync::{Arc, Mutex};
 use vm_memory::GuestRegionMmap;
 use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap, GuestMemoryRegion};
 
+// Size of the FACS, which must be aligned on a 64 bytes boundary.
+const FACS_SIZE: u64 = 64;
+
+#[repr(packed)]
+#[derive(Default)]
+struct Facs {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub hardware_signature: u32,
+    pub firmware_waking_vector: u32,
+    pub global_lock: u32,
+    pub flags: u32,
+    pub x_firmware_waking_vector: u64,
+    pub version: u8,
+    _reserved1: [u8; 3],
+    pub ospm_flags: u32,
+    _reserved2: [u8; 24],
+}
+
+unsafe impl ByteValued for Facs {}
+
 #[repr(packed)]
 #[derive(Default)]
 struct PCIRangeEntry {
@@ -56,6 +77,132 @@ struct ProcessorLocalX2ApicAffinity {
     _reserved2: u32,
 }
 
+#[repr(packed)]
+#[derive(Default)]
+struct NfitSpaRange {
+    pub type_: u16,
+    pub length: u16,
+    pub spa_range_index: u16,
+    pub flags: u16,
+    _reserved: u32,
+    pub proximity_domain: u32,
+    pub range_type_guid: [u8; 16],
+    pub base: u64,
+    pub size: u64,
+    pub memory_mapping_attribute: u64,
+}
+
+#[repr(packed)]
+#[derive(Default)]
+struct NfitMemoryDeviceMap {
+    pub type_: u16,
+    pub length: u16,
+    pub device_handle: u32,
+    pub physical_id: u16,
+    pub region_id: u16,
+    pub spa_range_index: u16,
+    pub control_region_index: u16,
+    pub region_size: u64,
+    pub region_offset: u64,
+    pub region_dpa: u64,
+    pub interleave_index: u16,
+    pub interleave_ways: u16,
+    pub flags: u16,
+    _reserved: u16,
+}
+
+#[repr(packed)]
+#[derive(Default)]
+struct NfitControlRegion {
+    pub type_: u16,
+    pub length: u16,
+    pub control_region_index: u16,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_device_id: u16,
+    pub subsystem_revision_id: u16,
+    pub valid_fields: u8,
+    pub manufacturing_location: u8,
+    pub manufacturing_date: u16,
+    _reserved1: u16,
+    pub serial_number: u32,
+    pub region_format_interface_code: u16,
+    pub block_control_windows: u16,
+    pub block_control_window_size: u64,
+    pub command_register_offset: u64,
+    pub command_register_size: u64,
+    pub status_register_offset: u64,
+    pub status_register_size: u64,
+    pub flags: u16,
+    _reserved2: [u8; 6],
+}
+
+// Persistent memory region GUID 66F0D379-B4F3-4074-AC43-0D3318B78CDB, with
+// the first three fields stored little endian.
+const NFIT_PM_REGION_GUID: [u8; 16] = [
+    0x79, 0xd3, 0xf0, 0x66, 0xf3, 0xb4, 0x74, 0x40, 0xac, 0x43, 0x0d, 0x33, 0x18, 0xb7, 0x8c, 0xdb,
+];
+// EFI_MEMORY_WB | EFI_MEMORY_NV
+const NFIT_MEMORY_MAPPING_ATTRIBUTE: u64 = 0x8 | 0x8000;
+// Byte addressable persistent memory, without energy backed guarantee
+const NFIT_FORMAT_INTERFACE_CODE: u16 = 0x0301;
+
+fn create_nfit_table(nvdimm_regions: &[NvdimmRegion]) -> SDT {
+    let mut nfit = SDT::new(*b"NFIT", 36, 1, *b"CLOUDH", *b"CHNFIT  ", 1);
+    // NFIT reserved 4 bytes
+    nfit.append(0u32);
+
+    // Check the structures are the right size as expected by the ACPI
+    // specification.
+    assert_eq!(std::mem::size_of::<NfitSpaRange>(), 56);
+    assert_eq!(std::mem::size_of::<NfitMemoryDeviceMap>(), 48);
+    assert_eq!(std::mem::size_of::<NfitControlRegion>(), 80);
+
+    // Each region is exposed as a distinct NVDIMM, indexes starting from 1.
+    for (i, region) in nvdimm_regions.iter().enumerate() {
+        let index = i as u16 + 1;
+
+        nfit.append(NfitSpaRange {
+            type_: 0,
+            length: 56,
+            spa_range_index: index,
+            range_type_guid: NFIT_PM_REGION_GUID,
+            base: region.base.raw_value(),
+            size: region.size,
+            memory_mapping_attribute: NFIT_MEMORY_MAPPING_ATTRIBUTE,
+            ..Default::default()
+        });
+
+        nfit.append(NfitMemoryDeviceMap {
+            type_: 1,
+            length: 48,
+            device_handle: region.handle,
+            spa_range_index: index,
+            control_region_index: index,
+            region_size: region.size,
+            interleave_ways: 1,
+            ..Default::default()
+        });
+
+        nfit.append(NfitControlRegion {
+            type_: 4,
+            length: 80,
+            control_region_index: index,
+            vendor_id: 0x8086,
+            device_id: 0x1,
+            revision_id: 0x1,
+            serial_number: region.handle,
+            region_format_interface_code: NFIT_FORMAT_INTERFACE_CODE,
+            ..Default::default()
+        });
+    }
+
+    nfit.update_checksum();
+    nfit
+}
+
 bitflags! {
     pub struct MemAffinityFlags: u32 {
         const NOFLAGS = 0;
@@ -154,8 +301,34 @@ pub fn create_acpi_tables(
 
     facp.write(268, b"CLOUDHYP"); // Hypervisor Vendor Identity
 
-    facp.update_checksum();
     let facp_offset = dsdt_offset.checked_add(dsdt.len() as u64).unwrap();
+
+    // FACS, holding the waking vector the guest resumes from after
+    // suspending to RAM.
+    let facs_offset = GuestAddress(
+        facp_offset
+            .checked_add(facp.len() as u64)
+            .unwrap()
+            .0
+            .wrapping_add(FACS_SIZE - 1)
+            & !(FACS_SIZE - 1),
+    );
+    assert_eq!(std::mem::size_of::<Facs>() as u64, FACS_SIZE);
+    guest_mem
+        .write_obj(
+            Facs {
+                signature: *b"FACS",
+                length: FACS_SIZE as u32,
+                version: 2,
+                ..Default::default()
+            },
+            facs_offset,
+        )
+        .expect("Error writing FACS table");
+    // FIRMWARE_CTRL must be left to 0 when X_FIRMWARE_CTRL is set.
+    facp.write(132, facs_offset.0); // X_FIRMWARE_CTRL
+
+    facp.update_checksum();
     guest_mem
         .write_slice(facp.as_slice(), facp_offset)
         .expect("Error writing FACP table");
@@ -163,7 +336,7 @@ pub fn create_acpi_tables(
 
     // MADT
     let madt = cpu_manager.lock().unwrap().create_madt();
-    let madt_offset = facp_offset.checked_add(facp.len() as u64).unwrap();
+    let madt_offset = facs_offset.checked_add(FACS_SIZE).unwrap();
     guest_mem
         .write_slice(madt.as_slice(), madt_offset)
         .expect("Error writing MADT table");
@@ -224,7 +397,7 @@ pub fn create_acpi_tables(
             }
 
             for cpu in node.cpus() {
-                let x2apic_id = *cpu as u32;
+                let x2apic_id = u32::from(*cpu);
 
                 // Flags
                 // - Enabled = 1 (bit 0)
@@ -279,6 +452,25 @@ pub fn create_acpi_tables(
         (slit.len(), slit_offset)
     };
 
+    // NFIT
+    // Only created if some persistent memory is exposed as NVDIMM.
+    let (prev_tbl_len, prev_tbl_off) = {
+        let device_manager = device_manager.lock().unwrap();
+        let nvdimm_regions = device_manager.nvdimm_regions();
+        if nvdimm_regions.is_empty() {
+            (prev_tbl_len, prev_tbl_off)
+        } else {
+            let nfit = create_nfit_table(nvdimm_regions);
+            let nfit_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+            guest_mem
+                .write_slice(nfit.as_slice(), nfit_offset)
+                .expect("Error writing NFIT table");
+            tables.push(nfit_offset.0);
+
+            (nfit.len(), nfit_offset)
+        }
+    };
+
     // XSDT
     let mut xsdt = SDT::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
     for table in tables {
@@ -299,3 +491,29 @@ pub fn create_acpi_tables(
 
     rsdp_offset
 }
+
+/// Real mode address the guest expects to resume from after suspending to
+/// RAM, as found in the FACS. The tables are looked up from the RSDP, as
+/// they can come from a snapshot.
+pub fn waking_vector(guest_mem: &GuestMemoryMmap) -> Option<u32> {
+    // XsdtAddress
+    let xsdt: u64 = guest_mem
+        .read_obj(layout::RSDP_POINTER.unchecked_add(24))
+        .ok()?;
+    let xsdt_len: u32 = guest_mem.read_obj(GuestAddress(xsdt + 4)).ok()?;
+
+    for entry in (xsdt + 36..xsdt + u64::from(xsdt_len)).step_by(8) {
+        let table: u64 = guest_mem.read_obj(GuestAddress(entry)).ok()?;
+        let signature: [u8; 4] = guest_mem.read_obj(GuestAddress(table)).ok()?;
+        if &signature != b"FACP" {
+            continue;
+        }
+
+        // X_FIRMWARE_CTRL
+        let facs: u64 = guest_mem.read_obj(GuestAddress(table + 132)).ok()?;
+        let facs: Facs = guest_mem.read_obj(GuestAddress(facs)).ok()?;
+        return Some(facs.firmware_waking_vector).filter(|vector| *vector != 0);
+    }
+
+    None
+}
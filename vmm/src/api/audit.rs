@@ -0,0 +1,261 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Audit log of the state-changing API requests, recording each of them with
+//! its parameters, sender and result to an append-only file, one JSON object
+//! a line, for the deployments which have to account for the changes made to
+//! a VM.
+
+use micro_http::{Body, Request, Response, StatusCode};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    // The requests are only audited once the audit log is opened.
+    static ref AUDIT_LOG: Mutex<Option<File>> = Mutex::new(None);
+}
+
+// Parameters which are, or point to, secrets, hence left out of the log.
+const REDACTED_PARAMETERS: &[&str] = &[
+    "key_file",
+    "user_data",
+    "secret",
+    "password",
+    "passphrase",
+    "token",
+];
+const REDACTED: &str = "<redacted>";
+
+fn is_redacted(name: &str) -> bool {
+    REDACTED_PARAMETERS
+        .iter()
+        .any(|redacted| name.contains(redacted))
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                if is_redacted(name) {
+                    *value = Value::String(REDACTED.to_owned());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Credentials of the process which sent a request, as given by the kernel
+/// for the API socket connection.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Peer {
+    pid: i32,
+    uid: u32,
+    gid: u32,
+}
+
+impl Peer {
+    /// The credentials of the process connected to the UNIX socket `fd`.
+    pub fn from_fd(fd: RawFd) -> Option<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // Safe because the kernel only writes up to len bytes to the ucred,
+        // and the call fails on an fd which isn't a UNIX socket.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+
+        Some(Peer {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct AuditRecord {
+    /// Time of the request, in milliseconds since the Unix epoch.
+    timestamp: u64,
+    endpoint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<Peer>,
+    parameters: Value,
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AuditRecord {
+    fn new(endpoint: &str, peer: Option<Peer>, body: Option<&Body>, response: &Response) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default();
+        // A body which isn't JSON, hence rejected, is still recorded as is,
+        // unless it may hold a secret.
+        let parameters = body.map_or(Value::Null, |body| {
+            match serde_json::from_slice(body.raw()) {
+                Ok(mut parameters) => {
+                    redact(&mut parameters);
+                    parameters
+                }
+                Err(_) => {
+                    let body = String::from_utf8_lossy(body.raw());
+                    if is_redacted(&body) {
+                        Value::String(REDACTED.to_owned())
+                    } else {
+                        Value::String(body.into_owned())
+                    }
+                }
+            }
+        });
+        let error = match response.status() {
+            StatusCode::OK | StatusCode::NoContent => None,
+            _ => response
+                .body()
+                .map(|body| String::from_utf8_lossy(body.raw()).into_owned()),
+        };
+
+        AuditRecord {
+            timestamp,
+            endpoint: endpoint.to_owned(),
+            peer,
+            parameters,
+            status: format!("{:?}", response.status()),
+            error,
+        }
+    }
+}
+
+/// Open the audit log `path`, created if needed and only readable by the
+/// user running the VMM, the records being appended to the existing ones.
+pub fn open_audit_log(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?;
+    *AUDIT_LOG.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Record the state-changing `request` sent by `peer` along with its
+/// `response`, each record being flushed to the disk before the response is
+/// sent back.
+pub fn record(request: &Request, peer: Option<Peer>, response: &Response) {
+    if let Some(file) = AUDIT_LOG.lock().unwrap().as_mut() {
+        let record = AuditRecord::new(
+            request.uri().get_abs_path(),
+            peer,
+            request.body.as_ref(),
+            response,
+        );
+        let result = serde_json::to_vec(&record)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                file.write_all(&line)?;
+                file.sync_data()
+            });
+        if let Err(e) = result {
+            error!("Error writing to the audit log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use micro_http::Version;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_audit_record() {
+        let body = Body::new("{\"path\":\"/tmp/disk.img\"}");
+        let response = Response::new(Version::Http11, StatusCode::NoContent);
+        let record = AuditRecord::new("/api/v1/vm.add-disk", None, Some(&body), &response);
+        assert_eq!(record.endpoint, "/api/v1/vm.add-disk");
+        assert_eq!(record.parameters["path"], "/tmp/disk.img");
+        assert_eq!(record.status, "NoContent");
+        assert_eq!(record.error, None);
+
+        let mut response = Response::new(Version::Http11, StatusCode::InternalServerError);
+        response.set_body(Body::new("VmPause(VmNotRunning)"));
+        let peer = Some(Peer {
+            pid: 42,
+            uid: 1000,
+            gid: 1000,
+        });
+        let record = AuditRecord::new("/api/v1/vm.pause", peer, None, &response);
+        assert_eq!(record.parameters, Value::Null);
+        assert_eq!(record.status, "InternalServerError");
+        assert_eq!(record.error, Some("VmPause(VmNotRunning)".to_owned()));
+
+        let line = serde_json::to_string(&record).unwrap();
+        assert!(line.starts_with("{\"timestamp\":"));
+        assert!(line.contains("\"peer\":{\"pid\":42,\"uid\":1000,\"gid\":1000}"));
+        assert!(line.ends_with("\"error\":\"VmPause(VmNotRunning)\"}"));
+
+        // Not JSON.
+        let body = Body::new("path=/tmp/disk.img");
+        let response = Response::new(Version::Http11, StatusCode::BadRequest);
+        let record = AuditRecord::new("/api/v1/vm.add-disk", None, Some(&body), &response);
+        assert_eq!(record.parameters, "path=/tmp/disk.img");
+    }
+
+    #[test]
+    fn test_audit_record_redacted() {
+        let body = Body::new(
+            "{\"disks\":[{\"path\":\"/tmp/disk.img\",\"key_file\":\"/tmp/key\"}],\
+             \"metadata\":{\"user_data\":\"/tmp/user-data\"},\"client_secret\":\"s\"}",
+        );
+        let response = Response::new(Version::Http11, StatusCode::NoContent);
+        let record = AuditRecord::new("/api/v1/vm.create", None, Some(&body), &response);
+        assert_eq!(record.parameters["disks"][0]["path"], "/tmp/disk.img");
+        assert_eq!(record.parameters["disks"][0]["key_file"], REDACTED);
+        assert_eq!(record.parameters["metadata"]["user_data"], REDACTED);
+        assert_eq!(record.parameters["client_secret"], REDACTED);
+
+        // Not JSON.
+        let body = Body::new("path=/tmp/disk.img,key_file=/tmp/key");
+        let response = Response::new(Version::Http11, StatusCode::BadRequest);
+        let record = AuditRecord::new("/api/v1/vm.add-disk", None, Some(&body), &response);
+        assert_eq!(record.parameters, REDACTED);
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let (stream, _peer_stream) = UnixStream::pair().unwrap();
+        let peer = Peer::from_fd(stream.as_raw_fd()).unwrap();
+        // Safe because getuid() and getpid() can't fail.
+        assert_eq!(peer.uid, unsafe { libc::getuid() });
+        assert_eq!(peer.pid, unsafe { libc::getpid() });
+    }
+}
@@ -3,18 +3,29 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::api::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdown};
-use crate::api::{ApiError, ApiRequest, VmAction};
+use crate::api::http_endpoint::{
+    vmm_events_wait, VmActionHandler, VmCreate, VmInfo, VmmEvents, VmmPing, VmmResourceUsage,
+    VmmShutdown,
+};
+use crate::api::{audit, ApiError, ApiRequest, VmAction};
+use crate::event_monitor;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{Error, Result};
-use micro_http::{Body, HttpServer, MediaType, Method, Request, Response, StatusCode, Version};
+use micro_http::{
+    Body, HttpServer, MediaType, Method, Request, Response, ServerRequest, ServerResponse,
+    StatusCode, Version,
+};
 use seccomp::{SeccompAction, SeccompFilter};
 use serde_json::Error as SerdeError;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 use vmm_sys_util::eventfd::EventFd;
 
 /// Errors associated with VMM management
@@ -50,6 +61,12 @@ pub enum HttpError {
     /// Could not pause the VM
     VmResume(ApiError),
 
+    /// Could not wake the VM up
+    VmWakeup(ApiError),
+
+    /// Could not press the VM power button
+    VmPowerButton(ApiError),
+
     /// Could not shut a VM down
     VmShutdown(ApiError),
 
@@ -83,6 +100,9 @@ pub enum HttpError {
     /// Could not handle VMM ping
     VmmPing(ApiError),
 
+    /// Could not get the VMM resource usage
+    VmmResourceUsage(ApiError),
+
     /// Could not add a disk to a VM
     VmAddDisk(ApiError),
 
@@ -98,9 +118,33 @@ pub enum HttpError {
     /// Could not add a vsock device to a VM
     VmAddVsock(ApiError),
 
+    /// Could not set the link status of a network device
+    VmSetNetLink(ApiError),
+
+    /// Could not toggle the virtqueue trace of a device
+    VmTraceQueues(ApiError),
+
+    /// Could not inject a machine check into a VM
+    VmInjectMce(ApiError),
+
+    /// Could not start a hotplug job
+    VmHotplug(ApiError),
+
+    /// Could not get the status of a hotplug job
+    VmHotplugStatus(ApiError),
+
+    /// Could not cancel a hotplug job
+    VmCancelHotplug(ApiError),
+
     /// Could not get counters from VM
     VmCounters(ApiError),
 
+    /// Could not get the memory layout of the VM
+    VmMemoryLayout(ApiError),
+
+    /// Could not get the access trace of the VM
+    VmAccessTrace(ApiError),
+
     /// Error setting up migration received
     VmReceiveMigration(ApiError),
 
@@ -116,6 +160,10 @@ impl From<serde_json::Error> for HttpError {
 
 const HTTP_ROOT: &str = "/api/v1";
 
+// Number of threads processing the HTTP requests, for a request waiting on
+// the VMM not to hold up the others.
+const HTTP_WORKERS: usize = 4;
+
 pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     let mut response = Response::new(Version::Http11, status);
     response.set_body(Body::new(format!("{:?}", error)));
@@ -198,6 +246,7 @@ lazy_static! {
             routes: HashMap::new(),
         };
 
+        r.routes.insert(endpoint!("/vm.access-trace"), Box::new(VmActionHandler::new(VmAction::AccessTrace)));
         r.routes.insert(endpoint!("/vm.add-device"), Box::new(VmActionHandler::new(VmAction::AddDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-disk"), Box::new(VmActionHandler::new(VmAction::AddDisk(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-fs"), Box::new(VmActionHandler::new(VmAction::AddFs(Arc::default()))));
@@ -205,11 +254,17 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.add-pmem"), Box::new(VmActionHandler::new(VmAction::AddPmem(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-vsock"), Box::new(VmActionHandler::new(VmAction::AddVsock(Arc::default()))));
         r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
+        r.routes.insert(endpoint!("/vm.cancel-hotplug"), Box::new(VmActionHandler::new(VmAction::CancelHotplug(Arc::default()))));
         r.routes.insert(endpoint!("/vm.counters"), Box::new(VmActionHandler::new(VmAction::Counters)));
         r.routes.insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
         r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
+        r.routes.insert(endpoint!("/vm.hotplug"), Box::new(VmActionHandler::new(VmAction::Hotplug(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.hotplug-status"), Box::new(VmActionHandler::new(VmAction::HotplugStatus(Arc::default()))));
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
+        r.routes.insert(endpoint!("/vm.inject-mce"), Box::new(VmActionHandler::new(VmAction::InjectMce(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.memory-layout"), Box::new(VmActionHandler::new(VmAction::MemoryLayout)));
         r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
+        r.routes.insert(endpoint!("/vm.power-button"), Box::new(VmActionHandler::new(VmAction::PowerButton)));
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
         r.routes.insert(endpoint!("/vm.receive-migration"), Box::new(VmActionHandler::new(VmAction::ReceiveMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmActionHandler::new(VmAction::RemoveDevice(Arc::default()))));
@@ -217,10 +272,15 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.resize-zone"), Box::new(VmActionHandler::new(VmAction::ResizeZone(Arc::default()))));
         r.routes.insert(endpoint!("/vm.restore"), Box::new(VmActionHandler::new(VmAction::Restore(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
+        r.routes.insert(endpoint!("/vm.set-net-link"), Box::new(VmActionHandler::new(VmAction::SetNetLink(Arc::default()))));
         r.routes.insert(endpoint!("/vm.send-migration"), Box::new(VmActionHandler::new(VmAction::SendMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
         r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmActionHandler::new(VmAction::Snapshot(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.trace-queues"), Box::new(VmActionHandler::new(VmAction::TraceQueues(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.wakeup"), Box::new(VmActionHandler::new(VmAction::Wakeup)));
+        r.routes.insert(endpoint!("/vmm.events"), Box::new(VmmEvents {}));
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
+        r.routes.insert(endpoint!("/vmm.resource-usage"), Box::new(VmmResourceUsage {}));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
 
         r
@@ -249,6 +309,184 @@ fn handle_http_request(
     response
 }
 
+fn start_http_worker(
+    id: usize,
+    request_receiver: Arc<Mutex<Receiver<(ServerRequest, Option<audit::Peer>)>>>,
+    response_sender: Sender<ServerResponse>,
+    response_notifier: EventFd,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+) -> Result<()> {
+    // Retrieve seccomp filter for API thread
+    let api_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::Api).map_err(Error::CreateSeccompFilter)?;
+
+    thread::Builder::new()
+        .name(format!("http-worker{}", id))
+        .spawn(move || {
+            // Apply seccomp filter for API thread.
+            if let Err(e) = SeccompFilter::apply(api_seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            loop {
+                // The receiver is only locked while waiting for a request.
+                let server_request = request_receiver.lock().unwrap().recv();
+                let (server_request, peer) = match server_request {
+                    Ok(server_request) => server_request,
+                    Err(_) => return,
+                };
+
+                let response = server_request.process(|request| {
+                    let response = handle_http_request(request, &api_notifier, &api_sender);
+                    // Only the PUT requests change the state of the VMM.
+                    if request.method() == Method::Put {
+                        audit::record(request, peer, &response);
+                    }
+                    response
+                });
+                if response_sender.send(response).is_err() {
+                    return;
+                }
+                if let Err(e) = response_notifier.write(1) {
+                    error!("HTTP worker error on waking the server up: {}", e);
+                }
+            }
+        })
+        .map_err(Error::HttpThreadSpawn)?;
+
+    Ok(())
+}
+
+// A `vmm.events` request waiting for an event after `after`.
+struct EventsWaiter {
+    request: ServerRequest,
+    after: u64,
+    deadline: Instant,
+}
+
+// The `vmm.events` long-polls are held by a single thread until an event is
+// recorded or their timeout expires, rather than by the workers which would
+// be all taken by a few idle clients.
+#[allow(clippy::too_many_arguments)]
+fn start_http_events_thread(
+    waiter_receiver: Receiver<ServerRequest>,
+    waiter_notifier: EventFd,
+    response_sender: Sender<ServerResponse>,
+    response_notifier: EventFd,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+) -> Result<()> {
+    // Woken up by the new long-polls as well as by the new events.
+    event_monitor::add_notifier(waiter_notifier.try_clone().map_err(Error::EventFdClone)?);
+
+    let epoll_fd = epoll::create(true).map_err(Error::Epoll)?;
+    // Safe because the epoll fd was just created and is owned by nothing
+    // else. Using 'File' enforces closing it.
+    let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        waiter_notifier.as_raw_fd(),
+        epoll::Event::new(epoll::Events::EPOLLIN, 0),
+    )
+    .map_err(Error::Epoll)?;
+
+    // Retrieve seccomp filter for API thread
+    let api_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::Api).map_err(Error::CreateSeccompFilter)?;
+
+    thread::Builder::new()
+        .name("http-events".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for API thread.
+            if let Err(e) = SeccompFilter::apply(api_seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 1];
+            let mut waiters: Vec<EventsWaiter> = Vec::new();
+            loop {
+                // The notifier is read before looking for the new long-polls
+                // and events, not to miss the ones coming in between.
+                let _ = waiter_notifier.read();
+
+                loop {
+                    match waiter_receiver.try_recv() {
+                        Ok(request) => {
+                            // Checked by the server before handing it over.
+                            if let Some((after, timeout)) = vmm_events_wait(&request.request) {
+                                waiters.push(EventsWaiter {
+                                    request,
+                                    after,
+                                    deadline: Instant::now() + timeout,
+                                });
+                            }
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                }
+
+                let last_event_id = event_monitor::last_event_id();
+                let now = Instant::now();
+                let (ready, waiting): (Vec<_>, Vec<_>) = waiters
+                    .drain(..)
+                    .partition(|w| w.after < last_event_id || w.deadline <= now);
+                waiters = waiting;
+
+                for waiter in ready {
+                    let response = waiter.request.process(|request| {
+                        handle_http_request(request, &api_notifier, &api_sender)
+                    });
+                    if response_sender.send(response).is_err() {
+                        return;
+                    }
+                    if let Err(e) = response_notifier.write(1) {
+                        error!("HTTP events thread error on waking the server up: {}", e);
+                    }
+                }
+
+                // Waiting until the next deadline, rounded up not to wake up
+                // right before it.
+                let timeout = match waiters.iter().map(|w| w.deadline).min() {
+                    Some(deadline) => {
+                        let timeout = deadline.saturating_duration_since(now);
+                        (timeout.as_micros() as i32 + 999) / 1000
+                    }
+                    None => -1,
+                };
+                if let Err(e) = epoll::wait(epoll_file.as_raw_fd(), timeout, &mut events[..]) {
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        error!("HTTP events thread error on epoll: {}", e);
+                        return;
+                    }
+                }
+            }
+        })
+        .map_err(Error::HttpThreadSpawn)?;
+
+    Ok(())
+}
+
+// The sender of a state-changing request, for the audit log. The requests
+// are identified by the fd of their connection, which stays open until the
+// response is sent back.
+fn request_peer(server_request: &ServerRequest) -> Option<audit::Peer> {
+    if server_request.request.method() == Method::Put {
+        audit::Peer::from_fd(server_request.id() as RawFd)
+    } else {
+        None
+    }
+}
+
+const HTTP_SERVER_EVENT: u64 = 0;
+const HTTP_RESPONSE_EVENT: u64 = 1;
+
 pub fn start_http_thread(
     path: &str,
     api_notifier: EventFd,
@@ -258,6 +496,37 @@ pub fn start_http_thread(
     std::fs::remove_file(path).unwrap_or_default();
     let socket_path = PathBuf::from(path);
 
+    // The requests are processed by a pool of workers, the server thread
+    // only receiving the requests and sending the responses back once woken
+    // up through the response notifier.
+    let (request_sender, request_receiver) = channel();
+    let request_receiver = Arc::new(Mutex::new(request_receiver));
+    let (response_sender, response_receiver) = channel();
+    let response_notifier = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+    for id in 0..HTTP_WORKERS {
+        start_http_worker(
+            id,
+            request_receiver.clone(),
+            response_sender.clone(),
+            response_notifier.try_clone().map_err(Error::EventFdClone)?,
+            api_notifier.try_clone().map_err(Error::EventFdClone)?,
+            api_sender.clone(),
+            seccomp_action,
+        )?;
+    }
+
+    let (waiter_sender, waiter_receiver) = channel();
+    let waiter_notifier = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+    start_http_events_thread(
+        waiter_receiver,
+        waiter_notifier.try_clone().map_err(Error::EventFdClone)?,
+        response_sender,
+        response_notifier.try_clone().map_err(Error::EventFdClone)?,
+        api_notifier,
+        api_sender,
+        seccomp_action,
+    )?;
+
     // Retrieve seccomp filter for API thread
     let api_seccomp_filter =
         get_seccomp_filter(seccomp_action, Thread::Api).map_err(Error::CreateSeccompFilter)?;
@@ -270,26 +539,70 @@ pub fn start_http_thread(
 
             let mut server = HttpServer::new(socket_path).unwrap();
             server.start_server().unwrap();
+
+            // The server only returns from requests() on a new request, hence
+            // its epoll is nested into one woken up by the responses too.
+            let epoll_fd = epoll::create(true).map_err(Error::Epoll)?;
+            // Safe because the epoll fd was just created and is owned by
+            // nothing else. Using 'File' enforces closing it.
+            let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+            for (fd, token) in &[
+                (server.epoll().as_raw_fd(), HTTP_SERVER_EVENT),
+                (response_notifier.as_raw_fd(), HTTP_RESPONSE_EVENT),
+            ] {
+                epoll::ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    *fd,
+                    epoll::Event::new(epoll::Events::EPOLLIN, *token),
+                )
+                .map_err(Error::Epoll)?;
+            }
+
+            let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
             loop {
-                match server.requests() {
-                    Ok(request_vec) => {
-                        for server_request in request_vec {
-                            server
-                                .respond(server_request.process(|request| {
-                                    handle_http_request(request, &api_notifier, &api_sender)
-                                }))
-                                .or_else(|e| {
-                                    error!("HTTP server error on response: {}", e);
-                                    Ok(())
-                                })?;
+                let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+                    Ok(num_events) => num_events,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Error::Epoll(e)),
+                };
+
+                for event in events.iter().take(num_events) {
+                    match event.data {
+                        HTTP_SERVER_EVENT => match server.requests() {
+                            Ok(request_vec) => {
+                                for server_request in request_vec {
+                                    if vmm_events_wait(&server_request.request).is_none() {
+                                        let peer = request_peer(&server_request);
+                                        if request_sender.send((server_request, peer)).is_err() {
+                                            error!("HTTP server error on dispatching request: no worker left");
+                                        }
+                                    } else if waiter_sender.send(server_request).is_err() {
+                                        error!("HTTP server error on dispatching request: no events thread");
+                                    } else if let Err(e) = waiter_notifier.write(1) {
+                                        error!("HTTP server error on waking the events thread up: {}", e);
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                error!(
+                                    "HTTP server error on retrieving incoming request. Error: {}",
+                                    e
+                                );
+                            }
+                        },
+                        HTTP_RESPONSE_EVENT => {
+                            response_notifier.read().map_err(Error::EventFdRead)?;
                         }
+                        _ => {}
                     }
-                    Err(e) => {
-                        error!(
-                            "HTTP server error on retrieving incoming request. Error: {}",
-                            e
-                        );
-                    }
+                }
+
+                for response in response_receiver.try_iter() {
+                    server.respond(response).or_else(|e| {
+                        error!("HTTP server error on response: {}", e);
+                        Ok(())
+                    })?;
                 }
             }
         })
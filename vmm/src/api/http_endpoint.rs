@@ -5,14 +5,19 @@
 
 use crate::api::http::{error_response, EndpointHandler, HttpError};
 use crate::api::{
-    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock, vm_boot,
-    vm_counters, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_receive_migration,
-    vm_remove_device, vm_resize, vm_resize_zone, vm_restore, vm_resume, vm_send_migration,
-    vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown, ApiRequest, VmAction, VmConfig,
+    vm_access_trace, vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock,
+    vm_boot, vm_cancel_hotplug, vm_counters, vm_create, vm_delete, vm_hotplug, vm_hotplug_status,
+    vm_info, vm_inject_mce, vm_memory_layout, vm_pause, vm_power_button, vm_reboot,
+    vm_receive_migration, vm_remove_device, vm_resize, vm_resize_zone, vm_restore, vm_resume,
+    vm_send_migration, vm_set_net_link, vm_shutdown, vm_snapshot, vm_trace_queues, vm_wakeup,
+    vmm_ping, vmm_resource_usage, vmm_shutdown, ApiRequest, VmAction, VmConfig, VmmEventsData,
 };
+use crate::event_monitor;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use std::cmp;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use vmm_sys_util::eventfd::EventFd;
 
 // /api/v1/vm.create handler
@@ -125,6 +130,48 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmRemoveDevice),
 
+                SetNetLink(_) => vm_set_net_link(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetNetLink),
+
+                TraceQueues(_) => vm_trace_queues(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmTraceQueues),
+
+                InjectMce(_) => vm_inject_mce(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmInjectMce),
+
+                Hotplug(_) => vm_hotplug(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmHotplug),
+
+                HotplugStatus(_) => vm_hotplug_status(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmHotplugStatus),
+
+                CancelHotplug(_) => vm_cancel_hotplug(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmCancelHotplug),
+
                 Resize(_) => vm_resize(
                     api_notifier,
                     api_sender,
@@ -177,6 +224,10 @@ impl EndpointHandler for VmActionHandler {
                 Reboot => vm_reboot(api_notifier, api_sender).map_err(HttpError::VmReboot),
                 Pause => vm_pause(api_notifier, api_sender).map_err(HttpError::VmPause),
                 Resume => vm_resume(api_notifier, api_sender).map_err(HttpError::VmResume),
+                Wakeup => vm_wakeup(api_notifier, api_sender).map_err(HttpError::VmWakeup),
+                PowerButton => {
+                    vm_power_button(api_notifier, api_sender).map_err(HttpError::VmPowerButton)
+                }
                 _ => Err(HttpError::BadRequest),
             }
         }
@@ -191,6 +242,12 @@ impl EndpointHandler for VmActionHandler {
         use VmAction::*;
         match self.action {
             Counters => vm_counters(api_notifier, api_sender).map_err(HttpError::VmCounters),
+            MemoryLayout => {
+                vm_memory_layout(api_notifier, api_sender).map_err(HttpError::VmMemoryLayout)
+            }
+            AccessTrace => {
+                vm_access_trace(api_notifier, api_sender).map_err(HttpError::VmAccessTrace)
+            }
             _ => Err(HttpError::BadRequest),
         }
     }
@@ -222,6 +279,71 @@ impl EndpointHandler for VmInfo {
     }
 }
 
+// Longest time a client can wait for new events, for an idle client not to
+// hold its connection to the HTTP server forever.
+const MAX_EVENTS_TIMEOUT: u64 = 60;
+
+/// The time a `vmm.events` request waits for a new event, if it is a valid
+/// one which has to wait. Such long-polls are held by the events thread of
+/// the HTTP server until an event is recorded or their timeout expires, and
+/// only then handed over to `VmmEvents`.
+pub fn vmm_events_wait(req: &Request) -> Option<(u64, Duration)> {
+    if req.method() != Method::Get {
+        return None;
+    }
+
+    let events_data: VmmEventsData = match &req.body {
+        Some(body) => serde_json::from_slice(body.raw()).ok()?,
+        None => return None,
+    };
+    if events_data.timeout == 0 || event_monitor::last_event_id() > events_data.after {
+        return None;
+    }
+
+    Some((
+        events_data.after,
+        Duration::from_secs(cmp::min(events_data.timeout, MAX_EVENTS_TIMEOUT)),
+    ))
+}
+
+// /api/v1/vmm.events handler
+pub struct VmmEvents {}
+
+impl EndpointHandler for VmmEvents {
+    fn handle_request(
+        &self,
+        req: &Request,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                let events_data: VmmEventsData = match &req.body {
+                    Some(body) => match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    },
+                    None => VmmEventsData::default(),
+                };
+
+                // The events are recorded by the VMM thread, hence there is
+                // no need to go through it to retrieve them. The request has
+                // already waited for them if needed, see vmm_events_wait().
+                let events = event_monitor::events_after(events_data.after, Duration::from_secs(0));
+
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                let events_serialized = serde_json::to_string(&events).unwrap();
+
+                response.set_body(Body::new(events_serialized));
+                response
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.info handler
 pub struct VmmPing {}
 
@@ -248,6 +370,34 @@ impl EndpointHandler for VmmPing {
     }
 }
 
+// /api/v1/vmm.resource-usage handler
+pub struct VmmResourceUsage {}
+
+impl EndpointHandler for VmmResourceUsage {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => match vmm_resource_usage(api_notifier, api_sender)
+                .map_err(HttpError::VmmResourceUsage)
+            {
+                Ok(usage) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let usage_serialized = serde_json::to_string(&usage).unwrap();
+
+                    response.set_body(Body::new(usage_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.shutdown handler
 pub struct VmmShutdown {}
 
@@ -33,6 +33,7 @@ extern crate vmm_sys_util;
 
 pub use self::http::start_http_thread;
 
+pub mod audit;
 pub mod http;
 pub mod http_endpoint;
 
@@ -40,9 +41,11 @@ use crate::config::{
     DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
 };
 use crate::device_tree::DeviceTree;
+use crate::hotplug::HotplugDevice;
 use crate::vm::{Error as VmError, VmState};
 use micro_http::Body;
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use vm_migration::MigratableError;
@@ -78,6 +81,12 @@ pub enum ApiError {
     /// The VM info is not available.
     VmInfo(VmError),
 
+    /// The VM memory layout is not available.
+    VmMemoryLayout(VmError),
+
+    /// The VM access trace is not available.
+    VmAccessTrace(VmError),
+
     /// The VM config is missing.
     VmMissingConfig,
 
@@ -87,6 +96,12 @@ pub enum ApiError {
     /// The VM could not resume.
     VmResume(VmError),
 
+    /// The VM could not wake up.
+    VmWakeup(VmError),
+
+    /// The VM power button could not be pressed.
+    VmPowerButton(VmError),
+
     /// The VM is not booted.
     VmNotBooted,
 
@@ -141,11 +156,32 @@ pub enum ApiError {
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
 
+    /// The network link status could not be updated.
+    VmSetNetLink(VmError),
+
+    /// The virtqueue trace could not be toggled.
+    VmTraceQueues(VmError),
+
+    /// The machine check could not be injected into the VM.
+    VmInjectMce(VmError),
+
+    /// The hotplug job could not be started.
+    VmHotplug(VmError),
+
+    /// The hotplug job status could not be retrieved.
+    VmHotplugStatus(VmError),
+
+    /// The hotplug job could not be cancelled.
+    VmCancelHotplug(VmError),
+
     /// Error starting migration receiever
     VmReceiveMigration(MigratableError),
 
     /// Error starting migration sender
     VmSendMigration(MigratableError),
+
+    /// The VMM resource usage could not be collected.
+    VmmResourceUsage(io::Error),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -155,6 +191,15 @@ pub struct VmInfo {
     pub state: VmState,
     pub memory_actual_size: u64,
     pub device_tree: Option<Arc<Mutex<DeviceTree>>>,
+    #[serde(default)]
+    pub virtio_mem: Vec<VirtioMemInfo>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VirtioMemInfo {
+    pub id: String,
+    pub requested_size: u64,
+    pub plugged_size: u64,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -162,9 +207,31 @@ pub struct VmmPingResponse {
     pub version: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct VmmResourceUsage {
+    /// Resident set size of the VMM process, in bytes
+    pub rss: u64,
+    /// Resident anonymous memory, in bytes
+    pub rss_anon: u64,
+    /// Resident file mappings, in bytes
+    pub rss_file: u64,
+    /// Resident shared memory, in bytes
+    pub rss_shmem: u64,
+    /// Size of the guest RAM mapped by the VMM, in bytes
+    pub guest_memory_size: u64,
+    /// Resident part of the guest RAM, in bytes
+    pub guest_memory_rss: u64,
+    /// Resident memory not backing the guest RAM, in bytes
+    pub vmm_rss: u64,
+    /// Number of open file descriptors
+    pub open_fds: u64,
+    /// Number of threads
+    pub threads: u64,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmResizeData {
-    pub desired_vcpus: Option<u8>,
+    pub desired_vcpus: Option<u16>,
     pub desired_ram: Option<u64>,
     pub desired_balloon: Option<u64>,
 }
@@ -180,10 +247,64 @@ pub struct VmRemoveDeviceData {
     pub id: String,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmSetNetLinkData {
+    /// Identifier of the virtio-net device
+    pub id: String,
+    /// Whether the guest should see the link as up or down
+    pub up: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmTraceQueuesData {
+    /// Identifier of the virtio device
+    pub id: String,
+    /// Whether the descriptor chains going through its queues are logged
+    pub enable: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmInjectMceData {
+    /// vCPU the machine check is reported on
+    #[serde(default)]
+    pub cpu_id: u16,
+    /// Guest physical address of the poisoned memory
+    pub address: u64,
+    /// Whether the guest consumed the poisoned data (SRAR), rather than
+    /// the error being found by the memory scrubber (SRAO)
+    #[serde(default)]
+    pub action_required: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmHotplugJobData {
+    /// Identifier of the hotplug job
+    pub id: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmmEventsData {
+    /// Identifier of the last event already retrieved
+    #[serde(default)]
+    pub after: u64,
+    /// Seconds to wait for a new event, if there is none yet
+    #[serde(default)]
+    pub timeout: u64,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
     pub destination_url: String,
+    /// File containing the key the snapshot files are encrypted with
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    /// Compress the memory region files
+    #[serde(default)]
+    pub compress: bool,
+    /// Only store the memory dirtied since the previous snapshot
+    #[serde(default)]
+    pub incremental: bool,
 }
 
 #[derive(Clone, Deserialize, Serialize, Default)]
@@ -208,6 +329,9 @@ pub enum ApiResponsePayload {
     /// Vmm ping response
     VmmPing(VmmPingResponse),
 
+    /// Vmm resource usage
+    VmmResourceUsage(VmmResourceUsage),
+
     /// Vm action response
     VmAction(Vec<u8>),
 }
@@ -240,15 +364,30 @@ pub enum ApiRequest {
     /// Request the VMM API server status
     VmmPing(Sender<ApiResponse>),
 
+    /// Request the resources used by the VMM process
+    VmmResourceUsage(Sender<ApiResponse>),
+
     /// Pause a VM.
     VmPause(Sender<ApiResponse>),
 
     /// Resume a VM.
     VmResume(Sender<ApiResponse>),
 
+    /// Wake a VM up from the S3 sleep state.
+    VmWakeup(Sender<ApiResponse>),
+
+    /// Press the VM power button.
+    VmPowerButton(Sender<ApiResponse>),
+
     /// Get counters for a VM.
     VmCounters(Sender<ApiResponse>),
 
+    /// Get the guest physical address space layout of a VM.
+    VmMemoryLayout(Sender<ApiResponse>),
+
+    /// Get the last port I/O and MMIO accesses of each vCPU of a VM.
+    VmAccessTrace(Sender<ApiResponse>),
+
     /// Shut the previously booted virtual machine down.
     /// If the VM was not previously booted or created, the VMM API server
     /// will send a VmShutdown error back.
@@ -291,6 +430,24 @@ pub enum ApiRequest {
     /// Add a vsock device to the VM.
     VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
 
+    /// Set the link status of a network device.
+    VmSetNetLink(Arc<VmSetNetLinkData>, Sender<ApiResponse>),
+
+    /// Start or stop tracing the virtqueues of a device.
+    VmTraceQueues(Arc<VmTraceQueuesData>, Sender<ApiResponse>),
+
+    /// Report a guest memory error through a machine check.
+    VmInjectMce(Arc<VmInjectMceData>, Sender<ApiResponse>),
+
+    /// Start adding a device to the VM in the background.
+    VmHotplug(Arc<HotplugDevice>, Sender<ApiResponse>),
+
+    /// Get the status of a hotplug job.
+    VmHotplugStatus(Arc<VmHotplugJobData>, Sender<ApiResponse>),
+
+    /// Cancel a pending hotplug job.
+    VmCancelHotplug(Arc<VmHotplugJobData>, Sender<ApiResponse>),
+
     /// Take a VM snapshot
     VmSnapshot(Arc<VmSnapshotConfig>, Sender<ApiResponse>),
 
@@ -344,9 +501,21 @@ pub enum VmAction {
     /// Resume a VM
     Resume,
 
+    /// Wake a VM up
+    Wakeup,
+
+    /// Press the VM power button
+    PowerButton,
+
     /// Return VM counters
     Counters,
 
+    /// Return the VM memory layout
+    MemoryLayout,
+
+    /// Return the VM access trace
+    AccessTrace,
+
     /// Add VFIO device
     AddDevice(Arc<DeviceConfig>),
 
@@ -368,6 +537,24 @@ pub enum VmAction {
     /// Remove VFIO device
     RemoveDevice(Arc<VmRemoveDeviceData>),
 
+    /// Set network link status
+    SetNetLink(Arc<VmSetNetLinkData>),
+
+    /// Toggle virtqueue trace
+    TraceQueues(Arc<VmTraceQueuesData>),
+
+    /// Inject machine check
+    InjectMce(Arc<VmInjectMceData>),
+
+    /// Start hotplug job
+    Hotplug(Arc<HotplugDevice>),
+
+    /// Return hotplug job status
+    HotplugStatus(Arc<VmHotplugJobData>),
+
+    /// Cancel hotplug job
+    CancelHotplug(Arc<VmHotplugJobData>),
+
     /// Resize VM
     Resize(Arc<VmResizeData>),
 
@@ -402,7 +589,11 @@ fn vm_action(
         Reboot => ApiRequest::VmReboot(response_sender),
         Pause => ApiRequest::VmPause(response_sender),
         Resume => ApiRequest::VmResume(response_sender),
+        Wakeup => ApiRequest::VmWakeup(response_sender),
+        PowerButton => ApiRequest::VmPowerButton(response_sender),
         Counters => ApiRequest::VmCounters(response_sender),
+        MemoryLayout => ApiRequest::VmMemoryLayout(response_sender),
+        AccessTrace => ApiRequest::VmAccessTrace(response_sender),
         AddDevice(v) => ApiRequest::VmAddDevice(v, response_sender),
         AddDisk(v) => ApiRequest::VmAddDisk(v, response_sender),
         AddFs(v) => ApiRequest::VmAddFs(v, response_sender),
@@ -410,6 +601,12 @@ fn vm_action(
         AddNet(v) => ApiRequest::VmAddNet(v, response_sender),
         AddVsock(v) => ApiRequest::VmAddVsock(v, response_sender),
         RemoveDevice(v) => ApiRequest::VmRemoveDevice(v, response_sender),
+        SetNetLink(v) => ApiRequest::VmSetNetLink(v, response_sender),
+        TraceQueues(v) => ApiRequest::VmTraceQueues(v, response_sender),
+        InjectMce(v) => ApiRequest::VmInjectMce(v, response_sender),
+        Hotplug(v) => ApiRequest::VmHotplug(v, response_sender),
+        HotplugStatus(v) => ApiRequest::VmHotplugStatus(v, response_sender),
+        CancelHotplug(v) => ApiRequest::VmCancelHotplug(v, response_sender),
         Resize(v) => ApiRequest::VmResize(v, response_sender),
         ResizeZone(v) => ApiRequest::VmResizeZone(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
@@ -455,10 +652,35 @@ pub fn vm_resume(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<
     vm_action(api_evt, api_sender, VmAction::Resume)
 }
 
+pub fn vm_wakeup(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Wakeup)
+}
+
+pub fn vm_power_button(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::PowerButton)
+}
+
 pub fn vm_counters(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::Counters)
 }
 
+pub fn vm_memory_layout(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MemoryLayout)
+}
+
+pub fn vm_access_trace(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AccessTrace)
+}
+
 pub fn vm_receive_migration(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -524,6 +746,25 @@ pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<V
     }
 }
 
+pub fn vmm_resource_usage(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<VmmResourceUsage> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmmResourceUsage(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let usage = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match usage {
+        ApiResponsePayload::VmmResourceUsage(usage) => Ok(usage),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
 pub fn vmm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
@@ -609,3 +850,51 @@ pub fn vm_add_vsock(
 ) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::AddVsock(data))
 }
+
+pub fn vm_set_net_link(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetNetLinkData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetNetLink(data))
+}
+
+pub fn vm_trace_queues(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmTraceQueuesData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::TraceQueues(data))
+}
+
+pub fn vm_inject_mce(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmInjectMceData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::InjectMce(data))
+}
+
+pub fn vm_hotplug(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<HotplugDevice>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Hotplug(data))
+}
+
+pub fn vm_hotplug_status(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmHotplugJobData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::HotplugStatus(data))
+}
+
+pub fn vm_cancel_hotplug(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmHotplugJobData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::CancelHotplug(data))
+}
@@ -0,0 +1,190 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::VmConfig;
+use crate::device_manager::DeviceManager;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use seccomp::{SeccompAction, SeccompFilter};
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const PSI_MEMORY_PATH: &str = "/proc/pressure/memory";
+// Delay between two evaluations of the host memory pressure.
+const POLICY_INTERVAL: Duration = Duration::from_secs(1);
+// Share of the last 10 seconds, in percent, during which some tasks have been
+// stalled on memory, above which the balloon gets inflated.
+const PRESSURE_HIGH: f64 = 10.0;
+// Host memory pressure under which the balloon gets deflated.
+const PRESSURE_LOW: f64 = 1.0;
+// Amount of memory the balloon is resized by at each step.
+const POLICY_STEP: u64 = 128 << 20;
+
+// Returns the "avg10" value of the "some" line of a PSI file.
+fn parse_psi_some_avg10(psi: &str) -> Option<f64> {
+    psi.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+fn host_memory_pressure() -> io::Result<f64> {
+    parse_psi_some_avg10(&fs::read_to_string(PSI_MEMORY_PATH)?).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid content for {}", PSI_MEMORY_PATH),
+        )
+    })
+}
+
+/// Adjusts the balloon size, within the `min` and `max` bounds, based on the
+/// host memory pressure. The balloon is inflated while the host is under
+/// pressure, and deflated back once the pressure is gone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BalloonPolicy {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl BalloonPolicy {
+    fn target(&self, current: u64, pressure: f64) -> u64 {
+        let target = if pressure > PRESSURE_HIGH {
+            current.saturating_add(POLICY_STEP)
+        } else if pressure < PRESSURE_LOW {
+            current.saturating_sub(POLICY_STEP)
+        } else {
+            current
+        };
+        target.max(self.min).min(self.max)
+    }
+}
+
+/// Handle on the thread running the balloon policy. The thread terminates
+/// when the handle is dropped.
+pub struct BalloonPolicyHandle {
+    paused: Arc<AtomicBool>,
+    _stop: Sender<()>,
+}
+
+impl BalloonPolicyHandle {
+    /// Prevent the balloon from being resized while the VM is paused.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+}
+
+pub fn start_balloon_policy(
+    policy: BalloonPolicy,
+    device_manager: Arc<Mutex<DeviceManager>>,
+    config: Arc<Mutex<VmConfig>>,
+    seccomp_action: &SeccompAction,
+) -> io::Result<(BalloonPolicyHandle, thread::JoinHandle<()>)> {
+    // Fail early if the host does not provide the memory pressure.
+    host_memory_pressure()?;
+
+    let balloon_policy_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::BalloonPolicy)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+    let (stop, stop_receiver) = channel::<()>();
+    let paused = Arc::new(AtomicBool::new(false));
+    let thread_paused = paused.clone();
+
+    let thread = thread::Builder::new()
+        .name("balloon_policy".to_string())
+        .spawn(move || {
+            if let Err(e) = SeccompFilter::apply(balloon_policy_seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            // Dropping the handle disconnects the channel, which stops the
+            // loop right away.
+            while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(POLICY_INTERVAL) {
+                let pressure = match host_memory_pressure() {
+                    Ok(pressure) => pressure,
+                    Err(e) => {
+                        error!("Failed reading host memory pressure: {}", e);
+                        continue;
+                    }
+                };
+
+                let current = match &config.lock().unwrap().balloon {
+                    Some(balloon_config) => balloon_config.size,
+                    None => break,
+                };
+                let target = policy.target(current, pressure);
+                if target == current {
+                    continue;
+                }
+
+                {
+                    let mut device_manager = device_manager.lock().unwrap();
+                    if thread_paused.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    info!(
+                        "Resizing balloon from {} to {} bytes (memory pressure {})",
+                        current, target, pressure
+                    );
+                    if let Err(e) = device_manager.resize_balloon(target) {
+                        error!("Failed resizing balloon: {:?}", e);
+                        continue;
+                    }
+                }
+
+                // Update the configuration value for the balloon size to
+                // ensure a reboot would use the right value.
+                if let Some(balloon_config) = &mut config.lock().unwrap().balloon {
+                    balloon_config.size = target;
+                }
+            }
+        })?;
+
+    Ok((
+        BalloonPolicyHandle {
+            paused,
+            _stop: stop,
+        },
+        thread,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_psi_some_avg10() {
+        let psi = "some avg10=12.50 avg60=3.02 avg300=0.75 total=6530146\n\
+                   full avg10=4.00 avg60=1.00 avg300=0.25 total=2611230\n";
+        assert_eq!(parse_psi_some_avg10(psi), Some(12.5));
+        assert_eq!(parse_psi_some_avg10("full avg10=4.00"), None);
+        assert_eq!(parse_psi_some_avg10(""), None);
+    }
+
+    #[test]
+    fn test_balloon_policy_target() {
+        let policy = BalloonPolicy {
+            min: 256 << 20,
+            max: 1 << 30,
+        };
+        // Inflate under pressure, up to the maximum.
+        assert_eq!(policy.target(256 << 20, 20.0), 384 << 20);
+        assert_eq!(policy.target(960 << 20, 20.0), 1 << 30);
+        // Deflate without pressure, down to the minimum.
+        assert_eq!(policy.target(1 << 30, 0.0), 896 << 20);
+        assert_eq!(policy.target(300 << 20, 0.0), 256 << 20);
+        // Keep the size in between.
+        assert_eq!(policy.target(512 << 20, 5.0), 512 << 20);
+        // Bring the size back within the bounds.
+        assert_eq!(policy.target(0, 5.0), 256 << 20);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::ResourceGroupConfig;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CGROUP_MOUNT_PATH: &str = "/sys/fs/cgroup";
+// Period used for enforcing the CPU bandwidth limit, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot find the control group of the VMM process.
+    CurrentCgroup(io::Error),
+
+    /// The VMM process does not run on a cgroup v2 hierarchy.
+    CgroupV2Unavailable,
+
+    /// Cannot create the control group.
+    CreateCgroup(io::Error),
+
+    /// Cannot move the VMM process into the control group.
+    MoveProcess(io::Error),
+
+    /// Cannot enable a controller for the control group.
+    EnableController(&'static str, io::Error),
+
+    /// Cannot set a limit on the control group.
+    SetLimit(&'static str, io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Returns the path of the cgroup v2 the current process belongs to, relative
+// to the root of the hierarchy.
+fn current_cgroup() -> Result<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").map_err(Error::CurrentCgroup)?;
+    parse_cgroup_v2_path(&cgroups).ok_or(Error::CgroupV2Unavailable)
+}
+
+// The cgroup v2 hierarchy is identified by an entry "0::<path>".
+fn parse_cgroup_v2_path(cgroups: &str) -> Option<PathBuf> {
+    cgroups
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(|p| PathBuf::from(p.trim_start_matches('/')))
+}
+
+fn write_file(path: &Path, value: &str) -> io::Result<()> {
+    fs::write(path, value.as_bytes())
+}
+
+/// Control group the VMM has been moved into. When dropped, the VMM moves
+/// back to the control group it was started from, and the control group is
+/// removed if it has been created by the VMM.
+pub struct ResourceGroup {
+    path: PathBuf,
+    parent: PathBuf,
+    created: bool,
+    // Controllers enabled by the VMM for the children of the parent.
+    controllers: Vec<&'static str>,
+}
+
+impl ResourceGroup {
+    fn enable_controller(&mut self, controller: &'static str) -> Result<()> {
+        let subtree_control = self.parent.join("cgroup.subtree_control");
+        let enabled = fs::read_to_string(&subtree_control)
+            .map_err(|e| Error::EnableController(controller, e))?;
+        if enabled.split_whitespace().any(|c| c == controller) {
+            return Ok(());
+        }
+
+        write_file(&subtree_control, &format!("+{}", controller))
+            .map_err(|e| Error::EnableController(controller, e))?;
+        self.controllers.push(controller);
+
+        Ok(())
+    }
+}
+
+impl Drop for ResourceGroup {
+    fn drop(&mut self) {
+        // The controllers must be disabled first, as the parent can't have
+        // both processes and controllers enabled for its children.
+        let subtree_control = self.parent.join("cgroup.subtree_control");
+        for controller in self.controllers.drain(..) {
+            if let Err(e) = write_file(&subtree_control, &format!("-{}", controller)) {
+                warn!("Cannot disable the {} controller: {}", controller, e);
+            }
+        }
+
+        if let Err(e) = write_file(
+            &self.parent.join("cgroup.procs"),
+            &std::process::id().to_string(),
+        ) {
+            warn!("Cannot move the VMM out of {:?}: {}", self.path, e);
+            return;
+        }
+
+        if self.created {
+            if let Err(e) = fs::remove_dir(&self.path) {
+                warn!("Cannot remove control group {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// Create the control group described by the configuration, as a child of
+/// the control group the VMM currently belongs to, and move the whole VMM
+/// process into it. All threads, including the vCPU and device worker ones,
+/// are subject to the limits set on the control group.
+pub fn setup(config: &ResourceGroupConfig) -> Result<ResourceGroup> {
+    let parent = Path::new(CGROUP_MOUNT_PATH).join(current_cgroup()?);
+    let path = parent.join(&config.name);
+
+    let created = !path.exists();
+    if created {
+        fs::create_dir(&path).map_err(Error::CreateCgroup)?;
+    }
+
+    // From here, the control group is cleaned up on error.
+    let mut group = ResourceGroup {
+        path,
+        parent,
+        created,
+        controllers: Vec::new(),
+    };
+
+    // A non-root control group can't have both processes and controllers
+    // enabled for its children, which is why the VMM is moved into the
+    // control group before enabling any controller. This fails if other
+    // processes share the control group the VMM was started from.
+    write_file(
+        &group.path.join("cgroup.procs"),
+        &std::process::id().to_string(),
+    )
+    .map_err(Error::MoveProcess)?;
+
+    if let Some(cpu_quota) = config.cpu_quota {
+        group.enable_controller("cpu")?;
+        let quota = u64::from(cpu_quota) * CPU_PERIOD_US / 100;
+        write_file(
+            &group.path.join("cpu.max"),
+            &format!("{} {}", quota, CPU_PERIOD_US),
+        )
+        .map_err(|e| Error::SetLimit("cpu.max", e))?;
+    }
+
+    if let Some(memory_max) = config.memory_max {
+        group.enable_controller("memory")?;
+        write_file(&group.path.join("memory.max"), &memory_max.to_string())
+            .map_err(|e| Error::SetLimit("memory.max", e))?;
+    }
+
+    if let Some(io_weight) = config.io_weight {
+        group.enable_controller("io")?;
+        write_file(
+            &group.path.join("io.weight"),
+            &format!("default {}", io_weight),
+        )
+        .map_err(|e| Error::SetLimit("io.weight", e))?;
+    }
+
+    info!("VMM moved into control group {:?}", group.path);
+
+    Ok(group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgroup_v2_path() {
+        assert_eq!(
+            parse_cgroup_v2_path("0::/user.slice/session-1.scope\n"),
+            Some(PathBuf::from("user.slice/session-1.scope"))
+        );
+        assert_eq!(
+            parse_cgroup_v2_path("12:cpuset:/\n1:name=systemd:/init.scope\n0::/\n"),
+            Some(PathBuf::from(""))
+        );
+        assert_eq!(parse_cgroup_v2_path("1:name=systemd:/init.scope\n"), None);
+    }
+}
@@ -3,25 +3,38 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use block_util::MAX_SERIAL_LEN;
 use clap::ArgMatches;
 use net_util::MacAddr;
 use option_parser::{
-    ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, TupleTwoIntegers,
+    ByteSized, Integer, IntegerList, OptionParser, OptionParserError, StringList, Toggle,
+    TupleTwoIntegers,
 };
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::From;
 use std::fmt;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
 
-pub const DEFAULT_VCPUS: u8 = 1;
+pub const DEFAULT_VCPUS: u16 = 1;
+// The hypervisor backends only create vCPUs with 8 bits identifiers.
+pub const MAX_VCPUS: u16 = 256;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
 pub const DEFAULT_NUM_QUEUES_VUNET: usize = 2;
 pub const DEFAULT_QUEUE_SIZE_VUNET: u16 = 256;
 pub const DEFAULT_NUM_QUEUES_VUBLK: usize = 1;
 pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
+// Range of guest physical address widths which can be requested through
+// the "max_phys_bits" option.
+const MIN_PHYS_BITS: u8 = 32;
+const MAX_PHYS_BITS: u8 = 52;
+// Additional ports of the virtio-console device, besides the console.
+const MAX_CONSOLE_PORTS: usize = 31;
+// Largest size of a split virtqueue, as defined by the virtio specification.
+const MAX_QUEUE_SIZE: u16 = 32768;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
@@ -64,6 +77,12 @@ pub enum Error {
     ParseConsole(OptionParserError),
     /// No mode given for console
     ParseConsoleInvalidModeGiven,
+    /// Failed parsing console port
+    ParseConsolePort(OptionParserError),
+    /// Missing name from console port
+    ParseConsolePortNameMissing,
+    /// No mode, or several modes, given for console port
+    ParseConsolePortInvalidModeGiven,
     /// Failed parsing device parameters
     ParseDevice(OptionParserError),
     /// Missing path from device,
@@ -72,11 +91,50 @@ pub enum Error {
     ParseVsock(OptionParserError),
     /// Failed to parse restore parameters
     ParseRestore(OptionParserError),
+    /// Failed to parse metrics parameters
+    ParseMetrics(OptionParserError),
+    /// Missing metrics exporter address
+    ParseMetricsAddressMissing,
+    /// Failed to parse OTLP parameters
+    #[cfg(feature = "otlp")]
+    ParseOtlp(OptionParserError),
+    /// Missing OTLP collector endpoint
+    #[cfg(feature = "otlp")]
+    ParseOtlpEndpointMissing,
     /// Failed to parse SGX EPC parameters
     #[cfg(target_arch = "x86_64")]
     ParseSgxEpc(OptionParserError),
+    /// Error parsing reserved memory parameters
+    #[cfg(target_arch = "x86_64")]
+    ParseReservedMemory(OptionParserError),
+    /// Missing start or size for a reserved memory range
+    #[cfg(target_arch = "x86_64")]
+    ParseReservedMemoryRangeMissing,
+    /// Error parsing platform parameters
+    #[cfg(target_arch = "x86_64")]
+    ParsePlatform(OptionParserError),
     /// Failed to parse NUMA parameters
     ParseNuma(OptionParserError),
+    /// Failed to parse the action taken on guest crash
+    ParseOnCrash(ParseOnCrashActionError),
+    /// Failed to parse resource group parameters
+    ParseResourceGroup(OptionParserError),
+    /// Missing name from resource group
+    ParseResourceGroupNameMissing,
+    /// Failed to parse worker pool parameters
+    ParseWorkerPool(OptionParserError),
+    /// Failed to parse clock parameters
+    ParseClock(OptionParserError),
+    /// Failed to parse metadata service parameters
+    ParseMetadata(OptionParserError),
+    /// Missing instance ID from the metadata service
+    ParseMetadataInstanceIdMissing,
+    /// Missing tap interface from the metadata service
+    ParseMetadataTapMissing,
+    /// Failed to read the configuration file
+    ReadConfigFile(std::io::Error),
+    /// Failed to parse the configuration file
+    ParseConfigFile(serde_json::Error),
     /// Failed to validate configuration
     Validation(ValidationError),
 }
@@ -91,6 +149,8 @@ pub enum ValidationError {
     ConsoleFileMissing,
     /// Max is less than boot
     CpusMaxLowerThanBoot,
+    /// Max is more than the hypervisor backends support
+    CpusMaxTooLarge(u16),
     /// Both socket and path specified
     DiskSocketAndPath,
     /// Using vhost user requires shared memory
@@ -107,6 +167,99 @@ pub enum ValidationError {
     CpuTopologyZeroPart,
     /// Virtio needs a min of 2 queues
     VnetQueueLowerThan2,
+    /// Physical address width out of the supported range
+    InvalidMaxPhysBits(u8),
+    /// Guest memory can't be addressed with the physical address width
+    MemoryExceedsPhysBits(u8),
+    /// Crash kernel memory not a multiple of 1MiB or not smaller than the memory
+    InvalidCrashKernelSize,
+    /// Crash kernel memory reserved both from the memory and the command line
+    CrashKernelInCmdline,
+    /// Reserved memory range empty, not page aligned or past the address space
+    #[cfg(target_arch = "x86_64")]
+    InvalidReservedMemory(u64),
+    /// Reserved memory range overlapping the guest RAM, or an area of the
+    /// 32-bit hole or of the device area used by the VMM
+    #[cfg(target_arch = "x86_64")]
+    ReservedMemoryOverlap(u64),
+    /// CPU affinity refers to an unknown vCPU, has no host CPU or a host
+    /// CPU beyond CPU_SETSIZE
+    InvalidCpuAffinity(u16),
+    /// Resource group name is not a valid cgroup name
+    InvalidResourceGroupName(String),
+    /// Resource group CPU quota is zero
+    InvalidResourceGroupCpuQuota,
+    /// Resource group I/O weight out of the supported range
+    InvalidResourceGroupIoWeight(u16),
+    /// Interrupt coalescing needs both a non-zero number of events and delay
+    InvalidInterruptCoalescing,
+    /// Interrupt coalescing is handled by the backend with vhost-user
+    InterruptCoalescingVhostUser,
+    /// Worker pool without any thread
+    InvalidWorkerPoolThreads,
+    /// Balloon policy needs both bounds, the minimum not exceeding the maximum
+    InvalidBalloonPolicy,
+    /// NVDIMM regions are described through ACPI
+    NvdimmUnsupported,
+    /// NVDIMM regions are not PCI devices, hence can't be behind the IOMMU
+    NvdimmIommu,
+    /// Device overridden on restore not found in the snapshot
+    RestoreDeviceUnknown(String),
+    /// Disk serial too long or with non printable characters
+    InvalidDiskSerial(String),
+    /// Disk serial is reported by the backend with vhost-user
+    DiskSerialVhostUser,
+    /// Disk encryption is handled by the backend with vhost-user
+    DiskKeyFileVhostUser,
+    /// Several devices share the same boot index
+    DuplicateBootIndex(u16),
+    /// Console ports are part of the virtio-console device
+    ConsolePortsWithoutConsole,
+    /// Too many console ports
+    TooManyConsolePorts,
+    /// Console port name empty or not usable as a file name
+    InvalidConsolePortName(String),
+    /// Several console ports share the same name
+    DuplicateConsolePortName(String),
+    /// Missing file or socket path for a console port
+    ConsolePortPathMissing(String),
+    /// Path given for another mode than the one of the console port
+    ConsolePortPathUnexpected(String),
+    /// Console output rotation options without file mode
+    ConsoleRotationWithoutFile,
+    /// Number of rotated console output files without maximum size
+    ConsoleRotateWithoutMaxSize,
+    /// Console output maximum size is zero
+    InvalidConsoleMaxSize,
+    /// Entropy rate limit of zero bytes per second
+    InvalidRngRateLimit,
+    /// PCI class code of a passed through device wider than 24 bits
+    InvalidPciClassCode(u32),
+    /// Virtqueue size zero, not a power of two or larger than the maximum
+    InvalidQueueSize(u16),
+    /// Queue affinity refers to an unknown queue, has no host CPU or one
+    /// beyond CPU_SETSIZE
+    InvalidQueueAffinity(u16),
+    /// Queues are processed by the backend with vhost-user
+    QueueAffinityVhostUser,
+    /// Queues are processed by the threads of the worker pool
+    QueueAffinityWorkerPool,
+    /// Clones map their memory privately from the snapshot files
+    CloneSharedMemory,
+    /// Vsock device overridden on restore not found in the snapshot
+    RestoreVsockMissing,
+    /// Platform serial number empty or with non printable characters
+    #[cfg(target_arch = "x86_64")]
+    InvalidPlatformSerialNumber(String),
+    /// Platform UUID not in the 8-4-4-4-12 hexadecimal digits form
+    #[cfg(target_arch = "x86_64")]
+    InvalidPlatformUuid(String),
+    /// Guest agent refers to an unknown console port
+    ClockGuestAgentPortMissing(String),
+    /// Metadata service with an empty instance ID
+    InvalidMetadataInstanceId,
+    /// Metadata service tap interface name empty or too long
+    InvalidMetadataTap(String),
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -119,6 +272,9 @@ impl fmt::Display for ValidationError {
             KernelMissing => write!(f, "No kernel specified"),
             ConsoleFileMissing => write!(f, "Path missing when using file console mode"),
             CpusMaxLowerThanBoot => write!(f, "Max CPUs greater than boot CPUs"),
+            CpusMaxTooLarge(max) => {
+                write!(f, "Max CPUs {} above the {} supported", max, MAX_VCPUS)
+            }
             DiskSocketAndPath => write!(f, "Disk path and vhost socket both provided"),
             VhostUserRequiresSharedMemory => {
                 write!(f, "Using vhost-user requires using shared memory")
@@ -132,6 +288,138 @@ impl fmt::Display for ValidationError {
                 "Product of CPU topology parts does not match maximum vCPUs"
             ),
             VnetQueueLowerThan2 => write!(f, "Number of queues to virtio_net less than 2"),
+            InvalidMaxPhysBits(b) => write!(
+                f,
+                "Physical address bits {} out of the supported range [{}, {}]",
+                b, MIN_PHYS_BITS, MAX_PHYS_BITS
+            ),
+            MemoryExceedsPhysBits(b) => write!(
+                f,
+                "Guest memory is too large to be addressed with {} physical address bits",
+                b
+            ),
+            InvalidCrashKernelSize => write!(
+                f,
+                "Crash kernel memory must be a multiple of 1MiB, smaller than the guest memory"
+            ),
+            CrashKernelInCmdline => write!(
+                f,
+                "Crash kernel memory can't be reserved from both --memory and --cmdline"
+            ),
+            #[cfg(target_arch = "x86_64")]
+            InvalidReservedMemory(s) => {
+                write!(f, "Invalid reserved memory range starting at 0x{:x}", s)
+            }
+            #[cfg(target_arch = "x86_64")]
+            ReservedMemoryOverlap(s) => write!(
+                f,
+                "Reserved memory range starting at 0x{:x} overlaps the guest RAM or a device area",
+                s
+            ),
+            InvalidCpuAffinity(v) => write!(f, "Invalid CPU affinity for vCPU {}", v),
+            InvalidResourceGroupName(n) => write!(f, "Invalid resource group name \"{}\"", n),
+            InvalidResourceGroupCpuQuota => {
+                write!(f, "Resource group CPU quota must be above 0")
+            }
+            InvalidResourceGroupIoWeight(w) => write!(
+                f,
+                "Resource group I/O weight {} out of the supported range [1, 10000]",
+                w
+            ),
+            InvalidInterruptCoalescing => write!(
+                f,
+                "Interrupt coalescing requires non-zero coalesce_events and coalesce_usecs"
+            ),
+            InterruptCoalescingVhostUser => {
+                write!(f, "Interrupt coalescing is not supported with vhost-user")
+            }
+            InvalidWorkerPoolThreads => write!(f, "Worker pool requires at least one thread"),
+            InvalidBalloonPolicy => write!(
+                f,
+                "Balloon policy requires policy_min and policy_max, with policy_min <= policy_max"
+            ),
+            NvdimmUnsupported => write!(f, "Using NVDIMM without ACPI support is unsupported"),
+            NvdimmIommu => write!(f, "NVDIMM regions can't be placed behind the IOMMU"),
+            RestoreDeviceUnknown(id) => {
+                write!(f, "Device {} to override on restore not found", id)
+            }
+            InvalidDiskSerial(serial) => write!(
+                f,
+                "Disk serial \"{}\" must be made of at most {} printable ASCII characters",
+                serial, MAX_SERIAL_LEN
+            ),
+            DiskSerialVhostUser => write!(f, "Disk serial is not supported with vhost-user"),
+            DiskKeyFileVhostUser => {
+                write!(f, "Disk encryption is not supported with vhost-user")
+            }
+            DuplicateBootIndex(i) => write!(f, "Boot index {} used by several devices", i),
+            ConsolePortsWithoutConsole => {
+                write!(f, "Console ports require the virtio-console device")
+            }
+            TooManyConsolePorts => write!(
+                f,
+                "Number of console ports greater than {}",
+                MAX_CONSOLE_PORTS
+            ),
+            InvalidConsolePortName(n) => write!(f, "Invalid console port name \"{}\"", n),
+            DuplicateConsolePortName(n) => {
+                write!(f, "Console port name \"{}\" used by several ports", n)
+            }
+            ConsolePortPathMissing(n) => write!(f, "Path missing for console port \"{}\"", n),
+            ConsolePortPathUnexpected(n) => write!(
+                f,
+                "Path given for another mode than the one of console port \"{}\"",
+                n
+            ),
+            InvalidQueueSize(s) => write!(
+                f,
+                "Queue size {} must be a power of two, not greater than {}",
+                s, MAX_QUEUE_SIZE
+            ),
+            InvalidQueueAffinity(q) => write!(f, "Invalid queue affinity for queue {}", q),
+            QueueAffinityVhostUser => write!(f, "Queue affinity is not supported with vhost-user"),
+            QueueAffinityWorkerPool => {
+                write!(f, "Queue affinity is not supported with the worker pool")
+            }
+            CloneSharedMemory => write!(
+                f,
+                "Cloning is not supported with shared memory or huge pages"
+            ),
+            RestoreVsockMissing => write!(f, "Vsock device to override on restore not found"),
+            #[cfg(target_arch = "x86_64")]
+            InvalidPlatformSerialNumber(serial_number) => write!(
+                f,
+                "Platform serial number \"{}\" must be made of printable ASCII characters",
+                serial_number
+            ),
+            #[cfg(target_arch = "x86_64")]
+            InvalidPlatformUuid(uuid) => write!(
+                f,
+                "Platform UUID \"{}\" must be made of 8-4-4-4-12 hexadecimal digits",
+                uuid
+            ),
+            ClockGuestAgentPortMissing(name) => {
+                write!(f, "Guest agent console port \"{}\" not found", name)
+            }
+            InvalidMetadataInstanceId => {
+                write!(f, "Metadata service instance ID must not be empty")
+            }
+            InvalidMetadataTap(tap) => write!(
+                f,
+                "Metadata service tap interface name \"{}\" must be 1 to {} bytes long",
+                tap,
+                libc::IFNAMSIZ - 1
+            ),
+            ConsoleRotationWithoutFile => write!(
+                f,
+                "Console output rotation is only supported in file console mode"
+            ),
+            ConsoleRotateWithoutMaxSize => {
+                write!(f, "Console output rotation requires a maximum size")
+            }
+            InvalidConsoleMaxSize => write!(f, "Console output maximum size must not be zero"),
+            InvalidRngRateLimit => write!(f, "Entropy rate limit must not be zero"),
+            InvalidPciClassCode(c) => write!(f, "PCI class code 0x{:x} wider than 24 bits", c),
         }
     }
 }
@@ -144,6 +432,11 @@ impl fmt::Display for Error {
             ParseConsoleInvalidModeGiven => {
                 write!(f, "Error parsing --console: invalid console mode given")
             }
+            ParseConsolePort(o) => write!(f, "Error parsing --console-port: {}", o),
+            ParseConsolePortNameMissing => write!(f, "Error parsing --console-port: name missing"),
+            ParseConsolePortInvalidModeGiven => {
+                write!(f, "Error parsing --console-port: invalid port mode given")
+            }
             ParseCpus(o) => write!(f, "Error parsing --cpus: {}", o),
 
             ParseDevice(o) => write!(f, "Error parsing --device: {}", o),
@@ -167,9 +460,42 @@ impl fmt::Display for Error {
             ParseRNG(o) => write!(f, "Error parsing --rng: {}", o),
             ParseBalloon(o) => write!(f, "Error parsing --balloon: {}", o),
             ParseRestore(o) => write!(f, "Error parsing --restore: {}", o),
+            ParseMetrics(o) => write!(f, "Error parsing --metrics: {}", o),
+            ParseMetricsAddressMissing => write!(f, "Error parsing --metrics: tcp missing"),
+            #[cfg(feature = "otlp")]
+            ParseOtlp(o) => write!(f, "Error parsing --otlp: {}", o),
+            #[cfg(feature = "otlp")]
+            ParseOtlpEndpointMissing => write!(f, "Error parsing --otlp: endpoint missing"),
             #[cfg(target_arch = "x86_64")]
             ParseSgxEpc(o) => write!(f, "Error parsing --sgx-epc: {}", o),
+            #[cfg(target_arch = "x86_64")]
+            ParseReservedMemory(o) => write!(f, "Error parsing --reserved-memory: {}", o),
+            #[cfg(target_arch = "x86_64")]
+            ParseReservedMemoryRangeMissing => {
+                write!(
+                    f,
+                    "Error parsing --reserved-memory: start and size required"
+                )
+            }
+            #[cfg(target_arch = "x86_64")]
+            ParsePlatform(o) => write!(f, "Error parsing --platform: {}", o),
             ParseNuma(o) => write!(f, "Error parsing --numa: {}", o),
+            ParseOnCrash(ParseOnCrashActionError::InvalidValue(v)) => {
+                write!(f, "Error parsing --on-crash: invalid action \"{}\"", v)
+            }
+            ParseResourceGroup(o) => write!(f, "Error parsing --resource-group: {}", o),
+            ParseResourceGroupNameMissing => {
+                write!(f, "Error parsing --resource-group: name missing")
+            }
+            ParseWorkerPool(o) => write!(f, "Error parsing --worker-pool: {}", o),
+            ParseClock(o) => write!(f, "Error parsing --clock: {}", o),
+            ParseMetadata(o) => write!(f, "Error parsing --metadata: {}", o),
+            ParseMetadataInstanceIdMissing => {
+                write!(f, "Error parsing --metadata: instance_id missing")
+            }
+            ParseMetadataTapMissing => write!(f, "Error parsing --metadata: tap missing"),
+            ReadConfigFile(e) => write!(f, "Error reading --config: {}", e),
+            ParseConfigFile(e) => write!(f, "Error parsing --config: {}", e),
             ParseRestoreSourceUrlMissing => {
                 write!(f, "Error parsing --restore: source_url missing")
             }
@@ -195,12 +521,25 @@ pub struct VmParams<'a> {
     pub pmem: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
+    pub console_ports: Option<Vec<&'a str>>,
     pub devices: Option<Vec<&'a str>>,
     pub vsock: Option<&'a str>,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
+    #[cfg(target_arch = "x86_64")]
+    pub reserved_memory: Option<Vec<&'a str>>,
+    #[cfg(target_arch = "x86_64")]
+    pub platform: Option<&'a str>,
     pub numa: Option<Vec<&'a str>>,
     pub watchdog: bool,
+    pub on_crash: &'a str,
+    pub resource_group: Option<&'a str>,
+    pub record: Option<&'a str>,
+    pub worker_pool: Option<&'a str>,
+    pub clock: Option<&'a str>,
+    pub metadata: Option<&'a str>,
+    #[cfg(target_arch = "aarch64")]
+    pub efi_vars: Option<&'a str>,
 }
 
 impl<'a> VmParams<'a> {
@@ -219,6 +558,7 @@ impl<'a> VmParams<'a> {
         let disks: Option<Vec<&str>> = args.values_of("disk").map(|x| x.collect());
         let net: Option<Vec<&str>> = args.values_of("net").map(|x| x.collect());
         let console = args.value_of("console").unwrap();
+        let console_ports: Option<Vec<&str>> = args.values_of("console-port").map(|x| x.collect());
         let balloon = args.value_of("balloon");
         let fs: Option<Vec<&str>> = args.values_of("fs").map(|x| x.collect());
         let pmem: Option<Vec<&str>> = args.values_of("pmem").map(|x| x.collect());
@@ -226,8 +566,21 @@ impl<'a> VmParams<'a> {
         let vsock: Option<&str> = args.value_of("vsock");
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
+        #[cfg(target_arch = "x86_64")]
+        let reserved_memory: Option<Vec<&str>> =
+            args.values_of("reserved-memory").map(|x| x.collect());
+        #[cfg(target_arch = "x86_64")]
+        let platform = args.value_of("platform");
         let numa: Option<Vec<&str>> = args.values_of("numa").map(|x| x.collect());
         let watchdog = args.is_present("watchdog");
+        let on_crash = args.value_of("on-crash").unwrap();
+        let resource_group = args.value_of("resource-group");
+        let record = args.value_of("record");
+        let worker_pool = args.value_of("worker-pool");
+        let clock = args.value_of("clock");
+        let metadata = args.value_of("metadata");
+        #[cfg(target_arch = "aarch64")]
+        let efi_vars = args.value_of("efi-vars");
 
         VmParams {
             cpus,
@@ -244,12 +597,25 @@ impl<'a> VmParams<'a> {
             pmem,
             serial,
             console,
+            console_ports,
             devices,
             vsock,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            reserved_memory,
+            #[cfg(target_arch = "x86_64")]
+            platform,
             numa,
             watchdog,
+            on_crash,
+            resource_group,
+            record,
+            worker_pool,
+            clock,
+            metadata,
+            #[cfg(target_arch = "aarch64")]
+            efi_vars,
         }
     }
 }
@@ -283,10 +649,84 @@ impl FromStr for HotplugMethod {
     }
 }
 
+/// Action taken when the guest crashes, e.g. on a triple fault.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum OnCrashAction {
+    Reboot,
+    Exit,
+    Pause,
+}
+
+impl Default for OnCrashAction {
+    fn default() -> Self {
+        OnCrashAction::Reboot
+    }
+}
+
+impl fmt::Display for OnCrashAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnCrashAction::Reboot => write!(f, "reboot"),
+            OnCrashAction::Exit => write!(f, "exit"),
+            OnCrashAction::Pause => write!(f, "pause"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseOnCrashActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for OnCrashAction {
+    type Err = ParseOnCrashActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reboot" => Ok(OnCrashAction::Reboot),
+            "exit" => Ok(OnCrashAction::Exit),
+            "pause" => Ok(OnCrashAction::Pause),
+            _ => Err(ParseOnCrashActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 pub enum CpuTopologyParseError {
     InvalidValue(String),
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuAffinity {
+    pub vcpu: u16,
+    pub host_cpus: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct QueueAffinity {
+    pub queue_index: u16,
+    pub host_cpus: Vec<usize>,
+}
+
+// Gather the host CPUs listed for each queue, the same queue being allowed
+// to appear several times.
+fn parse_queue_affinity(affinity: TupleTwoIntegers) -> Vec<QueueAffinity> {
+    let mut host_cpus: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+    for (queue_index, host_cpu) in affinity.0 {
+        host_cpus
+            .entry(queue_index as u16)
+            .or_default()
+            .push(host_cpu as usize);
+    }
+
+    host_cpus
+        .into_iter()
+        .map(|(queue_index, host_cpus)| QueueAffinity {
+            queue_index,
+            host_cpus,
+        })
+        .collect()
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct CpuTopology {
     pub threads_per_core: u8,
@@ -326,14 +766,16 @@ impl FromStr for CpuTopology {
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct CpusConfig {
-    pub boot_vcpus: u8,
-    pub max_vcpus: u8,
+    pub boot_vcpus: u16,
+    pub max_vcpus: u16,
     #[serde(default)]
     pub topology: Option<CpuTopology>,
     #[serde(default)]
     pub kvm_hyperv: bool,
     #[serde(default)]
     pub max_phys_bits: Option<u8>,
+    #[serde(default)]
+    pub affinity: Option<Vec<CpuAffinity>>,
 }
 
 impl CpusConfig {
@@ -347,11 +789,11 @@ impl CpusConfig {
             .add("max_phys_bits");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
-        let boot_vcpus: u8 = parser
+        let boot_vcpus: u16 = parser
             .convert("boot")
             .map_err(Error::ParseCpus)?
             .unwrap_or(DEFAULT_VCPUS);
-        let max_vcpus: u8 = parser
+        let max_vcpus: u16 = parser
             .convert("max")
             .map_err(Error::ParseCpus)?
             .unwrap_or(boot_vcpus);
@@ -371,6 +813,7 @@ impl CpusConfig {
             topology,
             kvm_hyperv,
             max_phys_bits,
+            affinity: None,
         })
     }
 }
@@ -383,6 +826,7 @@ impl Default for CpusConfig {
             topology: None,
             kvm_hyperv: false,
             max_phys_bits: None,
+            affinity: None,
         }
     }
 }
@@ -422,9 +866,49 @@ pub struct MemoryConfig {
     pub hugepages: bool,
     #[serde(default)]
     pub zones: Option<Vec<MemoryZoneConfig>>,
+    /// Memory the guest reserves for a kdump crash kernel.
+    #[serde(default)]
+    pub crashkernel: Option<u64>,
 }
 
 impl MemoryConfig {
+    // Amount of guest memory, including the memory which can be hotplugged,
+    // that needs to fit in the guest physical address space.
+    fn max_total_size(&self) -> u64 {
+        let mut total_size = self
+            .size
+            .saturating_add(self.hotplug_size.unwrap_or_default());
+        if let Some(zones) = &self.zones {
+            for zone in zones.iter() {
+                total_size = total_size
+                    .saturating_add(zone.size)
+                    .saturating_add(zone.hotplug_size.unwrap_or_default());
+            }
+        }
+        total_size
+    }
+
+    // Guest physical address right after the end of the RAM, including the
+    // hotpluggable one, which is laid out around the 32-bit hole.
+    fn max_end_address(&self) -> u64 {
+        let total_size = self.max_total_size();
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if total_size > arch::layout::MEM_32BIT_RESERVED_START.0 {
+                total_size.saturating_add(
+                    arch::layout::RAM_64BIT_START.0 - arch::layout::MEM_32BIT_RESERVED_START.0,
+                )
+            } else {
+                total_size
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            total_size.saturating_add(arch::layout::RAM_64BIT_START)
+        }
+    }
+
     pub fn parse(memory: &str, memory_zones: Option<Vec<&str>>) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
@@ -435,7 +919,8 @@ impl MemoryConfig {
             .add("hotplug_size")
             .add("hotplugged_size")
             .add("shared")
-            .add("hugepages");
+            .add("hugepages")
+            .add("crashkernel");
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -470,6 +955,10 @@ impl MemoryConfig {
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let crashkernel = parser
+            .convert::<ByteSized>("crashkernel")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0);
 
         let zones: Option<Vec<MemoryZoneConfig>> = if let Some(memory_zones) = &memory_zones {
             let mut zones = Vec::new();
@@ -540,6 +1029,7 @@ impl MemoryConfig {
             shared,
             hugepages,
             zones,
+            crashkernel,
         })
     }
 
@@ -573,6 +1063,7 @@ impl Default for MemoryConfig {
             shared: false,
             hugepages: false,
             zones: None,
+            crashkernel: None,
         }
     }
 }
@@ -587,6 +1078,17 @@ pub struct InitramfsConfig {
     pub path: PathBuf,
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RecordConfig {
+    pub path: PathBuf,
+}
+
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct EfiVarsConfig {
+    pub path: PathBuf,
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct CmdlineConfig {
     pub args: String,
@@ -625,6 +1127,22 @@ pub struct DiskConfig {
     // For testing use only. Not exposed in API.
     #[serde(default)]
     pub disable_io_uring: bool,
+    #[serde(default)]
+    pub coalesce_events: Option<u32>,
+    #[serde(default)]
+    pub coalesce_usecs: Option<u64>,
+    #[serde(default)]
+    pub queue_affinity: Option<Vec<QueueAffinity>>,
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub boot_index: Option<u16>,
+    #[serde(default)]
+    pub pci_subsystem_vendor_id: Option<u16>,
+    #[serde(default)]
+    pub pci_subsystem_id: Option<u16>,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -653,6 +1171,14 @@ impl Default for DiskConfig {
             poll_queue: default_diskconfig_poll_queue(),
             id: None,
             disable_io_uring: false,
+            coalesce_events: None,
+            coalesce_usecs: None,
+            queue_affinity: None,
+            serial: None,
+            boot_index: None,
+            pci_subsystem_vendor_id: None,
+            pci_subsystem_id: None,
+            key_file: None,
         }
     }
 }
@@ -660,8 +1186,13 @@ impl Default for DiskConfig {
 impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
          \"path=<disk_image_path>,readonly=on|off,iommu=on|off,num_queues=<number_of_queues>,\
-         queue_size=<size_of_each_queue>,vhost_user=<vhost_user_enable>,\
-         socket=<vhost_user_socket_path>, default true>,id=<device_id>\"";
+         queue_size=<size_of_each_queue, power of two>,vhost_user=<vhost_user_enable>,\
+         socket=<vhost_user_socket_path>, default true>,id=<device_id>,\
+         coalesce_events=<max_completions_per_interrupt>,\
+         coalesce_usecs=<max_interrupt_delay_us>,\
+         queue_affinity=<list_of_queue_index@host_cpu>,serial=<serial_number>,\
+         boot_index=<boot_order_index>,pci_subsystem_vendor_id=<subsystem_vendor_id>,\
+         pci_subsystem_id=<subsystem_id>,key_file=<luks_passphrase_file>\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -676,7 +1207,15 @@ impl DiskConfig {
             .add("socket")
             .add("poll_queue")
             .add("id")
-            .add("_disable_io_uring");
+            .add("_disable_io_uring")
+            .add("coalesce_events")
+            .add("coalesce_usecs")
+            .add("queue_affinity")
+            .add("serial")
+            .add("boot_index")
+            .add("pci_subsystem_vendor_id")
+            .add("pci_subsystem_id")
+            .add("key_file");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
@@ -720,6 +1259,25 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or(Toggle(false))
             .0;
+        let coalesce_events = parser
+            .convert("coalesce_events")
+            .map_err(Error::ParseDisk)?;
+        let coalesce_usecs = parser.convert("coalesce_usecs").map_err(Error::ParseDisk)?;
+        let queue_affinity = parser
+            .convert::<TupleTwoIntegers>("queue_affinity")
+            .map_err(Error::ParseDisk)?
+            .map(parse_queue_affinity);
+        let serial = parser.get("serial");
+        let boot_index = parser.convert("boot_index").map_err(Error::ParseDisk)?;
+        let pci_subsystem_vendor_id = parser
+            .convert::<Integer<u16>>("pci_subsystem_vendor_id")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let pci_subsystem_id = parser
+            .convert::<Integer<u16>>("pci_subsystem_id")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let key_file = parser.get("key_file").map(PathBuf::from);
 
         if parser.is_set("poll_queue") && !vhost_user {
             warn!("poll_queue parameter currently only has effect when used vhost_user=true");
@@ -737,8 +1295,93 @@ impl DiskConfig {
             poll_queue,
             id,
             disable_io_uring,
+            coalesce_events,
+            coalesce_usecs,
+            queue_affinity,
+            serial,
+            boot_index,
+            pci_subsystem_vendor_id,
+            pci_subsystem_id,
+            key_file,
         })
     }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.vhost_socket.as_ref().and(self.path.as_ref()).is_some() {
+            return Err(ValidationError::DiskSocketAndPath);
+        }
+        if self.vhost_user && self.vhost_socket.is_none() {
+            return Err(ValidationError::VhostUserMissingSocket);
+        }
+        validate_queue_size(self.queue_size)?;
+        validate_interrupt_coalescing(self.coalesce_events, self.coalesce_usecs, self.vhost_user)?;
+        validate_queue_affinity(&self.queue_affinity, self.num_queues, self.vhost_user)?;
+        if let Some(serial) = &self.serial {
+            if self.vhost_user {
+                return Err(ValidationError::DiskSerialVhostUser);
+            }
+            // The serial ends up in /dev/disk/by-id paths.
+            if serial.len() > MAX_SERIAL_LEN || !serial.chars().all(|c| c.is_ascii_graphic()) {
+                return Err(ValidationError::InvalidDiskSerial(serial.clone()));
+            }
+        }
+        if self.key_file.is_some() && self.vhost_user {
+            return Err(ValidationError::DiskKeyFileVhostUser);
+        }
+        Ok(())
+    }
+}
+
+// Interrupt coalescing is enabled by providing both the maximum number of
+// notifications aggregated into a single interrupt and the maximum delay.
+fn validate_interrupt_coalescing(
+    coalesce_events: Option<u32>,
+    coalesce_usecs: Option<u64>,
+    vhost_user: bool,
+) -> ValidationResult<()> {
+    match (coalesce_events, coalesce_usecs) {
+        (None, None) => Ok(()),
+        (Some(events), Some(usecs)) if events > 0 && usecs > 0 => {
+            if vhost_user {
+                Err(ValidationError::InterruptCoalescingVhostUser)
+            } else {
+                Ok(())
+            }
+        }
+        _ => Err(ValidationError::InvalidInterruptCoalescing),
+    }
+}
+
+// Without vhost-user, each queue, or queue pair for virtio-net, is processed
+// by its own thread which can be pinned to some host CPUs.
+fn validate_queue_affinity(
+    queue_affinity: &Option<Vec<QueueAffinity>>,
+    num_queue_threads: usize,
+    vhost_user: bool,
+) -> ValidationResult<()> {
+    if let Some(queue_affinity) = queue_affinity {
+        if vhost_user {
+            return Err(ValidationError::QueueAffinityVhostUser);
+        }
+        for a in queue_affinity.iter() {
+            if a.queue_index as usize >= num_queue_threads
+                || a.host_cpus.is_empty()
+                || a.host_cpus.iter().any(|c| *c >= libc::CPU_SETSIZE as usize)
+            {
+                return Err(ValidationError::InvalidQueueAffinity(a.queue_index));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The driver relies on the size of a split virtqueue being a power of two.
+fn validate_queue_size(queue_size: u16) -> ValidationResult<()> {
+    if queue_size.is_power_of_two() && queue_size <= MAX_QUEUE_SIZE {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidQueueSize(queue_size))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -766,6 +1409,20 @@ pub struct NetConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub fd: Option<i32>,
+    #[serde(default)]
+    pub coalesce_events: Option<u32>,
+    #[serde(default)]
+    pub coalesce_usecs: Option<u64>,
+    #[serde(default)]
+    pub queue_affinity: Option<Vec<QueueAffinity>>,
+    #[serde(default)]
+    pub boot_index: Option<u16>,
+    #[serde(default)]
+    pub romfile: Option<PathBuf>,
+    #[serde(default)]
+    pub pci_subsystem_vendor_id: Option<u16>,
+    #[serde(default)]
+    pub pci_subsystem_id: Option<u16>,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -807,6 +1464,13 @@ impl Default for NetConfig {
             vhost_socket: None,
             id: None,
             fd: None,
+            coalesce_events: None,
+            coalesce_usecs: None,
+            queue_affinity: None,
+            boot_index: None,
+            romfile: None,
+            pci_subsystem_vendor_id: None,
+            pci_subsystem_id: None,
         }
     }
 }
@@ -814,8 +1478,12 @@ impl Default for NetConfig {
 impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
     \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,fd=<fd>,iommu=on|off,\
-    num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>\"";
+    num_queues=<number_of_queues>,queue_size=<size_of_each_queue, power of two>,\
+    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>,\
+    coalesce_events=<max_notifications_per_interrupt>,coalesce_usecs=<max_interrupt_delay_us>,\
+    queue_affinity=<list_of_queue_pair_index@host_cpu>,\
+    boot_index=<boot_order_index>,romfile=<option_rom_path>,\
+    pci_subsystem_vendor_id=<subsystem_vendor_id>,pci_subsystem_id=<subsystem_id>\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -832,7 +1500,14 @@ impl NetConfig {
             .add("vhost_user")
             .add("socket")
             .add("id")
-            .add("fd");
+            .add("fd")
+            .add("coalesce_events")
+            .add("coalesce_usecs")
+            .add("queue_affinity")
+            .add("boot_index")
+            .add("romfile")
+            .add("pci_subsystem_vendor_id")
+            .add("pci_subsystem_id");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -870,6 +1545,26 @@ impl NetConfig {
         let vhost_socket = parser.get("socket");
         let id = parser.get("id");
         let fd = parser.convert("fd").map_err(Error::ParseNetwork)?;
+        let coalesce_events = parser
+            .convert("coalesce_events")
+            .map_err(Error::ParseNetwork)?;
+        let coalesce_usecs = parser
+            .convert("coalesce_usecs")
+            .map_err(Error::ParseNetwork)?;
+        let queue_affinity = parser
+            .convert::<TupleTwoIntegers>("queue_affinity")
+            .map_err(Error::ParseNetwork)?
+            .map(parse_queue_affinity);
+        let boot_index = parser.convert("boot_index").map_err(Error::ParseNetwork)?;
+        let romfile = parser.get("romfile").map(PathBuf::from);
+        let pci_subsystem_vendor_id = parser
+            .convert::<Integer<u16>>("pci_subsystem_vendor_id")
+            .map_err(Error::ParseNetwork)?
+            .map(|v| v.0);
+        let pci_subsystem_id = parser
+            .convert::<Integer<u16>>("pci_subsystem_id")
+            .map_err(Error::ParseNetwork)?
+            .map(|v| v.0);
         let config = NetConfig {
             tap,
             ip,
@@ -883,6 +1578,13 @@ impl NetConfig {
             vhost_socket,
             id,
             fd,
+            coalesce_events,
+            coalesce_usecs,
+            queue_affinity,
+            boot_index,
+            romfile,
+            pci_subsystem_vendor_id,
+            pci_subsystem_id,
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
@@ -891,6 +1593,9 @@ impl NetConfig {
         if self.num_queues < 2 {
             return Err(ValidationError::VnetQueueLowerThan2);
         }
+        validate_queue_size(self.queue_size)?;
+        validate_interrupt_coalescing(self.coalesce_events, self.coalesce_usecs, self.vhost_user)?;
+        validate_queue_affinity(&self.queue_affinity, self.num_queues / 2, self.vhost_user)?;
         Ok(())
     }
 }
@@ -900,12 +1605,14 @@ pub struct RngConfig {
     pub src: PathBuf,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
 }
 
 impl RngConfig {
     pub fn parse(rng: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("src").add("iommu");
+        parser.add("src").add("iommu").add("rate_limit");
         parser.parse(rng).map_err(Error::ParseRNG)?;
 
         let src = PathBuf::from(
@@ -918,8 +1625,25 @@ impl RngConfig {
             .map_err(Error::ParseRNG)?
             .unwrap_or(Toggle(false))
             .0;
+        let rate_limit = parser
+            .convert::<ByteSized>("rate_limit")
+            .map_err(Error::ParseRNG)?
+            .map(|v| v.0);
+
+        Ok(RngConfig {
+            src,
+            iommu,
+            rate_limit,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        // No entropy would ever be handed to the guest.
+        if self.rate_limit == Some(0) {
+            return Err(ValidationError::InvalidRngRateLimit);
+        }
 
-        Ok(RngConfig { src, iommu })
+        Ok(())
     }
 }
 
@@ -928,21 +1652,27 @@ impl Default for RngConfig {
         RngConfig {
             src: PathBuf::from(DEFAULT_RNG_SOURCE),
             iommu: false,
+            rate_limit: None,
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct BalloonConfig {
     pub size: u64,
+    #[serde(default)]
+    pub policy_min: Option<u64>,
+    #[serde(default)]
+    pub policy_max: Option<u64>,
 }
 
 impl BalloonConfig {
-    pub const SYNTAX: &'static str = "Balloon parameters \"size=<balloon_size>\"";
+    pub const SYNTAX: &'static str = "Balloon parameters \"size=<balloon_size>,\
+        policy_min=<balloon_min_size>,policy_max=<balloon_max_size>\"";
 
     pub fn parse(balloon: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("size");
+        parser.add("size").add("policy_min").add("policy_max");
         parser.parse(balloon).map_err(Error::ParseBalloon)?;
 
         let size = parser
@@ -950,46 +1680,269 @@ impl BalloonConfig {
             .map_err(Error::ParseBalloon)?
             .map(|v| v.0)
             .unwrap_or(0);
+        let policy_min = parser
+            .convert::<ByteSized>("policy_min")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0);
+        let policy_max = parser
+            .convert::<ByteSized>("policy_max")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0);
+
+        Ok(BalloonConfig {
+            size,
+            policy_min,
+            policy_max,
+        })
+    }
 
-        Ok(BalloonConfig { size })
+    pub fn validate(&self) -> ValidationResult<()> {
+        match (self.policy_min, self.policy_max) {
+            (None, None) => Ok(()),
+            (Some(min), Some(max)) if min <= max => Ok(()),
+            _ => Err(ValidationError::InvalidBalloonPolicy),
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct FsConfig {
-    pub tag: String,
-    pub socket: PathBuf,
-    #[serde(default = "default_fsconfig_num_queues")]
-    pub num_queues: usize,
-    #[serde(default = "default_fsconfig_queue_size")]
-    pub queue_size: u16,
-    #[serde(default = "default_fsconfig_dax")]
-    pub dax: bool,
-    #[serde(default = "default_fsconfig_cache_size")]
-    pub cache_size: u64,
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct ResourceGroupConfig {
+    pub name: String,
     #[serde(default)]
-    pub id: Option<String>,
+    pub cpu_quota: Option<u32>,
+    #[serde(default)]
+    pub memory_max: Option<u64>,
+    #[serde(default)]
+    pub io_weight: Option<u16>,
 }
 
-fn default_fsconfig_num_queues() -> usize {
-    1
-}
+impl ResourceGroupConfig {
+    pub const SYNTAX: &'static str = "Control group v2 the VMM is moved into \
+        \"name=<cgroup_name>,cpu_quota=<percentage_of_one_cpu>,memory_max=<memory_limit>,\
+        io_weight=<io_weight>\"";
 
-fn default_fsconfig_queue_size() -> u16 {
-    1024
+    pub fn parse(resource_group: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("name")
+            .add("cpu_quota")
+            .add("memory_max")
+            .add("io_weight");
+        parser
+            .parse(resource_group)
+            .map_err(Error::ParseResourceGroup)?;
+
+        let name = parser
+            .get("name")
+            .ok_or(Error::ParseResourceGroupNameMissing)?;
+        let cpu_quota = parser
+            .convert("cpu_quota")
+            .map_err(Error::ParseResourceGroup)?;
+        let memory_max = parser
+            .convert::<ByteSized>("memory_max")
+            .map_err(Error::ParseResourceGroup)?
+            .map(|v| v.0);
+        let io_weight = parser
+            .convert("io_weight")
+            .map_err(Error::ParseResourceGroup)?;
+
+        Ok(ResourceGroupConfig {
+            name,
+            cpu_quota,
+            memory_max,
+            io_weight,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.name.is_empty() || self.name.contains('/') || self.name.starts_with('.') {
+            return Err(ValidationError::InvalidResourceGroupName(self.name.clone()));
+        }
+
+        if self.cpu_quota == Some(0) {
+            return Err(ValidationError::InvalidResourceGroupCpuQuota);
+        }
+
+        if let Some(io_weight) = self.io_weight {
+            if !(1..=10000).contains(&io_weight) {
+                return Err(ValidationError::InvalidResourceGroupIoWeight(io_weight));
+            }
+        }
+
+        Ok(())
+    }
 }
 
-fn default_fsconfig_dax() -> bool {
-    true
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WorkerPoolConfig {
+    #[serde(default = "default_worker_pool_threads")]
+    pub threads: u8,
 }
 
-fn default_fsconfig_cache_size() -> u64 {
-    0x0002_0000_0000
+fn default_worker_pool_threads() -> u8 {
+    1
 }
 
-impl Default for FsConfig {
-    fn default() -> Self {
-        Self {
+impl WorkerPoolConfig {
+    pub const SYNTAX: &'static str = "Threads shared by the virtio-blk and virtio-net \
+        devices, instead of one thread per queue \"threads=<number_of_threads>\"";
+
+    pub fn parse(worker_pool: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("threads");
+        parser.parse(worker_pool).map_err(Error::ParseWorkerPool)?;
+
+        let threads = parser
+            .convert("threads")
+            .map_err(Error::ParseWorkerPool)?
+            .unwrap_or_else(default_worker_pool_threads);
+
+        Ok(WorkerPoolConfig { threads })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.threads == 0 {
+            return Err(ValidationError::InvalidWorkerPoolThreads);
+        }
+
+        Ok(())
+    }
+}
+
+/// Synchronization of the guest wall clock with the host one, once the VM
+/// is resumed from a pause or a snapshot.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct ClockConfig {
+    /// Console port of the QEMU guest agent, asked to set the guest time.
+    #[serde(default)]
+    pub guest_agent: Option<String>,
+}
+
+impl ClockConfig {
+    pub const SYNTAX: &'static str = "Guest wall clock synchronization when resuming the VM \
+        \"guest_agent=<console_port_name>\"";
+
+    pub fn parse(clock: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("guest_agent");
+        parser.parse(clock).map_err(Error::ParseClock)?;
+
+        let guest_agent = parser.get("guest_agent");
+
+        Ok(ClockConfig { guest_agent })
+    }
+}
+
+/// Metadata service reachable from the guest, serving the identity of the VM
+/// and its user data in the EC2 and OpenStack formats.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MetadataConfig {
+    #[serde(default = "default_metadata_address")]
+    pub address: SocketAddr,
+    /// Host interface, normally the tap of a network device of the VM, the
+    /// service is only reachable through.
+    pub tap: String,
+    pub instance_id: String,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// File holding the user data, served as is.
+    #[serde(default)]
+    pub user_data: Option<PathBuf>,
+    /// File holding the SSH public keys, one per line.
+    #[serde(default)]
+    pub ssh_keys: Option<PathBuf>,
+}
+
+fn default_metadata_address() -> SocketAddr {
+    SocketAddr::from(([169, 254, 169, 254], 80))
+}
+
+impl MetadataConfig {
+    pub const SYNTAX: &'static str = "Metadata service serving the VM identity and user \
+        data in the EC2 and OpenStack formats \"tap=<if_name>,instance_id=<instance_id>,\
+        hostname=<hostname>,user_data=</path/to/user_data>,\
+        ssh_keys=</path/to/authorized_keys>,address=<ip:port>\"";
+
+    pub fn parse(metadata: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("tap")
+            .add("instance_id")
+            .add("hostname")
+            .add("user_data")
+            .add("ssh_keys")
+            .add("address");
+        parser.parse(metadata).map_err(Error::ParseMetadata)?;
+
+        let tap = parser.get("tap").ok_or(Error::ParseMetadataTapMissing)?;
+        let instance_id = parser
+            .get("instance_id")
+            .ok_or(Error::ParseMetadataInstanceIdMissing)?;
+        let hostname = parser.get("hostname");
+        let user_data = parser.get("user_data").map(PathBuf::from);
+        let ssh_keys = parser.get("ssh_keys").map(PathBuf::from);
+        let address = parser
+            .convert("address")
+            .map_err(Error::ParseMetadata)?
+            .unwrap_or_else(default_metadata_address);
+
+        Ok(MetadataConfig {
+            address,
+            tap,
+            instance_id,
+            hostname,
+            user_data,
+            ssh_keys,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.instance_id.is_empty() {
+            return Err(ValidationError::InvalidMetadataInstanceId);
+        }
+        if self.tap.is_empty() || self.tap.len() >= libc::IFNAMSIZ {
+            return Err(ValidationError::InvalidMetadataTap(self.tap.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FsConfig {
+    pub tag: String,
+    pub socket: PathBuf,
+    #[serde(default = "default_fsconfig_num_queues")]
+    pub num_queues: usize,
+    #[serde(default = "default_fsconfig_queue_size")]
+    pub queue_size: u16,
+    #[serde(default = "default_fsconfig_dax")]
+    pub dax: bool,
+    #[serde(default = "default_fsconfig_cache_size")]
+    pub cache_size: u64,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+fn default_fsconfig_num_queues() -> usize {
+    1
+}
+
+fn default_fsconfig_queue_size() -> u16 {
+    1024
+}
+
+fn default_fsconfig_dax() -> bool {
+    true
+}
+
+fn default_fsconfig_cache_size() -> u64 {
+    0x0002_0000_0000
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
             tag: "".to_owned(),
             socket: PathBuf::new(),
             num_queues: default_fsconfig_num_queues(),
@@ -1004,7 +1957,7 @@ impl Default for FsConfig {
 impl FsConfig {
     pub const SYNTAX: &'static str = "virtio-fs parameters \
     \"tag=<tag_name>,socket=<socket_path>,num_queues=<number_of_queues>,\
-    queue_size=<size_of_each_queue>,dax=on|off,cache_size=<DAX cache size: \
+    queue_size=<size_of_each_queue, power of two>,dax=on|off,cache_size=<DAX cache size: \
     default 8Gib>,id=<device_id>\"";
 
     pub fn parse(fs: &str) -> Result<Self> {
@@ -1059,6 +2012,10 @@ impl FsConfig {
             id,
         })
     }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        validate_queue_size(self.queue_size)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
@@ -1073,13 +2030,15 @@ pub struct PmemConfig {
     #[serde(default)]
     pub discard_writes: bool,
     #[serde(default)]
+    pub nvdimm: bool,
+    #[serde(default)]
     pub id: Option<String>,
 }
 
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
-    mergeable=on|off,discard_writes=on|off,id=<device_id>\"";
+    mergeable=on|off,discard_writes=on|off,nvdimm=on|off,id=<device_id>\"";
     pub fn parse(pmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
@@ -1088,6 +2047,7 @@ impl PmemConfig {
             .add("mergeable")
             .add("iommu")
             .add("discard_writes")
+            .add("nvdimm")
             .add("id");
         parser.parse(pmem).map_err(Error::ParsePersistentMemory)?;
 
@@ -1111,6 +2071,11 @@ impl PmemConfig {
             .map_err(Error::ParsePersistentMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let nvdimm = parser
+            .convert::<Toggle>("nvdimm")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
         let id = parser.get("id");
 
         Ok(PmemConfig {
@@ -1119,6 +2084,7 @@ impl PmemConfig {
             iommu,
             mergeable,
             discard_writes,
+            nvdimm,
             id,
         })
     }
@@ -1127,6 +2093,7 @@ impl PmemConfig {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum ConsoleOutputMode {
     Off,
+    Pty,
     Tty,
     File,
     Null,
@@ -1145,6 +2112,12 @@ pub struct ConsoleConfig {
     pub mode: ConsoleOutputMode,
     #[serde(default)]
     pub iommu: bool,
+    /// Size the output file can grow to before being rotated.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Number of rotated output files kept, 1 by default.
+    #[serde(default)]
+    pub rotate: Option<u32>,
 }
 
 fn default_consoleconfig_file() -> Option<PathBuf> {
@@ -1156,16 +2129,21 @@ impl ConsoleConfig {
         let mut parser = OptionParser::new();
         parser
             .add_valueless("off")
+            .add_valueless("pty")
             .add_valueless("tty")
             .add_valueless("null")
             .add("file")
-            .add("iommu");
+            .add("iommu")
+            .add("max_size")
+            .add("rotate");
         parser.parse(console).map_err(Error::ParseConsole)?;
 
         let mut file: Option<PathBuf> = default_consoleconfig_file();
         let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
 
         if parser.is_set("off") {
+        } else if parser.is_set("pty") {
+            mode = ConsoleOutputMode::Pty
         } else if parser.is_set("tty") {
             mode = ConsoleOutputMode::Tty
         } else if parser.is_set("null") {
@@ -1184,8 +2162,38 @@ impl ConsoleConfig {
             .map_err(Error::ParseConsole)?
             .unwrap_or(Toggle(false))
             .0;
+        let max_size = parser
+            .convert::<ByteSized>("max_size")
+            .map_err(Error::ParseConsole)?
+            .map(|v| v.0);
+        let rotate = parser.convert("rotate").map_err(Error::ParseConsole)?;
+
+        Ok(Self {
+            mode,
+            file,
+            iommu,
+            max_size,
+            rotate,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.mode == ConsoleOutputMode::File && self.file.is_none() {
+            return Err(ValidationError::ConsoleFileMissing);
+        }
 
-        Ok(Self { mode, file, iommu })
+        if self.max_size.is_some() || self.rotate.is_some() {
+            if self.mode != ConsoleOutputMode::File {
+                return Err(ValidationError::ConsoleRotationWithoutFile);
+            }
+            match self.max_size {
+                None => return Err(ValidationError::ConsoleRotateWithoutMaxSize),
+                Some(0) => return Err(ValidationError::InvalidConsoleMaxSize),
+                _ => {}
+            }
+        }
+
+        Ok(())
     }
 
     pub fn default_serial() -> Self {
@@ -1193,6 +2201,8 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Null,
             iommu: false,
+            max_size: None,
+            rotate: None,
         }
     }
 
@@ -1201,7 +2211,129 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Tty,
             iommu: false,
+            max_size: None,
+            rotate: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ConsolePortMode {
+    Pty,
+    File,
+    Socket,
+    Device,
+}
+
+/// Additional port of the virtio-console device, which the guest finds from
+/// its name.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ConsolePortConfig {
+    pub name: String,
+    pub mode: ConsolePortMode,
+    /// Output file, or pseudo terminal allocated in pty mode.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    /// Host character device, a serial port for instance.
+    #[serde(default)]
+    pub device: Option<PathBuf>,
+}
+
+impl ConsolePortConfig {
+    pub const SYNTAX: &'static str = "Additional virtio-console port, found by the guest \
+        from its name \"name=<port_name>,pty|file=</path/to/a/file>|socket=</path/to/a/socket>\
+        |device=</path/to/a/char/device>\"";
+
+    pub fn parse(console_port: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("name")
+            .add_valueless("pty")
+            .add("file")
+            .add("socket")
+            .add("device");
+        parser
+            .parse(console_port)
+            .map_err(Error::ParseConsolePort)?;
+
+        let name = parser
+            .get("name")
+            .ok_or(Error::ParseConsolePortNameMissing)?;
+        let file = parser.get("file").map(PathBuf::from);
+        let socket = parser.get("socket").map(PathBuf::from);
+        let device = parser.get("device").map(PathBuf::from);
+        // A port is connected to a single endpoint.
+        let modes = [
+            parser.is_set("pty"),
+            file.is_some(),
+            socket.is_some(),
+            device.is_some(),
+        ];
+        if modes.iter().filter(|set| **set).count() != 1 {
+            return Err(Error::ParseConsolePortInvalidModeGiven);
+        }
+        let mode = if parser.is_set("pty") {
+            ConsolePortMode::Pty
+        } else if file.is_some() {
+            ConsolePortMode::File
+        } else if socket.is_some() {
+            ConsolePortMode::Socket
+        } else if device.is_some() {
+            ConsolePortMode::Device
+        } else {
+            return Err(Error::ParseConsolePortInvalidModeGiven);
+        };
+
+        Ok(ConsolePortConfig {
+            name,
+            mode,
+            file,
+            socket,
+            device,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        // The name ends up in /dev/virtio-ports paths.
+        if self.name.is_empty()
+            || self.name.contains('/')
+            || !self.name.chars().all(|c| c.is_ascii_graphic())
+        {
+            return Err(ValidationError::InvalidConsolePortName(self.name.clone()));
+        }
+
+        let path_missing = match self.mode {
+            ConsolePortMode::Pty => false,
+            ConsolePortMode::File => self.file.is_none(),
+            ConsolePortMode::Socket => self.socket.is_none(),
+            ConsolePortMode::Device => self.device.is_none(),
+        };
+        if path_missing {
+            return Err(ValidationError::ConsolePortPathMissing(self.name.clone()));
         }
+
+        // The path of the pseudo terminal of a pty port is reported through
+        // its file, hence found again on reboot or restore.
+        let paths = [
+            (
+                &self.file,
+                self.mode == ConsolePortMode::File || self.mode == ConsolePortMode::Pty,
+            ),
+            (&self.socket, self.mode == ConsolePortMode::Socket),
+            (&self.device, self.mode == ConsolePortMode::Device),
+        ];
+        if paths
+            .iter()
+            .any(|(path, expected)| path.is_some() && !expected)
+        {
+            return Err(ValidationError::ConsolePortPathUnexpected(
+                self.name.clone(),
+            ));
+        }
+
+        Ok(())
     }
 }
 
@@ -1212,14 +2344,27 @@ pub struct DeviceConfig {
     pub iommu: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub romfile: Option<PathBuf>,
+    #[serde(default)]
+    pub pci_class_code: Option<u32>,
+    #[serde(default)]
+    pub pci_revision: Option<u8>,
 }
 
 impl DeviceConfig {
-    pub const SYNTAX: &'static str =
-        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>\"";
+    pub const SYNTAX: &'static str = "Direct device assignment parameters \
+        \"path=<device_path>,iommu=on|off,id=<device_id>,romfile=<option_rom_path>,\
+        pci_class_code=<class_code>,pci_revision=<revision_id>\"";
     pub fn parse(device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("path").add("id").add("iommu");
+        parser
+            .add("path")
+            .add("id")
+            .add("iommu")
+            .add("romfile")
+            .add("pci_class_code")
+            .add("pci_revision");
         parser.parse(device).map_err(Error::ParseDevice)?;
 
         let path = parser
@@ -1232,7 +2377,32 @@ impl DeviceConfig {
             .unwrap_or(Toggle(false))
             .0;
         let id = parser.get("id");
-        Ok(DeviceConfig { path, iommu, id })
+        let romfile = parser.get("romfile").map(PathBuf::from);
+        let pci_class_code = parser
+            .convert::<Integer<u32>>("pci_class_code")
+            .map_err(Error::ParseDevice)?
+            .map(|v| v.0);
+        let pci_revision = parser
+            .convert::<Integer<u8>>("pci_revision")
+            .map_err(Error::ParseDevice)?
+            .map(|v| v.0);
+        Ok(DeviceConfig {
+            path,
+            iommu,
+            id,
+            romfile,
+            pci_class_code,
+            pci_revision,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if let Some(class_code) = self.pci_class_code {
+            if class_code > 0xff_ffff {
+                return Err(ValidationError::InvalidPciClassCode(class_code));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -1311,6 +2481,154 @@ impl SgxEpcConfig {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct ReservedMemoryConfig {
+    pub start: u64,
+    pub size: u64,
+    #[serde(default)]
+    pub nvs: bool,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl ReservedMemoryConfig {
+    pub const SYNTAX: &'static str = "Range reserved in the guest memory map \
+        \"start=<range_start_address>,size=<range_size>,nvs=on|off\"";
+    pub fn parse(reserved_memory: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("start").add("size").add("nvs");
+        parser
+            .parse(reserved_memory)
+            .map_err(Error::ParseReservedMemory)?;
+
+        let start = parser
+            .convert::<ByteSized>("start")
+            .map_err(Error::ParseReservedMemory)?
+            .ok_or(Error::ParseReservedMemoryRangeMissing)?
+            .0;
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseReservedMemory)?
+            .ok_or(Error::ParseReservedMemoryRangeMissing)?
+            .0;
+        let nvs = parser
+            .convert::<Toggle>("nvs")
+            .map_err(Error::ParseReservedMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(ReservedMemoryConfig { start, size, nvs })
+    }
+
+    pub fn validate(&self, memory: &MemoryConfig) -> ValidationResult<()> {
+        if self.size == 0
+            || self.start % (4 << 10) != 0
+            || self.size % (4 << 10) != 0
+            || self.start.checked_add(self.size).is_none()
+        {
+            return Err(ValidationError::InvalidReservedMemory(self.start));
+        }
+
+        // The boot RAM starts at 0, and its part not fitting below the
+        // 32-bit hole, the hotpluggable RAM and the 64-bit device area are
+        // all laid out from 4GiB up to the end of the address space. The
+        // 32-bit hole is only free around the local and I/O APICs, after the
+        // PCI MMCONFIG space and before the pages KVM uses for the TSS.
+        let mut boot_size = memory.size;
+        if let Some(zones) = &memory.zones {
+            for zone in zones.iter() {
+                boot_size = boot_size.saturating_add(zone.size);
+            }
+        }
+        let used_areas = [
+            (
+                0,
+                std::cmp::min(boot_size, arch::layout::MEM_32BIT_RESERVED_START.0),
+            ),
+            (
+                arch::layout::MEM_32BIT_DEVICES_START.0,
+                arch::layout::PCI_MMCONFIG_START.0 + arch::layout::PCI_MMCONFIG_SIZE,
+            ),
+            (
+                arch::layout::IOAPIC_START.0,
+                arch::layout::IOAPIC_START.0 + (4 << 10),
+            ),
+            (
+                arch::layout::APIC_START.0,
+                arch::layout::APIC_START.0 + (4 << 10),
+            ),
+            (arch::layout::KVM_TSS_ADDRESS.0, std::u64::MAX),
+        ];
+        let end = self.start + self.size;
+        if used_areas
+            .iter()
+            .any(|(area_start, area_end)| self.start < *area_end && *area_start < end)
+        {
+            return Err(ValidationError::ReservedMemoryOverlap(self.start));
+        }
+
+        Ok(())
+    }
+}
+
+/// Identifiers of the platform, reported to the guest through the SMBIOS
+/// system information.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct PlatformConfig {
+    #[serde(default)]
+    pub serial_number: Option<String>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PlatformConfig {
+    pub const SYNTAX: &'static str = "Platform identifiers reported through SMBIOS \
+        \"serial_number=<serial_number>,uuid=<uuid>\"";
+    pub fn parse(platform: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("serial_number").add("uuid");
+        parser.parse(platform).map_err(Error::ParsePlatform)?;
+
+        let serial_number = parser.get("serial_number");
+        let uuid = parser.get("uuid");
+
+        Ok(PlatformConfig {
+            serial_number,
+            uuid,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if let Some(serial_number) = &self.serial_number {
+            // An empty string would end the SMBIOS string set.
+            if serial_number.is_empty()
+                || !serial_number
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() || c == ' ')
+            {
+                return Err(ValidationError::InvalidPlatformSerialNumber(
+                    serial_number.clone(),
+                ));
+            }
+        }
+
+        if let Some(uuid) = &self.uuid {
+            let valid = uuid.len() == 36
+                && uuid.char_indices().all(|(i, c)| match i {
+                    8 | 13 | 18 | 23 => c == '-',
+                    _ => c.is_ascii_hexdigit(),
+                });
+            if !valid {
+                return Err(ValidationError::InvalidPlatformUuid(uuid.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct NumaDistance {
     #[serde(default)]
@@ -1324,7 +2642,7 @@ pub struct NumaConfig {
     #[serde(default)]
     pub guest_numa_id: u32,
     #[serde(default)]
-    pub cpus: Option<Vec<u8>>,
+    pub cpus: Option<Vec<u16>>,
     #[serde(default)]
     pub distances: Option<Vec<NumaDistance>>,
     #[serde(default)]
@@ -1334,7 +2652,8 @@ pub struct NumaConfig {
 impl NumaConfig {
     pub const SYNTAX: &'static str = "Settings related to a given NUMA node \
         \"guest_numa_id=<node_id>,cpus=<cpus_id>,distances=<list_of_distances_to_destination_nodes>,\
-        memory_zones=<list_of_memory_zones>\"";
+        memory_zones=<list_of_memory_zones>\" \
+        \n\"auto\" pins vCPUs and binds memory zones to matching host NUMA nodes";
     pub fn parse(numa: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
@@ -1351,7 +2670,7 @@ impl NumaConfig {
         let cpus = parser
             .convert::<IntegerList>("cpus")
             .map_err(Error::ParseNuma)?
-            .map(|v| v.0.iter().map(|e| *e as u8).collect());
+            .map(|v| v.0.iter().map(|e| *e as u16).collect());
         let distances = parser
             .convert::<TupleTwoIntegers>("distances")
             .map_err(Error::ParseNuma)?
@@ -1377,22 +2696,136 @@ impl NumaConfig {
     }
 }
 
+/// Host specific parts of a disk configuration, overridden on restore.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct RestoreConfig {
-    pub source_url: PathBuf,
+pub struct RestoredDiskConfig {
+    pub id: String,
     #[serde(default)]
-    pub prefault: bool,
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub vhost_socket: Option<String>,
 }
 
-impl RestoreConfig {
-    pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
-        \nRestore parameters \"source_url=<source_url>,prefault=on|off\" \
-        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
-        \n`prefault` brings memory pages in when enabled (disabled by default)";
-    pub fn parse(restore: &str) -> Result<Self> {
-        let mut parser = OptionParser::new();
-        parser.add("source_url").add("prefault");
-        parser.parse(restore).map_err(Error::ParseRestore)?;
+/// Host specific parts of a network configuration, overridden on restore.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct RestoredNetConfig {
+    pub id: String,
+    #[serde(default)]
+    pub tap: Option<String>,
+    #[serde(default)]
+    pub fd: Option<i32>,
+    #[serde(default)]
+    pub vhost_socket: Option<String>,
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+}
+
+/// Host specific parts of a virtio-fs configuration, overridden on restore.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct RestoredFsConfig {
+    pub id: String,
+    pub socket: PathBuf,
+}
+
+/// Parts of the vsock configuration overridden on restore, which can't be
+/// shared by a clone and the VM it is cloned from.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct RestoredVsockConfig {
+    #[serde(default)]
+    pub cid: Option<u64>,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct RestoreConfig {
+    pub source_url: PathBuf,
+    #[serde(default)]
+    pub prefault: bool,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub clone: bool,
+    #[serde(default)]
+    pub disks: Option<Vec<RestoredDiskConfig>>,
+    #[serde(default)]
+    pub net: Option<Vec<RestoredNetConfig>>,
+    #[serde(default)]
+    pub fs: Option<Vec<RestoredFsConfig>>,
+    #[serde(default)]
+    pub vsock: Option<RestoredVsockConfig>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub platform: Option<PlatformConfig>,
+}
+
+// Parses a list of "<id>@<value>" overrides, separated by ':'.
+fn parse_restore_overrides(parser: &OptionParser, option: &str) -> Result<Vec<(String, String)>> {
+    let list = match parser
+        .convert::<StringList>(option)
+        .map_err(Error::ParseRestore)?
+    {
+        Some(list) => list.0,
+        None => return Ok(Vec::new()),
+    };
+
+    list.iter()
+        .map(|item| {
+            let parts: Vec<&str> = item.splitn(2, '@').collect();
+            if parts.len() != 2 || parts[0].is_empty() || parts[1].is_empty() {
+                return Err(Error::ParseRestore(OptionParserError::InvalidSyntax(
+                    item.to_owned(),
+                )));
+            }
+            Ok((parts[0].to_owned(), parts[1].to_owned()))
+        })
+        .collect()
+}
+
+fn restore_overrides_list<T>(overrides: BTreeMap<String, T>) -> Option<Vec<T>> {
+    if overrides.is_empty() {
+        None
+    } else {
+        Some(overrides.into_iter().map(|(_, device)| device).collect())
+    }
+}
+
+impl RestoreConfig {
+    pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
+        \nRestore parameters \"source_url=<source_url>,prefault=on|off,key_file=<key_file>,\
+        clone=on|off,disk_path=<id@path>,disk_socket=<id@socket>,net_tap=<id@tap>,\
+        net_fd=<id@fd>,net_socket=<id@socket>,net_mac=<id@mac>,fs_socket=<id@socket>,\
+        vsock_cid=<context_id>,vsock_socket=<socket_path>,serial_number=<serial_number>,\
+        uuid=<uuid>\" \
+        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
+        \n`prefault` brings memory pages in when enabled (disabled by default) \
+        \n`key_file` contains the key an encrypted snapshot is decrypted with \
+        \n`clone` maps the memory copy-on-write from the snapshot, shared with the \
+        other clones, and gives new MAC addresses to the network devices (disabled by default) \
+        \nThe other parameters override the host specific parts and the identifiers of \
+        the saved configuration, for the devices identified by `id`, several devices being \
+        separated by ':' (e.g net_tap=_net2@tap0:_net3@tap1), the bytes of the MAC addresses \
+        being separated by '-' (e.g net_mac=_net2@12-34-56-78-9a-bc) \
+        \n`serial_number` and `uuid` are reported through SMBIOS from the next reboot";
+    pub fn parse(restore: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("source_url")
+            .add("prefault")
+            .add("clone")
+            .add("key_file")
+            .add("disk_path")
+            .add("disk_socket")
+            .add("net_tap")
+            .add("net_fd")
+            .add("net_socket")
+            .add("net_mac")
+            .add("fs_socket")
+            .add("vsock_cid")
+            .add("vsock_socket");
+        #[cfg(target_arch = "x86_64")]
+        parser.add("serial_number").add("uuid");
+        parser.parse(restore).map_err(Error::ParseRestore)?;
 
         let source_url = parser
             .get("source_url")
@@ -1403,10 +2836,322 @@ impl RestoreConfig {
             .map_err(Error::ParseRestore)?
             .unwrap_or(Toggle(false))
             .0;
+        let key_file = parser.get("key_file").map(PathBuf::from);
+        let clone = parser
+            .convert::<Toggle>("clone")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        let mut disks = BTreeMap::new();
+        for (id, path) in parse_restore_overrides(&parser, "disk_path")? {
+            disks
+                .entry(id.clone())
+                .or_insert_with(|| RestoredDiskConfig {
+                    id,
+                    ..Default::default()
+                })
+                .path = Some(PathBuf::from(path));
+        }
+        for (id, socket) in parse_restore_overrides(&parser, "disk_socket")? {
+            disks
+                .entry(id.clone())
+                .or_insert_with(|| RestoredDiskConfig {
+                    id,
+                    ..Default::default()
+                })
+                .vhost_socket = Some(socket);
+        }
+
+        let mut net = BTreeMap::new();
+        for (id, tap) in parse_restore_overrides(&parser, "net_tap")? {
+            net.entry(id.clone())
+                .or_insert_with(|| RestoredNetConfig {
+                    id,
+                    ..Default::default()
+                })
+                .tap = Some(tap);
+        }
+        for (id, fd) in parse_restore_overrides(&parser, "net_fd")? {
+            let fd = fd.parse().map_err(|_| {
+                Error::ParseRestore(OptionParserError::Conversion("net_fd".to_owned(), fd))
+            })?;
+            net.entry(id.clone())
+                .or_insert_with(|| RestoredNetConfig {
+                    id,
+                    ..Default::default()
+                })
+                .fd = Some(fd);
+        }
+        for (id, socket) in parse_restore_overrides(&parser, "net_socket")? {
+            net.entry(id.clone())
+                .or_insert_with(|| RestoredNetConfig {
+                    id,
+                    ..Default::default()
+                })
+                .vhost_socket = Some(socket);
+        }
+        for (id, mac) in parse_restore_overrides(&parser, "net_mac")? {
+            let mac = MacAddr::parse_str(&mac.replace('-', ":")).map_err(|_| {
+                Error::ParseRestore(OptionParserError::Conversion("net_mac".to_owned(), mac))
+            })?;
+            net.entry(id.clone())
+                .or_insert_with(|| RestoredNetConfig {
+                    id,
+                    ..Default::default()
+                })
+                .mac = Some(mac);
+        }
+
+        let mut fs = BTreeMap::new();
+        for (id, socket) in parse_restore_overrides(&parser, "fs_socket")? {
+            fs.insert(
+                id.clone(),
+                RestoredFsConfig {
+                    id,
+                    socket: PathBuf::from(socket),
+                },
+            );
+        }
+
+        let vsock_cid = parser
+            .convert::<u64>("vsock_cid")
+            .map_err(Error::ParseRestore)?;
+        let vsock_socket = parser.get("vsock_socket").map(PathBuf::from);
+        let vsock = if vsock_cid.is_some() || vsock_socket.is_some() {
+            Some(RestoredVsockConfig {
+                cid: vsock_cid,
+                socket: vsock_socket,
+            })
+        } else {
+            None
+        };
+
+        #[cfg(target_arch = "x86_64")]
+        let platform = {
+            let serial_number = parser.get("serial_number");
+            let uuid = parser.get("uuid");
+            if serial_number.is_some() || uuid.is_some() {
+                Some(PlatformConfig {
+                    serial_number,
+                    uuid,
+                })
+            } else {
+                None
+            }
+        };
 
         Ok(RestoreConfig {
             source_url,
             prefault,
+            key_file,
+            clone,
+            disks: restore_overrides_list(disks),
+            net: restore_overrides_list(net),
+            fs: restore_overrides_list(fs),
+            vsock,
+            #[cfg(target_arch = "x86_64")]
+            platform,
+        })
+    }
+
+    /// Override the host specific parts of the configuration saved in the
+    /// snapshot, so that it can be restored on a host with a different layout,
+    /// or alongside the VM it is cloned from.
+    pub fn apply_overrides(&self, config: &mut VmConfig) -> ValidationResult<()> {
+        if self.clone {
+            let memory = &config.memory;
+            let zones = memory.zones.iter().flatten();
+            if memory.shared || memory.hugepages || zones.any(|z| z.shared || z.hugepages) {
+                return Err(ValidationError::CloneSharedMemory);
+            }
+
+            // The clones run side by side with the VM they're cloned from,
+            // unless given explicit MAC addresses below.
+            for net in config.net.iter_mut().flatten() {
+                net.mac = MacAddr::local_random();
+            }
+        }
+
+        for restored in self.disks.iter().flatten() {
+            let disk = config
+                .disks
+                .iter_mut()
+                .flatten()
+                .find(|disk| disk.id.as_ref() == Some(&restored.id))
+                .ok_or_else(|| ValidationError::RestoreDeviceUnknown(restored.id.clone()))?;
+            if restored.path.is_some() {
+                disk.path = restored.path.clone();
+            }
+            if restored.vhost_socket.is_some() {
+                disk.vhost_socket = restored.vhost_socket.clone();
+            }
+        }
+
+        for restored in self.net.iter().flatten() {
+            let net = config
+                .net
+                .iter_mut()
+                .flatten()
+                .find(|net| net.id.as_ref() == Some(&restored.id))
+                .ok_or_else(|| ValidationError::RestoreDeviceUnknown(restored.id.clone()))?;
+            if restored.tap.is_some() {
+                net.tap = restored.tap.clone();
+                net.fd = None;
+            }
+            if restored.fd.is_some() {
+                net.fd = restored.fd;
+                net.tap = None;
+            }
+            if restored.vhost_socket.is_some() {
+                net.vhost_socket = restored.vhost_socket.clone();
+            }
+            if let Some(mac) = restored.mac {
+                net.mac = mac;
+            }
+        }
+
+        for restored in self.fs.iter().flatten() {
+            let fs = config
+                .fs
+                .iter_mut()
+                .flatten()
+                .find(|fs| fs.id.as_ref() == Some(&restored.id))
+                .ok_or_else(|| ValidationError::RestoreDeviceUnknown(restored.id.clone()))?;
+            fs.socket = restored.socket.clone();
+        }
+
+        if let Some(restored) = &self.vsock {
+            let vsock = config
+                .vsock
+                .as_mut()
+                .ok_or(ValidationError::RestoreVsockMissing)?;
+            if let Some(cid) = restored.cid {
+                vsock.cid = cid;
+            }
+            if let Some(socket) = &restored.socket {
+                vsock.socket = socket.clone();
+            }
+        }
+
+        // The SMBIOS tables the guest already read are part of its memory,
+        // so the new identifiers are only reported from the next reboot.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(restored) = &self.platform {
+                let platform = config.platform.get_or_insert_with(Default::default);
+                if restored.serial_number.is_some() {
+                    platform.serial_number = restored.serial_number.clone();
+                }
+                if restored.uuid.is_some() {
+                    platform.uuid = restored.uuid.clone();
+                }
+                platform.validate()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsConfig {
+    pub address: SocketAddr,
+}
+
+impl MetricsConfig {
+    pub const SYNTAX: &'static str = "Prometheus metrics exporter \
+        \nMetrics parameters \"tcp=<ip:port>\" \
+        \nThe device and vCPU counters, along with the migration progress, \
+        are served in Prometheus text format on http://<ip:port>/metrics";
+    pub fn parse(metrics: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("tcp");
+        parser.parse(metrics).map_err(Error::ParseMetrics)?;
+
+        let address = parser
+            .convert("tcp")
+            .map_err(Error::ParseMetrics)?
+            .ok_or(Error::ParseMetricsAddressMissing)?;
+
+        Ok(MetricsConfig { address })
+    }
+}
+
+/// W3C trace context of the span the exported spans are children of.
+#[cfg(feature = "otlp")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+#[cfg(feature = "otlp")]
+#[derive(Debug)]
+pub enum ParseTraceParentError {
+    InvalidValue(String),
+}
+
+#[cfg(feature = "otlp")]
+impl FromStr for TraceParent {
+    type Err = ParseTraceParentError;
+
+    // "<version>-<trace_id>-<parent_id>-<flags>", in lowercase hexadecimal,
+    // the identifiers not being all zeros.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let is_id = |id: &str, len: usize| {
+            id.len() == len
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+                && id.bytes().any(|b| b != b'0')
+        };
+        let parts: Vec<&str> = s.split('-').collect();
+        if parts.len() != 4
+            || parts[0] != "00"
+            || !is_id(parts[1], 32)
+            || !is_id(parts[2], 16)
+            || parts[3].len() != 2
+        {
+            return Err(ParseTraceParentError::InvalidValue(s.to_owned()));
+        }
+
+        Ok(TraceParent {
+            trace_id: parts[1].to_owned(),
+            span_id: parts[2].to_owned(),
+        })
+    }
+}
+
+#[cfg(feature = "otlp")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtlpConfig {
+    pub endpoint: SocketAddr,
+    pub trace_parent: Option<TraceParent>,
+}
+
+#[cfg(feature = "otlp")]
+impl OtlpConfig {
+    pub const SYNTAX: &'static str = "OpenTelemetry spans exporter \
+        \nOTLP parameters \"endpoint=<ip:port>,traceparent=<traceparent>\" \
+        \n`endpoint` is the address of the OTLP/HTTP collector the spans of the \
+        boot, hotplug, snapshot, restore and migration operations are sent to \
+        \n`traceparent` is the W3C trace context of the span these spans are \
+        children of (e.g 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01)";
+    pub fn parse(otlp: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("endpoint").add("traceparent");
+        parser.parse(otlp).map_err(Error::ParseOtlp)?;
+
+        let endpoint = parser
+            .convert("endpoint")
+            .map_err(Error::ParseOtlp)?
+            .ok_or(Error::ParseOtlpEndpointMissing)?;
+        let trace_parent = parser.convert("traceparent").map_err(Error::ParseOtlp)?;
+
+        Ok(OtlpConfig {
+            endpoint,
+            trace_parent,
         })
     }
 }
@@ -1433,15 +3178,40 @@ pub struct VmConfig {
     pub serial: ConsoleConfig,
     #[serde(default = "ConsoleConfig::default_console")]
     pub console: ConsoleConfig,
+    #[serde(default)]
+    pub console_ports: Option<Vec<ConsolePortConfig>>,
     pub devices: Option<Vec<DeviceConfig>>,
     pub vsock: Option<VsockConfig>,
     #[serde(default)]
     pub iommu: bool,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub reserved_memory: Option<Vec<ReservedMemoryConfig>>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub platform: Option<PlatformConfig>,
     pub numa: Option<Vec<NumaConfig>>,
     #[serde(default)]
+    pub numa_auto: bool,
+    #[serde(default)]
     pub watchdog: bool,
+    #[serde(default)]
+    pub on_crash: OnCrashAction,
+    #[serde(default)]
+    pub resource_group: Option<ResourceGroupConfig>,
+    #[serde(default)]
+    pub record: Option<RecordConfig>,
+    #[serde(default)]
+    pub worker_pool: Option<WorkerPoolConfig>,
+    #[serde(default)]
+    pub clock: Option<ClockConfig>,
+    #[serde(default)]
+    pub metadata: Option<MetadataConfig>,
+    #[cfg(target_arch = "aarch64")]
+    #[serde(default)]
+    pub efi_vars: Option<EfiVarsConfig>,
 }
 
 impl VmConfig {
@@ -1453,29 +3223,43 @@ impl VmConfig {
             return Err(ValidationError::DoubleTtyMode);
         }
 
-        if self.console.mode == ConsoleOutputMode::File && self.console.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
-        }
+        self.console.validate()?;
+        self.serial.validate()?;
+        self.rng.validate()?;
 
-        if self.serial.mode == ConsoleOutputMode::File && self.serial.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
+        if let Some(console_ports) = &self.console_ports {
+            if !console_ports.is_empty() && self.console.mode == ConsoleOutputMode::Off {
+                return Err(ValidationError::ConsolePortsWithoutConsole);
+            }
+            if console_ports.len() > MAX_CONSOLE_PORTS {
+                return Err(ValidationError::TooManyConsolePorts);
+            }
+
+            let mut names = BTreeSet::new();
+            for console_port in console_ports {
+                console_port.validate()?;
+                if !names.insert(&console_port.name) {
+                    return Err(ValidationError::DuplicateConsolePortName(
+                        console_port.name.clone(),
+                    ));
+                }
+            }
         }
 
         if self.cpus.max_vcpus < self.cpus.boot_vcpus {
             return Err(ValidationError::CpusMaxLowerThanBoot);
         }
 
+        if self.cpus.max_vcpus > MAX_VCPUS {
+            return Err(ValidationError::CpusMaxTooLarge(self.cpus.max_vcpus));
+        }
+
         if let Some(disks) = &self.disks {
             for disk in disks {
-                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
-                    return Err(ValidationError::DiskSocketAndPath);
-                }
                 if disk.vhost_user && !self.memory.shared {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
-                if disk.vhost_user && disk.vhost_socket.is_none() {
-                    return Err(ValidationError::VhostUserMissingSocket);
-                }
+                disk.validate()?;
             }
         }
 
@@ -1484,6 +3268,20 @@ impl VmConfig {
                 if net.vhost_user && !self.memory.shared {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
+                net.validate()?;
+            }
+        }
+
+        for device in self.devices.iter().flatten() {
+            device.validate()?;
+        }
+
+        let mut boot_indexes = BTreeSet::new();
+        let disk_boot_indexes = self.disks.iter().flatten().filter_map(|d| d.boot_index);
+        let net_boot_indexes = self.net.iter().flatten().filter_map(|n| n.boot_index);
+        for boot_index in disk_boot_indexes.chain(net_boot_indexes) {
+            if !boot_indexes.insert(boot_index) {
+                return Err(ValidationError::DuplicateBootIndex(boot_index));
             }
         }
 
@@ -1491,6 +3289,20 @@ impl VmConfig {
             if !fses.is_empty() && !self.memory.shared {
                 return Err(ValidationError::VhostUserRequiresSharedMemory);
             }
+            for fs in fses {
+                fs.validate()?;
+            }
+        }
+
+        if let Some(pmems) = &self.pmem {
+            for pmem in pmems.iter().filter(|pmem| pmem.nvdimm) {
+                if cfg!(not(feature = "acpi")) {
+                    return Err(ValidationError::NvdimmUnsupported);
+                }
+                if pmem.iommu {
+                    return Err(ValidationError::NvdimmIommu);
+                }
+            }
         }
 
         if let Some(t) = &self.cpus.topology {
@@ -1502,12 +3314,100 @@ impl VmConfig {
                 return Err(ValidationError::CpuTopologyZeroPart);
             }
 
-            let total = t.threads_per_core * t.cores_per_die * t.dies_per_package * t.packages;
-            if total != self.cpus.max_vcpus {
+            let total = u32::from(t.threads_per_core)
+                * u32::from(t.cores_per_die)
+                * u32::from(t.dies_per_package)
+                * u32::from(t.packages);
+            if total != u32::from(self.cpus.max_vcpus) {
                 return Err(ValidationError::CpuTopologyCount);
             }
         }
 
+        if let Some(resource_group) = &self.resource_group {
+            resource_group.validate()?;
+        }
+
+        if let Some(clock) = &self.clock {
+            if let Some(guest_agent) = &clock.guest_agent {
+                if !self
+                    .console_ports
+                    .iter()
+                    .flatten()
+                    .any(|p| &p.name == guest_agent)
+                {
+                    return Err(ValidationError::ClockGuestAgentPortMissing(
+                        guest_agent.clone(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(metadata) = &self.metadata {
+            metadata.validate()?;
+        }
+
+        if let Some(worker_pool) = &self.worker_pool {
+            worker_pool.validate()?;
+
+            let disk_affinity = self.disks.iter().flatten().map(|d| &d.queue_affinity);
+            let net_affinity = self.net.iter().flatten().map(|n| &n.queue_affinity);
+            if disk_affinity.chain(net_affinity).any(|a| a.is_some()) {
+                return Err(ValidationError::QueueAffinityWorkerPool);
+            }
+        }
+
+        if let Some(balloon) = &self.balloon {
+            balloon.validate()?;
+        }
+
+        if let Some(affinity) = &self.cpus.affinity {
+            for a in affinity.iter() {
+                if a.vcpu >= self.cpus.max_vcpus
+                    || a.host_cpus.is_empty()
+                    || a.host_cpus.iter().any(|c| *c >= libc::CPU_SETSIZE as usize)
+                {
+                    return Err(ValidationError::InvalidCpuAffinity(a.vcpu));
+                }
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(reserved_memory) = &self.reserved_memory {
+                for range in reserved_memory.iter() {
+                    range.validate(&self.memory)?;
+                }
+            }
+
+            if let Some(platform) = &self.platform {
+                platform.validate()?;
+            }
+        }
+
+        if let Some(crashkernel) = self.memory.crashkernel {
+            if crashkernel == 0 || crashkernel % (1 << 20) != 0 || crashkernel >= self.memory.size {
+                return Err(ValidationError::InvalidCrashKernelSize);
+            }
+            if self
+                .cmdline
+                .args
+                .split_whitespace()
+                .any(|a| a.starts_with("crashkernel="))
+            {
+                return Err(ValidationError::CrashKernelInCmdline);
+            }
+        }
+
+        if let Some(max_phys_bits) = self.cpus.max_phys_bits {
+            if !(MIN_PHYS_BITS..=MAX_PHYS_BITS).contains(&max_phys_bits) {
+                return Err(ValidationError::InvalidMaxPhysBits(max_phys_bits));
+            }
+
+            if self.memory.max_end_address() >= 1 << max_phys_bits {
+                return Err(ValidationError::MemoryExceedsPhysBits(max_phys_bits));
+            }
+        }
+
         Ok(())
     }
 
@@ -1578,6 +3478,15 @@ impl VmConfig {
         }
         let serial = ConsoleConfig::parse(vm_params.serial)?;
 
+        let mut console_ports: Option<Vec<ConsolePortConfig>> = None;
+        if let Some(console_port_list) = &vm_params.console_ports {
+            let mut console_port_config_list = Vec::new();
+            for item in console_port_list.iter() {
+                console_port_config_list.push(ConsolePortConfig::parse(item)?);
+            }
+            console_ports = Some(console_port_config_list);
+        }
+
         let mut devices: Option<Vec<DeviceConfig>> = None;
         if let Some(device_list) = &vm_params.devices {
             let mut device_config_list = Vec::new();
@@ -1614,14 +3523,82 @@ impl VmConfig {
             }
         }
 
+        #[cfg(target_arch = "x86_64")]
+        let mut reserved_memory: Option<Vec<ReservedMemoryConfig>> = None;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(reserved_memory_list) = &vm_params.reserved_memory {
+                let mut reserved_memory_config_list = Vec::new();
+                for item in reserved_memory_list.iter() {
+                    let reserved_memory_config = ReservedMemoryConfig::parse(item)?;
+                    reserved_memory_config_list.push(reserved_memory_config);
+                }
+                reserved_memory = Some(reserved_memory_config_list);
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        let mut platform: Option<PlatformConfig> = None;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(platform_params) = &vm_params.platform {
+                platform = Some(PlatformConfig::parse(platform_params)?);
+            }
+        }
+
         let mut numa: Option<Vec<NumaConfig>> = None;
+        let mut numa_auto = false;
         if let Some(numa_list) = &vm_params.numa {
             let mut numa_config_list = Vec::new();
             for item in numa_list.iter() {
+                if *item == "auto" {
+                    numa_auto = true;
+                    continue;
+                }
                 let numa_config = NumaConfig::parse(item)?;
                 numa_config_list.push(numa_config);
             }
-            numa = Some(numa_config_list);
+            if !numa_config_list.is_empty() {
+                numa = Some(numa_config_list);
+            }
+        }
+
+        let mut resource_group: Option<ResourceGroupConfig> = None;
+        if let Some(resource_group_params) = &vm_params.resource_group {
+            resource_group = Some(ResourceGroupConfig::parse(resource_group_params)?);
+        }
+
+        let mut record: Option<RecordConfig> = None;
+        if let Some(p) = vm_params.record {
+            record = Some(RecordConfig {
+                path: PathBuf::from(p),
+            });
+        }
+
+        let mut worker_pool: Option<WorkerPoolConfig> = None;
+        if let Some(worker_pool_params) = &vm_params.worker_pool {
+            worker_pool = Some(WorkerPoolConfig::parse(worker_pool_params)?);
+        }
+
+        let mut clock: Option<ClockConfig> = None;
+        if let Some(clock_params) = &vm_params.clock {
+            clock = Some(ClockConfig::parse(clock_params)?);
+        }
+
+        let mut metadata: Option<MetadataConfig> = None;
+        if let Some(metadata_params) = &vm_params.metadata {
+            metadata = Some(MetadataConfig::parse(metadata_params)?);
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        let mut efi_vars: Option<EfiVarsConfig> = None;
+        #[cfg(target_arch = "aarch64")]
+        {
+            if let Some(p) = vm_params.efi_vars {
+                efi_vars = Some(EfiVarsConfig {
+                    path: PathBuf::from(p),
+                });
+            }
         }
 
         let mut kernel: Option<KernelConfig> = None;
@@ -1652,22 +3629,59 @@ impl VmConfig {
             pmem,
             serial,
             console,
+            console_ports,
             devices,
             vsock,
             iommu,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            reserved_memory,
+            #[cfg(target_arch = "x86_64")]
+            platform,
             numa,
+            numa_auto,
             watchdog: vm_params.watchdog,
+            on_crash: vm_params.on_crash.parse().map_err(Error::ParseOnCrash)?,
+            resource_group,
+            record,
+            worker_pool,
+            clock,
+            metadata,
+            #[cfg(target_arch = "aarch64")]
+            efi_vars,
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
     }
+
+    /// Load the whole configuration from a JSON file, in the format of the
+    /// `vm.create` API request.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(Error::ReadConfigFile)?;
+        let config: VmConfig = serde_json::from_str(&content).map_err(Error::ParseConfigFile)?;
+        config.validate().map_err(Error::Validation)?;
+        Ok(config)
+    }
+
+    /// Whether all the memory written on behalf of the guest can be tracked
+    /// for incremental snapshots. The vhost-user backends and the VFIO
+    /// devices write into the guest memory without going through the VMM
+    /// mappings, nor the vCPUs, and hugepages have no soft-dirty flags.
+    pub fn supports_incremental_snapshot(&self) -> bool {
+        !(self.disks.iter().flatten().any(|d| d.vhost_user)
+            || self.net.iter().flatten().any(|n| n.vhost_user)
+            || self.fs.iter().flatten().next().is_some()
+            || self.devices.iter().flatten().next().is_some()
+            || self.memory.hugepages
+            || self.memory.zones.iter().flatten().any(|z| z.hugepages))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
 
     #[test]
     fn test_option_parser() -> std::result::Result<(), OptionParserError> {
@@ -1724,6 +3738,15 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            CpusConfig::parse("boot=4,max=256")?,
+            CpusConfig {
+                boot_vcpus: 4,
+                max_vcpus: 256,
+                ..Default::default()
+            }
+        );
+
         assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
         assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
         assert_eq!(
@@ -1783,10 +3806,18 @@ mod tests {
             }
         );
         assert_eq!(
-            MemoryConfig::parse("hotplug_method=virtio-mem,hotplug_size=512M", None)?,
+            MemoryConfig::parse("size=1G,crashkernel=128M", None)?,
             MemoryConfig {
-                hotplug_size: Some(512 << 20),
-                hotplug_method: HotplugMethod::VirtioMem,
+                size: 1 << 30,
+                crashkernel: Some(128 << 20),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_method=virtio-mem,hotplug_size=512M", None)?,
+            MemoryConfig {
+                hotplug_size: Some(512 << 20),
+                hotplug_method: HotplugMethod::VirtioMem,
                 ..Default::default()
             }
         );
@@ -1883,6 +3914,71 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,coalesce_events=8,coalesce_usecs=50")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                coalesce_events: Some(8),
+                coalesce_usecs: Some(50),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,serial=ABCD-1234")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                serial: Some("ABCD-1234".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,boot_index=1")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                boot_index: Some(1),
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,boot_index=first").is_err());
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,pci_subsystem_vendor_id=0x1af4,pci_subsystem_id=4098"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                pci_subsystem_vendor_id: Some(0x1af4),
+                pci_subsystem_id: Some(4098),
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,pci_subsystem_id=0x10000").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,num_queues=2,queue_affinity=1@4:0@2:0@3")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                num_queues: 2,
+                queue_affinity: Some(vec![
+                    QueueAffinity {
+                        queue_index: 0,
+                        host_cpus: vec![2, 3],
+                    },
+                    QueueAffinity {
+                        queue_index: 1,
+                        host_cpus: vec![4],
+                    },
+                ]),
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,queue_affinity=0-2").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,key_file=/path/to/key")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                key_file: Some(PathBuf::from("/path/to/key")),
+                ..Default::default()
+            }
+        );
 
         Ok(())
     }
@@ -1957,6 +4053,75 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,coalesce_events=16,coalesce_usecs=100")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                coalesce_events: Some(16),
+                coalesce_usecs: Some(100),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("coalesce_events=16").is_err());
+        assert!(NetConfig::parse("coalesce_events=0,coalesce_usecs=100").is_err());
+        assert!(NetConfig::parse(
+            "vhost_user=true,socket=/tmp/sock,coalesce_events=16,coalesce_usecs=100"
+        )
+        .is_err());
+        assert!(NetConfig::parse("queue_size=300").is_err());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,num_queues=4,queue_affinity=0@2:1@3")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                num_queues: 4,
+                queue_affinity: Some(vec![
+                    QueueAffinity {
+                        queue_index: 0,
+                        host_cpus: vec![2],
+                    },
+                    QueueAffinity {
+                        queue_index: 1,
+                        host_cpus: vec![3],
+                    },
+                ]),
+                ..Default::default()
+            }
+        );
+        // Two queues make a single queue pair.
+        assert!(NetConfig::parse("queue_affinity=1@3").is_err());
+        assert!(NetConfig::parse("vhost_user=true,socket=/tmp/sock,queue_affinity=0@2").is_err());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,boot_index=0")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                boot_index: Some(0),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,romfile=/path/to/ipxe.rom")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                romfile: Some(PathBuf::from("/path/to/ipxe.rom")),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,pci_subsystem_vendor_id=0x8086,pci_subsystem_id=0x0001"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                pci_subsystem_vendor_id: Some(0x8086),
+                pci_subsystem_id: Some(0x0001),
+                ..Default::default()
+            }
+        );
+
         Ok(())
     }
 
@@ -1975,6 +4140,7 @@ mod tests {
             RngConfig {
                 src: PathBuf::from("/dev/random"),
                 iommu: true,
+                ..Default::default()
             }
         );
         assert_eq!(
@@ -1984,6 +4150,14 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            RngConfig::parse("rate_limit=1K")?,
+            RngConfig {
+                rate_limit: Some(1024),
+                ..Default::default()
+            }
+        );
+        assert!(RngConfig::parse("rate_limit=foo").is_err());
         Ok(())
     }
 
@@ -2078,10 +4252,186 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,nvdimm=on")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                nvdimm: true,
+                ..Default::default()
+            }
+        );
 
         Ok(())
     }
 
+    #[test]
+    fn test_restore_parsing() -> Result<()> {
+        assert!(RestoreConfig::parse("prefault=on").is_err());
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot,prefault=on,key_file=/path/to/key")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                prefault: true,
+                key_file: Some(PathBuf::from("/path/to/key")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse(
+                "source_url=/path/to/snapshot,disk_path=_disk0@/path/to/disk,\
+                 net_tap=_net2@tap1:_net1@tap0,net_socket=_net3@/tmp/sock,fs_socket=myfs@/tmp/fs"
+            )?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                disks: Some(vec![RestoredDiskConfig {
+                    id: "_disk0".to_owned(),
+                    path: Some(PathBuf::from("/path/to/disk")),
+                    ..Default::default()
+                }]),
+                net: Some(vec![
+                    RestoredNetConfig {
+                        id: "_net1".to_owned(),
+                        tap: Some("tap0".to_owned()),
+                        ..Default::default()
+                    },
+                    RestoredNetConfig {
+                        id: "_net2".to_owned(),
+                        tap: Some("tap1".to_owned()),
+                        ..Default::default()
+                    },
+                    RestoredNetConfig {
+                        id: "_net3".to_owned(),
+                        vhost_socket: Some("/tmp/sock".to_owned()),
+                        ..Default::default()
+                    },
+                ]),
+                fs: Some(vec![RestoredFsConfig {
+                    id: "myfs".to_owned(),
+                    socket: PathBuf::from("/tmp/fs"),
+                }]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot,net_tap=_net1@tap0,net_fd=_net1@3")?
+                .net,
+            Some(vec![RestoredNetConfig {
+                id: "_net1".to_owned(),
+                tap: Some("tap0".to_owned()),
+                fd: Some(3),
+                ..Default::default()
+            }])
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot,clone=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                clone: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse(
+                "source_url=/path/to/snapshot,clone=on,net_mac=_net1@12-34-56-78-9a-bc,\
+                 vsock_cid=4"
+            )?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                clone: true,
+                net: Some(vec![RestoredNetConfig {
+                    id: "_net1".to_owned(),
+                    mac: Some(MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()),
+                    ..Default::default()
+                }]),
+                vsock: Some(RestoredVsockConfig {
+                    cid: Some(4),
+                    socket: None,
+                }),
+                ..Default::default()
+            }
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot,serial_number=abc123")?.platform,
+            Some(PlatformConfig {
+                serial_number: Some("abc123".to_owned()),
+                uuid: None,
+            })
+        );
+        assert!(RestoreConfig::parse("source_url=/path/to/snapshot,net_tap=tap0").is_err());
+        assert!(RestoreConfig::parse("source_url=/path/to/snapshot,net_fd=_net1@foo").is_err());
+        assert!(RestoreConfig::parse(
+            "source_url=/path/to/snapshot,net_mac=_net1@12:34:56:78:9a:bc"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_parsing() -> Result<()> {
+        assert!(MetricsConfig::parse("").is_err());
+        assert!(MetricsConfig::parse("tcp=localhost").is_err());
+        assert_eq!(
+            MetricsConfig::parse("tcp=127.0.0.1:9090")?,
+            MetricsConfig {
+                address: "127.0.0.1:9090".parse().unwrap(),
+            }
+        );
+        assert_eq!(
+            MetricsConfig::parse("tcp=[::1]:9090")?.address,
+            "[::1]:9090".parse().unwrap()
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp_parsing() -> Result<()> {
+        assert!(OtlpConfig::parse("traceparent=").is_err());
+        assert_eq!(
+            OtlpConfig::parse("endpoint=127.0.0.1:4318")?,
+            OtlpConfig {
+                endpoint: "127.0.0.1:4318".parse().unwrap(),
+                trace_parent: None,
+            }
+        );
+        assert_eq!(
+            OtlpConfig::parse(
+                "endpoint=127.0.0.1:4318,\
+                 traceparent=00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            )?
+            .trace_parent,
+            Some(TraceParent {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_owned(),
+                span_id: "00f067aa0ba902b7".to_owned(),
+            })
+        );
+        // Wrong version, uppercase and all zeros identifiers.
+        for trace_parent in &[
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(OtlpConfig::parse(&format!(
+                "endpoint=127.0.0.1:4318,traceparent={}",
+                trace_parent
+            ))
+            .is_err());
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_console_parsing() -> Result<()> {
         assert!(ConsoleConfig::parse("").is_err());
@@ -2092,6 +4442,18 @@ mod tests {
                 mode: ConsoleOutputMode::Off,
                 iommu: false,
                 file: None,
+                max_size: None,
+                rotate: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+                max_size: None,
+                rotate: None,
             }
         );
         assert_eq!(
@@ -2100,6 +4462,8 @@ mod tests {
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 file: None,
+                max_size: None,
+                rotate: None,
             }
         );
         assert_eq!(
@@ -2108,6 +4472,8 @@ mod tests {
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 file: None,
+                max_size: None,
+                rotate: None,
             }
         );
         assert_eq!(
@@ -2115,15 +4481,30 @@ mod tests {
             ConsoleConfig {
                 mode: ConsoleOutputMode::File,
                 iommu: false,
-                file: Some(PathBuf::from("/tmp/console"))
+                file: Some(PathBuf::from("/tmp/console")),
+                max_size: None,
+                rotate: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("file=/tmp/console,max_size=1M,rotate=3")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::File,
+                iommu: false,
+                file: Some(PathBuf::from("/tmp/console")),
+                max_size: Some(1 << 20),
+                rotate: Some(3),
             }
         );
+        assert!(ConsoleConfig::parse("file=/tmp/console,rotate=foo").is_err());
         assert_eq!(
             ConsoleConfig::parse("null,iommu=on")?,
             ConsoleConfig {
                 mode: ConsoleOutputMode::Null,
                 iommu: true,
                 file: None,
+                max_size: None,
+                rotate: None,
             }
         );
         assert_eq!(
@@ -2131,9 +4512,84 @@ mod tests {
             ConsoleConfig {
                 mode: ConsoleOutputMode::File,
                 iommu: true,
-                file: Some(PathBuf::from("/tmp/console"))
+                file: Some(PathBuf::from("/tmp/console")),
+                max_size: None,
+                rotate: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_port_parsing() -> Result<()> {
+        assert!(ConsolePortConfig::parse("").is_err());
+        assert!(ConsolePortConfig::parse("pty").is_err());
+        assert!(ConsolePortConfig::parse("name=agent").is_err());
+        assert!(ConsolePortConfig::parse("name=agent,pty,socket=/tmp/qga.sock").is_err());
+        assert!(ConsolePortConfig::parse("name=log,file=/tmp/log,device=/dev/ttyS1").is_err());
+        assert_eq!(
+            ConsolePortConfig::parse("name=agent,pty")?,
+            ConsolePortConfig {
+                name: "agent".to_owned(),
+                mode: ConsolePortMode::Pty,
+                file: None,
+                socket: None,
+                device: None,
             }
         );
+        assert_eq!(
+            ConsolePortConfig::parse("name=log,file=/tmp/log")?,
+            ConsolePortConfig {
+                name: "log".to_owned(),
+                mode: ConsolePortMode::File,
+                file: Some(PathBuf::from("/tmp/log")),
+                socket: None,
+                device: None,
+            }
+        );
+        assert_eq!(
+            ConsolePortConfig::parse("name=org.qemu.guest_agent.0,socket=/tmp/qga.sock")?,
+            ConsolePortConfig {
+                name: "org.qemu.guest_agent.0".to_owned(),
+                mode: ConsolePortMode::Socket,
+                file: None,
+                socket: Some(PathBuf::from("/tmp/qga.sock")),
+                device: None,
+            }
+        );
+        assert_eq!(
+            ConsolePortConfig::parse("name=serial0,device=/dev/ttyUSB0")?,
+            ConsolePortConfig {
+                name: "serial0".to_owned(),
+                mode: ConsolePortMode::Device,
+                file: None,
+                socket: None,
+                device: Some(PathBuf::from("/dev/ttyUSB0")),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_port_validation() -> Result<()> {
+        let mut port = ConsolePortConfig::parse("name=agent,pty")?;
+        assert!(port.validate().is_ok());
+        // Once created, the pseudo terminal is reported through the file.
+        port.file = Some(PathBuf::from("/dev/pts/3"));
+        assert!(port.validate().is_ok());
+        port.socket = Some(PathBuf::from("/tmp/qga.sock"));
+        assert!(matches!(
+            port.validate(),
+            Err(ValidationError::ConsolePortPathUnexpected(_))
+        ));
+
+        let mut port = ConsolePortConfig::parse("name=serial0,device=/dev/ttyUSB0")?;
+        port.file = Some(PathBuf::from("/tmp/log"));
+        assert!(matches!(
+            port.validate(),
+            Err(ValidationError::ConsolePortPathUnexpected(_))
+        ));
+
         Ok(())
     }
 
@@ -2146,7 +4602,10 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: None,
-                iommu: false
+                iommu: false,
+                romfile: None,
+                pci_class_code: None,
+                pci_revision: None,
             }
         );
 
@@ -2155,7 +4614,10 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: None,
-                iommu: true
+                iommu: true,
+                romfile: None,
+                pci_class_code: None,
+                pci_revision: None,
             }
         );
 
@@ -2164,10 +4626,37 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: Some("mydevice0".to_owned()),
-                iommu: true
+                iommu: true,
+                romfile: None,
+                pci_class_code: None,
+                pci_revision: None,
             }
         );
 
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,romfile=/path/to/rom.bin")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: false,
+                romfile: Some(PathBuf::from("/path/to/rom.bin")),
+                pci_class_code: None,
+                pci_revision: None,
+            }
+        );
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,pci_class_code=0x030200,pci_revision=161")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: false,
+                romfile: None,
+                pci_class_code: Some(0x03_02_00),
+                pci_revision: Some(0xa1),
+            }
+        );
+        assert!(DeviceConfig::parse("path=/path/to/device,pci_revision=0x100").is_err());
+
         Ok(())
     }
 
@@ -2196,6 +4685,246 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_on_crash_parsing() {
+        assert_eq!(
+            "reboot".parse::<OnCrashAction>().unwrap(),
+            OnCrashAction::Reboot
+        );
+        assert_eq!(
+            "exit".parse::<OnCrashAction>().unwrap(),
+            OnCrashAction::Exit
+        );
+        assert_eq!(
+            "Pause".parse::<OnCrashAction>().unwrap(),
+            OnCrashAction::Pause
+        );
+        assert!("poweroff".parse::<OnCrashAction>().is_err());
+        assert_eq!(OnCrashAction::default(), OnCrashAction::Reboot);
+    }
+
+    #[test]
+    fn test_resource_group_parsing() -> Result<()> {
+        assert!(ResourceGroupConfig::parse("").is_err());
+        assert!(ResourceGroupConfig::parse("cpu_quota=150").is_err());
+        assert_eq!(
+            ResourceGroupConfig::parse("name=vm0")?,
+            ResourceGroupConfig {
+                name: "vm0".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            ResourceGroupConfig::parse("name=vm0,cpu_quota=150,memory_max=2G,io_weight=200")?,
+            ResourceGroupConfig {
+                name: "vm0".to_owned(),
+                cpu_quota: Some(150),
+                memory_max: Some(2 << 30),
+                io_weight: Some(200),
+            }
+        );
+        assert!(ResourceGroupConfig::parse("name=../vm0")?
+            .validate()
+            .is_err());
+        assert!(ResourceGroupConfig::parse("name=vm0,io_weight=0")?
+            .validate()
+            .is_err());
+        assert!(matches!(
+            ResourceGroupConfig::parse("name=vm0,cpu_quota=0")?.validate(),
+            Err(ValidationError::InvalidResourceGroupCpuQuota)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_balloon_parsing() -> Result<()> {
+        assert_eq!(BalloonConfig::parse("")?, BalloonConfig::default());
+        assert_eq!(
+            BalloonConfig::parse("size=1G")?,
+            BalloonConfig {
+                size: 1 << 30,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            BalloonConfig::parse("size=512M,policy_min=256M,policy_max=2G")?,
+            BalloonConfig {
+                size: 512 << 20,
+                policy_min: Some(256 << 20),
+                policy_max: Some(2 << 30),
+            }
+        );
+        assert!(BalloonConfig::parse("policy_min=256M")?.validate().is_err());
+        assert!(BalloonConfig::parse("policy_min=2G,policy_max=1G")?
+            .validate()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_pool_parsing() -> Result<()> {
+        assert_eq!(
+            WorkerPoolConfig::parse("")?,
+            WorkerPoolConfig { threads: 1 }
+        );
+        assert_eq!(
+            WorkerPoolConfig::parse("threads=4")?,
+            WorkerPoolConfig { threads: 4 }
+        );
+        assert!(WorkerPoolConfig::parse("threads=four").is_err());
+        assert!(WorkerPoolConfig::parse("threads=0")?.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clock_parsing() -> Result<()> {
+        assert_eq!(ClockConfig::parse("")?, ClockConfig::default());
+        assert_eq!(
+            ClockConfig::parse("guest_agent=org.qemu.guest_agent.0")?,
+            ClockConfig {
+                guest_agent: Some("org.qemu.guest_agent.0".to_owned()),
+            }
+        );
+        assert!(ClockConfig::parse("sync=on").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_parsing() -> Result<()> {
+        assert!(MetadataConfig::parse("").is_err());
+        assert!(MetadataConfig::parse("instance_id=i-0123456789").is_err());
+        assert_eq!(
+            MetadataConfig::parse("tap=tap0,instance_id=i-0123456789")?,
+            MetadataConfig {
+                address: "169.254.169.254:80".parse().unwrap(),
+                tap: "tap0".to_owned(),
+                instance_id: "i-0123456789".to_owned(),
+                hostname: None,
+                user_data: None,
+                ssh_keys: None,
+            }
+        );
+        assert_eq!(
+            MetadataConfig::parse(
+                "tap=tap0,instance_id=i-0123456789,hostname=vm-1,user_data=/path/to/user_data,\
+                 ssh_keys=/path/to/authorized_keys,address=192.168.249.1:8080"
+            )?,
+            MetadataConfig {
+                address: "192.168.249.1:8080".parse().unwrap(),
+                tap: "tap0".to_owned(),
+                instance_id: "i-0123456789".to_owned(),
+                hostname: Some("vm-1".to_owned()),
+                user_data: Some(PathBuf::from("/path/to/user_data")),
+                ssh_keys: Some(PathBuf::from("/path/to/authorized_keys")),
+            }
+        );
+        assert!(
+            MetadataConfig::parse("tap=tap0,instance_id=i-0123456789,address=169.254.169.254")
+                .is_err()
+        );
+        assert!(MetadataConfig::parse("tap=tap0,instance_id=").is_err());
+        let metadata = MetadataConfig::parse("tap=tap0,instance_id=i-0123456789")?;
+        assert!(metadata.validate().is_ok());
+        let mut invalid_metadata = metadata.clone();
+        invalid_metadata.instance_id.clear();
+        assert!(invalid_metadata.validate().is_err());
+        let mut invalid_metadata = metadata;
+        invalid_metadata.tap = "a-very-long-tap0".to_owned();
+        assert!(matches!(
+            invalid_metadata.validate(),
+            Err(ValidationError::InvalidMetadataTap(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_reserved_memory_parsing() -> Result<()> {
+        assert!(ReservedMemoryConfig::parse("").is_err());
+        assert!(ReservedMemoryConfig::parse("start=4032M").is_err());
+        assert_eq!(
+            ReservedMemoryConfig::parse("start=4032M,size=4K")?,
+            ReservedMemoryConfig {
+                start: 4032 << 20,
+                size: 4 << 10,
+                nvs: false,
+            }
+        );
+        assert_eq!(
+            ReservedMemoryConfig::parse("start=1G,size=2M,nvs=on")?,
+            ReservedMemoryConfig {
+                start: 1 << 30,
+                size: 2 << 20,
+                nvs: true,
+            }
+        );
+        let memory = MemoryConfig {
+            size: 512 << 20,
+            ..Default::default()
+        };
+        assert!(ReservedMemoryConfig::parse("start=1G,size=2M")?
+            .validate(&memory)
+            .is_ok());
+        assert!(ReservedMemoryConfig::parse("start=4032M,size=4K")?
+            .validate(&memory)
+            .is_ok());
+        assert!(ReservedMemoryConfig::parse("start=1G,size=0")?
+            .validate(&memory)
+            .is_err());
+        assert!(ReservedMemoryConfig::parse("start=1025,size=4K")?
+            .validate(&memory)
+            .is_err());
+        // Guest RAM
+        assert!(matches!(
+            ReservedMemoryConfig::parse("start=508M,size=8M")?.validate(&memory),
+            Err(ValidationError::ReservedMemoryOverlap(_))
+        ));
+        // 32-bit device area
+        assert!(matches!(
+            ReservedMemoryConfig::parse("start=3G,size=4K")?.validate(&memory),
+            Err(ValidationError::ReservedMemoryOverlap(_))
+        ));
+        // Local APIC
+        assert!(matches!(
+            ReservedMemoryConfig::parse("start=4078M,size=4M")?.validate(&memory),
+            Err(ValidationError::ReservedMemoryOverlap(_))
+        ));
+        // RAM above 4GiB, hotplug and 64-bit device area
+        assert!(matches!(
+            ReservedMemoryConfig::parse("start=8G,size=4K")?.validate(&memory),
+            Err(ValidationError::ReservedMemoryOverlap(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_platform_parsing() -> Result<()> {
+        assert_eq!(PlatformConfig::parse("")?, PlatformConfig::default());
+        assert_eq!(
+            PlatformConfig::parse(
+                "serial_number=abc123,uuid=4f1c3a86-2b1d-4c7e-9a8f-0d6e5b3c2a10"
+            )?,
+            PlatformConfig {
+                serial_number: Some("abc123".to_owned()),
+                uuid: Some("4f1c3a86-2b1d-4c7e-9a8f-0d6e5b3c2a10".to_owned()),
+            }
+        );
+        assert!(PlatformConfig::parse("serial_number=abc123")?
+            .validate()
+            .is_ok());
+        assert!(PlatformConfig::parse("serial_number=")?.validate().is_err());
+        assert!(PlatformConfig::parse("uuid=4f1c3a86-2b1d-4c7e-9a8f")?
+            .validate()
+            .is_err());
+        assert!(
+            PlatformConfig::parse("uuid=4f1c3a86_2b1d_4c7e_9a8f_0d6e5b3c2a10")?
+                .validate()
+                .is_err()
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_config_validation() -> Result<()> {
         let valid_config = VmConfig {
@@ -2213,6 +4942,7 @@ mod tests {
                 shared: false,
                 hugepages: false,
                 zones: None,
+                crashkernel: None,
             },
             kernel: Some(KernelConfig {
                 path: PathBuf::from("/path/to/kernel"),
@@ -2226,6 +4956,7 @@ mod tests {
             rng: RngConfig {
                 src: PathBuf::from("/dev/urandom"),
                 iommu: false,
+                rate_limit: None,
             },
             balloon: None,
             fs: None,
@@ -2234,19 +4965,37 @@ mod tests {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
+                max_size: None,
+                rotate: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
+                max_size: None,
+                rotate: None,
             },
+            console_ports: None,
             devices: None,
             vsock: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
+            #[cfg(target_arch = "x86_64")]
+            reserved_memory: None,
+            #[cfg(target_arch = "x86_64")]
+            platform: None,
             numa: None,
+            numa_auto: false,
             watchdog: false,
+            on_crash: OnCrashAction::Reboot,
+            resource_group: None,
+            record: None,
+            worker_pool: None,
+            clock: None,
+            metadata: None,
+            #[cfg(target_arch = "aarch64")]
+            efi_vars: None,
         };
 
         assert!(valid_config.validate().is_ok());
@@ -2260,16 +5009,124 @@ mod tests {
         invalid_config.kernel = None;
         assert!(invalid_config.validate().is_err());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.crashkernel = Some(128 << 20);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.rng.rate_limit = Some(0);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidRngRateLimit)
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.crashkernel = Some((128 << 20) + 1);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.crashkernel = Some(invalid_config.memory.size);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cmdline.args = String::from("console=ttyS0 crashkernel=64M");
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.serial.mode = ConsoleOutputMode::File;
         invalid_config.serial.file = None;
         assert!(invalid_config.validate().is_err());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.serial.mode = ConsoleOutputMode::File;
+        still_valid_config.serial.file = Some(PathBuf::from("/tmp/serial"));
+        still_valid_config.serial.max_size = Some(1 << 20);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.serial.max_size = Some(0);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.serial.max_size = None;
+        invalid_config.serial.rotate = Some(2);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console.max_size = Some(1 << 20);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.serial.mode = ConsoleOutputMode::Pty;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.console.mode = ConsoleOutputMode::Pty;
+        assert!(still_valid_config.validate().is_ok());
+
+        let console_port = ConsolePortConfig {
+            name: "agent".to_owned(),
+            mode: ConsolePortMode::Socket,
+            file: None,
+            socket: Some(PathBuf::from("/tmp/agent.sock")),
+            device: None,
+        };
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.console_ports = Some(vec![console_port.clone()]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.console.mode = ConsoleOutputMode::Off;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(vec![console_port.clone(), console_port.clone()]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(vec![ConsolePortConfig {
+            name: "../agent".to_owned(),
+            ..console_port.clone()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(vec![ConsolePortConfig {
+            socket: None,
+            ..console_port.clone()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let clock = ClockConfig {
+            guest_agent: Some(console_port.name.clone()),
+        };
+        let mut still_valid_config = still_valid_config.clone();
+        still_valid_config.clock = Some(clock.clone());
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.clock = Some(clock);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::ClockGuestAgentPortMissing(name)) if name == console_port.name
+        ));
+
         let mut invalid_config = valid_config.clone();
         invalid_config.cpus.max_vcpus = 16;
         invalid_config.cpus.boot_vcpus = 32;
         assert!(invalid_config.validate().is_err());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.max_vcpus = MAX_VCPUS;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = MAX_VCPUS + 1;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::CpusMaxTooLarge(_))
+        ));
+
         let mut invalid_config = valid_config.clone();
         invalid_config.cpus.max_vcpus = 16;
         invalid_config.cpus.boot_vcpus = 16;
@@ -2281,10 +5138,214 @@ mod tests {
         });
         assert!(invalid_config.validate().is_err());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_phys_bits = Some(64);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_phys_bits = Some(40);
+        invalid_config.memory.size = 1 << 40;
+        assert!(invalid_config.validate().is_err());
+
+        // The RAM above the 32-bit hole ends beyond 2^32.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.max_phys_bits = Some(32);
+            invalid_config.memory.size = 3584 << 20;
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::MemoryExceedsPhysBits(32))
+            ));
+        }
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.max_phys_bits = Some(46);
+        still_valid_config.memory.size = 2 << 40;
+        still_valid_config.memory.hotplug_size = Some(4 << 40);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            path: Some(PathBuf::from("/path/to/image")),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            coalesce_usecs: Some(50),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            serial: Some("01234567890123456789".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            serial: Some("012345678901234567890".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            serial: Some("my disk".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
             vhost_socket: Some("/path/to/sock".to_owned()),
+            key_file: Some(PathBuf::from("/path/to/key")),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyFileVhostUser)
+        ));
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            boot_index: Some(1),
+            ..Default::default()
+        }]);
+        still_valid_config.net = Some(vec![NetConfig {
+            boot_index: Some(0),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.net.as_mut().unwrap()[0].boot_index = Some(1);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::DuplicateBootIndex(1))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/sys/bus/pci/devices/0000:01:00.0"),
+            iommu: false,
+            id: None,
+            romfile: None,
+            pci_class_code: Some(0x0103_0200),
+            pci_revision: None,
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPciClassCode(0x0103_0200))
+        ));
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            queue_size: 1024,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].queue_size = 1000;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidQueueSize(1000))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].queue_size = 0;
+        assert!(invalid_config.validate().is_err());
+
+        // Validated on their own when hotplugged.
+        let disk = DiskConfig {
+            queue_size: 1000,
+            ..Default::default()
+        };
+        assert!(matches!(
+            disk.validate(),
+            Err(ValidationError::InvalidQueueSize(1000))
+        ));
+        let fs = FsConfig {
+            queue_size: 1000,
+            ..Default::default()
+        };
+        assert!(matches!(
+            fs.validate(),
+            Err(ValidationError::InvalidQueueSize(1000))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 0,
+            host_cpus: vec![libc::CPU_SETSIZE as usize],
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidCpuAffinity(0))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            queue_size: 65535,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
             path: Some(PathBuf::from("/path/to/image")),
+            num_queues: 2,
+            queue_affinity: Some(vec![QueueAffinity {
+                queue_index: 1,
+                host_cpus: vec![2],
+            }]),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].num_queues = 1;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidQueueAffinity(1))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0]
+            .queue_affinity
+            .as_mut()
+            .unwrap()[0]
+            .host_cpus = vec![libc::CPU_SETSIZE as usize];
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidQueueAffinity(1))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.worker_pool = Some(WorkerPoolConfig { threads: 2 });
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::QueueAffinityWorkerPool)
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.fs = Some(vec![FsConfig {
+            queue_size: 768,
             ..Default::default()
         }]);
         assert!(invalid_config.validate().is_err());
@@ -2335,6 +5396,14 @@ mod tests {
         }]);
         assert!(invalid_config.validate().is_err());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pmem = Some(vec![PmemConfig {
+            nvdimm: true,
+            iommu: true,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
         let mut still_valid_config = valid_config;
         invalid_config.fs = Some(vec![FsConfig {
             ..Default::default()
@@ -2342,6 +5411,183 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        // Host specific parts overridden on restore
+        let mut config = still_valid_config;
+        config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/disk")),
+            id: Some("_disk0".to_owned()),
+            ..Default::default()
+        }]);
+        config.net = Some(vec![NetConfig {
+            fd: Some(3),
+            id: Some("_net1".to_owned()),
+            ..Default::default()
+        }]);
+        let restore_config = RestoreConfig {
+            disks: Some(vec![RestoredDiskConfig {
+                id: "_disk0".to_owned(),
+                path: Some(PathBuf::from("/other/path/to/disk")),
+                ..Default::default()
+            }]),
+            net: Some(vec![RestoredNetConfig {
+                id: "_net1".to_owned(),
+                tap: Some("tap0".to_owned()),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        restore_config.apply_overrides(&mut config).unwrap();
+        let disk = &config.disks.as_ref().unwrap()[0];
+        assert_eq!(disk.path, Some(PathBuf::from("/other/path/to/disk")));
+        let net = &config.net.as_ref().unwrap()[0];
+        assert_eq!(net.tap, Some("tap0".to_owned()));
+        assert_eq!(net.fd, None);
+
+        let restore_config = RestoreConfig {
+            fs: Some(vec![RestoredFsConfig {
+                id: "myfs".to_owned(),
+                socket: PathBuf::from("/tmp/fs"),
+            }]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            restore_config.apply_overrides(&mut config),
+            Err(ValidationError::RestoreDeviceUnknown(id)) if id == "myfs"
+        ));
+
+        let restore_config = RestoreConfig {
+            clone: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            restore_config.apply_overrides(&mut config),
+            Err(ValidationError::CloneSharedMemory)
+        ));
+        config.memory.shared = false;
+        let mac = config.net.as_ref().unwrap()[0].mac;
+        restore_config.apply_overrides(&mut config).unwrap();
+        assert_ne!(config.net.as_ref().unwrap()[0].mac, mac);
+
+        // Identifiers given to a clone
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let restore_config = RestoreConfig {
+            clone: true,
+            net: Some(vec![RestoredNetConfig {
+                id: "_net1".to_owned(),
+                mac: Some(mac),
+                ..Default::default()
+            }]),
+            vsock: Some(RestoredVsockConfig {
+                cid: Some(4),
+                socket: None,
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            restore_config.apply_overrides(&mut config),
+            Err(ValidationError::RestoreVsockMissing)
+        ));
+        config.vsock = Some(VsockConfig {
+            cid: 3,
+            socket: PathBuf::from("/tmp/vsock"),
+            ..Default::default()
+        });
+        restore_config.apply_overrides(&mut config).unwrap();
+        assert_eq!(config.net.as_ref().unwrap()[0].mac, mac);
+        assert_eq!(config.vsock.as_ref().unwrap().cid, 4);
+        assert_eq!(
+            config.vsock.as_ref().unwrap().socket,
+            PathBuf::from("/tmp/vsock")
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut restore_config = RestoreConfig {
+                platform: Some(PlatformConfig {
+                    uuid: Some("4f1c3a86-2b1d-4c7e-9a8f-0d6e5b3c2a10".to_owned()),
+                    serial_number: None,
+                }),
+                ..Default::default()
+            };
+            restore_config.apply_overrides(&mut config).unwrap();
+            assert_eq!(
+                config.platform.as_ref().unwrap().uuid.as_deref(),
+                Some("4f1c3a86-2b1d-4c7e-9a8f-0d6e5b3c2a10")
+            );
+            restore_config.platform.as_mut().unwrap().uuid = Some("invalid".to_owned());
+            assert!(matches!(
+                restore_config.apply_overrides(&mut config),
+                Err(ValidationError::InvalidPlatformUuid(_))
+            ));
+        }
+
+        assert!(valid_config.supports_incremental_snapshot());
+        let mut config = valid_config.clone();
+        config.memory.hugepages = true;
+        assert!(!config.supports_incremental_snapshot());
+        let mut config = valid_config.clone();
+        config.memory.zones = Some(vec![MemoryZoneConfig {
+            id: "mem0".to_owned(),
+            size: 536_870_912,
+            file: None,
+            shared: false,
+            hugepages: true,
+            host_numa_node: None,
+            hotplug_size: None,
+            hotplugged_size: None,
+        }]);
+        assert!(!config.supports_incremental_snapshot());
+        let mut config = valid_config.clone();
+        config.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/sys/bus/pci/devices/0000:01:00.0"),
+            ..Default::default()
+        }]);
+        assert!(!config.supports_incremental_snapshot());
+
         Ok(())
     }
+
+    #[test]
+    fn test_config_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            br#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "cpus": {"boot_vcpus": 2, "max_vcpus": 4},
+                "disks": [{"path": "/path/to/disk"}]
+            }"#,
+        )
+        .unwrap();
+        let config = VmConfig::from_file(file.path()).unwrap();
+        assert_eq!(
+            config.kernel.unwrap().path,
+            PathBuf::from("/path/to/kernel")
+        );
+        assert_eq!(config.cpus.boot_vcpus, 2);
+        assert_eq!(config.cpus.max_vcpus, 4);
+        assert_eq!(
+            config.disks.unwrap()[0].path,
+            Some(PathBuf::from("/path/to/disk"))
+        );
+
+        // Same validation as the command line options.
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(br#"{"cpus": {"boot_vcpus": 1, "max_vcpus": 1}}"#)
+            .unwrap();
+        assert!(matches!(
+            VmConfig::from_file(file.path()),
+            Err(Error::Validation(ValidationError::KernelMissing))
+        ));
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"kernel = \"/path/to/kernel\"").unwrap();
+        assert!(matches!(
+            VmConfig::from_file(file.path()),
+            Err(Error::ParseConfigFile(_))
+        ));
+        assert!(matches!(
+            VmConfig::from_file(Path::new("/path/to/nowhere")),
+            Err(Error::ReadConfigFile(_))
+        ));
+    }
 }
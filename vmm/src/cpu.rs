@@ -11,10 +11,13 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use crate::access_trace::{set_current_vcpu, AccessTrace};
 #[cfg(target_arch = "x86_64")]
 use crate::config::CpuTopology;
 use crate::config::CpusConfig;
 use crate::device_manager::DeviceManager;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use crate::memory_error::{guest_address, handle_vcpu_memory_errors, take_pending_error};
 use crate::memory_manager::MemoryManager;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::vm::physical_bits;
@@ -30,20 +33,32 @@ use arch::x86_64::SgxEpcSection;
 use arch::CpuidPatch;
 use arch::EntryPoint;
 use devices::interrupt_controller::InterruptController;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use hypervisor::arch::x86::{
+    MCE_BANKS, MCG_STATUS_EIPV, MCG_STATUS_MCIP, MCG_STATUS_RIPV, MCI_STATUS_ADDRV, MCI_STATUS_AR,
+    MCI_STATUS_EN, MCI_STATUS_MISCV, MCI_STATUS_S, MCI_STATUS_UC, MCI_STATUS_VAL,
+};
 #[cfg(target_arch = "aarch64")]
 use hypervisor::kvm::kvm_bindings;
 #[cfg(target_arch = "x86_64")]
 use hypervisor::CpuId;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use hypervisor::MachineCheckEvent;
 use hypervisor::{vm::VmmOps, CpuState, HypervisorCpuError, VmExit};
 use libc::{c_void, siginfo_t};
 use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::HashMap;
+use std::num::Wrapping;
 use std::os::unix::thread::JoinHandleExt;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::{cmp, io, result, thread};
+use virtio_devices::set_thread_affinity;
 use vm_device::BusDevice;
 #[cfg(target_arch = "x86_64")]
 use vm_memory::GuestAddress;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use vm_memory::{Address, GuestAddressSpace};
 use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
@@ -59,6 +74,16 @@ const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
 #[cfg(target_arch = "x86_64")]
 const MTRR_EDX_BIT: u8 = 12; // Hypervisor ecx bit.
+#[cfg(target_arch = "x86_64")]
+const KVM_FEATURE_MSI_EXT_DEST_ID_EAX_BIT: u8 = 15; // MSI extended destination ID eax bit.
+
+// Highest APIC ID usable while the local APICs are in xAPIC mode, since 0xff
+// is the broadcast ID. Bigger guests must rely on x2APIC.
+#[cfg(any(target_arch = "x86_64", feature = "acpi"))]
+const MAX_XAPIC_ID: u16 = 0xfe;
+
+// Prefix of the names identifying the vCPUs in the counters.
+pub const VCPU_NAME_PREFIX: &str = "_vcpu";
 
 #[derive(Debug)]
 pub enum Error {
@@ -84,6 +109,10 @@ pub enum Error {
     /// Error configuring VCPU
     VcpuConfiguration(arch::Error),
 
+    #[cfg(target_arch = "x86_64")]
+    /// Error enabling the x2APIC API.
+    EnableX2ApicApi(hypervisor::HypervisorVmError),
+
     #[cfg(target_arch = "aarch64")]
     /// Error fetching prefered target
     VcpuArmPreferredTarget(hypervisor::HypervisorVmError),
@@ -92,6 +121,14 @@ pub enum Error {
     /// Error doing vCPU init on Arm.
     VcpuArmInit(hypervisor::HypervisorCpuError),
 
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    /// The vCPU doesn't exist or is not running.
+    InvalidVcpu(u16),
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    /// Cannot get the vCPU to inject a machine check.
+    InjectMce(MigratableError),
+
     /// Failed to join on vCPU threads
     ThreadCleanup(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
 
@@ -167,6 +204,9 @@ pub enum Error {
     /// Cannot apply seccomp filter
     ApplySeccompFilter(seccomp::Error),
 
+    /// Cannot set the vCPU thread affinity
+    SetVcpuAffinity(io::Error),
+
     /// Error starting vCPU after restore
     StartRestoreVcpu(anyhow::Error),
 
@@ -185,6 +225,18 @@ struct LocalAPIC {
     pub flags: u32,
 }
 
+#[cfg(feature = "acpi")]
+#[repr(packed)]
+#[derive(Default)]
+struct LocalX2APIC {
+    pub r#type: u8,
+    pub length: u8,
+    _reserved: u16,
+    pub apic_id: u32,
+    pub flags: u32,
+    pub processor_id: u32,
+}
+
 #[repr(packed)]
 #[derive(Default)]
 struct IOAPIC {
@@ -211,7 +263,7 @@ struct InterruptSourceOverride {
 pub struct Vcpu {
     // The hypervisor abstracted CPU.
     vcpu: Arc<dyn hypervisor::Vcpu>,
-    id: u8,
+    id: u16,
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
     saved_state: Option<CpuState>,
@@ -226,13 +278,17 @@ impl Vcpu {
     /// * `vm` - The virtual machine this vcpu will get attached to.
     /// * `vmmops` - Optional object for exit handling.
     pub fn new(
-        id: u8,
+        id: u16,
         vm: &Arc<dyn hypervisor::Vm>,
         vmmops: Option<Arc<Box<dyn VmmOps>>>,
     ) -> Result<Arc<Mutex<Self>>> {
         let vcpu = vm
             .create_vcpu(id, vmmops)
             .map_err(|e| Error::VcpuCreate(e.into()))?;
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if let Err(e) = vcpu.setup_mce() {
+            warn!("Machine checks not available on vCPU {}: {}", id, e);
+        }
         // Initially the cpuid per vCPU is the one supported by this VM.
         Ok(Arc::new(Mutex::new(Vcpu {
             vcpu,
@@ -405,11 +461,13 @@ pub struct CpuManager {
     exit_evt: EventFd,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     reset_evt: EventFd,
+    crash_evt: EventFd,
     vcpu_states: Vec<VcpuState>,
-    selected_cpu: u8,
+    selected_cpu: u16,
     vcpus: Vec<Arc<Mutex<Vcpu>>>,
     seccomp_action: SeccompAction,
     vmmops: Arc<Box<dyn VmmOps>>,
+    access_trace: Arc<AccessTrace>,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -449,9 +507,16 @@ impl BusDevice for CpuManager {
     fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
         match offset {
             CPU_SELECTION_OFFSET => {
-                self.selected_cpu = data[0];
+                let mut selection = [0u8; 4];
+                let len = std::cmp::min(data.len(), selection.len());
+                selection[..len].copy_from_slice(&data[..len]);
+                self.selected_cpu = u32::from_le_bytes(selection) as u16;
             }
             CPU_STATUS_OFFSET => {
+                if self.selected_cpu >= self.max_vcpus() {
+                    warn!("Invalid CPU selected: {}", self.selected_cpu);
+                    return None;
+                }
                 let state = &mut self.vcpu_states[usize::from(self.selected_cpu)];
                 // The ACPI code writes back a 1 to acknowledge the insertion
                 if (data[0] & (1 << CPU_INSERTING_FLAG) == 1 << CPU_INSERTING_FLAG)
@@ -489,6 +554,8 @@ struct VcpuState {
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    pending_mces: Arc<Mutex<Vec<MachineCheckEvent>>>,
 }
 
 impl VcpuState {
@@ -526,6 +593,30 @@ impl VcpuState {
             handle.thread().unpark()
         }
     }
+
+    // CPU time consumed by the vCPU thread so far, in nanoseconds, which
+    // accounts for the time spent running the guest.
+    fn cpu_time(&self) -> Option<u64> {
+        let handle = self.handle.as_ref()?;
+        let mut clock_id: libc::clockid_t = 0;
+        // Safe because the thread has not been joined yet, hence its
+        // pthread_t is still valid.
+        if unsafe { libc::pthread_getcpuclockid(handle.as_pthread_t() as _, &mut clock_id) } != 0 {
+            return None;
+        }
+
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safe because the timespec is a valid output for the call, which
+        // fails if the thread has already exited.
+        if unsafe { libc::clock_gettime(clock_id, &mut time) } != 0 {
+            return None;
+        }
+
+        Some(time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64)
+    }
 }
 
 impl CpuManager {
@@ -538,9 +629,11 @@ impl CpuManager {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        crash_evt: EventFd,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         seccomp_action: SeccompAction,
         vmmops: Arc<Box<dyn VmmOps>>,
+        access_trace: Arc<AccessTrace>,
     ) -> Result<Arc<Mutex<CpuManager>>> {
         let guest_memory = memory_manager.lock().unwrap().guest_memory();
         let mut vcpu_states = Vec::with_capacity(usize::from(config.max_vcpus));
@@ -554,7 +647,14 @@ impl CpuManager {
                 None
             };
         #[cfg(target_arch = "x86_64")]
-        let cpuid = CpuManager::patch_cpuid(hypervisor, &config.topology, sgx_epc_sections)?;
+        let x2apic = config.max_vcpus > MAX_XAPIC_ID + 1;
+        #[cfg(target_arch = "x86_64")]
+        if x2apic {
+            vm.enable_x2apic_api().map_err(Error::EnableX2ApicApi)?;
+        }
+        #[cfg(target_arch = "x86_64")]
+        let cpuid =
+            CpuManager::patch_cpuid(hypervisor, &config.topology, sgx_epc_sections, x2apic)?;
 
         let device_manager = device_manager.lock().unwrap();
         let cpu_manager = Arc::new(Mutex::new(CpuManager {
@@ -569,10 +669,12 @@ impl CpuManager {
             vcpu_states,
             exit_evt,
             reset_evt,
+            crash_evt,
             selected_cpu: 0,
             vcpus: Vec::with_capacity(usize::from(config.max_vcpus)),
             seccomp_action,
             vmmops,
+            access_trace,
         }));
 
         #[cfg(target_arch = "x86_64")]
@@ -597,6 +699,7 @@ impl CpuManager {
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         topology: &Option<CpuTopology>,
         sgx_epc_sections: Option<Vec<SgxEpcSection>>,
+        x2apic: bool,
     ) -> Result<CpuId> {
         let mut cpuid_patches = Vec::new();
 
@@ -633,6 +736,20 @@ impl CpuManager {
             edx_bit: Some(MTRR_EDX_BIT),
         });
 
+        // Let the guest address APIC IDs above 255 through the MSI extended
+        // destination ID, as there is no interrupt remapping.
+        if x2apic {
+            cpuid_patches.push(CpuidPatch {
+                function: 0x4000_0001,
+                index: 0,
+                flags_bit: None,
+                eax_bit: Some(KVM_FEATURE_MSI_EXT_DEST_ID_EAX_BIT),
+                ebx_bit: None,
+                ecx_bit: None,
+                edx_bit: None,
+            });
+        }
+
         // Supported CPUID
         let mut cpuid = hypervisor
             .get_cpuid()
@@ -658,7 +775,7 @@ impl CpuManager {
 
     fn create_vcpu(
         &mut self,
-        cpu_id: u8,
+        cpu_id: u16,
         entry_point: Option<EntryPoint>,
         snapshot: Option<Snapshot>,
     ) -> Result<Arc<Mutex<Vcpu>>> {
@@ -706,7 +823,7 @@ impl CpuManager {
     }
 
     /// Only create new vCPUs if there aren't any inactive ones to reuse
-    fn create_vcpus(&mut self, desired_vcpus: u8, entry_point: Option<EntryPoint>) -> Result<()> {
+    fn create_vcpus(&mut self, desired_vcpus: u16, entry_point: Option<EntryPoint>) -> Result<()> {
         info!(
             "Request to create new vCPUs: desired = {}, max = {}, allocated = {}, present = {}",
             desired_vcpus,
@@ -720,7 +837,7 @@ impl CpuManager {
         }
 
         // Only create vCPUs in excess of all the allocated vCPUs.
-        for cpu_id in self.vcpus.len() as u8..desired_vcpus {
+        for cpu_id in self.vcpus.len() as u16..desired_vcpus {
             self.create_vcpu(cpu_id, entry_point, None)?;
         }
 
@@ -736,6 +853,8 @@ impl CpuManager {
         let cpu_id = vcpu.lock().unwrap().id;
         let reset_evt = self.reset_evt.try_clone().unwrap();
         let exit_evt = self.exit_evt.try_clone().unwrap();
+        let crash_evt = self.crash_evt.try_clone().unwrap();
+        let access_trace = self.access_trace.clone();
         let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
         let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
 
@@ -743,9 +862,20 @@ impl CpuManager {
         let vcpu_run_interrupted = self.vcpu_states[usize::from(cpu_id)]
             .vcpu_run_interrupted
             .clone();
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        let pending_mces = self.vcpu_states[usize::from(cpu_id)].pending_mces.clone();
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        let vm_memory = self.vm_memory.clone();
 
         info!("Starting vCPU: cpu_id = {}", cpu_id);
 
+        let vcpu_affinity = self.config.affinity.as_ref().and_then(|affinity| {
+            affinity
+                .iter()
+                .find(|a| a.vcpu == cpu_id)
+                .map(|a| a.host_cpus.clone())
+        });
+
         // Retrieve seccomp filter for vcpu thread
         let vcpu_seccomp_filter = get_seccomp_filter(&self.seccomp_action, Thread::Vcpu)
             .map_err(Error::CreateSeccompFilter)?;
@@ -762,6 +892,17 @@ impl CpuManager {
             thread::Builder::new()
                 .name(format!("vcpu{}", cpu_id))
                 .spawn(move || {
+                    // Pin the vCPU thread to the requested host CPUs. This
+                    // must happen before applying the seccomp filter.
+                    if let Some(host_cpus) = vcpu_affinity {
+                        if let Err(e) =
+                            set_thread_affinity(&host_cpus).map_err(Error::SetVcpuAffinity)
+                        {
+                            error!("Error setting vCPU {} affinity: {:?}", cpu_id, e);
+                            return;
+                        }
+                    }
+
                     // Apply seccomp filter for vcpu thread.
                     if let Err(e) =
                         SeccompFilter::apply(vcpu_seccomp_filter).map_err(Error::ApplySeccompFilter)
@@ -775,6 +916,13 @@ impl CpuManager {
                     register_signal_handler(SIGRTMIN(), handle_signal)
                         .expect("Failed to register vcpu signal handler");
 
+                    // Report the host memory errors hit by the guest through
+                    // machine checks, rather than being killed by SIGBUS.
+                    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                    handle_vcpu_memory_errors().expect("Failed to register vcpu SIGBUS handler");
+
+                    set_current_vcpu(cpu_id);
+
                     // Block until all CPUs are ready.
                     vcpu_thread_barrier.wait();
 
@@ -807,7 +955,17 @@ impl CpuManager {
                             break;
                         }
 
-                        // vcpu.run() returns false on a triple-fault so trigger a reset
+                        // Machine checks are injected right before running
+                        // the guest, once the vCPU state has been restored
+                        // after a pause.
+                        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                        for mce in pending_mces.lock().unwrap().drain(..) {
+                            if let Err(e) = vcpu.lock().unwrap().vcpu.inject_mce(&mce) {
+                                error!("Error injecting machine check into vCPU {}: {}", cpu_id, e);
+                            }
+                        }
+
+                        let mut crashed = false;
                         match vcpu.lock().unwrap().run() {
                             Ok(run) => match run {
                                 #[cfg(target_arch = "x86_64")]
@@ -834,6 +992,12 @@ impl CpuManager {
                                     exit_evt.write(1).unwrap();
                                     break;
                                 }
+                                VmExit::Crash => {
+                                    error!("vCPU {} crashed", cpu_id);
+                                    access_trace.log_vcpu_trace(cpu_id);
+                                    crash_evt.write(1).unwrap();
+                                    crashed = true;
+                                }
                                 _ => {
                                     error!("VCPU generated error: {:?}", Error::UnexpectedVmExit);
                                     break;
@@ -842,10 +1006,46 @@ impl CpuManager {
 
                             Err(e) => {
                                 error!("VCPU generated error: {:?}", Error::VcpuRun(e.into()));
+                                access_trace.log_vcpu_trace(cpu_id);
                                 break;
                             }
                         }
 
+                        // The thread of a crashed vCPU is kept around until
+                        // the VMM acts on the crash, so that pausing the VM
+                        // leaves it resumable.
+                        if crashed {
+                            vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                            while !vcpu_pause_signalled.load(Ordering::SeqCst)
+                                && !vcpu_kill_signalled.load(Ordering::SeqCst)
+                                && !vcpu_kill.load(Ordering::SeqCst)
+                            {
+                                thread::park_timeout(std::time::Duration::from_millis(10));
+                            }
+                            continue;
+                        }
+
+                        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                        if let Some(e) = take_pending_error() {
+                            match guest_address(&vm_memory.memory(), e.host_address) {
+                                Some(address) => {
+                                    warn!(
+                                        "Host memory error at guest address 0x{:x} on vCPU {}",
+                                        address.raw_value(),
+                                        cpu_id
+                                    );
+                                    pending_mces.lock().unwrap().push(memory_error_mce(
+                                        address.raw_value(),
+                                        e.action_required,
+                                    ));
+                                }
+                                None => warn!(
+                                    "Host memory error outside of the guest RAM at 0x{:x} on vCPU {}",
+                                    e.host_address, cpu_id
+                                ),
+                            }
+                        }
+
                         // We've been told to terminate
                         if vcpu_kill_signalled.load(Ordering::SeqCst)
                             || vcpu_kill.load(Ordering::SeqCst)
@@ -867,7 +1067,7 @@ impl CpuManager {
     }
 
     /// Start up as many vCPUs threads as needed to reach `desired_vcpus`
-    fn activate_vcpus(&mut self, desired_vcpus: u8, inserting: bool) -> Result<()> {
+    fn activate_vcpus(&mut self, desired_vcpus: u16, inserting: bool) -> Result<()> {
         if desired_vcpus > self.config.max_vcpus {
             return Err(Error::DesiredVCPUCountExceedsMax);
         }
@@ -894,7 +1094,7 @@ impl CpuManager {
         Ok(())
     }
 
-    fn mark_vcpus_for_removal(&mut self, desired_vcpus: u8) -> Result<()> {
+    fn mark_vcpus_for_removal(&mut self, desired_vcpus: u16) -> Result<()> {
         // Mark vCPUs for removal, actual removal happens on ejection
         for cpu_id in desired_vcpus..self.present_vcpus() {
             self.vcpu_states[usize::from(cpu_id)].removing = true;
@@ -902,7 +1102,7 @@ impl CpuManager {
         Ok(())
     }
 
-    fn remove_vcpu(&mut self, cpu_id: u8) -> Result<()> {
+    fn remove_vcpu(&mut self, cpu_id: u16) -> Result<()> {
         info!("Removing vCPU: cpu_id = {}", cpu_id);
         let mut state = &mut self.vcpu_states[usize::from(cpu_id)];
         state.kill.store(true, Ordering::SeqCst);
@@ -944,7 +1144,7 @@ impl CpuManager {
         Ok(())
     }
 
-    pub fn resize(&mut self, desired_vcpus: u8) -> Result<bool> {
+    pub fn resize(&mut self, desired_vcpus: u16) -> Result<bool> {
         match desired_vcpus.cmp(&self.present_vcpus()) {
             cmp::Ordering::Greater => {
                 self.create_vcpus(desired_vcpus, None)?;
@@ -983,18 +1183,77 @@ impl CpuManager {
         Ok(())
     }
 
-    pub fn boot_vcpus(&self) -> u8 {
+    pub fn boot_vcpus(&self) -> u16 {
         self.config.boot_vcpus
     }
 
-    pub fn max_vcpus(&self) -> u8 {
+    pub fn max_vcpus(&self) -> u16 {
         self.config.max_vcpus
     }
 
-    fn present_vcpus(&self) -> u8 {
+    fn present_vcpus(&self) -> u16 {
         self.vcpu_states
             .iter()
-            .fold(0, |acc, state| acc + state.active() as u8)
+            .fold(0, |acc, state| acc + state.active() as u16)
+    }
+
+    /// Report an uncorrected memory error at the guest physical `address`
+    /// through a machine check on the vCPU `cpu_id`. The vCPU thread injects
+    /// it before running the guest again, hence a running vCPU is paused and
+    /// resumed to get there, while a paused one gets it when resumed.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    pub fn inject_memory_error(
+        &mut self,
+        cpu_id: u16,
+        address: u64,
+        action_required: bool,
+    ) -> Result<()> {
+        let state = self
+            .vcpu_states
+            .get(usize::from(cpu_id))
+            .filter(|state| state.active())
+            .ok_or(Error::InvalidVcpu(cpu_id))?;
+        state
+            .pending_mces
+            .lock()
+            .unwrap()
+            .push(memory_error_mce(address, action_required));
+
+        if !self.vcpus_pause_signalled.load(Ordering::SeqCst) {
+            self.pause().map_err(Error::InjectMce)?;
+            self.resume().map_err(Error::InjectMce)?;
+        }
+
+        Ok(())
+    }
+
+    /// Restart the boot vCPU from the waking vector after the guest suspended
+    /// to RAM. The other vCPUs are left for the guest to bring up again
+    /// through INIT/SIPI, as it takes them offline before suspending.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn wake_boot_vcpu(&self, waking_vector: u32) -> Result<()> {
+        if let Some(vcpu) = self.vcpus.first() {
+            arch::configure_waking_vcpu(&vcpu.lock().unwrap().vcpu, waking_vector)
+                .map_err(Error::VcpuConfiguration)?;
+        }
+
+        Ok(())
+    }
+
+    /// CPU time consumed by each running vCPU, keyed by "_vcpu<cpu_id>", from
+    /// which the guest CPU usage can be computed.
+    pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        for (cpu_id, state) in self.vcpu_states.iter().enumerate() {
+            if let Some(cpu_time) = state.cpu_time() {
+                let mut vcpu_counters = HashMap::new();
+                vcpu_counters.insert("cpu_time_ns", Wrapping(cpu_time));
+                counters.insert(format!("{}{}", VCPU_NAME_PREFIX, cpu_id), vcpu_counters);
+            }
+        }
+
+        counters
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -1022,18 +1281,31 @@ impl CpuManager {
         madt.write(36, layout::APIC_START);
 
         for cpu in 0..self.config.max_vcpus {
-            let lapic = LocalAPIC {
-                r#type: 0,
-                length: 8,
-                processor_id: cpu,
-                apic_id: cpu,
-                flags: if cpu < self.config.boot_vcpus {
-                    1 << MADT_CPU_ENABLE_FLAG
-                } else {
-                    0
-                },
+            let flags = if cpu < self.config.boot_vcpus {
+                1 << MADT_CPU_ENABLE_FLAG
+            } else {
+                0
             };
-            madt.append(lapic);
+            // APIC IDs which don't fit in a local APIC structure must be
+            // described through a local x2APIC structure.
+            if cpu <= MAX_XAPIC_ID {
+                madt.append(LocalAPIC {
+                    r#type: 0,
+                    length: 8,
+                    processor_id: cpu as u8,
+                    apic_id: cpu as u8,
+                    flags,
+                });
+            } else {
+                madt.append(LocalX2APIC {
+                    r#type: 9,
+                    length: 16,
+                    processor_id: u32::from(cpu),
+                    apic_id: u32::from(cpu),
+                    flags,
+                    ..Default::default()
+                });
+            }
         }
 
         madt.append(IOAPIC {
@@ -1060,7 +1332,7 @@ impl CpuManager {
 
 #[cfg(feature = "acpi")]
 struct CPU {
-    cpu_id: u8,
+    cpu_id: u16,
 }
 
 #[cfg(feature = "acpi")]
@@ -1069,20 +1341,32 @@ const MADT_CPU_ENABLE_FLAG: usize = 0;
 #[cfg(feature = "acpi")]
 impl Aml for CPU {
     fn to_aml_bytes(&self) -> Vec<u8> {
-        let lapic = LocalAPIC {
-            r#type: 0,
-            length: 8,
-            processor_id: self.cpu_id,
-            apic_id: self.cpu_id,
-            flags: 1 << MADT_CPU_ENABLE_FLAG,
-        };
-
         let mut mat_data: Vec<u8> = Vec::new();
-        mat_data.resize(std::mem::size_of_val(&lapic), 0);
-        unsafe { *(mat_data.as_mut_ptr() as *mut LocalAPIC) = lapic };
+        if self.cpu_id <= MAX_XAPIC_ID {
+            let lapic = LocalAPIC {
+                r#type: 0,
+                length: 8,
+                processor_id: self.cpu_id as u8,
+                apic_id: self.cpu_id as u8,
+                flags: 1 << MADT_CPU_ENABLE_FLAG,
+            };
+            mat_data.resize(std::mem::size_of_val(&lapic), 0);
+            unsafe { *(mat_data.as_mut_ptr() as *mut LocalAPIC) = lapic };
+        } else {
+            let x2apic = LocalX2APIC {
+                r#type: 9,
+                length: 16,
+                processor_id: u32::from(self.cpu_id),
+                apic_id: u32::from(self.cpu_id),
+                flags: 1 << MADT_CPU_ENABLE_FLAG,
+                ..Default::default()
+            };
+            mat_data.resize(std::mem::size_of_val(&x2apic), 0);
+            unsafe { *(mat_data.as_mut_ptr() as *mut LocalX2APIC) = x2apic };
+        }
 
         aml::Device::new(
-            format!("C{:03}", self.cpu_id).as_str().into(),
+            format!("C{:03X}", self.cpu_id).as_str().into(),
             vec![
                 &aml::Name::new("_HID".into(), &"ACPI0007"),
                 &aml::Name::new("_UID".into(), &self.cpu_id),
@@ -1128,13 +1412,13 @@ impl Aml for CPU {
 
 #[cfg(feature = "acpi")]
 struct CPUNotify {
-    cpu_id: u8,
+    cpu_id: u16,
 }
 
 #[cfg(feature = "acpi")]
 impl Aml for CPUNotify {
     fn to_aml_bytes(&self) -> Vec<u8> {
-        let object = aml::Path::new(&format!("C{:03}", self.cpu_id));
+        let object = aml::Path::new(&format!("C{:03X}", self.cpu_id));
         aml::If::new(
             &aml::Equal::new(&aml::Arg(0), &self.cpu_id),
             vec![&aml::Notify::new(&object, &aml::Arg(1))],
@@ -1145,7 +1429,7 @@ impl Aml for CPUNotify {
 
 #[cfg(feature = "acpi")]
 struct CPUMethods {
-    max_vcpus: u8,
+    max_vcpus: u16,
 }
 
 #[cfg(feature = "acpi")]
@@ -1414,8 +1698,12 @@ impl Snapshottable for CpuManager {
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         for (cpu_id, snapshot) in snapshot.snapshots.iter() {
             debug!("Restoring VCPU {}", cpu_id);
-            self.create_vcpu(cpu_id.parse::<u8>().unwrap(), None, Some(*snapshot.clone()))
-                .map_err(|e| MigratableError::Restore(anyhow!("Could not create vCPU {:?}", e)))?;
+            self.create_vcpu(
+                cpu_id.parse::<u16>().unwrap(),
+                None,
+                Some(*snapshot.clone()),
+            )
+            .map_err(|e| MigratableError::Restore(anyhow!("Could not create vCPU {:?}", e)))?;
         }
 
         Ok(())
@@ -1425,6 +1713,40 @@ impl Snapshottable for CpuManager {
 impl Transportable for CpuManager {}
 impl Migratable for CpuManager {}
 
+/// Machine check reporting an uncorrected memory error at the guest physical
+/// `address`, the way a host would report it through the last bank. An error
+/// requiring action (SRAR) is raised when the guest consumes the poisoned
+/// data, while an action optional one (SRAO) comes from the memory scrubber.
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+pub fn memory_error_mce(address: u64, action_required: bool) -> MachineCheckEvent {
+    let status = MCI_STATUS_VAL
+        | MCI_STATUS_UC
+        | MCI_STATUS_EN
+        | MCI_STATUS_MISCV
+        | MCI_STATUS_ADDRV
+        | MCI_STATUS_S;
+    let (status, mcg_status) = if action_required {
+        // Data load error.
+        (
+            status | MCI_STATUS_AR | 0x134,
+            MCG_STATUS_MCIP | MCG_STATUS_EIPV,
+        )
+    } else {
+        // Memory scrubbing error.
+        (status | 0xc0, MCG_STATUS_MCIP | MCG_STATUS_RIPV)
+    };
+
+    MachineCheckEvent {
+        status,
+        addr: address,
+        // Physical address, with a 4KiB granularity.
+        misc: (2 << 6) | 12,
+        mcg_status,
+        bank: (MCE_BANKS - 1) as u8,
+        ..Default::default()
+    }
+}
+
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 #[cfg(test)]
 mod tests {
@@ -1555,6 +1877,19 @@ mod tests {
         let actual_sregs: SpecialRegisters = vcpu.get_sregs().unwrap();
         assert_eq!(expected_sregs, actual_sregs);
     }
+
+    #[test]
+    fn test_memory_error_mce() {
+        let mce = memory_error_mce(0x1234_5000, true);
+        assert_eq!(mce.addr, 0x1234_5000);
+        assert_eq!(mce.bank, 9);
+        assert_eq!(mce.status, 0xbd80_0000_0000_0134);
+        assert_eq!(mce.mcg_status, MCG_STATUS_MCIP | MCG_STATUS_EIPV);
+
+        let mce = memory_error_mce(0x1234_5000, false);
+        assert_eq!(mce.status, 0xbd00_0000_0000_00c0);
+        assert_eq!(mce.mcg_status, MCG_STATUS_MCIP | MCG_STATUS_RIPV);
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -9,16 +9,22 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
-use crate::config::ConsoleOutputMode;
 use crate::config::DeviceConfig;
-use crate::config::{DiskConfig, FsConfig, NetConfig, PmemConfig, VmConfig, VsockConfig};
+use crate::config::{ConsoleConfig, ConsoleOutputMode, ConsolePortMode};
+use crate::config::{
+    DiskConfig, FsConfig, NetConfig, PmemConfig, QueueAffinity, VmConfig, VsockConfig,
+};
 use crate::device_tree::{DeviceNode, DeviceTree};
+use crate::hotplug::HotplugDevice;
 #[cfg(feature = "kvm")]
 use crate::interrupt::kvm::KvmMsiInterruptManager as MsiInterruptManager;
 #[cfg(feature = "mshv")]
 use crate::interrupt::mshv::MshvMsiInterruptManager as MsiInterruptManager;
 use crate::interrupt::LegacyUserspaceInterruptManager;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use crate::intx::IntxForwarder;
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::rotating_file::RotatingFile;
 #[cfg(feature = "acpi")]
 use crate::vm::NumaNodes;
 use crate::PciDeviceInfo;
@@ -37,6 +43,7 @@ use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
 #[cfg(target_arch = "aarch64")]
 use arch::DeviceType;
 use block_util::block_io_uring_is_supported;
+use block_util::luks::{clear_secret, LuksFile};
 #[cfg(target_arch = "aarch64")]
 use devices::gic;
 #[cfg(target_arch = "x86_64")]
@@ -54,19 +61,24 @@ use hypervisor::IoEventAddress;
 use libc::TIOCGWINSZ;
 use libc::{MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, O_TMPFILE, PROT_READ, PROT_WRITE};
 use pci::{
-    DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRoot,
-    VfioPciDevice,
+    DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRom,
+    PciRoot, VfioPciDevice, MAX_MSIX_VECTORS_PER_DEVICE,
 };
 use qcow::{self, ImageType, QcowFile};
 use seccomp::SeccompAction;
 use std::any::Any;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+use std::io::Read;
 use std::io::{self, sink, stdout, Seek, SeekFrom};
 use std::num::Wrapping;
-use std::os::unix::fs::OpenOptionsExt;
-#[cfg(feature = "kvm")]
-use std::os::unix::io::FromRawFd;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(target_arch = "aarch64")]
+use std::path::Path;
+use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Barrier, Mutex};
 #[cfg(feature = "kvm")]
@@ -74,17 +86,21 @@ use vfio_ioctls::{VfioContainer, VfioDevice, VfioDmaMapping};
 use virtio_devices::transport::VirtioPciDevice;
 use virtio_devices::transport::VirtioTransport;
 use virtio_devices::vhost_user::VhostUserConfig;
-use virtio_devices::{DmaRemapping, IommuMapping};
+use virtio_devices::{
+    ConsolePort, ConsolePortEndpoint, DmaRemapping, EpollWorkerPool, IommuMapping,
+};
 use virtio_devices::{VirtioSharedMemory, VirtioSharedMemoryList};
 use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
 };
-use vm_device::{Bus, BusDevice, Resource};
+use vm_device::{Bus, BusDevice, Recorder, Resource};
 use vm_memory::guest_memory::FileOffset;
 use vm_memory::{
     Address, GuestAddress, GuestAddressSpace, GuestRegionMmap, GuestUsize, MmapRegion,
 };
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+use vm_memory::{Bytes, GuestMemoryError};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
@@ -95,6 +111,10 @@ use vmm_sys_util::eventfd::EventFd;
 #[cfg(target_arch = "aarch64")]
 const MMIO_LEN: u64 = 0x1000;
 
+// Number of legacy interrupts shared by the PCI slots for INTx.
+#[cfg(target_arch = "x86_64")]
+const PCI_INTX_IRQS: usize = 4;
+
 #[cfg(feature = "kvm")]
 const VFIO_DEVICE_NAME_PREFIX: &str = "_vfio";
 
@@ -178,6 +198,12 @@ pub enum DeviceManagerError {
     /// Cannot open qcow disk path
     QcowDeviceCreate(qcow::Error),
 
+    /// Cannot read the passphrase of an encrypted disk
+    DiskKeyFile(io::Error),
+
+    /// Cannot unlock the LUKS disk image
+    LuksDeviceCreate(io::Error),
+
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
@@ -223,6 +249,32 @@ pub enum DeviceManagerError {
     /// Error creating console output file
     ConsoleOutputFileOpen(io::Error),
 
+    /// Error creating the pseudo terminal for the serial port
+    SerialPtyOpen(io::Error),
+
+    /// Error creating the pseudo terminal for the virtio-console device
+    ConsolePtyOpen(io::Error),
+
+    /// Error creating a console port output file
+    ConsolePortFileOpen(io::Error),
+
+    /// Error creating the pseudo terminal for a console port
+    ConsolePortPtyOpen(io::Error),
+
+    /// Error binding the socket of a console port
+    ConsolePortSocketBind(io::Error),
+
+    /// Error opening the host character device of a console port
+    ConsolePortDeviceOpen(io::Error),
+
+    /// Error creating the UEFI variable store flash
+    #[cfg(target_arch = "aarch64")]
+    EfiVarsFlash(io::Error),
+
+    /// The UEFI variable store file is bigger than the flash
+    #[cfg(target_arch = "aarch64")]
+    EfiVarsTooBig,
+
     /// Cannot create a VFIO device
     VfioCreate(vfio_ioctls::VfioError),
 
@@ -331,6 +383,9 @@ pub enum DeviceManagerError {
     /// Failed updating guest memory for VFIO PCI device.
     UpdateMemoryForVfioPciDevice(pci::VfioPciError),
 
+    /// Failed forwarding the legacy interrupt of a VFIO PCI device.
+    ForwardVfioIntx(io::Error),
+
     /// Trying to use a directory for pmem but no size specified
     PmemWithDirectorySizeMissing,
 
@@ -366,9 +421,52 @@ pub enum DeviceManagerError {
 
     /// Missing virtio-balloon, can't proceed as expected.
     MissingVirtioBalloon,
+
+    /// Missing ACPI shutdown device, can't wake the VM up.
+    MissingAcpiShutdownDevice,
+
+    /// Failed to update the virtio-net link status
+    VirtioNetLinkStatus(virtio_devices::net::Error),
+
+    /// The virtqueues of a device which isn't a virtio one, or of a
+    /// vhost-user one processed by its backend, can't be traced
+    QueueTraceNotSupported(String),
+
+    /// Cannot create the device inputs recorder
+    CreateRecorder(io::Error),
+
+    /// Cannot create the virtio devices worker pool
+    CreateWorkerPool(io::Error),
+
+    /// NVDIMM regions can't be hot plugged
+    NvdimmHotplugUnsupported,
+
+    /// Failed to load an option ROM
+    LoadOptionRom(io::Error),
+
+    /// Failed to generate a new VM generation ID
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    GenerateGenerationId(io::Error),
+
+    /// Failed to write the VM generation ID to the guest memory
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    WriteGenerationId(GuestMemoryError),
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
+/// Persistent memory exposed to the guest as an NVDIMM, described by the ACPI
+/// NFIT table instead of a virtio-pmem device.
+pub struct NvdimmRegion {
+    pub id: String,
+    // NFIT device handle, also used as the address of the NVDIMM device
+    pub handle: u32,
+    pub base: GuestAddress,
+    pub size: GuestUsize,
+
+    // Hold ownership of the memory backing the region
+    _region: MmapRegion,
+}
+
 type VirtioDeviceArc = Arc<Mutex<dyn virtio_devices::VirtioDevice>>;
 
 pub fn get_win_size() -> (u16, u16) {
@@ -389,7 +487,243 @@ pub fn get_win_size() -> (u16, u16) {
     (ws.cols, ws.rows)
 }
 
-enum ConsoleInput {
+/// Pseudo terminal the serial port or the virtio-console device is connected
+/// to. The VMM keeps the subsidiary side open so that reading from the main
+/// side does not report a hang up while no user is attached.
+pub struct PtyPair {
+    pub main: File,
+    pub sub: File,
+    pub path: PathBuf,
+}
+
+fn create_pty() -> io::Result<PtyPair> {
+    // Safe because posix_openpt() doesn't access any memory.
+    let main_fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+    if main_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the file descriptor has just been opened, and nothing
+    // else owns it.
+    let main = unsafe { File::from_raw_fd(main_fd) };
+
+    // Safe because the file descriptor is valid, and these calls don't
+    // access any memory.
+    if unsafe { libc::grantpt(main.as_raw_fd()) } < 0
+        || unsafe { libc::unlockpt(main.as_raw_fd()) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut name = [0 as libc::c_char; 64];
+    // Safe because the length of the buffer is provided, and
+    // ptsname_r() null terminates the name on success.
+    let ret = unsafe { libc::ptsname_r(main.as_raw_fd(), name.as_mut_ptr(), name.len()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    // Safe because ptsname_r() succeeded, hence the name is null terminated.
+    let path = PathBuf::from(
+        unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned(),
+    );
+
+    let sub = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&path)?;
+
+    // Raw mode prevents the terminal from echoing the guest output back to
+    // the guest when nobody is attached.
+    // Safe because termios is a plain C structure, for which the all zeroes
+    // pattern is valid, and the calls below only access this structure.
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(sub.as_raw_fd(), &mut termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { libc::cfmakeraw(&mut termios) };
+    if unsafe { libc::tcsetattr(sub.as_raw_fd(), libc::TCSANOW, &termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PtyPair { main, sub, path })
+}
+
+// Open the host character device a virtio-console port is bound to. A
+// terminal, like a serial port, is switched to raw mode for the guest data
+// to go through unchanged, keeping the line settings made on the host.
+fn open_console_port_device(path: &Path) -> io::Result<File> {
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(path)?;
+
+    // A regular file or a FIFO would not behave like a device, with the
+    // input being read back from the output, or blocking the VMM.
+    if !device.metadata()?.file_type().is_char_device() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a character device", path.display()),
+        ));
+    }
+
+    // Safe because the file descriptor is valid for the lifetime of
+    // `device`.
+    if unsafe { libc::isatty(device.as_raw_fd()) } != 0 {
+        // Safe because termios only holds integers, for which zero is valid.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // Safe because we check the return value, and the kernel only writes
+        // within the termios structure we own.
+        if unsafe { libc::tcgetattr(device.as_raw_fd(), &mut termios) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because cfmakeraw() only updates the structure we own.
+        unsafe { libc::cfmakeraw(&mut termios) };
+        // Safe because we check the return value, and the kernel only reads
+        // the termios structure we own.
+        if unsafe { libc::tcsetattr(device.as_raw_fd(), libc::TCSANOW, &termios) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(device)
+}
+
+// Reuse the pseudo terminal from before a reboot, so that users don't have to
+// reattach, or create a new one. Returns the pseudo terminal along with the
+// writer for the device output.
+fn setup_pty(pty: Option<Arc<PtyPair>>) -> io::Result<(Arc<PtyPair>, File)> {
+    let pty = match pty {
+        Some(pty) => pty,
+        None => Arc::new(create_pty()?),
+    };
+    let writer = pty.main.try_clone()?;
+
+    Ok((pty, writer))
+}
+
+// Open the file the serial port or virtio-console output goes to, rotated
+// past the maximum size from the configuration, if any.
+fn open_console_output_file(
+    config: &ConsoleConfig,
+) -> io::Result<Box<dyn io::Write + Send + Sync>> {
+    let path = config.file.as_ref().unwrap();
+    Ok(match config.max_size {
+        Some(max_size) => Box::new(RotatingFile::new(
+            path,
+            max_size,
+            config.rotate.unwrap_or(1),
+        )?),
+        None => Box::new(File::create(path)?),
+    })
+}
+
+enum VhostUserDeviceKind {
+    Blk(VhostUserConfig),
+    Net(VhostUserConfig, net_util::MacAddr),
+    Fs {
+        socket: String,
+        tag: String,
+        num_queues: usize,
+        queue_size: u16,
+        cache: Option<(VirtioSharedMemoryList, MmapRegion)>,
+    },
+}
+
+/// vhost-user device named and with its resources allocated, which remains
+/// to be created. Creating it waits for the backend to answer, hence a
+/// hotplugged device is created without holding the DeviceManager lock.
+pub struct PendingVhostUserDevice {
+    id: String,
+    kind: VhostUserDeviceKind,
+    node: DeviceNode,
+    iommu: bool,
+    // DAX cache of a virtio-fs device, to be released if the device isn't
+    // added in the end.
+    cache_mapping: Option<VirtioSharedMemoryList>,
+    seccomp_action: SeccompAction,
+}
+
+impl PendingVhostUserDevice {
+    /// Create the device, connecting to its backend unless `stream` is
+    /// already connected to it.
+    pub fn create(&mut self, stream: Option<UnixStream>) -> DeviceManagerResult<VhostUserDevice> {
+        let id = self.id.clone();
+        let device: VirtioDeviceArc = match &mut self.kind {
+            VhostUserDeviceKind::Blk(vu_cfg) => {
+                let device = Arc::new(Mutex::new(
+                    virtio_devices::vhost_user::Blk::new(
+                        id.clone(),
+                        vu_cfg.clone(),
+                        stream,
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVhostUserBlk)?,
+                ));
+                self.node.migratable = Some(Arc::clone(&device) as Arc<Mutex<dyn Migratable>>);
+                device
+            }
+            VhostUserDeviceKind::Net(vu_cfg, mac) => {
+                let device = Arc::new(Mutex::new(
+                    virtio_devices::vhost_user::Net::new(
+                        id.clone(),
+                        *mac,
+                        vu_cfg.clone(),
+                        stream,
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVhostUserNet)?,
+                ));
+                self.node.migratable = Some(Arc::clone(&device) as Arc<Mutex<dyn Migratable>>);
+                device
+            }
+            VhostUserDeviceKind::Fs {
+                socket,
+                tag,
+                num_queues,
+                queue_size,
+                cache,
+            } => {
+                let device = Arc::new(Mutex::new(
+                    virtio_devices::vhost_user::Fs::new(
+                        id.clone(),
+                        socket,
+                        tag,
+                        *num_queues,
+                        *queue_size,
+                        cache.take(),
+                        stream,
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioFs)?,
+                ));
+                self.node.migratable = Some(Arc::clone(&device) as Arc<Mutex<dyn Migratable>>);
+                device
+            }
+        };
+
+        Ok(VhostUserDevice {
+            id,
+            device,
+            iommu: self.iommu,
+            node: self.node.clone(),
+        })
+    }
+}
+
+/// vhost-user device connected to its backend, ready to be added to the VM.
+pub struct VhostUserDevice {
+    id: String,
+    device: VirtioDeviceArc,
+    iommu: bool,
+    node: DeviceNode,
+}
+
+/// Device the console input is sent to.
+#[derive(Clone, Copy)]
+pub enum ConsoleInput {
     Serial,
     VirtioConsole,
 }
@@ -402,6 +736,34 @@ pub struct Console {
 }
 
 impl Console {
+    /// Send the input straight to the serial port, whichever device the
+    /// standard input is routed to.
+    pub fn queue_serial_input_bytes(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
+        if let Some(serial) = &self.serial {
+            serial
+                .lock()
+                .expect("Failed to process pty event due to poisoned lock")
+                .queue_input_bytes(out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the input straight to the virtio-console device, whichever
+    /// device the standard input is routed to.
+    pub fn queue_virtio_console_input_bytes(&self, out: &[u8]) {
+        if let Some(virtio_console_input) = &self.virtio_console_input {
+            virtio_console_input.queue_input_bytes(out);
+        }
+    }
+
+    /// Send the input to the additional virtio-console port `name`.
+    pub fn queue_port_input_bytes(&self, name: &str, out: &[u8]) {
+        if let Some(virtio_console_input) = &self.virtio_console_input {
+            virtio_console_input.queue_port_input_bytes(name, out);
+        }
+    }
+
     pub fn queue_input_bytes(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
         match self.input {
             Some(ConsoleInput::Serial) => {
@@ -711,6 +1073,10 @@ pub struct DeviceManager {
     #[cfg(feature = "acpi")]
     ged_notification_device: Option<Arc<Mutex<devices::AcpiGEDDevice>>>,
 
+    // ACPI shutdown, reboot and suspend device
+    #[cfg(feature = "acpi")]
+    acpi_shutdown_device: Option<Arc<Mutex<devices::AcpiShutdownDevice>>>,
+
     // VM configuration
     config: Arc<Mutex<VmConfig>>,
 
@@ -736,6 +1102,22 @@ pub struct DeviceManager {
     // MSI Interrupt Manager
     msi_interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
 
+    // Legacy Interrupt Manager, forwarding the INTx interrupt of the
+    // passed through devices
+    legacy_interrupt_manager: Option<Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>>,
+
+    // Legacy interrupt each PCI slot is routed to
+    pci_irq_slots: [u8; 32],
+
+    // Thread triggering the legacy interrupts of the passed through devices
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    intx_forwarder: Option<IntxForwarder>,
+
+    // Forwarded legacy interrupt of each passed through device, indexed by
+    // b/d/f, along with the handler unmasking it on the end of interrupt
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    vfio_intx: HashMap<u32, (u64, u8, interrupt_controller::EoiHandler)>,
+
     // Passthrough device handle
     passthrough_device: Option<Arc<dyn hypervisor::Device>>,
 
@@ -764,6 +1146,10 @@ pub struct DeviceManager {
 
     reset_evt: EventFd,
 
+    // Suspend event
+    #[cfg(feature = "acpi")]
+    suspend_evt: EventFd,
+
     #[cfg(target_arch = "aarch64")]
     id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
 
@@ -777,6 +1163,38 @@ pub struct DeviceManager {
     // Possible handle to the virtio-balloon device
     balloon: Option<Arc<Mutex<virtio_devices::Balloon>>>,
 
+    // Hashmap of device's name to their corresponding virtio-net device,
+    // used to control the link status seen by the guest.
+    net_devices: HashMap<String, Arc<Mutex<virtio_devices::Net>>>,
+
+    // Hashmap of virtio device's name to the option ROM exposed through the
+    // expansion ROM BAR of its virtio-pci transport.
+    option_roms: HashMap<String, PciRom>,
+
+    // Hashmap of virtio device's name to the subsystem vendor and device IDs
+    // overriding the default ones of its virtio-pci transport.
+    pci_subsystem_ids: HashMap<String, (Option<u16>, Option<u16>)>,
+
+    // Pseudo terminal the serial port is connected to, in pty mode
+    serial_pty: Option<Arc<PtyPair>>,
+
+    // Pseudo terminal the virtio-console device is connected to, in pty mode
+    console_pty: Option<Arc<PtyPair>>,
+
+    // Pseudo terminals the virtio-console ports are connected to, kept open
+    // for the lifetime of the VM
+    console_port_ptys: Vec<PtyPair>,
+
+    // Records the nondeterministic inputs provided by the devices to the
+    // guest, when enabled through the configuration.
+    recorder: Option<Arc<Recorder>>,
+
+    // Worker threads shared by the virtio devices
+    worker_pool: Option<Arc<EpollWorkerPool>>,
+
+    // Persistent memory regions exposed through the ACPI NFIT table
+    nvdimm_regions: Vec<NvdimmRegion>,
+
     // Virtio Device activation EventFd to allow the VMM thread to trigger device
     // activation and thus start the threads from the VMM thread
     activate_evt: EventFd,
@@ -790,6 +1208,7 @@ impl DeviceManager {
         memory_manager: Arc<Mutex<MemoryManager>>,
         _exit_evt: &EventFd,
         reset_evt: &EventFd,
+        _suspend_evt: &EventFd,
         seccomp_action: SeccompAction,
         #[cfg(feature = "acpi")] numa_nodes: NumaNodes,
         activate_evt: &EventFd,
@@ -817,6 +1236,21 @@ impl DeviceManager {
                 vm,
             ));
 
+        let recorder = match &config.lock().unwrap().record {
+            Some(record) => Some(Arc::new(
+                Recorder::new(&record.path).map_err(DeviceManagerError::CreateRecorder)?,
+            )),
+            None => None,
+        };
+
+        let worker_pool = match &config.lock().unwrap().worker_pool {
+            Some(worker_pool) => Some(Arc::new(
+                EpollWorkerPool::new(worker_pool.threads as usize, &seccomp_action)
+                    .map_err(DeviceManagerError::CreateWorkerPool)?,
+            )),
+            None => None,
+        };
+
         let device_manager = DeviceManager {
             address_manager: Arc::clone(&address_manager),
             console: Arc::new(Console::default()),
@@ -826,6 +1260,8 @@ impl DeviceManager {
             cmdline_additions: Vec::new(),
             #[cfg(feature = "acpi")]
             ged_notification_device: None,
+            #[cfg(feature = "acpi")]
+            acpi_shutdown_device: None,
             config,
             memory_manager,
             virtio_devices: Vec::new(),
@@ -833,6 +1269,12 @@ impl DeviceManager {
             device_id_cnt: Wrapping(0),
             pci_bus: None,
             msi_interrupt_manager,
+            legacy_interrupt_manager: None,
+            pci_irq_slots: [0; 32],
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            intx_forwarder: None,
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            vfio_intx: HashMap::new(),
             passthrough_device: None,
             iommu_device: None,
             pci_devices_up: 0,
@@ -843,12 +1285,25 @@ impl DeviceManager {
             #[cfg(feature = "acpi")]
             exit_evt: _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
             reset_evt: reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+            #[cfg(feature = "acpi")]
+            suspend_evt: _suspend_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
             #[cfg(target_arch = "aarch64")]
             id_to_dev_info: HashMap::new(),
             seccomp_action,
             #[cfg(feature = "acpi")]
             numa_nodes,
             balloon: None,
+            net_devices: HashMap::new(),
+            option_roms: HashMap::new(),
+            pci_subsystem_ids: HashMap::new(),
+            serial_pty: None,
+            console_pty: None,
+            console_port_ptys: Vec::new(),
+            recorder,
+            worker_pool,
+            nvdimm_regions: Vec::new(),
             activate_evt: activate_evt
                 .try_clone()
                 .map_err(DeviceManagerError::EventFd)?,
@@ -877,7 +1332,14 @@ impl DeviceManager {
         Ok(device_manager)
     }
 
-    pub fn create_devices(&mut self) -> DeviceManagerResult<()> {
+    pub fn create_devices(
+        &mut self,
+        serial_pty: Option<Arc<PtyPair>>,
+        console_pty: Option<Arc<PtyPair>>,
+    ) -> DeviceManagerResult<()> {
+        self.serial_pty = serial_pty;
+        self.console_pty = console_pty;
+
         let mut virtio_devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
 
         let interrupt_controller = self.add_interrupt_controller()?;
@@ -890,6 +1352,12 @@ impl DeviceManager {
             &interrupt_controller,
         )));
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.legacy_interrupt_manager = Some(Arc::clone(&legacy_interrupt_manager));
+            self.reserve_legacy_interrupts_for_pci_devices()?;
+        }
+
         #[cfg(feature = "acpi")]
         self.address_manager
             .allocator
@@ -928,6 +1396,9 @@ impl DeviceManager {
                 self.exit_evt
                     .try_clone()
                     .map_err(DeviceManagerError::EventFd)?,
+                self.suspend_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
             )?;
         }
 
@@ -937,11 +1408,90 @@ impl DeviceManager {
 
         self.add_pci_devices(virtio_devices.clone())?;
 
+        // The boot order refers to the PCI devices, hence it can only be
+        // provided once they have been added.
+        #[cfg(target_arch = "x86_64")]
+        self.add_fw_cfg_device()?;
+
         self.virtio_devices = virtio_devices;
 
         Ok(())
     }
 
+    // OpenFirmware paths of the devices with a boot index, sorted by index,
+    // in the format expected by the firmware from the "bootorder" file.
+    #[cfg(target_arch = "x86_64")]
+    fn boot_order(&self) -> DeviceManagerResult<Vec<String>> {
+        let config = self.config.lock().unwrap();
+        let disks = config
+            .disks
+            .iter()
+            .flatten()
+            .filter_map(|disk| Some((disk.boot_index?, disk.id.clone()?, "scsi", "disk@0,0")));
+        let nets = config.net.iter().flatten().filter_map(|net| {
+            Some((
+                net.boot_index?,
+                net.id.clone()?,
+                "ethernet",
+                "ethernet-phy@0",
+            ))
+        });
+
+        let mut boot_order = Vec::new();
+        for (boot_index, id, class, child) in disks.chain(nets) {
+            let pci_device_bdf = self
+                .device_tree
+                .lock()
+                .unwrap()
+                .get(&format!("{}-{}", VIRTIO_PCI_DEVICE_NAME_PREFIX, id))
+                .and_then(|node| node.pci_bdf)
+                .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
+            // Same as the path built by SeaBIOS, where the extra root buses
+            // are prefixed with a "pci-root" node, and the function only
+            // appears for the functions other than 0.
+            let bus = (pci_device_bdf >> 8) & 0xff;
+            let device = (pci_device_bdf >> 3) & 0x1f;
+            let function = pci_device_bdf & 0x7;
+            let mut path = String::new();
+            if bus != 0 {
+                path.push_str(&format!("/pci-root@{:x}", bus));
+            }
+            path.push_str(&format!("/pci@i0cf8/{}@{:x}", class, device));
+            if function != 0 {
+                path.push_str(&format!(",{:x}", function));
+            }
+            path.push_str(&format!("/{}", child));
+            boot_order.push((boot_index, path));
+        }
+        boot_order.sort();
+
+        Ok(boot_order.into_iter().map(|(_, path)| path).collect())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn add_fw_cfg_device(&mut self) -> DeviceManagerResult<()> {
+        let boot_order = self.boot_order()?;
+        if boot_order.is_empty() {
+            return Ok(());
+        }
+
+        let mut fw_cfg = devices::legacy::FwCfg::new();
+        let mut bootorder = boot_order.join("\n").into_bytes();
+        bootorder.push(0);
+        fw_cfg.add_file("bootorder", bootorder);
+        let fw_cfg = Arc::new(Mutex::new(fw_cfg));
+
+        self.bus_devices
+            .push(Arc::clone(&fw_cfg) as Arc<Mutex<dyn BusDevice>>);
+
+        self.address_manager
+            .io_bus
+            .insert(fw_cfg, 0x510, 0x2)
+            .map_err(DeviceManagerError::BusError)?;
+
+        Ok(())
+    }
+
     fn state(&self) -> DeviceManagerState {
         DeviceManagerState {
             device_tree: self.device_tree.lock().unwrap().clone(),
@@ -1137,6 +1687,28 @@ impl DeviceManager {
         Ok(())
     }
 
+    // Reserve the legacy interrupts the INTx interrupt of the PCI slots is
+    // routed to, the slots sharing them in a round-robin fashion.
+    #[cfg(target_arch = "x86_64")]
+    fn reserve_legacy_interrupts_for_pci_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut irqs = [0u8; PCI_INTX_IRQS];
+        for irq in irqs.iter_mut() {
+            *irq = self
+                .address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .allocate_irq()
+                .ok_or(DeviceManagerError::AllocateIrq)? as u8;
+        }
+
+        for (slot, irq) in self.pci_irq_slots.iter_mut().enumerate() {
+            *irq = irqs[slot % PCI_INTX_IRQS];
+        }
+
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn add_interrupt_controller(
         &mut self,
@@ -1180,10 +1752,14 @@ impl DeviceManager {
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
         reset_evt: EventFd,
         exit_evt: EventFd,
+        suspend_evt: EventFd,
     ) -> DeviceManagerResult<Option<Arc<Mutex<devices::AcpiGEDDevice>>>> {
         let acpi_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
-            exit_evt, reset_evt,
+            exit_evt,
+            reset_evt,
+            suspend_evt,
         )));
+        self.acpi_shutdown_device = Some(acpi_device.clone());
 
         self.bus_devices
             .push(Arc::clone(&acpi_device) as Arc<Mutex<dyn BusDevice>>);
@@ -1226,12 +1802,20 @@ impl DeviceManager {
             .allocator
             .lock()
             .unwrap()
-            .allocate_io_addresses(Some(GuestAddress(0xb000)), 0x1, None)
+            .allocate_io_addresses(
+                Some(GuestAddress(devices::GED_DEVICE_ADDRESS)),
+                devices::GED_DEVICE_SIZE,
+                None,
+            )
             .ok_or(DeviceManagerError::AllocateIOPort)?;
 
         self.address_manager
             .io_bus
-            .insert(ged_device.clone(), 0xb000, 0x1)
+            .insert(
+                ged_device.clone(),
+                devices::GED_DEVICE_ADDRESS,
+                devices::GED_DEVICE_SIZE,
+            )
             .map_err(DeviceManagerError::BusError)?;
 
         let pm_timer_device = Arc::new(Mutex::new(devices::AcpiPMTimerDevice::new()));
@@ -1282,10 +1866,11 @@ impl DeviceManager {
             let mem_below_4g = std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
             let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
 
-            let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
-                mem_below_4g,
-                mem_above_4g,
-            )));
+            let mut cmos = devices::legacy::Cmos::new(mem_below_4g, mem_above_4g);
+            if let Some(recorder) = &self.recorder {
+                cmos.set_recorder(recorder.clone());
+            }
+            let cmos = Arc::new(Mutex::new(cmos));
 
             self.bus_devices
                 .push(Arc::clone(&cmos) as Arc<Mutex<dyn BusDevice>>);
@@ -1331,7 +1916,11 @@ impl DeviceManager {
             })
             .map_err(DeviceManagerError::CreateInterruptGroup)?;
 
-        let rtc_device = Arc::new(Mutex::new(devices::legacy::RTC::new(interrupt_group)));
+        let mut rtc_device = devices::legacy::RTC::new(interrupt_group);
+        if let Some(recorder) = &self.recorder {
+            rtc_device.set_recorder(recorder.clone());
+        }
+        let rtc_device = Arc::new(Mutex::new(rtc_device));
 
         self.bus_devices
             .push(Arc::clone(&rtc_device) as Arc<Mutex<dyn BusDevice>>);
@@ -1352,6 +1941,47 @@ impl DeviceManager {
             },
         );
 
+        let efi_vars = self.config.lock().unwrap().efi_vars.clone();
+        if let Some(efi_vars) = efi_vars {
+            self.add_efi_vars_flash(&efi_vars.path)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn add_efi_vars_flash(&mut self, path: &Path) -> DeviceManagerResult<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(DeviceManagerError::EfiVarsFlash)?;
+        let flash =
+            devices::legacy::CfiFlash::new(file).map_err(DeviceManagerError::EfiVarsFlash)?;
+        let size = flash.size();
+        if size > arch::layout::UEFI_VARS_SIZE {
+            return Err(DeviceManagerError::EfiVarsTooBig);
+        }
+        let flash = Arc::new(Mutex::new(flash));
+
+        self.bus_devices
+            .push(Arc::clone(&flash) as Arc<Mutex<dyn BusDevice>>);
+
+        self.address_manager
+            .mmio_bus
+            .insert(flash, arch::layout::UEFI_VARS_START, size)
+            .map_err(DeviceManagerError::BusError)?;
+
+        // The flash doesn't raise any interrupt.
+        self.id_to_dev_info.insert(
+            (DeviceType::Flash, "flash".to_string()),
+            MMIODeviceInfo {
+                addr: arch::layout::UEFI_VARS_START,
+                len: size,
+                irq: 0,
+            },
+        );
+
         Ok(())
     }
 
@@ -1472,10 +2102,19 @@ impl DeviceManager {
     ) -> DeviceManagerResult<Arc<Console>> {
         let serial_config = self.config.lock().unwrap().serial.clone();
         let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
-            ConsoleOutputMode::File => Some(Box::new(
-                File::create(serial_config.file.as_ref().unwrap())
+            ConsoleOutputMode::File => Some(
+                open_console_output_file(&serial_config)
                     .map_err(DeviceManagerError::SerialOutputFileOpen)?,
-            )),
+            ),
+            ConsoleOutputMode::Pty => {
+                let (pty, writer) =
+                    setup_pty(self.serial_pty.take()).map_err(DeviceManagerError::SerialPtyOpen)?;
+                info!("Serial port connected to {}", pty.path.display());
+                // Report the path through the VM information.
+                self.config.lock().unwrap().serial.file = Some(pty.path.clone());
+                self.serial_pty = Some(pty);
+                Some(Box::new(writer))
+            }
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
         };
@@ -1488,10 +2127,18 @@ impl DeviceManager {
         // Create serial and virtio-console
         let console_config = self.config.lock().unwrap().console.clone();
         let console_writer: Option<Box<dyn io::Write + Send + Sync>> = match console_config.mode {
-            ConsoleOutputMode::File => Some(Box::new(
-                File::create(console_config.file.as_ref().unwrap())
+            ConsoleOutputMode::File => Some(
+                open_console_output_file(&console_config)
                     .map_err(DeviceManagerError::ConsoleOutputFileOpen)?,
-            )),
+            ),
+            ConsoleOutputMode::Pty => {
+                let (pty, writer) = setup_pty(self.console_pty.take())
+                    .map_err(DeviceManagerError::ConsolePtyOpen)?;
+                info!("virtio-console connected to {}", pty.path.display());
+                self.config.lock().unwrap().console.file = Some(pty.path.clone());
+                self.console_pty = Some(pty);
+                Some(Box::new(writer))
+            }
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Null => Some(Box::new(sink())),
             ConsoleOutputMode::Off => None,
@@ -1500,11 +2147,13 @@ impl DeviceManager {
         let virtio_console_input = if let Some(writer) = console_writer {
             let id = String::from(CONSOLE_DEVICE_NAME);
 
+            let ports = self.make_console_ports()?;
             let (virtio_console_device, virtio_console_input) = virtio_devices::Console::new(
                 id.clone(),
                 writer,
                 col,
                 row,
+                ports,
                 console_config.iommu,
                 self.seccomp_action.clone(),
             )
@@ -1544,6 +2193,56 @@ impl DeviceManager {
         }))
     }
 
+    fn make_console_ports(&mut self) -> DeviceManagerResult<Vec<ConsolePort>> {
+        let mut ports = Vec::new();
+        let console_ports = self.config.lock().unwrap().console_ports.clone();
+
+        for (index, port_config) in console_ports.iter().flatten().enumerate() {
+            let endpoint = match port_config.mode {
+                ConsolePortMode::File => ConsolePortEndpoint::File(
+                    File::create(port_config.file.as_ref().unwrap())
+                        .map_err(DeviceManagerError::ConsolePortFileOpen)?,
+                ),
+                ConsolePortMode::Pty => {
+                    let pty = create_pty().map_err(DeviceManagerError::ConsolePortPtyOpen)?;
+                    info!(
+                        "virtio-console port {} connected to {}",
+                        port_config.name,
+                        pty.path.display()
+                    );
+                    // Report the path through the VM information.
+                    if let Some(console_ports) = &mut self.config.lock().unwrap().console_ports {
+                        console_ports[index].file = Some(pty.path.clone());
+                    }
+                    let main = pty
+                        .main
+                        .try_clone()
+                        .map_err(DeviceManagerError::ConsolePortPtyOpen)?;
+                    self.console_port_ptys.push(pty);
+                    ConsolePortEndpoint::Pty(main)
+                }
+                ConsolePortMode::Socket => {
+                    let path = port_config.socket.clone().unwrap();
+                    // Remove the socket left over by a previous run.
+                    std::fs::remove_file(&path).ok();
+                    let listener = UnixListener::bind(&path)
+                        .map_err(DeviceManagerError::ConsolePortSocketBind)?;
+                    ConsolePortEndpoint::Socket(listener, path)
+                }
+                ConsolePortMode::Device => ConsolePortEndpoint::Device(
+                    open_console_port_device(port_config.device.as_ref().unwrap())
+                        .map_err(DeviceManagerError::ConsolePortDeviceOpen)?,
+                ),
+            };
+            ports.push(ConsolePort {
+                name: port_config.name.clone(),
+                endpoint,
+            });
+        }
+
+        Ok(ports)
+    }
+
     fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
 
@@ -1572,10 +2271,7 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_block_device(
-        &mut self,
-        disk_cfg: &mut DiskConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    fn disk_id(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<String> {
         let id = if let Some(id) = &disk_cfg.id {
             id.clone()
         } else {
@@ -1584,49 +2280,55 @@ impl DeviceManager {
             id
         };
 
-        if disk_cfg.vhost_user {
-            let socket = disk_cfg.vhost_socket.as_ref().unwrap().clone();
-            let vu_cfg = VhostUserConfig {
-                socket,
-                num_queues: disk_cfg.num_queues,
-                queue_size: disk_cfg.queue_size,
-            };
-            let vhost_user_block_device = Arc::new(Mutex::new(
-                match virtio_devices::vhost_user::Blk::new(
-                    id.clone(),
-                    vu_cfg,
-                    self.seccomp_action.clone(),
-                ) {
-                    Ok(vub_device) => vub_device,
-                    Err(e) => {
-                        return Err(DeviceManagerError::CreateVhostUserBlk(e));
-                    }
-                },
-            ));
+        if disk_cfg.pci_subsystem_vendor_id.is_some() || disk_cfg.pci_subsystem_id.is_some() {
+            self.pci_subsystem_ids.insert(
+                id.clone(),
+                (disk_cfg.pci_subsystem_vendor_id, disk_cfg.pci_subsystem_id),
+            );
+        }
 
-            // Fill the device tree with a new node. In case of restore, we
-            // know there is nothing to do, so we can simply override the
-            // existing entry.
-            self.device_tree
-                .lock()
-                .unwrap()
-                .insert(id.clone(), device_node!(id, vhost_user_block_device));
+        Ok(id)
+    }
 
-            Ok((
-                Arc::clone(&vhost_user_block_device) as VirtioDeviceArc,
-                false,
-                id,
-            ))
-        } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let image: File = options
-                .open(
+    fn prepare_vhost_user_blk(
+        &mut self,
+        disk_cfg: &mut DiskConfig,
+    ) -> DeviceManagerResult<PendingVhostUserDevice> {
+        let id = self.disk_id(disk_cfg)?;
+        let vu_cfg = VhostUserConfig {
+            socket: disk_cfg.vhost_socket.as_ref().unwrap().clone(),
+            num_queues: disk_cfg.num_queues,
+            queue_size: disk_cfg.queue_size,
+        };
+
+        Ok(PendingVhostUserDevice {
+            node: device_node!(id),
+            id,
+            kind: VhostUserDeviceKind::Blk(vu_cfg),
+            iommu: false,
+            cache_mapping: None,
+            seccomp_action: self.seccomp_action.clone(),
+        })
+    }
+
+    fn make_virtio_block_device(
+        &mut self,
+        disk_cfg: &mut DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+        if disk_cfg.vhost_user {
+            let device = self.prepare_vhost_user_blk(disk_cfg)?.create(None)?;
+            Ok(self.insert_vhost_user_device(device))
+        } else {
+            let id = self.disk_id(disk_cfg)?;
+            let mut options = OpenOptions::new();
+            options.read(true);
+            options.write(!disk_cfg.readonly);
+            if disk_cfg.direct {
+                options.custom_flags(libc::O_DIRECT);
+            }
+            // Open block device path
+            let image: File = options
+                .open(
                     disk_cfg
                         .path
                         .as_ref()
@@ -1639,7 +2341,56 @@ impl DeviceManager {
 
             let image_type = qcow::detect_image_type(&mut raw_img)
                 .map_err(DeviceManagerError::DetectImageType)?;
+            let interrupt_coalescing = disk_cfg.coalesce_events.zip(disk_cfg.coalesce_usecs);
+            let queue_affinity = disk_cfg.queue_affinity.as_ref().map(queue_affinity_map);
             let (virtio_device, migratable_device) = match image_type {
+                // The payload of an encrypted image is only accessed through
+                // the synchronous backend, which decrypts it.
+                _ if disk_cfg.key_file.is_some() => {
+                    let mut passphrase = std::fs::read(disk_cfg.key_file.as_ref().unwrap())
+                        .map_err(DeviceManagerError::DiskKeyFile)?;
+                    let luks_img = LuksFile::open(raw_img, &passphrase);
+                    clear_secret(&mut passphrase);
+                    let luks_img = luks_img.map_err(DeviceManagerError::LuksDeviceCreate)?;
+                    let dev = Arc::new(Mutex::new(
+                        virtio_devices::Block::new(
+                            id.clone(),
+                            luks_img,
+                            disk_cfg
+                                .path
+                                .as_ref()
+                                .ok_or(DeviceManagerError::NoDiskPath)?
+                                .clone(),
+                            disk_cfg.readonly,
+                            disk_cfg.iommu,
+                            disk_cfg.num_queues,
+                            disk_cfg.queue_size,
+                            self.seccomp_action.clone(),
+                        )
+                        .map_err(DeviceManagerError::CreateVirtioBlock)?,
+                    ));
+                    if let Some((max_events, max_usecs)) = interrupt_coalescing {
+                        dev.lock()
+                            .unwrap()
+                            .set_interrupt_coalescing(max_events, max_usecs);
+                    }
+                    if let Some(serial) = &disk_cfg.serial {
+                        dev.lock().unwrap().set_serial(serial.clone());
+                    }
+                    if let Some(worker_pool) = &self.worker_pool {
+                        dev.lock().unwrap().set_worker_pool(worker_pool.clone());
+                    }
+                    if let Some(queue_affinity) = &queue_affinity {
+                        dev.lock()
+                            .unwrap()
+                            .set_queue_affinity(queue_affinity.clone());
+                    }
+
+                    (
+                        Arc::clone(&dev) as VirtioDeviceArc,
+                        dev as Arc<Mutex<dyn Migratable>>,
+                    )
+                }
                 ImageType::Raw => {
                     // Use asynchronous backend relying on io_uring if the
                     // syscalls are supported.
@@ -1661,6 +2412,22 @@ impl DeviceManager {
                             )
                             .map_err(DeviceManagerError::CreateVirtioBlock)?,
                         ));
+                        if let Some((max_events, max_usecs)) = interrupt_coalescing {
+                            dev.lock()
+                                .unwrap()
+                                .set_interrupt_coalescing(max_events, max_usecs);
+                        }
+                        if let Some(serial) = &disk_cfg.serial {
+                            dev.lock().unwrap().set_serial(serial.clone());
+                        }
+                        if let Some(worker_pool) = &self.worker_pool {
+                            dev.lock().unwrap().set_worker_pool(worker_pool.clone());
+                        }
+                        if let Some(queue_affinity) = &queue_affinity {
+                            dev.lock()
+                                .unwrap()
+                                .set_queue_affinity(queue_affinity.clone());
+                        }
 
                         (
                             Arc::clone(&dev) as VirtioDeviceArc,
@@ -1684,6 +2451,22 @@ impl DeviceManager {
                             )
                             .map_err(DeviceManagerError::CreateVirtioBlock)?,
                         ));
+                        if let Some((max_events, max_usecs)) = interrupt_coalescing {
+                            dev.lock()
+                                .unwrap()
+                                .set_interrupt_coalescing(max_events, max_usecs);
+                        }
+                        if let Some(serial) = &disk_cfg.serial {
+                            dev.lock().unwrap().set_serial(serial.clone());
+                        }
+                        if let Some(worker_pool) = &self.worker_pool {
+                            dev.lock().unwrap().set_worker_pool(worker_pool.clone());
+                        }
+                        if let Some(queue_affinity) = &queue_affinity {
+                            dev.lock()
+                                .unwrap()
+                                .set_queue_affinity(queue_affinity.clone());
+                        }
 
                         (
                             Arc::clone(&dev) as VirtioDeviceArc,
@@ -1711,6 +2494,22 @@ impl DeviceManager {
                         )
                         .map_err(DeviceManagerError::CreateVirtioBlock)?,
                     ));
+                    if let Some((max_events, max_usecs)) = interrupt_coalescing {
+                        dev.lock()
+                            .unwrap()
+                            .set_interrupt_coalescing(max_events, max_usecs);
+                    }
+                    if let Some(serial) = &disk_cfg.serial {
+                        dev.lock().unwrap().set_serial(serial.clone());
+                    }
+                    if let Some(worker_pool) = &self.worker_pool {
+                        dev.lock().unwrap().set_worker_pool(worker_pool.clone());
+                    }
+                    if let Some(queue_affinity) = &queue_affinity {
+                        dev.lock()
+                            .unwrap()
+                            .set_queue_affinity(queue_affinity.clone());
+                    }
 
                     (
                         Arc::clone(&dev) as VirtioDeviceArc,
@@ -1746,10 +2545,7 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_net_device(
-        &mut self,
-        net_cfg: &mut NetConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    fn net_id(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<String> {
         let id = if let Some(id) = &net_cfg.id {
             id.clone()
         } else {
@@ -1758,41 +2554,51 @@ impl DeviceManager {
             id
         };
 
-        if net_cfg.vhost_user {
-            let socket = net_cfg.vhost_socket.as_ref().unwrap().clone();
-            let vu_cfg = VhostUserConfig {
-                socket,
-                num_queues: net_cfg.num_queues,
-                queue_size: net_cfg.queue_size,
-            };
-            let vhost_user_net_device = Arc::new(Mutex::new(
-                match virtio_devices::vhost_user::Net::new(
-                    id.clone(),
-                    net_cfg.mac,
-                    vu_cfg,
-                    self.seccomp_action.clone(),
-                ) {
-                    Ok(vun_device) => vun_device,
-                    Err(e) => {
-                        return Err(DeviceManagerError::CreateVhostUserNet(e));
-                    }
-                },
-            ));
+        if let Some(romfile) = &net_cfg.romfile {
+            let rom = PciRom::from_file(romfile).map_err(DeviceManagerError::LoadOptionRom)?;
+            self.option_roms.insert(id.clone(), rom);
+        }
 
-            // Fill the device tree with a new node. In case of restore, we
-            // know there is nothing to do, so we can simply override the
-            // existing entry.
-            self.device_tree
-                .lock()
-                .unwrap()
-                .insert(id.clone(), device_node!(id, vhost_user_net_device));
+        if net_cfg.pci_subsystem_vendor_id.is_some() || net_cfg.pci_subsystem_id.is_some() {
+            self.pci_subsystem_ids.insert(
+                id.clone(),
+                (net_cfg.pci_subsystem_vendor_id, net_cfg.pci_subsystem_id),
+            );
+        }
 
-            Ok((
-                Arc::clone(&vhost_user_net_device) as VirtioDeviceArc,
-                net_cfg.iommu,
-                id,
-            ))
+        Ok(id)
+    }
+
+    fn prepare_vhost_user_net(
+        &mut self,
+        net_cfg: &mut NetConfig,
+    ) -> DeviceManagerResult<PendingVhostUserDevice> {
+        let id = self.net_id(net_cfg)?;
+        let vu_cfg = VhostUserConfig {
+            socket: net_cfg.vhost_socket.as_ref().unwrap().clone(),
+            num_queues: net_cfg.num_queues,
+            queue_size: net_cfg.queue_size,
+        };
+
+        Ok(PendingVhostUserDevice {
+            node: device_node!(id),
+            id,
+            kind: VhostUserDeviceKind::Net(vu_cfg, net_cfg.mac),
+            iommu: net_cfg.iommu,
+            cache_mapping: None,
+            seccomp_action: self.seccomp_action.clone(),
+        })
+    }
+
+    fn make_virtio_net_device(
+        &mut self,
+        net_cfg: &mut NetConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+        if net_cfg.vhost_user {
+            let device = self.prepare_vhost_user_net(net_cfg)?.create(None)?;
+            Ok(self.insert_vhost_user_device(device))
         } else {
+            let id = self.net_id(net_cfg)?;
             let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
@@ -1839,6 +2645,33 @@ impl DeviceManager {
                 ))
             };
 
+            if let Some(recorder) = &self.recorder {
+                virtio_net_device
+                    .lock()
+                    .unwrap()
+                    .set_recorder(recorder.clone());
+            }
+            if let Some((max_events, max_usecs)) =
+                net_cfg.coalesce_events.zip(net_cfg.coalesce_usecs)
+            {
+                virtio_net_device
+                    .lock()
+                    .unwrap()
+                    .set_interrupt_coalescing(max_events, max_usecs);
+            }
+            if let Some(worker_pool) = &self.worker_pool {
+                virtio_net_device
+                    .lock()
+                    .unwrap()
+                    .set_worker_pool(worker_pool.clone());
+            }
+            if let Some(queue_affinity) = &net_cfg.queue_affinity {
+                virtio_net_device
+                    .lock()
+                    .unwrap()
+                    .set_queue_affinity(queue_affinity_map(queue_affinity));
+            }
+
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
             // existing entry.
@@ -1847,6 +2680,9 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, virtio_net_device));
 
+            self.net_devices
+                .insert(id.clone(), Arc::clone(&virtio_net_device));
+
             Ok((
                 Arc::clone(&virtio_net_device) as VirtioDeviceArc,
                 net_cfg.iommu,
@@ -1881,15 +2717,18 @@ impl DeviceManager {
         if let Some(rng_path) = rng_config.src.to_str() {
             let id = String::from(RNG_DEVICE_NAME);
 
-            let virtio_rng_device = Arc::new(Mutex::new(
-                virtio_devices::Rng::new(
-                    id.clone(),
-                    rng_path,
-                    rng_config.iommu,
-                    self.seccomp_action.clone(),
-                )
-                .map_err(DeviceManagerError::CreateVirtioRng)?,
-            ));
+            let mut virtio_rng_device = virtio_devices::Rng::new(
+                id.clone(),
+                rng_path,
+                rng_config.iommu,
+                rng_config.rate_limit,
+                self.seccomp_action.clone(),
+            )
+            .map_err(DeviceManagerError::CreateVirtioRng)?;
+            if let Some(recorder) = &self.recorder {
+                virtio_rng_device.set_recorder(recorder.clone());
+            }
+            let virtio_rng_device = Arc::new(Mutex::new(virtio_rng_device));
             devices.push((
                 Arc::clone(&virtio_rng_device) as VirtioDeviceArc,
                 rng_config.iommu,
@@ -1908,10 +2747,10 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_fs_device(
+    fn prepare_virtio_fs(
         &mut self,
         fs_cfg: &mut FsConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<PendingVhostUserDevice> {
         let id = if let Some(id) = &fs_cfg.id {
             id.clone()
         } else {
@@ -1920,6 +2759,12 @@ impl DeviceManager {
             id
         };
 
+        let fs_socket = fs_cfg
+            .socket
+            .to_str()
+            .ok_or(DeviceManagerError::NoVirtioFsSock)?
+            .to_owned();
+
         let mut node = device_node!(id);
 
         // Look for the id in the device tree. If it can be found, that means
@@ -1952,103 +2797,101 @@ impl DeviceManager {
             None
         };
 
-        if let Some(fs_socket) = fs_cfg.socket.to_str() {
-            let cache = if fs_cfg.dax {
-                let (cache_base, cache_size) = if let Some((base, size)) = cache_range {
-                    // The memory needs to be 2MiB aligned in order to support
-                    // hugepages.
-                    self.address_manager
-                        .allocator
-                        .lock()
-                        .unwrap()
-                        .allocate_mmio_addresses(
-                            Some(GuestAddress(base)),
-                            size as GuestUsize,
-                            Some(0x0020_0000),
-                        )
-                        .ok_or(DeviceManagerError::FsRangeAllocation)?;
-
-                    (base, size)
-                } else {
-                    let size = fs_cfg.cache_size;
-                    // The memory needs to be 2MiB aligned in order to support
-                    // hugepages.
-                    let base = self
-                        .address_manager
-                        .allocator
-                        .lock()
-                        .unwrap()
-                        .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
-                        .ok_or(DeviceManagerError::FsRangeAllocation)?;
-
-                    (base.raw_value(), size)
-                };
-
-                // Update the node with correct resource information.
-                node.resources.push(Resource::MmioAddressRange {
-                    base: cache_base,
-                    size: cache_size,
-                });
-
-                let mmap_region = MmapRegion::build(
-                    None,
-                    cache_size as usize,
-                    libc::PROT_NONE,
-                    libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-                )
-                .map_err(DeviceManagerError::NewMmapRegion)?;
-                let host_addr: u64 = mmap_region.as_ptr() as u64;
-
-                let mem_slot = self
-                    .memory_manager
+        let cache = if fs_cfg.dax {
+            let (cache_base, cache_size) = if let Some((base, size)) = cache_range {
+                // The memory needs to be 2MiB aligned in order to support
+                // hugepages.
+                self.address_manager
+                    .allocator
                     .lock()
                     .unwrap()
-                    .create_userspace_mapping(
-                        cache_base, cache_size, host_addr, false, false, false,
+                    .allocate_mmio_addresses(
+                        Some(GuestAddress(base)),
+                        size as GuestUsize,
+                        Some(0x0020_0000),
                     )
-                    .map_err(DeviceManagerError::MemoryManager)?;
-
-                let mut region_list = Vec::new();
-                region_list.push(VirtioSharedMemory {
-                    offset: 0,
-                    len: cache_size,
-                });
-
-                Some((
-                    VirtioSharedMemoryList {
-                        host_addr,
-                        mem_slot,
-                        addr: GuestAddress(cache_base),
-                        len: cache_size as GuestUsize,
-                        region_list,
-                    },
-                    mmap_region,
-                ))
+                    .ok_or(DeviceManagerError::FsRangeAllocation)?;
+
+                (base, size)
             } else {
-                None
+                let size = fs_cfg.cache_size;
+                // The memory needs to be 2MiB aligned in order to support
+                // hugepages.
+                let base = self
+                    .address_manager
+                    .allocator
+                    .lock()
+                    .unwrap()
+                    .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
+                    .ok_or(DeviceManagerError::FsRangeAllocation)?;
+
+                (base.raw_value(), size)
             };
 
-            let virtio_fs_device = Arc::new(Mutex::new(
-                virtio_devices::vhost_user::Fs::new(
-                    id.clone(),
-                    fs_socket,
-                    &fs_cfg.tag,
-                    fs_cfg.num_queues,
-                    fs_cfg.queue_size,
-                    cache,
-                    self.seccomp_action.clone(),
-                )
-                .map_err(DeviceManagerError::CreateVirtioFs)?,
-            ));
+            // Update the node with correct resource information.
+            node.resources.push(Resource::MmioAddressRange {
+                base: cache_base,
+                size: cache_size,
+            });
 
-            // Update the device tree with the migratable device.
-            node.migratable = Some(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
-            self.device_tree.lock().unwrap().insert(id.clone(), node);
+            let mmap_region = MmapRegion::build(
+                None,
+                cache_size as usize,
+                libc::PROT_NONE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+            )
+            .map_err(DeviceManagerError::NewMmapRegion)?;
+            let host_addr: u64 = mmap_region.as_ptr() as u64;
 
-            Ok((Arc::clone(&virtio_fs_device) as VirtioDeviceArc, false, id))
+            let mem_slot = self
+                .memory_manager
+                .lock()
+                .unwrap()
+                .create_userspace_mapping(cache_base, cache_size, host_addr, false, false, false)
+                .map_err(DeviceManagerError::MemoryManager)?;
+
+            let mut region_list = Vec::new();
+            region_list.push(VirtioSharedMemory {
+                offset: 0,
+                len: cache_size,
+            });
+
+            Some((
+                VirtioSharedMemoryList {
+                    host_addr,
+                    mem_slot,
+                    addr: GuestAddress(cache_base),
+                    len: cache_size as GuestUsize,
+                    region_list,
+                },
+                mmap_region,
+            ))
         } else {
-            Err(DeviceManagerError::NoVirtioFsSock)
-        }
+            None
+        };
+
+        Ok(PendingVhostUserDevice {
+            node,
+            cache_mapping: cache.as_ref().map(|(list, _)| list.clone()),
+            kind: VhostUserDeviceKind::Fs {
+                socket: fs_socket,
+                tag: fs_cfg.tag.clone(),
+                num_queues: fs_cfg.num_queues,
+                queue_size: fs_cfg.queue_size,
+                cache,
+            },
+            id,
+            iommu: false,
+            seccomp_action: self.seccomp_action.clone(),
+        })
+    }
+
+    fn make_virtio_fs_device(
+        &mut self,
+        fs_cfg: &mut FsConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+        let device = self.prepare_virtio_fs(fs_cfg)?.create(None)?;
+        Ok(self.insert_vhost_user_device(device))
     }
 
     fn make_virtio_fs_devices(
@@ -2067,24 +2910,17 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_pmem_device(
+    // Map the file backing a persistent memory device into the guest address
+    // space, returning the file along with the mapping.
+    fn map_pmem_region(
         &mut self,
-        pmem_cfg: &mut PmemConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
-        let id = if let Some(id) = &pmem_cfg.id {
-            id.clone()
-        } else {
-            let id = self.next_device_name(PMEM_DEVICE_NAME_PREFIX)?;
-            pmem_cfg.id = Some(id.clone());
-            id
-        };
-
-        let mut node = device_node!(id);
-
+        id: &str,
+        pmem_cfg: &PmemConfig,
+    ) -> DeviceManagerResult<(File, MmapRegion, virtio_devices::UserspaceMapping)> {
         // Look for the id in the device tree. If it can be found, that means
         // the device is being restored, otherwise it's created from scratch.
-        let region_range = if let Some(node) = self.device_tree.lock().unwrap().get(&id) {
-            debug!("Restoring virtio-pmem {} resources", id);
+        let region_range = if let Some(node) = self.device_tree.lock().unwrap().get(id) {
+            debug!("Restoring pmem {} resources", id);
 
             let mut region_range: Option<(u64, u64)> = None;
             for resource in node.resources.iter() {
@@ -2208,6 +3044,27 @@ impl DeviceManager {
             mergeable: pmem_cfg.mergeable,
         };
 
+        Ok((file, mmap_region, mapping))
+    }
+
+    fn make_virtio_pmem_device(
+        &mut self,
+        pmem_cfg: &mut PmemConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+        let id = if let Some(id) = &pmem_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(PMEM_DEVICE_NAME_PREFIX)?;
+            pmem_cfg.id = Some(id.clone());
+            id
+        };
+
+        let mut node = device_node!(id);
+
+        let (file, mmap_region, mapping) = self.map_pmem_region(&id, pmem_cfg)?;
+        let region_base = mapping.addr.raw_value();
+        let region_size = mapping.len;
+
         let virtio_pmem_device = Arc::new(Mutex::new(
             virtio_devices::Pmem::new(
                 id.clone(),
@@ -2237,6 +3094,46 @@ impl DeviceManager {
         ))
     }
 
+    fn make_nvdimm_region(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<()> {
+        let id = if let Some(id) = &pmem_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(PMEM_DEVICE_NAME_PREFIX)?;
+            pmem_cfg.id = Some(id.clone());
+            id
+        };
+
+        let mut node = device_node!(id);
+
+        // The file is only accessed by the guest through the mapping, which
+        // owns its own handle on the file.
+        let (_, mmap_region, mapping) = self.map_pmem_region(&id, pmem_cfg)?;
+
+        // Update the device tree with correct resource information, so that
+        // the region is mapped at the same address on restore.
+        node.resources.push(Resource::MmioAddressRange {
+            base: mapping.addr.raw_value(),
+            size: mapping.len,
+        });
+        self.device_tree.lock().unwrap().insert(id.clone(), node);
+
+        let handle = self.nvdimm_regions.len() as u32 + 1;
+        self.nvdimm_regions.push(NvdimmRegion {
+            id,
+            handle,
+            base: mapping.addr,
+            size: mapping.len,
+            _region: mmap_region,
+        });
+
+        Ok(())
+    }
+
+    /// Persistent memory regions to be described by the ACPI NFIT table.
+    pub fn nvdimm_regions(&self) -> &[NvdimmRegion] {
+        &self.nvdimm_regions
+    }
+
     fn make_virtio_pmem_devices(
         &mut self,
     ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
@@ -2245,7 +3142,11 @@ impl DeviceManager {
         let mut pmem_devices = self.config.lock().unwrap().pmem.clone();
         if let Some(pmem_list_cfg) = &mut pmem_devices {
             for pmem_cfg in pmem_list_cfg.iter_mut() {
-                devices.push(self.make_virtio_pmem_device(pmem_cfg)?);
+                if pmem_cfg.nvdimm {
+                    self.make_nvdimm_region(pmem_cfg)?;
+                } else {
+                    devices.push(self.make_virtio_pmem_device(pmem_cfg)?);
+                }
             }
         }
         self.config.lock().unwrap().pmem = pmem_devices;
@@ -2545,6 +3446,29 @@ impl DeviceManager {
         )
         .map_err(DeviceManagerError::VfioPciCreate)?;
 
+        if let Some(romfile) = &device_cfg.romfile {
+            let rom = PciRom::from_file(romfile).map_err(DeviceManagerError::LoadOptionRom)?;
+            vfio_pci_device.set_rom(rom);
+        }
+
+        if let Some(class_code) = device_cfg.pci_class_code {
+            vfio_pci_device.set_class_code(class_code);
+        }
+        if let Some(revision) = device_cfg.pci_revision {
+            vfio_pci_device.set_revision(revision);
+        }
+
+        if let (Some(legacy_interrupt_manager), Some(irq)) =
+            (&self.legacy_interrupt_manager, self.pci_irq(pci_device_bdf))
+        {
+            let interrupt_group = legacy_interrupt_manager
+                .create_group(LegacyIrqGroupConfig {
+                    irq: irq as InterruptIndex,
+                })
+                .map_err(DeviceManagerError::CreateInterruptGroup)?;
+            vfio_pci_device.set_intx(interrupt_group, irq);
+        }
+
         let vfio_name = if let Some(id) = &device_cfg.id {
             if self.pci_id_list.contains_key(id) {
                 return Err(DeviceManagerError::DeviceIdAlreadyInUse);
@@ -2572,6 +3496,9 @@ impl DeviceManager {
             });
         }
 
+        #[cfg(target_arch = "x86_64")]
+        self.forward_vfio_intx(&vfio_pci_device, pci_device_bdf)?;
+
         let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
 
         self.add_pci_device(
@@ -2590,6 +3517,77 @@ impl DeviceManager {
         Ok((pci_device_bdf, vfio_name))
     }
 
+    // Trigger the legacy interrupt of the device whenever the host signals
+    // it, and unmask it on the host once the guest reports the end of the
+    // interrupt to the IOAPIC.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn forward_vfio_intx(
+        &mut self,
+        vfio_pci_device: &VfioPciDevice,
+        pci_device_bdf: u32,
+    ) -> DeviceManagerResult<()> {
+        let (trigger, interrupt_group, eoi_handler, irq) = match (
+            vfio_pci_device.intx_trigger(),
+            vfio_pci_device.intx_eoi_handler(),
+            self.pci_irq(pci_device_bdf),
+        ) {
+            (Some((trigger, interrupt_group)), Some(eoi_handler), Some(irq)) => {
+                (trigger, interrupt_group, eoi_handler, irq)
+            }
+            _ => return Ok(()),
+        };
+
+        if self.intx_forwarder.is_none() {
+            self.intx_forwarder = Some(
+                IntxForwarder::new(&self.seccomp_action)
+                    .map_err(DeviceManagerError::ForwardVfioIntx)?,
+            );
+        }
+        let token = self
+            .intx_forwarder
+            .as_mut()
+            .unwrap()
+            .add(trigger, interrupt_group)
+            .map_err(DeviceManagerError::ForwardVfioIntx)?;
+
+        if let Some(interrupt_controller) = &self.interrupt_controller {
+            interrupt_controller
+                .lock()
+                .unwrap()
+                .add_eoi_handler(irq as usize, eoi_handler.clone());
+        }
+        self.vfio_intx
+            .insert(pci_device_bdf, (token, irq, eoi_handler));
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn remove_vfio_intx(&mut self, pci_device_bdf: u32) {
+        if let Some((token, irq, eoi_handler)) = self.vfio_intx.remove(&pci_device_bdf) {
+            if let Some(intx_forwarder) = &mut self.intx_forwarder {
+                intx_forwarder.remove(token);
+            }
+            if let Some(interrupt_controller) = &self.interrupt_controller {
+                interrupt_controller
+                    .lock()
+                    .unwrap()
+                    .remove_eoi_handler(irq as usize, &eoi_handler);
+            }
+        }
+    }
+
+    // Legacy interrupt the device at `pci_device_bdf` is routed to. Only the
+    // slots of the bus 0 are described to the guest.
+    #[cfg(feature = "kvm")]
+    fn pci_irq(&self, pci_device_bdf: u32) -> Option<u8> {
+        if (pci_device_bdf >> 8) & 0xff != 0 {
+            return None;
+        }
+
+        Some(self.pci_irq_slots[((pci_device_bdf >> 3) & 0x1f) as usize])
+    }
+
     fn add_pci_device(
         &mut self,
         pci_bus: &mut PciBus,
@@ -2679,7 +3677,7 @@ impl DeviceManager {
 
         // Look for the id in the device tree. If it can be found, that means
         // the device is being restored, otherwise it's created from scratch.
-        let (pci_device_bdf, config_bar_addr) =
+        let (pci_device_bdf, config_bar_addr, rom_bar_addr) =
             if let Some(node) = self.device_tree.lock().unwrap().get(&id) {
                 debug!("Restoring virtio-pci {} resources", id);
                 let pci_device_bdf = node
@@ -2703,7 +3701,15 @@ impl DeviceManager {
                     }
                 };
 
-                (pci_device_bdf, config_bar_addr)
+                // The expansion ROM BAR, if any, is always allocated last.
+                let rom_bar_addr = match node.resources.last() {
+                    Some(Resource::MmioAddressRange { base, .. }) if node.resources.len() > 1 => {
+                        Some(*base)
+                    }
+                    _ => None,
+                };
+
+                (pci_device_bdf, config_bar_addr, rom_bar_addr)
             } else {
                 // We need to shift the device id since the 3 first bits are dedicated
                 // to the PCI function, and we know we don't do multifunction.
@@ -2714,7 +3720,7 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::NextPciDeviceId)?
                     << 3;
 
-                (pci_device_bdf, None)
+                (pci_device_bdf, None, None)
             };
 
         // Update the existing virtio node by setting the parent.
@@ -2727,7 +3733,25 @@ impl DeviceManager {
         // Allows support for one MSI-X vector per queue. It also adds 1
         // as we need to take into account the dedicated vector to notify
         // about a virtio config change.
-        let msix_num = (virtio_device.lock().unwrap().queue_max_sizes().len() + 1) as u16;
+        let num_queues = virtio_device.lock().unwrap().queue_max_sizes().len();
+        let mut msix_num =
+            std::cmp::min(num_queues + 1, MAX_MSIX_VECTORS_PER_DEVICE as usize) as u16;
+
+        // Without enough GSIs left, the queues share a single vector, which
+        // the driver falls back to as it can't select the other ones.
+        let available_gsis = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .available_gsis();
+        if msix_num > 2 && u32::from(msix_num) > available_gsis {
+            warn!(
+                "Only {} GSIs left for the {} MSI-X vectors of {}, sharing a vector between the queues",
+                available_gsis, msix_num, id
+            );
+            msix_num = 2;
+        }
 
         // Create the callback from the implementation of the DmaRemapping
         // trait. The point with the callback is to simplify the code as we
@@ -2771,6 +3795,17 @@ impl DeviceManager {
             virtio_pci_device.set_config_bar_addr(addr);
         }
 
+        if let Some(rom) = self.option_roms.remove(&virtio_device_id) {
+            virtio_pci_device.set_rom(rom);
+            if let Some(addr) = rom_bar_addr {
+                virtio_pci_device.set_rom_bar_addr(addr);
+            }
+        }
+
+        if let Some((vendor_id, device_id)) = self.pci_subsystem_ids.remove(&virtio_device_id) {
+            virtio_pci_device.set_subsystem_id(vendor_id, device_id);
+        }
+
         let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
         let bars = self.add_pci_device(
             pci,
@@ -2829,6 +3864,14 @@ impl DeviceManager {
         &self.console
     }
 
+    pub fn serial_pty(&self) -> Option<Arc<PtyPair>> {
+        self.serial_pty.clone()
+    }
+
+    pub fn console_pty(&self) -> Option<Arc<PtyPair>> {
+        self.console_pty.clone()
+    }
+
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
     }
@@ -2886,6 +3929,42 @@ impl DeviceManager {
         return Ok(());
     }
 
+    /// Give the guest a new VM generation ID, telling it the VM may have
+    /// been duplicated when `notify` is set, as it is running already.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn update_generation_id(&self, notify: bool) -> DeviceManagerResult<()> {
+        let mut generation_id = [0u8; 16];
+        File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut generation_id))
+            .map_err(DeviceManagerError::GenerateGenerationId)?;
+
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .memory()
+            .write_slice(&generation_id, layout::VMGENID_START)
+            .map_err(DeviceManagerError::WriteGenerationId)?;
+
+        if notify {
+            self.notify_hotplug(HotPlugNotificationFlags::GENERATION_ID_CHANGED)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "acpi")]
+    pub fn wake(&self) -> DeviceManagerResult<()> {
+        self.acpi_shutdown_device
+            .as_ref()
+            .ok_or(DeviceManagerError::MissingAcpiShutdownDevice)?
+            .lock()
+            .unwrap()
+            .wake();
+
+        Ok(())
+    }
+
     pub fn add_device(
         &mut self,
         device_cfg: &mut DeviceConfig,
@@ -2952,6 +4031,8 @@ impl DeviceManager {
             // Update the PCID bitmap
             self.pci_devices_down |= 1 << (*pci_device_bdf >> 3);
 
+            self.net_devices.remove(&id);
+
             // Remove the device from the device tree along with its parent.
             let mut device_tree = self.device_tree.lock().unwrap();
             if let Some(node) = device_tree.remove(&id) {
@@ -2991,6 +4072,9 @@ impl DeviceManager {
             let (pci_device, bus_device, virtio_device) = if let Ok(vfio_pci_device) =
                 any_device.clone().downcast::<Mutex<VfioPciDevice>>()
             {
+                #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                self.remove_vfio_intx(pci_device_bdf);
+
                 (
                     Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>,
                     Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
@@ -3115,6 +4199,78 @@ impl DeviceManager {
         Ok(PciDeviceInfo { id, bdf: device_id })
     }
 
+    // Fill the device tree with a new node. In case of restore, we know there
+    // is nothing to do, so we can simply override the existing entry.
+    fn insert_vhost_user_device(
+        &mut self,
+        device: VhostUserDevice,
+    ) -> (VirtioDeviceArc, bool, String) {
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(device.id.clone(), device.node);
+
+        (device.device, device.iommu, device.id)
+    }
+
+    /// Name the vhost-user device to hotplug and allocate its resources, for
+    /// it to be created without holding the DeviceManager lock. Nothing is
+    /// returned for the other devices.
+    pub fn prepare_vhost_user_device(
+        &mut self,
+        device: &mut HotplugDevice,
+    ) -> DeviceManagerResult<Option<PendingVhostUserDevice>> {
+        match device {
+            HotplugDevice::Disk(disk_cfg) if disk_cfg.vhost_user => {
+                self.prepare_vhost_user_blk(disk_cfg).map(Some)
+            }
+            HotplugDevice::Net(net_cfg) if net_cfg.vhost_user => {
+                self.prepare_vhost_user_net(net_cfg).map(Some)
+            }
+            HotplugDevice::Fs(fs_cfg) => self.prepare_virtio_fs(fs_cfg).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn add_vhost_user_device(
+        &mut self,
+        device: VhostUserDevice,
+    ) -> DeviceManagerResult<PciDeviceInfo> {
+        let (device, iommu_attached, id) = self.insert_vhost_user_device(device);
+        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+    }
+
+    /// Release the resources of a vhost-user device which won't be added,
+    /// its creation having failed or been cancelled.
+    pub fn release_vhost_user_device(
+        &mut self,
+        device: PendingVhostUserDevice,
+    ) -> DeviceManagerResult<()> {
+        self.option_roms.remove(&device.id);
+        self.pci_subsystem_ids.remove(&device.id);
+
+        if let Some(cache) = device.cache_mapping {
+            self.memory_manager
+                .lock()
+                .unwrap()
+                .remove_userspace_mapping(
+                    cache.addr.raw_value(),
+                    cache.len,
+                    cache.host_addr,
+                    false,
+                    cache.mem_slot,
+                )
+                .map_err(DeviceManagerError::MemoryManager)?;
+            self.address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .free_mmio_addresses(cache.addr, cache.len);
+        }
+
+        Ok(())
+    }
+
     pub fn add_disk(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<PciDeviceInfo> {
         let (device, iommu_attached, id) = self.make_virtio_block_device(disk_cfg)?;
         self.hotplug_virtio_pci_device(device, iommu_attached, id)
@@ -3126,6 +4282,11 @@ impl DeviceManager {
     }
 
     pub fn add_pmem(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<PciDeviceInfo> {
+        // The NFIT table is only generated at boot time.
+        if pmem_cfg.nvdimm {
+            return Err(DeviceManagerError::NvdimmHotplugUnsupported);
+        }
+
         let (device, iommu_attached, id) = self.make_virtio_pmem_device(pmem_cfg)?;
         self.hotplug_virtio_pci_device(device, iommu_attached, id)
     }
@@ -3166,6 +4327,65 @@ impl DeviceManager {
         Err(DeviceManagerError::MissingVirtioBalloon)
     }
 
+    pub fn set_net_link(&mut self, id: &str, up: bool) -> DeviceManagerResult<()> {
+        if let Some(net) = self.net_devices.get(id) {
+            return net
+                .lock()
+                .unwrap()
+                .set_link_status(up)
+                .map_err(DeviceManagerError::VirtioNetLinkStatus);
+        }
+
+        Err(DeviceManagerError::UnknownDeviceId(id.to_owned()))
+    }
+
+    // The virtqueues of the vhost-user devices are processed by their backend,
+    // out of reach of the VMM.
+    fn is_vhost_user_device(&self, id: &str) -> bool {
+        let config = self.config.lock().unwrap();
+        let id = Some(id.to_owned());
+        config
+            .disks
+            .iter()
+            .flatten()
+            .any(|disk_cfg| disk_cfg.vhost_user && disk_cfg.id == id)
+            || config
+                .net
+                .iter()
+                .flatten()
+                .any(|net_cfg| net_cfg.vhost_user && net_cfg.id == id)
+            || config.fs.iter().flatten().any(|fs_cfg| fs_cfg.id == id)
+    }
+
+    pub fn trace_queues(&mut self, id: &str, enable: bool) -> DeviceManagerResult<()> {
+        if self.is_vhost_user_device(id) {
+            return Err(DeviceManagerError::QueueTraceNotSupported(id.to_owned()));
+        }
+
+        let pci_device_bdf = self
+            .pci_id_list
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        let any_device = self
+            .pci_devices
+            .get(pci_device_bdf)
+            .ok_or(DeviceManagerError::UnknownPciBdf(*pci_device_bdf))?;
+        let virtio_pci_device = Arc::clone(any_device)
+            .downcast::<Mutex<VirtioPciDevice>>()
+            .map_err(|_| DeviceManagerError::QueueTraceNotSupported(id.to_owned()))?;
+
+        virtio_pci_device.lock().unwrap().set_queue_trace(enable);
+        // Logged at the same level as the trace itself, which is only shown
+        // with -v.
+        info!(
+            "Virtqueue trace of device {} {}",
+            id,
+            if enable { "enabled" } else { "disabled" }
+        );
+
+        Ok(())
+    }
+
     pub fn balloon_size(&self) -> u64 {
         if let Some(balloon) = &self.balloon {
             return balloon.lock().unwrap().get_actual();
@@ -3179,6 +4399,15 @@ impl DeviceManager {
     }
 }
 
+// Host CPUs the thread processing each queue is pinned to, keyed by the
+// queue index.
+fn queue_affinity_map(queue_affinity: &[QueueAffinity]) -> HashMap<u16, Vec<usize>> {
+    queue_affinity
+        .iter()
+        .map(|a| (a.queue_index, a.host_cpus.clone()))
+        .collect()
+}
+
 #[cfg(feature = "acpi")]
 fn numa_node_id_from_memory_zone_id(numa_nodes: &NumaNodes, memory_zone_id: &str) -> Option<u32> {
     for (numa_node_id, numa_node) in numa_nodes.iter() {
@@ -3286,6 +4515,23 @@ impl Aml for PciDevSlotMethods {
     }
 }
 
+#[cfg(feature = "acpi")]
+struct NvdimmDevice {
+    index: usize,
+    handle: u32,
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for NvdimmDevice {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        aml::Device::new(
+            format!("NV{:02X}", self.index).as_str().into(),
+            vec![&aml::Name::new("_ADR".into(), &self.handle)],
+        )
+        .to_aml_bytes()
+    }
+}
+
 #[cfg(feature = "acpi")]
 impl Aml for DeviceManager {
     fn to_aml_bytes(&self) -> Vec<u8> {
@@ -3387,22 +4633,66 @@ impl Aml for DeviceManager {
         let pci_device_methods = PciDevSlotMethods {};
         pci_dsdt_inner_data.push(&pci_device_methods);
 
+        // Routing of the INTA interrupt of each slot, the only one used by
+        // the devices, to the legacy interrupt reserved for it.
+        let prt_entries: Vec<(u32, u32)> = self
+            .pci_irq_slots
+            .iter()
+            .enumerate()
+            .map(|(slot, irq)| ((slot as u32) << 16 | 0xffff, u32::from(*irq)))
+            .collect();
+        let prt_packages: Vec<aml::Package> = prt_entries
+            .iter()
+            .map(|(adr, irq)| aml::Package::new(vec![adr, &0u8, &0u8, irq]))
+            .collect();
+        let prt = aml::Name::new(
+            "_PRT".into(),
+            &aml::Package::new(
+                prt_packages
+                    .iter()
+                    .map(|package| package as &dyn aml::Aml)
+                    .collect(),
+            ),
+        );
+        if self.legacy_interrupt_manager.is_some() {
+            pci_dsdt_inner_data.push(&prt);
+        }
+
         let pci_dsdt_data =
             aml::Device::new("_SB_.PCI0".into(), pci_dsdt_inner_data).to_aml_bytes();
 
+        let mmconfig = aml::Memory32Fixed::new(
+            true,
+            layout::PCI_MMCONFIG_START.0 as u32,
+            layout::PCI_MMCONFIG_SIZE as u32,
+        );
+        // The ranges reserved from the configuration are reported as
+        // motherboard resources too, consistently with the memory map.
+        #[allow(unused_mut)]
+        let mut reserved_memory: Vec<aml::AddressSpace<u64>> = Vec::new();
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(ranges) = &self.config.lock().unwrap().reserved_memory {
+                for range in ranges.iter() {
+                    reserved_memory.push(aml::AddressSpace::new_memory(
+                        aml::AddressSpaceCachable::NotCacheable,
+                        true,
+                        range.start,
+                        range.start + range.size - 1,
+                    ));
+                }
+            }
+        }
+        let mut mbrd_resources: Vec<&dyn aml::Aml> = vec![&mmconfig];
+        for range in reserved_memory.iter() {
+            mbrd_resources.push(range);
+        }
         let mbrd_dsdt_data = aml::Device::new(
             "_SB_.MBRD".into(),
             vec![
                 &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C02")),
                 &aml::Name::new("_UID".into(), &aml::ZERO),
-                &aml::Name::new(
-                    "_CRS".into(),
-                    &aml::ResourceTemplate::new(vec![&aml::Memory32Fixed::new(
-                        true,
-                        layout::PCI_MMCONFIG_START.0 as u32,
-                        layout::PCI_MMCONFIG_SIZE as u32,
-                    )]),
-                ),
+                &aml::Name::new("_CRS".into(), &aml::ResourceTemplate::new(mbrd_resources)),
             ],
         )
         .to_aml_bytes();
@@ -3423,6 +4713,43 @@ impl Aml for DeviceManager {
         )
         .to_aml_bytes();
 
+        let power_button_dsdt_data = aml::Device::new(
+            "_SB_.PWRB".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C0C")),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+            ],
+        )
+        .to_aml_bytes();
+
+        // VM generation ID, the guest reading its address through ADDR.
+        #[cfg(target_arch = "x86_64")]
+        let vmgenid_dsdt_data = {
+            let address_low = layout::VMGENID_START.0 as u32;
+            let address_high = (layout::VMGENID_START.0 >> 32) as u32;
+            aml::Device::new(
+                "_SB_.VGEN".into(),
+                vec![
+                    &aml::Name::new("_HID".into(), &"VMGENCTR"),
+                    &aml::Name::new("_CID".into(), &"VM_Gen_Counter"),
+                    &aml::Name::new("_DDN".into(), &"VM_Gen_Counter"),
+                    &aml::Method::new(
+                        "ADDR".into(),
+                        0,
+                        false,
+                        vec![&aml::Return::new(&aml::Package::new(vec![
+                            &address_low,
+                            &address_high,
+                        ]))],
+                    ),
+                ],
+            )
+            .to_aml_bytes()
+        };
+
+        let s3_sleep_data =
+            aml::Name::new("_S3_".into(), &aml::Package::new(vec![&3u8])).to_aml_bytes();
+
         let s5_sleep_data =
             aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes();
 
@@ -3439,8 +4766,34 @@ impl Aml for DeviceManager {
         if self.config.lock().unwrap().serial.mode != ConsoleOutputMode::Off {
             bytes.extend_from_slice(com1_dsdt_data.as_slice());
         }
+        bytes.extend_from_slice(power_button_dsdt_data.as_slice());
+        #[cfg(target_arch = "x86_64")]
+        bytes.extend_from_slice(vmgenid_dsdt_data.as_slice());
+        bytes.extend_from_slice(s3_sleep_data.as_slice());
         bytes.extend_from_slice(s5_sleep_data.as_slice());
         bytes.extend_from_slice(ged_data.as_slice());
+
+        // NVDIMM root device, through which the guest finds the NFIT table
+        if !self.nvdimm_regions.is_empty() {
+            let nvdimm_devices: Vec<NvdimmDevice> = self
+                .nvdimm_regions
+                .iter()
+                .enumerate()
+                .map(|(index, region)| NvdimmDevice {
+                    index,
+                    handle: region.handle,
+                })
+                .collect();
+            let hid = aml::Name::new("_HID".into(), &"ACPI0012");
+            let mut nvdimm_dsdt_inner_data: Vec<&dyn aml::Aml> = vec![&hid];
+            for nvdimm_device in nvdimm_devices.iter() {
+                nvdimm_dsdt_inner_data.push(nvdimm_device);
+            }
+            bytes.extend_from_slice(
+                &aml::Device::new("_SB_.NVDR".into(), nvdimm_dsdt_inner_data).to_aml_bytes(),
+            );
+        }
+
         bytes
     }
 }
@@ -3515,7 +4868,7 @@ impl Snapshottable for DeviceManager {
 
         // Now that DeviceManager is updated with the right states, it's time
         // to create the devices based on the configuration.
-        self.create_devices()
+        self.create_devices(None, None)
             .map_err(|e| MigratableError::Restore(anyhow!("Could not create devices {:?}", e)))?;
 
         // Finally, restore all devices associated with the DeviceManager.
@@ -0,0 +1,178 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Lifecycle events of the VMM and its VM, such as the VM being booted or
+//! paused, kept in memory for the API clients to wait for them through the
+//! `vmm.events` endpoint instead of polling the VM state.
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+// Number of events kept for the clients which are late to retrieve them.
+const MAX_EVENTS: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    /// Identifier of the event, increasing from 1.
+    pub id: u64,
+    /// Time of the event, in microseconds since the start of the VMM.
+    pub timestamp: u64,
+    pub source: &'static str,
+    pub event: &'static str,
+}
+
+struct EventLog {
+    events: VecDeque<Event>,
+    next_id: u64,
+}
+
+struct EventMonitor {
+    log: Mutex<EventLog>,
+    new_event: Condvar,
+    notifiers: Mutex<Vec<EventFd>>,
+    start: Instant,
+}
+
+impl EventMonitor {
+    fn new() -> Self {
+        EventMonitor {
+            log: Mutex::new(EventLog {
+                events: VecDeque::with_capacity(MAX_EVENTS),
+                next_id: 1,
+            }),
+            new_event: Condvar::new(),
+            notifiers: Mutex::new(Vec::new()),
+            start: Instant::now(),
+        }
+    }
+
+    fn event(&self, source: &'static str, event: &'static str) {
+        let mut log = self.log.lock().unwrap();
+        if log.events.len() == MAX_EVENTS {
+            log.events.pop_front();
+        }
+        let id = log.next_id;
+        log.next_id += 1;
+        log.events.push_back(Event {
+            id,
+            timestamp: self.start.elapsed().as_micros() as u64,
+            source,
+            event,
+        });
+        self.new_event.notify_all();
+        drop(log);
+
+        for notifier in self.notifiers.lock().unwrap().iter() {
+            if let Err(e) = notifier.write(1) {
+                error!("Error notifying a new event: {}", e);
+            }
+        }
+    }
+
+    fn last_event_id(&self) -> u64 {
+        self.log.lock().unwrap().next_id - 1
+    }
+
+    fn events_after(&self, after: u64, timeout: Duration) -> Vec<Event> {
+        let deadline = Instant::now() + timeout;
+        let mut log = self.log.lock().unwrap();
+        loop {
+            let events: Vec<Event> = log
+                .events
+                .iter()
+                .filter(|event| event.id > after)
+                .cloned()
+                .collect();
+            let now = Instant::now();
+            if !events.is_empty() || now >= deadline {
+                return events;
+            }
+            log = self.new_event.wait_timeout(log, deadline - now).unwrap().0;
+        }
+    }
+}
+
+lazy_static! {
+    static ref EVENT_MONITOR: EventMonitor = EventMonitor::new();
+}
+
+/// Record the `event` of `source`, waking up the clients waiting for it.
+pub fn event(source: &'static str, event: &'static str) {
+    EVENT_MONITOR.event(source, event);
+}
+
+/// The events recorded after the one identified by `after`, waiting up to
+/// `timeout` for one if there is none yet. Only the latest events are kept,
+/// hence the older ones are missed by a client too late to retrieve them.
+pub fn events_after(after: u64, timeout: Duration) -> Vec<Event> {
+    EVENT_MONITOR.events_after(after, timeout)
+}
+
+/// Identifier of the latest event, 0 if none has been recorded yet.
+pub fn last_event_id() -> u64 {
+    EVENT_MONITOR.last_event_id()
+}
+
+/// Write `notifier` on every new event, for a thread waiting on an epoll
+/// rather than in `events_after()`.
+pub fn add_notifier(notifier: EventFd) {
+    EVENT_MONITOR.notifiers.lock().unwrap().push(notifier);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_event_monitor() {
+        let monitor = Arc::new(EventMonitor::new());
+        monitor.event("vm", "booted");
+        monitor.event("vm", "paused");
+
+        let events = monitor.events_after(0, Duration::from_secs(0));
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].id, events[0].event), (1, "booted"));
+        assert_eq!((events[1].id, events[1].event), (2, "paused"));
+        assert!(events[0].timestamp <= events[1].timestamp);
+        assert_eq!(monitor.last_event_id(), 2);
+
+        // No new event before the timeout.
+        assert!(monitor
+            .events_after(2, Duration::from_millis(10))
+            .is_empty());
+
+        // Woken up by a new event.
+        let thread_monitor = monitor.clone();
+        let waiter = thread::spawn(move || thread_monitor.events_after(2, Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(10));
+        monitor.event("vm", "resumed");
+        let events = waiter.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].id, events[0].event), (3, "resumed"));
+
+        // Notified of a new event.
+        let notifier = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        monitor
+            .notifiers
+            .lock()
+            .unwrap()
+            .push(notifier.try_clone().unwrap());
+        monitor.event("vm", "paused");
+        assert_eq!(notifier.read().unwrap(), 1);
+        monitor.notifiers.lock().unwrap().clear();
+
+        // Only the latest events are kept.
+        for _ in 0..MAX_EVENTS {
+            monitor.event("vm", "rebooted");
+        }
+        let events = monitor.events_after(0, Duration::from_secs(0));
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0].id, 5);
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Sanity checks of the vCPU affinity against the host CPUs, which catch
+//! vCPUs pinned to offline CPUs, or not fully isolated from the host
+//! housekeeping tasks when the host relies on `isolcpus` or `nohz_full`.
+
+use crate::config::CpuAffinity;
+use crate::numa::parse_list;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SYSFS_CPU_PATH: &str = "/sys/devices/system/cpu";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed reading the host CPUs.
+    ReadHostCpus(io::Error),
+
+    /// Invalid CPU list found for the host CPUs.
+    ParseHostCpus(String),
+
+    /// A vCPU is pinned to an offline or missing host CPU.
+    OfflineHostCpu(u16, usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Online host CPUs, along with the ones isolated from the scheduler load
+/// balancing (`isolcpus`) and the ones running without scheduler tick
+/// (`nohz_full`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HostCpus {
+    pub online: Vec<usize>,
+    pub isolated: Vec<usize>,
+    pub nohz_full: Vec<usize>,
+}
+
+// A missing file means the feature is not available on the host, and the
+// kernel reports "(null)" when no CPU is configured for nohz_full.
+fn read_optional_list(path: &Path) -> Result<Vec<usize>> {
+    let list = match fs::read_to_string(path) {
+        Ok(list) => list,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::ReadHostCpus(e)),
+    };
+    if list.trim() == "(null)" {
+        return Ok(Vec::new());
+    }
+    parse_list(&list).ok_or(Error::ParseHostCpus(list))
+}
+
+impl HostCpus {
+    pub fn new() -> Result<Self> {
+        let sysfs_path = Path::new(SYSFS_CPU_PATH);
+        let online = fs::read_to_string(sysfs_path.join("online")).map_err(Error::ReadHostCpus)?;
+
+        Ok(HostCpus {
+            online: parse_list(&online).ok_or(Error::ParseHostCpus(online))?,
+            isolated: read_optional_list(&sysfs_path.join("isolated"))?,
+            nohz_full: read_optional_list(&sysfs_path.join("nohz_full"))?,
+        })
+    }
+
+    fn is_isolated(&self, host_cpu: usize) -> bool {
+        self.isolated.contains(&host_cpu) || self.nohz_full.contains(&host_cpu)
+    }
+
+    /// Fails if a vCPU is pinned to a host CPU which is not online, and
+    /// returns a warning for each vCPU whose affinity defeats the host CPU
+    /// isolation.
+    pub fn check_affinity(&self, affinity: &[CpuAffinity]) -> Result<Vec<String>> {
+        let mut warnings = Vec::new();
+
+        for a in affinity.iter() {
+            if let Some(host_cpu) = a.host_cpus.iter().find(|c| !self.online.contains(c)) {
+                return Err(Error::OfflineHostCpu(a.vcpu, *host_cpu));
+            }
+
+            let (isolated, housekeeping): (Vec<usize>, Vec<usize>) =
+                a.host_cpus.iter().partition(|c| self.is_isolated(**c));
+            if !isolated.is_empty() && !housekeeping.is_empty() {
+                warnings.push(format!(
+                    "vCPU {} may run on the housekeeping host CPUs {:?}, as it is \
+                     pinned to both isolated and housekeeping host CPUs",
+                    a.vcpu, housekeeping
+                ));
+            }
+
+            // The scheduler doesn't move tasks between CPUs from isolcpus.
+            let unbalanced = a
+                .host_cpus
+                .iter()
+                .filter(|c| self.isolated.contains(c))
+                .count();
+            if unbalanced > 1 {
+                warnings.push(format!(
+                    "vCPU {} is pinned to several host CPUs from isolcpus, which \
+                     are not load balanced: pin it to a single host CPU instead",
+                    a.vcpu
+                ));
+            }
+        }
+
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn affinity(vcpu: u16, host_cpus: &[usize]) -> CpuAffinity {
+        CpuAffinity {
+            vcpu,
+            host_cpus: host_cpus.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_check_affinity() {
+        let host_cpus = HostCpus {
+            online: vec![0, 1, 2, 3, 4, 5],
+            isolated: vec![2, 3],
+            nohz_full: vec![4, 5],
+        };
+
+        assert!(host_cpus
+            .check_affinity(&[
+                affinity(0, &[0, 1]),
+                affinity(1, &[2]),
+                affinity(2, &[4, 5]),
+            ])
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            host_cpus.check_affinity(&[affinity(0, &[0]), affinity(1, &[6])]),
+            Err(Error::OfflineHostCpu(1, 6))
+        ));
+
+        // Mixing isolated and housekeeping CPUs
+        assert_eq!(
+            host_cpus
+                .check_affinity(&[affinity(0, &[1, 4])])
+                .unwrap()
+                .len(),
+            1
+        );
+        // Several CPUs from isolcpus
+        assert_eq!(
+            host_cpus
+                .check_affinity(&[affinity(0, &[2, 3])])
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Nothing to warn about without any isolated CPU.
+        let host_cpus = HostCpus {
+            online: vec![0, 1],
+            ..Default::default()
+        };
+        assert!(host_cpus
+            .check_affinity(&[affinity(0, &[0, 1])])
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Device hotplug jobs, run off the VMM thread so that a device slow to be
+//! created, such as a vhost-user device waiting for its backend, doesn't hold
+//! up the other API requests. A job can be queried until it completes, and
+//! cancelled as long as it hasn't started adding the device to the VM,
+//! including while it is connecting to the backend.
+
+use crate::config::{DeviceConfig, DiskConfig, FsConfig, NetConfig, ValidationError};
+use crate::PciDeviceInfo;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// Delay between two checks for the backend of a pending job.
+const BACKEND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HotplugDevice {
+    Disk(DiskConfig),
+    Net(NetConfig),
+    Fs(FsConfig),
+    Device(DeviceConfig),
+}
+
+// Placeholder for the API route of the hotplug jobs.
+impl Default for HotplugDevice {
+    fn default() -> Self {
+        HotplugDevice::Disk(DiskConfig::default())
+    }
+}
+
+impl HotplugDevice {
+    /// Validate the device configuration, as done at boot for the devices
+    /// the VM starts with.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        match self {
+            HotplugDevice::Disk(disk_cfg) => disk_cfg.validate(),
+            HotplugDevice::Net(net_cfg) => net_cfg.validate(),
+            HotplugDevice::Fs(fs_cfg) => fs_cfg.validate(),
+            HotplugDevice::Device(device_cfg) => device_cfg.validate(),
+        }
+    }
+
+    // Socket of the vhost-user backend the device connects to, if any.
+    fn backend_socket(&self) -> Option<PathBuf> {
+        match self {
+            HotplugDevice::Disk(disk_cfg) if disk_cfg.vhost_user => {
+                disk_cfg.vhost_socket.as_ref().map(PathBuf::from)
+            }
+            HotplugDevice::Net(net_cfg) if net_cfg.vhost_user => {
+                net_cfg.vhost_socket.as_ref().map(PathBuf::from)
+            }
+            HotplugDevice::Fs(fs_cfg) => Some(fs_cfg.socket.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum HotplugState {
+    /// Waiting for the backend of the device to be available.
+    Pending,
+    /// Connected to the backend, creating the device.
+    Connecting,
+    /// Adding the device to the VM.
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize)]
+pub struct HotplugStatus {
+    pub id: u64,
+    pub state: HotplugState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<PciDeviceInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct HotplugJob {
+    status: Mutex<HotplugStatus>,
+    // Connection to the backend, shut down to cancel the job while the
+    // device is being created. It is always locked after the status.
+    connection: Mutex<Option<UnixStream>>,
+}
+
+impl HotplugJob {
+    pub fn new(id: u64) -> Self {
+        HotplugJob {
+            status: Mutex::new(HotplugStatus {
+                id,
+                state: HotplugState::Pending,
+                device: None,
+                error: None,
+            }),
+            connection: Mutex::new(None),
+        }
+    }
+
+    pub fn state(&self) -> HotplugState {
+        self.status.lock().unwrap().state
+    }
+
+    /// Whether the job is over, successfully or not.
+    pub fn finished(&self) -> bool {
+        matches!(
+            self.state(),
+            HotplugState::Completed | HotplugState::Failed | HotplugState::Cancelled
+        )
+    }
+
+    /// The status of the job, serialized in JSON.
+    pub fn status_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&*self.status.lock().unwrap())
+    }
+
+    /// Cancel the job if it hasn't started adding the device, returning
+    /// whether it was cancelled. Shutting the connection to the backend down
+    /// makes the creation of the device fail, if the backend doesn't answer.
+    pub fn cancel(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+        match status.state {
+            HotplugState::Pending => {}
+            HotplugState::Connecting => {
+                if let Some(connection) = self.connection.lock().unwrap().take() {
+                    let _ = connection.shutdown(Shutdown::Both);
+                }
+            }
+            _ => return false,
+        }
+        status.state = HotplugState::Cancelled;
+        true
+    }
+
+    // Move the job to the connecting state, unless it has been cancelled.
+    fn connect(&self, stream: &UnixStream) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if status.state != HotplugState::Pending {
+            return false;
+        }
+        *self.connection.lock().unwrap() = stream.try_clone().ok();
+        status.state = HotplugState::Connecting;
+        true
+    }
+
+    // Move the job to the running state, unless it has been cancelled.
+    fn start(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+        match status.state {
+            HotplugState::Pending | HotplugState::Connecting => {}
+            _ => return false,
+        }
+        self.connection.lock().unwrap().take();
+        status.state = HotplugState::Running;
+        true
+    }
+
+    fn finish(&self, result: Result<PciDeviceInfo, String>) {
+        let mut status = self.status.lock().unwrap();
+        if status.state == HotplugState::Cancelled {
+            return;
+        }
+        match result {
+            Ok(device) => {
+                status.state = HotplugState::Completed;
+                status.device = Some(device);
+            }
+            Err(e) => {
+                status.state = HotplugState::Failed;
+                status.error = Some(e);
+            }
+        }
+    }
+
+    /// Run the job on the current thread, waiting for the backend socket of
+    /// the device to be created and connecting to it before calling `add`
+    /// with the connection. `add` must call the function it is given before
+    /// adding the device to the VM, and give up if it returns false, since
+    /// the job has been cancelled.
+    pub fn run<F>(&self, device: HotplugDevice, add: F)
+    where
+        F: FnOnce(
+            HotplugDevice,
+            Option<UnixStream>,
+            &dyn Fn() -> bool,
+        ) -> Result<PciDeviceInfo, String>,
+    {
+        // Connecting to the backend only to check it is listening could make
+        // it exit once disconnected, hence only the socket file is checked.
+        let mut stream = None;
+        if let Some(socket) = device.backend_socket() {
+            while !socket.exists() {
+                if self.state() != HotplugState::Pending {
+                    return;
+                }
+                thread::sleep(BACKEND_POLL_INTERVAL);
+            }
+
+            match UnixStream::connect(&socket) {
+                Ok(s) => {
+                    if !self.connect(&s) {
+                        return;
+                    }
+                    stream = Some(s);
+                }
+                Err(e) => {
+                    self.finish(Err(format!(
+                        "Failed connecting to {}: {}",
+                        socket.display(),
+                        e
+                    )));
+                    return;
+                }
+            }
+        }
+
+        self.finish(add(device, stream, &|| self.start()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_hotplug_job() {
+        let job = HotplugJob::new(1);
+        job.run(HotplugDevice::Disk(DiskConfig::default()), |_, _, start| {
+            assert!(start());
+            Ok(PciDeviceInfo {
+                id: "disk0".to_owned(),
+                bdf: 0x20,
+            })
+        });
+        assert_eq!(job.state(), HotplugState::Completed);
+        assert_eq!(
+            job.status_json().unwrap(),
+            br#"{"id":1,"state":"Completed","device":{"id":"disk0","bdf":"0000:00:04.0"}}"#
+        );
+        // Too late to cancel the job.
+        assert!(!job.cancel());
+
+        let job = HotplugJob::new(2);
+        job.run(HotplugDevice::Net(NetConfig::default()), |_, _, _| {
+            Err("No TAP interface".to_owned())
+        });
+        assert_eq!(job.state(), HotplugState::Failed);
+
+        // Waiting for the backend socket until cancelled.
+        let job = Arc::new(HotplugJob::new(3));
+        let thread_job = job.clone();
+        let thread = thread::spawn(move || {
+            let disk_cfg = DiskConfig {
+                vhost_user: true,
+                vhost_socket: Some("/nonexistent/vhost-user-blk.sock".to_owned()),
+                ..Default::default()
+            };
+            thread_job.run(HotplugDevice::Disk(disk_cfg), |_, _, _| {
+                Err("Unexpected device creation".to_owned())
+            });
+        });
+        thread::sleep(BACKEND_POLL_INTERVAL);
+        assert_eq!(job.state(), HotplugState::Pending);
+        assert!(job.cancel());
+        thread.join().unwrap();
+        assert_eq!(job.state(), HotplugState::Cancelled);
+
+        // Connected to a backend which doesn't answer until cancelled.
+        let socket = std::env::temp_dir().join(format!("hotplug-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let job = Arc::new(HotplugJob::new(4));
+        let thread_job = job.clone();
+        let disk_cfg = DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some(socket.to_str().unwrap().to_owned()),
+            ..Default::default()
+        };
+        let thread = thread::spawn(move || {
+            thread_job.run(HotplugDevice::Disk(disk_cfg), |_, stream, start| {
+                let mut buf = [0u8; 1];
+                assert_eq!(stream.unwrap().read(&mut buf).unwrap_or(0), 0);
+                assert!(!start());
+                Err("Backend connection shut down".to_owned())
+            });
+        });
+        let _backend = listener.accept().unwrap();
+        while job.state() != HotplugState::Connecting {
+            thread::sleep(BACKEND_POLL_INTERVAL);
+        }
+        assert!(job.cancel());
+        thread.join().unwrap();
+        assert_eq!(job.state(), HotplugState::Cancelled);
+        assert!(job.finished());
+        std::fs::remove_file(&socket).unwrap();
+    }
+}
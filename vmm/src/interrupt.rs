@@ -307,6 +307,14 @@ where
         config: Self::GroupConfig,
     ) -> Result<Arc<Box<dyn InterruptSourceGroup>>> {
         let mut allocator = self.allocator.lock().unwrap();
+        // Checked beforehand as the GSIs can't be given back to the
+        // allocator.
+        if allocator.available_gsis() < config.count {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Not enough GSIs left for {} interrupts", config.count),
+            ));
+        }
         let mut irq_routes: HashMap<InterruptIndex, InterruptRoute> =
             HashMap::with_capacity(config.count as usize);
         for i in config.base..config.base + config.count {
@@ -331,6 +339,13 @@ pub mod kvm {
     use hypervisor::kvm::KVM_MSI_VALID_DEVID;
     use hypervisor::kvm::{kvm_irq_routing_entry, KVM_IRQ_ROUTING_MSI};
 
+    // Bits 8-14 of the destination ID, when the guest relies on the extended
+    // destination ID to target APIC IDs above 255.
+    #[cfg(target_arch = "x86_64")]
+    const MSI_ADDR_EXT_DEST_ID_SHIFT: u32 = 5;
+    #[cfg(target_arch = "x86_64")]
+    const MSI_ADDR_EXT_DEST_ID_MASK: u32 = 0x7f << MSI_ADDR_EXT_DEST_ID_SHIFT;
+
     type KvmMsiInterruptGroup = MsiInterruptGroup<kvm_irq_routing_entry>;
     type KvmRoutingEntry = RoutingEntry<kvm_irq_routing_entry>;
     pub type KvmMsiInterruptManager = MsiInterruptManager<kvm_irq_routing_entry>;
@@ -352,6 +367,16 @@ pub mod kvm {
                 kvm_route.u.msi.address_hi = cfg.high_addr;
                 kvm_route.u.msi.data = cfg.data;
 
+                // KVM expects the bits 8-31 of the destination ID to be
+                // provided through the upper 32 bits of the address.
+                #[cfg(target_arch = "x86_64")]
+                {
+                    let ext_dest_id =
+                        (cfg.low_addr & MSI_ADDR_EXT_DEST_ID_MASK) >> MSI_ADDR_EXT_DEST_ID_SHIFT;
+                    kvm_route.u.msi.address_lo &= !MSI_ADDR_EXT_DEST_ID_MASK;
+                    kvm_route.u.msi.address_hi |= ext_dest_id << 8;
+                }
+
                 if vm.check_extension(hypervisor::Cap::MsiDevid) {
                     kvm_route.flags = KVM_MSI_VALID_DEVID;
                     kvm_route.u.msi.__bindgen_anon_1.devid = cfg.devid;
@@ -0,0 +1,149 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vm_device::interrupt::InterruptSourceGroup;
+use vmm_sys_util::eventfd::EventFd;
+
+const KILL_EVENT: u64 = 0;
+
+struct IntxSource {
+    trigger: EventFd,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+}
+
+/// Triggers the legacy interrupts of the passed through devices, signalled
+/// by the host through eventfds, from a dedicated thread. Going through the
+/// emulated IOAPIC, rather than injecting them straight into the guest, lets
+/// the end of interrupt be reported back to the devices.
+pub struct IntxForwarder {
+    epoll_file: File,
+    sources: Arc<Mutex<HashMap<u64, IntxSource>>>,
+    next_token: u64,
+    kill_evt: EventFd,
+}
+
+impl IntxForwarder {
+    pub fn new(seccomp_action: &SeccompAction) -> io::Result<Self> {
+        let epoll_fd = epoll::create(true)?;
+        // Use 'File' to enforce closing on 'epoll_fd'
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        epoll::ctl(
+            epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, KILL_EVENT),
+        )?;
+
+        let intx_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::VfioIntx)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+        let sources = Arc::new(Mutex::new(HashMap::<u64, IntxSource>::new()));
+        let thread_sources = sources.clone();
+        let thread_epoll_fd = epoll_file.try_clone()?;
+        let thread_kill_evt = kill_evt.try_clone()?;
+
+        thread::Builder::new()
+            .name("vfio_intx".to_string())
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(intx_seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    return;
+                }
+
+                let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 16];
+                loop {
+                    let num_events =
+                        match epoll::wait(thread_epoll_fd.as_raw_fd(), -1, &mut events[..]) {
+                            Ok(num_events) => num_events,
+                            Err(e) => {
+                                if e.kind() == io::ErrorKind::Interrupted {
+                                    continue;
+                                }
+                                error!("Error waiting on the INTx eventfds: {}", e);
+                                return;
+                            }
+                        };
+
+                    for event in events.iter().take(num_events) {
+                        let token = event.data;
+                        if token == KILL_EVENT {
+                            let _ = thread_kill_evt.read();
+                            return;
+                        }
+
+                        if let Some(source) = thread_sources.lock().unwrap().get(&token) {
+                            if source.trigger.read().is_ok() {
+                                if let Err(e) = source.interrupt_source_group.trigger(0) {
+                                    error!("Failed triggering INTx: {}", e);
+                                }
+                            }
+                        }
+                    }
+                }
+            })?;
+
+        Ok(IntxForwarder {
+            epoll_file,
+            sources,
+            next_token: KILL_EVENT + 1,
+            kill_evt,
+        })
+    }
+
+    /// Trigger `interrupt_source_group` whenever `trigger` is signalled,
+    /// until the returned token is passed to `remove()`.
+    pub fn add(
+        &mut self,
+        trigger: EventFd,
+        interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    ) -> io::Result<u64> {
+        let token = self.next_token;
+        epoll::ctl(
+            self.epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            trigger.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, token),
+        )?;
+        self.sources.lock().unwrap().insert(
+            token,
+            IntxSource {
+                trigger,
+                interrupt_source_group,
+            },
+        );
+        self.next_token += 1;
+
+        Ok(token)
+    }
+
+    pub fn remove(&mut self, token: u64) {
+        if let Some(source) = self.sources.lock().unwrap().remove(&token) {
+            if let Err(e) = epoll::ctl(
+                self.epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_DEL,
+                source.trigger.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, token),
+            ) {
+                warn!("Could not stop listening to the INTx eventfd: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for IntxForwarder {
+    fn drop(&mut self) {
+        if let Err(e) = self.kill_evt.write(1) {
+            warn!("Could not stop the INTx thread: {}", e);
+        }
+    }
+}
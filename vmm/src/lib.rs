@@ -23,42 +23,71 @@ extern crate vmm_sys_util;
 extern crate credibility;
 
 use crate::api::{
-    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmHotplugJobData, VmInfo,
+    VmInjectMceData, VmReceiveMigrationData, VmSendMigrationData, VmSetNetLinkData,
+    VmSnapshotConfig, VmTraceQueuesData, VmmPingResponse, VmmResourceUsage,
 };
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, NetConfig, OnCrashAction, PmemConfig, RestoreConfig,
+    VmConfig, VsockConfig,
 };
-use crate::migration::{get_vm_snapshot, recv_vm_snapshot};
+use crate::device_manager::{ConsoleInput, PtyPair};
+use crate::hotplug::HotplugDevice;
+use crate::migration::{get_vm_snapshot, recv_vm_snapshot, set_vm_snapshot};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::snapshot_encryption::SnapshotKey;
 use crate::vm::{Error as VmError, Vm, VmState};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
+use signal_hook::{iterator::Signals, SIGUSR1};
 use std::fs::File;
 use std::io;
 use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
-use std::{result, thread};
+use std::{fmt, result, thread};
 use thiserror::Error;
 use vm_migration::protocol::*;
 use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
+pub mod access_trace;
 pub mod api;
+pub mod balloon_policy;
+pub mod cgroup;
 pub mod config;
 pub mod cpu;
 pub mod device_manager;
 pub mod device_tree;
+pub mod event_monitor;
+pub mod host_cpus;
+pub mod hotplug;
 pub mod interrupt;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+pub mod intx;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+pub mod memory_error;
+pub mod memory_layout;
 pub mod memory_manager;
+pub mod metadata;
+pub mod metrics;
 pub mod migration;
+pub mod numa;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod resource_usage;
+pub mod rotating_file;
 pub mod seccomp_filters;
+pub mod simple_http;
+pub mod snapshot_compression;
+pub mod snapshot_encryption;
+pub mod version;
 pub mod vm;
 
 #[cfg(feature = "acpi")]
@@ -100,6 +129,24 @@ pub enum Error {
     #[error("Error spawning HTTP thread: {0}")]
     HttpThreadSpawn(#[source] io::Error),
 
+    /// Cannot bind to the metrics exporter address
+    #[error("Error binding to the metrics address: {0}")]
+    MetricsBind(#[source] io::Error),
+
+    /// Cannot create metrics thread
+    #[error("Error spawning metrics thread: {0}")]
+    MetricsThreadSpawn(#[source] io::Error),
+
+    /// Cannot set the OTLP exporter up
+    #[cfg(feature = "otlp")]
+    #[error("Error setting the OTLP exporter up: {0}")]
+    OtlpExporter(#[source] io::Error),
+
+    /// Cannot create OTLP thread
+    #[cfg(feature = "otlp")]
+    #[error("Error spawning OTLP thread: {0}")]
+    OtlpThreadSpawn(#[source] io::Error),
+
     /// Cannot handle the VM STDIN stream
     #[error("Error handling VM stdin: {0:?}")]
     Stdin(VmError),
@@ -135,6 +182,18 @@ pub enum Error {
     /// Error activating virtio devices
     #[error("Error activating virtio devices: {0:?}")]
     ActivateVirtioDevices(VmError),
+
+    /// Cannot suspend the VM
+    #[error("Error suspending VM: {0:?}")]
+    VmSuspend(VmError),
+
+    /// Cannot pause the VM
+    #[error("Error pausing VM: {0:?}")]
+    VmPause(VmError),
+
+    /// Cannot create the signal handler thread
+    #[error("Error spawning signal handler thread: {0}")]
+    SignalHandlerSpawn(#[source] io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -143,8 +202,13 @@ pub enum EpollDispatch {
     Exit,
     Reset,
     Stdin,
+    SerialPty,
+    ConsolePty,
     Api,
     ActivateVirtioDevices,
+    Suspend,
+    Crash,
+    PauseToggle,
 }
 
 pub struct EpollContext {
@@ -237,6 +301,21 @@ impl Serialize for PciDeviceInfo {
     }
 }
 
+/// Run `operation`, recorded as the span `name` when the OTLP exporter is
+/// built in.
+#[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+pub(crate) fn traced<T, E: fmt::Debug>(
+    name: &'static str,
+    operation: impl FnOnce() -> result::Result<T, E>,
+) -> result::Result<T, E> {
+    #[cfg(feature = "otlp")]
+    let span = otlp::Span::start(name);
+    let result = operation();
+    #[cfg(feature = "otlp")]
+    span.end(result.as_ref().err().map(|e| format!("{:?}", e)));
+    result
+}
+
 pub fn start_vmm_thread(
     vmm_version: String,
     http_path: &str,
@@ -276,10 +355,17 @@ pub fn start_vmm_thread(
     Ok(thread)
 }
 
+// Connection the migration goes through, either a UNIX or a TCP socket.
+trait MigrationSocket: Read + Write {}
+impl<T: Read + Write> MigrationSocket for T {}
+
 pub struct Vmm {
     epoll: EpollContext,
     exit_evt: EventFd,
     reset_evt: EventFd,
+    suspend_evt: EventFd,
+    crash_evt: EventFd,
+    pause_toggle_evt: EventFd,
     api_evt: EventFd,
     version: String,
     vm: Option<Vm>,
@@ -299,7 +385,10 @@ impl Vmm {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let suspend_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let crash_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let activate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let pause_toggle_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
         if unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
             epoll.add_stdin().map_err(Error::Epoll)?;
@@ -313,18 +402,35 @@ impl Vmm {
             .add_event(&reset_evt, EpollDispatch::Reset)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&suspend_evt, EpollDispatch::Suspend)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&crash_evt, EpollDispatch::Crash)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&activate_evt, EpollDispatch::ActivateVirtioDevices)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&pause_toggle_evt, EpollDispatch::PauseToggle)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
 
+        Vmm::start_signal_handler(&pause_toggle_evt, &seccomp_action)?;
+
         Ok(Vmm {
             epoll,
             exit_evt,
             reset_evt,
+            suspend_evt,
+            crash_evt,
+            pause_toggle_evt,
             api_evt,
             version: vmm_version,
             vm: None,
@@ -335,11 +441,68 @@ impl Vmm {
         })
     }
 
+    // Let SIGUSR1 pause the VM, or resume it when it is already paused, so
+    // that it can be frozen without going through the API socket.
+    fn start_signal_handler(
+        pause_toggle_evt: &EventFd,
+        seccomp_action: &SeccompAction,
+    ) -> Result<()> {
+        let signals = match Signals::new(&[SIGUSR1]) {
+            Ok(signals) => signals,
+            Err(e) => {
+                error!("Signal not found {}", e);
+                return Ok(());
+            }
+        };
+        let pause_toggle_evt = pause_toggle_evt.try_clone().map_err(Error::EventFdClone)?;
+        let signal_handler_seccomp_filter =
+            get_seccomp_filter(seccomp_action, Thread::SignalHandler)
+                .map_err(Error::CreateSeccompFilter)?;
+        thread::Builder::new()
+            .name("vmm_signal_handler".to_string())
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(signal_handler_seccomp_filter)
+                    .map_err(Error::ApplySeccompFilter)
+                {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    return;
+                }
+
+                for signal in signals.forever() {
+                    if signal == SIGUSR1 {
+                        if let Err(e) = pause_toggle_evt.write(1) {
+                            error!("Error writing to pause toggle event: {}", e);
+                        }
+                    }
+                }
+            })
+            .map_err(Error::SignalHandlerSpawn)?;
+
+        Ok(())
+    }
+
+    fn vm_pause_toggle(&mut self) -> result::Result<(), VmError> {
+        let state = match self.vm {
+            Some(ref vm) => vm.get_state()?,
+            None => return Err(VmError::VmNotRunning),
+        };
+        match state {
+            VmState::Running => self.vm_pause(),
+            VmState::Paused => self.vm_resume(),
+            _ => Err(VmError::VmNotRunning),
+        }
+    }
+
     fn vm_boot(&mut self) -> result::Result<(), VmError> {
         // Create a new VM is we don't have one yet.
         if self.vm.is_none() {
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let suspend_evt = self
+                .suspend_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+            let crash_evt = self.crash_evt.try_clone().map_err(VmError::EventFdClone)?;
             let activate_evt = self
                 .activate_evt
                 .try_clone()
@@ -350,25 +513,53 @@ impl Vmm {
                     Arc::clone(vm_config),
                     exit_evt,
                     reset_evt,
+                    suspend_evt,
+                    crash_evt,
                     &self.seccomp_action,
                     self.hypervisor.clone(),
                     activate_evt,
+                    None,
+                    None,
                 )?;
+                self.add_pty_events(vm.serial_pty(), vm.console_pty())?;
                 self.vm = Some(vm);
             }
         }
 
         // Now we can boot the VM.
         if let Some(ref mut vm) = self.vm {
-            vm.boot()
+            vm.boot()?;
+            event_monitor::event("vm", "booted");
+            Ok(())
         } else {
             Err(VmError::VmNotCreated)
         }
     }
 
+    fn add_pty_events(
+        &mut self,
+        serial_pty: Option<Arc<PtyPair>>,
+        console_pty: Option<Arc<PtyPair>>,
+    ) -> result::Result<(), VmError> {
+        if let Some(pty) = serial_pty {
+            self.epoll
+                .add_event(&pty.main, EpollDispatch::SerialPty)
+                .map_err(VmError::SerialPty)?;
+        }
+        if let Some(pty) = console_pty {
+            self.epoll
+                .add_event(&pty.main, EpollDispatch::ConsolePty)
+                .map_err(VmError::ConsolePty)?;
+        }
+
+        Ok(())
+    }
+
     fn vm_pause(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.pause().map_err(VmError::Pause)
+            vm.pause().map_err(VmError::Pause)?;
+            event_monitor::event("vm", "paused");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -376,20 +567,57 @@ impl Vmm {
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.resume().map_err(VmError::Resume)
+            // Only a wake up brings the guest out of suspend to RAM.
+            if vm.is_suspended() {
+                return Err(VmError::VmSuspended);
+            }
+            vm.resume().map_err(VmError::Resume)?;
+            event_monitor::event("vm", "resumed");
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_power_button(&self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.power_button()?;
+            event_monitor::event("vm", "power-button");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+    fn vm_wakeup(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
+            vm.wakeup()?;
+            event_monitor::event("vm", "woken-up");
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_snapshot(&mut self, snapshot_cfg: &VmSnapshotConfig) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let key = snapshot_cfg
+                .key_file
+                .as_ref()
+                .map(|key_file| SnapshotKey::from_file(key_file))
+                .transpose()
+                .map_err(VmError::SnapshotKey)?;
+
+            vm.set_snapshot_compression(snapshot_cfg.compress);
+            vm.set_snapshot_incremental(snapshot_cfg.incremental)?;
             vm.snapshot()
                 .map_err(VmError::Snapshot)
                 .and_then(|snapshot| {
-                    vm.send(&snapshot, destination_url)
+                    vm.send_snapshot(&snapshot, &snapshot_cfg.destination_url, key.as_ref())
                         .map_err(VmError::SnapshotSend)
-                })
+                })?;
+            event_monitor::event("vm", "snapshotted");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -407,13 +635,31 @@ impl Vmm {
         // Safe to unwrap as we checked it was Some(&str).
         let source_url = source_url.unwrap();
 
-        let snapshot = recv_vm_snapshot(source_url).map_err(VmError::Restore)?;
+        let key = restore_cfg
+            .key_file
+            .as_ref()
+            .map(|key_file| SnapshotKey::from_file(key_file))
+            .transpose()
+            .map_err(VmError::SnapshotKey)?;
+
+        let mut snapshot = recv_vm_snapshot(source_url, key.as_ref()).map_err(VmError::Restore)?;
         let vm_snapshot = get_vm_snapshot(&snapshot).map_err(VmError::Restore)?;
 
+        // Adapt the configuration to the host the VM is restored on.
+        restore_cfg
+            .apply_overrides(&mut vm_snapshot.config.lock().unwrap())
+            .map_err(VmError::ConfigValidation)?;
+        set_vm_snapshot(&mut snapshot, &vm_snapshot).map_err(VmError::Restore)?;
+
         self.vm_config = Some(Arc::clone(&vm_snapshot.config));
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let suspend_evt = self
+            .suspend_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let crash_evt = self.crash_evt.try_clone().map_err(VmError::EventFdClone)?;
         let activate_evt = self
             .activate_evt
             .try_clone()
@@ -423,8 +669,12 @@ impl Vmm {
             &snapshot,
             exit_evt,
             reset_evt,
+            suspend_evt,
+            crash_evt,
             Some(source_url),
+            key.as_ref(),
             restore_cfg.prefault,
+            restore_cfg.clone,
             &self.seccomp_action,
             self.hypervisor.clone(),
             activate_evt,
@@ -433,7 +683,15 @@ impl Vmm {
 
         // Now we can restore the rest of the VM.
         if let Some(ref mut vm) = self.vm {
-            vm.restore(snapshot).map_err(VmError::Restore)
+            vm.restore(snapshot).map_err(VmError::Restore)?;
+            #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+            vm.update_generation_id()?;
+            // The pseudo terminals are created along with the devices.
+            let serial_pty = vm.serial_pty();
+            let console_pty = vm.console_pty();
+            self.add_pty_events(serial_pty, console_pty)?;
+            event_monitor::event("vm", "restored");
+            Ok(())
         } else {
             Err(VmError::VmNotCreated)
         }
@@ -461,10 +719,19 @@ impl Vmm {
         // First we stop the current VM and create a new one.
         if let Some(ref mut vm) = self.vm {
             let config = vm.get_config();
+            // The new VM reuses the pseudo terminals, which are already
+            // registered.
+            let serial_pty = vm.serial_pty();
+            let console_pty = vm.console_pty();
             self.vm_shutdown()?;
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let suspend_evt = self
+                .suspend_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+            let crash_evt = self.crash_evt.try_clone().map_err(VmError::EventFdClone)?;
             let activate_evt = self
                 .activate_evt
                 .try_clone()
@@ -480,9 +747,13 @@ impl Vmm {
                 config,
                 exit_evt,
                 reset_evt,
+                suspend_evt,
+                crash_evt,
                 &self.seccomp_action,
                 self.hypervisor.clone(),
                 activate_evt,
+                serial_pty,
+                console_pty,
             )?);
         }
 
@@ -492,6 +763,7 @@ impl Vmm {
         } else {
             return Err(VmError::VmNotCreated);
         }
+        event_monitor::event("vm", "rebooted");
 
         Ok(())
     }
@@ -512,12 +784,18 @@ impl Vmm {
                 }
 
                 let device_tree = self.vm.as_ref().map(|vm| vm.device_tree());
+                let virtio_mem = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.virtio_mem_info())
+                    .unwrap_or_default();
 
                 Ok(VmInfo {
                     config,
                     state,
                     memory_actual_size,
                     device_tree,
+                    virtio_mem,
                 })
             }
             None => Err(VmError::VmNotCreated),
@@ -530,6 +808,15 @@ impl Vmm {
         })
     }
 
+    fn vmm_resource_usage(&self) -> result::Result<VmmResourceUsage, ApiError> {
+        let guest_memory_ranges = self
+            .vm
+            .as_ref()
+            .map(|vm| vm.guest_memory_host_ranges())
+            .unwrap_or_default();
+        resource_usage::vmm_resource_usage(&guest_memory_ranges).map_err(ApiError::VmmResourceUsage)
+    }
+
     fn vm_delete(&mut self) -> result::Result<(), VmError> {
         if self.vm_config.is_none() {
             return Ok(());
@@ -538,20 +825,24 @@ impl Vmm {
         // If a VM is booted, we first try to shut it down.
         if self.vm.is_some() {
             self.vm_shutdown()?;
+            event_monitor::event("vm", "shutdown");
         }
 
         self.vm_config = None;
+        event_monitor::event("vm", "deleted");
 
         Ok(())
     }
 
     fn vmm_shutdown(&mut self) -> result::Result<(), VmError> {
-        self.vm_delete()
+        self.vm_delete()?;
+        event_monitor::event("vmm", "shutdown");
+        Ok(())
     }
 
     fn vm_resize(
         &mut self,
-        desired_vcpus: Option<u8>,
+        desired_vcpus: Option<u16>,
         desired_ram: Option<u64>,
         desired_balloon: Option<u64>,
     ) -> result::Result<(), VmError> {
@@ -665,6 +956,78 @@ impl Vmm {
         }
     }
 
+    fn vm_set_net_link(&mut self, data: &VmSetNetLinkData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.set_net_link(&data.id, data.up) {
+                error!("Error when setting network link status: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_trace_queues(&mut self, data: &VmTraceQueuesData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.trace_queues(&data.id, data.enable) {
+                error!("Error when toggling virtqueue trace: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_inject_mce(&mut self, data: &VmInjectMceData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.inject_mce(data.cpu_id, data.address, data.action_required) {
+                error!("Error when injecting machine check: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_hotplug(&mut self, device: HotplugDevice) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let id = vm.hotplug(device).map_err(|e| {
+                error!("Error when starting hotplug job: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&VmHotplugJobData { id }).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_hotplug_status(&mut self, data: &VmHotplugJobData) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.hotplug_status(data.id)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_cancel_hotplug(&mut self, data: &VmHotplugJobData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.cancel_hotplug(data.id) {
+                error!("Error when cancelling hotplug job: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_counters(&mut self) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.counters().map_err(|e| {
@@ -677,6 +1040,22 @@ impl Vmm {
         }
     }
 
+    fn vm_memory_layout(&mut self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.memory_layout()).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotCreated)
+        }
+    }
+
+    fn vm_access_trace(&mut self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.access_trace()).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotCreated)
+        }
+    }
+
     fn vm_receive_config<T>(
         &mut self,
         req: &Request,
@@ -703,6 +1082,12 @@ impl Vmm {
         let reset_evt = self.reset_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning reset EventFd: {}", e))
         })?;
+        let suspend_evt = self.suspend_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning suspend EventFd: {}", e))
+        })?;
+        let crash_evt = self.crash_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning crash EventFd: {}", e))
+        })?;
         let activate_evt = self.activate_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning activate EventFd: {}", e))
         })?;
@@ -712,6 +1097,8 @@ impl Vmm {
             self.vm_config.clone().unwrap(),
             exit_evt,
             reset_evt,
+            suspend_evt,
+            crash_evt,
             &self.seccomp_action,
             self.hypervisor.clone(),
             activate_evt,
@@ -751,6 +1138,10 @@ impl Vmm {
             Response::error().write_to(socket).ok();
             e
         })?;
+        self.add_pty_events(vm.serial_pty(), vm.console_pty())
+            .map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error adding pty events: {:?}", e))
+            })?;
         self.vm = Some(vm);
 
         Response::ok().write_to(socket)?;
@@ -791,7 +1182,7 @@ impl Vmm {
         let url = url::Url::parse(&receive_data_migration.receiver_url)
             .map_err(|e| MigratableError::MigrateReceive(anyhow!("Error parsing URL: {}", e)))?;
 
-        let mut socket = match url.scheme() {
+        let mut socket: Box<dyn MigrationSocket> = match url.scheme() {
             "unix" => {
                 let path = url.to_file_path().map_err(|_| {
                     MigratableError::MigrateReceive(anyhow!("Error extracting path from URL"))
@@ -808,7 +1199,19 @@ impl Vmm {
                 std::fs::remove_file(&path).map_err(|e| {
                     MigratableError::MigrateReceive(anyhow!("Error unlinking UNIX socket: {}", e))
                 })?;
-                socket
+                Box::new(socket)
+            }
+            "tcp" => {
+                let address = Self::migration_tcp_address(&url).ok_or_else(|| {
+                    MigratableError::MigrateReceive(anyhow!("Error extracting address from URL"))
+                })?;
+                let listener = TcpListener::bind(&address).map_err(|e| {
+                    MigratableError::MigrateReceive(anyhow!("Error binding to TCP socket: {}", e))
+                })?;
+                let (socket, _addr) = listener.accept().map_err(|e| {
+                    MigratableError::MigrateReceive(anyhow!("Error accepting on TCP socket: {}", e))
+                })?;
+                Box::new(socket)
             }
             _ => {
                 return Err(MigratableError::MigrateReceive(anyhow!(
@@ -894,6 +1297,11 @@ impl Vmm {
         Ok(())
     }
 
+    // Address of a "tcp://<host>:<port>" migration URL.
+    fn migration_tcp_address(url: &url::Url) -> Option<String> {
+        Some(format!("{}:{}", url.host_str()?, url.port()?))
+    }
+
     // Returns true if there were dirty pages to send
     fn vm_maybe_send_dirty_pages<T>(
         vm: &mut Vm,
@@ -914,6 +1322,7 @@ impl Vmm {
         table.write_to(socket)?;
         // And then the memory itself
         vm.send_memory_regions(&table, socket)?;
+        metrics::migration_memory_sent(&table);
         let res = Response::read_from(socket)?;
         if res.status() != Status::Ok {
             warn!("Error during dirty memory migration");
@@ -938,13 +1347,29 @@ impl Vmm {
         if let Some(ref mut vm) = self.vm {
             let url = url::Url::parse(&send_data_migration.destination_url)
                 .map_err(|e| MigratableError::MigrateSend(anyhow!("Error parsing URL: {}", e)))?;
-            let mut socket = match url.scheme() {
-                "unix" => UnixStream::connect(url.to_file_path().map_err(|_| {
-                    MigratableError::MigrateSend(anyhow!("Error extracting path from URL"))
-                })?)
-                .map_err(|e| {
-                    MigratableError::MigrateSend(anyhow!("Error connecting to UNIX socket: {}", e))
-                })?,
+            let mut socket: Box<dyn MigrationSocket> = match url.scheme() {
+                "unix" => Box::new(
+                    UnixStream::connect(url.to_file_path().map_err(|_| {
+                        MigratableError::MigrateSend(anyhow!("Error extracting path from URL"))
+                    })?)
+                    .map_err(|e| {
+                        MigratableError::MigrateSend(anyhow!(
+                            "Error connecting to UNIX socket: {}",
+                            e
+                        ))
+                    })?,
+                ),
+                "tcp" => Box::new(
+                    TcpStream::connect(Self::migration_tcp_address(&url).ok_or_else(|| {
+                        MigratableError::MigrateSend(anyhow!("Error extracting address from URL"))
+                    })?)
+                    .map_err(|e| {
+                        MigratableError::MigrateSend(anyhow!(
+                            "Error connecting to TCP socket: {}",
+                            e
+                        ))
+                    })?,
+                ),
                 _ => {
                     return Err(MigratableError::MigrateReceive(anyhow!(
                         "Unsupported URL scheme"
@@ -991,6 +1416,7 @@ impl Vmm {
             table.write_to(&mut socket)?;
             // And then the memory itself
             vm.send_memory_regions(&table, &mut socket)?;
+            metrics::migration_memory_sent(&table);
             let res = Response::read_from(&mut socket)?;
             if res.status() != Status::Ok {
                 warn!("Error during memory migration");
@@ -1092,11 +1518,66 @@ impl Vmm {
                             self.reset_evt.read().map_err(Error::EventFdRead)?;
                             self.vm_reboot().map_err(Error::VmReboot)?;
                         }
+                        EpollDispatch::Suspend => {
+                            // Consume the event.
+                            self.suspend_evt.read().map_err(Error::EventFdRead)?;
+                            if let Some(ref mut vm) = self.vm {
+                                vm.suspend().map_err(Error::VmSuspend)?;
+                                event_monitor::event("vm", "suspended");
+                            }
+                        }
+                        EpollDispatch::PauseToggle => {
+                            // Consume the event.
+                            self.pause_toggle_evt.read().map_err(Error::EventFdRead)?;
+                            if let Err(e) = self.vm_pause_toggle() {
+                                error!("Error pausing or resuming the VM: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::Crash => {
+                            // Consume the event.
+                            self.crash_evt.read().map_err(Error::EventFdRead)?;
+                            let on_crash = self
+                                .vm_config
+                                .as_ref()
+                                .map(|config| config.lock().unwrap().on_crash)
+                                .unwrap_or_default();
+                            error!("Guest crashed, action: {}", on_crash);
+                            event_monitor::event("vm", "crashed");
+                            match on_crash {
+                                OnCrashAction::Reboot => {
+                                    self.vm_reboot().map_err(Error::VmReboot)?;
+                                }
+                                OnCrashAction::Exit => {
+                                    self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+
+                                    break 'outer;
+                                }
+                                OnCrashAction::Pause => {
+                                    self.vm_pause().map_err(Error::VmPause)?;
+                                }
+                            }
+                        }
                         EpollDispatch::Stdin => {
                             if let Some(ref vm) = self.vm {
                                 vm.handle_stdin().map_err(Error::Stdin)?;
                             }
                         }
+                        // A failure to read from a pseudo terminal must not
+                        // bring the VMM down.
+                        EpollDispatch::SerialPty => {
+                            if let Some(ref vm) = self.vm {
+                                if let Err(e) = vm.handle_pty(ConsoleInput::Serial) {
+                                    error!("Error handling serial port pty: {:?}", e);
+                                }
+                            }
+                        }
+                        EpollDispatch::ConsolePty => {
+                            if let Some(ref vm) = self.vm {
+                                if let Err(e) = vm.handle_pty(ConsoleInput::VirtioConsole) {
+                                    error!("Error handling virtio-console pty: {:?}", e);
+                                }
+                            }
+                        }
                         EpollDispatch::ActivateVirtioDevices => {
                             if let Some(ref vm) = self.vm {
                                 vm.activate_virtio_devices()
@@ -1117,6 +1598,7 @@ impl Vmm {
                                     // The VM will be created when being asked to boot it.
                                     let response = if self.vm_config.is_none() {
                                         self.vm_config = Some(config);
+                                        event_monitor::event("vm", "created");
                                         Ok(ApiResponsePayload::Empty)
                                     } else {
                                         Err(ApiError::VmAlreadyCreated)
@@ -1141,8 +1623,7 @@ impl Vmm {
                                         continue;
                                     }
 
-                                    let response = self
-                                        .vm_boot()
+                                    let response = traced("vm.boot", || self.vm_boot())
                                         .map_err(ApiError::VmBoot)
                                         .map(|_| ApiResponsePayload::Empty);
 
@@ -1152,7 +1633,10 @@ impl Vmm {
                                     let response = self
                                         .vm_shutdown()
                                         .map_err(ApiError::VmShutdown)
-                                        .map(|_| ApiResponsePayload::Empty);
+                                        .map(|_| {
+                                            event_monitor::event("vm", "shutdown");
+                                            ApiResponsePayload::Empty
+                                        });
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
@@ -1177,6 +1661,13 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmmResourceUsage(sender) => {
+                                    let response = self
+                                        .vmm_resource_usage()
+                                        .map(ApiResponsePayload::VmmResourceUsage);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmPause(sender) => {
                                     let response = self
                                         .vm_pause()
@@ -1193,22 +1684,39 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmSnapshot(snapshot_data, sender) => {
+                                ApiRequest::VmPowerButton(sender) => {
                                     let response = self
-                                        .vm_snapshot(&snapshot_data.destination_url)
-                                        .map_err(ApiError::VmSnapshot)
+                                        .vm_power_button()
+                                        .map_err(ApiError::VmPowerButton)
                                         .map(|_| ApiResponsePayload::Empty);
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmRestore(restore_data, sender) => {
+                                ApiRequest::VmWakeup(sender) => {
                                     let response = self
-                                        .vm_restore(restore_data.as_ref().clone())
-                                        .map_err(ApiError::VmRestore)
+                                        .vm_wakeup()
+                                        .map_err(ApiError::VmWakeup)
                                         .map(|_| ApiResponsePayload::Empty);
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmSnapshot(snapshot_data, sender) => {
+                                    let response =
+                                        traced("vm.snapshot", || self.vm_snapshot(&snapshot_data))
+                                            .map_err(ApiError::VmSnapshot)
+                                            .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmRestore(restore_data, sender) => {
+                                    let response = traced("vm.restore", || {
+                                        self.vm_restore(restore_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmRestore)
+                                    .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmmShutdown(sender) => {
                                     let response = self
                                         .vmm_shutdown()
@@ -1241,52 +1749,101 @@ impl Vmm {
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
                                 ApiRequest::VmAddDevice(add_device_data, sender) => {
-                                    let response = self
-                                        .vm_add_device(add_device_data.as_ref().clone())
-                                        .map_err(ApiError::VmAddDevice)
-                                        .map(ApiResponsePayload::VmAction);
+                                    let response = traced("vm.add-device", || {
+                                        self.vm_add_device(add_device_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmAddDevice)
+                                    .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
                                 ApiRequest::VmRemoveDevice(remove_device_data, sender) => {
+                                    let response = traced("vm.remove-device", || {
+                                        self.vm_remove_device(remove_device_data.id.clone())
+                                    })
+                                    .map_err(ApiError::VmRemoveDevice)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddDisk(add_disk_data, sender) => {
+                                    let response = traced("vm.add-disk", || {
+                                        self.vm_add_disk(add_disk_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmAddDisk)
+                                    .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddFs(add_fs_data, sender) => {
+                                    let response = traced("vm.add-fs", || {
+                                        self.vm_add_fs(add_fs_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmAddFs)
+                                    .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddPmem(add_pmem_data, sender) => {
+                                    let response = traced("vm.add-pmem", || {
+                                        self.vm_add_pmem(add_pmem_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmAddPmem)
+                                    .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddNet(add_net_data, sender) => {
+                                    let response = traced("vm.add-net", || {
+                                        self.vm_add_net(add_net_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmAddNet)
+                                    .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddVsock(add_vsock_data, sender) => {
+                                    let response = traced("vm.add-vsock", || {
+                                        self.vm_add_vsock(add_vsock_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmAddVsock)
+                                    .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetNetLink(set_net_link_data, sender) => {
                                     let response = self
-                                        .vm_remove_device(remove_device_data.id.clone())
-                                        .map_err(ApiError::VmRemoveDevice)
+                                        .vm_set_net_link(set_net_link_data.as_ref())
+                                        .map_err(ApiError::VmSetNetLink)
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmAddDisk(add_disk_data, sender) => {
+                                ApiRequest::VmTraceQueues(trace_queues_data, sender) => {
                                     let response = self
-                                        .vm_add_disk(add_disk_data.as_ref().clone())
-                                        .map_err(ApiError::VmAddDisk)
-                                        .map(ApiResponsePayload::VmAction);
+                                        .vm_trace_queues(trace_queues_data.as_ref())
+                                        .map_err(ApiError::VmTraceQueues)
+                                        .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmAddFs(add_fs_data, sender) => {
+                                ApiRequest::VmInjectMce(inject_mce_data, sender) => {
                                     let response = self
-                                        .vm_add_fs(add_fs_data.as_ref().clone())
-                                        .map_err(ApiError::VmAddFs)
-                                        .map(ApiResponsePayload::VmAction);
+                                        .vm_inject_mce(inject_mce_data.as_ref())
+                                        .map_err(ApiError::VmInjectMce)
+                                        .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmAddPmem(add_pmem_data, sender) => {
+                                ApiRequest::VmHotplug(hotplug_data, sender) => {
                                     let response = self
-                                        .vm_add_pmem(add_pmem_data.as_ref().clone())
-                                        .map_err(ApiError::VmAddPmem)
+                                        .vm_hotplug(hotplug_data.as_ref().clone())
+                                        .map_err(ApiError::VmHotplug)
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmAddNet(add_net_data, sender) => {
+                                ApiRequest::VmHotplugStatus(hotplug_job_data, sender) => {
                                     let response = self
-                                        .vm_add_net(add_net_data.as_ref().clone())
-                                        .map_err(ApiError::VmAddNet)
+                                        .vm_hotplug_status(hotplug_job_data.as_ref())
+                                        .map_err(ApiError::VmHotplugStatus)
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmAddVsock(add_vsock_data, sender) => {
+                                ApiRequest::VmCancelHotplug(hotplug_job_data, sender) => {
                                     let response = self
-                                        .vm_add_vsock(add_vsock_data.as_ref().clone())
-                                        .map_err(ApiError::VmAddVsock)
-                                        .map(ApiResponsePayload::VmAction);
+                                        .vm_cancel_hotplug(hotplug_job_data.as_ref())
+                                        .map_err(ApiError::VmCancelHotplug)
+                                        .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
                                 ApiRequest::VmCounters(sender) => {
@@ -1295,22 +1852,46 @@ impl Vmm {
                                         .map_err(ApiError::VmInfo)
                                         .map(ApiResponsePayload::VmAction);
 
+                                    // The metrics exporter stops waiting for
+                                    // the counters after a while.
+                                    if sender.send(response).is_err() {
+                                        debug!("Counters requested but no longer awaited");
+                                    }
+                                }
+                                ApiRequest::VmMemoryLayout(sender) => {
+                                    let response = self
+                                        .vm_memory_layout()
+                                        .map_err(ApiError::VmMemoryLayout)
+                                        .map(ApiResponsePayload::VmAction);
+
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmReceiveMigration(receive_migration_data, sender) => {
+                                ApiRequest::VmAccessTrace(sender) => {
                                     let response = self
-                                        .vm_receive_migration(
+                                        .vm_access_trace()
+                                        .map_err(ApiError::VmAccessTrace)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmReceiveMigration(receive_migration_data, sender) => {
+                                    let response = traced("vm.receive-migration", || {
+                                        self.vm_receive_migration(
                                             receive_migration_data.as_ref().clone(),
                                         )
-                                        .map_err(ApiError::VmReceiveMigration)
-                                        .map(|_| ApiResponsePayload::Empty);
+                                    })
+                                    .map_err(ApiError::VmReceiveMigration)
+                                    .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
                                 ApiRequest::VmSendMigration(send_migration_data, sender) => {
-                                    let response = self
-                                        .vm_send_migration(send_migration_data.as_ref().clone())
-                                        .map_err(ApiError::VmSendMigration)
-                                        .map(|_| ApiResponsePayload::Empty);
+                                    metrics::migration_started();
+                                    let response = traced("vm.send-migration", || {
+                                        self.vm_send_migration(send_migration_data.as_ref().clone())
+                                    })
+                                    .map_err(ApiError::VmSendMigration)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                    metrics::migration_finished();
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
                             }
@@ -0,0 +1,140 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Handling of the host memory errors hit by the guest. When the guest
+//! accesses a guest RAM page the host found corrupted, KVM sends a SIGBUS to
+//! the vCPU thread, which would otherwise kill the whole VMM. Instead, the
+//! error is reported to the guest, which can then isolate the page.
+
+use libc::{c_void, siginfo_t};
+use std::cell::Cell;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::signal::register_signal_handler;
+
+// si_code values of SIGBUS, from the Linux siginfo.h.
+#[cfg(test)]
+const BUS_ADRERR: i32 = 2;
+const BUS_MCEERR_AR: i32 = 4;
+const BUS_MCEERR_AO: i32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryError {
+    /// Host virtual address of the poisoned memory.
+    pub host_address: u64,
+    /// Whether the memory was consumed, rather than found corrupted by the
+    /// host in the background.
+    pub action_required: bool,
+}
+
+thread_local! {
+    static HANDLE_ERRORS: Cell<bool> = Cell::new(false);
+    static PENDING_ERROR: Cell<Option<MemoryError>> = Cell::new(None);
+}
+
+// Record the error for the thread to report it once back from the signal
+// handler, which is all it can safely do. A second error before the first
+// one was reported means the VMM itself rather than the guest keeps faulting
+// on the poisoned memory, in which case the default action is restored for
+// the access to kill the VMM as it used to.
+fn record(si_code: i32, host_address: u64) -> bool {
+    let action_required = match si_code {
+        BUS_MCEERR_AR => true,
+        BUS_MCEERR_AO => false,
+        _ => return false,
+    };
+    if !HANDLE_ERRORS.with(|h| h.get()) || PENDING_ERROR.with(|p| p.get()).is_some() {
+        // The memory is still usable until it is consumed.
+        return !action_required;
+    }
+
+    PENDING_ERROR.with(|p| {
+        p.set(Some(MemoryError {
+            host_address,
+            action_required,
+        }))
+    });
+
+    true
+}
+
+extern "C" fn handle_sigbus(_: i32, info: *mut siginfo_t, _: *mut c_void) {
+    // Safe because the kernel hands a valid siginfo_t to the handler.
+    let (si_code, address) = unsafe { ((*info).si_code, (*info).si_addr() as u64) };
+    if !record(si_code, address) {
+        // Safe because restoring the default action has no side effect
+        // besides the signal being fatal again.
+        unsafe { libc::signal(libc::SIGBUS, libc::SIG_DFL) };
+    }
+}
+
+/// Handle the memory errors the current thread hits while running the guest,
+/// by recording them for `take_pending_error()`.
+pub fn handle_vcpu_memory_errors() -> vmm_sys_util::errno::Result<()> {
+    HANDLE_ERRORS.with(|h| h.set(true));
+    register_signal_handler(libc::SIGBUS, handle_sigbus)
+}
+
+/// The memory error the current thread hit since the last call, if any.
+pub fn take_pending_error() -> Option<MemoryError> {
+    PENDING_ERROR.with(|p| p.take())
+}
+
+/// Guest physical address of the guest RAM mapped at `host_address`.
+pub fn guest_address(memory: &GuestMemoryMmap, host_address: u64) -> Option<GuestAddress> {
+    memory.iter().find_map(|region| {
+        let start = region.as_ptr() as u64;
+        if host_address >= start && host_address - start < region.len() {
+            region.start_addr().checked_add(host_address - start)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        // Not reported by threads which are not running the guest.
+        assert!(record(BUS_MCEERR_AO, 0x1000));
+        assert!(!record(BUS_MCEERR_AR, 0x1000));
+        assert_eq!(take_pending_error(), None);
+
+        HANDLE_ERRORS.with(|h| h.set(true));
+        // Not a memory error.
+        assert!(!record(BUS_ADRERR, 0x1000));
+        assert!(record(BUS_MCEERR_AR, 0x2000));
+        // The VMM faults again on the memory before reporting the error.
+        assert!(!record(BUS_MCEERR_AR, 0x2000));
+        assert_eq!(
+            take_pending_error(),
+            Some(MemoryError {
+                host_address: 0x2000,
+                action_required: true
+            })
+        );
+        assert_eq!(take_pending_error(), None);
+    }
+
+    #[test]
+    fn test_guest_address() {
+        let memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x1_0000_0000), 0x10000),
+        ])
+        .unwrap();
+        let host_start = memory
+            .get_host_address(GuestAddress(0x1_0000_0000))
+            .unwrap() as u64;
+
+        assert_eq!(
+            guest_address(&memory, host_start + 0x3000),
+            Some(GuestAddress(0x1_0000_3000))
+        );
+        assert_eq!(guest_address(&memory, host_start + 0x10000), None);
+    }
+}
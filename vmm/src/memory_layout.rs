@@ -0,0 +1,142 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Guest physical address space layout, gathering the RAM, the reserved
+//! areas and the ranges allocated to the devices, which helps debugging the
+//! overlaps between them.
+
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum MemoryRangeType {
+    /// Guest RAM.
+    Ram,
+    /// Memory a virtio-mem device can plug into the guest.
+    VirtioMem,
+    /// SGX Enclave Page Cache.
+    SgxEpc,
+    /// Area the devices are allocated from.
+    DeviceArea,
+    /// Area not usable for RAM or devices allocation.
+    Reserved,
+    /// Range used by a device.
+    Device,
+}
+
+impl MemoryRangeType {
+    // Areas are expected to contain other ranges.
+    fn is_area(self) -> bool {
+        matches!(
+            self,
+            MemoryRangeType::DeviceArea | MemoryRangeType::Reserved
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemoryRange {
+    pub start: u64,
+    pub size: u64,
+    pub range_type: MemoryRangeType,
+    pub description: String,
+    /// Descriptions of the other ranges, besides the areas, overlapping with
+    /// this one, which must be empty for the layout to be valid.
+    #[serde(default)]
+    pub overlaps: Vec<String>,
+}
+
+impl MemoryRange {
+    fn end(&self) -> u64 {
+        self.start.saturating_add(self.size)
+    }
+
+    fn overlaps(&self, other: &MemoryRange) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryLayout {
+    ranges: Vec<MemoryRange>,
+}
+
+impl MemoryLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, start: u64, size: u64, range_type: MemoryRangeType, description: &str) {
+        if size == 0 {
+            return;
+        }
+
+        self.ranges.push(MemoryRange {
+            start,
+            size,
+            range_type,
+            description: description.to_string(),
+            overlaps: Vec::new(),
+        });
+    }
+
+    /// The ranges sorted by address, the biggest first when they start at
+    /// the same address, with the overlaps between them.
+    pub fn ranges(mut self) -> Vec<MemoryRange> {
+        self.ranges.sort_by(|a, b| match a.start.cmp(&b.start) {
+            Ordering::Equal => b.size.cmp(&a.size),
+            o => o,
+        });
+
+        for i in 0..self.ranges.len() {
+            if self.ranges[i].range_type.is_area() {
+                continue;
+            }
+            let overlaps: Vec<String> = self
+                .ranges
+                .iter()
+                .enumerate()
+                .filter(|(j, r)| *j != i && !r.range_type.is_area() && r.overlaps(&self.ranges[i]))
+                .map(|(_, r)| r.description.clone())
+                .collect();
+            self.ranges[i].overlaps = overlaps;
+        }
+
+        self.ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_layout() {
+        let mut layout = MemoryLayout::new();
+        layout.add(
+            0x1_0000_0000,
+            0x1000_0000,
+            MemoryRangeType::DeviceArea,
+            "64-bit device area",
+        );
+        layout.add(0, 0x8000_0000, MemoryRangeType::Ram, "memory zone mem0");
+        layout.add(0x1_0000_0000, 0x4000, MemoryRangeType::Device, "_disk0");
+        layout.add(0x1_0000_2000, 0x4000, MemoryRangeType::Device, "_net1");
+        layout.add(0x1_0001_0000, 0, MemoryRangeType::Device, "_empty");
+
+        let ranges = layout.ranges();
+        let descriptions: Vec<&str> = ranges.iter().map(|r| r.description.as_str()).collect();
+        assert_eq!(
+            descriptions,
+            vec!["memory zone mem0", "64-bit device area", "_disk0", "_net1"]
+        );
+
+        // The devices are expected within the device area, not on top of
+        // each other.
+        assert!(ranges[0].overlaps.is_empty());
+        assert!(ranges[1].overlaps.is_empty());
+        assert_eq!(ranges[2].overlaps, vec!["_net1".to_string()]);
+        assert_eq!(ranges[3].overlaps, vec!["_disk0".to_string()]);
+    }
+}
@@ -6,6 +6,9 @@ extern crate hypervisor;
 #[cfg(target_arch = "x86_64")]
 use crate::config::SgxEpcConfig;
 use crate::config::{HotplugMethod, MemoryConfig, MemoryZoneConfig};
+use crate::migration::recv_vm_snapshot;
+use crate::snapshot_compression::{compressor, decompressor, read_sparse, write_sparse};
+use crate::snapshot_encryption::SnapshotKey;
 use crate::MEMORY_MANAGER_SNAPSHOT_ID;
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
@@ -17,12 +20,14 @@ use arch::{layout, RegionType};
 use devices::ioapic;
 #[cfg(target_arch = "x86_64")]
 use libc::{MAP_NORESERVE, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::cmp;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi;
-use std::fs::{File, OpenOptions};
-use std::io;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read};
 use std::ops::Deref;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
@@ -126,8 +131,15 @@ pub struct MemoryManager {
     hugepages: bool,
     #[cfg(target_arch = "x86_64")]
     sgx_epc_region: Option<SgxEpcRegion>,
+    // Read only flash the UEFI firmware is loaded into.
+    #[cfg(target_arch = "aarch64")]
+    uefi_flash: Option<GuestMemoryMmap>,
     user_provided_zones: bool,
     snapshot_memory_regions: Vec<MemoryRegion>,
+    snapshot_compression: bool,
+    snapshot_incremental: bool,
+    // Last snapshot taken or restored, from which the dirty pages are tracked.
+    snapshot_parent: Mutex<Option<String>>,
     memory_zones: MemoryZones,
 
     // Keep track of calls to create_userspace_mapping() for guest RAM.
@@ -248,11 +260,17 @@ pub enum Error {
     /// Guest address overflow
     GuestAddressOverFlow,
 
+    /// Guest physical address width too small for the requested memory
+    InsufficientPhysicalAddressBits(u8),
+
     /// Error opening snapshot file
     SnapshotOpen(io::Error),
 
     // Error copying snapshot into region
     SnapshotCopy(GuestMemoryError),
+
+    /// Error mapping snapshot file over region
+    SnapshotMap(io::Error),
 }
 
 const ENABLE_FLAG: usize = 0;
@@ -276,6 +294,16 @@ fn mmio_address_space_size(phys_bits: u8) -> u64 {
     (1 << phys_bits) - (1 << 16)
 }
 
+// Smallest number of physical address bits giving an MMIO address space
+// extending beyond the provided address.
+fn required_physical_bits(addr: GuestAddress) -> u8 {
+    let mut phys_bits = cmp::max(64 - addr.0.leading_zeros(), 17) as u8;
+    while phys_bits < 64 && mmio_address_space_size(phys_bits) - 1 <= addr.0 {
+        phys_bits += 1;
+    }
+    phys_bits
+}
+
 impl BusDevice for MemoryManager {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         if self.selected_slot < self.hotplug_slots.len() {
@@ -458,23 +486,113 @@ impl MemoryManager {
         Ok((mem_regions, memory_zones))
     }
 
-    fn fill_saved_regions(&mut self, saved_regions: Vec<MemoryRegion>) -> Result<(), Error> {
+    // Copy the content of the saved regions into the guest memory. The zero
+    // pages are skipped when the memory has just been allocated, which is not
+    // the case when applying an incremental snapshot on top of its parent.
+    fn fill_saved_regions(
+        &mut self,
+        saved_regions: Vec<MemoryRegion>,
+        key: Option<&SnapshotKey>,
+        sparse: bool,
+    ) -> Result<(), Error> {
         for region in saved_regions {
-            if let Some(content) = region.content {
+            if let Some(content) = &region.content {
                 // Open (read only) the snapshot file for the given region.
-                let mut memory_region_file = OpenOptions::new()
+                let memory_region_file = OpenOptions::new()
                     .read(true)
                     .open(content)
                     .map_err(Error::SnapshotOpen)?;
 
-                self.guest_memory
-                    .memory()
-                    .read_exact_from(
-                        region.start_addr,
-                        &mut memory_region_file,
+                let mut reader: Box<dyn Read + '_> = if let Some(key) = key {
+                    // The file name is authenticated along with the content.
+                    let name = content
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .unwrap_or_default();
+                    Box::new(
+                        key.reader(name, BufReader::new(memory_region_file))
+                            .map_err(Error::SnapshotOpen)?,
+                    )
+                } else {
+                    Box::new(memory_region_file)
+                };
+                if region.compressed {
+                    reader = Box::new(decompressor(reader).map_err(Error::SnapshotOpen)?);
+                }
+
+                let guest_memory = self.guest_memory.memory();
+                let sparse = sparse && Self::is_zeroed(&guest_memory, region.start_addr);
+                for (addr, size) in region.saved_ranges() {
+                    if sparse {
+                        // Skip the zero pages, which are then left unallocated.
+                        read_sparse(&guest_memory, addr, size, &mut reader)
+                            .map_err(|e| Error::SnapshotCopy(GuestMemoryError::IOError(e)))?;
+                    } else {
+                        guest_memory
+                            .read_exact_from(addr, &mut reader, size)
+                            .map_err(Error::SnapshotCopy)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Map the snapshot file of each region copy-on-write over the guest
+    // memory instead of copying it, so that the clones of a VM share the
+    // pages none of them modified. The files must hold the whole regions.
+    // The regions keep describing their original file, which no longer
+    // backs the memory. This is fine as long as the memory is private, since
+    // the devices releasing memory only punch holes into the files of the
+    // shared mappings.
+    fn map_saved_regions(
+        &mut self,
+        saved_regions: Vec<MemoryRegion>,
+        prefault: bool,
+    ) -> Result<(), Error> {
+        let guest_memory = self.guest_memory.memory();
+        for region in saved_regions {
+            if let Some(content) = &region.content {
+                if region.compressed || region.ranges.is_some() {
+                    return Err(Error::Restore(MigratableError::Restore(anyhow!(
+                        "Cloning requires the memory to be stored uncompressed, in full"
+                    ))));
+                }
+                let guest_region = guest_memory
+                    .find_region(region.start_addr)
+                    .filter(|r| r.start_addr() == region.start_addr && r.len() == region.size)
+                    .ok_or_else(|| {
+                        Error::Restore(MigratableError::Restore(anyhow!(
+                            "No memory region matching the snapshot one at 0x{:x}",
+                            region.start_addr.0
+                        )))
+                    })?;
+
+                let memory_region_file = OpenOptions::new()
+                    .read(true)
+                    .open(content)
+                    .map_err(Error::SnapshotOpen)?;
+
+                let mut mmap_flags = libc::MAP_PRIVATE | libc::MAP_FIXED | MAP_NORESERVE;
+                if prefault {
+                    mmap_flags |= MAP_POPULATE;
+                }
+                // Safe because the mapping replaces the one of the guest
+                // memory region, at the same address and with the same size.
+                let addr = unsafe {
+                    libc::mmap(
+                        guest_region.deref().as_ptr() as *mut libc::c_void,
                         region.size as usize,
+                        PROT_READ | PROT_WRITE,
+                        mmap_flags,
+                        memory_region_file.as_raw_fd(),
+                        0,
                     )
-                    .map_err(Error::SnapshotCopy)?;
+                };
+                if addr == libc::MAP_FAILED {
+                    return Err(Error::SnapshotMap(io::Error::last_os_error()));
+                }
             }
         }
 
@@ -685,6 +803,19 @@ impl MemoryManager {
             }
         }
 
+        // RAM, including the area reserved for hotplug, must leave some room
+        // for the 64-bit device area below the top of the address space.
+        if start_of_device_area >= end_of_device_area {
+            error!(
+                "Guest memory layout ends at {:#x}, beyond the {} bits address \
+                space: at least {} physical address bits are required",
+                start_of_device_area.0,
+                phys_bits,
+                required_physical_bits(start_of_device_area),
+            );
+            return Err(Error::InsufficientPhysicalAddressBits(phys_bits));
+        }
+
         let guest_memory = GuestMemoryAtomic::new(guest_memory);
 
         let mut hotplug_slots = Vec::with_capacity(HOTPLUG_COUNT);
@@ -730,8 +861,13 @@ impl MemoryManager {
             hugepages: config.hugepages,
             #[cfg(target_arch = "x86_64")]
             sgx_epc_region: None,
+            #[cfg(target_arch = "aarch64")]
+            uefi_flash: None,
             user_provided_zones,
             snapshot_memory_regions: Vec::new(),
+            snapshot_compression: false,
+            snapshot_incremental: false,
+            snapshot_parent: Mutex::new(None),
             memory_zones,
             guest_ram_mappings: Vec::new(),
         }));
@@ -791,35 +927,61 @@ impl MemoryManager {
         Ok(memory_manager)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_snapshot(
         snapshot: &Snapshot,
         vm: Arc<dyn hypervisor::Vm>,
         config: &MemoryConfig,
         source_url: Option<&str>,
+        key: Option<&SnapshotKey>,
         prefault: bool,
+        clone: bool,
         phys_bits: u8,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
-        let mm = MemoryManager::new(vm, config, prefault, phys_bits)?;
+        // The memory of a clone is replaced with the mapping of the snapshot
+        // files, which is the one to prefault.
+        let mm = MemoryManager::new(vm, config, prefault && !clone, phys_bits)?;
 
         if let Some(source_url) = source_url {
-            let url = Url::parse(source_url).unwrap();
-            /* url must be valid dir which is verified in recv_vm_snapshot() */
-            let vm_snapshot_path = url.to_file_path().unwrap();
-
-            if let Some(mem_section) = snapshot
-                .snapshot_data
-                .get(&format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID))
-            {
-                let mem_snapshot: MemoryManagerSnapshotData =
-                    match serde_json::from_slice(&mem_section.snapshot) {
-                        Ok(snapshot) => snapshot,
-                        Err(error) => {
-                            return Err(Error::Restore(MigratableError::Restore(anyhow!(
-                                "Could not deserialize MemoryManager {}",
-                                error
-                            ))))
-                        }
-                    };
+            // Gather the chain of snapshots, from the one being restored up to
+            // the last full snapshot, for the incremental snapshots only hold
+            // the memory dirtied since their parent.
+            let mut layers = vec![(
+                source_url.to_string(),
+                MemoryManagerSnapshotData::from_snapshot(snapshot)?,
+            )];
+            while let Some(parent_url) = layers.last().unwrap().1.parent_url.clone() {
+                if layers.iter().any(|(url, _)| *url == parent_url) {
+                    return Err(Error::Restore(MigratableError::Restore(anyhow!(
+                        "Loop in the chain of snapshots at {}",
+                        parent_url
+                    ))));
+                }
+
+                let parent = recv_vm_snapshot(&parent_url, key).map_err(Error::Restore)?;
+                let parent_data = parent
+                    .snapshots
+                    .get(MEMORY_MANAGER_SNAPSHOT_ID)
+                    .ok_or_else(|| {
+                        Error::Restore(MigratableError::Restore(anyhow!(
+                            "Missing memory manager snapshot from {}",
+                            parent_url
+                        )))
+                    })
+                    .and_then(|snapshot| MemoryManagerSnapshotData::from_snapshot(snapshot))?;
+                layers.push((parent_url, parent_data));
+            }
+
+            if clone && (key.is_some() || layers.len() > 1) {
+                return Err(Error::Restore(MigratableError::Restore(anyhow!(
+                    "Cloning requires a full snapshot, which isn't encrypted"
+                ))));
+            }
+
+            for (index, (layer_url, layer)) in layers.into_iter().rev().enumerate() {
+                let url = Url::parse(&layer_url).unwrap();
+                /* url must be valid dir which is verified in recv_vm_snapshot() */
+                let vm_snapshot_path = url.to_file_path().unwrap();
 
                 // Here we turn the content file name into a content file path as
                 // this will be needed to copy the content of the saved memory
@@ -829,7 +991,7 @@ impl MemoryManager {
                 // no need for saving into a dedicated external file. For these
                 // files, the VmConfig already contains the information on where to
                 // find them.
-                let mut saved_regions = mem_snapshot.memory_regions;
+                let mut saved_regions = layer.memory_regions;
                 for region in saved_regions.iter_mut() {
                     if let Some(content) = &mut region.content {
                         let mut memory_region_path = vm_snapshot_path.clone();
@@ -838,18 +1000,30 @@ impl MemoryManager {
                     }
                 }
 
-                mm.lock().unwrap().fill_saved_regions(saved_regions)?;
+                if clone {
+                    mm.lock()
+                        .unwrap()
+                        .map_saved_regions(saved_regions, prefault)?;
+                } else {
+                    mm.lock()
+                        .unwrap()
+                        .fill_saved_regions(saved_regions, key, index == 0)?;
+                }
+            }
 
-                Ok(mm)
-            } else {
-                Err(Error::Restore(MigratableError::Restore(anyhow!(
-                    "Could not find {}-section from snapshot",
-                    MEMORY_MANAGER_SNAPSHOT_ID
-                ))))
+            // The dirty log starts empty, and the memory matches the snapshot,
+            // which can therefore be the parent of an incremental snapshot,
+            // once the pages written by the VMM while restoring are forgotten.
+            match clear_soft_dirty() {
+                Ok(()) => {
+                    *mm.lock().unwrap().snapshot_parent.lock().unwrap() =
+                        Some(source_url.to_string())
+                }
+                Err(e) => warn!("Cannot track the memory written by the VMM: {}", e),
             }
-        } else {
-            Ok(mm)
         }
+
+        Ok(mm)
     }
 
     fn memfd_create(name: &ffi::CStr, flags: u32) -> Result<RawFd, io::Error> {
@@ -1281,10 +1455,12 @@ impl MemoryManager {
         let mut region: Option<Arc<GuestRegionMmap>> = None;
         match self.hotplug_method {
             HotplugMethod::VirtioMem => {
-                if desired_ram >= self.boot_ram {
-                    self.virtio_mem_resize(DEFAULT_MEMORY_ZONE, desired_ram - self.boot_ram)?;
-                    self.current_ram = desired_ram;
-                }
+                // Asking for less than the boot RAM unplugs all the memory
+                // previously hotplugged through virtio-mem, as the boot RAM
+                // itself can't be removed.
+                let desired_ram = cmp::max(desired_ram, self.boot_ram);
+                self.virtio_mem_resize(DEFAULT_MEMORY_ZONE, desired_ram - self.boot_ram)?;
+                self.current_ram = desired_ram;
             }
             HotplugMethod::Acpi => {
                 if desired_ram > self.current_ram {
@@ -1397,6 +1573,40 @@ impl MemoryManager {
         &self.sgx_epc_region
     }
 
+    /// Map the flash the UEFI firmware is loaded into. It is read only from
+    /// the guest, and kept out of the guest RAM.
+    #[cfg(target_arch = "aarch64")]
+    pub fn add_uefi_flash(&mut self) -> Result<GuestMemoryMmap, Error> {
+        if let Some(uefi_flash) = &self.uefi_flash {
+            return Ok(uefi_flash.clone());
+        }
+
+        let region = MemoryManager::create_ram_region(
+            &None,
+            0,
+            GuestAddress(layout::UEFI_START),
+            layout::UEFI_SIZE as usize,
+            false,
+            false,
+            false,
+            None,
+        )?;
+        self.create_userspace_mapping(
+            region.start_addr().raw_value(),
+            region.len() as u64,
+            region.as_ptr() as u64,
+            false,
+            true,
+            false,
+        )?;
+
+        let uefi_flash =
+            GuestMemoryMmap::from_arc_regions(vec![region]).map_err(Error::GuestMemory)?;
+        self.uefi_flash = Some(uefi_flash.clone());
+
+        Ok(uefi_flash)
+    }
+
     pub fn is_hardlink(f: &File) -> bool {
         let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
         let ret = unsafe { libc::fstat(f.as_raw_fd(), stat.as_mut_ptr()) };
@@ -1408,6 +1618,29 @@ impl MemoryManager {
         unsafe { (*stat.as_ptr()).st_nlink as usize > 0 }
     }
 
+    // Whether the region starting at this address has been freshly allocated,
+    // as opposed to being backed by an existing file with its own content.
+    fn is_zeroed(guest_memory: &GuestMemoryMmap, start_addr: GuestAddress) -> bool {
+        match guest_memory
+            .find_region(start_addr)
+            .and_then(|region| region.file_offset())
+        {
+            Some(file_offset) => !Self::is_hardlink(file_offset.file()),
+            None => true,
+        }
+    }
+
+    /// Compress the memory regions of the next snapshots.
+    pub fn set_snapshot_compression(&mut self, compress: bool) {
+        self.snapshot_compression = compress;
+    }
+
+    /// Only store the memory dirtied since the last snapshot taken or
+    /// restored, for the next snapshot.
+    pub fn set_snapshot_incremental(&mut self, incremental: bool) {
+        self.snapshot_incremental = incremental;
+    }
+
     pub fn memory_zones(&self) -> &MemoryZones {
         &self.memory_zones
     }
@@ -1427,14 +1660,48 @@ impl MemoryManager {
     // in the block and instead create smaller ranges covering those pages.
     pub fn dirty_memory_range_table(
         &self,
+    ) -> std::result::Result<MemoryRangeTable, MigratableError> {
+        // The pages dirtied since the parent snapshot are not tracked anymore
+        // once the dirty log is consumed, by a live migration for instance.
+        self.snapshot_parent.lock().unwrap().take();
+        self.dirty_log_range_table(false)
+    }
+
+    // Same as dirty_memory_range_table(), also including the pages written by
+    // the VMM itself when requested, such as the buffers filled by the virtio
+    // devices, which the dirty log of the hypervisor doesn't track.
+    fn dirty_log_range_table(
+        &self,
+        vmm_dirty: bool,
     ) -> std::result::Result<MemoryRangeTable, MigratableError> {
         let page_size = 4096; // TODO: Does this need to vary?
         let mut table = MemoryRangeTable::default();
         let mut total_pages = 0;
+        let pagemap = if vmm_dirty {
+            Some(File::open("/proc/self/pagemap").map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error opening the pagemap {}", e))
+            })?)
+        } else {
+            None
+        };
         for r in &self.guest_ram_mappings {
-            let dirty_bitmap = self.vm.get_dirty_log(r.slot, r.size).map_err(|e| {
+            let mut dirty_bitmap = self.vm.get_dirty_log(r.slot, r.size).map_err(|e| {
                 MigratableError::MigrateSend(anyhow!("Error getting VM dirty log {}", e))
             })?;
+            if let Some(pagemap) = &pagemap {
+                let hva = self
+                    .guest_memory
+                    .memory()
+                    .get_host_address(GuestAddress(r.gpa))
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                let soft_dirty_bitmap =
+                    soft_dirty_bitmap(pagemap, hva as u64, r.size).map_err(|e| {
+                        MigratableError::MigrateSend(anyhow!("Error reading the pagemap {}", e))
+                    })?;
+                for (block, soft_dirty) in dirty_bitmap.iter_mut().zip(soft_dirty_bitmap) {
+                    *block |= soft_dirty;
+                }
+            }
 
             let mut entry: Option<MemoryRange> = None;
             for (i, block) in dirty_bitmap.iter().enumerate() {
@@ -1481,6 +1748,7 @@ impl MemoryManager {
     // Just before we do a bulk copy we want to clear the dirty log so that
     // pages touched during our bulk copy are tracked.
     pub fn start_memory_dirty_log(&self) -> std::result::Result<(), MigratableError> {
+        self.snapshot_parent.lock().unwrap().take();
         for r in &self.guest_ram_mappings {
             self.vm.get_dirty_log(r.slot, r.size).map_err(|e| {
                 MigratableError::MigrateSend(anyhow!("Error getting VM dirty log {}", e))
@@ -1490,6 +1758,47 @@ impl MemoryManager {
     }
 }
 
+// Soft-dirty flag of a pagemap entry, set when the page has been written since
+// the soft-dirty flags were last cleared.
+const PAGEMAP_SOFT_DIRTY: u64 = 1 << 55;
+
+// Clear the soft-dirty flags of the VMM pages. As it write protects them, the
+// hypervisor faults on the next write from the guest as well, which sets the
+// flag again.
+fn clear_soft_dirty() -> io::Result<()> {
+    fs::write("/proc/self/clear_refs", b"4")
+}
+
+// Bitmap of the pages of the mapping written since the soft-dirty flags were
+// cleared, in the same format as the dirty log of the hypervisor.
+fn soft_dirty_bitmap(pagemap: &File, hva: u64, size: u64) -> io::Result<Vec<u64>> {
+    const PAGE_SIZE: u64 = 4096;
+    const ENTRY_SIZE: usize = std::mem::size_of::<u64>();
+    const CHUNK_PAGES: usize = 4096;
+
+    let pages = (size / PAGE_SIZE) as usize;
+    let mut bitmap = vec![0u64; (pages + 63) / 64];
+    let mut entries = vec![0u8; CHUNK_PAGES * ENTRY_SIZE];
+    let mut page = 0;
+    while page < pages {
+        let count = cmp::min(CHUNK_PAGES, pages - page);
+        let offset = (hva / PAGE_SIZE + page as u64) * ENTRY_SIZE as u64;
+        pagemap.read_exact_at(&mut entries[..count * ENTRY_SIZE], offset)?;
+        for (i, entry) in entries[..count * ENTRY_SIZE]
+            .chunks_exact(ENTRY_SIZE)
+            .enumerate()
+        {
+            // Safe to unwrap as the chunk has the size of an entry.
+            if u64::from_ne_bytes(entry.try_into().unwrap()) & PAGEMAP_SOFT_DIRTY != 0 {
+                bitmap[(page + i) / 64] |= 1 << ((page + i) % 64);
+            }
+        }
+        page += count;
+    }
+
+    Ok(bitmap)
+}
+
 #[cfg(feature = "acpi")]
 struct MemoryNotify {
     slot_id: usize,
@@ -1871,11 +2180,54 @@ pub struct MemoryRegion {
     #[serde(with = "GuestAddressDef")]
     start_addr: GuestAddress,
     size: GuestUsize,
+    #[serde(default)]
+    compressed: bool,
+    // Ranges dirtied since the parent snapshot, stored one after the other,
+    // or None when the whole region is stored.
+    #[serde(default)]
+    ranges: Option<Vec<MemoryRange>>,
+}
+
+impl MemoryRegion {
+    // Guest memory ranges stored in the content file, in order.
+    fn saved_ranges(&self) -> Vec<(GuestAddress, usize)> {
+        match &self.ranges {
+            Some(ranges) => ranges
+                .iter()
+                .map(|range| (GuestAddress(range.gpa), range.length as usize))
+                .collect(),
+            None => vec![(self.start_addr, self.size as usize)],
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct MemoryManagerSnapshotData {
     memory_regions: Vec<MemoryRegion>,
+    // URL of the parent of an incremental snapshot.
+    #[serde(default)]
+    parent_url: Option<String>,
+}
+
+impl MemoryManagerSnapshotData {
+    fn from_snapshot(snapshot: &Snapshot) -> Result<Self, Error> {
+        let mem_section = snapshot
+            .snapshot_data
+            .get(&format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID))
+            .ok_or_else(|| {
+                Error::Restore(MigratableError::Restore(anyhow!(
+                    "Could not find {}-section from snapshot",
+                    MEMORY_MANAGER_SNAPSHOT_ID
+                )))
+            })?;
+
+        serde_json::from_slice(&mem_section.snapshot).map_err(|error| {
+            Error::Restore(MigratableError::Restore(anyhow!(
+                "Could not deserialize MemoryManager {}",
+                error
+            )))
+        })
+    }
 }
 
 impl Snapshottable for MemoryManager {
@@ -1887,6 +2239,18 @@ impl Snapshottable for MemoryManager {
         let mut memory_manager_snapshot = Snapshot::new(MEMORY_MANAGER_SNAPSHOT_ID);
         let guest_memory = self.guest_memory.memory();
 
+        let parent_url = self.snapshot_parent.lock().unwrap().take();
+        let parent_url = if std::mem::replace(&mut self.snapshot_incremental, false) {
+            Some(parent_url.ok_or_else(|| {
+                MigratableError::Snapshot(anyhow!("No parent snapshot for an incremental snapshot"))
+            })?)
+        } else {
+            None
+        };
+        // Getting the dirty log clears it, hence the next incremental snapshot
+        // only holds what is dirtied from now on.
+        let dirty_table = self.dirty_log_range_table(parent_url.is_some())?;
+
         let mut memory_regions: Vec<MemoryRegion> = Vec::new();
 
         guest_memory.with_regions_mut(|index, region| {
@@ -1912,10 +2276,26 @@ impl Snapshottable for MemoryManager {
                 }
             }
 
+            let ranges = parent_url.as_ref().map(|_| {
+                let start = region.start_addr().raw_value();
+                let end = start + region.len();
+                dirty_table
+                    .regions()
+                    .iter()
+                    .filter(|range| range.gpa >= start && range.gpa < end)
+                    .map(|range| MemoryRange {
+                        gpa: range.gpa,
+                        length: cmp::min(range.length, end - range.gpa),
+                    })
+                    .collect()
+            });
+
             memory_regions.push(MemoryRegion {
+                compressed: content.is_some() && self.snapshot_compression,
                 content,
                 start_addr: region.start_addr(),
                 size: region.len(),
+                ranges,
             });
 
             Ok(())
@@ -1931,9 +2311,11 @@ impl Snapshottable for MemoryManager {
         // memory region content for the regions requiring it.
         self.snapshot_memory_regions = memory_regions.clone();
 
-        let snapshot_data_section =
-            serde_json::to_vec(&MemoryManagerSnapshotData { memory_regions })
-                .map_err(|e| MigratableError::Snapshot(e.into()))?;
+        let snapshot_data_section = serde_json::to_vec(&MemoryManagerSnapshotData {
+            memory_regions,
+            parent_url,
+        })
+        .map_err(|e| MigratableError::Snapshot(e.into()))?;
 
         memory_manager_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID),
@@ -1947,11 +2329,13 @@ impl Snapshottable for MemoryManager {
     }
 }
 
-impl Transportable for MemoryManager {
-    fn send(
+impl MemoryManager {
+    /// Write the content of the memory regions from the last snapshot to the
+    /// destination, encrypting the files when a key is provided.
+    pub fn send_snapshot(
         &self,
-        _snapshot: &Snapshot,
         destination_url: &str,
+        key: Option<&SnapshotKey>,
     ) -> result::Result<(), MigratableError> {
         let url = Url::parse(destination_url).map_err(|e| {
             MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
@@ -1982,23 +2366,33 @@ impl Transportable for MemoryManager {
                             memory_region_path.push(content);
 
                             // Create the snapshot file for the region
-                            let mut memory_region_file = OpenOptions::new()
+                            let memory_region_file = OpenOptions::new()
                                 .read(true)
                                 .write(true)
                                 .create_new(true)
                                 .open(memory_region_path)
                                 .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
-                            guest_memory
-                                .write_all_to(
-                                    region.start_addr,
-                                    &mut memory_region_file,
-                                    region.size as usize,
-                                )
-                                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                            Self::write_region(
+                                guest_memory,
+                                region,
+                                memory_region_file,
+                                &content.to_string_lossy(),
+                                key,
+                            )
+                            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
                         }
                     }
                 }
+
+                // The pages written by the VMM are tracked from now on.
+                clear_soft_dirty().map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "Cannot track the memory written by the VMM: {}",
+                        e
+                    ))
+                })?;
+                *self.snapshot_parent.lock().unwrap() = Some(destination_url.to_string());
             }
             _ => {
                 return Err(MigratableError::MigrateSend(anyhow!(
@@ -2009,5 +2403,67 @@ impl Transportable for MemoryManager {
         }
         Ok(())
     }
+
+    // Write the content of the region to its snapshot file, compressed and
+    // encrypted as requested. The zero pages are left as holes in the file
+    // when the content is written as is.
+    fn write_region(
+        guest_memory: &GuestMemoryMmap,
+        region: &MemoryRegion,
+        file: File,
+        name: &str,
+        key: Option<&SnapshotKey>,
+    ) -> io::Result<()> {
+        let ranges = region.saved_ranges();
+        let write_all_to = |mut writer: &mut dyn io::Write| -> io::Result<()> {
+            for (addr, size) in ranges.iter() {
+                guest_memory
+                    .write_all_to(*addr, &mut writer, *size)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+            }
+            Ok(())
+        };
+
+        match (key, region.compressed) {
+            (None, false) => {
+                let mut file_offset = 0;
+                for (addr, size) in ranges.iter() {
+                    write_sparse(guest_memory, *addr, *size, &file, file_offset)?;
+                    file_offset += *size as u64;
+                }
+                Ok(())
+            }
+            (None, true) => {
+                let mut encoder = compressor(file)?;
+                write_all_to(&mut encoder)?;
+                encoder.finish()?;
+                Ok(())
+            }
+            (Some(key), false) => {
+                let mut writer = key.writer(name, file)?;
+                write_all_to(&mut writer)?;
+                writer.finish()?;
+                Ok(())
+            }
+            (Some(key), true) => {
+                // Compressing before encrypting, as encrypted data can't be
+                // compressed.
+                let mut encoder = compressor(key.writer(name, file)?)?;
+                write_all_to(&mut encoder)?;
+                encoder.finish()?.finish()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Transportable for MemoryManager {
+    fn send(
+        &self,
+        _snapshot: &Snapshot,
+        destination_url: &str,
+    ) -> result::Result<(), MigratableError> {
+        self.send_snapshot(destination_url, None)
+    }
 }
 impl Migratable for MemoryManager {}
@@ -0,0 +1,415 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Instance metadata service, serving the identity of the VM and its user
+//! data over HTTP in the EC2 and OpenStack formats, for agents like
+//! cloud-init to configure the guest on first boot.
+
+use crate::config::MetadataConfig;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::simple_http::{read_request, write_response};
+use seccomp::{SeccompAction, SeccompFilter};
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const LISTEN_BACKLOG: i32 = 128;
+
+#[derive(Debug, PartialEq)]
+struct Metadata {
+    instance_id: String,
+    hostname: Option<String>,
+    ssh_keys: Vec<String>,
+    user_data: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    // The files are read once, when the service starts.
+    fn load(config: &MetadataConfig) -> io::Result<Self> {
+        let ssh_keys = match &config.ssh_keys {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from)
+                .collect(),
+            None => Vec::new(),
+        };
+        let user_data = match &config.user_data {
+            Some(path) => Some(fs::read(path)?),
+            None => None,
+        };
+
+        Ok(Metadata {
+            instance_id: config.instance_id.clone(),
+            hostname: config.hostname.clone(),
+            ssh_keys,
+            user_data,
+        })
+    }
+
+    // EC2 layout, the version being ignored since the same content is
+    // served for all of them.
+    fn ec2(&self, path: &str) -> Option<Vec<u8>> {
+        let text = match path {
+            "" if self.user_data.is_some() => "meta-data\nuser-data".to_owned(),
+            "" => "meta-data".to_owned(),
+            "user-data" => return self.user_data.clone(),
+            "meta-data" | "meta-data/" => {
+                let mut entries = vec!["instance-id"];
+                if self.hostname.is_some() {
+                    entries.push("hostname");
+                    entries.push("local-hostname");
+                }
+                if !self.ssh_keys.is_empty() {
+                    entries.push("public-keys/");
+                }
+                entries.join("\n")
+            }
+            "meta-data/instance-id" => self.instance_id.clone(),
+            "meta-data/hostname" | "meta-data/local-hostname" => self.hostname.clone()?,
+            "meta-data/public-keys" | "meta-data/public-keys/" if !self.ssh_keys.is_empty() => {
+                let entries: Vec<String> = (0..self.ssh_keys.len())
+                    .map(|i| format!("{}=key{}", i, i))
+                    .collect();
+                entries.join("\n")
+            }
+            _ => {
+                let key = path.strip_prefix("meta-data/public-keys/")?;
+                let (index, file) = match key.find('/') {
+                    Some(pos) => (&key[..pos], &key[pos + 1..]),
+                    None => (key, ""),
+                };
+                let ssh_key = self.ssh_keys.get(index.parse::<usize>().ok()?)?;
+                match file {
+                    "" => "openssh-key".to_owned(),
+                    "openssh-key" => ssh_key.clone(),
+                    _ => return None,
+                }
+            }
+        };
+
+        Some(text.into_bytes())
+    }
+
+    // OpenStack layout, below /openstack/<version>/.
+    fn openstack(&self, path: &str) -> Option<Vec<u8>> {
+        match path {
+            "" => Some(b"meta_data.json\nuser_data".to_vec()),
+            "user_data" => self.user_data.clone(),
+            "meta_data.json" => {
+                let public_keys: serde_json::Map<String, serde_json::Value> = self
+                    .ssh_keys
+                    .iter()
+                    .enumerate()
+                    .map(|(i, k)| (format!("key{}", i), k.clone().into()))
+                    .collect();
+                let mut meta_data = serde_json::json!({
+                    "uuid": self.instance_id,
+                    "public_keys": public_keys,
+                });
+                if let Some(hostname) = &self.hostname {
+                    meta_data["hostname"] = hostname.clone().into();
+                    meta_data["name"] = hostname.clone().into();
+                }
+                Some(meta_data.to_string().into_bytes())
+            }
+            _ => None,
+        }
+    }
+
+    fn get(&self, path: &str) -> Option<Vec<u8>> {
+        let path = path.strip_prefix('/')?;
+        if path.is_empty() {
+            return Some(b"latest\nopenstack".to_vec());
+        }
+
+        let (version, path) = match path.strip_prefix("openstack") {
+            Some("") | Some("/") => return Some(b"latest".to_vec()),
+            Some(path) => {
+                let (_, path) = split_version(path.strip_prefix('/')?);
+                return self.openstack(path);
+            }
+            None => split_version(path),
+        };
+        if version.is_empty() {
+            return None;
+        }
+
+        self.ec2(path)
+    }
+}
+
+// Split the version from the rest of the path.
+fn split_version(path: &str) -> (&str, &str) {
+    match path.find('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => (path, ""),
+    }
+}
+
+fn handle_connection(stream: &mut TcpStream, metadata: &Metadata) -> io::Result<()> {
+    let request = match read_request(stream)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    let path = request.path.as_str();
+    let (status, content_type, body) = match request.method.as_str() {
+        "GET" => match metadata.get(path) {
+            Some(body) if path.ends_with(".json") => ("200 OK", "application/json", body),
+            Some(body) => ("200 OK", "text/plain", body),
+            None => ("404 Not Found", "text/plain", Vec::new()),
+        },
+        _ => ("405 Method Not Allowed", "text/plain", Vec::new()),
+    };
+
+    write_response(stream, status, content_type, &body)
+}
+
+/// Handle on the thread serving the metadata. The thread terminates when
+/// the handle is dropped.
+pub struct MetadataServiceHandle {
+    listener: TcpListener,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for MetadataServiceHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the thread waiting for a connection up. This is safe as the
+        // listener is a valid socket owned by the handle.
+        unsafe { libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RDWR) };
+    }
+}
+
+fn setsockopt(fd: i32, level: i32, name: i32, value: &[u8]) -> io::Result<()> {
+    // Safe because the kernel only reads the bytes of `value`.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Listen on `address` for the connections coming through the `tap`
+// interface only. Sockets bound to different interfaces don't conflict, so
+// several VMs can serve their metadata on the same address, each one being
+// unreachable from the other guests and from the host processes.
+fn bind_to_tap(address: SocketAddr, tap: &str) -> io::Result<TcpListener> {
+    let domain = match address {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // Safe because the returned value is checked.
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because fd is a valid socket, owned by the listener from now on
+    // for it to be closed on error.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    let reuse_addr: libc::c_int = 1;
+    setsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_REUSEADDR,
+        &reuse_addr.to_ne_bytes(),
+    )?;
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_BINDTODEVICE, tap.as_bytes())?;
+
+    // Safe because the socket addresses are plain structures, for which the
+    // all zeroes pattern is valid, and the kernel only reads the size of the
+    // one passed to bind().
+    let ret = match address {
+        SocketAddr::V4(address) => {
+            let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = address.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*address.ip()).to_be();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(address) => {
+            let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = address.port().to_be();
+            sockaddr.sin6_addr.s6_addr = address.ip().octets();
+            sockaddr.sin6_scope_id = address.scope_id();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because fd is a valid bound socket.
+    if unsafe { libc::listen(fd, LISTEN_BACKLOG) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+/// Start serving the metadata described by `config` on its TCP address,
+/// through its tap interface only. The host must own the address on that
+/// interface.
+pub fn start_metadata_service(
+    config: &MetadataConfig,
+    seccomp_action: &SeccompAction,
+) -> io::Result<(MetadataServiceHandle, thread::JoinHandle<()>)> {
+    let metadata = Metadata::load(config)?;
+    let listener = bind_to_tap(config.address, &config.tap)?;
+
+    let metadata_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::Metadata)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread_stopped = stopped.clone();
+    let thread_listener = listener.try_clone()?;
+
+    let thread = thread::Builder::new()
+        .name("metadata".to_string())
+        .spawn(move || {
+            if let Err(e) = SeccompFilter::apply(metadata_seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            for stream in thread_listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let result =
+                    stream.and_then(|mut stream| handle_connection(&mut stream, &metadata));
+                if let Err(e) = result {
+                    warn!("Error serving the metadata: {}", e);
+                }
+            }
+        })?;
+
+    Ok((MetadataServiceHandle { listener, stopped }, thread))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            instance_id: "i-0123456789".to_owned(),
+            hostname: Some("vm-1".to_owned()),
+            ssh_keys: vec!["ssh-ed25519 AAAA user@host".to_owned()],
+            user_data: Some(b"#cloud-config\n".to_vec()),
+        }
+    }
+
+    fn get(metadata: &Metadata, path: &str) -> Option<String> {
+        metadata
+            .get(path)
+            .map(|body| String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn test_ec2_metadata() {
+        let metadata = metadata();
+        assert_eq!(
+            get(&metadata, "/latest/meta-data/"),
+            Some("instance-id\nhostname\nlocal-hostname\npublic-keys/".to_owned())
+        );
+        assert_eq!(
+            get(&metadata, "/2009-04-04/meta-data/instance-id"),
+            Some("i-0123456789".to_owned())
+        );
+        assert_eq!(
+            get(&metadata, "/latest/meta-data/local-hostname"),
+            Some("vm-1".to_owned())
+        );
+        assert_eq!(
+            get(&metadata, "/latest/meta-data/public-keys/"),
+            Some("0=key0".to_owned())
+        );
+        assert_eq!(
+            get(&metadata, "/latest/meta-data/public-keys/0/"),
+            Some("openssh-key".to_owned())
+        );
+        assert_eq!(
+            get(&metadata, "/latest/meta-data/public-keys/0/openssh-key"),
+            Some("ssh-ed25519 AAAA user@host".to_owned())
+        );
+        assert_eq!(get(&metadata, "/latest/meta-data/public-keys/1/"), None);
+        assert_eq!(
+            get(&metadata, "/latest/user-data"),
+            Some("#cloud-config\n".to_owned())
+        );
+        assert_eq!(
+            get(&metadata, "/latest"),
+            Some("meta-data\nuser-data".to_owned())
+        );
+        assert_eq!(get(&metadata, "/latest/dynamic"), None);
+
+        let metadata = Metadata {
+            hostname: None,
+            ssh_keys: Vec::new(),
+            user_data: None,
+            ..metadata
+        };
+        assert_eq!(
+            get(&metadata, "/latest/meta-data"),
+            Some("instance-id".to_owned())
+        );
+        assert_eq!(get(&metadata, "/latest/meta-data/hostname"), None);
+        assert_eq!(get(&metadata, "/latest/user-data"), None);
+    }
+
+    #[test]
+    fn test_openstack_metadata() {
+        let metadata = metadata();
+        assert_eq!(get(&metadata, "/openstack/"), Some("latest".to_owned()));
+        assert_eq!(
+            get(&metadata, "/openstack/latest/user_data"),
+            Some("#cloud-config\n".to_owned())
+        );
+
+        let meta_data: serde_json::Value =
+            serde_json::from_slice(&metadata.get("/openstack/latest/meta_data.json").unwrap())
+                .unwrap();
+        assert_eq!(
+            meta_data,
+            serde_json::json!({
+                "uuid": "i-0123456789",
+                "hostname": "vm-1",
+                "name": "vm-1",
+                "public_keys": {"key0": "ssh-ed25519 AAAA user@host"},
+            })
+        );
+        assert_eq!(get(&metadata, "/openstack/latest/network_data.json"), None);
+    }
+}
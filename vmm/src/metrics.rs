@@ -0,0 +1,301 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Prometheus metrics exporter, serving the device and vCPU counters along
+//! with the progress of an outgoing migration, for the standard monitoring
+//! stacks to scrape the VMs directly.
+
+use crate::api::{ApiRequest, ApiResponsePayload};
+use crate::config::MetricsConfig;
+use crate::cpu::VCPU_NAME_PREFIX;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::simple_http::{read_request, write_response};
+use crate::{Error, Result};
+use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+use vm_migration::protocol::MemoryRangeTable;
+use vmm_sys_util::eventfd::EventFd;
+
+const METRICS_PREFIX: &str = "cloud_hypervisor";
+
+// Time given to the VMM thread to report the counters, for a scrape not to
+// hang while it is busy, such as with a migration started right after the
+// exporter checked there was none.
+const COUNTERS_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Progress of the outgoing migration, updated by the VMM thread while it
+// sends the VM, during which it can't report the counters.
+static MIGRATION_ACTIVE: AtomicBool = AtomicBool::new(false);
+static MIGRATION_MEMORY_PASSES: AtomicU64 = AtomicU64::new(0);
+static MIGRATION_MEMORY_BYTES: AtomicU64 = AtomicU64::new(0);
+static MIGRATION_LAST_PASS_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub fn migration_started() {
+    MIGRATION_MEMORY_PASSES.store(0, Ordering::Release);
+    MIGRATION_MEMORY_BYTES.store(0, Ordering::Release);
+    MIGRATION_LAST_PASS_BYTES.store(0, Ordering::Release);
+    MIGRATION_ACTIVE.store(true, Ordering::Release);
+}
+
+/// Account for the guest memory described by `table` being sent, either the
+/// whole memory or the pages dirtied since the previous pass.
+pub fn migration_memory_sent(table: &MemoryRangeTable) {
+    let bytes = table.regions().iter().map(|range| range.length).sum();
+    MIGRATION_MEMORY_PASSES.fetch_add(1, Ordering::AcqRel);
+    MIGRATION_MEMORY_BYTES.fetch_add(bytes, Ordering::AcqRel);
+    MIGRATION_LAST_PASS_BYTES.store(bytes, Ordering::Release);
+}
+
+pub fn migration_finished() {
+    MIGRATION_ACTIVE.store(false, Ordering::Release);
+}
+
+// Metric names can only contain [a-zA-Z0-9_:].
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Samples of the metrics, by metric name, along with their type.
+type Metrics = BTreeMap<String, (&'static str, Vec<(String, u64)>)>;
+
+fn add_sample(metrics: &mut Metrics, name: &str, kind: &'static str, labels: String, value: u64) {
+    metrics
+        .entry(format!("{}_{}", METRICS_PREFIX, name))
+        .or_insert_with(|| (kind, Vec::new()))
+        .1
+        .push((labels, value));
+}
+
+fn add_counters(metrics: &mut Metrics, counters: &BTreeMap<String, BTreeMap<String, u64>>) {
+    for (id, values) in counters {
+        let (name, labels) = match id.strip_prefix(VCPU_NAME_PREFIX) {
+            Some(cpu_id) => ("vcpu", format!("vcpu=\"{}\"", cpu_id)),
+            None => ("device", format!("device=\"{}\"", escape_label(id))),
+        };
+        for (counter, value) in values {
+            add_sample(
+                metrics,
+                &format!("{}_{}_total", name, metric_name(counter)),
+                "counter",
+                labels.clone(),
+                *value,
+            );
+        }
+    }
+}
+
+fn add_migration_progress(metrics: &mut Metrics) {
+    add_sample(
+        metrics,
+        "migration_active",
+        "gauge",
+        String::new(),
+        MIGRATION_ACTIVE.load(Ordering::Acquire) as u64,
+    );
+    add_sample(
+        metrics,
+        "migration_memory_passes",
+        "gauge",
+        String::new(),
+        MIGRATION_MEMORY_PASSES.load(Ordering::Acquire),
+    );
+    add_sample(
+        metrics,
+        "migration_memory_bytes",
+        "gauge",
+        String::new(),
+        MIGRATION_MEMORY_BYTES.load(Ordering::Acquire),
+    );
+    add_sample(
+        metrics,
+        "migration_last_pass_bytes",
+        "gauge",
+        String::new(),
+        MIGRATION_LAST_PASS_BYTES.load(Ordering::Acquire),
+    );
+}
+
+// Prometheus text exposition format.
+fn format_metrics(metrics: &Metrics) -> String {
+    let mut output = String::new();
+    for (name, (kind, samples)) in metrics {
+        writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+        for (labels, value) in samples {
+            if labels.is_empty() {
+                writeln!(output, "{} {}", name, value).unwrap();
+            } else {
+                writeln!(output, "{}{{{}}} {}", name, labels, value).unwrap();
+            }
+        }
+    }
+    output
+}
+
+// The counters of the VM, if the VMM thread reports them in time.
+fn vm_counters(
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> Option<BTreeMap<String, BTreeMap<String, u64>>> {
+    let (response_sender, response_receiver) = channel();
+    api_sender
+        .send(ApiRequest::VmCounters(response_sender))
+        .ok()?;
+    api_notifier.write(1).ok()?;
+
+    match response_receiver.recv_timeout(COUNTERS_TIMEOUT).ok()? {
+        Ok(ApiResponsePayload::VmAction(body)) => serde_json::from_slice(&body).ok(),
+        _ => None,
+    }
+}
+
+fn collect_metrics(api_notifier: &EventFd, api_sender: &Sender<ApiRequest>) -> String {
+    let mut metrics = Metrics::new();
+
+    // The VMM thread is busy sending the VM during a migration, hence only
+    // its progress is reported meanwhile. A migration can still start right
+    // after this check, which the timeout on the counters accounts for.
+    if !MIGRATION_ACTIVE.load(Ordering::Acquire) {
+        if let Some(counters) = vm_counters(api_notifier, api_sender) {
+            add_counters(&mut metrics, &counters);
+        }
+    }
+    add_migration_progress(&mut metrics);
+
+    format_metrics(&metrics)
+}
+
+fn handle_connection(
+    stream: &mut TcpStream,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> io::Result<()> {
+    let request = match read_request(stream)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    let (status, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => ("200 OK", collect_metrics(api_notifier, api_sender)),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write_response(stream, status, "text/plain; version=0.0.4", body.as_bytes())
+}
+
+/// Start serving the metrics on the TCP address of `config`, retrieving the
+/// counters through the internal API.
+pub fn start_metrics_thread(
+    config: &MetricsConfig,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+) -> Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(config.address).map_err(Error::MetricsBind)?;
+
+    // Retrieve seccomp filter for metrics thread
+    let metrics_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::Metrics).map_err(Error::CreateSeccompFilter)?;
+
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for metrics thread.
+            if let Err(e) = SeccompFilter::apply(metrics_seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            for stream in listener.incoming() {
+                let result = stream.and_then(|mut stream| {
+                    handle_connection(&mut stream, &api_notifier, &api_sender)
+                });
+                if let Err(e) = result {
+                    warn!("Error serving the metrics: {}", e);
+                }
+            }
+        })
+        .map_err(Error::MetricsThreadSpawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_migration::protocol::MemoryRange;
+
+    #[test]
+    fn test_format_metrics() {
+        let mut counters = BTreeMap::new();
+        let mut disk = BTreeMap::new();
+        disk.insert("read_bytes".to_owned(), 4096);
+        disk.insert("write_ops".to_owned(), 2);
+        counters.insert("_disk0".to_owned(), disk.clone());
+        counters.insert("my\"disk".to_owned(), disk);
+        let mut vcpu = BTreeMap::new();
+        vcpu.insert("cpu_time_ns".to_owned(), 1_000_000);
+        counters.insert("_vcpu1".to_owned(), vcpu);
+
+        let mut metrics = Metrics::new();
+        add_counters(&mut metrics, &counters);
+        assert_eq!(
+            format_metrics(&metrics),
+            "# TYPE cloud_hypervisor_device_read_bytes_total counter\n\
+             cloud_hypervisor_device_read_bytes_total{device=\"_disk0\"} 4096\n\
+             cloud_hypervisor_device_read_bytes_total{device=\"my\\\"disk\"} 4096\n\
+             # TYPE cloud_hypervisor_device_write_ops_total counter\n\
+             cloud_hypervisor_device_write_ops_total{device=\"_disk0\"} 2\n\
+             cloud_hypervisor_device_write_ops_total{device=\"my\\\"disk\"} 2\n\
+             # TYPE cloud_hypervisor_vcpu_cpu_time_ns_total counter\n\
+             cloud_hypervisor_vcpu_cpu_time_ns_total{vcpu=\"1\"} 1000000\n"
+        );
+    }
+
+    #[test]
+    fn test_migration_progress() {
+        let mut table = MemoryRangeTable::default();
+        table.push(MemoryRange {
+            gpa: 0,
+            length: 0x1000,
+        });
+        table.push(MemoryRange {
+            gpa: 0x10_0000,
+            length: 0x3000,
+        });
+
+        migration_started();
+        migration_memory_sent(&table);
+        migration_memory_sent(&table);
+        let mut metrics = Metrics::new();
+        add_migration_progress(&mut metrics);
+        migration_finished();
+
+        assert_eq!(
+            format_metrics(&metrics),
+            "# TYPE cloud_hypervisor_migration_active gauge\n\
+             cloud_hypervisor_migration_active 1\n\
+             # TYPE cloud_hypervisor_migration_last_pass_bytes gauge\n\
+             cloud_hypervisor_migration_last_pass_bytes 16384\n\
+             # TYPE cloud_hypervisor_migration_memory_bytes gauge\n\
+             cloud_hypervisor_migration_memory_bytes 32768\n\
+             # TYPE cloud_hypervisor_migration_memory_passes gauge\n\
+             cloud_hypervisor_migration_memory_passes 2\n"
+        );
+    }
+}
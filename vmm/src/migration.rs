@@ -2,13 +2,14 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::snapshot_encryption::{is_encrypted, SnapshotKey};
 use crate::vm::{VmSnapshot, VM_SNAPSHOT_ID};
 use anyhow::anyhow;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::path::PathBuf;
 use url::Url;
-use vm_migration::{MigratableError, Snapshot};
+use vm_migration::{MigratableError, Snapshot, SnapshotDataSection};
 
 pub const VM_SNAPSHOT_FILE: &str = "vm.json";
 
@@ -35,7 +36,10 @@ pub fn url_to_path(url: &Url) -> std::result::Result<PathBuf, MigratableError> {
     }
 }
 
-pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, MigratableError> {
+pub fn recv_vm_snapshot(
+    source_url: &str,
+    key: Option<&SnapshotKey>,
+) -> std::result::Result<Snapshot, MigratableError> {
     let url = Url::parse(source_url).map_err(|e| {
         MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
     })?;
@@ -48,8 +52,26 @@ pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, Migra
             // Try opening the snapshot file
             let vm_snapshot_file =
                 File::open(vm_snapshot_path).map_err(|e| MigratableError::MigrateSend(e.into()))?;
-            let vm_snapshot_reader = BufReader::new(vm_snapshot_file);
-            let vm_snapshot = serde_json::from_reader(vm_snapshot_reader)
+            let mut vm_snapshot_reader = BufReader::new(vm_snapshot_file);
+
+            // Decrypt and authenticate the snapshot file if a key is provided
+            let mut content = Vec::new();
+            if let Some(key) = key {
+                key.reader(VM_SNAPSHOT_FILE, vm_snapshot_reader)
+                    .and_then(|mut reader| reader.read_to_end(&mut content))
+                    .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+            } else {
+                vm_snapshot_reader
+                    .read_to_end(&mut content)
+                    .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+                if is_encrypted(&content) {
+                    return Err(MigratableError::MigrateReceive(anyhow!(
+                        "Snapshot is encrypted, a key is required"
+                    )));
+                }
+            }
+
+            let vm_snapshot = serde_json::from_slice(&content)
                 .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
 
             Ok(vm_snapshot)
@@ -61,6 +83,20 @@ pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, Migra
     }
 }
 
+pub fn set_vm_snapshot(
+    snapshot: &mut Snapshot,
+    vm_snapshot: &VmSnapshot,
+) -> std::result::Result<(), MigratableError> {
+    let vm_snapshot_data =
+        serde_json::to_vec(vm_snapshot).map_err(|e| MigratableError::Restore(e.into()))?;
+    snapshot.add_data_section(SnapshotDataSection {
+        id: format!("{}-section", VM_SNAPSHOT_ID),
+        snapshot: vm_snapshot_data,
+    });
+
+    Ok(())
+}
+
 pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, MigratableError> {
     if let Some(vm_section) = snapshot
         .snapshot_data
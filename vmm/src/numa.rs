@@ -0,0 +1,221 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::{CpuAffinity, VmConfig};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SYSFS_NODE_PATH: &str = "/sys/devices/system/node";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed reading the host NUMA topology.
+    ReadHostTopology(io::Error),
+
+    /// Invalid CPU or node list found in the host NUMA topology.
+    ParseHostTopology(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostNumaNode {
+    pub id: u32,
+    pub cpus: Vec<usize>,
+}
+
+// Parse a list such as "0-3,8,10-11" as found in sysfs.
+pub(crate) fn parse_list(list: &str) -> Option<Vec<usize>> {
+    let mut values = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start = bounds.next()?.parse::<usize>().ok()?;
+        let end = match bounds.next() {
+            Some(end) => end.parse::<usize>().ok()?,
+            None => start,
+        };
+        if end < start {
+            return None;
+        }
+        values.extend(start..=end);
+    }
+    Some(values)
+}
+
+fn read_list(path: &Path) -> Result<Vec<usize>> {
+    let list = fs::read_to_string(path).map_err(Error::ReadHostTopology)?;
+    parse_list(&list).ok_or(Error::ParseHostTopology(list))
+}
+
+/// Returns the host NUMA nodes having at least one CPU, sorted by identifier.
+pub fn host_numa_nodes() -> Result<Vec<HostNumaNode>> {
+    let sysfs_path = Path::new(SYSFS_NODE_PATH);
+    let mut nodes = Vec::new();
+
+    for id in read_list(&sysfs_path.join("online"))? {
+        let cpus = read_list(&sysfs_path.join(format!("node{}", id)).join("cpulist"))?;
+        if !cpus.is_empty() {
+            nodes.push(HostNumaNode {
+                id: id as u32,
+                cpus,
+            });
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// Place the guest onto the host NUMA nodes.
+///
+/// Each guest NUMA node is assigned a host NUMA node, in a round-robin
+/// fashion. The vCPUs of a guest NUMA node are pinned to the CPUs of the
+/// matching host node, and its memory zones are bound to it. Without any
+/// guest NUMA node, the whole guest is placed onto the host node with the
+/// largest number of CPUs.
+///
+/// Affinities and memory zone bindings explicitly set through the
+/// configuration are left untouched.
+pub fn auto_placement(config: &mut VmConfig, host_nodes: &[HostNumaNode]) {
+    if host_nodes.is_empty() {
+        return;
+    }
+
+    // List the guest vCPUs and memory zones to place on each host node.
+    let mut placement: Vec<(&HostNumaNode, Vec<u16>, Vec<String>)> = Vec::new();
+    if let Some(numa) = &config.numa {
+        let mut guest_nodes: Vec<_> = numa.iter().collect();
+        guest_nodes.sort_by_key(|n| n.guest_numa_id);
+        for (i, guest_node) in guest_nodes.iter().enumerate() {
+            placement.push((
+                &host_nodes[i % host_nodes.len()],
+                guest_node.cpus.clone().unwrap_or_default(),
+                guest_node.memory_zones.clone().unwrap_or_default(),
+            ));
+        }
+    } else {
+        // Safe to unwrap as the list of host nodes is not empty.
+        let host_node = host_nodes.iter().max_by_key(|n| n.cpus.len()).unwrap();
+        let zones = config
+            .memory
+            .zones
+            .as_ref()
+            .map(|zones| zones.iter().map(|z| z.id.clone()).collect())
+            .unwrap_or_default();
+        placement.push((host_node, (0..config.cpus.max_vcpus).collect(), zones));
+    }
+
+    if config.cpus.affinity.is_none() {
+        let mut affinity = Vec::new();
+        for (host_node, vcpus, _) in placement.iter() {
+            for vcpu in vcpus.iter() {
+                affinity.push(CpuAffinity {
+                    vcpu: *vcpu,
+                    host_cpus: host_node.cpus.clone(),
+                });
+            }
+        }
+        config.cpus.affinity = Some(affinity);
+    }
+
+    if let Some(zones) = config.memory.zones.as_mut() {
+        for (host_node, _, zone_ids) in placement.iter() {
+            for zone in zones.iter_mut() {
+                // Binding a shared file backed zone is not supported.
+                if zone_ids.contains(&zone.id)
+                    && zone.host_numa_node.is_none()
+                    && !(zone.shared && zone.file.is_some())
+                {
+                    zone.host_numa_node = Some(host_node.id);
+                }
+            }
+        }
+    }
+
+    info!(
+        "NUMA auto placement: vCPU affinity {:?}",
+        config.cpus.affinity
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MemoryZoneConfig, NumaConfig};
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(parse_list("0\n"), Some(vec![0]));
+        assert_eq!(parse_list("0-3,8,10-11"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_list(""), Some(vec![]));
+        assert_eq!(parse_list("3-1"), None);
+        assert_eq!(parse_list("a-b"), None);
+    }
+
+    #[test]
+    fn test_auto_placement() {
+        let host_nodes = vec![
+            HostNumaNode {
+                id: 0,
+                cpus: vec![0, 1],
+            },
+            HostNumaNode {
+                id: 1,
+                cpus: vec![2, 3],
+            },
+        ];
+
+        let zone = |id: &str| MemoryZoneConfig {
+            id: id.to_owned(),
+            size: 1 << 30,
+            file: None,
+            shared: false,
+            hugepages: false,
+            host_numa_node: None,
+            hotplug_size: None,
+            hotplugged_size: None,
+        };
+
+        let mut config: VmConfig =
+            serde_json::from_str(r#"{ "kernel": {"path": "/path/to/kernel"} }"#).unwrap();
+        config.cpus.boot_vcpus = 2;
+        config.cpus.max_vcpus = 2;
+        config.memory.size = 0;
+        config.memory.zones = Some(vec![zone("mem0"), zone("mem1")]);
+        config.numa = Some(vec![
+            NumaConfig {
+                guest_numa_id: 1,
+                cpus: Some(vec![1]),
+                distances: None,
+                memory_zones: Some(vec!["mem1".to_owned()]),
+            },
+            NumaConfig {
+                guest_numa_id: 0,
+                cpus: Some(vec![0]),
+                distances: None,
+                memory_zones: Some(vec!["mem0".to_owned()]),
+            },
+        ]);
+
+        auto_placement(&mut config, &host_nodes);
+
+        assert_eq!(
+            config.cpus.affinity,
+            Some(vec![
+                CpuAffinity {
+                    vcpu: 0,
+                    host_cpus: vec![0, 1]
+                },
+                CpuAffinity {
+                    vcpu: 1,
+                    host_cpus: vec![2, 3]
+                },
+            ])
+        );
+        let zones = config.memory.zones.unwrap();
+        assert_eq!(zones[0].host_numa_node, Some(0));
+        assert_eq!(zones[1].host_numa_node, Some(1));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Export of the VM lifecycle operations, such as the boot, the device
+//! hotplug, the snapshots and the migrations, as OpenTelemetry spans sent to
+//! an OTLP/HTTP collector, for the traces of a multi-host orchestration to
+//! include the timing of the operations within the VMM.
+
+use crate::config::{OtlpConfig, TraceParent};
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{Error, Result};
+use seccomp::{SeccompAction, SeccompFilter};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SERVICE_NAME: &str = "cloud-hypervisor";
+const TRACES_PATH: &str = "/v1/traces";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// From the OTLP trace protocol.
+const SPAN_KIND_INTERNAL: u32 = 1;
+const STATUS_CODE_OK: u32 = 1;
+const STATUS_CODE_ERROR: u32 = 2;
+
+struct SpanData {
+    name: &'static str,
+    start: u64,
+    end: u64,
+    error: Option<String>,
+}
+
+lazy_static! {
+    // The spans are only recorded once the exporter is started.
+    static ref SPAN_SENDER: Mutex<Option<Sender<SpanData>>> = Mutex::new(None);
+}
+
+fn unix_time_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+pub struct Span {
+    name: &'static str,
+    start: u64,
+}
+
+impl Span {
+    pub fn start(name: &'static str) -> Self {
+        Span {
+            name,
+            start: unix_time_ns(),
+        }
+    }
+
+    /// End the span, which failed with `error` if any, and hand it over to
+    /// the exporter.
+    pub fn end(self, error: Option<String>) {
+        if let Some(sender) = SPAN_SENDER.lock().unwrap().as_ref() {
+            let _ = sender.send(SpanData {
+                name: self.name,
+                start: self.start,
+                end: unix_time_ns(),
+                error,
+            });
+        }
+    }
+}
+
+struct Exporter {
+    endpoint: SocketAddr,
+    trace_parent: Option<TraceParent>,
+    urandom: File,
+}
+
+impl Exporter {
+    fn random_id(&mut self, size: usize) -> io::Result<String> {
+        let mut id = vec![0u8; size];
+        self.urandom.read_exact(&mut id)?;
+        Ok(id.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    fn span_json(&mut self, span: &SpanData) -> io::Result<Value> {
+        // Without a parent, each operation is a trace of its own.
+        let (trace_id, parent_span_id) = match &self.trace_parent {
+            Some(parent) => (parent.trace_id.clone(), parent.span_id.clone()),
+            None => (self.random_id(16)?, String::new()),
+        };
+        let status = match &span.error {
+            Some(error) => json!({ "code": STATUS_CODE_ERROR, "message": error }),
+            None => json!({ "code": STATUS_CODE_OK }),
+        };
+
+        Ok(json!({
+            "traceId": trace_id,
+            "spanId": self.random_id(8)?,
+            "parentSpanId": parent_span_id,
+            "name": span.name,
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": span.start.to_string(),
+            "endTimeUnixNano": span.end.to_string(),
+            "status": status,
+        }))
+    }
+
+    fn request_body(&mut self, spans: &[SpanData]) -> io::Result<String> {
+        let spans = spans
+            .iter()
+            .map(|span| self.span_json(span))
+            .collect::<io::Result<Vec<Value>>>()?;
+
+        Ok(json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": SERVICE_NAME },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": SERVICE_NAME },
+                    "spans": spans,
+                }],
+            }],
+        })
+        .to_string())
+    }
+
+    fn export(&mut self, spans: &[SpanData]) -> io::Result<()> {
+        let body = self.request_body(spans)?;
+
+        let mut stream = TcpStream::connect_timeout(&self.endpoint, EXPORT_TIMEOUT)?;
+        stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+        stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            TRACES_PATH,
+            self.endpoint,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unexpected collector response: {}", status_line.trim()),
+            )),
+        }
+    }
+}
+
+/// Start exporting the spans to the OTLP/HTTP collector of `config`, the
+/// spans ended meanwhile being sent together.
+pub fn start_otlp_thread(
+    config: &OtlpConfig,
+    seccomp_action: &SeccompAction,
+) -> Result<thread::JoinHandle<()>> {
+    let mut exporter = Exporter {
+        endpoint: config.endpoint,
+        trace_parent: config.trace_parent.clone(),
+        urandom: File::open("/dev/urandom").map_err(Error::OtlpExporter)?,
+    };
+
+    // Retrieve seccomp filter for OTLP thread
+    let otlp_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::Otlp).map_err(Error::CreateSeccompFilter)?;
+
+    let (span_sender, span_receiver) = channel();
+    *SPAN_SENDER.lock().unwrap() = Some(span_sender);
+
+    thread::Builder::new()
+        .name("otlp".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for OTLP thread.
+            if let Err(e) = SeccompFilter::apply(otlp_seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            while let Ok(span) = span_receiver.recv() {
+                let mut spans = vec![span];
+                spans.extend(span_receiver.try_iter());
+                if let Err(e) = exporter.export(&spans) {
+                    warn!("Error exporting {} OTLP spans: {}", spans.len(), e);
+                }
+            }
+        })
+        .map_err(Error::OtlpThreadSpawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_body() {
+        let mut exporter = Exporter {
+            endpoint: "127.0.0.1:4318".parse().unwrap(),
+            trace_parent: Some(TraceParent {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_owned(),
+                span_id: "00f067aa0ba902b7".to_owned(),
+            }),
+            urandom: File::open("/dev/urandom").unwrap(),
+        };
+        let span_data = vec![
+            SpanData {
+                name: "vm.boot",
+                start: 1_000,
+                end: 2_000,
+                error: None,
+            },
+            SpanData {
+                name: "vm.add-disk",
+                start: 3_000,
+                end: 4_000,
+                error: Some("VmNotRunning".to_owned()),
+            },
+        ];
+
+        let body: Value =
+            serde_json::from_str(&exporter.request_body(&span_data).unwrap()).unwrap();
+        let resource_spans = &body["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"][0]["value"]["stringValue"],
+            SERVICE_NAME
+        );
+        let spans = resource_spans["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["name"], "vm.boot");
+        assert_eq!(spans[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[0]["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(spans[0]["spanId"].as_str().unwrap().len(), 16);
+        assert_ne!(spans[0]["spanId"], spans[1]["spanId"]);
+        assert_eq!(spans[0]["startTimeUnixNano"], "1000");
+        assert_eq!(spans[0]["status"]["code"], STATUS_CODE_OK);
+        assert_eq!(spans[1]["status"]["code"], STATUS_CODE_ERROR);
+        assert_eq!(spans[1]["status"]["message"], "VmNotRunning");
+
+        // Without a parent, each span has its own trace.
+        exporter.trace_parent = None;
+        let body: Value =
+            serde_json::from_str(&exporter.request_body(&span_data).unwrap()).unwrap();
+        let spans = body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans[0]["traceId"].as_str().unwrap().len(), 32);
+        assert_ne!(spans[0]["traceId"], spans[1]["traceId"]);
+        assert_eq!(spans[0]["parentSpanId"], "");
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::api::VmmResourceUsage;
+use std::fs;
+use std::io;
+
+// Returns the value, converted to bytes, of a "<key>: <value> kB" entry as
+// found in /proc/self/status or /proc/self/smaps.
+fn parse_kb_entry(line: &str, key: &str) -> Option<u64> {
+    let value = line.strip_prefix(key)?.strip_prefix(':')?;
+    let kb = value
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb << 10)
+}
+
+fn parse_status(status: &str, usage: &mut VmmResourceUsage) {
+    for line in status.lines() {
+        if let Some(v) = parse_kb_entry(line, "VmRSS") {
+            usage.rss = v;
+        } else if let Some(v) = parse_kb_entry(line, "RssAnon") {
+            usage.rss_anon = v;
+        } else if let Some(v) = parse_kb_entry(line, "RssFile") {
+            usage.rss_file = v;
+        } else if let Some(v) = parse_kb_entry(line, "RssShmem") {
+            usage.rss_shmem = v;
+        } else if let Some(v) = line.strip_prefix("Threads:") {
+            usage.threads = v.trim().parse().unwrap_or_default();
+        }
+    }
+}
+
+// Sum the resident memory of the mappings, described in smaps format, which
+// start within one of the provided host address ranges.
+fn parse_smaps_rss(smaps: &str, ranges: &[(u64, u64)]) -> u64 {
+    let mut rss = 0;
+    let mut in_range = false;
+    for line in smaps.lines() {
+        // Mapping headers start with the "<start>-<end>" address range,
+        // while the other lines start with a "<key>:" entry.
+        let first = line.split_whitespace().next().unwrap_or_default();
+        if !first.ends_with(':') {
+            in_range = first
+                .split('-')
+                .next()
+                .and_then(|s| u64::from_str_radix(s, 16).ok())
+                .map(|start| {
+                    ranges
+                        .iter()
+                        .any(|(base, len)| start >= *base && start < base + len)
+                })
+                .unwrap_or(false);
+        } else if in_range {
+            if let Some(v) = parse_kb_entry(line, "Rss") {
+                rss += v;
+            }
+        }
+    }
+    rss
+}
+
+/// Collect the resource usage of the VMM process. The guest memory host
+/// address ranges are used to split the resident memory between the guest
+/// RAM and the VMM itself.
+pub fn vmm_resource_usage(guest_memory_ranges: &[(u64, u64)]) -> io::Result<VmmResourceUsage> {
+    let mut usage = VmmResourceUsage::default();
+
+    parse_status(&fs::read_to_string("/proc/self/status")?, &mut usage);
+    // Don't count the descriptor of the directory being listed.
+    usage.open_fds = (fs::read_dir("/proc/self/fd")?.count() as u64).saturating_sub(1);
+
+    if !guest_memory_ranges.is_empty() {
+        usage.guest_memory_size = guest_memory_ranges.iter().map(|(_, len)| len).sum();
+        usage.guest_memory_rss = parse_smaps_rss(
+            &fs::read_to_string("/proc/self/smaps")?,
+            guest_memory_ranges,
+        );
+    }
+    usage.vmm_rss = usage.rss.saturating_sub(usage.guest_memory_rss);
+
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        let status = "Name:\tcloud-hypervisor\n\
+                      VmRSS:\t  204800 kB\n\
+                      RssAnon:\t  200704 kB\n\
+                      RssFile:\t    4096 kB\n\
+                      RssShmem:\t       0 kB\n\
+                      Threads:\t7\n";
+        let mut usage = VmmResourceUsage::default();
+        parse_status(status, &mut usage);
+        assert_eq!(usage.rss, 200 << 20);
+        assert_eq!(usage.rss_anon, 196 << 20);
+        assert_eq!(usage.rss_file, 4 << 20);
+        assert_eq!(usage.rss_shmem, 0);
+        assert_eq!(usage.threads, 7);
+    }
+
+    #[test]
+    fn test_parse_smaps_rss() {
+        let smaps = "7f0000000000-7f0040000000 rw-p 00000000 00:00 0\n\
+                     Size:            1048576 kB\n\
+                     Rss:               65536 kB\n\
+                     7f0040000000-7f0040001000 rw-p 00000000 00:00 0\n\
+                     Size:                  4 kB\n\
+                     Rss:                   4 kB\n\
+                     VmFlags: rd wr mr mw me ac\n";
+        assert_eq!(
+            parse_smaps_rss(smaps, &[(0x7f00_0000_0000, 1 << 30)]),
+            64 << 20
+        );
+        assert_eq!(parse_smaps_rss(smaps, &[]), 0);
+    }
+}
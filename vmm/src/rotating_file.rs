@@ -0,0 +1,109 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Output file of the serial port or the virtio-console, rotated once it
+//! reaches a maximum size so that a chatty guest can't fill the host disk.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    rotate: u32,
+}
+
+impl RotatingFile {
+    /// Create the file `path`, which is renamed to `path.1` once `max_size`
+    /// bytes have been written, the previous `path.1` becoming `path.2` and
+    /// so on, up to `rotate` files. With `rotate` being 0, the file is just
+    /// truncated.
+    pub fn new(path: &Path, max_size: u64, rotate: u32) -> io::Result<Self> {
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file: File::create(path)?,
+            size: 0,
+            max_size,
+            rotate,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.rotate > 0 {
+            for i in (1..self.rotate).rev() {
+                match fs::rename(self.rotated_path(i), self.rotated_path(i + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let count = self.file.write(buf)?;
+        self.size += count as u64;
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("ch-rotating-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("serial.log");
+
+        let mut file = RotatingFile::new(&path, 8, 2).unwrap();
+        file.write_all(b"aaaaaa").unwrap();
+        file.write_all(b"bbbbbb").unwrap();
+        file.write_all(b"cccccc").unwrap();
+        file.write_all(b"dddddd").unwrap();
+        file.flush().unwrap();
+
+        // Only the most recent output is kept, across the rotated files.
+        assert_eq!(fs::read(&path).unwrap(), b"dddddd");
+        assert_eq!(fs::read(dir.join("serial.log.1")).unwrap(), b"cccccc");
+        assert_eq!(fs::read(dir.join("serial.log.2")).unwrap(), b"bbbbbb");
+        assert!(!dir.join("serial.log.3").exists());
+
+        // Output bigger than the maximum size is written anyway.
+        let mut file = RotatingFile::new(&path, 4, 0).unwrap();
+        file.write_all(b"eeeeee").unwrap();
+        file.write_all(b"ff").unwrap();
+        file.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"ff");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -13,8 +13,15 @@ use std::convert::TryInto;
 
 pub enum Thread {
     Api,
+    BalloonPolicy,
+    Metadata,
+    Metrics,
+    #[cfg(feature = "otlp")]
+    Otlp,
     SignalHandler,
     Vcpu,
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    VfioIntx,
     Vmm,
 }
 
@@ -49,6 +56,8 @@ const TCSETS: u64 = 0x5402;
 const TIOCGWINSZ: u64 = 0x5413;
 const FIOCLEX: u64 = 0x5451;
 const FIONBIO: u64 = 0x5421;
+const TIOCGPTN: u64 = 0x8004_5430;
+const TIOCSPTLCK: u64 = 0x4004_5431;
 
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNGETIFF: u64 = 0x8004_54d2;
@@ -154,7 +163,9 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCSIFNETMASK)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TCSETS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TCGETS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGPTN)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGWINSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TIOCSPTLCK)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETFEATURES)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
@@ -205,6 +216,7 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     const KVM_SET_TSS_ADDR: u64 = 0xae47;
     const KVM_SET_XCRS: u64 = 0x4188_aea7;
     const KVM_SET_XSAVE: u64 = 0x5000_aea5;
+    const KVM_X86_SETUP_MCE: u64 = 0x4008_ae9c;
 
     let common_rules = create_vmm_ioctl_seccomp_rule_common()?;
     let mut arch_rules = or![
@@ -228,6 +240,7 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_MSRS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XCRS,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XSAVE,)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_X86_SETUP_MCE)?],
     ];
     arch_rules.extend(common_rules);
 
@@ -249,7 +262,8 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     Ok(arch_rules)
 }
 
-fn create_api_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+// Switching a socket to non-blocking mode.
+fn create_fionbio_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     Ok(or![and![Cond::new(1, ArgLen::DWORD, Eq, FIONBIO)?],])
 }
 
@@ -318,6 +332,7 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         #[cfg(target_arch = "aarch64")]
         allow_syscall(libc::SYS_newfstatat),
         allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getdents64),
         allow_syscall(libc::SYS_getpid),
         allow_syscall(libc::SYS_getrandom),
         allow_syscall(libc::SYS_gettid),
@@ -333,6 +348,10 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_madvise),
         allow_syscall(libc::SYS_mbind),
         allow_syscall(libc::SYS_memfd_create),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_mkdir),
+        #[cfg(target_arch = "aarch64")]
+        allow_syscall(libc::SYS_mkdirat),
         allow_syscall(libc::SYS_mmap),
         allow_syscall(libc::SYS_mprotect),
         allow_syscall(libc::SYS_mremap),
@@ -360,12 +379,15 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_sendto),
         allow_syscall(libc::SYS_set_robust_list),
         allow_syscall(libc::SYS_set_tid_address),
+        allow_syscall(libc::SYS_setsockopt),
+        allow_syscall(libc::SYS_shutdown),
         allow_syscall(libc::SYS_sigaltstack),
         allow_syscall_if(
             libc::SYS_socket,
             or![
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+                and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
             ],
         ),
         allow_syscall(libc::SYS_socketpair),
@@ -390,7 +412,11 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
 }
 
 fn create_vcpu_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
-    Ok(or![
+    #[cfg(target_arch = "x86_64")]
+    const KVM_X86_SET_MCE: u64 = 0x4040_ae9e;
+
+    #[allow(unused_mut)]
+    let mut rules = or![
         and![Cond::new(1, ArgLen::DWORD, Eq, FIONBIO,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_CHECK_EXTENSION,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_IOEVENTFD)?],
@@ -402,7 +428,12 @@ fn create_vcpu_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_SET_IRQS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_GROUP_UNSET_CONTAINER)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_UNMAP_DMA)?],
-    ])
+    ];
+    // Machine checks are injected from the vCPU threads.
+    #[cfg(target_arch = "x86_64")]
+    rules.extend(or![and![Cond::new(1, ArgLen::DWORD, Eq, KVM_X86_SET_MCE)?]]);
+
+    Ok(rules)
 }
 
 fn vcpu_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
@@ -455,6 +486,9 @@ fn vcpu_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_read),
         allow_syscall(libc::SYS_recvfrom),
         allow_syscall(libc::SYS_recvmsg),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_rename),
+        allow_syscall(libc::SYS_renameat),
         allow_syscall(libc::SYS_rt_sigaction),
         allow_syscall(libc::SYS_rt_sigprocmask),
         allow_syscall(libc::SYS_rt_sigreturn),
@@ -493,13 +527,16 @@ fn api_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         #[cfg(target_arch = "x86_64")]
         allow_syscall(libc::SYS_epoll_wait),
         allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_fdatasync),
         allow_syscall(libc::SYS_futex),
         allow_syscall(libc::SYS_getrandom),
-        allow_syscall_if(libc::SYS_ioctl, create_api_ioctl_seccomp_rule()?),
+        allow_syscall(libc::SYS_getsockopt),
+        allow_syscall_if(libc::SYS_ioctl, create_fionbio_ioctl_seccomp_rule()?),
         allow_syscall(libc::SYS_listen),
         allow_syscall(libc::SYS_madvise),
         allow_syscall(libc::SYS_mprotect),
         allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
         allow_syscall(libc::SYS_recvfrom),
         allow_syscall(libc::SYS_sigaltstack),
         allow_syscall(libc::SYS_socket),
@@ -507,11 +544,137 @@ fn api_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     ])
 }
 
+// The filter containing the white listed syscall rules required by the
+// balloon policy thread, reading the host memory pressure and resizing the
+// balloon.
+fn balloon_policy_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_fstat),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_openat),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sched_yield),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_statx),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+// The filter containing the white listed syscall rules required by the
+// thread triggering the legacy interrupts of the passed through devices.
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+fn vfio_intx_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_epoll_pwait),
+        allow_syscall(libc::SYS_epoll_wait),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+// The filter containing the white listed syscall rules required by the
+// metadata service thread, serving the metadata on the already bound socket.
+fn metadata_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_accept4),
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_mremap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_sendto),
+        allow_syscall(libc::SYS_setsockopt),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+// The filter containing the white listed syscall rules required by the
+// metrics exporter thread, serving the metrics on the already bound socket.
+fn metrics_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_accept4),
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getrandom),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_mremap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_sendto),
+        allow_syscall(libc::SYS_setsockopt),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+// The filter containing the white listed syscall rules required by the OTLP
+// exporter thread, sending the spans to the collector.
+#[cfg(feature = "otlp")]
+fn otlp_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_connect),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getsockopt),
+        allow_syscall_if(libc::SYS_ioctl, create_fionbio_ioctl_seccomp_rule()?),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_mremap),
+        allow_syscall(libc::SYS_munmap),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_poll),
+        allow_syscall(libc::SYS_ppoll),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_sendto),
+        allow_syscall(libc::SYS_setsockopt),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_socket),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
 fn get_seccomp_filter_trap(thread_type: Thread) -> Result<SeccompFilter, Error> {
     let rules = match thread_type {
         Thread::Api => api_thread_rules()?,
+        Thread::BalloonPolicy => balloon_policy_thread_rules()?,
+        Thread::Metadata => metadata_thread_rules()?,
+        Thread::Metrics => metrics_thread_rules()?,
+        #[cfg(feature = "otlp")]
+        Thread::Otlp => otlp_thread_rules()?,
         Thread::SignalHandler => signal_handler_thread_rules()?,
         Thread::Vcpu => vcpu_thread_rules()?,
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        Thread::VfioIntx => vfio_intx_thread_rules()?,
         Thread::Vmm => vmm_thread_rules()?,
     };
 
@@ -524,8 +687,15 @@ fn get_seccomp_filter_trap(thread_type: Thread) -> Result<SeccompFilter, Error>
 fn get_seccomp_filter_log(thread_type: Thread) -> Result<SeccompFilter, Error> {
     let rules = match thread_type {
         Thread::Api => api_thread_rules()?,
+        Thread::BalloonPolicy => balloon_policy_thread_rules()?,
+        Thread::Metadata => metadata_thread_rules()?,
+        Thread::Metrics => metrics_thread_rules()?,
+        #[cfg(feature = "otlp")]
+        Thread::Otlp => otlp_thread_rules()?,
         Thread::SignalHandler => signal_handler_thread_rules()?,
         Thread::Vcpu => vcpu_thread_rules()?,
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        Thread::VfioIntx => vfio_intx_thread_rules()?,
         Thread::Vmm => vmm_thread_rules()?,
     };
 
@@ -0,0 +1,67 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Bare HTTP/1.1 handling for the services only answering simple GET
+//! requests, one connection at a time, like the metrics exporter and the
+//! metadata service.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// Time given to a client to send its request, and to read the response, for
+// a stuck one not to block the service.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Method and path of a request, left empty when missing.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+/// Read the request from the client. Only the request line matters, the
+/// headers and body are ignored. Returns None when the client closes the
+/// connection, or sends a request too large.
+pub fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut request: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let count = stream.read(&mut buf)?;
+        if count == 0 || request.len() + count > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..count]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut words = request_line.split_whitespace();
+    Ok(Some(Request {
+        method: words.next().unwrap_or_default().to_owned(),
+        path: words.next().unwrap_or_default().to_owned(),
+    }))
+}
+
+/// Send the response, the connection being closed afterwards.
+pub fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
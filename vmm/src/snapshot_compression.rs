@@ -0,0 +1,188 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Compression of the memory snapshot files, and detection of the zero pages.
+//!
+//! The pages full of zeros are not written to the uncompressed memory region
+//! files, which are left sparse, and they are not written to the guest memory
+//! on restore, which avoids allocating memory on the host for pages the guest
+//! never used.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+// Granularity of the zero pages detection.
+const PAGE_SIZE: usize = 4096;
+// Amount of memory copied at once.
+const CHUNK_SIZE: usize = 1 << 20;
+// Default zstd compression level, favouring speed over ratio.
+const COMPRESSION_LEVEL: i32 = 1;
+
+fn is_zero_page(page: &[u8]) -> bool {
+    page.iter().all(|b| *b == 0)
+}
+
+// Calls `f` with the offset and the content of each run of consecutive non
+// zero pages from the buffer.
+fn for_each_data_run<F>(buf: &[u8], mut f: F) -> io::Result<()>
+where
+    F: FnMut(usize, &[u8]) -> io::Result<()>,
+{
+    let mut start = None;
+    for (index, page) in buf.chunks(PAGE_SIZE).enumerate() {
+        let offset = index * PAGE_SIZE;
+        match (is_zero_page(page), start) {
+            (false, None) => start = Some(offset),
+            (true, Some(run_start)) => {
+                f(run_start, &buf[run_start..offset])?;
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(run_start) = start {
+        f(run_start, &buf[run_start..])?;
+    }
+
+    Ok(())
+}
+
+/// Copy `size` bytes of guest memory from `addr` into the file at
+/// `file_offset`, leaving holes in place of the zero pages.
+pub fn write_sparse(
+    guest_memory: &GuestMemoryMmap,
+    addr: GuestAddress,
+    size: usize,
+    file: &File,
+    file_offset: u64,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = std::cmp::min(CHUNK_SIZE, size - offset);
+        guest_memory
+            .read_slice(&mut buf[..len], addr.unchecked_add(offset as u64))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+        for_each_data_run(&buf[..len], |run_offset, run| {
+            file.write_all_at(run, file_offset + (offset + run_offset) as u64)
+        })?;
+        offset += len;
+    }
+
+    // Trailing zero pages only extend the file.
+    file.set_len(file_offset + size as u64)
+}
+
+/// Copy `size` bytes from the reader into the guest memory at `addr`, which
+/// must be zeroed already, skipping the zero pages.
+pub fn read_sparse<R: Read>(
+    guest_memory: &GuestMemoryMmap,
+    addr: GuestAddress,
+    size: usize,
+    mut reader: R,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = std::cmp::min(CHUNK_SIZE, size - offset);
+        reader.read_exact(&mut buf[..len])?;
+        for_each_data_run(&buf[..len], |run_offset, run| {
+            guest_memory
+                .write_slice(run, addr.unchecked_add((offset + run_offset) as u64))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+        })?;
+        offset += len;
+    }
+
+    Ok(())
+}
+
+pub fn compressor<W: Write>(inner: W) -> io::Result<zstd::Encoder<W>> {
+    zstd::Encoder::new(inner, COMPRESSION_LEVEL)
+}
+
+pub fn decompressor<R: Read>(inner: R) -> io::Result<zstd::Decoder<io::BufReader<R>>> {
+    zstd::Decoder::new(inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEM_SIZE: usize = 4 << 20;
+
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_sparse_copy() {
+        let source = guest_memory();
+        // Data spanning two chunks, and a lone page at the end of the memory.
+        source
+            .write_slice(
+                &[0x42; 2 * PAGE_SIZE],
+                GuestAddress((CHUNK_SIZE - PAGE_SIZE) as u64),
+            )
+            .unwrap();
+        source
+            .write_slice(&[0x43; 16], GuestAddress((MEM_SIZE - 16) as u64))
+            .unwrap();
+
+        let file = tempfile::tempfile().unwrap();
+        write_sparse(&source, GuestAddress(0), MEM_SIZE, &file, 0).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), MEM_SIZE as u64);
+
+        // Mark the destination memory to check the zero pages are skipped.
+        let destination = guest_memory();
+        destination
+            .write_slice(&[0xff; 16], GuestAddress(0))
+            .unwrap();
+        read_sparse(&destination, GuestAddress(0), MEM_SIZE, &file).unwrap();
+
+        let mut content = vec![0u8; MEM_SIZE];
+        destination
+            .read_slice(&mut content, GuestAddress(0))
+            .unwrap();
+        let mut expected = vec![0u8; MEM_SIZE];
+        source.read_slice(&mut expected, GuestAddress(0)).unwrap();
+        assert_eq!(&content[..16], &[0xff; 16]);
+        assert!(content[16..] == expected[16..]);
+    }
+
+    #[test]
+    fn test_snapshot_compression() {
+        let source = guest_memory();
+        source
+            .write_slice(&[0x42; PAGE_SIZE], GuestAddress(PAGE_SIZE as u64))
+            .unwrap();
+
+        let mut encoder = compressor(Vec::new()).unwrap();
+        source
+            .write_all_to(GuestAddress(0), &mut encoder, MEM_SIZE)
+            .unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() < MEM_SIZE / 100);
+
+        let destination = guest_memory();
+        read_sparse(
+            &destination,
+            GuestAddress(0),
+            MEM_SIZE,
+            decompressor(&compressed[..]).unwrap(),
+        )
+        .unwrap();
+
+        let mut content = vec![0u8; MEM_SIZE];
+        let mut expected = vec![0u8; MEM_SIZE];
+        destination
+            .read_slice(&mut content, GuestAddress(0))
+            .unwrap();
+        source.read_slice(&mut expected, GuestAddress(0)).unwrap();
+        assert!(content == expected);
+    }
+}
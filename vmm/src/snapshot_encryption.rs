@@ -0,0 +1,300 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Encryption of the snapshot files, authenticated on restore.
+//!
+//! An encrypted file starts with `ENCRYPTED_FILE_MAGIC`, followed by the
+//! content split into chunks of up to `CHUNK_SIZE` bytes. Each chunk is stored
+//! as the length of its ciphertext (u32, little endian), its random nonce and
+//! the AES-256-GCM ciphertext, including the authentication tag. The name of
+//! the file, the index of the chunk and whether this is the last chunk are
+//! authenticated along with each chunk, which prevents the files or chunks
+//! from being swapped, reordered or truncated.
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use block_util::luks::{clear_secret_value, Secret};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const ENCRYPTED_FILE_MAGIC: &[u8; 8] = b"CHSNENC1";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 1 << 20;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Key used to encrypt and authenticate the snapshot files.
+pub struct SnapshotKey {
+    cipher: Aes256Gcm,
+}
+
+impl SnapshotKey {
+    pub fn new(key: &[u8]) -> io::Result<Self> {
+        if key.len() != KEY_SIZE {
+            return Err(invalid_data("Snapshot key must be 32 bytes long"));
+        }
+
+        Ok(SnapshotKey {
+            cipher: Aes256Gcm::new(GenericArray::from_slice(key)),
+        })
+    }
+
+    /// Load the key from a file containing the 32 raw bytes of the key.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        // Read into a fixed size buffer, as growing a vector would leave
+        // copies of the key behind.
+        let mut key = Secret::new(KEY_SIZE);
+        file.read_exact(&mut key).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid_data("Snapshot key must be 32 bytes long"),
+            _ => e,
+        })?;
+        if file.read(&mut [0u8; 1])? != 0 {
+            return Err(invalid_data("Snapshot key must be 32 bytes long"));
+        }
+
+        SnapshotKey::new(&key)
+    }
+
+    pub fn writer<W: Write>(&self, name: &str, mut inner: W) -> io::Result<EncryptedWriter<W>> {
+        inner.write_all(ENCRYPTED_FILE_MAGIC)?;
+
+        Ok(EncryptedWriter {
+            key: self,
+            name: name.to_string(),
+            inner,
+            urandom: File::open("/dev/urandom")?,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            index: 0,
+        })
+    }
+
+    pub fn reader<R: Read>(&self, name: &str, mut inner: R) -> io::Result<DecryptedReader<R>> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != ENCRYPTED_FILE_MAGIC {
+            return Err(invalid_data("Snapshot file is not encrypted"));
+        }
+
+        Ok(DecryptedReader {
+            key: self,
+            name: name.to_string(),
+            inner,
+            buffer: Vec::new(),
+            offset: 0,
+            index: 0,
+            last: false,
+        })
+    }
+}
+
+impl Drop for SnapshotKey {
+    fn drop(&mut self) {
+        // Safe because the cipher only holds the AES round keys and the GHASH
+        // key, not implementing Drop, for which zero is a valid value.
+        unsafe { clear_secret_value(&mut self.cipher) };
+    }
+}
+
+// Associated data authenticated along with each chunk.
+fn chunk_aad(name: &str, index: u64, last: bool) -> Vec<u8> {
+    let mut aad = name.as_bytes().to_vec();
+    aad.extend_from_slice(&index.to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+pub struct EncryptedWriter<'a, W: Write> {
+    key: &'a SnapshotKey,
+    name: String,
+    inner: W,
+    urandom: File,
+    buffer: Vec<u8>,
+    index: u64,
+}
+
+impl<'a, W: Write> EncryptedWriter<'a, W> {
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        self.urandom.read_exact(&mut nonce)?;
+
+        let aad = chunk_aad(&self.name, self.index, last);
+        let ciphertext = self
+            .key
+            .cipher
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &self.buffer,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Snapshot encryption failed"))?;
+
+        self.inner
+            .write_all(&(ciphertext.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&nonce)?;
+        self.inner.write_all(&ciphertext)?;
+
+        self.buffer.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Write the last chunk, which must be done for the file to be valid.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<'a, W: Write> Write for EncryptedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk(false)?;
+        }
+
+        let len = std::cmp::min(buf.len(), CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct DecryptedReader<'a, R: Read> {
+    key: &'a SnapshotKey,
+    name: String,
+    inner: R,
+    buffer: Vec<u8>,
+    offset: usize,
+    index: u64,
+    last: bool,
+}
+
+impl<'a, R: Read> DecryptedReader<'a, R> {
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                invalid_data("Snapshot file is truncated")
+            } else {
+                e
+            }
+        })?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len < TAG_SIZE || len > CHUNK_SIZE + TAG_SIZE {
+            return Err(invalid_data("Invalid snapshot chunk length"));
+        }
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        self.inner.read_exact(&mut nonce)?;
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext)?;
+
+        // The last chunk is the only one authenticated as such, hence the
+        // attempt with both flags.
+        let nonce = GenericArray::from_slice(&nonce);
+        for last in [false, true].iter() {
+            let aad = chunk_aad(&self.name, self.index, *last);
+            if let Ok(plaintext) = self.key.cipher.decrypt(
+                nonce,
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            ) {
+                self.buffer = plaintext;
+                self.offset = 0;
+                self.index += 1;
+                self.last = *last;
+                return Ok(());
+            }
+        }
+
+        Err(invalid_data("Snapshot authentication failed"))
+    }
+}
+
+impl<'a, R: Read> Read for DecryptedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.buffer.len() {
+            if self.last {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+
+        let len = std::cmp::min(buf.len(), self.buffer.len() - self.offset);
+        buf[..len].copy_from_slice(&self.buffer[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+/// Whether the content starts as an encrypted snapshot file.
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(ENCRYPTED_FILE_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(key: &SnapshotKey, name: &str, content: &[u8]) -> Vec<u8> {
+        let mut writer = key.writer(name, Vec::new()).unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(key: &SnapshotKey, name: &str, encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        key.reader(name, encrypted)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn test_snapshot_encryption() {
+        let key = SnapshotKey::new(&[0x42; KEY_SIZE]).unwrap();
+        let content: Vec<u8> = (0..(CHUNK_SIZE * 2 + 100)).map(|i| i as u8).collect();
+
+        let encrypted = encrypt(&key, "memory-region-0", &content);
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(
+            decrypt(&key, "memory-region-0", &encrypted).unwrap(),
+            content
+        );
+        assert_eq!(
+            decrypt(&key, "vm.json", &encrypt(&key, "vm.json", b"")).unwrap(),
+            b""
+        );
+
+        // Wrong key or file name
+        let other_key = SnapshotKey::new(&[0x43; KEY_SIZE]).unwrap();
+        assert!(decrypt(&other_key, "memory-region-0", &encrypted).is_err());
+        assert!(decrypt(&key, "memory-region-1", &encrypted).is_err());
+
+        // Tampered or truncated content
+        let mut tampered = encrypted.clone();
+        tampered[100] ^= 1;
+        assert!(decrypt(&key, "memory-region-0", &tampered).is_err());
+        let last_chunk_len = 4 + NONCE_SIZE + 100 + TAG_SIZE;
+        let truncated = &encrypted[..encrypted.len() - last_chunk_len];
+        assert!(decrypt(&key, "memory-region-0", truncated).is_err());
+
+        // Not encrypted
+        assert!(decrypt(&key, "vm.json", b"{\"config\": {}}").is_err());
+        assert!(SnapshotKey::new(&[0; 16]).is_err());
+    }
+}
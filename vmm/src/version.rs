@@ -0,0 +1,133 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Detailed version information, listing the features built in and what the
+//! host supports, for the support tooling to capture the environment the VMM
+//! runs in with a single command.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// Whether the host provides what a component relies on.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostSupport {
+    Supported,
+    Unsupported,
+    /// The component isn't built in, hence the host isn't probed for it.
+    NotBuiltIn,
+}
+
+impl From<bool> for HostSupport {
+    fn from(supported: bool) -> Self {
+        if supported {
+            HostSupport::Supported
+        } else {
+            HostSupport::Unsupported
+        }
+    }
+}
+
+impl fmt::Display for HostSupport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostSupport::Supported => write!(f, "yes"),
+            HostSupport::Unsupported => write!(f, "no"),
+            HostSupport::NotBuiltIn => write!(f, "not built in"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: String,
+    pub arch: &'static str,
+    /// Whether each optional component is built in.
+    pub features: BTreeMap<&'static str, bool>,
+    /// Whether the host provides what each component relies on.
+    pub host: BTreeMap<&'static str, HostSupport>,
+}
+
+impl VersionInfo {
+    pub fn new(version: &str) -> Self {
+        let mut features = BTreeMap::new();
+        features.insert("acpi", cfg!(feature = "acpi"));
+        features.insert("cmos", cfg!(feature = "cmos"));
+        features.insert("fwdebug", cfg!(feature = "fwdebug"));
+        features.insert("io_uring", cfg!(feature = "io_uring"));
+        features.insert("kvm", cfg!(feature = "kvm"));
+        features.insert("mshv", cfg!(feature = "mshv"));
+        features.insert("otlp", cfg!(feature = "otlp"));
+        // Always built in, but listed for the tooling not to assume so.
+        features.insert("vfio", true);
+        features.insert("vhost_user", true);
+
+        let mut host = BTreeMap::new();
+        host.insert("hypervisor", hypervisor::new().is_ok().into());
+        host.insert(
+            "io_uring",
+            if cfg!(feature = "io_uring") {
+                block_util::block_io_uring_is_supported().into()
+            } else {
+                HostSupport::NotBuiltIn
+            },
+        );
+        host.insert("vfio", Path::new("/dev/vfio/vfio").exists().into());
+        host.insert(
+            "hugepages",
+            Path::new("/sys/kernel/mm/hugepages").exists().into(),
+        );
+
+        VersionInfo {
+            version: version.to_owned(),
+            arch: std::env::consts::ARCH,
+            features,
+            host,
+        }
+    }
+}
+
+impl fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cloud-hypervisor {}", self.version)?;
+        writeln!(f, "Architecture: {}", self.arch)?;
+        writeln!(f, "Features:")?;
+        for (name, built_in) in &self.features {
+            writeln!(f, "  {}: {}", name, if *built_in { "yes" } else { "no" })?;
+        }
+        writeln!(f, "Host support:")?;
+        for (name, supported) in &self.host {
+            writeln!(f, "  {}: {}", name, supported)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_info() {
+        let info = VersionInfo::new("v0.12.0");
+        assert_eq!(info.features["kvm"], cfg!(feature = "kvm"));
+        assert!(info.features["vhost_user"]);
+
+        let human = info.to_string();
+        assert!(human.starts_with("cloud-hypervisor v0.12.0\n"));
+        assert!(human.contains("\n  vhost_user: yes\n"));
+
+        let json: serde_json::Value = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["version"], "v0.12.0");
+        assert_eq!(json["features"]["vfio"], true);
+        assert!(json["host"]["hypervisor"].is_string());
+        if !cfg!(feature = "io_uring") {
+            assert_eq!(info.host["io_uring"], HostSupport::NotBuiltIn);
+            assert!(human.contains("\n  io_uring: not built in\n"));
+            assert_eq!(json["host"]["io_uring"], "not-built-in");
+        }
+    }
+}
@@ -22,18 +22,30 @@ extern crate signal_hook;
 extern crate vm_allocator;
 extern crate vm_memory;
 
+use crate::access_trace::{AccessTrace, AccessType, VcpuAccessTrace};
+use crate::api::VirtioMemInfo;
+use crate::balloon_policy::{start_balloon_policy, BalloonPolicy, BalloonPolicyHandle};
+use crate::cgroup;
 #[cfg(feature = "acpi")]
 use crate::config::NumaConfig;
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig, ValidationError,
-    VmConfig, VsockConfig,
+    BalloonConfig, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig,
+    ValidationError, VmConfig, VsockConfig,
 };
 use crate::cpu;
-use crate::device_manager::{self, get_win_size, Console, DeviceManager, DeviceManagerError};
+use crate::device_manager::{
+    self, get_win_size, Console, ConsoleInput, DeviceManager, DeviceManagerError, PtyPair,
+};
 use crate::device_tree::DeviceTree;
+use crate::host_cpus::{self, HostCpus};
+use crate::hotplug::{HotplugDevice, HotplugJob};
+use crate::memory_layout::{MemoryLayout, MemoryRange, MemoryRangeType};
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::metadata::{start_metadata_service, MetadataServiceHandle};
 use crate::migration::{get_vm_snapshot, url_to_path, VM_SNAPSHOT_FILE};
+use crate::numa;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::snapshot_encryption::SnapshotKey;
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
 };
@@ -49,6 +61,8 @@ use linux_loader::cmdline::Cmdline;
 use linux_loader::loader::elf::Error::InvalidElfMagicNumber;
 #[cfg(target_arch = "x86_64")]
 use linux_loader::loader::elf::PvhBootCapability::PvhEntryPresent;
+#[cfg(target_arch = "aarch64")]
+use linux_loader::loader::pe::Error::InvalidImageMagicNumber;
 use linux_loader::loader::KernelLoader;
 use seccomp::{SeccompAction, SeccompFilter};
 use signal_hook::{iterator::backend::Handle, iterator::Signals, SIGINT, SIGTERM, SIGWINCH};
@@ -63,10 +77,12 @@ use std::io::{self, Read, Write};
 use std::io::{Seek, SeekFrom};
 use std::num::Wrapping;
 use std::ops::Deref;
+use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{result, str, thread};
 use url::Url;
-use vm_device::Bus;
+use vm_device::{Bus, Resource};
 use vm_memory::{
     Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
@@ -80,7 +96,7 @@ use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
 
 #[cfg(target_arch = "aarch64")]
-use arch::aarch64::gic::gicv3::kvm::{KvmGICv3, GIC_V3_SNAPSHOT_ID};
+use arch::aarch64::gic::gicv3_its::kvm::{KvmGICv3ITS, GIC_V3_ITS_SNAPSHOT_ID};
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::kvm::create_gic;
 
@@ -100,6 +116,18 @@ pub enum Error {
     /// Cannot load the kernel in memory
     KernelLoad(linux_loader::loader::Error),
 
+    /// The firmware doesn't fit in the UEFI flash
+    #[cfg(target_arch = "aarch64")]
+    FirmwareTooBig,
+
+    /// Cannot map the UEFI flash
+    #[cfg(target_arch = "aarch64")]
+    UefiFlash(MemoryManagerError),
+
+    /// Cannot load the firmware in the UEFI flash
+    #[cfg(target_arch = "aarch64")]
+    FirmwareLoad(vm_memory::GuestMemoryError),
+
     /// Cannot load the initramfs in memory
     InitramfsLoad,
 
@@ -126,6 +154,12 @@ pub enum Error {
     /// Write to the console failed.
     Console(vmm_sys_util::errno::Error),
 
+    /// Cannot read from the serial port pseudo terminal.
+    SerialPty(io::Error),
+
+    /// Cannot read from the virtio-console pseudo terminal.
+    ConsolePty(io::Error),
+
     /// Cannot setup terminal in raw mode.
     SetTerminalRaw(vmm_sys_util::errno::Error),
 
@@ -156,6 +190,16 @@ pub enum Error {
     /// VM is not running
     VmNotRunning,
 
+    /// VM is not suspended
+    VmNotSuspended,
+
+    /// VM is suspended, waiting to be woken up
+    VmSuspended,
+
+    /// Incremental snapshots can't track the memory written by the vhost-user
+    /// backends and the VFIO devices, nor the hugepages
+    IncrementalSnapshotUnsupported,
+
     /// Cannot clone EventFd.
     EventFdClone(io::Error),
 
@@ -201,6 +245,9 @@ pub enum Error {
     /// Cannot send VM snapshot
     SnapshotSend(MigratableError),
 
+    /// Cannot load the snapshot encryption key
+    SnapshotKey(io::Error),
+
     /// Cannot convert source URL from Path into &str
     RestoreSourceUrlPathToStr,
 
@@ -216,6 +263,21 @@ pub enum Error {
     /// Invalid configuration for NUMA.
     InvalidNumaConfig,
 
+    /// Cannot retrieve the host NUMA topology
+    HostNumaTopology(numa::Error),
+
+    /// Invalid vCPU affinity for the host CPUs
+    HostCpus(host_cpus::Error),
+
+    /// Cannot set up the resource group
+    ResourceGroup(cgroup::Error),
+
+    /// Cannot start the balloon policy
+    BalloonPolicy(io::Error),
+
+    /// Cannot start the metadata service
+    MetadataService(io::Error),
+
     /// Cannot create seccomp filter
     CreateSeccompFilter(seccomp::SeccompError),
 
@@ -230,6 +292,24 @@ pub enum Error {
 
     /// Cannot activate virtio devices
     ActivateVirtioDevices(device_manager::DeviceManagerError),
+
+    /// Machine check address outside of the guest RAM
+    InvalidMceAddress(u64),
+
+    /// Machine check injection not supported
+    MceNotSupported,
+
+    /// Cannot spawn the thread of a hotplug job
+    HotplugThreadSpawn(io::Error),
+
+    /// No hotplug job with this id
+    UnknownHotplugJob(u64),
+
+    /// Hotplug job already adding the device, or over
+    HotplugJobNotPending(u64),
+
+    /// Hotplug job cancelled before the device was added
+    HotplugCancelled,
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -237,7 +317,7 @@ pub type Result<T> = result::Result<T, Error>;
 pub struct NumaNode {
     memory_regions: Vec<Arc<GuestRegionMmap>>,
     hotplug_regions: Vec<Arc<GuestRegionMmap>>,
-    cpus: Vec<u8>,
+    cpus: Vec<u16>,
     distances: BTreeMap<u32, u8>,
     memory_zones: Vec<String>,
 }
@@ -251,7 +331,7 @@ impl NumaNode {
         &self.hotplug_regions
     }
 
-    pub fn cpus(&self) -> &Vec<u8> {
+    pub fn cpus(&self) -> &Vec<u16> {
         &self.cpus
     }
 
@@ -360,6 +440,7 @@ struct VmOps {
     mmio_bus: Arc<Bus>,
     #[cfg(target_arch = "x86_64")]
     timestamp: std::time::Instant,
+    access_trace: Arc<AccessTrace>,
 }
 
 impl VmOps {
@@ -399,10 +480,12 @@ impl VmmOps for VmOps {
                 warn!("Guest MMIO read to unregistered address 0x{:x}", addr);
             }
         }
+        self.access_trace.record(AccessType::MmioRead, addr, data);
         Ok(())
     }
 
     fn mmio_write(&self, addr: u64, data: &[u8]) -> hypervisor::vm::Result<()> {
+        self.access_trace.record(AccessType::MmioWrite, addr, data);
         match self.mmio_bus.write(addr, data) {
             Err(e) => {
                 if let vm_device::BusError::MissingAddressRange = e {
@@ -426,11 +509,14 @@ impl VmmOps for VmOps {
                 warn!("Guest PIO read to unregistered address 0x{:x}", addr);
             }
         }
+        self.access_trace.record(AccessType::PioRead, addr, data);
         Ok(())
     }
 
     #[cfg(target_arch = "x86_64")]
     fn pio_write(&self, addr: u64, data: &[u8]) -> hypervisor::vm::Result<()> {
+        self.access_trace.record(AccessType::PioWrite, addr, data);
+
         if addr == DEBUG_IOPORT as u64 && data.len() == 1 {
             self.log_debug_ioport(data[0]);
             return Ok(());
@@ -458,10 +544,120 @@ pub fn physical_bits(max_phys_bits: Option<u8>) -> u8 {
     cmp::min(host_phys_bits, max_phys_bits.unwrap_or(host_phys_bits))
 }
 
+// Move the VMM into the resource group, if any, before the guest memory gets
+// allocated so that it is accounted against the group limits.
+fn setup_resource_group(config: &Arc<Mutex<VmConfig>>) -> Result<Option<cgroup::ResourceGroup>> {
+    config
+        .lock()
+        .unwrap()
+        .resource_group
+        .as_ref()
+        .map(|resource_group| cgroup::setup(resource_group).map_err(Error::ResourceGroup))
+        .transpose()
+}
+
+// Host wall clock, in nanoseconds since the epoch.
+fn realtime_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// Add the device to the VM, and to the VmConfig for the device to be created
+// again in case of a reboot. This is shared with the hotplug jobs, running
+// outside of the VMM thread, which connect to the vhost-user backend
+// beforehand through `stream`, and can be cancelled until `start` returns.
+fn add_hotplug_device(
+    device_manager: &Mutex<DeviceManager>,
+    config: &Mutex<VmConfig>,
+    mut device: HotplugDevice,
+    stream: Option<UnixStream>,
+    start: &dyn Fn() -> bool,
+) -> Result<PciDeviceInfo> {
+    device.validate().map_err(Error::ConfigValidation)?;
+
+    // Creating a vhost-user device waits for its backend to answer, hence
+    // the DeviceManager is only locked before and after, for the rest of the
+    // VM not to wait for the backend too.
+    let pending = device_manager
+        .lock()
+        .unwrap()
+        .prepare_vhost_user_device(&mut device)
+        .map_err(Error::DeviceManager)?;
+    let pci_device_info = if let Some(mut pending) = pending {
+        let vhost_user_device = pending
+            .create(stream)
+            .map_err(Error::DeviceManager)
+            .and_then(|d| {
+                if start() {
+                    Ok(d)
+                } else {
+                    Err(Error::HotplugCancelled)
+                }
+            });
+        match vhost_user_device {
+            Ok(d) => device_manager
+                .lock()
+                .unwrap()
+                .add_vhost_user_device(d)
+                .map_err(Error::DeviceManager)?,
+            Err(e) => {
+                device_manager
+                    .lock()
+                    .unwrap()
+                    .release_vhost_user_device(pending)
+                    .map_err(Error::DeviceManager)?;
+                return Err(e);
+            }
+        }
+    } else {
+        if !start() {
+            return Err(Error::HotplugCancelled);
+        }
+        let mut device_manager = device_manager.lock().unwrap();
+        let result = match &mut device {
+            HotplugDevice::Disk(disk_cfg) => device_manager.add_disk(disk_cfg),
+            HotplugDevice::Net(net_cfg) => device_manager.add_net(net_cfg),
+            HotplugDevice::Fs(fs_cfg) => device_manager.add_fs(fs_cfg),
+            HotplugDevice::Device(device_cfg) => device_manager.add_device(device_cfg),
+        };
+        result.map_err(Error::DeviceManager)?
+    };
+
+    {
+        let mut config = config.lock().unwrap();
+        match device {
+            HotplugDevice::Disk(disk_cfg) => {
+                config.disks.get_or_insert_with(Vec::new).push(disk_cfg)
+            }
+            HotplugDevice::Net(net_cfg) => config.net.get_or_insert_with(Vec::new).push(net_cfg),
+            HotplugDevice::Fs(fs_cfg) => config.fs.get_or_insert_with(Vec::new).push(fs_cfg),
+            HotplugDevice::Device(device_cfg) => {
+                config.devices.get_or_insert_with(Vec::new).push(device_cfg)
+            }
+        }
+    }
+
+    device_manager
+        .lock()
+        .unwrap()
+        .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
+        .map_err(Error::DeviceManager)?;
+
+    Ok(pci_device_info)
+}
+
+// Finished hotplug jobs kept for their status to be queried, the oldest ones
+// being forgotten first.
+const MAX_FINISHED_HOTPLUG_JOBS: usize = 64;
+
 pub struct Vm {
     kernel: File,
     initramfs: Option<File>,
     threads: Vec<thread::JoinHandle<()>>,
+    balloon_policy: Option<BalloonPolicyHandle>,
+    metadata_service: Option<MetadataServiceHandle>,
     device_manager: Arc<Mutex<DeviceManager>>,
     config: Arc<Mutex<VmConfig>>,
     on_tty: bool,
@@ -478,6 +674,11 @@ pub struct Vm {
     numa_nodes: NumaNodes,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
+    suspended: bool,
+    access_trace: Arc<AccessTrace>,
+    hotplug_jobs: BTreeMap<u64, (Arc<HotplugJob>, thread::JoinHandle<()>)>,
+    next_hotplug_id: u64,
+    resource_group: Option<cgroup::ResourceGroup>,
 }
 
 impl Vm {
@@ -488,6 +689,8 @@ impl Vm {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
+        crash_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         #[cfg(feature = "kvm")] _saved_clock: Option<hypervisor::ClockData>,
@@ -499,6 +702,15 @@ impl Vm {
             .validate()
             .map_err(Error::ConfigValidation)?;
 
+        if let Some(affinity) = &config.lock().unwrap().cpus.affinity {
+            let warnings = HostCpus::new()
+                .and_then(|host_cpus| host_cpus.check_affinity(affinity))
+                .map_err(Error::HostCpus)?;
+            for warning in warnings {
+                warn!("{}", warning);
+            }
+        }
+
         // Create NUMA nodes based on NumaConfig.
         #[cfg(feature = "acpi")]
         let numa_nodes =
@@ -510,6 +722,7 @@ impl Vm {
             memory_manager.clone(),
             &exit_evt,
             &reset_evt,
+            &suspend_evt,
             seccomp_action.clone(),
             #[cfg(feature = "acpi")]
             numa_nodes.clone(),
@@ -521,6 +734,7 @@ impl Vm {
         #[cfg(target_arch = "x86_64")]
         let io_bus = Arc::clone(device_manager.lock().unwrap().io_bus());
         let mmio_bus = Arc::clone(device_manager.lock().unwrap().mmio_bus());
+        let access_trace = Arc::new(AccessTrace::new(config.lock().unwrap().cpus.max_vcpus));
         // Create the VmOps structure, which implements the VmmOps trait.
         // And send it to the hypervisor.
         let vm_ops: Arc<Box<dyn VmmOps>> = Arc::new(Box::new(VmOps {
@@ -530,6 +744,7 @@ impl Vm {
             mmio_bus,
             #[cfg(target_arch = "x86_64")]
             timestamp: std::time::Instant::now(),
+            access_trace: access_trace.clone(),
         }));
 
         let exit_evt_clone = exit_evt.try_clone().map_err(Error::EventFdClone)?;
@@ -540,9 +755,11 @@ impl Vm {
             vm.clone(),
             exit_evt_clone,
             reset_evt,
+            crash_evt,
             hypervisor,
             seccomp_action.clone(),
             vm_ops,
+            access_trace.clone(),
         )
         .map_err(Error::CpuManager)?;
 
@@ -566,6 +783,8 @@ impl Vm {
             config,
             on_tty,
             threads: Vec::with_capacity(1),
+            balloon_policy: None,
+            metadata_service: None,
             signals: None,
             state: RwLock::new(VmState::Created),
             cpu_manager,
@@ -577,6 +796,11 @@ impl Vm {
             numa_nodes,
             seccomp_action: seccomp_action.clone(),
             exit_evt,
+            suspended: false,
+            access_trace,
+            hotplug_jobs: BTreeMap::new(),
+            next_hotplug_id: 0,
+            resource_group: None,
         })
     }
 
@@ -645,19 +869,32 @@ impl Vm {
         Ok(numa_nodes)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
+        crash_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        serial_pty: Option<Arc<PtyPair>>,
+        console_pty: Option<Arc<PtyPair>>,
     ) -> Result<Self> {
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         hypervisor.check_required_extensions().unwrap();
         let vm = hypervisor.create_vm().unwrap();
         #[cfg(target_arch = "x86_64")]
         vm.enable_split_irq().unwrap();
+
+        let resource_group = setup_resource_group(&config)?;
+
+        if config.lock().unwrap().numa_auto {
+            let host_nodes = numa::host_numa_nodes().map_err(Error::HostNumaTopology)?;
+            numa::auto_placement(&mut config.lock().unwrap(), &host_nodes);
+        }
+
         let phys_bits = physical_bits(config.lock().unwrap().cpus.max_phys_bits);
         let memory_manager = MemoryManager::new(
             vm.clone(),
@@ -678,18 +915,21 @@ impl Vm {
             }
         }
 
-        let new_vm = Vm::new_from_memory_manager(
+        let mut new_vm = Vm::new_from_memory_manager(
             config,
             memory_manager,
             vm,
             exit_evt,
             reset_evt,
+            suspend_evt,
+            crash_evt,
             seccomp_action,
             hypervisor,
             #[cfg(feature = "kvm")]
             None,
             activate_evt,
         )?;
+        new_vm.resource_group = resource_group;
 
         // The device manager must create the devices from here as it is part
         // of the regular code path creating everything from scratch.
@@ -697,7 +937,7 @@ impl Vm {
             .device_manager
             .lock()
             .unwrap()
-            .create_devices()
+            .create_devices(serial_pty, console_pty)
             .map_err(Error::DeviceManager)?;
         Ok(new_vm)
     }
@@ -707,8 +947,12 @@ impl Vm {
         snapshot: &Snapshot,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
+        crash_evt: EventFd,
         source_url: Option<&str>,
+        snapshot_key: Option<&SnapshotKey>,
         prefault: bool,
+        clone: bool,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
@@ -720,6 +964,7 @@ impl Vm {
         vm.enable_split_irq().unwrap();
         let vm_snapshot = get_vm_snapshot(snapshot).map_err(Error::Restore)?;
         let config = vm_snapshot.config;
+        let resource_group = setup_resource_group(&config)?;
         if let Some(state) = vm_snapshot.state {
             vm.set_state(state)
                 .map_err(|e| Error::Restore(MigratableError::Restore(e.into())))?;
@@ -734,7 +979,9 @@ impl Vm {
                 vm.clone(),
                 &config.lock().unwrap().memory.clone(),
                 source_url,
+                snapshot_key,
                 prefault,
+                clone,
                 phys_bits,
             )
             .map_err(Error::MemoryManager)?
@@ -744,24 +991,38 @@ impl Vm {
             ))));
         };
 
-        Vm::new_from_memory_manager(
+        let mut new_vm = Vm::new_from_memory_manager(
             config,
             memory_manager,
             vm,
             exit_evt,
             reset_evt,
+            suspend_evt,
+            crash_evt,
             seccomp_action,
             hypervisor,
             #[cfg(feature = "kvm")]
             None,
             activate_evt,
-        )
+        )?;
+        new_vm.resource_group = resource_group;
+
+        // The guest clock carries on from the snapshot once resumed.
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        {
+            new_vm.saved_clock = vm_snapshot.clock;
+        }
+
+        Ok(new_vm)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_migration(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
+        crash_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
@@ -771,6 +1032,7 @@ impl Vm {
         let vm = hypervisor.create_vm().unwrap();
         #[cfg(target_arch = "x86_64")]
         vm.enable_split_irq().unwrap();
+        let resource_group = setup_resource_group(&config)?;
         let phys_bits = physical_bits(config.lock().unwrap().cpus.max_phys_bits);
 
         let memory_manager = MemoryManager::new(
@@ -781,18 +1043,23 @@ impl Vm {
         )
         .map_err(Error::MemoryManager)?;
 
-        Vm::new_from_memory_manager(
+        let mut new_vm = Vm::new_from_memory_manager(
             config,
             memory_manager,
             vm,
             exit_evt,
             reset_evt,
+            suspend_evt,
+            crash_evt,
             seccomp_action,
             hypervisor,
             #[cfg(feature = "kvm")]
             None,
             activate_evt,
-        )
+        )?;
+        new_vm.resource_group = resource_group;
+
+        Ok(new_vm)
     }
 
     fn load_initramfs(&mut self, guest_mem: &GuestMemoryMmap) -> Result<arch::InitramfsConfig> {
@@ -825,6 +1092,14 @@ impl Vm {
         for entry in self.device_manager.lock().unwrap().cmdline_additions() {
             cmdline.insert_str(entry).map_err(Error::CmdLineInsertStr)?;
         }
+        // The guest kernel carves the crash kernel memory out of the RAM
+        // from the memory map itself, which is where kexec expects to load
+        // the crash kernel from, hence it is only requested from the guest.
+        if let Some(crashkernel) = self.config.lock().unwrap().memory.crashkernel {
+            cmdline
+                .insert_str(format!("crashkernel={}M", crashkernel >> 20))
+                .map_err(Error::CmdLineInsertStr)?;
+        }
         Ok(CString::new(cmdline).map_err(Error::CmdLineCString)?)
     }
 
@@ -839,6 +1114,10 @@ impl Vm {
             None,
         ) {
             Ok(entry_addr) => entry_addr,
+            // Not a kernel image, this must be a UEFI firmware.
+            Err(linux_loader::loader::Error::Pe(InvalidImageMagicNumber)) => {
+                return self.load_firmware();
+            }
             Err(e) => {
                 return Err(Error::KernelLoad(e));
             }
@@ -851,6 +1130,40 @@ impl Vm {
         })
     }
 
+    // The firmware image is copied into the UEFI flash, and started from its
+    // first instruction, with the address of the device tree in X0.
+    #[cfg(target_arch = "aarch64")]
+    fn load_firmware(&mut self) -> Result<EntryPoint> {
+        let size = self
+            .kernel
+            .seek(SeekFrom::End(0))
+            .map_err(Error::KernelFile)?;
+        if size > arch::layout::UEFI_SIZE {
+            return Err(Error::FirmwareTooBig);
+        }
+        self.kernel
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::KernelFile)?;
+
+        let uefi_flash = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .add_uefi_flash()
+            .map_err(Error::UefiFlash)?;
+        uefi_flash
+            .read_exact_from(
+                GuestAddress(arch::layout::UEFI_START),
+                &mut self.kernel,
+                size as usize,
+            )
+            .map_err(Error::FirmwareLoad)?;
+
+        Ok(EntryPoint {
+            entry_addr: GuestAddress(arch::layout::UEFI_START),
+        })
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn load_kernel(&mut self) -> Result<EntryPoint> {
         let cmdline_cstring = self.get_cmdline()?;
@@ -942,6 +1255,11 @@ impl Vm {
                 &self.memory_manager,
                 &self.numa_nodes,
             ));
+            self.device_manager
+                .lock()
+                .unwrap()
+                .update_generation_id(false)
+                .map_err(Error::DeviceManager)?;
         }
 
         let sgx_epc_region = self
@@ -952,6 +1270,25 @@ impl Vm {
             .as_ref()
             .cloned();
 
+        let reserved_regions: Vec<arch::ReservedRegion> = self
+            .config
+            .lock()
+            .unwrap()
+            .reserved_memory
+            .iter()
+            .flatten()
+            .map(|r| arch::ReservedRegion {
+                start: GuestAddress(r.start),
+                size: r.size,
+                nvs: r.nvs,
+            })
+            .collect();
+
+        let (serial_number, uuid) = match &self.config.lock().unwrap().platform {
+            Some(platform) => (platform.serial_number.clone(), platform.uuid.clone()),
+            None => (None, None),
+        };
+
         match entry_addr.setup_header {
             Some(hdr) => {
                 arch::configure_system(
@@ -964,6 +1301,9 @@ impl Vm {
                     rsdp_addr,
                     BootProtocol::LinuxBoot,
                     sgx_epc_region,
+                    &reserved_regions,
+                    serial_number.as_deref(),
+                    uuid.as_deref(),
                 )
                 .map_err(Error::ConfigureSystem)?;
             }
@@ -978,6 +1318,9 @@ impl Vm {
                     rsdp_addr,
                     entry_addr.protocol,
                     sgx_epc_region,
+                    &reserved_regions,
+                    serial_number.as_deref(),
+                    uuid.as_deref(),
                 )
                 .map_err(Error::ConfigureSystem)?;
             }
@@ -1052,6 +1395,68 @@ impl Vm {
         Ok(())
     }
 
+    /// Handle a guest request to enter the S3 sleep state. The vCPUs and the
+    /// devices are paused, preserving their state until the VM is woken up.
+    pub fn suspend(&mut self) -> Result<()> {
+        self.pause().map_err(Error::Pause)?;
+        self.suspended = true;
+
+        Ok(())
+    }
+
+    /// Wake a VM up from the S3 sleep state. When the guest provided a
+    /// waking vector, the boot vCPU restarts from there as it would on real
+    /// hardware. Otherwise the guest resumes from where it requested to
+    /// suspend, as soon as it reads the wake status.
+    pub fn wakeup(&mut self) -> Result<()> {
+        if !self.suspended {
+            return Err(Error::VmNotSuspended);
+        }
+
+        #[cfg(feature = "acpi")]
+        {
+            #[cfg(target_arch = "x86_64")]
+            if let Some(waking_vector) = crate::acpi::waking_vector(
+                &self.memory_manager.lock().unwrap().guest_memory().memory(),
+            ) {
+                self.cpu_manager
+                    .lock()
+                    .unwrap()
+                    .wake_boot_vcpu(waking_vector)
+                    .map_err(Error::CpuManager)?;
+            }
+
+            self.device_manager
+                .lock()
+                .unwrap()
+                .wake()
+                .map_err(Error::DeviceManager)?;
+        }
+
+        self.suspended = false;
+        self.resume().map_err(Error::Resume)?;
+
+        Ok(())
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Emulate a press on the ACPI power button, letting the guest shut
+    /// itself down cleanly.
+    pub fn power_button(&self) -> Result<()> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(HotPlugNotificationFlags::POWER_BUTTON_CHANGED)
+            .map_err(Error::DeviceManager)
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         let new_state = VmState::Shutdown;
@@ -1072,6 +1477,21 @@ impl Vm {
             signals.close();
         }
 
+        // Trigger the termination of the balloon_policy thread
+        self.balloon_policy = None;
+
+        // Trigger the termination of the metadata thread
+        self.metadata_service = None;
+
+        // Cancel the hotplug jobs not adding their device yet, and wait for
+        // all of them to be over
+        for (job, _) in self.hotplug_jobs.values() {
+            job.cancel();
+        }
+        for (_, (_, thread)) in std::mem::take(&mut self.hotplug_jobs) {
+            thread.join().map_err(Error::ThreadCleanup)?
+        }
+
         // Wake up the DeviceManager threads so they will get terminated cleanly
         self.device_manager
             .lock()
@@ -1089,6 +1509,10 @@ impl Vm {
         for thread in self.threads.drain(..) {
             thread.join().map_err(Error::ThreadCleanup)?
         }
+
+        // Leave the resource group, and remove it
+        self.resource_group = None;
+
         *state = new_state;
 
         Ok(())
@@ -1096,7 +1520,7 @@ impl Vm {
 
     pub fn resize(
         &mut self,
-        desired_vcpus: Option<u8>,
+        desired_vcpus: Option<u16>,
         desired_memory: Option<u64>,
         desired_balloon: Option<u64>,
     ) -> Result<()> {
@@ -1214,32 +1638,12 @@ impl Vm {
         Err(Error::ResizeZone)
     }
 
-    pub fn add_device(&mut self, mut _device_cfg: DeviceConfig) -> Result<PciDeviceInfo> {
-        let pci_device_info = self
-            .device_manager
-            .lock()
-            .unwrap()
-            .add_device(&mut _device_cfg)
-            .map_err(Error::DeviceManager)?;
-
-        // Update VmConfig by adding the new device. This is important to
-        // ensure the device would be created in case of a reboot.
-        {
-            let mut config = self.config.lock().unwrap();
-            if let Some(devices) = config.devices.as_mut() {
-                devices.push(_device_cfg);
-            } else {
-                config.devices = Some(vec![_device_cfg]);
-            }
-        }
-
-        self.device_manager
-            .lock()
-            .unwrap()
-            .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
-            .map_err(Error::DeviceManager)?;
-
-        Ok(pci_device_info)
+    pub fn add_device(&mut self, device_cfg: DeviceConfig) -> Result<PciDeviceInfo> {
+        add_hotplug_device(
+            &self.device_manager,
+            &self.config,
+            HotplugDevice::Device(device_cfg),
+        )
     }
 
     pub fn remove_device(&mut self, _id: String) -> Result<()> {
@@ -1288,22 +1692,42 @@ impl Vm {
         Ok(())
     }
 
-    pub fn add_disk(&mut self, mut _disk_cfg: DiskConfig) -> Result<PciDeviceInfo> {
+    pub fn add_disk(&mut self, disk_cfg: DiskConfig) -> Result<PciDeviceInfo> {
+        add_hotplug_device(
+            &self.device_manager,
+            &self.config,
+            HotplugDevice::Disk(disk_cfg),
+            None,
+            &|| true,
+        )
+    }
+
+    pub fn add_fs(&mut self, fs_cfg: FsConfig) -> Result<PciDeviceInfo> {
+        add_hotplug_device(
+            &self.device_manager,
+            &self.config,
+            HotplugDevice::Fs(fs_cfg),
+            None,
+            &|| true,
+        )
+    }
+
+    pub fn add_pmem(&mut self, mut _pmem_cfg: PmemConfig) -> Result<PciDeviceInfo> {
         let pci_device_info = self
             .device_manager
             .lock()
             .unwrap()
-            .add_disk(&mut _disk_cfg)
+            .add_pmem(&mut _pmem_cfg)
             .map_err(Error::DeviceManager)?;
 
         // Update VmConfig by adding the new device. This is important to
         // ensure the device would be created in case of a reboot.
         {
             let mut config = self.config.lock().unwrap();
-            if let Some(disks) = config.disks.as_mut() {
-                disks.push(_disk_cfg);
+            if let Some(pmem) = config.pmem.as_mut() {
+                pmem.push(_pmem_cfg);
             } else {
-                config.disks = Some(vec![_disk_cfg]);
+                config.pmem = Some(vec![_pmem_cfg]);
             }
         }
 
@@ -1316,23 +1740,33 @@ impl Vm {
         Ok(pci_device_info)
     }
 
-    pub fn add_fs(&mut self, mut _fs_cfg: FsConfig) -> Result<PciDeviceInfo> {
+    pub fn add_net(&mut self, net_cfg: NetConfig) -> Result<PciDeviceInfo> {
+        add_hotplug_device(
+            &self.device_manager,
+            &self.config,
+            HotplugDevice::Net(net_cfg),
+            None,
+            &|| true,
+        )
+    }
+
+    pub fn add_vsock(&mut self, mut _vsock_cfg: VsockConfig) -> Result<PciDeviceInfo> {
+        if self.config.lock().unwrap().vsock.is_some() {
+            return Err(Error::TooManyVsockDevices);
+        }
+
         let pci_device_info = self
             .device_manager
             .lock()
             .unwrap()
-            .add_fs(&mut _fs_cfg)
+            .add_vsock(&mut _vsock_cfg)
             .map_err(Error::DeviceManager)?;
 
         // Update VmConfig by adding the new device. This is important to
         // ensure the device would be created in case of a reboot.
         {
             let mut config = self.config.lock().unwrap();
-            if let Some(fs_config) = config.fs.as_mut() {
-                fs_config.push(_fs_cfg);
-            } else {
-                config.fs = Some(vec![_fs_cfg]);
-            }
+            config.vsock = Some(_vsock_cfg);
         }
 
         self.device_manager
@@ -1344,92 +1778,245 @@ impl Vm {
         Ok(pci_device_info)
     }
 
-    pub fn add_pmem(&mut self, mut _pmem_cfg: PmemConfig) -> Result<PciDeviceInfo> {
-        let pci_device_info = self
-            .device_manager
-            .lock()
-            .unwrap()
-            .add_pmem(&mut _pmem_cfg)
-            .map_err(Error::DeviceManager)?;
-
-        // Update VmConfig by adding the new device. This is important to
-        // ensure the device would be created in case of a reboot.
-        {
-            let mut config = self.config.lock().unwrap();
-            if let Some(pmem) = config.pmem.as_mut() {
-                pmem.push(_pmem_cfg);
-            } else {
-                config.pmem = Some(vec![_pmem_cfg]);
+    /// Start adding `device` to the VM on a separate thread, returning the
+    /// id of the job to query or cancel it through.
+    pub fn hotplug(&mut self, device: HotplugDevice) -> Result<u64> {
+        self.prune_hotplug_jobs()?;
+
+        let id = self.next_hotplug_id;
+        let job = Arc::new(HotplugJob::new(id));
+        let thread_job = job.clone();
+        let device_manager = self.device_manager.clone();
+        let config = self.config.clone();
+
+        // The thread is joined when the VM shuts down, after the job has been
+        // cancelled, which aborts the connection to a backend not answering.
+        let thread = thread::Builder::new()
+            .name(format!("hotplug{}", id))
+            .spawn(move || {
+                thread_job.run(device, |device, stream, start| {
+                    crate::traced("vm.hotplug", || {
+                        add_hotplug_device(&device_manager, &config, device, stream, start)
+                    })
+                    .map_err(|e| {
+                        error!("Error when hotplugging device: {:?}", e);
+                        format!("{:?}", e)
+                    })
+                })
+            })
+            .map_err(Error::HotplugThreadSpawn)?;
+
+        self.hotplug_jobs.insert(id, (job, thread));
+        self.next_hotplug_id += 1;
+
+        Ok(id)
+    }
+
+    // Forget the oldest finished hotplug jobs past MAX_FINISHED_HOTPLUG_JOBS.
+    fn prune_hotplug_jobs(&mut self) -> Result<()> {
+        let finished: Vec<u64> = self
+            .hotplug_jobs
+            .iter()
+            .filter(|(_, (job, _))| job.finished())
+            .map(|(id, _)| *id)
+            .collect();
+        let count = finished.len().saturating_sub(MAX_FINISHED_HOTPLUG_JOBS - 1);
+        for id in finished.into_iter().take(count) {
+            if let Some((_, thread)) = self.hotplug_jobs.remove(&id) {
+                thread.join().map_err(Error::ThreadCleanup)?;
             }
         }
 
+        Ok(())
+    }
+
+    /// The status of the hotplug job `id`, serialized in JSON.
+    pub fn hotplug_status(&self, id: u64) -> Result<Vec<u8>> {
+        self.hotplug_jobs
+            .get(&id)
+            .ok_or(Error::UnknownHotplugJob(id))?
+            .0
+            .status_json()
+            .map_err(Error::SerializeJson)
+    }
+
+    /// Cancel the hotplug job `id`, which must not be adding its device to
+    /// the VM yet.
+    pub fn cancel_hotplug(&mut self, id: u64) -> Result<()> {
+        let (job, _) = self
+            .hotplug_jobs
+            .get(&id)
+            .ok_or(Error::UnknownHotplugJob(id))?;
+        if !job.cancel() {
+            return Err(Error::HotplugJobNotPending(id));
+        }
+
+        Ok(())
+    }
+
+    pub fn set_net_link(&mut self, id: &str, up: bool) -> Result<()> {
         self.device_manager
             .lock()
             .unwrap()
-            .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
-            .map_err(Error::DeviceManager)?;
-
-        Ok(pci_device_info)
+            .set_net_link(id, up)
+            .map_err(Error::DeviceManager)
     }
 
-    pub fn add_net(&mut self, mut _net_cfg: NetConfig) -> Result<PciDeviceInfo> {
-        let pci_device_info = self
-            .device_manager
+    pub fn trace_queues(&mut self, id: &str, enable: bool) -> Result<()> {
+        self.device_manager
             .lock()
             .unwrap()
-            .add_net(&mut _net_cfg)
-            .map_err(Error::DeviceManager)?;
+            .trace_queues(id, enable)
+            .map_err(Error::DeviceManager)
+    }
 
-        // Update VmConfig by adding the new device. This is important to
-        // ensure the device would be created in case of a reboot.
-        {
-            let mut config = self.config.lock().unwrap();
-            if let Some(net) = config.net.as_mut() {
-                net.push(_net_cfg);
-            } else {
-                config.net = Some(vec![_net_cfg]);
-            }
+    pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
+        let mut counters = self.device_manager.lock().unwrap().counters();
+        counters.extend(self.cpu_manager.lock().unwrap().counters());
+        Ok(counters)
+    }
+
+    /// Report an uncorrected memory error at the guest physical `address` to
+    /// the guest through a machine check on the vCPU `cpu_id`, for testing
+    /// how the guest handles poisoned pages.
+    pub fn inject_mce(&mut self, cpu_id: u16, address: u64, action_required: bool) -> Result<()> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
         }
 
-        self.device_manager
-            .lock()
-            .unwrap()
-            .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
-            .map_err(Error::DeviceManager)?;
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        if !guest_memory
+            .memory()
+            .address_in_range(GuestAddress(address))
+        {
+            return Err(Error::InvalidMceAddress(address));
+        }
 
-        Ok(pci_device_info)
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        {
+            self.cpu_manager
+                .lock()
+                .unwrap()
+                .inject_memory_error(cpu_id, address, action_required)
+                .map_err(Error::CpuManager)
+        }
+        #[cfg(not(all(feature = "kvm", target_arch = "x86_64")))]
+        {
+            let _ = (cpu_id, action_required);
+            Err(Error::MceNotSupported)
+        }
     }
 
-    pub fn add_vsock(&mut self, mut _vsock_cfg: VsockConfig) -> Result<PciDeviceInfo> {
-        if self.config.lock().unwrap().vsock.is_some() {
-            return Err(Error::TooManyVsockDevices);
+    /// Last port I/O and MMIO accesses of each vCPU.
+    pub fn access_trace(&self) -> Vec<VcpuAccessTrace> {
+        self.access_trace.traces()
+    }
+
+    /// Guest physical address space layout, from the RAM and the reserved
+    /// areas to the ranges used by the devices.
+    pub fn memory_layout(&self) -> Vec<MemoryRange> {
+        let mut layout = MemoryLayout::new();
+
+        for (start, size, region_type) in arch::arch_memory_regions(0) {
+            match region_type {
+                arch::RegionType::SubRegion => layout.add(
+                    start.raw_value(),
+                    size as u64,
+                    MemoryRangeType::DeviceArea,
+                    "32-bit device area",
+                ),
+                arch::RegionType::Reserved => layout.add(
+                    start.raw_value(),
+                    size as u64,
+                    MemoryRangeType::Reserved,
+                    "reserved",
+                ),
+                arch::RegionType::Ram => {}
+            }
         }
 
-        let pci_device_info = self
-            .device_manager
-            .lock()
-            .unwrap()
-            .add_vsock(&mut _vsock_cfg)
-            .map_err(Error::DeviceManager)?;
+        let memory_manager = self.memory_manager.lock().unwrap();
+        let mut zone_regions = Vec::new();
+        for (id, zone) in memory_manager.memory_zones().iter() {
+            for region in zone.regions() {
+                let start = region.start_addr().raw_value();
+                layout.add(
+                    start,
+                    region.len() as u64,
+                    MemoryRangeType::Ram,
+                    &format!("memory zone {}", id),
+                );
+                zone_regions.push(start);
+            }
+            if let Some(virtio_mem_zone) = zone.virtio_mem_zone() {
+                let region = virtio_mem_zone.region();
+                let start = region.start_addr().raw_value();
+                layout.add(
+                    start,
+                    region.len() as u64,
+                    MemoryRangeType::VirtioMem,
+                    &format!("virtio-mem of memory zone {}", id),
+                );
+                zone_regions.push(start);
+            }
+        }
+        // Memory hotplugged through ACPI doesn't belong to any zone.
+        for region in memory_manager.guest_memory().memory().iter() {
+            let start = region.start_addr().raw_value();
+            if !zone_regions.contains(&start) {
+                layout.add(
+                    start,
+                    region.len() as u64,
+                    MemoryRangeType::Ram,
+                    "hotplugged memory",
+                );
+            }
+        }
 
-        // Update VmConfig by adding the new device. This is important to
-        // ensure the device would be created in case of a reboot.
+        let start_of_device_area = memory_manager.start_of_device_area().raw_value();
+        layout.add(
+            start_of_device_area,
+            memory_manager.end_of_device_area().raw_value() - start_of_device_area + 1,
+            MemoryRangeType::DeviceArea,
+            "64-bit device area",
+        );
+
+        #[cfg(target_arch = "x86_64")]
         {
-            let mut config = self.config.lock().unwrap();
-            config.vsock = Some(_vsock_cfg);
+            if let Some(sgx_epc_region) = memory_manager.sgx_epc_region() {
+                for (id, section) in sgx_epc_region.epc_sections().iter().enumerate() {
+                    layout.add(
+                        section.start().raw_value(),
+                        section.size(),
+                        MemoryRangeType::SgxEpc,
+                        &format!("SGX EPC section {}", id),
+                    );
+                }
+            }
+            if let Some(reserved_memory) = &self.config.lock().unwrap().reserved_memory {
+                for (id, range) in reserved_memory.iter().enumerate() {
+                    layout.add(
+                        range.start,
+                        range.size,
+                        MemoryRangeType::Reserved,
+                        &format!("reserved memory {}", id),
+                    );
+                }
+            }
         }
+        drop(memory_manager);
 
-        self.device_manager
-            .lock()
-            .unwrap()
-            .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
-            .map_err(Error::DeviceManager)?;
-
-        Ok(pci_device_info)
-    }
+        let device_tree = self.device_manager.lock().unwrap().device_tree();
+        for (id, node) in device_tree.lock().unwrap().iter() {
+            for resource in node.resources.iter() {
+                if let Resource::MmioAddressRange { base, size } = resource {
+                    layout.add(*base, *size, MemoryRangeType::Device, id);
+                }
+            }
+        }
 
-    pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
-        Ok(self.device_manager.lock().unwrap().counters())
+        layout.ranges()
     }
 
     fn os_signal_handler(
@@ -1486,6 +2073,9 @@ impl Vm {
             .start_boot_vcpus()
             .map_err(Error::CpuManager)?;
 
+        self.start_balloon_policy()?;
+        self.start_metadata_service()?;
+
         if self
             .device_manager
             .lock()
@@ -1536,6 +2126,70 @@ impl Vm {
         Ok(())
     }
 
+    // Start adjusting the balloon size based on the host memory pressure,
+    // when the balloon has been configured with a policy.
+    fn start_balloon_policy(&mut self) -> Result<()> {
+        let policy = match &self.config.lock().unwrap().balloon {
+            Some(BalloonConfig {
+                policy_min: Some(min),
+                policy_max: Some(max),
+                ..
+            }) => BalloonPolicy {
+                min: *min,
+                max: *max,
+            },
+            _ => return Ok(()),
+        };
+
+        let (handle, thread) = start_balloon_policy(
+            policy,
+            self.device_manager.clone(),
+            self.config.clone(),
+            &self.seccomp_action,
+        )
+        .map_err(Error::BalloonPolicy)?;
+        self.balloon_policy = Some(handle);
+        self.threads.push(thread);
+
+        Ok(())
+    }
+
+    // Start serving the metadata to the guest, when the metadata service has
+    // been configured.
+    fn start_metadata_service(&mut self) -> Result<()> {
+        let config = match &self.config.lock().unwrap().metadata {
+            Some(config) => config.clone(),
+            None => return Ok(()),
+        };
+
+        let (handle, thread) = start_metadata_service(&config, &self.seccomp_action)
+            .map_err(Error::MetadataService)?;
+        self.metadata_service = Some(handle);
+        self.threads.push(thread);
+
+        Ok(())
+    }
+
+    // Ask the QEMU guest agent behind the console port `name` to set the
+    // guest time from the host wall clock. The leading 0xff byte makes the
+    // agent drop any partial command left over, and its reply goes to the
+    // endpoint of the port.
+    fn set_guest_agent_time(&self, name: &str) {
+        let mut command = vec![0xff];
+        command.extend_from_slice(
+            format!(
+                "{{\"execute\":\"guest-set-time\",\"arguments\":{{\"time\":{}}}}}\n",
+                realtime_ns()
+            )
+            .as_bytes(),
+        );
+        self.device_manager
+            .lock()
+            .unwrap()
+            .console()
+            .queue_port_input_bytes(name, &command);
+    }
+
     pub fn handle_stdin(&self) -> Result<()> {
         let mut out = [0u8; 64];
         let count = io::stdin()
@@ -1566,6 +2220,59 @@ impl Vm {
         Ok(())
     }
 
+    /// Forward the input pending on the pseudo terminal the serial port or
+    /// the virtio-console device is connected to.
+    pub fn handle_pty(&self, device: ConsoleInput) -> Result<()> {
+        let (pty, pty_error): (_, fn(io::Error) -> Error) = match device {
+            ConsoleInput::Serial => (self.serial_pty(), Error::SerialPty),
+            ConsoleInput::VirtioConsole => (self.console_pty(), Error::ConsolePty),
+        };
+        let pty = match pty {
+            Some(pty) => pty,
+            None => return Ok(()),
+        };
+
+        let mut out = [0u8; 64];
+        let count = match (&pty.main).read(&mut out) {
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(pty_error(e)),
+        };
+
+        let device_manager = self.device_manager.lock().unwrap();
+        let console = device_manager.console();
+        match device {
+            ConsoleInput::Serial => console
+                .queue_serial_input_bytes(&out[..count])
+                .map_err(Error::Console),
+            ConsoleInput::VirtioConsole => {
+                console.queue_virtio_console_input_bytes(&out[..count]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Pseudo terminal the serial port is connected to, if any.
+    pub fn serial_pty(&self) -> Option<Arc<PtyPair>> {
+        self.device_manager.lock().unwrap().serial_pty()
+    }
+
+    /// Pseudo terminal the virtio-console device is connected to, if any.
+    pub fn console_pty(&self) -> Option<Arc<PtyPair>> {
+        self.device_manager.lock().unwrap().console_pty()
+    }
+
+    /// Tell the guest it runs from a snapshot, which may be restored several
+    /// times, through a new VM generation ID.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn update_generation_id(&self) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .update_generation_id(true)
+            .map_err(Error::DeviceManager)
+    }
+
     /// Gets a thread-safe reference counted pointer to the VM configuration.
     pub fn get_config(&self) -> Arc<Mutex<VmConfig>> {
         Arc::clone(&self.config)
@@ -1600,7 +2307,7 @@ impl Vm {
                 .lock()
                 .unwrap()
                 .as_any_concrete_mut()
-                .downcast_mut::<KvmGICv3>()
+                .downcast_mut::<KvmGICv3ITS>()
                 .unwrap()
                 .snapshot()?,
         );
@@ -1619,8 +2326,7 @@ impl Vm {
         let vcpu_numbers = saved_vcpu_states.len();
 
         // Creating a GIC device here, as the GIC will not be created when
-        // restoring the device manager. Note that currently only the bare GICv3
-        // without ITS is supported.
+        // restoring the device manager.
         let gic_device = create_gic(&self.vm, vcpu_numbers.try_into().unwrap())
             .map_err(|e| MigratableError::Restore(anyhow!("Could not create GIC: {:#?}", e)))?;
 
@@ -1636,8 +2342,8 @@ impl Vm {
             .unwrap()
             .construct_gicr_typers(&saved_vcpu_states);
 
-        // Restore GIC states.
-        if let Some(gic_v3_snapshot) = vm_snapshot.snapshots.get(GIC_V3_SNAPSHOT_ID) {
+        // Restore GIC and ITS states.
+        if let Some(gic_v3_its_snapshot) = vm_snapshot.snapshots.get(GIC_V3_ITS_SNAPSHOT_ID) {
             self.device_manager
                 .lock()
                 .unwrap()
@@ -1646,11 +2352,13 @@ impl Vm {
                 .lock()
                 .unwrap()
                 .as_any_concrete_mut()
-                .downcast_mut::<KvmGICv3>()
+                .downcast_mut::<KvmGICv3ITS>()
                 .unwrap()
-                .restore(*gic_v3_snapshot.clone())?;
+                .restore(*gic_v3_its_snapshot.clone())?;
         } else {
-            return Err(MigratableError::Restore(anyhow!("Missing GICv3 snapshot")));
+            return Err(MigratableError::Restore(anyhow!(
+                "Missing GICv3-ITS snapshot"
+            )));
         }
 
         self.device_manager
@@ -1751,6 +2459,40 @@ impl Vm {
         self.device_manager.lock().unwrap().device_tree()
     }
 
+    /// Report, for each virtio-mem device, the size requested to the guest
+    /// along with the size it actually plugged, so that the progress of a
+    /// resize, and especially of an unplug, can be monitored.
+    pub fn virtio_mem_info(&self) -> Vec<VirtioMemInfo> {
+        let memory_manager = self.memory_manager.lock().unwrap();
+        let mut info: Vec<VirtioMemInfo> = memory_manager
+            .memory_zones()
+            .iter()
+            .filter_map(|(id, zone)| {
+                zone.virtio_mem_zone().as_ref().map(|virtio_mem_zone| {
+                    let resize_handler = virtio_mem_zone.resize_handler();
+                    VirtioMemInfo {
+                        id: id.clone(),
+                        requested_size: resize_handler.requested_size(),
+                        plugged_size: resize_handler.plugged_size(),
+                    }
+                })
+            })
+            .collect();
+        info.sort_by(|a, b| a.id.cmp(&b.id));
+        info
+    }
+
+    /// Returns the host virtual address ranges, as (address, length) pairs,
+    /// of the memory backing the guest RAM.
+    pub fn guest_memory_host_ranges(&self) -> Vec<(u64, u64)> {
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let memory = guest_memory.memory();
+        memory
+            .iter()
+            .map(|region| (region.as_ptr() as u64, region.len()))
+            .collect()
+    }
+
     pub fn activate_virtio_devices(&self) -> Result<()> {
         self.device_manager
             .lock()
@@ -1782,6 +2524,9 @@ impl Pausable for Vm {
             clock.flags = 0;
             self.saved_clock = Some(clock);
         }
+        if let Some(balloon_policy) = &self.balloon_policy {
+            balloon_policy.set_paused(true);
+        }
         self.cpu_manager.lock().unwrap().pause()?;
         self.device_manager.lock().unwrap().pause()?;
 
@@ -1801,9 +2546,13 @@ impl Pausable for Vm {
             .valid_transition(new_state)
             .map_err(|e| MigratableError::Resume(anyhow!("Invalid transition: {:?}", e)))?;
 
+        let clock_config = self.config.lock().unwrap().clock.clone();
         self.cpu_manager.lock().unwrap().resume()?;
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         {
+            // The guest clock resumes where it stopped, as the guest expects
+            // from its monotonic clocks, only the wall clock being corrected
+            // through the guest agent.
             if let Some(clock) = &self.saved_clock {
                 self.vm.set_clock(clock).map_err(|e| {
                     MigratableError::Resume(anyhow!("Could not set VM clock: {}", e))
@@ -1811,6 +2560,12 @@ impl Pausable for Vm {
             }
         }
         self.device_manager.lock().unwrap().resume()?;
+        if let Some(guest_agent) = clock_config.and_then(|c| c.guest_agent) {
+            self.set_guest_agent_time(&guest_agent);
+        }
+        if let Some(balloon_policy) = &self.balloon_policy {
+            balloon_policy.set_paused(false);
+        }
 
         // And we're back to the Running state.
         *state = new_state;
@@ -1825,6 +2580,9 @@ pub struct VmSnapshot {
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
     pub clock: Option<hypervisor::ClockData>,
     pub state: Option<hypervisor::VmState>,
+    /// Whether the guest was suspended to RAM, waiting to be woken up.
+    #[serde(default)]
+    pub suspended: bool,
 }
 
 pub const VM_SNAPSHOT_ID: &str = "vm";
@@ -1851,6 +2609,7 @@ impl Snapshottable for Vm {
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             clock: self.saved_clock,
             state: Some(vm_state),
+            suspended: self.suspended,
         })
         .map_err(|e| MigratableError::Snapshot(e.into()))?;
 
@@ -1879,6 +2638,8 @@ impl Snapshottable for Vm {
             MigratableError::Restore(anyhow!("Could not restore VM state: {:#?}", e))
         })?;
 
+        self.suspended = get_vm_snapshot(&snapshot)?.suspended;
+
         if let Some(memory_manager_snapshot) = snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID) {
             self.memory_manager
                 .lock()
@@ -1924,6 +2685,18 @@ impl Snapshottable for Vm {
                 MigratableError::Restore(anyhow!("Cannot start restored vCPUs: {:#?}", e))
             })?;
 
+        // The VM is restored in the paused state, the balloon policy only
+        // takes effect once it has been resumed.
+        self.start_balloon_policy().map_err(|e| {
+            MigratableError::Restore(anyhow!("Cannot start balloon policy: {:#?}", e))
+        })?;
+        if let Some(balloon_policy) = &self.balloon_policy {
+            balloon_policy.set_paused(true);
+        }
+        self.start_metadata_service().map_err(|e| {
+            MigratableError::Restore(anyhow!("Cannot start metadata service: {:#?}", e))
+        })?;
+
         if self
             .device_manager
             .lock()
@@ -1994,11 +2767,36 @@ impl Snapshottable for Vm {
     }
 }
 
-impl Transportable for Vm {
-    fn send(
+impl Vm {
+    /// Compress the memory of the next snapshots.
+    pub fn set_snapshot_compression(&self, compress: bool) {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .set_snapshot_compression(compress);
+    }
+
+    /// Only store the memory dirtied since the last snapshot taken or
+    /// restored, for the next snapshot.
+    pub fn set_snapshot_incremental(&self, incremental: bool) -> Result<()> {
+        if incremental && !self.config.lock().unwrap().supports_incremental_snapshot() {
+            return Err(Error::IncrementalSnapshotUnsupported);
+        }
+
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .set_snapshot_incremental(incremental);
+        Ok(())
+    }
+
+    /// Write the snapshot to the destination, encrypting the files when a key
+    /// is provided.
+    pub fn send_snapshot(
         &self,
         snapshot: &Snapshot,
         destination_url: &str,
+        key: Option<&SnapshotKey>,
     ) -> std::result::Result<(), MigratableError> {
         let url = Url::parse(destination_url).map_err(|e| {
             MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
@@ -2021,18 +2819,28 @@ impl Transportable for Vm {
                 let vm_snapshot = serde_json::to_vec(snapshot)
                     .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
-                vm_snapshot_file
-                    .write(&vm_snapshot)
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                if let Some(key) = key {
+                    let mut writer = key
+                        .writer(VM_SNAPSHOT_FILE, vm_snapshot_file)
+                        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                    writer
+                        .write_all(&vm_snapshot)
+                        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                    writer
+                        .finish()
+                        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                } else {
+                    vm_snapshot_file
+                        .write(&vm_snapshot)
+                        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                }
 
                 // Tell the memory manager to also send/write its own snapshot.
-                if let Some(memory_manager_snapshot) =
-                    snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID)
-                {
+                if snapshot.snapshots.contains_key(MEMORY_MANAGER_SNAPSHOT_ID) {
                     self.memory_manager
                         .lock()
                         .unwrap()
-                        .send(&*memory_manager_snapshot.clone(), destination_url)?;
+                        .send_snapshot(destination_url, key)?;
                 } else {
                     return Err(MigratableError::Restore(anyhow!(
                         "Missing memory manager snapshot"
@@ -2049,6 +2857,16 @@ impl Transportable for Vm {
         Ok(())
     }
 }
+
+impl Transportable for Vm {
+    fn send(
+        &self,
+        snapshot: &Snapshot,
+        destination_url: &str,
+    ) -> std::result::Result<(), MigratableError> {
+        self.send_snapshot(snapshot, destination_url, None)
+    }
+}
 impl Migratable for Vm {}
 
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]